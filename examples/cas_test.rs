@@ -28,7 +28,7 @@ fn main() -> io::Result<()> {
     println!("\nTest 3: Check existence");
     write_frame(&mut writer, CasCommand::Exists, &hash)?;
     let (cmd, exists_data) = read_frame(&mut reader)?;
-    let exists = exists_data.get(0).copied().unwrap_or(0) != 0;
+    let exists = exists_data.first().copied().unwrap_or(0) != 0;
     println!("  Response: {:?}, exists: {}", cmd, exists);
 
     // Test 4: Read data back
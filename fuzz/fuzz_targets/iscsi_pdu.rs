@@ -0,0 +1,21 @@
+//! Fuzz iSCSI PDU parsing
+
+#![no_main]
+
+use aoe_server::iscsi::pdu::{DigestType, Digests, Pdu};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks which digests are in force
+    let Some((&mode, mut reader)) = data.split_first() else {
+        return;
+    };
+    let pick = |bit: u8| if mode & bit != 0 { DigestType::Crc32c } else { DigestType::None };
+    let digests = Digests {
+        header: pick(1),
+        data: pick(2),
+    };
+    while let Ok(pdu) = Pdu::read_with(&mut reader, digests) {
+        let _ = pdu.opcode();
+    }
+});
@@ -0,0 +1,27 @@
+//! Fuzz AoE frame parsing and dispatch
+//!
+//! Frames that parse are also run through a TargetManager backed by a
+//! small in-memory disk, so handler panics on odd headers are caught too.
+
+#![no_main]
+
+use aoe_server::protocol::parse_frame;
+use aoe_server::server::TargetManager;
+use aoe_server::storage::MemBackend;
+use libfuzzer_sys::fuzz_target;
+use std::sync::OnceLock;
+
+fn targets() -> &'static TargetManager {
+    static TARGETS: OnceLock<TargetManager> = OnceLock::new();
+    TARGETS.get_or_init(|| {
+        let mut targets = TargetManager::new();
+        targets.add_target(1, 0, Box::new(MemBackend::new(64 * 512)), "fuzz".to_string());
+        targets
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(frame) = parse_frame(data) {
+        let _ = targets().handle_frame(&frame);
+    }
+});
@@ -0,0 +1,229 @@
+//! Admin API client
+//!
+//! A small blocking HTTP/1.1 client for the management APIs of the
+//! running daemons: `aoe-server`'s (`[server] api`) and `iscsi-web`'s.
+//! Both answer with a `{success, data, error}` envelope; `request` returns
+//! the `data` of a successful response and turns `success: false` into
+//! `AdminError::Api`. Only plain `http://host:port` URLs are supported,
+//! which is all the daemons serve. A token set with `with_token` is sent
+//! as `Authorization: Bearer`.
+
+use serde_json::Value;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use thiserror::Error;
+
+/// Admin client errors
+#[derive(Debug, Error)]
+pub enum AdminError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("bad API URL {0:?}: expected http://host:port")]
+    Url(String),
+
+    #[error("bad HTTP response: {0}")]
+    Http(String),
+
+    /// The daemon refused the request
+    #[error("{0}")]
+    Api(String),
+}
+
+/// Result type for admin requests
+pub type AdminResult<T> = Result<T, AdminError>;
+
+/// Which daemon an API belongs to, from its OpenAPI document
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Daemon {
+    /// `aoe-server`: targets named `e<shelf>.<slot>`
+    Aoe,
+    /// `iscsi-web`: targets named by IQN, with GC jobs
+    Iscsi,
+}
+
+/// Client for one daemon's admin API
+pub struct AdminClient {
+    /// `host:port`
+    addr: String,
+    timeout: Duration,
+    token: Option<String>,
+}
+
+impl AdminClient {
+    /// Client for the API at `url` (`http://host:port`, or just `host:port`)
+    pub fn new(url: &str) -> AdminResult<Self> {
+        let addr = url.strip_prefix("http://").unwrap_or(url).trim_end_matches('/');
+        if addr.is_empty() || addr.contains('/') || url.starts_with("https://") {
+            return Err(AdminError::Url(url.to_string()));
+        }
+        Ok(Self {
+            addr: addr.to_string(),
+            timeout: Duration::from_secs(30),
+            token: None,
+        })
+    }
+
+    /// Give up on requests taking longer than `timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Authenticate with the API's bearer token
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Which daemon serves this API
+    pub fn daemon(&self) -> AdminResult<Daemon> {
+        let doc = self.send("GET", "/api/openapi.json", None)?;
+        let title = doc["info"]["title"].as_str().unwrap_or_default();
+        if title.contains("iSCSI") {
+            Ok(Daemon::Iscsi)
+        } else if title.contains("AoE") {
+            Ok(Daemon::Aoe)
+        } else {
+            Err(AdminError::Http(format!("unknown API {:?}", title)))
+        }
+    }
+
+    /// Send a request with an optional JSON body, returning the envelope's
+    /// `data`
+    pub fn request(&self, method: &str, path: &str, body: Option<&Value>) -> AdminResult<Value> {
+        let mut envelope = self.send(method, path, body)?;
+        if envelope["success"].as_bool() == Some(true) {
+            return Ok(envelope["data"].take());
+        }
+        match envelope["error"].as_str() {
+            Some(error) => Err(AdminError::Api(error.to_string())),
+            None => Err(AdminError::Http(format!("unexpected response: {}", envelope))),
+        }
+    }
+
+    /// `GET` shorthand for `request`
+    pub fn get(&self, path: &str) -> AdminResult<Value> {
+        self.request("GET", path, None)
+    }
+
+    /// Send a request and parse the response body as JSON
+    fn send(&self, method: &str, path: &str, body: Option<&Value>) -> AdminResult<Value> {
+        let mut stream = TcpStream::connect(&self.addr)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n",
+            method, path, self.addr
+        );
+        if let Some(token) = &self.token {
+            request.push_str(&format!("Authorization: Bearer {}\r\n", token));
+        }
+        if !body.is_empty() {
+            request.push_str("Content-Type: application/json\r\n");
+        }
+        request.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        stream.write_all(request.as_bytes())?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let body = parse_response(&response)?;
+        serde_json::from_slice(&body)
+            .map_err(|e| AdminError::Http(format!("{} {}: invalid JSON: {}", method, path, e)))
+    }
+}
+
+/// Body of a complete HTTP/1.1 response, failing on non-2xx statuses
+fn parse_response(response: &[u8]) -> AdminResult<Vec<u8>> {
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(|| AdminError::Http("truncated response".to_string()))?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let body = &response[split + 4..];
+
+    let mut lines = head.lines();
+    let status = lines.next().unwrap_or_default();
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+    if !code.starts_with('2') {
+        return Err(AdminError::Http(format!(
+            "{}: {}",
+            status,
+            String::from_utf8_lossy(body).trim()
+        )));
+    }
+    let chunked = lines.any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    if chunked {
+        dechunk(body)
+    } else {
+        Ok(body.to_vec())
+    }
+}
+
+/// Decode a chunked transfer-encoded body
+fn dechunk(mut body: &[u8]) -> AdminResult<Vec<u8>> {
+    let bad = || AdminError::Http("bad chunked encoding".to_string());
+    let mut data = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n").ok_or_else(bad)?;
+        let size = std::str::from_utf8(&body[..line_end]).map_err(|_| bad())?;
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16).map_err(|_| bad())?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(data);
+        }
+        if body.len() < size + 2 {
+            return Err(bad());
+        }
+        data.extend_from_slice(&body[..size]);
+        body = &body[size + 2..];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{api, TargetManager};
+    use crate::storage::MemBackend;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_parse_response() {
+        let plain = b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\n{}";
+        assert_eq!(parse_response(plain).unwrap(), b"{}");
+        let chunked = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                        3\r\n{\"a\r\n4\r\n\":1}\r\n0\r\n\r\n";
+        assert_eq!(parse_response(chunked).unwrap(), b"{\"a\":1}");
+        let missing = b"HTTP/1.1 404 Not Found\r\n\r\n";
+        assert!(matches!(parse_response(missing), Err(AdminError::Http(_))));
+    }
+
+    #[test]
+    fn test_admin_client() {
+        let mut manager = TargetManager::new();
+        manager.add_target(1, 0, Box::new(MemBackend::new(1024 * 1024)), String::new());
+        let token = Some("secret".to_string());
+        let (addr, _thread) = api::spawn("127.0.0.1:0", Arc::new(manager), token).unwrap();
+
+        let url = format!("http://{}/", addr);
+        let anonymous = AdminClient::new(&url).unwrap();
+        assert!(matches!(anonymous.daemon(), Err(AdminError::Http(e)) if e.contains("401")));
+
+        let client = AdminClient::new(&url).unwrap().with_token("secret");
+        assert_eq!(client.daemon().unwrap(), Daemon::Aoe);
+        let targets = client.get("/targets").unwrap();
+        assert_eq!(targets[0]["id"], "e1.0");
+        match client.request("POST", "/targets/e1.0/snapshot", None) {
+            Err(AdminError::Api(e)) => assert!(e.contains("does not support snapshots")),
+            other => panic!("expected an API error, got {:?}", other),
+        }
+        assert!(AdminClient::new("https://example.com").is_err());
+    }
+}
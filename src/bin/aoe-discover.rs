@@ -0,0 +1,185 @@
+//! AoE target discovery
+//!
+//! Broadcasts a Config query on a network interface and lists every target
+//! that answers: address, MAC, config string and firmware version, with
+//! size, model and ATA firmware from an IDENTIFY of each. Like `aoe-stat`,
+//! but needs no kernel driver, only a raw socket (root or CAP_NET_RAW).
+//!
+//! Example:
+//!   aoe-discover --interface eth0
+//!   aoe-discover --interface eth0 --json --no-identify
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use aoe_server::client::{config_fields, AoeClient, ClientConfig, EthernetTransport, RemoteTarget};
+
+#[derive(Parser, Debug)]
+#[command(name = "aoe-discover")]
+#[command(about = "List the AoE targets answering on a network interface", long_about = None)]
+struct Args {
+    /// Network interface to broadcast on
+    #[arg(short, long)]
+    interface: String,
+
+    /// How long to collect answers, in milliseconds
+    #[arg(long, default_value_t = 1000)]
+    wait_ms: u64,
+
+    /// How long to wait for each IDENTIFY before retrying, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    timeout_ms: u64,
+
+    /// Only list what the Config answers say, without IDENTIFYing targets
+    #[arg(long)]
+    no_identify: bool,
+
+    /// Print JSON instead of a table
+    #[arg(long)]
+    json: bool,
+}
+
+/// A target as listed
+#[derive(Serialize)]
+struct Discovered {
+    target: String,
+    shelf: u16,
+    slot: u8,
+    mac: String,
+    config_string: String,
+    /// `key=value` fields, if the config string is made of them
+    config_fields: Option<BTreeMap<String, String>>,
+    buffer_count: u16,
+    max_sectors: u8,
+    /// AoE firmware version from the Config answer
+    firmware_version: String,
+    size_bytes: Option<u64>,
+    total_sectors: Option<u64>,
+    sector_size: Option<u32>,
+    model: Option<String>,
+    serial: Option<String>,
+    /// ATA firmware revision from IDENTIFY
+    firmware: Option<String>,
+    /// Why the IDENTIFY failed
+    error: Option<String>,
+}
+
+impl Discovered {
+    fn new(target: &RemoteTarget) -> Self {
+        Self {
+            target: target.name(),
+            shelf: target.shelf,
+            slot: target.slot,
+            mac: target.mac_string(),
+            config_string: target.config_text(),
+            config_fields: config_fields(&target.config_string)
+                .map(|fields| fields.into_iter().collect()),
+            buffer_count: target.buffer_count,
+            max_sectors: target.max_sectors,
+            firmware_version: format!("{:#06x}", target.firmware_version),
+            size_bytes: None,
+            total_sectors: None,
+            sector_size: None,
+            model: None,
+            serial: None,
+            firmware: None,
+            error: None,
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let transport = EthernetTransport::open(&args.interface).with_context(|| {
+        format!(
+            "failed to open {} (needs root or CAP_NET_RAW)",
+            args.interface
+        )
+    })?;
+    let config = ClientConfig {
+        timeout: Duration::from_millis(args.timeout_ms),
+        ..ClientConfig::default()
+    };
+    let mut client = AoeClient::with_config(transport, config);
+
+    let targets = client
+        .discover(Duration::from_millis(args.wait_ms))
+        .context("discovery failed")?;
+
+    let mut found = Vec::new();
+    for target in targets {
+        let mut discovered = Discovered::new(&target);
+        if !args.no_identify {
+            match client.open(target) {
+                Ok(disk) => {
+                    discovered.size_bytes = Some(disk.total_sectors * disk.sector_size as u64);
+                    discovered.total_sectors = Some(disk.total_sectors);
+                    discovered.sector_size = Some(disk.sector_size);
+                    discovered.model = Some(disk.model);
+                    discovered.serial = Some(disk.serial);
+                    discovered.firmware = Some(disk.firmware);
+                }
+                Err(e) => discovered.error = Some(e.to_string()),
+            }
+        }
+        found.push(discovered);
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&found)?);
+    } else {
+        print_table(&found);
+    }
+    Ok(())
+}
+
+fn print_table(found: &[Discovered]) {
+    if found.is_empty() {
+        eprintln!("No AoE targets answered");
+        return;
+    }
+    println!(
+        "{:<10}  {:<17}  {:>10}  {:<24}  {:<16}  CONFIG",
+        "TARGET", "MAC", "SIZE", "MODEL", "FIRMWARE"
+    );
+    for target in found {
+        let size = match (target.size_bytes, &target.error) {
+            (Some(bytes), _) => format_size(bytes),
+            (None, Some(_)) => "error".to_string(),
+            (None, None) => "-".to_string(),
+        };
+        let firmware = match &target.firmware {
+            Some(firmware) => format!("{} ({})", firmware, target.firmware_version),
+            None => target.firmware_version.clone(),
+        };
+        println!(
+            "{:<10}  {:<17}  {:>10}  {:<24}  {:<16}  {}",
+            target.target,
+            target.mac,
+            size,
+            target.model.as_deref().unwrap_or("-"),
+            firmware,
+            target.config_string
+        );
+    }
+}
+
+/// Bytes in the largest binary unit that keeps at least one whole unit
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
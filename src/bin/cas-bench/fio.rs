@@ -0,0 +1,315 @@
+//! Minimal fio job file support
+//!
+//! Understands the part of fio's job file format that maps onto a
+//! `BlockStorage` workload: `rw`, `rwmixread`/`rwmixwrite`, `bs`,
+//! `iodepth`, `numjobs`, `runtime` and `size`, with `[global]` defaults
+//! applied to every job. Options about files and I/O engines (filename,
+//! ioengine, direct, ...) don't apply to a backend and are ignored with a
+//! warning. Each job's `iodepth * numjobs` becomes its queue depth, taking
+//! either from `[global]` if the job doesn't set it.
+
+use super::{Pattern, Workload};
+use std::time::Duration;
+
+/// A `[name]` section of a job file
+pub struct Job {
+    pub name: String,
+    pub workload: Workload,
+}
+
+/// Options fio accepts that make no difference here
+const IGNORED: &[&str] = &[
+    "filename",
+    "directory",
+    "ioengine",
+    "direct",
+    "buffered",
+    "time_based",
+    "group_reporting",
+    "randrepeat",
+    "norandommap",
+    "invalidate",
+    "thread",
+];
+
+/// Options that combine with each other, kept apart until their section
+/// ends so that the order they appear in (and which of them come from
+/// `[global]`) doesn't matter
+#[derive(Clone, Copy)]
+struct Combined {
+    iodepth: usize,
+    numjobs: usize,
+    /// Pattern, with the read percentage unless the job is mixed
+    rw: Option<(Pattern, Option<u8>)>,
+    rwmixread: Option<u8>,
+}
+
+impl Combined {
+    /// Resolve into `workload`: `iodepth * numjobs` threads, and a mixed
+    /// job's read percentage from rwmixread (half, as in fio, if not given)
+    fn apply(&self, workload: &mut Workload) -> Result<(), String> {
+        workload.queue_depth = self
+            .iodepth
+            .checked_mul(self.numjobs)
+            .ok_or_else(|| "iodepth * numjobs is too large".to_string())?;
+        if let Some((pattern, read_pct)) = self.rw {
+            workload.pattern = pattern;
+            workload.read_pct = read_pct.or(self.rwmixread).unwrap_or(50);
+        }
+        Ok(())
+    }
+}
+
+/// Parse `text`, starting every job from `base`
+pub fn parse(text: &str, base: &Workload) -> Result<Vec<Job>, String> {
+    let mut global = base.clone();
+    let mut jobs: Vec<Job> = Vec::new();
+    let mut in_global = false;
+    let mut global_options = Combined {
+        iodepth: base.queue_depth,
+        numjobs: 1,
+        rw: None,
+        rwmixread: None,
+    };
+    let mut options = global_options;
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+        let fail = |e: String| format!("line {}: {}", number + 1, e);
+
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            finish_section(&mut global, &mut jobs, in_global, &options).map_err(fail)?;
+            if in_global {
+                global_options = options;
+            }
+            in_global = name == "global";
+            if !in_global {
+                jobs.push(Job {
+                    name: name.to_string(),
+                    workload: global.clone(),
+                });
+            }
+            options = global_options;
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (line, ""),
+        };
+        let workload = match jobs.last_mut() {
+            Some(job) if !in_global => &mut job.workload,
+            _ if in_global => &mut global,
+            _ => return Err(fail(format!("'{}' outside of a section", key))),
+        };
+        match key {
+            "rw" | "readwrite" => options.rw = Some(parse_rw(value).map_err(fail)?),
+            "rwmixread" => options.rwmixread = Some(parse_pct(value).map_err(fail)?),
+            "rwmixwrite" => options.rwmixread = Some(100 - parse_pct(value).map_err(fail)?),
+            "bs" | "blocksize" => {
+                workload.block_size = parse_size(value).map_err(fail)? as usize
+            }
+            "iodepth" => options.iodepth = parse_count(value).map_err(fail)?,
+            "numjobs" => options.numjobs = parse_count(value).map_err(fail)?,
+            "runtime" => workload.duration = parse_time(value).map_err(fail)?,
+            "size" => workload.region = Some(parse_size(value).map_err(fail)?),
+            _ if IGNORED.contains(&key) => {
+                log::warn!("Ignoring fio option '{}' (not applicable to a backend)", key)
+            }
+            _ => return Err(fail(format!("unsupported fio option '{}'", key))),
+        }
+    }
+    finish_section(&mut global, &mut jobs, in_global, &options)?;
+
+    if jobs.is_empty() {
+        return Err("no jobs defined".to_string());
+    }
+    Ok(jobs)
+}
+
+/// Resolve the section's combined options into its workload
+fn finish_section(
+    global: &mut Workload,
+    jobs: &mut [Job],
+    in_global: bool,
+    options: &Combined,
+) -> Result<(), String> {
+    if in_global {
+        options.apply(global)
+    } else if let Some(job) = jobs.last_mut() {
+        options.apply(&mut job.workload)
+    } else {
+        Ok(())
+    }
+}
+
+/// Pattern and read percentage of an `rw` value; mixed ones leave the
+/// percentage to rwmixread
+fn parse_rw(value: &str) -> Result<(Pattern, Option<u8>), String> {
+    Ok(match value {
+        "read" => (Pattern::Seq, Some(100)),
+        "write" => (Pattern::Seq, Some(0)),
+        "randread" => (Pattern::Random, Some(100)),
+        "randwrite" => (Pattern::Random, Some(0)),
+        "rw" | "readwrite" => (Pattern::Seq, None),
+        "randrw" => (Pattern::Random, None),
+        _ => return Err(format!("unsupported rw '{}'", value)),
+    })
+}
+
+fn parse_pct(value: &str) -> Result<u8, String> {
+    match value.parse::<u8>() {
+        Ok(pct) if pct <= 100 => Ok(pct),
+        _ => Err(format!("invalid percentage '{}'", value)),
+    }
+}
+
+fn parse_count(value: &str) -> Result<usize, String> {
+    match value.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("invalid count '{}'", value)),
+    }
+}
+
+/// Byte size with an optional k/m/g/t suffix (powers of 1024, as in fio)
+fn parse_size(value: &str) -> Result<u64, String> {
+    let lower = value.to_ascii_lowercase();
+    let trimmed = lower.trim_end_matches(['b', 'i']);
+    let (digits, shift) = match trimmed.chars().last() {
+        Some('k') => (&trimmed[..trimmed.len() - 1], 10),
+        Some('m') => (&trimmed[..trimmed.len() - 1], 20),
+        Some('g') => (&trimmed[..trimmed.len() - 1], 30),
+        Some('t') => (&trimmed[..trimmed.len() - 1], 40),
+        _ => (trimmed, 0),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size '{}'", value))
+}
+
+/// Duration in seconds, or with an ms/s/m/h suffix
+fn parse_time(value: &str) -> Result<Duration, String> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, unit_ms) = if let Some(n) = lower.strip_suffix("ms") {
+        (n, 1)
+    } else if let Some(n) = lower.strip_suffix('s') {
+        (n, 1000)
+    } else if let Some(n) = lower.strip_suffix('m') {
+        (n, 60_000)
+    } else if let Some(n) = lower.strip_suffix('h') {
+        (n, 3_600_000)
+    } else {
+        (lower.as_str(), 1000)
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit_ms))
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("invalid time '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base() -> Workload {
+        Workload {
+            pattern: Pattern::Seq,
+            read_pct: 100,
+            queue_depth: 1,
+            block_size: 4096,
+            duration: Duration::from_secs(10),
+            region: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_job_file() {
+        let text = "\
+; random read then a mixed job
+[global]
+ioengine=libaio
+direct=1
+bs=8k
+runtime=30
+time_based
+
+[randread-4k]
+rw=randread
+bs=4k
+iodepth=16
+
+[mixed]
+rw=randrw
+rwmixread=70
+iodepth=4
+numjobs=2
+size=1g
+";
+        let jobs = parse(text, &base()).unwrap();
+        assert_eq!(jobs.len(), 2);
+
+        assert_eq!(jobs[0].name, "randread-4k");
+        let randread = &jobs[0].workload;
+        assert_eq!((randread.pattern, randread.read_pct), (Pattern::Random, 100));
+        assert_eq!((randread.block_size, randread.queue_depth), (4096, 16));
+        assert_eq!(randread.duration, Duration::from_secs(30));
+
+        let mixed = &jobs[1].workload;
+        assert_eq!((mixed.pattern, mixed.read_pct), (Pattern::Random, 70));
+        assert_eq!((mixed.block_size, mixed.queue_depth), (8192, 8));
+        assert_eq!(mixed.region, Some(1 << 30));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("[job]\nrw=trim\n", &base()).is_err());
+        assert!(parse("[job]\nverify=md5\n", &base()).is_err());
+        assert!(parse("bs=4k\n", &base()).is_err());
+        assert!(parse("[global]\nbs=4k\n", &base()).is_err());
+        assert_eq!(parse_size("64KiB").unwrap(), 65536);
+        assert_eq!(parse_time("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_time("500ms").unwrap(), Duration::from_millis(500));
+        assert!(parse_time("18446744073709551615h").is_err());
+    }
+
+    #[test]
+    fn test_combined_options() {
+        // numjobs from [global] multiplies a job's own iodepth, and
+        // rwmixread counts whether it comes before or after rw
+        let text = "\
+[global]
+numjobs=2
+rwmixread=30
+
+[mix-after]
+iodepth=8
+rw=randrw
+
+[mix-before]
+rwmixread=90
+rw=rw
+numjobs=3
+
+[pure]
+rw=randread
+rwmixread=10
+";
+        let jobs = parse(text, &base()).unwrap();
+        let after = &jobs[0].workload;
+        assert_eq!((after.pattern, after.read_pct), (Pattern::Random, 30));
+        assert_eq!(after.queue_depth, 16);
+        let before = &jobs[1].workload;
+        assert_eq!((before.pattern, before.read_pct), (Pattern::Seq, 90));
+        assert_eq!(before.queue_depth, 3);
+        assert_eq!(jobs[2].workload.read_pct, 100);
+
+        assert!(parse("[job]\niodepth=18446744073709551615\nnumjobs=2\n", &base()).is_err());
+    }
+}
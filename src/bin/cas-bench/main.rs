@@ -0,0 +1,389 @@
+//! Storage backend benchmark
+//!
+//! Drives a `BlockStorage` backend directly with a configurable workload
+//! and reports IOPS, bandwidth and latency percentiles, so backend changes
+//! can be measured without an initiator in the loop.
+//!
+//! Example:
+//!   cas-bench --backend cas --path /tmp/bench --size 1024 \
+//!     --pattern random --read-pct 70 --queue-depth 8 --block-size 4096 --seconds 30
+//!
+//! Existing fio job files can be run instead with `--job randread.fio`;
+//! see `fio` for the options understood.
+
+mod fio;
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use rand::{Rng, RngCore};
+use serde::Serialize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aoe_server::blob::FileBlobStore;
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::storage::cas_client::{self, CasBackendConfig};
+use aoe_server::storage::latency::LatencyHistogram;
+use aoe_server::storage::{CasBackend, FileBackend, MemBackend, OpLatency};
+use aoe_server::BlockStorage;
+
+#[derive(Parser, Debug)]
+#[command(name = "cas-bench")]
+#[command(about = "Benchmark a storage backend without an initiator", long_about = None)]
+struct Args {
+    /// Backend to drive
+    #[arg(long, value_enum)]
+    backend: Backend,
+
+    /// Backing file (file), blob directory (cas) or index file (cas-client)
+    #[arg(long)]
+    path: Option<PathBuf>,
+
+    /// Device size in MiB
+    #[arg(long, default_value = "1024")]
+    size: u64,
+
+    /// Data block size in bytes for the cas backend (default: one sector)
+    #[arg(long)]
+    cas_block_size: Option<u32>,
+
+    /// CAS server address for the cas-client backend
+    #[arg(long, default_value = "127.0.0.1:3000")]
+    cas_server: String,
+
+    /// Access pattern
+    #[arg(long, value_enum, default_value_t = Pattern::Random)]
+    pattern: Pattern,
+
+    /// Percentage of operations that are reads
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u8).range(0..=100))]
+    read_pct: u8,
+
+    /// Operations in flight (one thread each)
+    #[arg(long, default_value = "1")]
+    queue_depth: usize,
+
+    /// Bytes per operation; a multiple of the sector size
+    #[arg(long, default_value = "4096")]
+    block_size: usize,
+
+    /// How long to run
+    #[arg(long, default_value = "10")]
+    seconds: u64,
+
+    /// Write the whole device before measuring, so reads hit real data
+    #[arg(long)]
+    prefill: bool,
+
+    /// Print the report as JSON
+    #[arg(long)]
+    json: bool,
+
+    /// fio job file to run instead of the workload options above; each
+    /// job runs in turn
+    #[arg(long)]
+    job: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    Memory,
+    File,
+    Cas,
+    CasClient,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Pattern {
+    Seq,
+    Random,
+}
+
+/// What to run against the backend
+#[derive(Debug, Clone, PartialEq)]
+struct Workload {
+    pattern: Pattern,
+    read_pct: u8,
+    queue_depth: usize,
+    block_size: usize,
+    duration: Duration,
+    /// Bytes at the start of the device to use, if not all of it
+    region: Option<u64>,
+}
+
+/// Results for one kind of operation
+#[derive(Serialize)]
+struct OpReport {
+    iops: f64,
+    mib_per_sec: f64,
+    latency: OpLatency,
+}
+
+#[derive(Serialize)]
+struct Report {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    job: Option<String>,
+    seconds: f64,
+    read: OpReport,
+    write: OpReport,
+}
+
+/// Counters shared by the worker threads
+struct Totals {
+    read: LatencyHistogram,
+    write: LatencyHistogram,
+    read_bytes: AtomicU64,
+    write_bytes: AtomicU64,
+    errors: AtomicU64,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    logging::init("warn", LogFormat::Text);
+
+    let base = Workload {
+        pattern: args.pattern,
+        read_pct: args.read_pct,
+        queue_depth: args.queue_depth,
+        block_size: args.block_size,
+        duration: Duration::from_secs(args.seconds),
+        region: None,
+    };
+    let jobs = match &args.job {
+        Some(path) => {
+            let text = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+            fio::parse(&text, &base)
+                .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?
+                .into_iter()
+                .map(|job| (Some(job.name), job.workload))
+                .collect()
+        }
+        None => vec![(None, base)],
+    };
+
+    let storage = open_backend(&args)?;
+    let sector_size = storage.info().sector_size as usize;
+    for (_, workload) in &jobs {
+        if workload.block_size == 0
+            || !workload.block_size.is_multiple_of(sector_size)
+            || workload.block_size / sector_size > 255
+        {
+            bail!(
+                "block size must be a multiple of {} bytes, at most {} bytes",
+                sector_size,
+                255 * sector_size
+            );
+        }
+    }
+
+    if args.prefill {
+        let block_size = 255 * sector_size;
+        let total = storage.info().total_sectors;
+        eprintln!("Prefilling {} sectors...", total);
+        let mut data = vec![0u8; block_size];
+        let mut lba = 0;
+        while lba < total {
+            let count = (total - lba).min(255);
+            rand::thread_rng().fill_bytes(&mut data);
+            storage
+                .write(lba, &data[..count as usize * sector_size])
+                .context("prefill failed")?;
+            lba += count;
+        }
+        storage.flush().context("prefill flush failed")?;
+    }
+
+    let mut failed = 0;
+    let mut reports = Vec::new();
+    for (name, workload) in jobs {
+        let (report, errors) = run(storage.as_ref(), name, &workload)?;
+        if !args.json {
+            println!(
+                "{}{:?} {:?}, {}% reads, {} x {} bytes in flight, {:.1}s",
+                report.job.as_ref().map(|job| format!("{}: ", job)).unwrap_or_default(),
+                args.backend,
+                workload.pattern,
+                workload.read_pct,
+                workload.queue_depth,
+                workload.block_size,
+                report.seconds
+            );
+            print_op("read", &report.read);
+            print_op("write", &report.write);
+            if errors > 0 {
+                println!("errors: {}", errors);
+            }
+        }
+        failed += errors;
+        reports.push(report);
+    }
+    if args.json {
+        match reports.as_slice() {
+            [report] => println!("{}", serde_json::to_string_pretty(report)?),
+            reports => println!("{}", serde_json::to_string_pretty(reports)?),
+        }
+    }
+
+    if failed > 0 {
+        bail!("{} operations failed", failed);
+    }
+    Ok(())
+}
+
+/// Run one workload, returning its report and how many operations failed
+fn run(
+    storage: &dyn BlockStorage,
+    job: Option<String>,
+    workload: &Workload,
+) -> Result<(Report, u64)> {
+    let sector_size = storage.info().sector_size as usize;
+    let sectors = (workload.block_size / sector_size) as u64;
+    let device_sectors = match workload.region {
+        Some(bytes) => (bytes / sector_size as u64).min(storage.info().total_sectors),
+        None => storage.info().total_sectors,
+    };
+    let blocks = device_sectors / sectors;
+    if blocks == 0 {
+        bail!("device is smaller than one block");
+    }
+
+    let totals = Totals {
+        read: LatencyHistogram::new(),
+        write: LatencyHistogram::new(),
+        read_bytes: AtomicU64::new(0),
+        write_bytes: AtomicU64::new(0),
+        errors: AtomicU64::new(0),
+    };
+    let cursor = AtomicU64::new(0);
+    let started = Instant::now();
+
+    thread::scope(|scope| {
+        for _ in 0..workload.queue_depth.max(1) {
+            scope.spawn(|| {
+                let mut rng = rand::thread_rng();
+                let mut data = vec![0u8; workload.block_size];
+                while started.elapsed() < workload.duration {
+                    let block = match workload.pattern {
+                        Pattern::Seq => cursor.fetch_add(1, Ordering::Relaxed) % blocks,
+                        Pattern::Random => rng.gen_range(0..blocks),
+                    };
+                    let lba = block * sectors;
+                    if rng.gen_range(0..100) < workload.read_pct {
+                        let op_started = Instant::now();
+                        match storage.read(lba, sectors as u8) {
+                            Ok(_) => {
+                                totals.read.record(op_started.elapsed());
+                                totals.read_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            }
+                            Err(e) => record_error(&totals, "read", lba, &e),
+                        }
+                    } else {
+                        // Fresh contents throughout, so CAS backends can't deduplicate
+                        // any part of the block; filled before the write is timed
+                        rng.fill_bytes(&mut data);
+                        let op_started = Instant::now();
+                        match storage.write(lba, &data) {
+                            Ok(()) => {
+                                totals.write.record(op_started.elapsed());
+                                totals.write_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                            }
+                            Err(e) => record_error(&totals, "write", lba, &e),
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let elapsed = started.elapsed().as_secs_f64();
+    storage.flush().context("flush after run failed")?;
+
+    let op_report = |histogram: &LatencyHistogram, bytes: &AtomicU64| {
+        let latency = histogram.stats();
+        OpReport {
+            iops: latency.count as f64 / elapsed,
+            mib_per_sec: bytes.load(Ordering::Relaxed) as f64 / elapsed / (1024.0 * 1024.0),
+            latency,
+        }
+    };
+    let report = Report {
+        job,
+        seconds: elapsed,
+        read: op_report(&totals.read, &totals.read_bytes),
+        write: op_report(&totals.write, &totals.write_bytes),
+    };
+    Ok((report, totals.errors.load(Ordering::Relaxed)))
+}
+
+fn record_error(totals: &Totals, op: &str, lba: u64, e: &aoe_server::StorageError) {
+    // Only the first few, so a broken backend doesn't flood the terminal
+    if totals.errors.fetch_add(1, Ordering::Relaxed) < 10 {
+        eprintln!("{} at LBA {} failed: {}", op, lba, e);
+    }
+}
+
+fn print_op(name: &str, op: &OpReport) {
+    if op.latency.count == 0 {
+        return;
+    }
+    println!(
+        "{:>5}: {:>10.0} IOPS {:>9.1} MiB/s  latency us: mean {} p50 {} p99 {} max {}",
+        name,
+        op.iops,
+        op.mib_per_sec,
+        op.latency.mean_us,
+        op.latency.p50_us,
+        op.latency.p99_us,
+        op.latency.max_us
+    );
+}
+
+/// Open the backend under test
+fn open_backend(args: &Args) -> Result<Box<dyn BlockStorage>> {
+    let size_bytes = args.size * 1024 * 1024;
+    let path = || {
+        args.path
+            .clone()
+            .with_context(|| format!("--path is required for the {:?} backend", args.backend))
+    };
+
+    Ok(match args.backend {
+        Backend::Memory => Box::new(MemBackend::new(size_bytes)),
+        Backend::File => {
+            let path = path()?;
+            let backend = FileBackend::open_or_create(&path, size_bytes)
+                .with_context(|| format!("failed to open {}", path.display()))?;
+            Box::new(backend)
+        }
+        Backend::Cas => {
+            let path = path()?;
+            let store = FileBlobStore::new(path.join("blobs"))
+                .with_context(|| format!("failed to create blob store in {}", path.display()))?;
+            let backend = CasBackend::new(
+                Box::new(store),
+                size_bytes / 512,
+                &path.join("snapshots.json"),
+            )
+            .context("failed to create CAS backend")?;
+            match args.cas_block_size {
+                Some(block_size) => Box::new(backend.with_block_size(block_size)?),
+                None => Box::new(backend),
+            }
+        }
+        Backend::CasClient => {
+            let config = CasBackendConfig {
+                cas_server_addr: args.cas_server.clone(),
+                device_size_bytes: size_bytes,
+                device_model: "cas-bench".to_string(),
+                index_path: path()?,
+                tls: None,
+            };
+            let backend = cas_client::CasBackend::new(config)
+                .with_context(|| format!("failed to connect to {}", args.cas_server))?;
+            Box::new(backend)
+        }
+    })
+}
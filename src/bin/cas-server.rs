@@ -1,50 +1,251 @@
 //! CAS server binary
 //!
-//! Standalone content-addressable storage service.
+//! Standalone content-addressable storage service. With `--blob-store` it
+//! serves a blob store to aoe-server targets whose `blob_store` is
+//! `remote`, instead of CAS storage to iSCSI targets.
 
+use axum::{extract::State, response::Json, routing::get, Router};
 use clap::Parser;
-use env_logger::Env;
+use std::path::PathBuf;
 use std::process;
-use aoe_server::cas::{CasServer, CasServerConfig};
+use std::sync::Arc;
+use std::time::Duration;
+use aoe_server::blob::{BlobStore, FileBlobStore, HashAlgorithm, ShardedBlobStore};
+use aoe_server::cas::server::DEFAULT_DELETE_GRACE;
+use aoe_server::cas::{
+    CasQuota, CasServer, CasServerConfig, CasServerLimits, StorageUsage, UsageHandle,
+};
+use aoe_server::iscsi::status;
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::net::Listener;
+use aoe_server::server::api::ApiResponse;
+use aoe_server::systemd;
+use aoe_server::tls::{self, TlsConfig, TlsServer};
 
 #[derive(Parser, Debug)]
 #[command(name = "cas-server")]
 #[command(about = "Content-Addressable Storage server", long_about = None)]
 struct Args {
-    /// Bind address (e.g., 127.0.0.1:3000)
+    /// Bind address (e.g., 127.0.0.1:3000 or unix:/run/voe/cas.sock)
     #[arg(short, long, default_value = "127.0.0.1:3000")]
     bind: String,
 
     /// Storage directory path
     #[arg(short, long, default_value = "/var/lib/cas")]
     storage: String,
+
+    /// Server certificate (PEM); turns on mutual TLS, so only clients
+    /// with a certificate signed by --tls-ca are served
+    #[arg(long, requires_all = ["tls_key", "tls_ca"])]
+    tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// CA certificates (PEM) client certificates must chain to
+    #[arg(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of a client certificate to accept; if given,
+    /// only pinned clients are served (repeatable)
+    #[arg(long = "tls-pin", requires = "tls_cert")]
+    tls_pins: Vec<String>,
+
+    /// Serve a blob store (for aoe-server remote blob stores)
+    #[arg(long)]
+    blob_store: bool,
+
+    /// Hash to key a new blob store by: blake3, xxh3 or sha256. An existing
+    /// store keeps the hash it was created with.
+    #[arg(long, requires = "blob_store")]
+    hash: Option<HashAlgorithm>,
+
+    /// More blob store directories, typically on other disks, to spread
+    /// blobs across along with the storage directory. Only append to these.
+    #[arg(long = "shard", requires = "blob_store")]
+    shards: Vec<String>,
+
+    /// Don't check blobs against their keys, for clients that encrypt
+    #[arg(long, requires = "blob_store")]
+    no_verify: bool,
+
+    /// Seconds to keep newly written blocks from being deleted, so GC
+    /// can't remove blocks not yet in an index (0 turns this off)
+    #[arg(long, default_value_t = DEFAULT_DELETE_GRACE.as_secs())]
+    delete_grace_secs: u64,
+
+    /// Clients served at once; more are refused until one disconnects
+    #[arg(long, default_value_t = CasServerLimits::default().max_connections)]
+    max_connections: usize,
+
+    /// Largest request accepted, in MiB
+    #[arg(long, default_value_t = 16)]
+    max_request_mb: usize,
+
+    /// Close connections idle this many seconds (0 keeps them open)
+    #[arg(long, default_value_t = 600)]
+    idle_timeout_secs: u64,
+
+    /// Most CAS storage to use, in GiB; writes past it are refused
+    #[arg(long, conflicts_with = "blob_store")]
+    max_storage_gb: Option<u64>,
+
+    /// Serve storage usage over HTTP on this address (GET /stats)
+    #[arg(long, conflicts_with = "blob_store")]
+    status: Option<String>,
+
+    /// Log format: text or json
+    #[arg(long, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 }
 
 fn main() {
-    // Initialize logging
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
     let args = Args::parse();
+    logging::init("info", args.log_format);
 
+    let config_bind = args.bind.clone();
     let config = CasServerConfig {
-        bind_addr: args.bind,
-        storage_path: args.storage,
+        bind_addr: args.bind.clone(),
+        storage_path: args.storage.clone(),
     };
 
     log::info!("Starting CAS server");
     log::info!("  Bind address: {}", config.bind_addr);
     log::info!("  Storage path: {}", config.storage_path);
 
-    let server = match CasServer::new(config) {
-        Ok(server) => server,
+    let server = if args.blob_store {
+        open_blob_store(
+            &config.storage_path,
+            &args.shards,
+            args.hash,
+            !args.no_verify,
+        )
+        .map(|store| CasServer::for_blob_store(config, Arc::from(store)))
+    } else {
+        let grace = Duration::from_secs(args.delete_grace_secs);
+        let quota = args.max_storage_gb.map(|gb| CasQuota {
+            max_bytes: gb * 1024 * 1024 * 1024,
+        });
+        if let Some(quota) = &quota {
+            log::info!("  Storage quota: {} GiB", quota.max_bytes >> 30);
+        }
+        CasServer::new(config).and_then(|server| {
+            let server = server.with_delete_grace(grace);
+            match quota {
+                Some(quota) => server.with_quota(quota),
+                None if args.status.is_some() => server.track_usage(),
+                None => Ok(server),
+            }
+        })
+    };
+    let limits = CasServerLimits {
+        max_connections: args.max_connections,
+        max_request_size: args.max_request_mb * 1024 * 1024,
+        idle_timeout: (args.idle_timeout_secs > 0)
+            .then(|| Duration::from_secs(args.idle_timeout_secs)),
+    };
+    let mut server = match server {
+        Ok(server) => server.with_limits(limits),
         Err(e) => {
             log::error!("Failed to create server: {}", e);
             process::exit(1);
         }
     };
+    if let Some(tls) = tls_server(&args) {
+        server = server.with_tls(Arc::new(tls));
+    }
+
+    if let (Some(bind), Some(usage)) = (&args.status, server.usage_handle()) {
+        let router = Router::new().route("/stats", get(usage_stats)).with_state(usage);
+        match status::serve(bind, "cas-status", router) {
+            Ok((addr, _)) => log::info!("  Status: http://{}/stats", addr),
+            Err(e) => {
+                log::error!("Failed to bind status server {}: {}", bind, e);
+                process::exit(1);
+            }
+        }
+    }
 
-    if let Err(e) = server.run() {
+    let listener = match bind_listener(&config_bind) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to bind {}: {}", config_bind, e);
+            process::exit(1);
+        }
+    };
+    systemd::notify_ready();
+
+    if let Err(e) = server.serve(listener) {
         log::error!("Server error: {}", e);
         process::exit(1);
     }
 }
+
+async fn usage_stats(State(usage): State<UsageHandle>) -> Json<ApiResponse<StorageUsage>> {
+    ApiResponse::success(usage.usage())
+}
+
+/// Mutual TLS settings, if --tls-cert is given. Certificates are reloaded
+/// on SIGHUP; exits if they can't be loaded.
+fn tls_server(args: &Args) -> Option<TlsServer> {
+    let config = TlsConfig {
+        cert: args.tls_cert.clone()?,
+        key: args.tls_key.clone().unwrap_or_default(),
+        ca: args.tls_ca.clone().unwrap_or_default(),
+        pins: args.tls_pins.clone(),
+        server_name: None,
+    };
+    let server = tls::install_reload_signal()
+        .and_then(|()| tls::certificate_fingerprint(&config.cert))
+        .and_then(|fingerprint| {
+            log::info!("  TLS certificate: {} (SHA-256 {})", config.cert.display(), fingerprint);
+            TlsServer::new(config)
+        });
+    match server {
+        Ok(server) => Some(server),
+        Err(e) => {
+            log::error!("Failed to load TLS certificates: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Use the systemd-activated socket if there is one, else bind `addr`
+fn bind_listener(addr: &str) -> std::io::Result<Listener> {
+    match systemd::take_listener()? {
+        Some(listener) => {
+            log::info!("Using socket-activated listener");
+            Ok(listener)
+        }
+        None => Listener::bind(addr),
+    }
+}
+
+/// Open the blob store served with `--blob-store`
+fn open_blob_store(
+    path: &str,
+    shards: &[String],
+    mut hash: Option<HashAlgorithm>,
+    verify: bool,
+) -> std::io::Result<Box<dyn BlobStore>> {
+    let mut stores = Vec::new();
+    // Shards take the hash the first was created with, unless told
+    for path in std::iter::once(path).chain(shards.iter().map(String::as_str)) {
+        let store = if verify {
+            FileBlobStore::new(path)
+        } else {
+            FileBlobStore::unverified(path)
+        }
+        .map_err(std::io::Error::other)?;
+        let algorithm = *hash.get_or_insert(store.hash_algorithm());
+        stores.push(store.with_hash_algorithm(algorithm).map_err(std::io::Error::other)?);
+    }
+    log::info!(
+        "  Serving a {} blob store across {} directories{}",
+        stores[0].hash_algorithm(),
+        stores.len(),
+        if verify { "" } else { ", unverified" }
+    );
+    Ok(ShardedBlobStore::boxed(stores))
+}
@@ -6,15 +6,24 @@
 //! - list: List all targets
 //! - info: Show target details
 //! - delete: Delete a target
+//! - flatten: Copy inherited index layers into a clone
 //! - gc: Garbage collect CAS blocks (Phase 3)
+//! - history: Show the audit log entries that led to a target
+//! - gc-daemon: Periodically garbage collect CAS blocks across all targets
 
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use env_logger::Env;
-use std::collections::HashSet;
+use serde_json::json;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use aoe_server::iscsi::{CloneManager, TargetRegistry};
+use aoe_server::iscsi::gc::{self, GcDaemonStats};
+use aoe_server::iscsi::index::LbaIndex;
+use aoe_server::iscsi::{AuditLog, AuditOperation, CloneManager, GcReport, TargetRegistry};
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::shutdown;
+use aoe_server::tls::{self, TlsClient, TlsConfig};
 
 #[derive(Parser)]
 #[command(name = "iscsi-clone")]
@@ -32,6 +41,23 @@ struct Cli {
     #[arg(long, default_value = "127.0.0.1:3000")]
     cas_server: String,
 
+    /// Certificate (PEM) to present to the CAS server; turns on mutual TLS
+    #[arg(long, requires_all = ["cas_tls_key", "cas_tls_ca"])]
+    cas_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --cas-tls-cert
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_key: Option<PathBuf>,
+
+    /// CA certificates (PEM) the CAS server's certificate must chain to
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint the CAS server's certificate must match
+    /// (repeatable)
+    #[arg(long = "cas-tls-pin", requires = "cas_tls_cert")]
+    cas_tls_pins: Vec<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -92,6 +118,12 @@ enum Commands {
         yes: bool,
     },
 
+    /// Copy a clone's inherited index entries into its own index
+    Flatten {
+        /// Target IQN or name
+        target: String,
+    },
+
     /// Garbage collect CAS blocks (Phase 3)
     Gc {
         /// Target IQN or name
@@ -101,10 +133,36 @@ enum Commands {
         #[arg(short = 'n', long)]
         dry_run: bool,
     },
+
+    /// Show the operations on a target and the targets it was cloned from
+    History {
+        /// Target IQN or name (an IQN also works for deleted targets)
+        target: String,
+    },
+
+    /// Garbage collect CAS blocks across all targets every interval,
+    /// including running ones
+    GcDaemon {
+        /// Time between rounds, e.g. 30m, 6h or 1d
+        #[arg(long, default_value = "6h", value_parser = parse_interval)]
+        interval: Duration,
+
+        /// Find candidates each round without deleting them
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Serve GC metrics over HTTP on this address (GET /stats)
+        #[arg(long)]
+        status: Option<String>,
+
+        /// Blocks deleted between checks for blocks being written
+        #[arg(long, default_value_t = gc::DEFAULT_BATCH_SIZE)]
+        batch: usize,
+    },
 }
 
 fn main() -> Result<()> {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    logging::init("info", LogFormat::Text);
 
     let cli = Cli::parse();
 
@@ -124,14 +182,41 @@ fn main() -> Result<()> {
         Commands::Delete { target, purge, yes } => {
             cmd_delete(&cli, target, *purge, *yes)
         }
+        Commands::Flatten { target } => {
+            cmd_flatten(&cli, target)
+        }
         Commands::Gc { target, dry_run } => {
             cmd_gc(&cli, target, *dry_run)
         }
+        Commands::History { target } => {
+            cmd_history(&cli, target)
+        }
+        Commands::GcDaemon { interval, dry_run, status, batch } => {
+            cmd_gc_daemon(&cli, *interval, *dry_run, status.as_deref(), *batch)
+        }
     }
 }
 
+/// Open the registry, reaching the CAS server over TLS if configured
+fn open_manager(cli: &Cli) -> Result<CloneManager> {
+    let manager =
+        CloneManager::new(cli.registry.clone(), cli.targets_dir.clone(), cli.cas_server.clone())?;
+    let Some(cert) = &cli.cas_tls_cert else {
+        return Ok(manager);
+    };
+    let tls = TlsClient::new(TlsConfig {
+        cert: cert.clone(),
+        key: cli.cas_tls_key.clone().unwrap_or_default(),
+        ca: cli.cas_tls_ca.clone().unwrap_or_default(),
+        pins: cli.cas_tls_pins.clone(),
+        server_name: None,
+    })
+    .context("Failed to load CAS TLS certificates")?;
+    Ok(manager.with_cas_tls(Arc::new(tls)))
+}
+
 fn cmd_create(cli: &Cli, name: &str, size_mb: u64, description: Option<String>) -> Result<()> {
-    let mut manager = CloneManager::new(cli.registry.clone(), cli.targets_dir.clone(), cli.cas_server.clone())?;
+    let mut manager = open_manager(cli)?;
 
     println!("Creating target: {} ({} MB)", name, size_mb);
 
@@ -146,7 +231,7 @@ fn cmd_create(cli: &Cli, name: &str, size_mb: u64, description: Option<String>)
 }
 
 fn cmd_clone(cli: &Cli, source: &str, dest: &str) -> Result<()> {
-    let mut manager = CloneManager::new(cli.registry.clone(), cli.targets_dir.clone(), cli.cas_server.clone())?;
+    let mut manager = open_manager(cli)?;
 
     // Resolve source IQN
     let source_iqn = resolve_target_iqn(&manager.registry, source)?;
@@ -210,7 +295,7 @@ fn cmd_list(cli: &Cli, tree: bool) -> Result<()> {
 }
 
 fn cmd_info(cli: &Cli, target: &str, stats: bool) -> Result<()> {
-    let manager = CloneManager::new(cli.registry.clone(), cli.targets_dir.clone(), cli.cas_server.clone())?;
+    let manager = open_manager(cli)?;
 
     let iqn = resolve_target_iqn(&manager.registry, target)?;
     let metadata = manager.registry.get_target(&iqn)
@@ -247,9 +332,13 @@ fn cmd_info(cli: &Cli, target: &str, stats: bool) -> Result<()> {
 
         // Count blocks in index
         if metadata.index_path.exists() {
-            match sled::open(&metadata.index_path) {
-                Ok(db) => {
-                    println!("  Index entries: {}", db.len());
+            match LbaIndex::open(&metadata.index_path) {
+                Ok(Some(index)) => {
+                    println!("  Index entries: {}", index.own_entries());
+                    println!("  Shared layers: {}", index.depth());
+                }
+                Ok(None) => {
+                    println!("  Index entries: 0");
                 }
                 Err(e) => {
                     println!("  Index entries: Error - {}", e);
@@ -264,7 +353,7 @@ fn cmd_info(cli: &Cli, target: &str, stats: bool) -> Result<()> {
 }
 
 fn cmd_delete(cli: &Cli, target: &str, purge: bool, yes: bool) -> Result<()> {
-    let mut manager = CloneManager::new(cli.registry.clone(), cli.targets_dir.clone(), cli.cas_server.clone())?;
+    let mut manager = open_manager(cli)?;
 
     let iqn = resolve_target_iqn(&manager.registry, target)?;
     let metadata = manager.registry.get_target(&iqn)
@@ -308,67 +397,51 @@ fn cmd_delete(cli: &Cli, target: &str, purge: bool, yes: bool) -> Result<()> {
     Ok(())
 }
 
-fn cmd_gc(cli: &Cli, target: &str, dry_run: bool) -> Result<()> {
-    use std::net::TcpStream;
-    use std::io::{BufReader, BufWriter};
-    use aoe_server::cas::protocol::{write_frame, read_frame, CasCommand};
-    use aoe_server::cas::Hash;
-
-    let manager = CloneManager::new(cli.registry.clone(), cli.targets_dir.clone(), cli.cas_server.clone())?;
+fn cmd_flatten(cli: &Cli, target: &str) -> Result<()> {
+    let manager = open_manager(cli)?;
 
-    // Resolve target IQN
-    let target_iqn = resolve_target_iqn(&manager.registry, target)?;
-    let target_metadata = manager.registry.get_target(&target_iqn)
-        .ok_or_else(|| anyhow::anyhow!("Target not found: {}", target_iqn))?;
-
-    println!("Garbage collecting CAS blocks for target: {}", target_metadata.name);
-    println!("Target IQN: {}", target_iqn);
+    let iqn = resolve_target_iqn(&manager.registry, target)?;
+    println!("Flattening target: {}", iqn);
 
-    // Check if target is running
-    if manager.is_target_running(&target_iqn)? {
-        anyhow::bail!("Target is currently running: {}. Stop it first.", target_iqn);
-    }
+    let copied = manager.flatten_target(&iqn)?;
 
-    // Step 1: Collect all hashes from the target
-    println!("\nStep 1: Collecting hashes from target...");
-    let target_hashes = collect_target_hashes(&target_metadata.index_path)?;
-    println!("  Found {} unique hashes in target", target_hashes.len());
+    println!("✓ Flattened target: copied {} inherited entries", copied);
 
-    // Step 2: Collect all hashes from all OTHER targets
-    println!("\nStep 2: Collecting hashes from all other targets...");
-    let mut other_hashes = HashSet::new();
-    let mut other_count = 0;
+    Ok(())
+}
 
-    for (other_iqn, other_metadata) in &manager.registry.targets {
-        if other_iqn == &target_iqn {
-            continue; // Skip the target we're GC'ing
-        }
+fn cmd_gc(cli: &Cli, target: &str, dry_run: bool) -> Result<()> {
+    let manager = open_manager(cli)?;
 
-        if !other_metadata.index_path.exists() {
-            println!("  Skipping {} (index not created yet)", other_metadata.name);
-            continue;
-        }
+    // Resolve target IQN
+    let target_iqn = resolve_target_iqn(&manager.registry, target)?;
+    let result = run_gc(cli, &manager, &target_iqn, dry_run);
+    let params = json!({"dry_run": dry_run, "report": result.as_ref().ok()});
+    manager.audit(AuditOperation::Gc, &target_iqn, params, &result);
+    result.map(|_| ())
+}
 
-        println!("  Scanning {}...", other_metadata.name);
-        let hashes = collect_target_hashes(&other_metadata.index_path)?;
-        other_hashes.extend(hashes);
-        other_count += 1;
-    }
+fn run_gc(
+    cli: &Cli,
+    manager: &CloneManager,
+    target_iqn: &str,
+    dry_run: bool,
+) -> Result<Option<GcReport>> {
+    let target_metadata = manager.registry.get_target(target_iqn)
+        .ok_or_else(|| anyhow::anyhow!("Target not found: {}", target_iqn))?;
 
-    println!("  Found {} unique hashes across {} other targets", other_hashes.len(), other_count);
+    println!("Garbage collecting CAS blocks for target: {}", target_metadata.name);
+    println!("Target IQN: {}", target_iqn);
 
-    // Step 3: Compute difference (blocks unique to this target)
-    println!("\nStep 3: Finding blocks unique to target...");
-    let unique_hashes: Vec<Hash> = target_hashes
-        .difference(&other_hashes)
-        .cloned()
-        .collect();
+    // Blocks no other target or snapshot references
+    println!("\nFinding blocks unique to target...");
+    let unique_hashes = manager.gc_candidates(target_iqn)?;
 
     println!("  Found {} blocks unique to target", unique_hashes.len());
 
     if unique_hashes.is_empty() {
         println!("\nNo unique blocks to delete. All blocks are shared with other targets.");
-        return Ok(());
+        return Ok(Some(GcReport::default()));
     }
 
     // Calculate approximate space (assuming 4KB blocks)
@@ -377,101 +450,141 @@ fn cmd_gc(cli: &Cli, target: &str, dry_run: bool) -> Result<()> {
 
     if dry_run {
         println!("\n✓ Dry run complete. Use without --dry-run to actually delete blocks.");
-        return Ok(());
+        return Ok(None);
     }
 
-    // Step 4: Delete unique blocks from CAS
-    println!("\nStep 4: Deleting unique blocks from CAS...");
-    println!("  Connecting to CAS server at {}", cli.cas_server);
+    println!("\nDeleting unique blocks from CAS at {}...", cli.cas_server);
+    let report = manager.delete_blocks(&unique_hashes, |done| {
+        if done % 100 == 0 {
+            println!("  Progress: {}/{} blocks...", done, unique_hashes.len());
+        }
+    })?;
 
-    let stream = TcpStream::connect(&cli.cas_server)
-        .with_context(|| format!("Failed to connect to CAS server: {}", cli.cas_server))?;
+    println!("\n✓ Garbage collection complete:");
+    println!("  Deleted: {} blocks", report.deleted);
+    println!("  Not found: {} blocks", report.not_found);
+    if report.kept > 0 {
+        println!("  Kept: {} blocks written too recently (run gc again later)", report.kept);
+    }
+    println!("  Errors: {} blocks", report.errors);
+    println!("  Approximate space reclaimed: {} MB", (report.deleted * 4096) / (1024 * 1024));
 
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut writer = BufWriter::new(stream);
+    Ok(Some(report))
+}
 
-    let mut deleted_count = 0;
-    let mut not_found_count = 0;
-    let mut error_count = 0;
+fn cmd_gc_daemon(
+    cli: &Cli,
+    interval: Duration,
+    dry_run: bool,
+    status: Option<&str>,
+    batch: usize,
+) -> Result<()> {
+    shutdown::install()?;
+    if cli.cas_tls_cert.is_some() {
+        // Certificates are read afresh each round; SIGHUP mustn't kill us
+        tls::install_reload_signal()?;
+    }
 
-    for (i, hash) in unique_hashes.iter().enumerate() {
-        if i % 100 == 0 {
-            println!("  Progress: {}/{} blocks...", i, unique_hashes.len());
+    let stats = Arc::new(Mutex::new(GcDaemonStats::default()));
+    if let Some(bind) = status {
+        let (addr, _) = gc::spawn_status(bind, stats.clone())?;
+        println!("Serving GC metrics on http://{}/stats", addr);
+    }
+    println!("Running GC every {:?}{}", interval, if dry_run { " (dry run)" } else { "" });
+
+    while !shutdown::requested() {
+        // Reloaded each round, for targets created or deleted since
+        let manager = open_manager(cli)?;
+        let result = gc::run_round(&manager, dry_run, batch);
+        let params = json!({"dry_run": dry_run, "report": result.as_ref().ok()});
+        manager.audit(AuditOperation::Gc, "*", params, &result);
+
+        match &result {
+            Ok(report) => println!(
+                "GC round: {} live, {} candidates, {} deleted, {} spared, {} kept, \
+                 {} errors, ~{} MB reclaimed",
+                report.live,
+                report.candidates,
+                report.deleted,
+                report.spared,
+                report.kept,
+                report.errors,
+                report.bytes_reclaimed / (1024 * 1024)
+            ),
+            Err(e) => log::error!("GC round failed: {:#}", e),
         }
-
-        // Send DELETE command
-        if let Err(e) = write_frame(&mut writer, CasCommand::Delete, hash) {
-            log::warn!("Failed to send DELETE for hash {}: {}", hex::encode(hash), e);
-            error_count += 1;
-            continue;
+        {
+            let mut stats = stats.lock().unwrap();
+            stats.record(&result);
+            stats.next_round_at = Some(TargetRegistry::now() + interval.as_secs());
         }
 
-        // Read response
-        match read_frame(&mut reader) {
-            Ok((CasCommand::Delete, response_data)) => {
-                if response_data.len() == 1 {
-                    let deleted = response_data[0] != 0;
-                    if deleted {
-                        deleted_count += 1;
-                    } else {
-                        not_found_count += 1;
-                    }
-                } else {
-                    log::warn!("Invalid DELETE response for hash {}", hex::encode(hash));
-                    error_count += 1;
-                }
-            }
-            Ok((cmd, _)) => {
-                log::warn!("Unexpected response command: {:?}", cmd);
-                error_count += 1;
-            }
-            Err(e) => {
-                log::warn!("Failed to read DELETE response for hash {}: {}", hex::encode(hash), e);
-                error_count += 1;
-            }
+        let deadline = Instant::now() + interval;
+        while !shutdown::requested() && Instant::now() < deadline {
+            std::thread::sleep(shutdown::POLL_INTERVAL);
         }
     }
 
-    println!("\n✓ Garbage collection complete:");
-    println!("  Deleted: {} blocks", deleted_count);
-    println!("  Not found: {} blocks", not_found_count);
-    println!("  Errors: {} blocks", error_count);
-    println!("  Approximate space reclaimed: {} MB", (deleted_count * 4096) / (1024 * 1024));
-
+    println!("GC daemon stopped");
     Ok(())
 }
 
-/// Collect all unique hashes from a target's sled database
-fn collect_target_hashes(index_path: &std::path::Path) -> Result<HashSet<aoe_server::cas::Hash>> {
-    if !index_path.exists() {
-        anyhow::bail!("Index path does not exist: {:?}", index_path);
+/// Parse an interval such as "90s", "30m", "6h" or "1d" (seconds if bare)
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let seconds = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(format!("unknown interval unit {:?} (use s, m, h or d)", unit)),
+    };
+    let number: u64 = number.parse().map_err(|_| format!("invalid interval: {}", s))?;
+    if number == 0 {
+        return Err("interval must be positive".to_string());
     }
+    Ok(Duration::from_secs(number * seconds))
+}
 
-    let db = sled::open(index_path)
-        .with_context(|| format!("Failed to open database: {:?}", index_path))?;
-
-    let mut hashes = HashSet::new();
-    let zero_block_key = b"__ZERO_BLOCK__";
+fn cmd_history(cli: &Cli, target: &str) -> Result<()> {
+    let registry = TargetRegistry::load_or_create(&cli.registry)?;
 
-    for entry_result in db.iter() {
-        let (key, value) = entry_result.context("Failed to read entry from database")?;
+    // Deleted targets are only in the audit log
+    let iqn = match resolve_target_iqn(&registry, target) {
+        Ok(iqn) => iqn,
+        Err(_) if target.starts_with("iqn.") => target.to_string(),
+        Err(e) => return Err(e),
+    };
 
-        // Skip the zero block hash key (it's a special metadata key)
-        if key.as_ref() == zero_block_key {
-            continue;
-        }
+    let history = AuditLog::for_registry(&cli.registry).history(&iqn)?;
+    if history.is_empty() {
+        println!("No recorded operations for {}.", iqn);
+        return Ok(());
+    }
 
-        // Each value is a 16-byte hash
-        if value.len() == 16 {
-            let mut hash = [0u8; 16];
-            hash.copy_from_slice(&value);
-            hashes.insert(hash);
-        } else {
-            log::warn!("Skipping entry with invalid hash size: {} bytes", value.len());
+    println!("History of {}:\n", iqn);
+    for entry in history {
+        let outcome = match &entry.error {
+            None => "ok".to_string(),
+            Some(error) => format!("FAILED: {}", error),
+        };
+        println!(
+            "{}  {:<8} {}  by {}  {}",
+            format_timestamp(entry.timestamp),
+            entry.operation,
+            entry.target,
+            entry.actor,
+            outcome
+        );
+        if entry.params.as_object().is_some_and(|params| !params.is_empty()) {
+            println!("    {}", entry.params);
         }
     }
 
-    Ok(hashes)
+    Ok(())
 }
 
 /// Resolve a target name or IQN to a full IQN
@@ -497,6 +610,6 @@ fn resolve_target_iqn(registry: &TargetRegistry, target: &str) -> Result<String>
 /// Format Unix timestamp as human-readable date
 fn format_timestamp(timestamp: u64) -> String {
     let datetime = chrono::DateTime::from_timestamp(timestamp as i64, 0)
-        .unwrap_or_else(|| chrono::DateTime::UNIX_EPOCH);
+        .unwrap_or(chrono::DateTime::UNIX_EPOCH);
     datetime.format("%Y-%m-%d %H:%M:%S UTC").to_string()
 }
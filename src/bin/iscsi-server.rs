@@ -5,15 +5,34 @@
 //! Supports two modes:
 //! 1. Single-target mode (CLI args) - backwards compatible
 //! 2. Multi-target mode (TOML config) - new feature
+//!
+//! On SIGINT/SIGTERM new logins are rejected, existing sessions get
+//! DRAIN_TIMEOUT to log out, and every device's write cache is flushed.
 
 use clap::Parser;
-use env_logger::Env;
 use serde::Deserialize;
 use std::fs;
 use std::path::PathBuf;
 use std::process;
-
-use aoe_server::iscsi::{CasScsiDevice, CasScsiDeviceConfig};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aoe_server::cas::BlockCache;
+use aoe_server::ha::{HaConfig, HaNode};
+use aoe_server::iscsi::audit::{self, AuditLog, AuditOperation};
+use aoe_server::iscsi::discovery;
+use aoe_server::iscsi::isns::{IsnsClient, IsnsRegistration, IsnsTarget};
+use aoe_server::iscsi::live::{self, ServingLock};
+use aoe_server::iscsi::status;
+use aoe_server::iscsi::{
+    CasScsiDevice, CasScsiDeviceConfig, CasScsiFlushHandle, TracedScsiDevice,
+};
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::tls::{self, TlsClient, TlsConfig};
+use aoe_server::{shutdown, systemd};
+use aoe_server::qos::QosLimits;
+use aoe_server::storage::TargetUuid;
 use iscsi_target::{IscsiTarget, IscsiServer};
 
 #[derive(Parser, Debug)]
@@ -32,6 +51,25 @@ struct Args {
     #[arg(long, default_value = "127.0.0.1:3000")]
     cas_server: String,
 
+    /// Certificate (PEM) to present to the CAS server; turns on mutual TLS
+    /// [single-target mode]
+    #[arg(long, requires_all = ["cas_tls_key", "cas_tls_ca"])]
+    cas_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --cas-tls-cert [single-target mode]
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_key: Option<PathBuf>,
+
+    /// CA certificates (PEM) the CAS server's certificate must chain to
+    /// [single-target mode]
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint the CAS server's certificate must match
+    /// (repeatable) [single-target mode]
+    #[arg(long = "cas-tls-pin", requires = "cas_tls_cert")]
+    cas_tls_pins: Vec<String>,
+
     /// Device size in MB [single-target mode]
     #[arg(short, long, default_value = "100")]
     size: u64,
@@ -43,6 +81,43 @@ struct Args {
     /// iSCSI target name (IQN) [single-target mode]
     #[arg(short, long, default_value = "iqn.2025-12.local.voe:storage.cas-disk")]
     target: String,
+
+    /// Local read cache size in MB, 0 to disable [single-target mode]
+    #[arg(long, default_value = "64")]
+    read_cache_mb: usize,
+
+    /// Blocks to prefetch into the read cache ahead of sequential reads,
+    /// 0 to disable [single-target mode]
+    #[arg(long, default_value = "0")]
+    readahead_blocks: usize,
+
+    /// iSNS server to register with (host or host:port)
+    #[arg(long)]
+    isns_server: Option<String>,
+
+    /// Serve the target's runtime statistics over HTTP on this address
+    /// (e.g. 127.0.0.1:3261) [single-target mode]
+    #[arg(long)]
+    status: Option<String>,
+
+    /// Target registry whose audit log records the target starting and
+    /// stopping [single-target mode]
+    #[arg(long)]
+    registry: Option<PathBuf>,
+
+    /// Identity to give the target, as for an AoE target's `uuid`; kept
+    /// beside the index [single-target mode]
+    #[arg(long)]
+    uuid: Option<TargetUuid>,
+
+    /// Acknowledge writes from the write cache without journaling them,
+    /// reported as WCE in the Caching mode page [single-target mode]
+    #[arg(long)]
+    write_cache: bool,
+
+    /// Log format: text or json
+    #[arg(long, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
 }
 
 /// TOML configuration for multi-target server
@@ -50,12 +125,46 @@ struct Args {
 struct Config {
     server: ServerConfig,
     targets: Vec<TargetConfig>,
+    /// Active/passive failover with another server
+    #[serde(default)]
+    ha: Option<HaConfig>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ServerConfig {
     bind: String,
     cas_server: String,
+    /// Mutual TLS to the CAS server
+    #[serde(default)]
+    cas_tls: Option<TlsConfig>,
+    /// Read cache in MB, shared by all targets (0 disables it)
+    #[serde(default = "default_read_cache_mb")]
+    read_cache_mb: usize,
+    /// Blocks to prefetch into the read cache ahead of sequential reads
+    #[serde(default)]
+    readahead_blocks: usize,
+    /// Address to answer SendTargets discovery sessions on (e.g.
+    /// 0.0.0.0:3262), listing the targets at the `bind` portal
+    #[serde(default)]
+    discovery: Option<String>,
+    /// iSNS server to register the targets with (host or host:port)
+    #[serde(default)]
+    isns_server: Option<String>,
+    /// iSNS entity identifier, the host name by default
+    #[serde(default)]
+    isns_entity: Option<String>,
+    /// Address to serve per-target runtime statistics on over HTTP, for
+    /// `iscsi-web --status-url` (e.g. 127.0.0.1:3261)
+    #[serde(default)]
+    status: Option<String>,
+    /// Target registry (as used by `iscsi-clone` and `iscsi-web`) whose
+    /// audit log records the targets starting and stopping
+    #[serde(default)]
+    registry: Option<PathBuf>,
+}
+
+fn default_read_cache_mb() -> usize {
+    64
 }
 
 #[derive(Debug, Deserialize)]
@@ -65,12 +174,45 @@ struct TargetConfig {
     index_path: PathBuf,
     #[serde(default)]
     alias: Option<String>,
+    /// Read bandwidth limit in MiB/s
+    #[serde(default)]
+    max_read_mbps: Option<u32>,
+    /// Write bandwidth limit in MiB/s
+    #[serde(default)]
+    max_write_mbps: Option<u32>,
+    /// Operations per second limit (reads and writes)
+    #[serde(default)]
+    max_iops: Option<u32>,
+    /// Write MiB the device buffers before it drains to CAS; writes past
+    /// it are refused with TASK SET FULL until the drain finishes
+    #[serde(default)]
+    max_inflight_write_mb: Option<u32>,
+    /// Identity to give the target, kept beside its index; an index that
+    /// already has another is refused
+    #[serde(default)]
+    uuid: Option<TargetUuid>,
+    /// Acknowledge writes from the write cache without journaling them
+    /// (the Caching mode page's WCE); only a flush makes them stable
+    #[serde(default)]
+    write_cache: bool,
 }
 
-fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+/// How long sessions get to log out after a shutdown signal
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
 
+fn main() {
     let args = Args::parse();
+    logging::init("info", args.log_format);
+
+    if let Err(e) = shutdown::install() {
+        log::error!("Failed to install signal handlers: {}", e);
+        process::exit(1);
+    }
+
+    // The iscsi-target crate binds its own listener
+    if systemd::socket_activated() {
+        log::warn!("Socket activation is not supported by iscsi-server; binding the configured address");
+    }
 
     if let Some(config_path) = args.config {
         run_multi_target(config_path);
@@ -104,17 +246,29 @@ fn run_multi_target(config_path: PathBuf) {
     log::info!("  Bind address: {}", config.server.bind);
     log::info!("  CAS server: {}", config.server.cas_server);
     log::info!("  Targets: {}", config.targets.len());
+    let cas_tls = cas_tls_client(config.server.cas_tls.clone());
 
     if config.targets.is_empty() {
         log::error!("No targets defined in configuration");
         process::exit(1);
     }
 
+    // A passive node opens no indexes until it takes over
+    let _ha = config.ha.as_ref().map(wait_until_active);
+
     // Create multi-target server
     let mut server_builder = IscsiServer::builder()
         .bind_addr(&config.server.bind);
 
+    // Clones of one base image share most blocks, so share one cache
+    let read_cache = (config.server.read_cache_mb > 0)
+        .then(|| Arc::new(BlockCache::new(config.server.read_cache_mb)));
+
     // Add each target
+    let mut flush_handles = Vec::new();
+    let mut live_targets = Vec::new();
+    let mut status_targets = Vec::new();
+    let mut isns_targets = Vec::new();
     for target_config in &config.targets {
         log::info!("  - {} ({} MB)", target_config.name, target_config.size_mb);
 
@@ -127,6 +281,10 @@ fn run_multi_target(config_path: PathBuf) {
             vendor_id: "VoE     ".to_string(),
             product_id: format!("CAS Disk {:>6}MB", target_config.size_mb),
             product_rev: "1.0 ".to_string(),
+            read_cache_mb: 0,
+            journal: true,
+            cas_tls: cas_tls.clone(),
+            uuid: target_config.uuid,
         };
 
         let device = match CasScsiDevice::new(device_config) {
@@ -136,30 +294,88 @@ fn run_multi_target(config_path: PathBuf) {
                 process::exit(1);
             }
         };
+        if let Err(e) = device.set_write_cache_enabled(target_config.write_cache) {
+            log::error!("Failed to set {}'s write cache: {}", target_config.name, e);
+            process::exit(1);
+        }
+
+        let qos = QosLimits {
+            max_read_mbps: target_config.max_read_mbps,
+            max_write_mbps: target_config.max_write_mbps,
+            max_iops: target_config.max_iops,
+            max_inflight_write_mb: target_config.max_inflight_write_mb,
+        };
+        if !qos.is_unlimited() {
+            log::info!("    QoS limits: {:?}", qos);
+        }
+        let mut device = device.with_qos(&qos);
+        if let Some(cache) = &read_cache {
+            device = device.with_read_cache(Arc::clone(cache));
+        }
+        let device = device.with_readahead(config.server.readahead_blocks);
+        flush_handles.push(device.flush_handle());
+        live_targets.push((target_config.index_path.clone(), device.flush_handle()));
+        status_targets.push((target_config.name.clone(), device.flush_handle()));
 
         let alias = target_config.alias.clone();
+        isns_targets.push(IsnsTarget {
+            iqn: target_config.name.clone(),
+            alias: alias.clone(),
+        });
 
         server_builder = server_builder.add_target(
             target_config.name.clone(),
-            Box::new(device),
+            Box::new(TracedScsiDevice::new(target_config.name.as_str(), Box::new(device))),
             alias,
         );
     }
 
     let server = match server_builder.build() {
-        Ok(s) => s,
+        Ok(s) => Arc::new(s),
         Err(e) => {
             log::error!("Failed to create multi-target server: {}", e);
             process::exit(1);
         }
     };
 
+    let (begin, sessions, stop) = (Arc::clone(&server), Arc::clone(&server), Arc::clone(&server));
+    spawn_shutdown_watcher(
+        move || begin.shutdown_gracefully(),
+        move || sessions.active_session_count(),
+        move || stop.stop(),
+    );
+
+    let _locks = serve_live_snapshots(live_targets);
+    let iqns: Vec<String> = status_targets.iter().map(|(iqn, _)| iqn.clone()).collect();
+    if let Some(bind) = &config.server.status {
+        serve_status(bind, status_targets);
+    }
+    let audit_log = config.server.registry.as_deref().map(AuditLog::for_registry);
+    audit_serving(audit_log.as_ref(), AuditOperation::Start, &iqns, &Ok(()));
+
+    if let Some(bind) = &config.server.discovery {
+        serve_discovery(bind, &config.server.bind, iqns.clone());
+    }
+
     log::info!("Multi-target iSCSI server ready, waiting for connections...");
+    systemd::notify_ready();
+    let isns = config.server.isns_server.as_deref().and_then(|isns_server| {
+        start_isns(
+            isns_server,
+            config.server.isns_entity.as_deref(),
+            &config.server.bind,
+            isns_targets,
+        )
+    });
 
     if let Err(e) = server.run() {
         log::error!("Server error: {}", e);
+        audit_serving(audit_log.as_ref(), AuditOperation::Stop, &iqns, &Err(e.to_string()));
         process::exit(1);
     }
+    drop(isns);
+    let flushed = flush_devices(&flush_handles);
+    audit_serving(audit_log.as_ref(), AuditOperation::Stop, &iqns, &flushed);
 }
 
 /// Run in single-target mode (backwards compatible with original CLI)
@@ -174,45 +390,258 @@ fn run_single_target(args: Args) {
     // Calculate capacity in blocks (4KB each to match CAS device block size)
     let capacity_blocks = (args.size * 1024 * 1024) / 4096;
 
+    let cas_tls = args.cas_tls_cert.clone().map(|cert| TlsConfig {
+        cert,
+        key: args.cas_tls_key.clone().unwrap_or_default(),
+        ca: args.cas_tls_ca.clone().unwrap_or_default(),
+        pins: args.cas_tls_pins.clone(),
+        server_name: None,
+    });
+
     // Create CAS SCSI device
     let device_config = CasScsiDeviceConfig {
+        cas_tls: cas_tls_client(cas_tls),
         cas_server_addr: args.cas_server,
         capacity_blocks,
-        index_path: args.index,
+        index_path: args.index.clone(),
         vendor_id: "VoE     ".to_string(),
         product_id: format!("CAS Disk {:>6}MB", args.size),
         product_rev: "1.0 ".to_string(),
+        read_cache_mb: args.read_cache_mb,
+        journal: true,
+        uuid: args.uuid,
     };
 
     let device = match CasScsiDevice::new(device_config) {
-        Ok(device) => device,
+        Ok(device) => device.with_readahead(args.readahead_blocks),
         Err(e) => {
             log::error!("Failed to create CAS SCSI device: {}", e);
             process::exit(1);
         }
     };
+    if let Err(e) = device.set_write_cache_enabled(args.write_cache) {
+        log::error!("Failed to set the write cache: {}", e);
+        process::exit(1);
+    }
 
     log::info!("CAS SCSI device created successfully");
+    let flush_handles = vec![device.flush_handle()];
+    let _locks = serve_live_snapshots(vec![(args.index, device.flush_handle())]);
+    if let Some(bind) = &args.status {
+        serve_status(bind, vec![(args.target.clone(), device.flush_handle())]);
+    }
+    let audit_log = args.registry.as_deref().map(AuditLog::for_registry);
+    let iqns = [args.target.clone()];
     log::info!("  Capacity: {} blocks ({} MB)", capacity_blocks, args.size);
 
     // Create iSCSI target
     let target = match IscsiTarget::builder()
         .bind_addr(&args.bind)
         .target_name(&args.target)
-        .build(device)
+        .build(TracedScsiDevice::new(args.target.as_str(), Box::new(device)))
     {
-        Ok(target) => target,
+        Ok(target) => Arc::new(target),
         Err(e) => {
             log::error!("Failed to create iSCSI target: {}", e);
             process::exit(1);
         }
     };
 
+    let (begin, sessions, stop) = (Arc::clone(&target), Arc::clone(&target), Arc::clone(&target));
+    spawn_shutdown_watcher(
+        move || begin.shutdown_gracefully(),
+        move || sessions.active_session_count(),
+        move || stop.stop(),
+    );
+
     log::info!("iSCSI target ready, waiting for connections...");
+    audit_serving(audit_log.as_ref(), AuditOperation::Start, &iqns, &Ok(()));
+    systemd::notify_ready();
+    let isns = args.isns_server.as_deref().and_then(|isns_server| {
+        let target = IsnsTarget {
+            iqn: args.target.clone(),
+            alias: None,
+        };
+        start_isns(isns_server, None, &args.bind, vec![target])
+    });
 
     // Run the target
     if let Err(e) = target.run() {
         log::error!("Target error: {}", e);
+        audit_serving(audit_log.as_ref(), AuditOperation::Stop, &iqns, &Err(e.to_string()));
+        process::exit(1);
+    }
+    drop(isns);
+    let flushed = flush_devices(&flush_handles);
+    audit_serving(audit_log.as_ref(), AuditOperation::Stop, &iqns, &flushed);
+}
+
+/// On shutdown: reject new logins, wait for sessions to drain, then stop
+fn spawn_shutdown_watcher(
+    begin: impl FnOnce() + Send + 'static,
+    active_sessions: impl Fn() -> usize + Send + 'static,
+    stop: impl FnOnce() + Send + 'static,
+) {
+    thread::spawn(move || {
+        shutdown::wait();
+        begin();
+
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while active_sessions() > 0 && Instant::now() < deadline {
+            thread::sleep(shutdown::POLL_INTERVAL);
+        }
+        let remaining = active_sessions();
+        if remaining > 0 {
+            log::warn!("Stopping with {} session(s) still logged in", remaining);
+        }
+        stop();
+    });
+}
+
+/// Mark targets as served and take snapshots for `iscsi-clone` while
+/// running. The returned locks are released when dropped.
+/// Stay passive until the failover peer fails, then hold the lease; exits
+/// if shutdown is requested first
+fn wait_until_active(config: &HaConfig) -> HaNode {
+    if let Err(e) = config.validate() {
+        log::error!("Invalid ha config: {}", e);
         process::exit(1);
     }
+    let node = match HaNode::start(config) {
+        Ok(node) => node,
+        Err(e) => {
+            log::error!("Failed to start HA heartbeats on {}: {}", config.listen, e);
+            process::exit(1);
+        }
+    };
+    match node.wait_until_active() {
+        Ok(Some(lease)) => node.hold(lease),
+        Ok(None) => {
+            log::info!("iSCSI server stopped while passive");
+            process::exit(0);
+        }
+        Err(e) => {
+            log::error!("Failed to take the HA lease: {}", e);
+            process::exit(1);
+        }
+    }
+    node
+}
+
+fn serve_live_snapshots(targets: Vec<(PathBuf, CasScsiFlushHandle)>) -> Vec<ServingLock> {
+    let mut locks = Vec::new();
+    for (index_path, _) in &targets {
+        match ServingLock::acquire(index_path) {
+            Ok(lock) => locks.push(lock),
+            Err(e) => log::warn!("Failed to write serving lock for {:?}: {}", index_path, e),
+        }
+    }
+    if let Err(e) = live::spawn_snapshot_watcher(targets) {
+        log::warn!("Live snapshots unavailable: {}", e);
+    }
+    locks
+}
+
+/// Mutual TLS to the CAS server, if configured. Certificates are reloaded
+/// on SIGHUP; exits if they can't be loaded.
+fn cas_tls_client(config: Option<TlsConfig>) -> Option<Arc<TlsClient>> {
+    let config = config?;
+    log::info!("  CAS TLS certificate: {}", config.cert.display());
+    match tls::install_reload_signal().and_then(|()| TlsClient::new(config)) {
+        Ok(client) => Some(Arc::new(client)),
+        Err(e) => {
+            log::error!("Failed to load CAS TLS certificates: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Serve the targets' statistics until shutdown; the server runs on
+/// without them if the address can't be bound
+fn serve_status(bind: &str, targets: Vec<(String, CasScsiFlushHandle)>) {
+    match status::spawn(bind, targets) {
+        Ok((addr, _)) => log::info!("  Status: http://{}/stats", addr),
+        Err(e) => log::warn!("Target statistics unavailable on {}: {}", bind, e),
+    }
+}
+
+/// Answer discovery sessions on `bind`, pointing initiators at the targets'
+/// portal `portal`
+fn serve_discovery(bind: &str, portal: &str, targets: Vec<String>) {
+    let port = match portal.parse::<std::net::SocketAddr>() {
+        Ok(portal) => portal.port(),
+        Err(e) => {
+            log::error!("Not serving discovery: bind address {:?}: {}", portal, e);
+            return;
+        }
+    };
+    match discovery::spawn(bind, targets, port) {
+        Ok((addr, _)) => log::info!("  Discovery portal: {}", addr),
+        Err(e) => log::error!("Discovery unavailable on {}: {}", bind, e),
+    }
+}
+
+/// Register the targets with an iSNS server until the registration is
+/// dropped. The entity is named after the host unless `entity` is given.
+fn start_isns(
+    isns_server: &str,
+    entity: Option<&str>,
+    bind: &str,
+    targets: Vec<IsnsTarget>,
+) -> Option<IsnsRegistration> {
+    let portal = match bind.parse() {
+        Ok(portal) => portal,
+        Err(e) => {
+            log::error!("Not registering with iSNS: bind address {:?}: {}", bind, e);
+            return None;
+        }
+    };
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| "voe-iscsi".to_string());
+    let entity = entity.unwrap_or(&hostname);
+    log::info!("  iSNS server: {} (entity {})", isns_server, entity);
+    Some(IsnsRegistration::start(IsnsClient::new(
+        isns_server,
+        entity,
+        portal,
+        targets,
+    )))
+}
+
+/// Write every device's cached blocks to CAS before exiting. Fails if any
+/// device couldn't be flushed (its writes stay in its journal).
+fn flush_devices(handles: &[CasScsiFlushHandle]) -> Result<(), String> {
+    let mut failed = 0;
+    for handle in handles {
+        match handle.flush() {
+            Ok(blocks) => log::info!("Flushed {} cached block(s) on shutdown", blocks),
+            Err(e) => {
+                log::error!("Failed to flush device on shutdown: {}", e);
+                failed += 1;
+            }
+        }
+    }
+    log::info!("iSCSI server stopped");
+    match failed {
+        0 => Ok(()),
+        _ => Err(format!("{} device(s) failed to flush on shutdown", failed)),
+    }
+}
+
+/// Record targets starting or stopping in the registry's audit log, if
+/// one is configured
+fn audit_serving(
+    audit_log: Option<&AuditLog>,
+    operation: AuditOperation,
+    iqns: &[String],
+    result: &Result<(), String>,
+) {
+    let Some(audit_log) = audit_log else {
+        return;
+    };
+    let pid = serde_json::json!({"pid": std::process::id()});
+    for iqn in iqns {
+        audit_log.record(&audit::local_actor(), operation, iqn, pid.clone(), result);
+    }
 }
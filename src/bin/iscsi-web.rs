@@ -1,20 +1,39 @@
 //! Web interface for iSCSI target management
 //!
-//! Provides a REST API and web UI for managing iSCSI targets.
+//! Provides a REST API and web UI for managing iSCSI targets, their
+//! snapshots, and garbage collection. GC can take a long time, so it runs
+//! as a background job that the UI polls for progress. Changes are recorded
+//! in the registry's audit log as made by the client's address, and each
+//! target's history can be viewed. With `--status-url`,
+//! running targets' read/write, cache and CAS counters are scraped from
+//! `iscsi-server`'s status server and shown with them. The API is described
+//! at `/api/openapi.json`.
 
 use anyhow::Result;
 use axum::{
-    extract::{Path, State},
+    extract::{ConnectInfo, Path, State},
     response::{Html, Json},
     routing::{get, post, delete},
     Router,
 };
 use clap::Parser;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use aoe_server::iscsi::{CloneManager, TargetRegistry};
+use aoe_server::admin::AdminClient;
+use aoe_server::iscsi::{
+    AuditEntry, AuditLog, AuditOperation, CloneManager, GcReport, TargetRegistry, TargetSnapshot,
+};
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::tls::{self, TlsClient, TlsConfig};
+use aoe_server::openapi::{
+    any_object, array, boolean, integer, nullable, number, object, schema_ref, string,
+    string_enum, ApiDoc,
+};
 
 #[derive(Parser)]
 #[command(name = "iscsi-web")]
@@ -35,6 +54,28 @@ struct Cli {
     /// CAS server address
     #[arg(long, default_value = "127.0.0.1:3000")]
     cas_server: String,
+
+    /// Certificate (PEM) to present to the CAS server; turns on mutual TLS
+    #[arg(long, requires_all = ["cas_tls_key", "cas_tls_ca"])]
+    cas_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --cas-tls-cert
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_key: Option<PathBuf>,
+
+    /// CA certificates (PEM) the CAS server's certificate must chain to
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint the CAS server's certificate must match
+    /// (repeatable)
+    #[arg(long = "cas-tls-pin", requires = "cas_tls_cert")]
+    cas_tls_pins: Vec<String>,
+
+    /// iscsi-server status address to scrape target statistics from
+    /// (its `status` option, e.g. http://127.0.0.1:3261)
+    #[arg(long)]
+    status_url: Option<String>,
 }
 
 /// Shared application state
@@ -43,15 +84,28 @@ struct AppState {
     registry_path: PathBuf,
     targets_dir: PathBuf,
     cas_server: String,
+    cas_tls: Option<Arc<TlsClient>>,
+    status_url: Option<String>,
+    jobs: JobQueue,
 }
 
 impl AppState {
     fn new_manager(&self) -> Result<CloneManager> {
-        CloneManager::new(
+        let manager = CloneManager::new(
             self.registry_path.clone(),
             self.targets_dir.clone(),
             self.cas_server.clone(),
-        )
+        )?;
+        Ok(match &self.cas_tls {
+            Some(tls) => manager.with_cas_tls(tls.clone()),
+            None => manager,
+        })
+    }
+
+    /// Manager for changes asked for by `client`, who the audit log
+    /// records as making them
+    fn manager_for(&self, client: SocketAddr) -> Result<CloneManager> {
+        Ok(self.new_manager()?.with_actor(format!("web {}", client.ip())))
     }
 }
 
@@ -69,6 +123,17 @@ struct CloneTargetRequest {
     dest_name: String,
 }
 
+#[derive(Deserialize)]
+struct CreateSnapshotRequest {
+    description: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+struct GcRequest {
+    #[serde(default)]
+    dry_run: bool,
+}
+
 #[derive(Serialize)]
 struct TargetInfo {
     iqn: String,
@@ -107,16 +172,153 @@ impl<T> ApiResponse<T> {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+/// A background job and its progress
+#[derive(Debug, Clone, Serialize)]
+struct Job {
+    id: u64,
+    kind: String,
+    target: String,
+    status: JobStatus,
+    /// Units of work done so far, out of `total` (0 while unknown)
+    done: usize,
+    total: usize,
+    started_at: u64,
+    finished_at: Option<u64>,
+    /// Summary on success, error message on failure
+    message: Option<String>,
+    report: Option<GcReport>,
+}
+
+/// Background jobs run on the blocking thread pool, kept in memory so
+/// their progress can be polled
+#[derive(Clone, Default)]
+struct JobQueue {
+    inner: Arc<Mutex<JobTable>>,
+}
+
+#[derive(Default)]
+struct JobTable {
+    next_id: u64,
+    jobs: BTreeMap<u64, Job>,
+}
+
+/// Handle a running job uses to report progress
+struct JobHandle {
+    id: u64,
+    queue: JobQueue,
+}
+
+impl JobHandle {
+    fn progress(&self, done: usize, total: usize) {
+        self.queue.update(self.id, |job| {
+            job.done = done;
+            job.total = total;
+        });
+    }
+}
+
+impl JobQueue {
+    /// Start `work` in the background unless a job is already running for
+    /// the target, returning the new job's ID
+    fn spawn<F>(&self, kind: &str, target: &str, work: F) -> Result<u64>
+    where
+        F: FnOnce(&JobHandle) -> Result<(String, Option<GcReport>)> + Send + 'static,
+    {
+        let id = {
+            let mut table = self.inner.lock().unwrap();
+            if table.jobs.values().any(|job| job.target == target && job.status == JobStatus::Running) {
+                anyhow::bail!("A job is already running for {}", target);
+            }
+            table.next_id += 1;
+            let id = table.next_id;
+            table.jobs.insert(id, Job {
+                id,
+                kind: kind.to_string(),
+                target: target.to_string(),
+                status: JobStatus::Running,
+                done: 0,
+                total: 0,
+                started_at: TargetRegistry::now(),
+                finished_at: None,
+                message: None,
+                report: None,
+            });
+            id
+        };
+
+        let handle = JobHandle { id, queue: self.clone() };
+        tokio::task::spawn_blocking(move || {
+            let result = work(&handle);
+            handle.queue.update(id, |job| {
+                job.finished_at = Some(TargetRegistry::now());
+                match result {
+                    Ok((message, report)) => {
+                        job.status = JobStatus::Completed;
+                        job.message = Some(message);
+                        job.report = report;
+                    }
+                    Err(e) => {
+                        job.status = JobStatus::Failed;
+                        job.message = Some(format!("{:#}", e));
+                    }
+                }
+            });
+        });
+        Ok(id)
+    }
+
+    fn update<F: FnOnce(&mut Job)>(&self, id: u64, f: F) {
+        if let Some(job) = self.inner.lock().unwrap().jobs.get_mut(&id) {
+            f(job);
+        }
+    }
+
+    fn get(&self, id: u64) -> Option<Job> {
+        self.inner.lock().unwrap().jobs.get(&id).cloned()
+    }
+
+    /// All jobs, newest first
+    fn list(&self) -> Vec<Job> {
+        self.inner.lock().unwrap().jobs.values().rev().cloned().collect()
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    env_logger::init();
+    logging::init("error", LogFormat::Text);
 
     let cli = Cli::parse();
 
+    let cas_tls = match &cli.cas_tls_cert {
+        Some(cert) => {
+            tls::install_reload_signal()?;
+            let tls = TlsClient::new(TlsConfig {
+                cert: cert.clone(),
+                key: cli.cas_tls_key.clone().unwrap_or_default(),
+                ca: cli.cas_tls_ca.clone().unwrap_or_default(),
+                pins: cli.cas_tls_pins.clone(),
+                server_name: None,
+            })?;
+            Some(Arc::new(tls))
+        }
+        None => None,
+    };
+
     let state = AppState {
         registry_path: cli.registry.clone(),
         targets_dir: cli.targets_dir.clone(),
         cas_server: cli.cas_server.clone(),
+        cas_tls,
+        status_url: cli.status_url.clone(),
+        jobs: JobQueue::default(),
     };
 
     // Build router
@@ -127,7 +329,15 @@ async fn main() -> Result<()> {
         .route("/api/targets/{iqn}", get(get_target))
         .route("/api/targets/{iqn}", delete(delete_target))
         .route("/api/targets/clone", post(clone_target))
+        .route("/api/targets/{iqn}/snapshots", get(list_snapshots))
+        .route("/api/targets/{iqn}/snapshots", post(create_snapshot))
+        .route("/api/targets/{iqn}/snapshots/{id}/restore", post(restore_snapshot))
         .route("/api/targets/{iqn}/gc", post(gc_target))
+        .route("/api/targets/{iqn}/stats", get(target_stats))
+        .route("/api/targets/{iqn}/history", get(target_history))
+        .route("/api/jobs", get(list_jobs))
+        .route("/api/jobs/{id}", get(get_job))
+        .route("/api/openapi.json", get(|| async { Json(openapi_doc()) }))
         .with_state(state);
 
     let addr: SocketAddr = cli.bind.parse()?;
@@ -135,9 +345,12 @@ async fn main() -> Result<()> {
     println!("  Registry: {:?}", cli.registry);
     println!("  Targets:  {:?}", cli.targets_dir);
     println!("  CAS:      {}", cli.cas_server);
+    if let Some(status_url) = &cli.status_url {
+        println!("  Status:   {}", status_url);
+    }
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
@@ -207,9 +420,10 @@ async fn get_target(
 /// Create new target
 async fn create_target(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     Json(req): Json<CreateTargetRequest>,
 ) -> Json<ApiResponse<String>> {
-    match state.new_manager() {
+    match state.manager_for(client) {
         Ok(mut manager) => {
             match manager.create_target(&req.name, req.size_mb, req.description) {
                 Ok(iqn) => Json(ApiResponse::success(iqn)),
@@ -223,9 +437,10 @@ async fn create_target(
 /// Clone target
 async fn clone_target(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     Json(req): Json<CloneTargetRequest>,
 ) -> Json<ApiResponse<String>> {
-    match state.new_manager() {
+    match state.manager_for(client) {
         Ok(mut manager) => match manager.clone_target(&req.source_iqn, &req.dest_name) {
             Ok(iqn) => Json(ApiResponse::success(iqn)),
             Err(e) => Json(ApiResponse::error(e.to_string())),
@@ -237,9 +452,10 @@ async fn clone_target(
 /// Delete target
 async fn delete_target(
     State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
     Path(iqn): Path<String>,
 ) -> Json<ApiResponse<String>> {
-    match state.new_manager() {
+    match state.manager_for(client) {
         Ok(mut manager) => match manager.delete_target(&iqn, false) {
             Ok(_) => Json(ApiResponse::success(format!("Deleted target: {}", iqn))),
             Err(e) => Json(ApiResponse::error(e.to_string())),
@@ -248,15 +464,303 @@ async fn delete_target(
     }
 }
 
-/// Garbage collect target
-async fn gc_target(
+/// List a target's snapshots
+async fn list_snapshots(
     State(state): State<AppState>,
     Path(iqn): Path<String>,
+) -> Json<ApiResponse<Vec<TargetSnapshot>>> {
+    match state.new_manager() {
+        Ok(manager) => match manager.registry.get_target(&iqn) {
+            Some(t) => Json(ApiResponse::success(t.snapshots.clone())),
+            None => Json(ApiResponse::error(format!("Target not found: {}", iqn))),
+        },
+        Err(e) => Json(ApiResponse::error(e.to_string())),
+    }
+}
+
+/// Snapshot a target
+async fn create_snapshot(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(iqn): Path<String>,
+    Json(req): Json<CreateSnapshotRequest>,
+) -> Json<ApiResponse<TargetSnapshot>> {
+    // A running target's server may take a while to flush
+    let result = tokio::task::spawn_blocking(move || {
+        state.manager_for(client)?.snapshot_target(&iqn, req.description)
+    })
+    .await;
+
+    match result {
+        Ok(Ok(snapshot)) => Json(ApiResponse::success(snapshot)),
+        Ok(Err(e)) => Json(ApiResponse::error(format!("{:#}", e))),
+        Err(e) => Json(ApiResponse::error(e.to_string())),
+    }
+}
+
+/// Restore a stopped target to one of its snapshots
+async fn restore_snapshot(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path((iqn, id)): Path<(String, String)>,
 ) -> Json<ApiResponse<String>> {
-    // TODO: Implement GC via async task
-    Json(ApiResponse::error(
-        "Garbage collection not yet implemented in web interface".to_string(),
-    ))
+    match state.manager_for(client) {
+        Ok(manager) => match manager.restore_snapshot(&iqn, &id) {
+            Ok(()) => Json(ApiResponse::success(format!("Restored {} to {}", iqn, id))),
+            Err(e) => Json(ApiResponse::error(format!("{:#}", e))),
+        },
+        Err(e) => Json(ApiResponse::error(e.to_string())),
+    }
+}
+
+/// Start garbage collecting a target's unique blocks, returning the job ID
+async fn gc_target(
+    State(state): State<AppState>,
+    ConnectInfo(client): ConnectInfo<SocketAddr>,
+    Path(iqn): Path<String>,
+    req: Option<Json<GcRequest>>,
+) -> Json<ApiResponse<u64>> {
+    let dry_run = req.map(|Json(req)| req.dry_run).unwrap_or_default();
+    let manager = match state.manager_for(client) {
+        Ok(manager) => manager,
+        Err(e) => return Json(ApiResponse::error(e.to_string())),
+    };
+    if manager.registry.get_target(&iqn).is_none() {
+        return Json(ApiResponse::error(format!("Target not found: {}", iqn)));
+    }
+
+    let target = iqn.clone();
+    let spawned = state.jobs.spawn("gc", &iqn, move |job| {
+        let result = run_gc(&manager, &target, dry_run, job);
+        let report = result.as_ref().ok().and_then(|(_, report)| report.as_ref());
+        let params = serde_json::json!({"dry_run": dry_run, "report": report});
+        manager.audit(AuditOperation::Gc, &target, params, &result);
+        result
+    });
+
+    match spawned {
+        Ok(id) => Json(ApiResponse::success(id)),
+        Err(e) => Json(ApiResponse::error(e.to_string())),
+    }
+}
+
+/// Collect a target's unique blocks, or count them for a dry run
+fn run_gc(
+    manager: &CloneManager,
+    iqn: &str,
+    dry_run: bool,
+    job: &JobHandle,
+) -> Result<(String, Option<GcReport>)> {
+    let hashes = manager.gc_candidates(iqn)?;
+    if dry_run {
+        return Ok((format!("Dry run: {} unique blocks would be deleted", hashes.len()), None));
+    }
+    if hashes.is_empty() {
+        return Ok(("No unique blocks to delete".to_string(), Some(GcReport::default())));
+    }
+    job.progress(0, hashes.len());
+    let report = manager.delete_blocks(&hashes, |done| job.progress(done, hashes.len()))?;
+    let message = format!(
+        "Deleted {} blocks ({} not found, {} kept as recently written, {} errors)",
+        report.deleted, report.not_found, report.kept, report.errors
+    );
+    Ok((message, Some(report)))
+}
+
+/// List background jobs
+async fn list_jobs(State(state): State<AppState>) -> Json<ApiResponse<Vec<Job>>> {
+    Json(ApiResponse::success(state.jobs.list()))
+}
+
+/// Get a background job's progress
+async fn get_job(State(state): State<AppState>, Path(id): Path<u64>) -> Json<ApiResponse<Job>> {
+    match state.jobs.get(id) {
+        Some(job) => Json(ApiResponse::success(job)),
+        None => Json(ApiResponse::error(format!("Job not found: {}", id))),
+    }
+}
+
+/// Operations on a target and the targets it was cloned from, oldest first
+async fn target_history(
+    State(state): State<AppState>,
+    Path(iqn): Path<String>,
+) -> Json<ApiResponse<Vec<AuditEntry>>> {
+    match AuditLog::for_registry(&state.registry_path).history(&iqn) {
+        Ok(history) => Json(ApiResponse::success(history)),
+        Err(e) => Json(ApiResponse::error(e.to_string())),
+    }
+}
+
+/// Runtime statistics of a running target, from iscsi-server
+async fn target_stats(
+    State(state): State<AppState>,
+    Path(iqn): Path<String>,
+) -> Json<ApiResponse<serde_json::Value>> {
+    let Some(status_url) = state.status_url else {
+        return Json(ApiResponse::error("No iscsi-server status URL configured".to_string()));
+    };
+    let scraped = tokio::task::spawn_blocking(move || {
+        let client = AdminClient::new(&status_url)?.with_timeout(Duration::from_secs(5));
+        client.get(&format!("/targets/{}/stats", iqn))
+    })
+    .await;
+    match scraped {
+        Ok(Ok(stats)) => Json(ApiResponse::success(stats)),
+        Ok(Err(e)) => Json(ApiResponse::error(e.to_string())),
+        Err(e) => Json(ApiResponse::error(e.to_string())),
+    }
+}
+
+/// OpenAPI document for the endpoints above
+fn openapi_doc() -> serde_json::Value {
+    let job_status = string_enum(&["running", "completed", "failed"]);
+    let audit_operation = string_enum(&[
+        "create", "clone", "snapshot", "restore", "flatten", "delete", "gc", "start", "stop",
+    ]);
+
+    ApiDoc::new("VoE iSCSI web", "Manage iSCSI targets, snapshots and GC jobs")
+        .schema(
+            "TargetInfo",
+            object(&[
+                ("iqn", string()),
+                ("name", string()),
+                ("size_mb", integer()),
+                ("index_path", string()),
+                ("parent", nullable(string())),
+                ("children", array(string())),
+                ("created_at", integer()),
+                ("description", nullable(string())),
+                ("running", boolean()),
+            ]),
+        )
+        .schema(
+            "TargetSnapshot",
+            object(&[
+                ("id", string()),
+                ("layer_path", string()),
+                ("created_at", integer()),
+                ("description", nullable(string())),
+            ]),
+        )
+        .schema(
+            "GcReport",
+            object(&[
+                ("deleted", integer()),
+                ("not_found", integer()),
+                ("kept", integer()),
+                ("errors", integer()),
+            ]),
+        )
+        .schema(
+            "Job",
+            object(&[
+                ("id", integer()),
+                ("kind", string()),
+                ("target", string()),
+                ("status", job_status),
+                ("done", integer()),
+                ("total", integer()),
+                ("started_at", integer()),
+                ("finished_at", nullable(integer())),
+                ("message", nullable(string())),
+                ("report", nullable(schema_ref("GcReport"))),
+            ]),
+        )
+        .operation("GET", "/api/targets", "List targets", None, array(schema_ref("TargetInfo")))
+        .operation(
+            "POST",
+            "/api/targets",
+            "Create a target, returning its IQN",
+            Some(object(&[
+                ("name", string()),
+                ("size_mb", integer()),
+                ("description", nullable(string())),
+            ])),
+            string(),
+        )
+        .operation("GET", "/api/targets/{iqn}", "Get a target", None, schema_ref("TargetInfo"))
+        .operation("DELETE", "/api/targets/{iqn}", "Delete a stopped target", None, string())
+        .operation(
+            "POST",
+            "/api/targets/clone",
+            "Clone a target, returning the new IQN",
+            Some(object(&[("source_iqn", string()), ("dest_name", string())])),
+            string(),
+        )
+        .operation(
+            "GET",
+            "/api/targets/{iqn}/snapshots",
+            "List a target's snapshots",
+            None,
+            array(schema_ref("TargetSnapshot")),
+        )
+        .operation(
+            "POST",
+            "/api/targets/{iqn}/snapshots",
+            "Snapshot a target",
+            Some(object(&[("description", nullable(string()))])),
+            schema_ref("TargetSnapshot"),
+        )
+        .operation(
+            "POST",
+            "/api/targets/{iqn}/snapshots/{id}/restore",
+            "Restore a stopped target to a snapshot",
+            None,
+            string(),
+        )
+        .operation(
+            "POST",
+            "/api/targets/{iqn}/gc",
+            "Start garbage collection, returning the job ID",
+            Some(object(&[("dry_run", nullable(boolean()))])),
+            integer(),
+        )
+        .schema(
+            "TargetStats",
+            object(&[
+                ("iqn", string()),
+                ("reads", integer()),
+                ("writes", integer()),
+                ("blocks_read", integer()),
+                ("blocks_written", integer()),
+                ("write_cache_hits", integer()),
+                ("read_cache_hits", integer()),
+                ("read_cache_misses", integer()),
+                ("read_cache_hit_rate", nullable(number())),
+                ("flushes", integer()),
+                ("blocks_flushed", integer()),
+                ("cas_requests", integer()),
+            ]),
+        )
+        .schema(
+            "AuditEntry",
+            object(&[
+                ("timestamp", integer()),
+                ("actor", string()),
+                ("operation", audit_operation),
+                ("target", string()),
+                ("params", any_object()),
+                ("success", boolean()),
+                ("error", nullable(string())),
+            ]),
+        )
+        .operation(
+            "GET",
+            "/api/targets/{iqn}/history",
+            "Audit log entries of a target and the targets it was cloned from",
+            None,
+            array(schema_ref("AuditEntry")),
+        )
+        .operation(
+            "GET",
+            "/api/targets/{iqn}/stats",
+            "Runtime statistics of a running target, from iscsi-server",
+            None,
+            schema_ref("TargetStats"),
+        )
+        .operation("GET", "/api/jobs", "List background jobs", None, array(schema_ref("Job")))
+        .operation("GET", "/api/jobs/{id}", "Get a background job", None, schema_ref("Job"))
+        .to_json()
 }
 
 const INDEX_HTML: &str = r#"<!DOCTYPE html>
@@ -375,6 +879,17 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
             font-size: 13px;
             font-family: monospace;
         }
+        .snapshot, .job {
+            border-bottom: 1px solid #eee;
+            padding: 8px 0;
+            font-size: 12px;
+        }
+        .progress {
+            height: 8px;
+            border: 1px solid #000;
+            margin-top: 4px;
+        }
+        .progress-bar { height: 100%; background: #000; }
         .error {
             background: #fee;
             color: #d00;
@@ -397,6 +912,11 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
         <div class="targets" id="targets">
             Loading targets...
         </div>
+
+        <h1 style="margin-top: 20px;">Jobs</h1>
+        <div class="targets" id="jobs">
+            No jobs.
+        </div>
     </div>
 
     <!-- Create Target Modal -->
@@ -439,8 +959,35 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
         </div>
     </div>
 
+    <!-- Snapshots Modal -->
+    <div class="modal" id="snapshotModal">
+        <div class="modal-content">
+            <h2>Snapshots: <span id="snapshotTarget"></span></h2>
+            <div id="snapshotError" class="error" style="display:none;"></div>
+            <div id="snapshotList"></div>
+            <div class="form-group" style="margin-top: 15px;">
+                <label>New snapshot description (optional):</label>
+                <input type="text" id="snapshotDesc" placeholder="e.g., Before upgrade">
+            </div>
+            <button class="btn btn-success" onclick="createSnapshot()">Take Snapshot</button>
+            <button class="btn" onclick="hideModal('snapshotModal')">Close</button>
+        </div>
+    </div>
+
+    <!-- History Modal -->
+    <div class="modal" id="historyModal">
+        <div class="modal-content">
+            <h2>History: <span id="historyTarget"></span></h2>
+            <div id="historyError" class="error" style="display:none;"></div>
+            <div id="historyList"></div>
+            <button class="btn" onclick="hideModal('historyModal')">Close</button>
+        </div>
+    </div>
+
     <script>
         let targets = [];
+        let snapshotIqn = null;
+        let jobPoller = null;
 
         async function loadTargets() {
             try {
@@ -477,6 +1024,26 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
             });
 
             container.innerHTML = html;
+            loadStats();
+        }
+
+        async function loadStats() {
+            for (const el of document.querySelectorAll('[data-stats]')) {
+                try {
+                    const iqn = el.dataset.stats;
+                    const res = await fetch(`/api/targets/${encodeURIComponent(iqn)}/stats`);
+                    const data = await res.json();
+                    if (!data.success) continue;
+                    const s = data.data;
+                    const hitRate = s.read_cache_hit_rate === null
+                        ? '-' : `${(s.read_cache_hit_rate * 100).toFixed(1)}%`;
+                    el.textContent = `Reads: ${s.reads} | Writes: ${s.writes} | ` +
+                        `Cache hit rate: ${hitRate} | Flushes: ${s.flushes} | ` +
+                        `CAS requests: ${s.cas_requests}`;
+                } catch (e) {
+                    console.error('Failed to load stats', e);
+                }
+            }
         }
 
         function renderTarget(target, level) {
@@ -500,8 +1067,11 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                             <div class="target-iqn">${target.iqn}</div>
                         </div>
                         <div>
+                            <button class="btn" onclick="showSnapshotModal('${target.iqn}')">Snapshots</button>
+                            <button class="btn" onclick="showHistoryModal('${target.iqn}')">History</button>
                             ${!target.running ?
-                                `<button class="btn btn-danger" onclick="deleteTarget('${target.iqn}')">Delete</button>`
+                                `<button class="btn" onclick="startGc('${target.iqn}')">GC</button>
+                                 <button class="btn btn-danger" onclick="deleteTarget('${target.iqn}')">Delete</button>`
                                 : ''}
                         </div>
                     </div>
@@ -509,6 +1079,7 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
                         Size: ${target.size_mb} MB |
                         ${target.description || 'No description'}
                     </div>
+                    ${target.running ? `<div class="target-info" data-stats="${target.iqn}"></div>` : ''}
                 </div>
             `;
 
@@ -597,6 +1168,174 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
             }
         }
 
+        async function showSnapshotModal(iqn) {
+            snapshotIqn = iqn;
+            document.getElementById('snapshotTarget').textContent = iqn;
+            document.getElementById('snapshotModal').classList.add('active');
+            await loadSnapshots();
+        }
+
+        async function loadSnapshots() {
+            const list = document.getElementById('snapshotList');
+            const target = targets.find(t => t.iqn === snapshotIqn);
+            try {
+                const res = await fetch(`/api/targets/${encodeURIComponent(snapshotIqn)}/snapshots`);
+                const data = await res.json();
+                if (!data.success) {
+                    showError('snapshotError', data.error);
+                    return;
+                }
+                if (data.data.length === 0) {
+                    list.innerHTML = '<p>No snapshots.</p>';
+                    return;
+                }
+                list.innerHTML = data.data.slice().reverse().map(s => `
+                    <div class="snapshot">
+                        <b>${s.id}</b> (${new Date(s.created_at * 1000).toLocaleString()})
+                        ${s.description || ''}
+                        ${target && !target.running ?
+                            `<button class="btn" onclick="restoreSnapshot('${s.id}')">Restore</button>`
+                            : ''}
+                    </div>
+                `).join('');
+            } catch (e) {
+                showError('snapshotError', e.message);
+            }
+        }
+
+        // Text as HTML that shows it literally
+        function escapeHtml(text) {
+            const el = document.createElement('div');
+            el.textContent = text ?? '';
+            return el.innerHTML;
+        }
+
+        async function showHistoryModal(iqn) {
+            document.getElementById('historyTarget').textContent = iqn;
+            document.getElementById('historyModal').classList.add('active');
+            const list = document.getElementById('historyList');
+            try {
+                const res = await fetch(`/api/targets/${encodeURIComponent(iqn)}/history`);
+                const data = await res.json();
+                if (!data.success) {
+                    showError('historyError', data.error);
+                    return;
+                }
+                if (data.data.length === 0) {
+                    list.innerHTML = '<p>No recorded operations.</p>';
+                    return;
+                }
+                // Entries carry what users typed, such as descriptions
+                list.innerHTML = data.data.map(e => `
+                    <div class="snapshot">
+                        <b>${escapeHtml(e.operation.toUpperCase())}</b> ${escapeHtml(e.target)}
+                        (${new Date(e.timestamp * 1000).toLocaleString()}, ${escapeHtml(e.actor)})
+                        ${e.success ? '' : `<span class="badge badge-stopped">FAILED</span> ${escapeHtml(e.error)}`}
+                        <div>${escapeHtml(JSON.stringify(e.params))}</div>
+                    </div>
+                `).join('');
+            } catch (e) {
+                showError('historyError', e.message);
+            }
+        }
+
+        async function createSnapshot() {
+            const desc = document.getElementById('snapshotDesc').value;
+            try {
+                const res = await fetch(`/api/targets/${encodeURIComponent(snapshotIqn)}/snapshots`, {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({ description: desc || null })
+                });
+                const data = await res.json();
+                if (data.success) {
+                    document.getElementById('snapshotDesc').value = '';
+                    loadSnapshots();
+                } else {
+                    showError('snapshotError', data.error);
+                }
+            } catch (e) {
+                showError('snapshotError', e.message);
+            }
+        }
+
+        async function restoreSnapshot(id) {
+            if (!confirm(`Restore ${snapshotIqn} to ${id}? Changes since then are lost.`)) return;
+            try {
+                const res = await fetch(
+                    `/api/targets/${encodeURIComponent(snapshotIqn)}/snapshots/${encodeURIComponent(id)}/restore`,
+                    { method: 'POST' });
+                const data = await res.json();
+                if (data.success) {
+                    alert(data.data);
+                } else {
+                    showError('snapshotError', data.error);
+                }
+            } catch (e) {
+                showError('snapshotError', e.message);
+            }
+        }
+
+        async function startGc(iqn) {
+            const dryRun = !confirm(`Delete CAS blocks used only by ${iqn}?\n\nCancel runs a dry run instead.`);
+            try {
+                const res = await fetch(`/api/targets/${encodeURIComponent(iqn)}/gc`, {
+                    method: 'POST',
+                    headers: { 'Content-Type': 'application/json' },
+                    body: JSON.stringify({ dry_run: dryRun })
+                });
+                const data = await res.json();
+                if (data.success) {
+                    loadJobs();
+                } else {
+                    alert('Error: ' + data.error);
+                }
+            } catch (e) {
+                alert('Failed to start GC: ' + e.message);
+            }
+        }
+
+        async function loadJobs() {
+            try {
+                const res = await fetch('/api/jobs');
+                const data = await res.json();
+                if (!data.success) return;
+                renderJobs(data.data);
+
+                // Keep polling while anything is still running
+                const running = data.data.some(j => j.status === 'running');
+                if (running && !jobPoller) {
+                    jobPoller = setInterval(loadJobs, 1000);
+                } else if (!running && jobPoller) {
+                    clearInterval(jobPoller);
+                    jobPoller = null;
+                }
+            } catch (e) {
+                console.error('Failed to load jobs', e);
+            }
+        }
+
+        function renderJobs(jobs) {
+            const container = document.getElementById('jobs');
+            if (jobs.length === 0) {
+                container.innerHTML = 'No jobs.';
+                return;
+            }
+            container.innerHTML = jobs.map(j => {
+                const pct = j.total > 0 ? Math.floor(j.done * 100 / j.total) : 0;
+                return `
+                    <div class="job">
+                        <b>#${j.id} ${j.kind.toUpperCase()}</b> ${j.target}
+                        <span class="badge ${j.status === 'failed' ? 'badge-stopped' : 'badge-running'}">${j.status.toUpperCase()}</span>
+                        ${j.status === 'running'
+                            ? `<div>${j.done}/${j.total || '?'}</div>
+                               <div class="progress"><div class="progress-bar" style="width: ${pct}%"></div></div>`
+                            : `<div>${j.message || ''}</div>`}
+                    </div>
+                `;
+            }).join('');
+        }
+
         function showCreateModal() {
             document.getElementById('createModal').classList.add('active');
         }
@@ -621,8 +1360,9 @@ const INDEX_HTML: &str = r#"<!DOCTYPE html>
             el.style.display = 'block';
         }
 
-        // Load targets on page load
+        // Load targets and jobs on page load
         loadTargets();
+        loadJobs();
     </script>
 </body>
 </html>
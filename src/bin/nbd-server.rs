@@ -3,25 +3,51 @@
 //! Network Block Device server backed by CAS storage
 
 use clap::Parser;
-use env_logger::Env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
-use aoe_server::nbd::{NbdServer, NbdServerConfig};
+use aoe_server::ha::{HaConfig, HaNode};
+use aoe_server::nbd::{NbdServer, NbdServerConfig, DEFAULT_QUEUE_DEPTH};
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::qos::QosLimits;
 use aoe_server::storage::cas_client::{CasBackend, CasBackendConfig};
+use aoe_server::storage::{TimedStorage, DEFAULT_SLOW_THRESHOLD};
+use aoe_server::net::Listener;
+use aoe_server::systemd;
+use aoe_server::tls::{self, TlsClient, TlsConfig};
 
 #[derive(Parser, Debug)]
 #[command(name = "nbd-server")]
 #[command(about = "NBD server with CAS backend", long_about = None)]
 struct Args {
-    /// Bind address (e.g., 127.0.0.1:10809)
+    /// Bind address (e.g., 127.0.0.1:10809 or unix:/run/voe/nbd.sock)
     #[arg(short, long, default_value = "127.0.0.1:10809")]
     bind: String,
 
-    /// CAS server address
+    /// CAS server address (host:port or unix:/path)
     #[arg(short, long, default_value = "127.0.0.1:3000")]
     cas_server: String,
 
+    /// Certificate (PEM) to present to the CAS server; turns on mutual TLS
+    #[arg(long, requires_all = ["cas_tls_key", "cas_tls_ca"])]
+    cas_tls_cert: Option<PathBuf>,
+
+    /// Private key (PEM) of --cas-tls-cert
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_key: Option<PathBuf>,
+
+    /// CA certificates (PEM) the CAS server's certificate must chain to
+    #[arg(long, requires = "cas_tls_cert")]
+    cas_tls_ca: Option<PathBuf>,
+
+    /// SHA-256 fingerprint the CAS server's certificate must match
+    /// (repeatable)
+    #[arg(long = "cas-tls-pin", requires = "cas_tls_cert")]
+    cas_tls_pins: Vec<String>,
+
     /// Device size in MB
     #[arg(short, long, default_value = "100")]
     size: u64,
@@ -33,12 +59,40 @@ struct Args {
     /// Export name
     #[arg(short, long, default_value = "cas-disk")]
     export: String,
+
+    /// Read bandwidth limit in MiB/s
+    #[arg(long)]
+    max_read_mbps: Option<u32>,
+
+    /// Write bandwidth limit in MiB/s
+    #[arg(long)]
+    max_write_mbps: Option<u32>,
+
+    /// Operations per second limit (reads and writes)
+    #[arg(long)]
+    max_iops: Option<u32>,
+
+    /// Requests each connection runs concurrently
+    #[arg(long, default_value_t = DEFAULT_QUEUE_DEPTH)]
+    queue_depth: usize,
+
+    /// Log storage operations slower than this many milliseconds (0: never)
+    #[arg(long, default_value_t = DEFAULT_SLOW_THRESHOLD.as_millis() as u64)]
+    slow_ms: u64,
+
+    /// Log format: text or json
+    #[arg(long, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Failover pair settings (TOML, the keys of aoe-server's [ha] table);
+    /// the server stays passive until the active node fails
+    #[arg(long)]
+    ha_config: Option<PathBuf>,
 }
 
 fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
-
     let args = Args::parse();
+    logging::init("info", args.log_format);
 
     log::info!("Starting NBD server");
     log::info!("  Bind address: {}", args.bind);
@@ -46,13 +100,22 @@ fn main() {
     log::info!("  Device size: {} MB", args.size);
     log::info!("  Index file: {:?}", args.index);
     log::info!("  Export name: {}", args.export);
+    log::info!("  Queue depth: {}", args.queue_depth);
+
+    if let Err(e) = aoe_server::shutdown::install() {
+        log::error!("Failed to install signal handlers: {}", e);
+        process::exit(1);
+    }
+
+    // A passive node doesn't load the index until it takes over
+    let _ha = args.ha_config.as_deref().map(wait_until_active);
 
     // Create CAS backend
     let cas_config = CasBackendConfig {
+        tls: cas_tls(&args),
         cas_server_addr: args.cas_server,
         device_size_bytes: args.size * 1024 * 1024,
         device_model: format!("NBD CAS Disk {}MB", args.size),
-        device_serial: format!("NBD-CAS-{:08x}", rand::random::<u32>()),
         index_path: args.index,
     };
 
@@ -64,16 +127,106 @@ fn main() {
         }
     };
 
+    let listener = match bind_listener(&args.bind) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to bind {}: {}", args.bind, e);
+            process::exit(1);
+        }
+    };
+
     // Create NBD server
     let nbd_config = NbdServerConfig {
         bind_addr: args.bind,
         export_name: args.export,
+        read_only: false,
+        qos: QosLimits {
+            max_read_mbps: args.max_read_mbps,
+            max_write_mbps: args.max_write_mbps,
+            max_iops: args.max_iops,
+            ..QosLimits::default()
+        },
+        queue_depth: args.queue_depth.max(1),
     };
 
+    let backend = TimedStorage::new(nbd_config.export_name.as_str(), Box::new(backend))
+        .with_slow_threshold(Duration::from_millis(args.slow_ms));
     let server = NbdServer::new(nbd_config, backend);
 
-    if let Err(e) = server.run() {
+    systemd::notify_ready();
+
+    if let Err(e) = server.serve(listener) {
         log::error!("Server error: {}", e);
         process::exit(1);
     }
+    log::info!("NBD server stopped");
+}
+
+/// Stay passive until the failover peer fails, then hold the lease; exits
+/// if shutdown is requested first
+fn wait_until_active(path: &Path) -> HaNode {
+    let config: HaConfig = match fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|content| toml::from_str(&content).map_err(|e| e.to_string()))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Failed to load HA config {:?}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = config.validate() {
+        log::error!("Invalid HA config {:?}: {}", path, e);
+        process::exit(1);
+    }
+    let node = match HaNode::start(&config) {
+        Ok(node) => node,
+        Err(e) => {
+            log::error!("Failed to start HA heartbeats on {}: {}", config.listen, e);
+            process::exit(1);
+        }
+    };
+    match node.wait_until_active() {
+        Ok(Some(lease)) => node.hold(lease),
+        Ok(None) => {
+            log::info!("NBD server stopped while passive");
+            process::exit(0);
+        }
+        Err(e) => {
+            log::error!("Failed to take the HA lease: {}", e);
+            process::exit(1);
+        }
+    }
+    node
+}
+
+/// Mutual TLS to the CAS server, if --cas-tls-cert is given. Certificates
+/// are reloaded on SIGHUP; exits if they can't be loaded.
+fn cas_tls(args: &Args) -> Option<Arc<TlsClient>> {
+    let config = TlsConfig {
+        cert: args.cas_tls_cert.clone()?,
+        key: args.cas_tls_key.clone().unwrap_or_default(),
+        ca: args.cas_tls_ca.clone().unwrap_or_default(),
+        pins: args.cas_tls_pins.clone(),
+        server_name: None,
+    };
+    log::info!("  CAS TLS certificate: {}", config.cert.display());
+    match tls::install_reload_signal().and_then(|()| TlsClient::new(config)) {
+        Ok(client) => Some(Arc::new(client)),
+        Err(e) => {
+            log::error!("Failed to load CAS TLS certificates: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// Use the systemd-activated socket if there is one, else bind `addr`
+fn bind_listener(addr: &str) -> std::io::Result<Listener> {
+    match systemd::take_listener()? {
+        Some(listener) => {
+            log::info!("Using socket-activated listener");
+            Ok(listener)
+        }
+        None => Listener::bind(addr),
+    }
 }
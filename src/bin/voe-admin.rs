@@ -0,0 +1,740 @@
+//! Unified management CLI
+//!
+//! Manages running daemons through their admin APIs rather than their
+//! files, so it never races a live server: `aoe-server` (with `api` set
+//! under `[server]`) and `iscsi-web`. Which one is behind `--api` is read
+//! from its OpenAPI document; commands only one of them supports say so.
+//! Responses are printed as JSON.
+//!
+//! Blob store maintenance (`compact`, `fsck`) works on the store's
+//! directories directly. Compaction is safe alongside servers using them;
+//! checking iSCSI stores needs their targets stopped.
+//!
+//! Example:
+//!   voe-admin --api http://127.0.0.1:8081 targets list
+//!   voe-admin --api http://127.0.0.1:8081 snapshots create e1.0 --name nightly --tag daily
+//!   voe-admin --api http://127.0.0.1:8080 gc iqn.2024-06.local.voe:storage.web
+//!   voe-admin migrate-blobs --from file:/data/blobs --to file:/ssd/blobs
+//!   voe-admin import e1.0 /data/disk.img
+//!   voe-admin replicate e1.0 --from primary:10809
+//!   voe-admin compact --max-write-mbps 50 /data/blobs
+//!   voe-admin fsck cas --repair --replica /mnt/replica/blobs config.toml
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use aoe_server::admin::{AdminClient, Daemon};
+use aoe_server::blob::{self, compact::CompactOptions, BlobStore, FileBlobStore, ShardedBlobStore};
+use aoe_server::cas::{CasPool, CasPoolConfig};
+use aoe_server::config::{BackendType, BlobStoreConfig, Config};
+use aoe_server::fsck::{self, CasTree};
+use aoe_server::iscsi::TargetRegistry;
+use aoe_server::qos::QosLimits;
+
+#[derive(Parser, Debug)]
+#[command(name = "voe-admin")]
+#[command(about = "Manage running VoE daemons over their admin APIs", long_about = None)]
+struct Cli {
+    /// Admin API of the daemon to manage
+    #[arg(long, default_value = "http://127.0.0.1:8081")]
+    api: String,
+
+    /// Bearer token for the API (`api_token`); defaults to $VOE_API_TOKEN
+    #[arg(long)]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Manage targets
+    #[command(subcommand)]
+    Targets(TargetsCommand),
+
+    /// Health, deduplication and per-initiator statistics of a target
+    Stats {
+        /// Target (e<shelf>.<slot>, or IQN)
+        target: String,
+    },
+
+    /// Manage snapshots
+    #[command(subcommand)]
+    Snapshots(SnapshotsCommand),
+
+    /// Garbage collect a stopped iSCSI target's blocks
+    Gc {
+        /// Target IQN
+        target: String,
+
+        /// Report what would be deleted without deleting it
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+
+        /// Print the job and return instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+
+    /// Background jobs (iSCSI)
+    Jobs {
+        /// Show one job
+        id: Option<u64>,
+    },
+
+    /// Move the blobs of the CAS targets using one store to another while
+    /// they stay online (aoe-server)
+    MigrateBlobs {
+        /// Store the targets use now, e.g. file:/data/blobs
+        #[arg(long)]
+        from: String,
+
+        /// Store to move them to, file:<dir> or remote:<addr>
+        #[arg(long)]
+        to: String,
+
+        /// Print the migration and return instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+
+    /// Replace a CAS target's contents with a raw image on the daemon's
+    /// host (aoe-server)
+    Import {
+        /// Target (e<shelf>.<slot>)
+        target: String,
+
+        /// Absolute path of the image or block device
+        image: String,
+
+        /// Print the transfer and return instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+
+    /// Write a target to a new raw image on the daemon's host (aoe-server)
+    Export {
+        /// Target (e<shelf>.<slot>)
+        target: String,
+
+        /// Absolute path of the image to create
+        image: String,
+
+        /// Print the transfer and return instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+
+    /// Copy an NBD export into a target (aoe-server)
+    Replicate {
+        /// Target (e<shelf>.<slot>)
+        target: String,
+
+        /// NBD server to copy from, host:port or unix:/path
+        #[arg(long)]
+        from: String,
+
+        /// Export name to ask the NBD server for
+        #[arg(long, default_value = "")]
+        export: String,
+
+        /// Print the transfer and return instead of waiting for it
+        #[arg(long)]
+        no_wait: bool,
+    },
+
+    /// Defragment a file blob store and tidy its directories, online
+    Compact {
+        /// Blob store directories (every shard of a sharded store)
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+
+        /// Rewrite blobs stored in more extents than this
+        #[arg(long, default_value_t = 1)]
+        max_extents: u32,
+
+        /// Rewrite every blob, fragmented or not
+        #[arg(long)]
+        rewrite_all: bool,
+
+        /// Rewrite bandwidth limit in MiB/s
+        #[arg(long)]
+        max_write_mbps: Option<u32>,
+
+        /// Files examined or rewritten per second
+        #[arg(long)]
+        max_iops: Option<u32>,
+
+        /// Report what would change without changing it
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+    },
+
+    /// Check a blob store against everything referencing it
+    #[command(subcommand)]
+    Fsck(FsckCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum FsckCommand {
+    /// Check the blob stores of an aoe-server's CAS targets
+    Cas {
+        /// aoe-server config naming the targets and their stores
+        config: PathBuf,
+
+        /// Copy missing and corrupt blobs back from the replica
+        #[arg(long, requires = "replica")]
+        repair: bool,
+
+        /// Replica blob store directory (repeatable, one per shard)
+        #[arg(long)]
+        replica: Vec<PathBuf>,
+    },
+
+    /// Check a CAS server's storage against iSCSI LBA indexes; the targets
+    /// must be stopped
+    Iscsi {
+        /// CAS server storage directory
+        #[arg(long, default_value = "/var/lib/cas")]
+        store: PathBuf,
+
+        /// Registry of the targets and snapshots referencing it
+        #[arg(long, default_value = "/var/lib/voe-iscsi/registry.json")]
+        registry: PathBuf,
+
+        /// More indexes referencing it (repeatable)
+        #[arg(long = "index")]
+        indexes: Vec<PathBuf>,
+
+        /// Copy missing and corrupt blocks back from the replica
+        #[arg(long, requires = "replica")]
+        repair: bool,
+
+        /// Replica CAS server address
+        #[arg(long)]
+        replica: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum TargetsCommand {
+    /// List targets
+    List,
+
+    /// Create an iSCSI target
+    Create {
+        name: String,
+
+        #[arg(long)]
+        size_mb: u64,
+
+        #[arg(long)]
+        description: Option<String>,
+    },
+
+    /// Clone an iSCSI target
+    Clone {
+        /// Source target IQN
+        source: String,
+
+        /// Name of the new target
+        dest: String,
+    },
+
+    /// Delete an iSCSI target
+    Delete {
+        /// Target IQN
+        target: String,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotsCommand {
+    /// List a target's snapshots
+    List {
+        target: String,
+
+        /// Arrange them by parent (AoE)
+        #[arg(long)]
+        tree: bool,
+    },
+
+    /// Snapshot a target
+    Create {
+        target: String,
+
+        #[arg(long)]
+        description: Option<String>,
+
+        /// Unique name for the snapshot (AoE)
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Tag the snapshot (repeatable; needs --name)
+        #[arg(long = "tag", requires = "name")]
+        tags: Vec<String>,
+    },
+
+    /// Rename or retag a snapshot (AoE)
+    Update {
+        target: String,
+
+        /// Snapshot ID or name
+        snapshot: String,
+
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Replace the tags (repeatable)
+        #[arg(long = "tag")]
+        tags: Option<Vec<String>>,
+    },
+
+    /// Restore a stopped target to a snapshot (iSCSI)
+    Restore {
+        target: String,
+
+        /// Snapshot ID
+        snapshot: String,
+    },
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let data = match cli.command {
+        command @ Command::Compact { .. } => compact(command)?,
+        Command::Fsck(command) => {
+            let (data, clean) = fsck(command)?;
+            println!("{}", serde_json::to_string_pretty(&data)?);
+            if !clean {
+                bail!("missing or corrupt blobs remain");
+            }
+            return Ok(());
+        }
+        command => {
+            let mut client = AdminClient::new(&cli.api)?;
+            if let Some(token) = cli.token.or_else(|| std::env::var("VOE_API_TOKEN").ok()) {
+                client = client.with_token(token);
+            }
+            let daemon = client
+                .daemon()
+                .with_context(|| format!("no admin API at {}", cli.api))?;
+            run(&client, daemon, command)?
+        }
+    };
+    println!("{}", serde_json::to_string_pretty(&data)?);
+    Ok(())
+}
+
+fn run(client: &AdminClient, daemon: Daemon, command: Command) -> Result<Value> {
+    let only = |wanted: Daemon, what: &str| {
+        if daemon == wanted {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!("{} is not supported by {}", what, daemon_name(daemon)))
+        }
+    };
+
+    let data = match command {
+        Command::Targets(TargetsCommand::List) => match daemon {
+            Daemon::Aoe => client.get("/targets")?,
+            Daemon::Iscsi => client.get("/api/targets")?,
+        },
+        Command::Targets(TargetsCommand::Create {
+            name,
+            size_mb,
+            description,
+        }) => {
+            only(Daemon::Iscsi, "creating targets")?;
+            let body = json!({ "name": name, "size_mb": size_mb, "description": description });
+            client.request("POST", "/api/targets", Some(&body))?
+        }
+        Command::Targets(TargetsCommand::Clone { source, dest }) => {
+            only(Daemon::Iscsi, "cloning targets")?;
+            let body = json!({ "source_iqn": source, "dest_name": dest });
+            client.request("POST", "/api/targets/clone", Some(&body))?
+        }
+        Command::Targets(TargetsCommand::Delete { target }) => {
+            only(Daemon::Iscsi, "deleting targets")?;
+            client.request("DELETE", &format!("/api/targets/{}", seg(&target)), None)?
+        }
+        Command::Stats { target } => match daemon {
+            Daemon::Aoe => client.get(&format!("/targets/{}/stats", seg(&target)))?,
+            Daemon::Iscsi => client.get(&format!("/api/targets/{}", seg(&target)))?,
+        },
+        Command::Snapshots(SnapshotsCommand::List { target, tree }) => match daemon {
+            Daemon::Aoe if tree => {
+                client.get(&format!("/targets/{}/snapshots/tree", seg(&target)))?
+            }
+            Daemon::Aoe => client.get(&format!("/targets/{}/snapshots", seg(&target)))?,
+            Daemon::Iscsi => {
+                if tree {
+                    bail!("snapshot trees are not supported by iscsi-web");
+                }
+                client.get(&format!("/api/targets/{}/snapshots", seg(&target)))?
+            }
+        },
+        Command::Snapshots(SnapshotsCommand::Create {
+            target,
+            description,
+            name,
+            tags,
+        }) => match daemon {
+            Daemon::Aoe => {
+                let body = json!({ "description": description, "name": name, "tags": tags });
+                let path = format!("/targets/{}/snapshot", seg(&target));
+                client.request("POST", &path, Some(&body))?
+            }
+            Daemon::Iscsi => {
+                if name.is_some() {
+                    bail!("snapshot names are not supported by iscsi-web");
+                }
+                let body = json!({ "description": description });
+                let path = format!("/api/targets/{}/snapshots", seg(&target));
+                client.request("POST", &path, Some(&body))?
+            }
+        },
+        Command::Snapshots(SnapshotsCommand::Update {
+            target,
+            snapshot,
+            name,
+            tags,
+        }) => {
+            only(Daemon::Aoe, "updating snapshots")?;
+            let body = json!({ "name": name, "tags": tags });
+            let path = format!("/targets/{}/snapshots/{}", seg(&target), seg(&snapshot));
+            client.request("PATCH", &path, Some(&body))?
+        }
+        Command::Snapshots(SnapshotsCommand::Restore { target, snapshot }) => {
+            only(Daemon::Iscsi, "restoring snapshots")?;
+            let path =
+                format!("/api/targets/{}/snapshots/{}/restore", seg(&target), seg(&snapshot));
+            client.request("POST", &path, None)?
+        }
+        Command::Gc {
+            target,
+            dry_run,
+            no_wait,
+        } => {
+            only(Daemon::Iscsi, "GC")?;
+            let body = json!({ "dry_run": dry_run });
+            let path = format!("/api/targets/{}/gc", seg(&target));
+            let job = client.request("POST", &path, Some(&body))?;
+            if no_wait {
+                job
+            } else {
+                wait_for_job(client, &job)?
+            }
+        }
+        Command::Jobs { id } => {
+            only(Daemon::Iscsi, "jobs")?;
+            match id {
+                Some(id) => client.get(&format!("/api/jobs/{}", id))?,
+                None => client.get("/api/jobs")?,
+            }
+        }
+        Command::MigrateBlobs { from, to, no_wait } => {
+            only(Daemon::Aoe, "blob store migration")?;
+            let body = json!({ "from": from, "to": to });
+            let status = client.request("POST", "/blob-stores/migrate", Some(&body))?;
+            if no_wait {
+                status
+            } else {
+                wait_for_migration(client)?
+            }
+        }
+        Command::Import {
+            target,
+            image,
+            no_wait,
+        } => {
+            only(Daemon::Aoe, "importing images")?;
+            let body = json!({ "image": image });
+            let path = format!("/targets/{}/import", seg(&target));
+            let status = client.request("POST", &path, Some(&body))?;
+            if no_wait {
+                status
+            } else {
+                wait_for_transfer(client, &target)?
+            }
+        }
+        Command::Export {
+            target,
+            image,
+            no_wait,
+        } => {
+            only(Daemon::Aoe, "exporting images")?;
+            let body = json!({ "image": image });
+            let path = format!("/targets/{}/export", seg(&target));
+            let status = client.request("POST", &path, Some(&body))?;
+            if no_wait {
+                status
+            } else {
+                wait_for_transfer(client, &target)?
+            }
+        }
+        Command::Replicate {
+            target,
+            from,
+            export,
+            no_wait,
+        } => {
+            only(Daemon::Aoe, "replication")?;
+            let body = json!({ "source": from, "export": export });
+            let path = format!("/targets/{}/replicate", seg(&target));
+            let status = client.request("POST", &path, Some(&body))?;
+            if no_wait {
+                status
+            } else {
+                wait_for_transfer(client, &target)?
+            }
+        }
+        Command::Compact { .. } | Command::Fsck(_) => {
+            unreachable!("blob store maintenance runs without a daemon")
+        }
+    };
+    Ok(data)
+}
+
+/// Compact each blob store directory in turn
+fn compact(command: Command) -> Result<Value> {
+    let Command::Compact {
+        dirs,
+        max_extents,
+        rewrite_all,
+        max_write_mbps,
+        max_iops,
+        dry_run,
+    } = command
+    else {
+        unreachable!("not a compact command");
+    };
+    let options = CompactOptions {
+        max_extents,
+        rewrite_all,
+        limits: QosLimits {
+            max_write_mbps,
+            max_iops,
+            ..QosLimits::default()
+        },
+        dry_run,
+        ..CompactOptions::default()
+    };
+    let mut reports = serde_json::Map::new();
+    for dir in dirs {
+        eprintln!("Compacting {}", dir.display());
+        let report = blob::compact::compact(&dir, &options)
+            .with_context(|| format!("failed to compact {}", dir.display()))?;
+        reports.insert(dir.display().to_string(), serde_json::to_value(report)?);
+    }
+    Ok(Value::Object(reports))
+}
+
+/// Check blob stores, returning the reports and whether every store is
+/// free of missing and corrupt blobs
+fn fsck(command: FsckCommand) -> Result<(Value, bool)> {
+    let mut reports = serde_json::Map::new();
+    let mut clean = true;
+    match command {
+        FsckCommand::Cas {
+            config,
+            repair,
+            replica,
+        } => {
+            let config = Config::load(&config)
+                .with_context(|| format!("failed to load {}", config.display()))?;
+            let replica = if repair {
+                let mut stores = Vec::new();
+                for dir in &replica {
+                    stores.push(
+                        FileBlobStore::new(dir)
+                            .with_context(|| format!("failed to open replica {}", dir.display()))?,
+                    );
+                }
+                Some(ShardedBlobStore::new(stores))
+            } else {
+                None
+            };
+
+            // Targets sharing a store are checked together
+            let mut stores: Vec<(Vec<PathBuf>, Vec<CasTree>)> = Vec::new();
+            for target in &config.target {
+                let Some(cas) = &target.cas else {
+                    continue;
+                };
+                if target.backend != BackendType::Cas {
+                    continue;
+                }
+                if cas.encryption.is_some() {
+                    eprintln!(
+                        "Skipping e{}.{}: encrypted blob stores can't be checked",
+                        target.shelf, target.slot
+                    );
+                    continue;
+                }
+                let cold = cas.tiering.as_ref().map(|tiering| &tiering.cold_store);
+                let remote = std::iter::once(&cas.blob_store)
+                    .chain(cold)
+                    .any(|store| matches!(store, BlobStoreConfig::Remote { .. }));
+                if remote {
+                    eprintln!(
+                        "Skipping e{}.{}: remote blob stores can't be checked",
+                        target.shelf, target.slot
+                    );
+                    continue;
+                }
+                let mut dirs = cas.blob_store.dirs();
+                if let Some(cold) = cold {
+                    dirs.extend(cold.dirs());
+                }
+                let sector_size = target.sector_size.unwrap_or(512);
+                let per_block = cas.block_size.unwrap_or(sector_size) / sector_size;
+                let tree = CasTree {
+                    snapshots: cas.snapshot_path(),
+                    blocks: cas.total_sectors.div_ceil(per_block as u64),
+                };
+                match stores.iter_mut().find(|(existing, _)| *existing == dirs) {
+                    Some((_, trees)) => trees.push(tree),
+                    None => stores.push((dirs, vec![tree])),
+                }
+            }
+
+            for (dirs, trees) in stores {
+                eprintln!("Checking {}", dirs[0].display());
+                let report = fsck::check_cas_store(
+                    &dirs,
+                    &trees,
+                    replica.as_ref().map(|store| store as &dyn BlobStore),
+                )
+                .with_context(|| format!("failed to check {}", dirs[0].display()))?;
+                clean &= report.is_clean();
+                reports.insert(dirs[0].display().to_string(), serde_json::to_value(report)?);
+            }
+        }
+        FsckCommand::Iscsi {
+            store,
+            registry,
+            mut indexes,
+            repair,
+            replica,
+        } => {
+            let registry = TargetRegistry::load(&registry)?;
+            for target in registry.targets.values() {
+                indexes.push(target.index_path.clone());
+                indexes.extend(target.snapshots.iter().map(|s| s.layer_path.clone()));
+            }
+            let replica = match (repair, replica) {
+                (true, Some(address)) => Some(
+                    CasPool::connect(&address, CasPoolConfig::default())
+                        .with_context(|| format!("failed to connect to replica {}", address))?,
+                ),
+                _ => None,
+            };
+
+            eprintln!("Checking {}", store.display());
+            let report = fsck::check_iscsi_store(&store, &indexes, replica.as_ref())
+                .with_context(|| format!("failed to check {}", store.display()))?;
+            clean &= report.is_clean();
+            reports.insert(store.display().to_string(), serde_json::to_value(report)?);
+        }
+    }
+
+    Ok((Value::Object(reports), clean))
+}
+
+/// Poll a job until it finishes, reporting progress on stderr
+fn wait_for_job(client: &AdminClient, job: &Value) -> Result<Value> {
+    let id = job["id"].as_u64().context("job has no id")?;
+    loop {
+        let job = client.get(&format!("/api/jobs/{}", id))?;
+        match job["status"].as_str() {
+            Some("running") => {
+                eprint!("\r{} job {}: {}/{}", job["kind"], id, job["done"], job["total"]);
+                thread::sleep(Duration::from_secs(1));
+            }
+            Some("failed") => {
+                eprintln!();
+                bail!("job {} failed: {}", id, job["message"]);
+            }
+            _ => {
+                eprintln!();
+                return Ok(job);
+            }
+        }
+    }
+}
+
+/// Poll the blob store migration until it is done or has failed
+fn wait_for_migration(client: &AdminClient) -> Result<Value> {
+    loop {
+        let status = client.get("/blob-stores/migration")?;
+        match status["state"].as_str() {
+            Some(state @ ("copying" | "verifying")) => {
+                eprint!(
+                    "\rblob migration {}: {} copied, {}/{} verified",
+                    state, status["copied"], status["verified"], status["blobs"]
+                );
+                thread::sleep(Duration::from_secs(1));
+            }
+            Some("failed") => {
+                eprintln!();
+                bail!("blob migration failed: {}", status["error"]);
+            }
+            _ => {
+                eprintln!();
+                return Ok(status);
+            }
+        }
+    }
+}
+
+/// Poll a target's import, export or replication until it is done or has
+/// failed
+fn wait_for_transfer(client: &AdminClient, target: &str) -> Result<Value> {
+    let path = format!("/targets/{}/transfer", seg(target));
+    loop {
+        let status = client.get(&path)?;
+        match status["state"].as_str() {
+            Some("running") => {
+                eprint!(
+                    "\r{} {}: {}/{} bytes",
+                    status["kind"], target, status["copied_bytes"], status["total_bytes"]
+                );
+                thread::sleep(Duration::from_secs(1));
+            }
+            Some("failed") => {
+                eprintln!();
+                bail!("{} failed: {}", status["kind"], status["error"]);
+            }
+            _ => {
+                eprintln!();
+                return Ok(status);
+            }
+        }
+    }
+}
+
+fn daemon_name(daemon: Daemon) -> &'static str {
+    match daemon {
+        Daemon::Aoe => "aoe-server",
+        Daemon::Iscsi => "iscsi-web",
+    }
+}
+
+/// Percent-encode a path segment (target ids, IQNs, snapshot names)
+fn seg(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b':' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
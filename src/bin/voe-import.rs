@@ -0,0 +1,211 @@
+//! Raw image import
+//!
+//! Streams an existing disk image or block device into a CAS target, so a
+//! file target can be converted to CAS: import its image, then point the
+//! target's config at the blob store. Zero runs stay sparse and the rest
+//! is compressed and deduplicated on the way in. A snapshot of the result
+//! is taken at the end.
+//!
+//! Example:
+//!   voe-import --blob-store /data/blobs --block-size 65536 /data/disk.img
+//!   voe-import --blob-store /data/blobs --name nightly-2024-06-01 --tag nightly \
+//!       --keep nightly=7 /data/disk.img
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, ValueEnum};
+use std::fs::File;
+use std::io::{BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use aoe_server::blob::FileBlobStore;
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::storage::cas::DEFAULT_ZSTD_LEVEL;
+use aoe_server::storage::{ArchivalStorage, CasBackend, Compression, RetentionRule};
+use aoe_server::BlockStorage;
+
+#[derive(Parser, Debug)]
+#[command(name = "voe-import")]
+#[command(about = "Import a raw disk image into a CAS target", long_about = None)]
+struct Args {
+    /// Image file or block device to import
+    image: PathBuf,
+
+    /// Blob store directory of the CAS target
+    #[arg(short, long)]
+    blob_store: PathBuf,
+
+    /// Snapshot file (default: snapshots.json next to the blob store)
+    #[arg(long)]
+    snapshots: Option<PathBuf>,
+
+    /// Size of the target in 512-byte sectors (default: the image size)
+    #[arg(short, long)]
+    total_sectors: Option<u64>,
+
+    /// Logical sector size (512 or 4096)
+    #[arg(long, default_value = "512")]
+    sector_size: u32,
+
+    /// CAS data block size in bytes (default: one sector)
+    #[arg(long)]
+    block_size: Option<u32>,
+
+    /// Compression for stored blocks
+    #[arg(long, value_enum, default_value_t = CompressionArg::Lz4)]
+    compression: CompressionArg,
+
+    /// zstd level, with --compression zstd
+    #[arg(long, default_value_t = DEFAULT_ZSTD_LEVEL)]
+    zstd_level: i32,
+
+    /// Threads compressing and hashing blocks
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+
+    /// Description of the snapshot taken after the import
+    #[arg(long)]
+    description: Option<String>,
+
+    /// Unique name for the snapshot, usable in place of its ID
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Tag the snapshot (repeatable; needs --name)
+    #[arg(long = "tag", requires = "name")]
+    tags: Vec<String>,
+
+    /// Keep only the newest N snapshots tagged TAG, as TAG=N (repeatable)
+    #[arg(long = "keep", value_parser = parse_retention)]
+    retention: Vec<RetentionRule>,
+}
+
+fn parse_retention(rule: &str) -> Result<RetentionRule, String> {
+    let (tag, keep) = rule.split_once('=').ok_or("expected TAG=N")?;
+    let keep = keep.parse().map_err(|e| format!("bad count {:?}: {}", keep, e))?;
+    if tag.is_empty() || keep == 0 {
+        return Err("expected a tag and a count of at least 1".to_string());
+    }
+    Ok(RetentionRule {
+        tag: tag.to_string(),
+        keep,
+    })
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum CompressionArg {
+    None,
+    Lz4,
+    Zstd,
+}
+
+fn main() -> Result<()> {
+    logging::init("info", LogFormat::Text);
+
+    let args = Args::parse();
+    if !aoe_server::storage::is_valid_sector_size(args.sector_size) {
+        bail!("--sector-size must be 512 or 4096");
+    }
+
+    let mut image =
+        File::open(&args.image).with_context(|| format!("failed to open {:?}", args.image))?;
+    // Block devices report no length in their metadata, so seek to the end
+    let image_size = image.seek(SeekFrom::End(0))?;
+    image.seek(SeekFrom::Start(0))?;
+
+    let sector_size = args.sector_size as u64;
+    let total_sectors = args
+        .total_sectors
+        .unwrap_or(image_size.next_multiple_of(sector_size) / 512);
+    if total_sectors * 512 < image_size {
+        bail!(
+            "image is {} bytes, larger than {} sectors",
+            image_size,
+            total_sectors
+        );
+    }
+
+    let snapshot_path = args.snapshots.clone().unwrap_or_else(|| {
+        args.blob_store
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("snapshots.json")
+    });
+    let blob_store = FileBlobStore::new(&args.blob_store)
+        .with_context(|| format!("failed to open blob store at {:?}", args.blob_store))?;
+
+    let backend = CasBackend::new(Box::new(blob_store), total_sectors, &snapshot_path)
+        .context("failed to open CAS backend")?
+        .with_sector_size(args.sector_size)?
+        .with_compression(match args.compression {
+            CompressionArg::None => Compression::None,
+            CompressionArg::Lz4 => Compression::Lz4,
+            CompressionArg::Zstd => Compression::Zstd {
+                level: args.zstd_level,
+            },
+        })
+        .with_write_threads(args.threads);
+    let backend = match args.block_size {
+        Some(block_size) => backend.with_block_size(block_size)?,
+        None => backend,
+    };
+
+    log::info!(
+        "Importing {:?} ({} bytes) into {:?}",
+        args.image,
+        image_size,
+        args.blob_store
+    );
+    let started = Instant::now();
+    let imported = backend
+        .ingest_from(BufReader::new(image))
+        .context("import failed")?;
+    backend.flush().context("failed to flush the blob store")?;
+
+    let description = args
+        .description
+        .unwrap_or_else(|| format!("imported from {}", args.image.display()));
+    let snapshot = match &args.name {
+        Some(name) => backend.snapshot_named(name, Some(&description), &args.tags),
+        None => backend.snapshot(Some(&description)),
+    }
+    .context("failed to snapshot the imported image")?;
+    for pruned in backend
+        .prune_snapshots(&args.retention)
+        .context("failed to prune snapshots")?
+    {
+        log::info!("Pruned snapshot {}", pruned.name.as_deref().unwrap_or(&pruned.id));
+    }
+
+    let stats = backend.stats();
+    let elapsed = started.elapsed().as_secs_f64();
+    log::info!(
+        "Imported {} bytes in {:.1}s ({:.1} MiB/s): {} unique, {} duplicate, {} zero blocks",
+        imported,
+        elapsed,
+        imported as f64 / (1024.0 * 1024.0) / elapsed.max(f64::EPSILON),
+        stats.unique_blocks,
+        stats.duplicate_blocks,
+        stats.zero_blocks
+    );
+    log::info!(
+        "Stored {} bytes (dedup ratio {:.2}); snapshot {}",
+        stats.stored_bytes,
+        stats.dedup_ratio(),
+        snapshot
+    );
+
+    // The geometry the store was written with, which the target must match
+    println!("# In the target's [[target]] table:");
+    println!("backend = \"cas\"");
+    println!("sector_size = {}", backend.info().sector_size);
+    println!();
+    println!("[target.cas]");
+    println!("total_sectors = {}", total_sectors);
+    println!("block_size = {}", backend.block_size());
+    println!();
+    println!("[target.cas.blob_store]");
+    println!("type = \"file\"");
+    println!("path = {:?}", args.blob_store.display().to_string());
+    Ok(())
+}
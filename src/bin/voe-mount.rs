@@ -0,0 +1,140 @@
+//! Snapshot mount helper
+//!
+//! Exposes a CAS snapshot read-only over a loopback NBD export, so an old
+//! snapshot can be attached and individual files recovered without
+//! restoring the whole target.
+//!
+//! Example:
+//!   voe-mount --blob-store /data/blobs --total-sectors 2097152 --snapshot latest
+//!   nbd-client -N snapshot -readonly 127.0.0.1 10810 /dev/nbd0
+//!   mount -o ro /dev/nbd0p1 /mnt/recovered
+
+use anyhow::{Context, Result};
+use clap::Parser;
+use std::path::{Path, PathBuf};
+
+use aoe_server::blob::FileBlobStore;
+use aoe_server::logging::{self, LogFormat};
+use aoe_server::nbd::{NbdServer, NbdServerConfig};
+use aoe_server::storage::cas::SnapshotManager;
+use aoe_server::storage::{CasBackend, SnapshotTree};
+
+#[derive(Parser, Debug)]
+#[command(name = "voe-mount")]
+#[command(about = "Expose a CAS snapshot read-only over loopback NBD", long_about = None)]
+struct Args {
+    /// Blob store directory of the CAS target
+    #[arg(short, long)]
+    blob_store: PathBuf,
+
+    /// Snapshot file (default: snapshots.json next to the blob store)
+    #[arg(long)]
+    snapshots: Option<PathBuf>,
+
+    /// Total sectors of the CAS target (as configured)
+    #[arg(short, long)]
+    total_sectors: u64,
+
+    /// CAS data block size in bytes, if the target sets one
+    #[arg(long)]
+    block_size: Option<u32>,
+
+    /// Snapshot ID (root hash) or name to expose, or "latest"
+    #[arg(short, long, default_value = "latest")]
+    snapshot: String,
+
+    /// Bind address for the NBD export
+    #[arg(long, default_value = "127.0.0.1:10810")]
+    bind: String,
+
+    /// NBD export name
+    #[arg(short, long, default_value = "snapshot")]
+    export: String,
+
+    /// List available snapshots and exit
+    #[arg(short, long)]
+    list: bool,
+
+    /// With --list, show snapshots as a tree of branches
+    #[arg(long, requires = "list")]
+    tree: bool,
+}
+
+fn main() -> Result<()> {
+    logging::init("info", LogFormat::Text);
+
+    let args = Args::parse();
+
+    // Snapshot file lives alongside the blob store, as in aoe-server
+    let snapshot_path = args.snapshots.clone().unwrap_or_else(|| {
+        args.blob_store
+            .parent()
+            .unwrap_or(Path::new("."))
+            .join("snapshots.json")
+    });
+
+    let snapshots = SnapshotManager::new(&snapshot_path)
+        .with_context(|| format!("failed to load snapshots from {:?}", snapshot_path))?;
+
+    if args.list && args.tree {
+        for tree in SnapshotTree::build(&snapshots.list()) {
+            tree.print(0);
+        }
+        return Ok(());
+    }
+    if args.list {
+        for snapshot in snapshots.list() {
+            println!(
+                "{}  {}  {:<24}  {:<16}  {}",
+                snapshot.id,
+                snapshot.timestamp,
+                snapshot.name.as_deref().unwrap_or("-"),
+                if snapshot.tags.is_empty() { "-".to_string() } else { snapshot.tags.join(",") },
+                snapshot.description.as_deref().unwrap_or("")
+            );
+        }
+        return Ok(());
+    }
+
+    let root = if args.snapshot == "latest" {
+        snapshots.latest()
+    } else {
+        snapshots.get(&args.snapshot)
+    }
+    .ok_or_else(|| anyhow::anyhow!("snapshot not found: {}", args.snapshot))?;
+
+    let blob_store = FileBlobStore::new(&args.blob_store)
+        .with_context(|| format!("failed to open blob store at {:?}", args.blob_store))?;
+
+    let backend = CasBackend::with_root(
+        Box::new(blob_store),
+        args.total_sectors,
+        &snapshot_path,
+        root,
+    )
+    .context("failed to open CAS backend")?;
+    let backend = match args.block_size {
+        Some(block_size) => backend.with_block_size(block_size)?,
+        None => backend,
+    };
+
+    log::info!("Exposing snapshot {} read-only", root);
+    log::info!(
+        "Attach with: nbd-client -N {} -readonly {} /dev/nbd0",
+        args.export,
+        args.bind.replacen(':', " ", 1)
+    );
+
+    let config = NbdServerConfig {
+        bind_addr: args.bind,
+        export_name: args.export,
+        read_only: true,
+        ..Default::default()
+    };
+
+    NbdServer::new(config, backend)
+        .run()
+        .context("NBD server error")?;
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+//! Online compaction of a file blob store
+//!
+//! After heavy churn and garbage collection a `FileBlobStore` directory is
+//! left with empty prefix directories, temp files from interrupted writes,
+//! and blobs whose data is scattered over the disk. `compact` tidies it up
+//! while servers keep using the store:
+//!
+//! - blobs split into more extents than allowed are rewritten to a new
+//!   file and renamed over the old one, keeping their modification time
+//!   (tiering orders blobs by it), so readers see either copy
+//! - temp files older than a grace period are removed
+//! - prefix directories left empty are removed; `FileBlobStore::put`
+//!   recreates one if it loses that race
+//!
+//! Rewrites and directory scans are throttled so compaction doesn't starve
+//! the targets sharing the disk. Fragmentation is only known on Linux
+//! (FIEMAP); elsewhere just `rewrite_all` rewrites blobs.
+
+use super::Hash;
+use crate::qos::{QosLimits, RateLimiter};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// What `compact` does
+#[derive(Debug, Clone)]
+pub struct CompactOptions {
+    /// Rewrite blobs stored in more extents than this
+    pub max_extents: u32,
+    /// Rewrite every blob, fragmented or not
+    pub rewrite_all: bool,
+    /// Rewrite bandwidth (`max_write_mbps`) and files examined or
+    /// rewritten per second (`max_iops`)
+    pub limits: QosLimits,
+    /// Temp files younger than this may belong to writes in progress
+    pub temp_grace: Duration,
+    /// Report what would change without changing it
+    pub dry_run: bool,
+}
+
+impl Default for CompactOptions {
+    fn default() -> Self {
+        Self {
+            max_extents: 1,
+            rewrite_all: false,
+            limits: QosLimits::default(),
+            temp_grace: Duration::from_secs(3600),
+            dry_run: false,
+        }
+    }
+}
+
+/// What `compact` found and did
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct CompactReport {
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    /// Blobs stored in more than `max_extents` extents
+    pub fragmented: u64,
+    pub rewritten: u64,
+    pub rewritten_bytes: u64,
+    pub temp_files_removed: u64,
+    pub empty_dirs_removed: u64,
+}
+
+/// Compact the file blob store at `root`
+pub fn compact(root: &Path, options: &CompactOptions) -> io::Result<CompactReport> {
+    let limiter = RateLimiter::new(&options.limits);
+    let mut report = CompactReport::default();
+
+    for dir in fs::read_dir(root)? {
+        let dir = dir?;
+        let prefix = dir.file_name().to_string_lossy().into_owned();
+        if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+            continue;
+        }
+
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let name = file.file_name().to_string_lossy().into_owned();
+            let path = file.path();
+            limiter.throttle_read(0);
+
+            if name.ends_with(".tmp") {
+                // Gone already if its put has just finished
+                let Ok(metadata) = file.metadata() else {
+                    continue;
+                };
+                let modified = metadata.modified()?;
+                let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+                if age >= options.temp_grace {
+                    if !options.dry_run {
+                        remove_if_present(&path)?;
+                    }
+                    report.temp_files_removed += 1;
+                }
+                continue;
+            }
+            if Hash::from_hex(&format!("{}{}", prefix, name)).is_err() {
+                continue;
+            }
+
+            let blob = match File::open(&path) {
+                Ok(blob) => blob,
+                // Deleted by GC since the directory was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let metadata = blob.metadata()?;
+            report.blobs += 1;
+            report.blob_bytes += metadata.len();
+
+            let fragmented = match extent_count(&blob)? {
+                Some(extents) => extents > options.max_extents,
+                None => false,
+            };
+            if fragmented {
+                report.fragmented += 1;
+            }
+            if !(fragmented || options.rewrite_all) {
+                continue;
+            }
+
+            limiter.throttle_write(metadata.len() as usize);
+            if options.dry_run || rewrite(&path, metadata.modified()?)? {
+                report.rewritten += 1;
+                report.rewritten_bytes += metadata.len();
+            }
+        }
+
+        let empty = fs::read_dir(dir.path())?.next().is_none();
+        if empty {
+            if options.dry_run {
+                report.empty_dirs_removed += 1;
+            } else {
+                // Fails harmlessly if a put has just created a blob in it
+                match fs::remove_dir(dir.path()) {
+                    Ok(()) => report.empty_dirs_removed += 1,
+                    Err(e) => log::debug!("Keeping {:?}: {}", dir.path(), e),
+                }
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Copy a blob to a new file and rename it into place. Returns false if
+/// the blob was deleted (by GC) in the meantime.
+fn rewrite(path: &Path, modified: SystemTime) -> io::Result<bool> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+    let tmp = path.with_extension("compact.tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&data)?;
+        file.set_modified(modified)?;
+        file.sync_all()?;
+    }
+    // Not fully race-free: a blob deleted from here to the rename comes
+    // back unreferenced, and the next GC removes it again
+    if !path.exists() {
+        fs::remove_file(&tmp)?;
+        return Ok(false);
+    }
+    fs::rename(&tmp, path)?;
+    Ok(true)
+}
+
+/// Extents a file's data is stored in, or None if the filesystem can't say
+#[cfg(target_os = "linux")]
+fn extent_count(file: &File) -> io::Result<Option<u32>> {
+    use std::os::fd::AsRawFd;
+
+    /// `struct fiemap` without the extent array: with `extent_count` 0 the
+    /// kernel only counts the extents
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+    }
+    const FS_IOC_FIEMAP: u32 = 0xC020_660B;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+
+    let mut map = Fiemap {
+        start: 0,
+        length: u64::MAX,
+        flags: FIEMAP_FLAG_SYNC,
+        mapped_extents: 0,
+        extent_count: 0,
+        reserved: 0,
+    };
+    // SAFETY: FS_IOC_FIEMAP reads and writes a `struct fiemap`, which `map`
+    // matches, and writes no extents since `extent_count` is 0
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), FS_IOC_FIEMAP as _, &mut map) };
+    if result != 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOTTY) => Ok(None),
+            _ => Err(e),
+        };
+    }
+    Ok(Some(map.mapped_extents))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn extent_count(_file: &File) -> io::Result<Option<u32>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::{BlobStore, FileBlobStore};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_compact_tidies_layout() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path()).unwrap();
+        let kept = b"kept blob".to_vec();
+        let deleted = b"deleted blob".to_vec();
+        let (kept_hash, deleted_hash) = (Hash::from_data(&kept), Hash::from_data(&deleted));
+        store.put(&kept_hash, &kept).unwrap();
+        store.put(&deleted_hash, &deleted).unwrap();
+        store.delete(&deleted_hash).unwrap();
+
+        // A stale temp file beside the kept blob
+        let prefix = temp.path().join(&kept_hash.to_hex()[..2]);
+        fs::write(prefix.join("0000.tmp"), b"partial").unwrap();
+        let blob_path = prefix.join(&kept_hash.to_hex()[2..]);
+        let before = fs::metadata(&blob_path).unwrap().modified().unwrap();
+
+        let options = CompactOptions {
+            rewrite_all: true,
+            temp_grace: Duration::ZERO,
+            ..Default::default()
+        };
+        let dry_run = CompactOptions {
+            dry_run: true,
+            ..options.clone()
+        };
+        let dry = compact(temp.path(), &dry_run).unwrap();
+        let report = compact(temp.path(), &options).unwrap();
+        assert_eq!(dry, report);
+        assert_eq!((report.blobs, report.blob_bytes), (1, kept.len() as u64));
+        assert_eq!(report.rewritten, 1);
+        assert_eq!(report.temp_files_removed, 1);
+        let same_prefix = kept_hash.to_hex()[..2] == deleted_hash.to_hex()[..2];
+        assert_eq!(report.empty_dirs_removed, if same_prefix { 0 } else { 1 });
+
+        // The blob reads back unchanged, with its age
+        assert_eq!(store.get(&kept_hash).unwrap(), kept);
+        assert_eq!(fs::metadata(&blob_path).unwrap().modified().unwrap(), before);
+
+        // Nothing left to do, and puts recreate removed directories
+        let again = compact(temp.path(), &options).unwrap();
+        assert_eq!((again.temp_files_removed, again.empty_dirs_removed), (0, 0));
+        store.put(&deleted_hash, &deleted).unwrap();
+        assert_eq!(store.get(&deleted_hash).unwrap(), deleted);
+    }
+}
@@ -0,0 +1,270 @@
+//! Encrypting blob store wrapper
+//!
+//! Encrypts blob contents before handing them to an inner store. Encryption
+//! is convergent: each blob's key is derived from the store key and the
+//! plaintext hash, so identical blocks encrypt identically and still
+//! deduplicate. Blobs are stored under a keyed locator rather than the
+//! plaintext hash, so the inner store can't confirm known content.
+
+use super::{BlobError, BlobResult, BlobStore, Hash, HashAlgorithm, QuotaUsage};
+use crate::storage::SpaceStatus;
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+
+/// Context string for per-blob key derivation
+const KEY_CONTEXT: &str = "aoe-server 2025 convergent blob key v1";
+
+/// Authenticated cipher used for blob contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    Aes256Gcm,
+    XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Identifier stored as the first byte of each blob
+    fn id(self) -> u8 {
+        match self {
+            Cipher::Aes256Gcm => 0x01,
+            Cipher::XChaCha20Poly1305 => 0x02,
+        }
+    }
+}
+
+/// Blob store that encrypts contents with a per-store key
+pub struct EncryptedBlobStore {
+    inner: Box<dyn BlobStore>,
+    key: [u8; 32],
+    cipher: Cipher,
+}
+
+impl EncryptedBlobStore {
+    /// Wrap a blob store.
+    ///
+    /// The inner store must not verify content hashes (see
+    /// `FileBlobStore::unverified`); integrity comes from the cipher's tag.
+    pub fn new(inner: Box<dyn BlobStore>, key: [u8; 32], cipher: Cipher) -> Self {
+        Self { inner, key, cipher }
+    }
+
+    /// Inner store key for a plaintext hash
+    fn locator(&self, hash: &Hash) -> Hash {
+        Hash::from_bytes(blake3::keyed_hash(&self.key, hash.as_bytes()).into())
+    }
+
+    /// Convergent key for a blob
+    fn blob_key(&self, hash: &Hash) -> [u8; 32] {
+        let mut material = [0u8; 64];
+        material[..32].copy_from_slice(&self.key);
+        material[32..].copy_from_slice(hash.as_bytes());
+        blake3::derive_key(KEY_CONTEXT, &material)
+    }
+
+    fn encrypt(&self, hash: &Hash, data: &[u8]) -> BlobResult<Vec<u8>> {
+        // Each key only ever encrypts one plaintext, so a fixed nonce is safe
+        let key = self.blob_key(hash);
+        let ciphertext = match self.cipher {
+            Cipher::Aes256Gcm => Aes256Gcm::new(&key.into()).encrypt(&Default::default(), data),
+            Cipher::XChaCha20Poly1305 => {
+                XChaCha20Poly1305::new(&key.into()).encrypt(&Default::default(), data)
+            }
+        }
+        .map_err(|_| BlobError::Backend("encryption failed".to_string()))?;
+
+        let mut blob = Vec::with_capacity(ciphertext.len() + 1);
+        blob.push(self.cipher.id());
+        blob.extend_from_slice(&ciphertext);
+        Ok(blob)
+    }
+
+    fn decrypt(&self, hash: &Hash, blob: &[u8]) -> BlobResult<Vec<u8>> {
+        let (&id, ciphertext) = blob
+            .split_first()
+            .ok_or_else(|| BlobError::Corrupted(hash.to_hex()))?;
+
+        let key = self.blob_key(hash);
+        let plaintext = if id == Cipher::Aes256Gcm.id() {
+            Aes256Gcm::new(&key.into()).decrypt(&Default::default(), ciphertext)
+        } else if id == Cipher::XChaCha20Poly1305.id() {
+            XChaCha20Poly1305::new(&key.into()).decrypt(&Default::default(), ciphertext)
+        } else {
+            return Err(BlobError::Backend(format!("unknown cipher id {:#04x}", id)));
+        }
+        .map_err(|_| BlobError::Corrupted(hash.to_hex()))?;
+
+        if self.hash_algorithm().hash(&plaintext) != *hash {
+            return Err(BlobError::Corrupted(hash.to_hex()));
+        }
+
+        Ok(plaintext)
+    }
+}
+
+impl BlobStore for EncryptedBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        self.put_many(&[(*hash, data)])
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        let locator = self.locator(hash);
+        let blob = self.inner.get(&locator).map_err(|e| match e {
+            BlobError::NotFound(_) => BlobError::NotFound(hash.to_hex()),
+            other => other,
+        })?;
+        self.decrypt(hash, &blob)
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        self.inner.exists(&self.locator(hash))
+    }
+
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        for (hash, data) in blobs {
+            let actual_hash = self.hash_algorithm().hash(data);
+            if actual_hash != *hash {
+                return Err(BlobError::Corrupted(format!(
+                    "hash mismatch: expected {}, got {}",
+                    hash, actual_hash
+                )));
+            }
+        }
+
+        let locators: Vec<Hash> = blobs.iter().map(|(hash, _)| self.locator(hash)).collect();
+        let exists = self.inner.exists_many(&locators)?;
+        let mut sealed = Vec::new();
+        for ((hash, data), (locator, exists)) in blobs.iter().zip(locators.iter().zip(exists)) {
+            if !exists {
+                sealed.push((*locator, self.encrypt(hash, data)?));
+            }
+        }
+        let sealed: Vec<(Hash, &[u8])> =
+            sealed.iter().map(|(locator, blob)| (*locator, blob.as_slice())).collect();
+        self.inner.put_many(&sealed)
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        let locators: Vec<Hash> = hashes.iter().map(|hash| self.locator(hash)).collect();
+        let blobs = self.inner.get_many(&locators).map_err(|e| match e {
+            BlobError::NotFound(missing) => {
+                let index = locators.iter().position(|locator| locator.to_hex() == missing);
+                BlobError::NotFound(index.map_or(missing, |index| hashes[index].to_hex()))
+            }
+            other => other,
+        })?;
+        hashes.iter().zip(blobs).map(|(hash, blob)| self.decrypt(hash, &blob)).collect()
+    }
+
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        let locators: Vec<Hash> = hashes.iter().map(|hash| self.locator(hash)).collect();
+        self.inner.exists_many(&locators)
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        self.inner.delete(&self.locator(hash))
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        self.inner.sync()
+    }
+
+    fn check_health(&self) -> BlobResult<()> {
+        self.inner.check_health()
+    }
+
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        self.inner.space()
+    }
+
+    fn quota(&self) -> Option<QuotaUsage> {
+        self.inner.quota()
+    }
+
+    /// Plaintexts are keyed by the inner store's algorithm, which keeps it
+    /// though it doesn't check it
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.inner.hash_algorithm()
+    }
+}
+
+/// Parse a 256-bit key given as 64 hex characters or 32 raw bytes
+/// (as read from a key file)
+pub fn parse_key(material: &[u8]) -> BlobResult<[u8; 32]> {
+    let trimmed = material.trim_ascii();
+    let bytes = if trimmed.len() == 64 {
+        hex::decode(trimmed).map_err(|e| BlobError::Backend(format!("invalid hex key: {}", e)))?
+    } else {
+        material.to_vec()
+    };
+
+    bytes
+        .try_into()
+        .map_err(|_| BlobError::Backend("encryption key must be 32 bytes".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::FileBlobStore;
+    use tempfile::TempDir;
+
+    fn make_store(temp: &TempDir, cipher: Cipher) -> EncryptedBlobStore {
+        let inner = FileBlobStore::unverified(temp.path()).unwrap();
+        EncryptedBlobStore::new(Box::new(inner), [7u8; 32], cipher)
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        for cipher in [Cipher::Aes256Gcm, Cipher::XChaCha20Poly1305] {
+            let temp = TempDir::new().unwrap();
+            let store = make_store(&temp, cipher);
+
+            let data = b"secret sector contents";
+            let hash = Hash::from_data(data);
+            store.put(&hash, data).unwrap();
+            assert!(store.exists(&hash).unwrap());
+            assert_eq!(store.get(&hash).unwrap(), data);
+
+            // Neither the plaintext nor its hash are visible to the inner store
+            let raw = FileBlobStore::unverified(temp.path()).unwrap();
+            assert!(!raw.exists(&hash).unwrap());
+            let stored = raw.get(&store.locator(&hash)).unwrap();
+            assert!(!stored.windows(data.len()).any(|w| w == data));
+        }
+    }
+
+    #[test]
+    fn test_encryption_is_convergent() {
+        let temp = TempDir::new().unwrap();
+        let store = make_store(&temp, Cipher::Aes256Gcm);
+
+        let hash = Hash::from_data(b"same");
+        assert_eq!(store.encrypt(&hash, b"same").unwrap(), store.encrypt(&hash, b"same").unwrap());
+    }
+
+    #[test]
+    fn test_wrong_key_detected() {
+        let temp = TempDir::new().unwrap();
+        let store = make_store(&temp, Cipher::XChaCha20Poly1305);
+
+        let data = b"payload";
+        let hash = Hash::from_data(data);
+        store.put(&hash, data).unwrap();
+
+        let blob = store.inner.get(&store.locator(&hash)).unwrap();
+        let other = EncryptedBlobStore::new(
+            Box::new(FileBlobStore::unverified(temp.path()).unwrap()),
+            [8u8; 32],
+            Cipher::XChaCha20Poly1305,
+        );
+        assert!(matches!(other.decrypt(&hash, &blob), Err(BlobError::Corrupted(_))));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let hex_key = "ab".repeat(32);
+        assert_eq!(parse_key(hex_key.as_bytes()).unwrap(), [0xAB; 32]);
+        assert_eq!(parse_key(&[0x11; 32]).unwrap(), [0x11; 32]);
+        assert!(parse_key(b"short").is_err());
+    }
+}
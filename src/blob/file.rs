@@ -2,16 +2,24 @@
 //!
 //! Stores blobs as files in a directory structure.
 
-use super::{BlobError, BlobResult, BlobStore, Hash};
+use super::{BlobError, BlobResult, BlobStore, Hash, HashAlgorithm};
+use crate::storage::health::{available_space, fs_space, space_left, SpaceReserve, SpaceStatus};
+use crate::storage::{sync_parent_dir, StorageError};
 use std::fs::{self, File};
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// File in the store root naming its hash algorithm. Stores without one
+/// predate the choice and are BLAKE3.
+const HASH_FILE: &str = "hash";
 
 /// File-based blob store
 ///
 /// Directory structure:
 /// ```text
 /// root/
+///   hash           (hash algorithm, if not BLAKE3)
 ///   ab/
 ///     ab3f7c9d...  (first 2 chars = subdirectory)
 ///   cd/
@@ -19,15 +27,128 @@ use std::path::{Path, PathBuf};
 /// ```
 pub struct FileBlobStore {
     root: PathBuf,
+    /// Hash blobs are keyed by
+    algorithm: HashAlgorithm,
+    /// Check that blobs hash to their key on put/get
+    verify: bool,
+    /// Free space to keep back; new blobs are refused below it
+    reserve: Option<SpaceReserve>,
 }
 
 impl FileBlobStore {
-    /// Create a new file blob store at the given path
+    /// Create a new file blob store at the given path, keyed by the hash
+    /// algorithm recorded there (BLAKE3 for a new store)
     pub fn new<P: AsRef<Path>>(root: P) -> BlobResult<Self> {
         let root = root.as_ref().to_path_buf();
         fs::create_dir_all(&root)?;
+        let algorithm = recorded_algorithm(&root)?.unwrap_or_default();
+
+        Ok(Self {
+            root,
+            algorithm,
+            verify: true,
+            reserve: None,
+        })
+    }
+
+    /// Create a store that doesn't check blobs against their keys.
+    ///
+    /// For wrappers such as `EncryptedBlobStore` that store blobs under a
+    /// derived key and authenticate contents themselves.
+    pub fn unverified<P: AsRef<Path>>(root: P) -> BlobResult<Self> {
+        Ok(Self {
+            verify: false,
+            ..Self::new(root)?
+        })
+    }
+
+    /// Key blobs by `algorithm`, recording it with a new store. Fails if
+    /// the store already holds blobs keyed by another algorithm.
+    pub fn with_hash_algorithm(mut self, algorithm: HashAlgorithm) -> BlobResult<Self> {
+        let recorded = match recorded_algorithm(&self.root)? {
+            Some(recorded) => recorded,
+            None if self.is_empty()? => {
+                if algorithm != HashAlgorithm::Blake3 {
+                    record_algorithm(&self.root, algorithm)?;
+                }
+                algorithm
+            }
+            None => HashAlgorithm::Blake3,
+        };
+        if recorded != algorithm {
+            return Err(BlobError::Backend(format!(
+                "{:?} holds blobs keyed by {}, not {}",
+                self.root, recorded, algorithm
+            )));
+        }
+        self.algorithm = algorithm;
+        Ok(self)
+    }
+
+    /// Refuse new blobs while the filesystem has less free space than
+    /// `reserve` wants kept back
+    pub fn with_reserve(mut self, reserve: SpaceReserve) -> Self {
+        self.reserve = Some(reserve);
+        self
+    }
+
+    /// Blob error for a failed write, saying how much space is left if the
+    /// filesystem is full
+    fn write_error(&self, e: io::Error) -> BlobError {
+        if e.raw_os_error() != Some(libc::ENOSPC) {
+            return e.into();
+        }
+        match File::open(&self.root) {
+            Ok(root) => {
+                let (available, reserve) = space_left(&root, self.reserve.as_ref());
+                BlobError::NoSpace { available, reserve }
+            }
+            Err(_) => e.into(),
+        }
+    }
+
+    /// Every blob in the store with its size in bytes, oldest modified
+    /// first (a rough least-recently-written order)
+    pub fn blobs(&self) -> io::Result<Vec<(Hash, u64)>> {
+        let mut blobs = self.dated_blobs()?;
+        blobs.sort_by_key(|(modified, _, _)| *modified);
+        Ok(blobs.into_iter().map(|(_, hash, size)| (hash, size)).collect())
+    }
 
-        Ok(Self { root })
+    /// Every blob in the store with its modification time and size, in no
+    /// particular order
+    pub fn dated_blobs(&self) -> io::Result<Vec<(SystemTime, Hash, u64)>> {
+        let mut blobs = Vec::new();
+        for dir in fs::read_dir(&self.root)? {
+            let dir = dir?;
+            let prefix = dir.file_name().to_string_lossy().into_owned();
+            if prefix.len() != 2 || !dir.file_type()?.is_dir() {
+                continue;
+            }
+            for file in fs::read_dir(dir.path())? {
+                let file = file?;
+                let name = file.file_name().to_string_lossy().into_owned();
+                // Leftover temp files and anything else aren't blobs
+                let Ok(hash) = Hash::from_hex(&format!("{}{}", prefix, name)) else {
+                    continue;
+                };
+                let metadata = file.metadata()?;
+                blobs.push((metadata.modified()?, hash, metadata.len()));
+            }
+        }
+        Ok(blobs)
+    }
+
+    /// Whether the store holds no blobs (ignoring anything but the blob
+    /// directories)
+    fn is_empty(&self) -> io::Result<bool> {
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_name().len() == 2 && entry.file_type()?.is_dir() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
     }
 
     /// Get the file path for a hash
@@ -55,14 +176,25 @@ impl BlobStore for FileBlobStore {
         }
 
         // Verify hash matches content
-        let actual_hash = Hash::from_data(data);
-        if actual_hash != *hash {
+        let actual_hash = self.algorithm.hash(data);
+        if self.verify && actual_hash != *hash {
             return Err(BlobError::Corrupted(format!(
                 "hash mismatch: expected {}, got {}",
                 hash, actual_hash
             )));
         }
 
+        if let Some(reserve) = &self.reserve {
+            let root = File::open(&self.root)?;
+            match reserve.check(&root) {
+                Ok(()) => {}
+                Err(StorageError::NoSpace { available, reserve }) => {
+                    return Err(BlobError::NoSpace { available, reserve })
+                }
+                Err(e) => return Err(BlobError::Backend(e.to_string())),
+            }
+        }
+
         // Create directory if needed
         let dir = self.dir_for(hash);
         fs::create_dir_all(&dir)?;
@@ -70,12 +202,20 @@ impl BlobStore for FileBlobStore {
         // Write to temp file, then rename for atomicity
         let tmp_path = path.with_extension("tmp");
         {
-            let mut file = File::create(&tmp_path)?;
-            file.write_all(data)?;
-            file.sync_all()?;
+            let mut file = match File::create(&tmp_path) {
+                // Compaction removed the directory as it emptied
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    fs::create_dir_all(&dir)?;
+                    File::create(&tmp_path)?
+                }
+                result => result.map_err(|e| self.write_error(e))?,
+            };
+            file.write_all(data)
+                .and_then(|()| file.sync_all())
+                .map_err(|e| self.write_error(e))?;
         }
 
-        fs::rename(tmp_path, path)?;
+        fs::rename(tmp_path, path).map_err(|e| self.write_error(e))?;
         Ok(())
     }
 
@@ -89,8 +229,7 @@ impl BlobStore for FileBlobStore {
         let data = fs::read(&path)?;
 
         // Verify integrity
-        let actual_hash = Hash::from_data(&data);
-        if actual_hash != *hash {
+        if self.verify && self.algorithm.hash(&data) != *hash {
             return Err(BlobError::Corrupted(hash.to_hex()));
         }
 
@@ -101,6 +240,16 @@ impl BlobStore for FileBlobStore {
         Ok(self.path_for(hash).exists())
     }
 
+    fn size(&self, hash: &Hash) -> BlobResult<u64> {
+        match fs::metadata(self.path_for(hash)) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                Err(BlobError::NotFound(hash.to_hex()))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
     fn delete(&self, hash: &Hash) -> BlobResult<()> {
         let path = self.path_for(hash);
         if path.exists() {
@@ -113,6 +262,67 @@ impl BlobStore for FileBlobStore {
         // Files are synced on write, nothing to do
         Ok(())
     }
+
+    fn check_health(&self) -> BlobResult<()> {
+        let root = fs::File::open(&self.root)?;
+        if !root.metadata()?.is_dir() {
+            return Err(BlobError::Backend(format!("{:?} is not a directory", self.root)));
+        }
+        if available_space(&root)? == 0 {
+            return Err(BlobError::NoSpace {
+                available: 0,
+                reserve: self.reserve.as_ref().map_or(0, SpaceReserve::reserve),
+            });
+        }
+        Ok(())
+    }
+
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        let root = File::open(&self.root)?;
+        let status = match &self.reserve {
+            Some(reserve) => reserve.status(&root)?,
+            None => {
+                let (total, available) = fs_space(&root)?;
+                SpaceStatus {
+                    total_bytes: total,
+                    available_bytes: available,
+                    ..Default::default()
+                }
+            }
+        };
+        Ok(Some(status))
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+
+    fn list(&self) -> BlobResult<Option<Vec<(Hash, u64)>>> {
+        Ok(Some(self.blobs()?))
+    }
+}
+
+/// The hash algorithm recorded in a store root, if any
+fn recorded_algorithm(root: &Path) -> BlobResult<Option<HashAlgorithm>> {
+    match fs::read_to_string(root.join(HASH_FILE)) {
+        Ok(name) => name.trim().parse().map(Some).map_err(BlobError::Backend),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Record the algorithm a new store keys blobs by, durably, as blobs
+/// written after it are read by it
+fn record_algorithm(root: &Path, algorithm: HashAlgorithm) -> io::Result<()> {
+    let path = root.join(HASH_FILE);
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(format!("{}\n", algorithm).as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, &path)?;
+    sync_parent_dir(&path)
 }
 
 #[cfg(test)]
@@ -176,6 +386,39 @@ mod tests {
         assert!(matches!(result, Err(BlobError::Corrupted(_))));
     }
 
+    #[test]
+    fn test_file_blob_store_hash_algorithm() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path())
+            .unwrap()
+            .with_hash_algorithm(HashAlgorithm::Xxh3)
+            .unwrap();
+
+        let data = b"xxh3 keyed";
+        let hash = HashAlgorithm::Xxh3.hash(data);
+        store.put(&hash, data).unwrap();
+        assert!(matches!(
+            store.put(&Hash::from_data(data), data),
+            Err(BlobError::Corrupted(_))
+        ));
+
+        // Reopened, the store keeps its algorithm and refuses another
+        let store = FileBlobStore::new(temp.path()).unwrap();
+        assert_eq!(store.hash_algorithm(), HashAlgorithm::Xxh3);
+        assert_eq!(store.get(&hash).unwrap(), data);
+        let store = FileBlobStore::new(temp.path()).unwrap();
+        assert!(store.with_hash_algorithm(HashAlgorithm::Sha256).is_err());
+
+        // Stores from before the choice are BLAKE3
+        let legacy = TempDir::new().unwrap();
+        let store = FileBlobStore::new(legacy.path()).unwrap();
+        store.put(&Hash::from_data(data), data).unwrap();
+        let store = FileBlobStore::new(legacy.path()).unwrap();
+        assert!(store.with_hash_algorithm(HashAlgorithm::Xxh3).is_err());
+        let store = FileBlobStore::new(legacy.path()).unwrap();
+        assert!(store.with_hash_algorithm(HashAlgorithm::Blake3).is_ok());
+    }
+
     #[test]
     fn test_file_blob_store_delete() {
         let temp = TempDir::new().unwrap();
@@ -0,0 +1,697 @@
+//! Online blob store migration
+//!
+//! A CAS target's blob store is wrapped in a `MigratingBlobStore` so its
+//! blobs can be moved to another store while the target stays online
+//! (`POST /blob-stores/migrate`, `voe-admin migrate-blobs`):
+//!
+//! 1. Dual write: puts, deletes and syncs go to both stores, reads still
+//!    come from the old one. Puts already in flight finish first, so each
+//!    blob is either listed in the old store or written to both.
+//! 2. Copy: every blob listed in the old store that the new one lacks is
+//!    copied across.
+//! 3. Verify: every listed blob is read back from both stores and compared.
+//! 4. Cutover: with the targets' writes held, so no dual write can fail in
+//!    between, the new store is recorded beside each target's state
+//!    (`e<shelf>.<slot>.blob-store`), taking the configured store's place
+//!    when the server starts again, then reads and writes switch to it at
+//!    once.
+//!
+//! If the new store refuses anything, or the server stops, the migration
+//! fails and the targets carry on with the old store alone. The old store
+//! is never changed, and is left for the operator to remove once the
+//! configuration names the new one.
+//!
+//! Stores are given as `file:<dir>` or `remote:<addr>`; remote stores are
+//! reached without TLS. Only stores that can list their blobs (file
+//! stores, sharded or not) can be migrated from.
+
+use super::{
+    BlobError, BlobResult, BlobStore, FileBlobStore, Hash, HashAlgorithm, QuotaUsage,
+    RemoteBlobStore,
+};
+use crate::cas::CasPoolConfig;
+use crate::shutdown;
+use crate::storage::{sync_parent_dir, SpaceStatus};
+use serde::Serialize;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+
+/// Blobs copied or verified per batch
+const BATCH: usize = 64;
+
+/// A blob store to migrate from or to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlobStoreSpec {
+    /// File blob store in a directory
+    File(PathBuf),
+    /// Blob store on a `cas-server --blob-store` node
+    Remote(String),
+}
+
+impl BlobStoreSpec {
+    /// Open the store, keying blobs by `algorithm`, or by whatever the
+    /// store records if None
+    pub fn open(
+        &self,
+        algorithm: Option<HashAlgorithm>,
+        verify: bool,
+    ) -> BlobResult<Box<dyn BlobStore>> {
+        match self {
+            BlobStoreSpec::File(path) => {
+                let store = if verify {
+                    FileBlobStore::new(path)?
+                } else {
+                    FileBlobStore::unverified(path)?
+                };
+                match algorithm {
+                    Some(algorithm) => Ok(Box::new(store.with_hash_algorithm(algorithm)?)),
+                    None => Ok(Box::new(store)),
+                }
+            }
+            BlobStoreSpec::Remote(addr) => {
+                let store = if verify {
+                    RemoteBlobStore::new(addr, CasPoolConfig::default())?
+                } else {
+                    RemoteBlobStore::unverified(addr, CasPoolConfig::default())?
+                };
+                match algorithm {
+                    Some(algorithm) if store.hash_algorithm() != algorithm => {
+                        Err(BlobError::Backend(format!(
+                            "{} keys blobs by {}, not {}",
+                            self,
+                            store.hash_algorithm(),
+                            algorithm
+                        )))
+                    }
+                    _ => Ok(Box::new(store)),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for BlobStoreSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlobStoreSpec::File(path) => write!(f, "file:{}", path.display()),
+            BlobStoreSpec::Remote(addr) => write!(f, "remote:{}", addr),
+        }
+    }
+}
+
+impl FromStr for BlobStoreSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((_, "")) => Err(format!("blob store {:?} has no location", s)),
+            Some(("file", path)) => Ok(BlobStoreSpec::File(PathBuf::from(path))),
+            Some(("remote", addr)) => Ok(BlobStoreSpec::Remote(addr.to_string())),
+            Some((engine, _)) => Err(format!(
+                "unsupported blob store engine {:?} (file or remote)",
+                engine
+            )),
+            None => Err(format!(
+                "blob store {:?} has no engine, e.g. file:/data/blobs",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the store a target's blobs were moved to is recorded, for the
+/// target `name` keeping its state in `state_dir`
+pub fn record_path(state_dir: &Path, name: &str) -> PathBuf {
+    state_dir.join(format!("{}.blob-store", name))
+}
+
+/// The store a target's blobs were moved to, if they have been
+pub fn load_record(path: &Path) -> io::Result<Option<BlobStoreSpec>> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn save_record(path: &Path, spec: &BlobStoreSpec) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{}\n", spec))?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_parent_dir(path)
+}
+
+struct Stores {
+    spec: BlobStoreSpec,
+    current: Arc<dyn BlobStore>,
+    /// The store being migrated to, while blobs are written to both
+    next: Option<Arc<dyn BlobStore>>,
+    /// Why the new store stopped taking dual writes, failing the migration
+    failed: Option<String>,
+}
+
+impl Stores {
+    /// Fail unless the new store has taken every dual write
+    fn check_next(&self) -> BlobResult<()> {
+        if let Some(reason) = &self.failed {
+            return Err(BlobError::Backend(reason.clone()));
+        }
+        if self.next.is_none() {
+            return Err(BlobError::Backend("migration was abandoned".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Switch to the new store, once `check_next` has passed
+    fn cut_over(&mut self, spec: &BlobStoreSpec) {
+        if let Some(next) = self.next.take() {
+            self.current = next;
+            self.spec = spec.clone();
+        }
+    }
+}
+
+struct Shared {
+    stores: RwLock<Stores>,
+    verify: bool,
+    record: PathBuf,
+}
+
+/// Blob store wrapper whose store can be swapped for another while in use
+///
+/// Clones share the stores, so the one a target manager keeps migrates
+/// the one the backend writes through.
+#[derive(Clone)]
+pub struct MigratingBlobStore {
+    shared: Arc<Shared>,
+}
+
+impl MigratingBlobStore {
+    /// Wrap `store`, opened from `spec`. `verify` is whether the store
+    /// checks blobs against their hash, and a completed migration is
+    /// recorded at `record` (see `record_path`).
+    pub fn new(
+        spec: BlobStoreSpec,
+        store: Box<dyn BlobStore>,
+        verify: bool,
+        record: &Path,
+    ) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                stores: RwLock::new(Stores {
+                    spec,
+                    current: Arc::from(store),
+                    next: None,
+                    failed: None,
+                }),
+                verify,
+                record: record.to_path_buf(),
+            }),
+        }
+    }
+
+    /// The store in use
+    pub fn spec(&self) -> BlobStoreSpec {
+        self.shared.stores.read().unwrap().spec.clone()
+    }
+
+    fn current(&self) -> Arc<dyn BlobStore> {
+        self.shared.stores.read().unwrap().current.clone()
+    }
+
+    fn is_migrating(&self) -> bool {
+        self.shared.stores.read().unwrap().next.is_some()
+    }
+
+    /// Start writing to `next` as well
+    fn begin(&self, next: Arc<dyn BlobStore>) {
+        let mut stores = self.shared.stores.write().unwrap();
+        stores.next = Some(next);
+        stores.failed = None;
+    }
+
+    /// Stop writing to the new store, keeping the old one
+    fn abandon(&self) {
+        self.shared.stores.write().unwrap().next = None;
+    }
+
+    /// Apply `op` to the store in use, then to the new one during a
+    /// migration. The new store refusing fails the migration rather than
+    /// the request.
+    fn both(&self, what: &str, op: impl Fn(&dyn BlobStore) -> BlobResult<()>) -> BlobResult<()> {
+        let stores = self.shared.stores.read().unwrap();
+        op(stores.current.as_ref())?;
+        let Some(next) = &stores.next else {
+            return Ok(());
+        };
+        if stores.failed.is_some() {
+            return Ok(());
+        }
+        if let Err(e) = op(next.as_ref()) {
+            let reason = format!("new blob store failed a {}: {}", what, e);
+            log::warn!("Blob store migration from {}: {}", stores.spec, reason);
+            drop(stores);
+            let mut stores = self.shared.stores.write().unwrap();
+            stores.failed.get_or_insert(reason);
+        }
+        Ok(())
+    }
+
+    fn failure(&self) -> Option<String> {
+        self.shared.stores.read().unwrap().failed.clone()
+    }
+}
+
+impl BlobStore for MigratingBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        self.both("put", |store| store.put(hash, data))
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        self.current().get(hash)
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        self.current().exists(hash)
+    }
+
+    fn size(&self, hash: &Hash) -> BlobResult<u64> {
+        self.current().size(hash)
+    }
+
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        self.both("put", |store| store.put_many(blobs))
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        self.current().get_many(hashes)
+    }
+
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        self.current().exists_many(hashes)
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        self.both("delete", |store| store.delete(hash))
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        self.both("sync", |store| store.sync())
+    }
+
+    fn check_health(&self) -> BlobResult<()> {
+        self.current().check_health()
+    }
+
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        self.current().space()
+    }
+
+    fn quota(&self) -> Option<QuotaUsage> {
+        self.current().quota()
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.current().hash_algorithm()
+    }
+
+    fn list(&self) -> BlobResult<Option<Vec<(Hash, u64)>>> {
+        self.current().list()
+    }
+}
+
+/// Stage a migration is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationState {
+    Copying,
+    Verifying,
+    Done,
+    Failed,
+}
+
+/// Progress of a blob store migration
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub from: String,
+    pub to: String,
+    /// Targets whose blobs are being moved
+    pub targets: Vec<String>,
+    pub state: MigrationState,
+    /// Blobs listed in the old store when copying started
+    pub blobs: u64,
+    /// Blobs copied, not counting those the new store already had
+    pub copied: u64,
+    pub copied_bytes: u64,
+    pub verified: u64,
+    pub error: Option<String>,
+}
+
+impl MigrationStatus {
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.state,
+            MigrationState::Copying | MigrationState::Verifying
+        )
+    }
+}
+
+/// A migration of targets' blobs to another store, run on a thread of
+/// its own
+pub struct BlobMigration {
+    status: Mutex<MigrationStatus>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl BlobMigration {
+    /// Start moving the blobs of `targets`, by name, to `to`. The targets
+    /// must all use the same store.
+    pub fn start(
+        targets: Vec<(String, MigratingBlobStore)>,
+        to: BlobStoreSpec,
+    ) -> BlobResult<Arc<Self>> {
+        let Some((_, first)) = targets.first() else {
+            return Err(BlobError::Backend("no targets to migrate".to_string()));
+        };
+        let from = first.spec();
+        if targets.iter().any(|(_, store)| store.spec() != from) {
+            return Err(BlobError::Backend(
+                "targets use different blob stores".to_string(),
+            ));
+        }
+        if from == to {
+            return Err(BlobError::Backend(format!("targets already use {}", to)));
+        }
+        if let Some((name, _)) = targets.iter().find(|(_, store)| store.is_migrating()) {
+            return Err(BlobError::Backend(format!(
+                "{} is already being migrated",
+                name
+            )));
+        }
+        let verify = targets.iter().all(|(_, store)| store.shared.verify);
+        let next: Arc<dyn BlobStore> = Arc::from(to.open(Some(first.hash_algorithm()), verify)?);
+
+        let migration = Arc::new(Self {
+            status: Mutex::new(MigrationStatus {
+                from: from.to_string(),
+                to: to.to_string(),
+                targets: targets.iter().map(|(name, _)| name.clone()).collect(),
+                state: MigrationState::Copying,
+                blobs: 0,
+                copied: 0,
+                copied_bytes: 0,
+                verified: 0,
+                error: None,
+            }),
+            thread: Mutex::new(None),
+        });
+        log::info!("Migrating blobs from {} to {}", from, to);
+
+        let job = migration.clone();
+        let thread = thread::Builder::new()
+            .name("blob-migrate".to_string())
+            .spawn(move || job.run(&targets, next, &to))?;
+        *migration.thread.lock().unwrap() = Some(thread);
+        Ok(migration)
+    }
+
+    pub fn status(&self) -> MigrationStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    /// Wait for the migration to finish
+    pub fn wait(&self) -> MigrationStatus {
+        if let Some(thread) = self.thread.lock().unwrap().take() {
+            let _ = thread.join();
+        }
+        self.status()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut MigrationStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    fn run(
+        &self,
+        targets: &[(String, MigratingBlobStore)],
+        next: Arc<dyn BlobStore>,
+        to: &BlobStoreSpec,
+    ) {
+        // Once puts in flight have finished, new blobs go to both stores
+        for (_, store) in targets {
+            store.begin(next.clone());
+        }
+        match self.migrate(targets, next.as_ref(), to) {
+            Ok(()) => {
+                log::info!("Blob store migration to {} complete", to);
+                self.update(|status| status.state = MigrationState::Done);
+            }
+            Err(e) => {
+                log::error!("Blob store migration to {} failed: {}", to, e);
+                for (_, store) in targets {
+                    store.abandon();
+                }
+                self.update(|status| {
+                    status.state = MigrationState::Failed;
+                    status.error = Some(e.to_string());
+                });
+            }
+        }
+    }
+
+    fn migrate(
+        &self,
+        targets: &[(String, MigratingBlobStore)],
+        next: &dyn BlobStore,
+        to: &BlobStoreSpec,
+    ) -> BlobResult<()> {
+        let old = targets[0].1.current();
+        let check = || -> BlobResult<()> {
+            if shutdown::requested() {
+                return Err(BlobError::Backend("server is shutting down".to_string()));
+            }
+            match targets.iter().find_map(|(_, store)| store.failure()) {
+                Some(reason) => Err(BlobError::Backend(reason)),
+                None => Ok(()),
+            }
+        };
+
+        let blobs = old.list()?.ok_or_else(|| {
+            BlobError::Backend(format!("{} can't list its blobs", targets[0].1.spec()))
+        })?;
+        self.update(|status| status.blobs = blobs.len() as u64);
+
+        for batch in blobs.chunks(BATCH) {
+            check()?;
+            let hashes: Vec<Hash> = batch.iter().map(|(hash, _)| *hash).collect();
+            let present = next.exists_many(&hashes)?;
+            let mut copied = Vec::new();
+            for (hash, _) in hashes.iter().zip(present).filter(|(_, present)| !present) {
+                match old.get(hash) {
+                    Ok(data) => copied.push((*hash, data)),
+                    // Deleted since listing, from both stores
+                    Err(BlobError::NotFound(_)) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            let puts: Vec<(Hash, &[u8])> = copied
+                .iter()
+                .map(|(hash, data)| (*hash, data.as_slice()))
+                .collect();
+            next.put_many(&puts)?;
+            // A blob deleted while it was copied was only deleted from the
+            // new store before it got there
+            let copied_hashes: Vec<Hash> = puts.iter().map(|(hash, _)| *hash).collect();
+            let kept = old.exists_many(&copied_hashes)?;
+            for (hash, _) in copied_hashes.iter().zip(kept).filter(|(_, kept)| !kept) {
+                next.delete(hash)?;
+            }
+            self.update(|status| {
+                status.copied += puts.len() as u64;
+                status.copied_bytes += puts.iter().map(|(_, data)| data.len() as u64).sum::<u64>();
+            });
+        }
+        next.sync()?;
+
+        self.update(|status| status.state = MigrationState::Verifying);
+        for batch in blobs.chunks(BATCH) {
+            check()?;
+            for (hash, _) in batch {
+                let data = match old.get(hash) {
+                    Ok(data) => data,
+                    Err(BlobError::NotFound(_)) => continue,
+                    Err(e) => return Err(e),
+                };
+                match next.get(hash) {
+                    Ok(copy) if copy == data => {}
+                    Ok(_) => return Err(BlobError::Corrupted(hash.to_hex())),
+                    Err(BlobError::NotFound(_)) => {
+                        return Err(BlobError::Backend(format!("blob {} was not copied", hash)))
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            self.update(|status| status.verified += batch.len() as u64);
+        }
+
+        // With every target's stores held, no dual write can fail the
+        // migration between the last check and the switch. The new store is
+        // recorded for every target before switching any, so a restart
+        // never finds some targets moved and others not.
+        check()?;
+        let mut held: Vec<_> = targets
+            .iter()
+            .map(|(_, store)| store.shared.stores.write().unwrap())
+            .collect();
+        for stores in &held {
+            stores.check_next()?;
+        }
+        let mut saved = Vec::new();
+        for (_, store) in targets {
+            if let Err(e) = save_record(&store.shared.record, to) {
+                for path in saved {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(e.into());
+            }
+            saved.push(&store.shared.record);
+        }
+        for stores in &mut held {
+            stores.cut_over(to);
+        }
+        drop(held);
+        for (name, _) in targets {
+            log::info!("Target {} now uses blob store {}", name, to);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_spec_parsing() {
+        let file: BlobStoreSpec = "file:/data/blobs".parse().unwrap();
+        assert_eq!(file, BlobStoreSpec::File(PathBuf::from("/data/blobs")));
+        assert_eq!(file.to_string(), "file:/data/blobs");
+        let remote: BlobStoreSpec = "remote:10.0.0.5:3260".parse().unwrap();
+        assert_eq!(remote, BlobStoreSpec::Remote("10.0.0.5:3260".to_string()));
+        assert_eq!(remote.to_string().parse::<BlobStoreSpec>().unwrap(), remote);
+
+        let err = "rocksdb:/data/new".parse::<BlobStoreSpec>().unwrap_err();
+        assert!(err.contains("rocksdb"));
+        assert!("/data/blobs".parse::<BlobStoreSpec>().is_err());
+        assert!("file:".parse::<BlobStoreSpec>().is_err());
+    }
+
+    #[test]
+    fn test_online_migration() {
+        let temp = TempDir::new().unwrap();
+        let old_dir = temp.path().join("old");
+        let new_dir = temp.path().join("new");
+        let from = BlobStoreSpec::File(old_dir.clone());
+        let to = BlobStoreSpec::File(new_dir.clone());
+        let record = record_path(temp.path(), "e1.0");
+
+        let store =
+            MigratingBlobStore::new(from.clone(), from.open(None, true).unwrap(), true, &record);
+        let blob = |n: u32| {
+            let data = n.to_le_bytes().repeat(256);
+            (Hash::from_data(&data), data)
+        };
+        for n in 0..500 {
+            let (hash, data) = blob(n);
+            store.put(&hash, &data).unwrap();
+        }
+        let (deleted, _) = blob(0);
+        store.delete(&deleted).unwrap();
+
+        // Writes carry on throughout the migration
+        let stop = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (store, stop) = (store.clone(), stop.clone());
+            thread::spawn(move || {
+                let mut n = 1000;
+                while !stop.load(Ordering::Relaxed) {
+                    let (hash, data) = blob(n);
+                    store.put(&hash, &data).unwrap();
+                    n += 1;
+                }
+                n
+            })
+        };
+
+        let migration =
+            BlobMigration::start(vec![("e1.0".to_string(), store.clone())], to.clone()).unwrap();
+        let status = migration.wait();
+        stop.store(true, Ordering::Relaxed);
+        let written = writer.join().unwrap();
+        assert_eq!(status.state, MigrationState::Done, "{:?}", status.error);
+        // The blobs written before dual writes began, at least
+        assert!(status.blobs >= 499);
+        assert_eq!(status.verified, status.blobs);
+        assert_eq!(store.spec(), to);
+        assert_eq!(load_record(&record).unwrap(), Some(to.clone()));
+
+        // Everything is in the new store, and read from it alone
+        fs::remove_dir_all(&old_dir).unwrap();
+        for n in (1..500).chain(1000..written) {
+            let (hash, data) = blob(n);
+            assert_eq!(store.get(&hash).unwrap(), data);
+        }
+        assert!(!store.exists(&deleted).unwrap());
+
+        // Not to the store already in use
+        assert!(BlobMigration::start(vec![("e1.0".to_string(), store)], to).is_err());
+    }
+
+    #[test]
+    fn test_failed_migration_keeps_old_store() {
+        let temp = TempDir::new().unwrap();
+        let from = BlobStoreSpec::File(temp.path().join("old"));
+        let record = record_path(temp.path(), "e1.0");
+        // A remote store can't be listed, so nothing can migrate from it;
+        // stand one in for the old store with a wrapper that can't list
+        struct Unlisted(Box<dyn BlobStore>);
+        impl BlobStore for Unlisted {
+            fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+                self.0.put(hash, data)
+            }
+            fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+                self.0.get(hash)
+            }
+            fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+                self.0.exists(hash)
+            }
+            fn sync(&self) -> BlobResult<()> {
+                self.0.sync()
+            }
+        }
+        let inner = Unlisted(from.open(None, true).unwrap());
+        let store = MigratingBlobStore::new(from.clone(), Box::new(inner), true, &record);
+        let to = BlobStoreSpec::File(temp.path().join("new"));
+
+        let migration =
+            BlobMigration::start(vec![("e1.0".to_string(), store.clone())], to).unwrap();
+        let status = migration.wait();
+        assert_eq!(status.state, MigrationState::Failed);
+        assert!(status.error.unwrap().contains("can't list"));
+        assert_eq!(store.spec(), from);
+        assert!(!store.is_migrating());
+        assert_eq!(load_record(&record).unwrap(), None);
+    }
+}
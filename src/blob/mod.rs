@@ -2,10 +2,23 @@
 //!
 //! Defines the BlobStore trait for content-addressed storage backends.
 
+pub mod compact;
+pub mod encrypted;
 pub mod file;
+pub mod migrate;
+pub mod quota;
+pub mod remote;
+pub mod sharded;
+pub mod tiered;
+pub mod timed;
 
+use crate::storage::SpaceStatus;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::fmt;
+use std::str::FromStr;
 use thiserror::Error;
+use xxhash_rust::xxh3::xxh3_128;
 
 /// Blob storage errors
 #[derive(Debug, Error)]
@@ -24,6 +37,13 @@ pub enum BlobError {
 
     #[error("write quorum not met")]
     QuorumNotMet,
+
+    #[error("no space left: {available} bytes free, {reserve} reserved")]
+    NoSpace { available: u64, reserve: u64 },
+
+    /// The target has added its quota's worth of blobs to the store
+    #[error("blob quota exceeded: {used} of {quota} bytes used")]
+    QuotaExceeded { used: u64, quota: u64 },
 }
 
 /// Result type for blob operations
@@ -42,7 +62,7 @@ impl Hash {
         Hash(bytes)
     }
 
-    /// Compute hash of data
+    /// Compute hash of data (BLAKE3; see `HashAlgorithm` for the others)
     pub fn from_data(data: &[u8]) -> Self {
         Hash(blake3::hash(data).into())
     }
@@ -86,6 +106,74 @@ impl fmt::Display for Hash {
     }
 }
 
+/// Content hash a blob store keys its blobs by
+///
+/// Chosen when a store is created and recorded with it; a store only ever
+/// holds blobs keyed by one algorithm.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgorithm {
+    /// BLAKE3, the default
+    #[default]
+    Blake3,
+    /// XXH3-128, several times cheaper than BLAKE3 at high IOPS but not
+    /// collision resistant, so writers compare every dedup hit with the
+    /// stored blob. The hash fills the first 16 bytes of a `Hash`; the
+    /// rest are zero.
+    Xxh3,
+    /// SHA-256, for deployments that require a FIPS-approved hash
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Hash a blob's contents
+    pub fn hash(self, data: &[u8]) -> Hash {
+        match self {
+            HashAlgorithm::Blake3 => Hash::from_data(data),
+            HashAlgorithm::Xxh3 => {
+                let mut bytes = [0u8; 32];
+                bytes[..16].copy_from_slice(&xxh3_128(data).to_le_bytes());
+                Hash(bytes)
+            }
+            HashAlgorithm::Sha256 => Hash(Sha256::digest(data).into()),
+        }
+    }
+
+    /// Whether finding a blob by hash means finding the same contents;
+    /// dedup hits under other algorithms need checking
+    pub fn is_collision_resistant(self) -> bool {
+        self != HashAlgorithm::Xxh3
+    }
+
+    /// Name as recorded with a store and used in configuration
+    pub fn name(self) -> &'static str {
+        match self {
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake3" => Ok(HashAlgorithm::Blake3),
+            "xxh3" => Ok(HashAlgorithm::Xxh3),
+            "sha256" => Ok(HashAlgorithm::Sha256),
+            _ => Err(format!("unknown hash algorithm {:?} (blake3, xxh3 or sha256)", s)),
+        }
+    }
+}
+
 /// Blob store trait - simple key-value interface for content-addressed storage
 pub trait BlobStore: Send + Sync {
     /// Store a blob, keyed by its hash.
@@ -99,6 +187,28 @@ pub trait BlobStore: Send + Sync {
     /// Check if blob exists without fetching.
     fn exists(&self, hash: &Hash) -> BlobResult<bool>;
 
+    /// Store several blobs. Stores with a round trip per request (remote
+    /// ones) override the batch methods to make their requests together.
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        blobs.iter().try_for_each(|(hash, data)| self.put(hash, data))
+    }
+
+    /// Retrieve several blobs, in order.
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        hashes.iter().map(|hash| self.get(hash)).collect()
+    }
+
+    /// Check several blobs exist, in order.
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        hashes.iter().map(|hash| self.exists(hash)).collect()
+    }
+
+    /// Stored size of a blob. The default reads it; stores that can tell
+    /// without reading override this.
+    fn size(&self, hash: &Hash) -> BlobResult<u64> {
+        self.get(hash).map(|data| data.len() as u64)
+    }
+
     /// Delete a blob (optional, may be no-op for archival).
     fn delete(&self, _hash: &Hash) -> BlobResult<()> {
         Ok(()) // Default: ignore deletes
@@ -106,10 +216,42 @@ pub trait BlobStore: Send + Sync {
 
     /// Sync any pending writes.
     fn sync(&self) -> BlobResult<()>;
+
+    /// Check the store is reachable and can take new blobs.
+    fn check_health(&self) -> BlobResult<()> {
+        Ok(())
+    }
+
+    /// Free space where the blobs are kept, if the store can tell.
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        Ok(None)
+    }
+
+    /// Bytes attributed to the target using this store, if tracked.
+    fn quota(&self) -> Option<QuotaUsage> {
+        None
+    }
+
+    /// Hash the store keys blobs by; writers must key blobs with it.
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        HashAlgorithm::Blake3
+    }
+
+    /// Every blob held and its stored size, if the store can list them.
+    fn list(&self) -> BlobResult<Option<Vec<(Hash, u64)>>> {
+        Ok(None)
+    }
 }
 
 // Re-export implementations
+pub use encrypted::{Cipher, EncryptedBlobStore};
 pub use file::FileBlobStore;
+pub use migrate::{BlobMigration, BlobStoreSpec, MigratingBlobStore, MigrationStatus};
+pub use quota::{QuotaBlobStore, QuotaUsage};
+pub use remote::RemoteBlobStore;
+pub use sharded::{ShardStatus, ShardedBlobStore};
+pub use tiered::TieredBlobStore;
+pub use timed::TimedBlobStore;
 
 #[cfg(test)]
 mod tests {
@@ -134,6 +276,24 @@ mod tests {
         assert_eq!(hash, hash2);
     }
 
+    #[test]
+    fn test_hash_algorithms() {
+        let data = b"hello world";
+        assert_eq!(HashAlgorithm::Blake3.hash(data), Hash::from_data(data));
+        let xxh3 = HashAlgorithm::Xxh3.hash(data);
+        assert_eq!(&xxh3.as_bytes()[16..], &[0; 16]);
+        assert_ne!(xxh3, HashAlgorithm::Xxh3.hash(b"hello worle"));
+        assert_eq!(
+            HashAlgorithm::Sha256.hash(b"abc").to_hex(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+
+        for algorithm in [HashAlgorithm::Blake3, HashAlgorithm::Xxh3, HashAlgorithm::Sha256] {
+            assert_eq!(algorithm.name().parse::<HashAlgorithm>(), Ok(algorithm));
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+
     #[test]
     fn test_hash_zero() {
         assert!(Hash::ZERO.is_zero());
@@ -0,0 +1,221 @@
+//! Per-target blob store quotas
+//!
+//! Several CAS targets can share one blob store. Each target's view of the
+//! store is wrapped so the blobs it adds are attributed to it: a put of a
+//! blob the store doesn't have yet counts its stored size, while blobs
+//! already present (written by any target) are free. With a quota set,
+//! puts that would take a target past it are refused with
+//! `BlobError::QuotaExceeded`. Deleting a blob gives its bytes back to the
+//! target that deletes it, whichever target added it: once it's gone, no
+//! target holds it.
+//!
+//! Attributed bytes are saved to a small file on every sync, so they
+//! survive restarts.
+
+use super::{BlobError, BlobResult, BlobStore, Hash, HashAlgorithm};
+use crate::storage::SpaceStatus;
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bytes a target has added to its blob store, against its quota
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct QuotaUsage {
+    pub attributed_bytes: u64,
+    /// None: tracked but not limited
+    pub quota_bytes: Option<u64>,
+}
+
+/// Blob store wrapper attributing new blobs to one target
+pub struct QuotaBlobStore {
+    inner: Box<dyn BlobStore>,
+    quota_bytes: Option<u64>,
+    attributed: AtomicU64,
+    /// Where attributed bytes are saved
+    usage_path: PathBuf,
+    /// Last saved value, to skip needless writes
+    saved: AtomicU64,
+}
+
+impl QuotaBlobStore {
+    /// Attribute new blobs in `inner`, resuming the count saved at
+    /// `usage_path`, and refuse puts past `quota_bytes` if set
+    pub fn new(
+        inner: Box<dyn BlobStore>,
+        quota_bytes: Option<u64>,
+        usage_path: impl Into<PathBuf>,
+    ) -> io::Result<Self> {
+        let usage_path = usage_path.into();
+        let attributed = match fs::read_to_string(&usage_path) {
+            Ok(saved) => saved.trim().parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad usage file {:?}: {}", usage_path, e),
+                )
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            inner,
+            quota_bytes,
+            attributed: AtomicU64::new(attributed),
+            usage_path,
+            saved: AtomicU64::new(attributed),
+        })
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let attributed = self.attributed.load(Ordering::Relaxed);
+        if self.saved.swap(attributed, Ordering::Relaxed) == attributed {
+            return Ok(());
+        }
+        let tmp = self.usage_path.with_extension("tmp");
+        fs::write(&tmp, attributed.to_string())?;
+        fs::rename(tmp, &self.usage_path)
+    }
+}
+
+impl BlobStore for QuotaBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        self.put_many(&[(*hash, data)])
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        self.inner.get(hash)
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        self.inner.exists(hash)
+    }
+
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        let hashes: Vec<Hash> = blobs.iter().map(|(hash, _)| *hash).collect();
+        let exists = self.inner.exists_many(&hashes)?;
+        let new: Vec<(Hash, &[u8])> = blobs
+            .iter()
+            .zip(exists)
+            .filter(|(_, exists)| !exists)
+            .map(|(blob, _)| *blob)
+            .collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+
+        let len: u64 = new.iter().map(|(_, data)| data.len() as u64).sum();
+        let used = self.attributed.fetch_add(len, Ordering::Relaxed);
+        if let Some(quota) = self.quota_bytes {
+            if used + len > quota {
+                self.attributed.fetch_sub(len, Ordering::Relaxed);
+                return Err(BlobError::QuotaExceeded { used, quota });
+            }
+        }
+        self.inner.put_many(&new).inspect_err(|_| {
+            self.attributed.fetch_sub(len, Ordering::Relaxed);
+        })
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        self.inner.get_many(hashes)
+    }
+
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        self.inner.exists_many(hashes)
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        let size = match self.inner.size(hash) {
+            Ok(size) => size,
+            Err(BlobError::NotFound(_)) => return self.inner.delete(hash),
+            Err(e) => return Err(e),
+        };
+        self.inner.delete(hash)?;
+        let _ = self
+            .attributed
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |attributed| {
+                Some(attributed.saturating_sub(size))
+            });
+        Ok(())
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        self.inner.sync()?;
+        Ok(self.save()?)
+    }
+
+    fn check_health(&self) -> BlobResult<()> {
+        self.inner.check_health()
+    }
+
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        self.inner.space()
+    }
+
+    fn quota(&self) -> Option<QuotaUsage> {
+        Some(QuotaUsage {
+            attributed_bytes: self.attributed.load(Ordering::Relaxed),
+            quota_bytes: self.quota_bytes,
+        })
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.inner.hash_algorithm()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::FileBlobStore;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_quota_attribution() {
+        let temp = TempDir::new().unwrap();
+        let blobs = temp.path().join("blobs");
+        let usage = temp.path().join("e1.0.usage");
+        let open = |quota| {
+            let shared = FileBlobStore::new(&blobs).unwrap();
+            QuotaBlobStore::new(Box::new(shared), quota, &usage).unwrap()
+        };
+        let blob = |i: u8| (Hash::from_data(&[i; 100]), vec![i; 100]);
+
+        let store = open(Some(250));
+        for i in 0..2 {
+            let (hash, data) = blob(i);
+            store.put(&hash, &data).unwrap();
+        }
+        // Already stored blobs are free
+        let (hash, data) = blob(0);
+        store.put(&hash, &data).unwrap();
+        assert_eq!(store.quota().unwrap().attributed_bytes, 200);
+
+        let (hash, data) = blob(2);
+        match store.put(&hash, &data) {
+            Err(BlobError::QuotaExceeded { used, quota }) => assert_eq!((used, quota), (200, 250)),
+            other => panic!("expected QuotaExceeded, got {:?}", other),
+        }
+        assert!(!store.exists(&hash).unwrap());
+
+        // The count survives a restart once synced
+        store.sync().unwrap();
+        drop(store);
+        let store = open(None);
+        assert_eq!(
+            store.quota(),
+            Some(QuotaUsage {
+                attributed_bytes: 200,
+                quota_bytes: None
+            })
+        );
+        store.put(&hash, &data).unwrap();
+        assert_eq!(store.quota().unwrap().attributed_bytes, 300);
+
+        // Deleting gives the bytes back; deleting what's gone gives nothing
+        store.delete(&hash).unwrap();
+        store.delete(&hash).unwrap();
+        assert_eq!(store.quota().unwrap().attributed_bytes, 200);
+    }
+}
@@ -0,0 +1,322 @@
+//! Blob store on a remote CAS server
+//!
+//! Keeps blobs on a `cas-server --blob-store` node, over the same TCP
+//! protocol iSCSI targets use for their CAS storage, so an aoe-server head
+//! needs no blob storage of its own and several heads can share one store.
+//! Requests go through a `CasPool`, so they survive server restarts the
+//! way iSCSI targets' requests do, and several connections serve
+//! concurrent readers. Batches (`put_many`, `get_many`, `exists_many`)
+//! are pipelined on one connection, so a multi-block read or write costs
+//! a round trip or two rather than one per block. Blobs are keyed by the
+//! hash algorithm of the server's store, which it names when pinged.
+//!
+//! Free space is the server's, asked for on each `space` call; servers
+//! from before it could be asked fail the call. A quota is kept by the
+//! `QuotaBlobStore` wrapping this store on the head, so the store itself
+//! attributes nothing. Blobs can't be listed, as a shared store holds
+//! other heads' blobs too and more than fit in a frame, so targets can't
+//! be migrated off a remote store.
+
+use super::{BlobError, BlobResult, BlobStore, Hash, HashAlgorithm};
+use crate::cas::protocol::parse_space;
+use crate::cas::{CasCommand, CasPool, CasPoolConfig};
+use crate::storage::SpaceStatus;
+
+/// Blob store served by a remote CAS server
+pub struct RemoteBlobStore {
+    pool: CasPool,
+    /// Hash the server's store keys blobs by
+    algorithm: HashAlgorithm,
+    /// Check that blobs hash to their key on get
+    verify: bool,
+}
+
+type Response = (CasCommand, Vec<u8>);
+
+impl RemoteBlobStore {
+    /// Connect to the server at `addr` (`host:port` or `unix:/path`)
+    pub fn new(addr: &str, config: CasPoolConfig) -> BlobResult<Self> {
+        let pool = CasPool::connect(addr, config)?;
+        let algorithm = match checked(pool.request(CasCommand::Ping, &[])?)? {
+            // Servers from before the choice only serve BLAKE3 stores
+            (CasCommand::Ping, name) if name.is_empty() => HashAlgorithm::Blake3,
+            (CasCommand::Ping, name) => String::from_utf8_lossy(&name)
+                .parse()
+                .map_err(BlobError::Backend)?,
+            _ => return Err(invalid(CasCommand::Ping)),
+        };
+        Ok(Self {
+            pool,
+            algorithm,
+            verify: true,
+        })
+    }
+
+    /// Connect to a store that doesn't check blobs against their keys,
+    /// for wrappers such as `EncryptedBlobStore` (see
+    /// `FileBlobStore::unverified`). The server must not check them either.
+    pub fn unverified(addr: &str, config: CasPoolConfig) -> BlobResult<Self> {
+        Ok(Self {
+            verify: false,
+            ..Self::new(addr, config)?
+        })
+    }
+
+    fn request(&self, command: CasCommand, data: &[u8]) -> BlobResult<Response> {
+        checked(self.pool.request(command, data)?)
+    }
+
+    /// Send a batch of requests in one round trip
+    fn pipeline<D: AsRef<[u8]> + Sync>(
+        &self,
+        command: CasCommand,
+        requests: &[D],
+    ) -> BlobResult<Vec<Response>> {
+        self.pool.pipeline(command, requests)?.into_iter().map(checked).collect()
+    }
+
+    fn got(&self, hash: &Hash, response: Response) -> BlobResult<Vec<u8>> {
+        match response {
+            (CasCommand::Read, data) => {
+                if self.verify && self.algorithm.hash(&data) != *hash {
+                    return Err(BlobError::Corrupted(hash.to_hex()));
+                }
+                Ok(data)
+            }
+            (CasCommand::Exists, _) => Err(BlobError::NotFound(hash.to_hex())),
+            _ => Err(invalid(CasCommand::Read)),
+        }
+    }
+}
+
+/// Turn an error frame into an error
+fn checked(response: Response) -> BlobResult<Response> {
+    match response {
+        (CasCommand::Error, message) => {
+            Err(BlobError::Backend(String::from_utf8_lossy(&message).into_owned()))
+        }
+        response => Ok(response),
+    }
+}
+
+fn invalid(command: CasCommand) -> BlobError {
+    BlobError::Backend(format!("invalid response to {:?}", command))
+}
+
+fn put_request(hash: &Hash, data: &[u8]) -> Vec<u8> {
+    let mut request = Vec::with_capacity(32 + data.len());
+    request.extend_from_slice(hash.as_bytes());
+    request.extend_from_slice(data);
+    request
+}
+
+fn stored(response: Response) -> BlobResult<()> {
+    match response {
+        (CasCommand::Put, _) => Ok(()),
+        _ => Err(invalid(CasCommand::Put)),
+    }
+}
+
+fn existed(response: Response) -> BlobResult<bool> {
+    match response {
+        (CasCommand::Exists, found) if found.len() == 1 => Ok(found[0] != 0),
+        _ => Err(invalid(CasCommand::Exists)),
+    }
+}
+
+impl BlobStore for RemoteBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        stored(self.request(CasCommand::Put, &put_request(hash, data))?)
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        let response = self.request(CasCommand::Read, hash.as_bytes())?;
+        self.got(hash, response)
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        existed(self.request(CasCommand::Exists, hash.as_bytes())?)
+    }
+
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        let requests: Vec<Vec<u8>> =
+            blobs.iter().map(|(hash, data)| put_request(hash, data)).collect();
+        self.pipeline(CasCommand::Put, &requests)?.into_iter().try_for_each(stored)
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        let requests: Vec<&[u8]> = hashes.iter().map(|hash| &hash.as_bytes()[..]).collect();
+        let responses = self.pipeline(CasCommand::Read, &requests)?;
+        hashes.iter().zip(responses).map(|(hash, response)| self.got(hash, response)).collect()
+    }
+
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        let requests: Vec<&[u8]> = hashes.iter().map(|hash| &hash.as_bytes()[..]).collect();
+        self.pipeline(CasCommand::Exists, &requests)?.into_iter().map(existed).collect()
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        match self.request(CasCommand::Delete, hash.as_bytes())? {
+            (CasCommand::Delete, _) => Ok(()),
+            _ => Err(invalid(CasCommand::Delete)),
+        }
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        match self.request(CasCommand::Sync, &[])? {
+            (CasCommand::Sync, _) => Ok(()),
+            _ => Err(invalid(CasCommand::Sync)),
+        }
+    }
+
+    fn check_health(&self) -> BlobResult<()> {
+        match self.request(CasCommand::Ping, &[])? {
+            (CasCommand::Ping, _) => Ok(()),
+            _ => Err(invalid(CasCommand::Ping)),
+        }
+    }
+
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        match self.request(CasCommand::Space, &[])? {
+            (CasCommand::Space, data) => Ok(parse_space(&data)?),
+            _ => Err(invalid(CasCommand::Space)),
+        }
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.algorithm
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::FileBlobStore;
+    use crate::cas::{CasServer, CasServerConfig};
+    use crate::storage::SpaceReserve;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn start_blob_server(temp: &TempDir) -> String {
+        start_server(FileBlobStore::new(temp.path()).unwrap(), temp)
+    }
+
+    fn start_server(store: FileBlobStore, temp: &TempDir) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let config = CasServerConfig {
+            bind_addr: addr.clone(),
+            storage_path: temp.path().to_string_lossy().into_owned(),
+        };
+        let server = CasServer::for_blob_store(config, Arc::new(store));
+        std::thread::spawn(move || server.serve(listener));
+        addr
+    }
+
+    #[test]
+    fn test_remote_blob_store_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let addr = start_blob_server(&temp);
+        let store = RemoteBlobStore::new(&addr, CasPoolConfig::default()).unwrap();
+        store.check_health().unwrap();
+
+        let data = b"remote blob".to_vec();
+        let hash = Hash::from_data(&data);
+        assert!(!store.exists(&hash).unwrap());
+        assert!(matches!(store.get(&hash), Err(BlobError::NotFound(_))));
+
+        store.put(&hash, &data).unwrap();
+        store.sync().unwrap();
+        assert!(store.exists(&hash).unwrap());
+        assert_eq!(store.get(&hash).unwrap(), data);
+
+        // Kept in the server's store, under the same hash
+        let local = FileBlobStore::new(temp.path()).unwrap();
+        assert_eq!(local.get(&hash).unwrap(), data);
+
+        // The server refuses blobs that don't match their hash
+        let wrong = Hash::from_data(b"something else");
+        assert!(matches!(store.put(&wrong, &data), Err(BlobError::Backend(_))));
+
+        store.delete(&hash).unwrap();
+        assert!(!store.exists(&hash).unwrap());
+    }
+
+    #[test]
+    fn test_remote_blob_store_space() {
+        let temp = TempDir::new().unwrap();
+        let reserve = SpaceReserve::new("blobs", 1 << 20).with_alarm(2 << 20);
+        let store = FileBlobStore::new(temp.path()).unwrap();
+        let addr = start_server(store.with_reserve(reserve), &temp);
+        let store = RemoteBlobStore::new(&addr, CasPoolConfig::default()).unwrap();
+
+        let space = store.space().unwrap().unwrap();
+        assert!(space.total_bytes > 0);
+        assert_eq!(space.reserve_bytes, 1 << 20);
+        assert_eq!(space.alarm_bytes, 2 << 20);
+        assert_eq!(store.quota(), None);
+        assert!(store.list().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_remote_blob_store_batches() {
+        let temp = TempDir::new().unwrap();
+        let addr = start_blob_server(&temp);
+        let store = RemoteBlobStore::new(&addr, CasPoolConfig::default()).unwrap();
+
+        let blobs: Vec<Vec<u8>> = (0..32u32).map(|i| i.to_le_bytes().repeat(1024)).collect();
+        let hashes: Vec<Hash> = blobs.iter().map(|data| Hash::from_data(data)).collect();
+        let batch: Vec<(Hash, &[u8])> =
+            hashes.iter().zip(&blobs).map(|(hash, data)| (*hash, &data[..])).collect();
+
+        store.put_many(&batch[..16]).unwrap();
+        let exists = store.exists_many(&hashes).unwrap();
+        assert_eq!(exists, (0..32).map(|i| i < 16).collect::<Vec<_>>());
+        assert!(matches!(store.get_many(&hashes), Err(BlobError::NotFound(_))));
+
+        store.put_many(&batch).unwrap();
+        assert_eq!(store.get_many(&hashes).unwrap(), blobs);
+    }
+
+    #[test]
+    fn test_remote_blob_store_takes_server_algorithm() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path())
+            .unwrap()
+            .with_hash_algorithm(HashAlgorithm::Sha256)
+            .unwrap();
+        let addr = start_server(store, &temp);
+        let store = RemoteBlobStore::new(&addr, CasPoolConfig::default()).unwrap();
+        assert_eq!(store.hash_algorithm(), HashAlgorithm::Sha256);
+
+        let data = b"sha-256 keyed".to_vec();
+        let hash = HashAlgorithm::Sha256.hash(&data);
+        store.put(&hash, &data).unwrap();
+        assert_eq!(store.get(&hash).unwrap(), data);
+        assert!(store.put(&Hash::from_data(&data), &data).is_err());
+    }
+
+    #[test]
+    fn test_cas_backend_on_remote_store() {
+        use crate::storage::{BlockStorage, CasBackend};
+
+        let temp = TempDir::new().unwrap();
+        let addr = start_blob_server(&temp);
+        let store = RemoteBlobStore::new(&addr, CasPoolConfig::default()).unwrap();
+        let state = TempDir::new().unwrap();
+        let snapshots = state.path().join("snapshots.json");
+        let backend = CasBackend::new(Box::new(store), 1024, &snapshots).unwrap();
+
+        // Eight sectors, two of them repeated and one zero
+        let mut data: Vec<u8> = (0..8u8).flat_map(|i| [i + 1; 512]).collect();
+        data.copy_within(0..512, 3 * 512);
+        data[5 * 512..6 * 512].fill(0);
+        backend.write(16, &data).unwrap();
+        assert_eq!(backend.read(16, 8).unwrap(), data);
+
+        let stats = backend.stats();
+        assert_eq!(stats.zero_blocks, 1);
+        assert_eq!((stats.unique_blocks, stats.duplicate_blocks), (6, 1));
+    }
+}
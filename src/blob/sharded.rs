@@ -0,0 +1,397 @@
+//! Blob store sharded across several stores
+//!
+//! Spreads blobs over a set of stores, typically `FileBlobStore`s on
+//! different disks, so capacity and IOPS grow with the number of disks. A
+//! blob's home shard is picked from its hash prefix. When the home shard is
+//! full or failing, the blob goes to the next shard along instead, and
+//! lookups that miss the home shard try the others in the same order; so
+//! blobs stay readable after shards are added and placement changes. A
+//! blob already on some shard is never stored again on another.
+//!
+//! A shard that fails with an I/O error is marked unhealthy and skipped for
+//! new blobs until a health check finds it working again. It is still
+//! tried for reads, since its blobs are nowhere else.
+
+use super::{BlobError, BlobResult, BlobStore, FileBlobStore, Hash, HashAlgorithm};
+use crate::storage::SpaceStatus;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+/// Health of one shard
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardStatus {
+    /// Taking new blobs (false after an I/O error, until a health check)
+    pub healthy: bool,
+    /// I/O errors since the store was opened
+    pub errors: u64,
+}
+
+struct Shard<S> {
+    store: S,
+    healthy: AtomicBool,
+    errors: AtomicU64,
+}
+
+/// Blob store spreading blobs across shards by hash prefix
+pub struct ShardedBlobStore<S: BlobStore> {
+    shards: Vec<Shard<S>>,
+}
+
+/// Whether an error means the shard itself is failing, rather than the
+/// blob being absent or the shard full
+fn is_fault(error: &BlobError) -> bool {
+    matches!(error, BlobError::Io(_) | BlobError::Backend(_))
+}
+
+impl<S: BlobStore> ShardedBlobStore<S> {
+    /// Shard across `stores`, in order. Keep the order when adding shards:
+    /// existing blobs are still found, but lookups of them cost more.
+    pub fn new(stores: Vec<S>) -> Self {
+        assert!(!stores.is_empty(), "a sharded blob store needs a shard");
+        Self {
+            shards: stores
+                .into_iter()
+                .map(|store| Shard {
+                    store,
+                    healthy: AtomicBool::new(true),
+                    errors: AtomicU64::new(0),
+                })
+                .collect(),
+        }
+    }
+
+    /// Shard across `stores`, or serve the store as it is if there's only
+    /// one, sparing it the shard bookkeeping
+    pub fn boxed(mut stores: Vec<S>) -> Box<dyn BlobStore>
+    where
+        S: 'static,
+    {
+        if stores.len() == 1 {
+            Box::new(stores.pop().unwrap())
+        } else {
+            Box::new(Self::new(stores))
+        }
+    }
+
+    /// Number of shards
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Health of each shard, in order
+    pub fn shard_status(&self) -> Vec<ShardStatus> {
+        self.shards
+            .iter()
+            .map(|shard| ShardStatus {
+                healthy: shard.healthy.load(Ordering::Relaxed),
+                errors: shard.errors.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Shard indexes to try for a blob: its home shard, then the rest
+    fn order(&self, hash: &Hash) -> impl Iterator<Item = usize> {
+        let n = self.shards.len();
+        let bytes = hash.as_bytes();
+        let home = u16::from_be_bytes([bytes[0], bytes[1]]) as usize % n;
+        (0..n).map(move |i| (home + i) % n)
+    }
+
+    /// Note a failed operation on a shard, taking it out of placement if
+    /// the shard is at fault
+    fn record(&self, index: usize, error: &BlobError) {
+        if !is_fault(error) {
+            return;
+        }
+        let shard = &self.shards[index];
+        shard.errors.fetch_add(1, Ordering::Relaxed);
+        if shard.healthy.swap(false, Ordering::Relaxed) {
+            log::error!("Blob shard {} failed, taking it out of placement: {}", index, error);
+        }
+    }
+}
+
+impl ShardedBlobStore<FileBlobStore> {
+    /// Every blob across the shards with its size, oldest modified first
+    pub fn blobs(&self) -> io::Result<Vec<(Hash, u64)>> {
+        Self::blobs_of(self.shards.iter().map(|shard| &shard.store))
+    }
+
+    /// Every blob across `stores` with its size, oldest modified first
+    pub fn blobs_of<'a>(
+        stores: impl IntoIterator<Item = &'a FileBlobStore>,
+    ) -> io::Result<Vec<(Hash, u64)>> {
+        let mut blobs = Vec::new();
+        for store in stores {
+            blobs.extend(store.dated_blobs()?);
+        }
+        blobs.sort_by_key(|(modified, _, _)| *modified);
+        Ok(blobs.into_iter().map(|(_, hash, size)| (hash, size)).collect())
+    }
+}
+
+impl<S: BlobStore> BlobStore for ShardedBlobStore<S> {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        // A blob stored away from home while home was unavailable stays
+        // there; home's own put only finds copies it holds itself
+        for index in self.order(hash).skip(1) {
+            match self.shards[index].store.exists(hash) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => self.record(index, &e),
+            }
+        }
+        let mut last_error = None;
+        for index in self.order(hash) {
+            let shard = &self.shards[index];
+            if !shard.healthy.load(Ordering::Relaxed) {
+                continue;
+            }
+            match shard.store.put(hash, data) {
+                Ok(()) => return Ok(()),
+                // A bad blob is bad on every shard
+                Err(e @ BlobError::Corrupted(_)) => return Err(e),
+                Err(e) => {
+                    self.record(index, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| BlobError::Backend("no healthy blob shard".to_string())))
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        let mut error = None;
+        for index in self.order(hash) {
+            match self.shards[index].store.get(hash) {
+                Ok(data) => return Ok(data),
+                Err(BlobError::NotFound(_)) => {}
+                Err(e) => {
+                    self.record(index, &e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        Err(error.unwrap_or_else(|| BlobError::NotFound(hash.to_hex())))
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        let mut error = None;
+        for index in self.order(hash) {
+            match self.shards[index].store.exists(hash) {
+                Ok(true) => return Ok(true),
+                Ok(false) => {}
+                Err(e) => {
+                    self.record(index, &e);
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        // Absent from the shards that answered, but maybe on one that didn't
+        match error {
+            Some(e) => Err(e),
+            None => Ok(false),
+        }
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        // A blob can be on more than its home shard if it was stored while
+        // home was unavailable
+        let mut error = None;
+        for index in self.order(hash) {
+            if let Err(e) = self.shards[index].store.delete(hash) {
+                self.record(index, &e);
+                error.get_or_insert(e);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        let mut error = None;
+        for (index, shard) in self.shards.iter().enumerate() {
+            if let Err(e) = shard.store.sync() {
+                self.record(index, &e);
+                error.get_or_insert(e);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    /// Re-check every shard, returning failed ones to placement once they
+    /// work again. Healthy as long as some shard can take blobs.
+    fn check_health(&self) -> BlobResult<()> {
+        let mut error = None;
+        for (index, shard) in self.shards.iter().enumerate() {
+            match shard.store.check_health() {
+                Ok(()) => {
+                    if !shard.healthy.swap(true, Ordering::Relaxed) {
+                        log::info!("Blob shard {} recovered", index);
+                    }
+                }
+                Err(e) => {
+                    if shard.healthy.swap(false, Ordering::Relaxed) {
+                        log::error!("Blob shard {} is unhealthy: {}", index, e);
+                    }
+                    error.get_or_insert(e);
+                }
+            }
+        }
+        let healthy = self.shards.iter().any(|shard| shard.healthy.load(Ordering::Relaxed));
+        match error {
+            Some(e) if !healthy => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Free space summed over the shards; the alarm is raised if any shard
+    /// is short
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        let mut total: Option<SpaceStatus> = None;
+        for shard in &self.shards {
+            let Some(status) = shard.store.space()? else {
+                continue;
+            };
+            let sum = total.get_or_insert_with(SpaceStatus::default);
+            sum.total_bytes += status.total_bytes;
+            sum.available_bytes += status.available_bytes;
+            sum.reserve_bytes += status.reserve_bytes;
+            sum.alarm_bytes += status.alarm_bytes;
+            sum.alarm |= status.alarm;
+        }
+        Ok(total)
+    }
+
+    /// The first shard's; shards are opened with the same algorithm
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.shards[0].store.hash_algorithm()
+    }
+
+    fn list(&self) -> BlobResult<Option<Vec<(Hash, u64)>>> {
+        // A blob stored away from its home shard is listed by both
+        let mut blobs = Vec::new();
+        for shard in &self.shards {
+            match shard.store.list()? {
+                Some(listed) => blobs.extend(listed),
+                None => return Ok(None),
+            }
+        }
+        Ok(Some(blobs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn shards(temp: &TempDir, count: usize) -> ShardedBlobStore<FileBlobStore> {
+        let stores = (0..count)
+            .map(|i| FileBlobStore::new(temp.path().join(format!("shard{}", i))).unwrap())
+            .collect();
+        ShardedBlobStore::new(stores)
+    }
+
+    #[test]
+    fn test_blobs_spread_across_shards() {
+        let temp = TempDir::new().unwrap();
+        let store = shards(&temp, 3);
+
+        let blobs: Vec<Vec<u8>> = (0..64u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        for data in &blobs {
+            store.put(&Hash::from_data(data), data).unwrap();
+        }
+        for data in &blobs {
+            assert_eq!(&store.get(&Hash::from_data(data)).unwrap(), data);
+        }
+
+        // Each shard holds some, and each blob is in exactly one
+        let counts: Vec<usize> = (0..3)
+            .map(|i| {
+                FileBlobStore::new(temp.path().join(format!("shard{}", i)))
+                    .unwrap()
+                    .blobs()
+                    .unwrap()
+                    .len()
+            })
+            .collect();
+        assert!(counts.iter().all(|&count| count > 0), "{:?}", counts);
+        assert_eq!(counts.iter().sum::<usize>(), 64);
+        assert_eq!(store.blobs().unwrap().len(), 64);
+
+        let hash = Hash::from_data(&blobs[0]);
+        store.delete(&hash).unwrap();
+        assert!(!store.exists(&hash).unwrap());
+        assert!(matches!(store.get(&hash), Err(BlobError::NotFound(_))));
+    }
+
+    #[test]
+    fn test_failed_shard_is_bypassed() {
+        let temp = TempDir::new().unwrap();
+        let store = shards(&temp, 2);
+
+        // Break shard 0 by putting a file where its directory was
+        let broken = temp.path().join("shard0");
+        fs::remove_dir_all(&broken).unwrap();
+        fs::write(&broken, b"not a directory").unwrap();
+
+        for i in 0..16u32 {
+            let data = i.to_le_bytes();
+            store.put(&Hash::from_data(&data), &data).unwrap();
+            assert_eq!(store.get(&Hash::from_data(&data)).unwrap(), data);
+        }
+        let status = store.shard_status();
+        assert!(!status[0].healthy && status[0].errors > 0);
+        assert!(status[1].healthy);
+        assert!(store.check_health().is_ok());
+
+        // Repaired, it rejoins placement at the next check
+        fs::remove_file(&broken).unwrap();
+        fs::create_dir(&broken).unwrap();
+        store.check_health().unwrap();
+        assert!(store.shard_status()[0].healthy);
+    }
+
+    #[test]
+    fn test_blob_away_from_home_is_not_duplicated() {
+        let temp = TempDir::new().unwrap();
+        let store = shards(&temp, 2);
+        let data = (0..u32::MAX)
+            .map(u32::to_le_bytes)
+            .find(|data| store.order(&Hash::from_data(data)).next() == Some(0))
+            .unwrap();
+        let hash = Hash::from_data(&data);
+
+        // Stored on shard 1 while its home, shard 0, is broken
+        let broken = temp.path().join("shard0");
+        fs::remove_dir_all(&broken).unwrap();
+        fs::write(&broken, b"not a directory").unwrap();
+        store.put(&hash, &data).unwrap();
+        fs::remove_file(&broken).unwrap();
+        fs::create_dir(&broken).unwrap();
+        store.check_health().unwrap();
+
+        store.put(&hash, &data).unwrap();
+        assert_eq!(store.blobs().unwrap().len(), 1);
+        assert_eq!(store.get(&hash).unwrap(), data);
+    }
+
+    #[test]
+    fn test_adding_a_shard_keeps_blobs_readable() {
+        let temp = TempDir::new().unwrap();
+        let data: Vec<[u8; 4]> = (0..32u32).map(u32::to_le_bytes).collect();
+        {
+            let store = shards(&temp, 1);
+            for data in &data {
+                store.put(&Hash::from_data(data), data).unwrap();
+            }
+        }
+        let store = shards(&temp, 2);
+        for data in &data {
+            assert!(store.exists(&Hash::from_data(data)).unwrap());
+            assert_eq!(store.get(&Hash::from_data(data)).unwrap(), data);
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! Hot/cold blob store tiering
+//!
+//! Keeps recently used blobs in a fast local store and demotes the least
+//! recently used ones to a secondary store once the hot tier holds more
+//! than its capacity. New blobs land in the hot tier; a blob read from the
+//! cold tier is promoted back. Demotion copies a blob down before removing
+//! it from the hot tier, so every blob is always in at least one tier. It
+//! runs in the background, so the hot tier can briefly hold more than its
+//! capacity.
+
+use super::{BlobError, BlobResult, BlobStore, Hash, HashAlgorithm, QuotaUsage};
+use crate::storage::SpaceStatus;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// A blob resident in the hot tier
+struct Resident {
+    size: u64,
+    /// Position in the recency order
+    tick: u64,
+    /// Known to be in the cold tier already (demotion needn't copy it)
+    in_cold: bool,
+}
+
+/// Hot tier contents, by recency
+#[derive(Default)]
+struct HotIndex {
+    blobs: HashMap<Hash, Resident>,
+    /// Tick to blob, least recently used first
+    order: BTreeMap<u64, Hash>,
+    next_tick: u64,
+    bytes: u64,
+}
+
+impl HotIndex {
+    /// Record a use of a hot blob, adding it if new
+    fn touch(&mut self, hash: Hash, size: u64, in_cold: bool) {
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        match self.blobs.get_mut(&hash) {
+            Some(resident) => {
+                self.order.remove(&resident.tick);
+                resident.tick = tick;
+                resident.in_cold |= in_cold;
+            }
+            None => {
+                self.blobs.insert(hash, Resident { size, tick, in_cold });
+                self.bytes += size;
+            }
+        }
+        self.order.insert(tick, hash);
+    }
+
+    fn remove(&mut self, hash: &Hash) -> Option<Resident> {
+        let resident = self.blobs.remove(hash)?;
+        self.order.remove(&resident.tick);
+        self.bytes -= resident.size;
+        Some(resident)
+    }
+
+    /// Take blobs off the cold end until at most `capacity` bytes remain
+    fn evict(&mut self, capacity: u64) -> Vec<(Hash, Resident)> {
+        let mut victims = Vec::new();
+        while self.bytes > capacity {
+            let Some((_, hash)) = self.order.pop_first() else {
+                break;
+            };
+            let resident = self.blobs.remove(&hash).expect("ordered blob is indexed");
+            self.bytes -= resident.size;
+            victims.push((hash, resident));
+        }
+        victims
+    }
+}
+
+/// The two tiers and what the hot one holds, shared with the demoter
+struct Tiers {
+    hot: Box<dyn BlobStore>,
+    cold: Box<dyn BlobStore>,
+    capacity_bytes: u64,
+    index: Mutex<HotIndex>,
+    /// Held for a whole demotion pass, so passes don't overlap
+    demoting: Mutex<()>,
+}
+
+impl Tiers {
+    /// Demote least recently used blobs until the hot tier fits
+    fn demote_excess(&self) {
+        let _demoting = self.demoting.lock().unwrap();
+        let victims = self.index.lock().unwrap().evict(self.capacity_bytes);
+        for (hash, resident) in victims {
+            if let Err(e) = self.demote(&hash, resident.in_cold) {
+                // Still hot. Requeued as recently used, so an unreachable
+                // cold tier isn't retried on every put
+                log::warn!("Failed to demote blob {} to the cold tier: {}", hash, e);
+                self.index
+                    .lock()
+                    .unwrap()
+                    .touch(hash, resident.size, resident.in_cold);
+            }
+        }
+    }
+
+    fn demote(&self, hash: &Hash, in_cold: bool) -> BlobResult<()> {
+        if !in_cold && !self.cold.exists(hash)? {
+            let data = match self.hot.get(hash) {
+                Ok(data) => data,
+                // Deleted meanwhile: nothing to keep
+                Err(BlobError::NotFound(_)) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            self.cold.put(hash, &data)?;
+            self.cold.sync()?;
+        }
+        self.hot.delete(hash)
+    }
+}
+
+/// Blob store keeping the most recently used blobs in a capacity-limited
+/// hot tier in front of a cold tier. Demotion runs on a background
+/// thread, so puts and promotions don't wait for the cold tier.
+pub struct TieredBlobStore {
+    tiers: Arc<Tiers>,
+    /// Wakes the demoter (None if it couldn't be started: demote inline)
+    demoter: Option<SyncSender<()>>,
+}
+
+impl TieredBlobStore {
+    /// Tier `hot` in front of `cold`, keeping at most `capacity_bytes` of
+    /// blobs in `hot`
+    pub fn new(hot: Box<dyn BlobStore>, cold: Box<dyn BlobStore>, capacity_bytes: u64) -> Self {
+        let tiers = Arc::new(Tiers {
+            hot,
+            cold,
+            capacity_bytes,
+            index: Mutex::new(HotIndex::default()),
+            demoting: Mutex::new(()),
+        });
+
+        // One pending wakeup is enough: each pass demotes all the excess
+        let (sender, receiver) = mpsc::sync_channel::<()>(1);
+        let demoted = Arc::clone(&tiers);
+        let spawned = thread::Builder::new()
+            .name("blob-demoter".to_string())
+            .spawn(move || {
+                for () in receiver {
+                    demoted.demote_excess();
+                }
+            });
+        let demoter = match spawned {
+            Ok(_) => Some(sender),
+            Err(e) => {
+                log::warn!("Cannot start the blob demoter, demoting inline: {}", e);
+                None
+            }
+        };
+        Self { tiers, demoter }
+    }
+
+    /// Account for blobs already in the hot tier, with their sizes, least
+    /// recently used first (see `FileBlobStore::blobs`). Any excess over
+    /// capacity is demoted after the next put or promotion.
+    pub fn with_resident(self, blobs: impl IntoIterator<Item = (Hash, u64)>) -> Self {
+        {
+            let mut index = self.tiers.index.lock().unwrap();
+            for (hash, size) in blobs {
+                index.touch(hash, size, false);
+            }
+        }
+        self
+    }
+
+    /// Bytes of blobs in the hot tier
+    pub fn hot_bytes(&self) -> u64 {
+        self.tiers.index.lock().unwrap().bytes
+    }
+
+    /// Whether a blob is in the hot tier
+    pub fn is_hot(&self, hash: &Hash) -> bool {
+        self.tiers.index.lock().unwrap().blobs.contains_key(hash)
+    }
+
+    /// Demote least recently used blobs until the hot tier fits, waiting
+    /// for any background pass already under way
+    pub fn demote_excess(&self) {
+        self.tiers.demote_excess();
+    }
+
+    /// Record uses of hot blobs, then have the excess demoted
+    fn touch_all<'a>(&self, blobs: impl IntoIterator<Item = (&'a Hash, u64)>, in_cold: bool) {
+        let over = {
+            let mut index = self.tiers.index.lock().unwrap();
+            for (hash, size) in blobs {
+                index.touch(*hash, size, in_cold);
+            }
+            index.bytes > self.tiers.capacity_bytes
+        };
+        if over {
+            match &self.demoter {
+                // Full means a wakeup is already pending
+                Some(demoter) => {
+                    let _ = demoter.try_send(());
+                }
+                None => self.tiers.demote_excess(),
+            }
+        }
+    }
+
+    /// Copy blobs read from the cold tier up to the hot one
+    fn promote(&self, blobs: &[(Hash, &[u8])]) {
+        let mut promoted = Vec::with_capacity(blobs.len());
+        for (hash, data) in blobs {
+            match self.tiers.hot.put(hash, data) {
+                Ok(()) => promoted.push((hash, data.len() as u64)),
+                // Promotion is only an optimisation
+                Err(e) => log::debug!("Failed to promote blob {} to the hot tier: {}", hash, e),
+            }
+        }
+        self.touch_all(promoted, true);
+    }
+}
+
+impl BlobStore for TieredBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        self.put_many(&[(*hash, data)])
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        let mut blobs = self.get_many(std::slice::from_ref(hash))?;
+        Ok(blobs.pop().expect("one blob per hash"))
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        Ok(self.tiers.hot.exists(hash)? || self.tiers.cold.exists(hash)?)
+    }
+
+    fn size(&self, hash: &Hash) -> BlobResult<u64> {
+        match self.tiers.hot.size(hash) {
+            Err(BlobError::NotFound(_)) => self.tiers.cold.size(hash),
+            result => result,
+        }
+    }
+
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        match self.tiers.hot.put_many(blobs) {
+            Ok(()) => {}
+            // Local disk below its reserve: straight to the cold tier
+            Err(BlobError::NoSpace { .. }) => return self.tiers.cold.put_many(blobs),
+            Err(e) => return Err(e),
+        }
+        self.touch_all(
+            blobs.iter().map(|(hash, data)| (hash, data.len() as u64)),
+            false,
+        );
+        Ok(())
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        let mut found = Vec::with_capacity(hashes.len());
+        let mut hot = Vec::new();
+        let mut missing = Vec::new();
+        for (i, hash) in hashes.iter().enumerate() {
+            match self.tiers.hot.get(hash) {
+                Ok(data) => {
+                    hot.push((hash, data.len() as u64));
+                    found.push(Some(data));
+                }
+                // Possibly demoted meanwhile: try the cold tier
+                Err(BlobError::NotFound(_)) => {
+                    missing.push(i);
+                    found.push(None);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.touch_all(hot, false);
+
+        if !missing.is_empty() {
+            let wanted: Vec<Hash> = missing.iter().map(|&i| hashes[i]).collect();
+            let cold = self.tiers.cold.get_many(&wanted)?;
+            let promoted: Vec<(Hash, &[u8])> = wanted
+                .iter()
+                .copied()
+                .zip(cold.iter().map(Vec::as_slice))
+                .collect();
+            self.promote(&promoted);
+            for (i, data) in missing.into_iter().zip(cold) {
+                found[i] = Some(data);
+            }
+        }
+        Ok(found
+            .into_iter()
+            .map(|data| data.expect("every blob found"))
+            .collect())
+    }
+
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        let mut exists = self.tiers.hot.exists_many(hashes)?;
+        let missing: Vec<usize> = (0..hashes.len()).filter(|&i| !exists[i]).collect();
+        if !missing.is_empty() {
+            let wanted: Vec<Hash> = missing.iter().map(|&i| hashes[i]).collect();
+            for (i, cold) in missing
+                .into_iter()
+                .zip(self.tiers.cold.exists_many(&wanted)?)
+            {
+                exists[i] = cold;
+            }
+        }
+        Ok(exists)
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        self.tiers.index.lock().unwrap().remove(hash);
+        self.tiers.hot.delete(hash)?;
+        self.tiers.cold.delete(hash)
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        self.tiers.hot.sync()?;
+        self.tiers.cold.sync()
+    }
+
+    fn check_health(&self) -> BlobResult<()> {
+        self.tiers.hot.check_health()?;
+        self.tiers.cold.check_health()
+    }
+
+    /// The cold tier's space: it holds the archive, while the hot tier is
+    /// kept to its capacity
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        self.tiers.cold.space()
+    }
+
+    /// The cold tier's usage, for the same reason
+    fn quota(&self) -> Option<QuotaUsage> {
+        self.tiers.cold.quota()
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.tiers.hot.hash_algorithm()
+    }
+
+    /// Blobs in either tier, once each; None unless both tiers can list
+    fn list(&self) -> BlobResult<Option<Vec<(Hash, u64)>>> {
+        let (Some(hot), Some(cold)) = (self.tiers.hot.list()?, self.tiers.cold.list()?) else {
+            return Ok(None);
+        };
+        let mut seen = HashSet::with_capacity(cold.len());
+        let mut blobs = Vec::with_capacity(hot.len() + cold.len());
+        for (hash, size) in cold.into_iter().chain(hot) {
+            if seen.insert(hash) {
+                blobs.push((hash, size));
+            }
+        }
+        Ok(Some(blobs))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::FileBlobStore;
+    use tempfile::TempDir;
+
+    fn stores(temp: &TempDir) -> (FileBlobStore, FileBlobStore) {
+        (
+            FileBlobStore::new(temp.path().join("hot")).unwrap(),
+            FileBlobStore::new(temp.path().join("cold")).unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_tiered_demote_and_promote() {
+        let temp = TempDir::new().unwrap();
+        let (hot, cold) = stores(&temp);
+        let store = TieredBlobStore::new(Box::new(hot), Box::new(cold), 250);
+
+        let blobs: Vec<_> = (0..3u8).map(|i| vec![i; 100]).collect();
+        let hashes: Vec<_> = blobs.iter().map(|b| Hash::from_data(b)).collect();
+        for (hash, blob) in hashes.iter().zip(&blobs) {
+            store.put(hash, blob).unwrap();
+        }
+        // The oldest went cold to keep the hot tier within 250 bytes
+        store.demote_excess();
+        assert_eq!(store.hot_bytes(), 200);
+        assert!(!store.is_hot(&hashes[0]));
+        let (hot, cold) = stores(&temp);
+        assert!(!hot.exists(&hashes[0]).unwrap());
+        assert!(cold.exists(&hashes[0]).unwrap());
+
+        // Reading it promotes it, demoting the least recently used
+        store.get(&hashes[1]).unwrap();
+        assert_eq!(store.get(&hashes[0]).unwrap(), blobs[0]);
+        store.demote_excess();
+        assert!(store.is_hot(&hashes[0]));
+        assert!(!store.is_hot(&hashes[2]));
+        assert!(cold.exists(&hashes[2]).unwrap());
+        for (hash, blob) in hashes.iter().zip(&blobs) {
+            assert!(store.exists(hash).unwrap());
+            assert_eq!(&store.get(hash).unwrap(), blob);
+        }
+    }
+
+    #[test]
+    fn test_tiered_background_demotion() {
+        let temp = TempDir::new().unwrap();
+        let (hot, cold) = stores(&temp);
+        let store = TieredBlobStore::new(Box::new(hot), Box::new(cold), 250);
+
+        let blobs: Vec<_> = (0..3u8).map(|i| vec![i; 100]).collect();
+        let hashes: Vec<_> = blobs.iter().map(|b| Hash::from_data(b)).collect();
+        let batch: Vec<_> = hashes
+            .iter()
+            .copied()
+            .zip(blobs.iter().map(Vec::as_slice))
+            .collect();
+        store.put_many(&batch).unwrap();
+
+        // The demoter moves the oldest down without another call
+        let (_, cold) = stores(&temp);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !cold.exists(&hashes[0]).unwrap() {
+            assert!(std::time::Instant::now() < deadline, "demotion never ran");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        store.demote_excess();
+        assert_eq!(store.hot_bytes(), 200);
+
+        // Batches span both tiers
+        let unknown = Hash::from_data(b"unknown");
+        let exists = store.exists_many(&[hashes[0], unknown, hashes[2]]).unwrap();
+        assert_eq!(exists, vec![true, false, true]);
+        assert_eq!(store.get_many(&hashes).unwrap(), blobs);
+        let listed = store.list().unwrap().unwrap();
+        assert_eq!(listed.len(), 3);
+        let listed: HashSet<_> = listed.into_iter().collect();
+        assert_eq!(listed, hashes.iter().map(|hash| (*hash, 100)).collect());
+    }
+
+    #[test]
+    fn test_tiered_resident_blobs() {
+        let temp = TempDir::new().unwrap();
+        let (hot, cold) = stores(&temp);
+        let blobs: Vec<_> = (0..3u8).map(|i| vec![i; 100]).collect();
+        for blob in &blobs {
+            hot.put(&Hash::from_data(blob), blob).unwrap();
+        }
+
+        // A reopened hot tier over capacity sheds its excess on the next put
+        let resident = hot.blobs().unwrap();
+        assert_eq!(resident.len(), 3);
+        let store =
+            TieredBlobStore::new(Box::new(hot), Box::new(cold), 150).with_resident(resident);
+        assert_eq!(store.hot_bytes(), 300);
+        let blob = vec![9; 100];
+        store.put(&Hash::from_data(&blob), &blob).unwrap();
+        store.demote_excess();
+        assert_eq!(store.hot_bytes(), 100);
+        for blob in &blobs {
+            assert_eq!(&store.get(&Hash::from_data(blob)).unwrap(), blob);
+        }
+    }
+}
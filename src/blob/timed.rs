@@ -0,0 +1,104 @@
+//! Slow operation logging for blob stores
+//!
+//! Wraps a store and logs any get, put or sync slower than a threshold,
+//! so a slow CAS request can be traced to the blob store underneath it.
+//! Tree nodes are blobs too, so slow tree walks show up here as well.
+
+use super::{BlobResult, BlobStore, Hash, HashAlgorithm, QuotaUsage};
+use crate::storage::latency::is_slow;
+use crate::storage::SpaceStatus;
+use std::time::{Duration, Instant};
+
+/// Blob store wrapper logging slow operations
+pub struct TimedBlobStore {
+    name: String,
+    inner: Box<dyn BlobStore>,
+    slow_threshold: Duration,
+}
+
+impl TimedBlobStore {
+    /// Log operations on `inner` taking at least `slow_threshold`,
+    /// naming the store `name`
+    pub fn new(
+        name: impl Into<String>,
+        inner: Box<dyn BlobStore>,
+        slow_threshold: Duration,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            inner,
+            slow_threshold,
+        }
+    }
+
+    fn timed<T>(
+        &self,
+        op: &str,
+        hash: Option<&Hash>,
+        f: impl FnOnce() -> BlobResult<T>,
+    ) -> BlobResult<T> {
+        let started = Instant::now();
+        let result = f();
+        let elapsed = started.elapsed();
+        if is_slow(elapsed, self.slow_threshold) {
+            log::warn!(
+                "Slow blob store {} on {}: {:?}{}",
+                op,
+                self.name,
+                elapsed,
+                hash.map(|h| format!(" (blob {:?})", h)).unwrap_or_default()
+            );
+        }
+        result
+    }
+}
+
+impl BlobStore for TimedBlobStore {
+    fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+        self.timed("put", Some(hash), || self.inner.put(hash, data))
+    }
+
+    fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+        self.timed("get", Some(hash), || self.inner.get(hash))
+    }
+
+    fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+        self.timed("exists", Some(hash), || self.inner.exists(hash))
+    }
+
+    fn put_many(&self, blobs: &[(Hash, &[u8])]) -> BlobResult<()> {
+        self.timed("put_many", None, || self.inner.put_many(blobs))
+    }
+
+    fn get_many(&self, hashes: &[Hash]) -> BlobResult<Vec<Vec<u8>>> {
+        self.timed("get_many", None, || self.inner.get_many(hashes))
+    }
+
+    fn exists_many(&self, hashes: &[Hash]) -> BlobResult<Vec<bool>> {
+        self.timed("exists_many", None, || self.inner.exists_many(hashes))
+    }
+
+    fn delete(&self, hash: &Hash) -> BlobResult<()> {
+        self.inner.delete(hash)
+    }
+
+    fn sync(&self) -> BlobResult<()> {
+        self.timed("sync", None, || self.inner.sync())
+    }
+
+    fn check_health(&self) -> BlobResult<()> {
+        self.inner.check_health()
+    }
+
+    fn space(&self) -> BlobResult<Option<SpaceStatus>> {
+        self.inner.space()
+    }
+
+    fn quota(&self) -> Option<QuotaUsage> {
+        self.inner.quota()
+    }
+
+    fn hash_algorithm(&self) -> HashAlgorithm {
+        self.inner.hash_algorithm()
+    }
+}
@@ -0,0 +1,134 @@
+//! Client-side block cache
+//!
+//! CAS blocks never change once stored, so a block fetched by hash can be
+//! kept and served again without asking the server. Clones of one base
+//! image share most of their hashes, which makes a cache shared between
+//! their devices effective during boot storms.
+
+use super::Hash;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Bounded LRU cache of CAS blocks keyed by hash
+pub struct BlockCache {
+    capacity_bytes: usize,
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// Hash -> (data, last use)
+    blocks: HashMap<Hash, (Vec<u8>, u64)>,
+    /// Last use -> hash, oldest first
+    order: BTreeMap<u64, Hash>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl BlockCache {
+    /// Create a cache holding up to `capacity_mb` MiB of block data
+    pub fn new(capacity_mb: usize) -> Self {
+        Self {
+            capacity_bytes: capacity_mb * 1024 * 1024,
+            inner: Mutex::new(Inner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up a block, marking it recently used
+    pub fn get(&self, hash: &Hash) -> Option<Vec<u8>> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let Some((data, last_use)) = inner.blocks.get_mut(hash) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let previous = std::mem::replace(last_use, tick);
+        let data = data.clone();
+        inner.order.remove(&previous);
+        inner.order.insert(tick, *hash);
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(data)
+    }
+
+    /// Whether a block is cached, without counting a hit or miss
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.inner.lock().unwrap().blocks.contains_key(hash)
+    }
+
+    /// Add a block, evicting the least recently used ones to make room
+    pub fn insert(&self, hash: Hash, data: &[u8]) {
+        if data.len() > self.capacity_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((old, last_use)) = inner.blocks.insert(hash, (data.to_vec(), tick)) {
+            inner.order.remove(&last_use);
+            inner.bytes -= old.len();
+        }
+        inner.order.insert(tick, hash);
+        inner.bytes += data.len();
+
+        while inner.bytes > self.capacity_bytes {
+            let Some((_, oldest)) = inner.order.pop_first() else {
+                break;
+            };
+            if let Some((evicted, _)) = inner.blocks.remove(&oldest) {
+                inner.bytes -= evicted.len();
+            }
+        }
+    }
+
+    /// (hits, misses) since the cache was created
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Bytes of block data currently cached
+    pub fn size_bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // 1 MiB holds 256 blocks of 4 KiB
+        let cache = BlockCache::new(1);
+        for i in 0..256u32 {
+            let mut hash = [0u8; 16];
+            hash[..4].copy_from_slice(&i.to_le_bytes());
+            cache.insert(hash, &[i as u8; 4096]);
+        }
+        assert_eq!(cache.size_bytes(), 1024 * 1024);
+
+        // Touch block 0 so block 1 becomes the oldest
+        assert_eq!(cache.get(&[0u8; 16]).unwrap()[0], 0);
+        cache.insert([0xFF; 16], &[0xFF; 4096]);
+
+        let mut one = [0u8; 16];
+        one[0] = 1;
+        assert!(cache.get(&one).is_none());
+        assert!(cache.get(&[0u8; 16]).is_some());
+        assert!(cache.get(&[0xFF; 16]).is_some());
+        assert_eq!(cache.size_bytes(), 1024 * 1024);
+        assert_eq!(cache.stats(), (3, 1));
+    }
+}
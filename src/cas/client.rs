@@ -0,0 +1,517 @@
+//! CAS client connection pool
+//!
+//! Shared by the CAS-backed AoE/NBD backend and the iSCSI CAS device.
+//! Connections are opened on demand and returned to the pool after each
+//! request. Idle connections are pinged before reuse, and broken ones are
+//! dropped and replaced, so a CAS server restart only costs the requests
+//! in flight at the time: idempotent requests are retried with exponential
+//! backoff until the server is back or the retry budget runs out.
+//!
+//! A batch of requests can be pipelined: sent on one connection without
+//! waiting for each response, so it costs one round trip rather than one
+//! per request. The server answers in order.
+
+use super::protocol::{
+    read_frame, write_frame, CasCommand, DeleteOutcome, QUOTA_EXCEEDED, SERVER_BUSY,
+};
+use super::Hash;
+use crate::net::Stream;
+use crate::tls::TlsClient;
+use std::io::{self, BufReader, BufWriter};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Connection pool settings
+#[derive(Debug, Clone)]
+pub struct CasPoolConfig {
+    /// Idle connections kept open for reuse
+    pub max_idle: usize,
+    /// Retries after the first attempt for idempotent requests
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after each failure
+    pub initial_backoff: Duration,
+    /// Upper bound on the retry delay
+    pub max_backoff: Duration,
+    /// Idle connections older than this are pinged before reuse
+    pub health_check_after: Duration,
+    /// Connect and per-request read/write timeout
+    pub io_timeout: Duration,
+    /// Talk to the server over mutual TLS
+    pub tls: Option<Arc<TlsClient>>,
+}
+
+impl Default for CasPoolConfig {
+    fn default() -> Self {
+        Self {
+            max_idle: 4,
+            max_retries: 6,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            health_check_after: Duration::from_secs(30),
+            io_timeout: Duration::from_secs(30),
+            tls: None,
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    last_used: Instant,
+}
+
+impl Connection {
+    fn request(&mut self, command: CasCommand, data: &[u8]) -> io::Result<(CasCommand, Vec<u8>)> {
+        write_frame(&mut self.writer, command, data)?;
+        let response = read_response(&mut self.reader)?;
+        self.last_used = Instant::now();
+        Ok(response)
+    }
+
+    fn pipeline<D: AsRef<[u8]> + Sync>(
+        &mut self,
+        command: CasCommand,
+        requests: &[D],
+    ) -> io::Result<Vec<(CasCommand, Vec<u8>)>> {
+        let Connection { reader, writer, .. } = self;
+        let responses = thread::scope(|scope| {
+            // Sent from another thread, so a batch too big for the socket
+            // buffers can't leave both ends blocked writing
+            let sender = scope.spawn(move || {
+                requests
+                    .iter()
+                    .try_for_each(|data| write_frame(writer, command, data.as_ref()))
+            });
+            let responses: io::Result<Vec<_>> =
+                requests.iter().map(|_| read_response(reader)).collect();
+            let sent = sender.join().expect("CAS request sender panicked");
+            sent.and(responses)
+        })?;
+        self.last_used = Instant::now();
+        Ok(responses)
+    }
+}
+
+/// Read a response frame. A busy server's refusal is an error, so the
+/// request is retried on a new connection.
+fn read_response<R: io::Read>(reader: &mut R) -> io::Result<(CasCommand, Vec<u8>)> {
+    let (command, data) = read_frame(reader)?;
+    if command == CasCommand::Error && data.starts_with(SERVER_BUSY.as_bytes()) {
+        return Err(server_error(&data));
+    }
+    Ok((command, data))
+}
+
+/// Pool of connections to one CAS server
+pub struct CasPool {
+    addr: String,
+    config: CasPoolConfig,
+    idle: Mutex<Vec<Connection>>,
+}
+
+impl CasPool {
+    /// Create a pool, connecting once to fail early if the server is down.
+    /// `addr` is `host:port` or `unix:/path`.
+    pub fn connect(addr: &str, config: CasPoolConfig) -> io::Result<Self> {
+        let pool = Self {
+            addr: addr.to_string(),
+            config,
+            idle: Mutex::new(Vec::new()),
+        };
+        let conn = pool.open()?;
+        pool.release(conn);
+        Ok(pool)
+    }
+
+    /// Send a request and wait for its response.
+    ///
+    /// Idempotent commands are retried on connection failures; `Delete`
+    /// is attempted once since its result depends on prior state.
+    pub fn request(&self, command: CasCommand, data: &[u8]) -> io::Result<(CasCommand, Vec<u8>)> {
+        self.with_retries(command, |conn| conn.request(command, data))
+    }
+
+    /// Send a batch of requests with the same command on one connection
+    /// without waiting for each response, returning the responses in
+    /// order. Retried as a whole like `request`.
+    pub fn pipeline<D: AsRef<[u8]> + Sync>(
+        &self,
+        command: CasCommand,
+        requests: &[D],
+    ) -> io::Result<Vec<(CasCommand, Vec<u8>)>> {
+        match requests {
+            [] => Ok(Vec::new()),
+            [data] => Ok(vec![self.request(command, data.as_ref())?]),
+            _ => self.with_retries(command, |conn| conn.pipeline(command, requests)),
+        }
+    }
+
+    /// Run `exchange` on a pooled connection, retrying on a fresh one if
+    /// `command` is idempotent
+    fn with_retries<T>(
+        &self,
+        command: CasCommand,
+        exchange: impl Fn(&mut Connection) -> io::Result<T>,
+    ) -> io::Result<T> {
+        let retries = if is_idempotent(command) {
+            self.config.max_retries
+        } else {
+            0
+        };
+        let mut backoff = self.config.initial_backoff;
+        let mut attempt = 0;
+
+        loop {
+            let result = self
+                .checkout()
+                .and_then(|mut conn| exchange(&mut conn).map(|resp| (conn, resp)));
+            match result {
+                Ok((conn, response)) => {
+                    self.release(conn);
+                    return Ok(response);
+                }
+                Err(e) if attempt < retries && is_retryable(&e) => {
+                    attempt += 1;
+                    log::warn!(
+                        "CAS {:?} request to {} failed ({}), retry {}/{} in {:?}",
+                        command,
+                        self.addr,
+                        e,
+                        attempt,
+                        retries,
+                        backoff
+                    );
+                    // Whatever else is pooled shares the fate of this one
+                    self.idle.lock().unwrap().clear();
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(self.config.max_backoff);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Store a block, returning its hash
+    pub fn write(&self, data: &[u8]) -> io::Result<Hash> {
+        match self.request(CasCommand::Write, data)? {
+            (CasCommand::Write, hash) if hash.len() == 16 => {
+                let mut out = [0u8; 16];
+                out.copy_from_slice(&hash);
+                Ok(out)
+            }
+            (CasCommand::Error, message) => Err(server_error(&message)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid CAS write response",
+            )),
+        }
+    }
+
+    /// Fetch a block by hash
+    pub fn read(&self, hash: &[u8]) -> io::Result<Vec<u8>> {
+        match self.request(CasCommand::Read, hash)? {
+            (CasCommand::Read, data) => Ok(data),
+            (CasCommand::Error, message) => Err(server_error(&message)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid CAS read response",
+            )),
+        }
+    }
+
+    /// Delete a block. The server may keep a block written recently.
+    pub fn delete(&self, hash: &[u8]) -> io::Result<DeleteOutcome> {
+        match self.request(CasCommand::Delete, hash)? {
+            (CasCommand::Delete, outcome) if outcome.len() == 1 => {
+                DeleteOutcome::try_from(outcome[0])
+            }
+            (CasCommand::Error, message) => Err(server_error(&message)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid CAS delete response",
+            )),
+        }
+    }
+
+    /// Number of idle pooled connections
+    pub fn idle_connections(&self) -> usize {
+        self.idle.lock().unwrap().len()
+    }
+
+    /// Take a healthy idle connection, or open a new one
+    fn checkout(&self) -> io::Result<Connection> {
+        loop {
+            let conn = self.idle.lock().unwrap().pop();
+            let Some(mut conn) = conn else {
+                return self.open();
+            };
+            if conn.last_used.elapsed() < self.config.health_check_after {
+                return Ok(conn);
+            }
+            match conn.request(CasCommand::Ping, &[]) {
+                Ok((CasCommand::Ping, _)) => return Ok(conn),
+                _ => log::debug!("Dropping stale CAS connection to {}", self.addr),
+            }
+        }
+    }
+
+    fn release(&self, conn: Connection) {
+        let mut idle = self.idle.lock().unwrap();
+        if idle.len() < self.config.max_idle {
+            idle.push(conn);
+        }
+    }
+
+    fn open(&self) -> io::Result<Connection> {
+        let stream = Stream::connect(&self.addr, self.config.io_timeout)?;
+        stream.set_read_timeout(Some(self.config.io_timeout))?;
+        stream.set_write_timeout(Some(self.config.io_timeout))?;
+        stream.set_nodelay(true)?;
+        let stream = match &self.config.tls {
+            Some(tls) => tls.connect(&self.addr, stream)?,
+            None => stream,
+        };
+        log::debug!("Opened CAS connection to {}", self.addr);
+
+        Ok(Connection {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+            last_used: Instant::now(),
+        })
+    }
+}
+
+/// An `Error` frame from the server as an I/O error; a full store is
+/// `StorageFull` and a busy server `ConnectionRefused`
+fn server_error(message: &[u8]) -> io::Error {
+    let message = String::from_utf8_lossy(message).into_owned();
+    let kind = if message.starts_with(QUOTA_EXCEEDED) {
+        io::ErrorKind::StorageFull
+    } else if message.starts_with(SERVER_BUSY) {
+        io::ErrorKind::ConnectionRefused
+    } else {
+        io::ErrorKind::Other
+    };
+    io::Error::new(kind, message)
+}
+
+fn is_idempotent(command: CasCommand) -> bool {
+    // Writes are content-addressed, so repeating one stores nothing new
+    !matches!(command, CasCommand::Delete)
+}
+
+/// Connection-level failures are worth retrying; a malformed or error
+/// response from a live server is not
+fn is_retryable(e: &io::Error) -> bool {
+    !matches!(
+        e.kind(),
+        io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput | io::ErrorKind::PermissionDenied
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{hash, CasQuota, CasServer, CasServerConfig, CasServerLimits};
+    use std::net::TcpListener;
+
+    /// Server that answers one request per connection and then hangs up,
+    /// like a CAS server being restarted between requests
+    fn one_shot_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                if let Ok((command, data)) = read_frame(&mut stream) {
+                    let reply = match command {
+                        CasCommand::Write => vec![data.len() as u8; 16],
+                        _ => Vec::new(),
+                    };
+                    let _ = write_frame(&mut stream, command, &reply);
+                }
+            }
+        });
+        addr
+    }
+
+    /// Server that echoes every request back, on connections kept open
+    fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                thread::spawn(move || {
+                    while let Ok((command, data)) = read_frame(&mut stream) {
+                        if write_frame(&mut stream, command, &data).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    fn fast_config() -> CasPoolConfig {
+        CasPoolConfig {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(4),
+            ..CasPoolConfig::default()
+        }
+    }
+
+    #[test]
+    fn test_reconnects_after_server_hangs_up() {
+        let pool = CasPool::connect(&one_shot_server(), fast_config()).unwrap();
+        for len in 1..5 {
+            assert_eq!(pool.write(&vec![0u8; len]).unwrap(), [len as u8; 16]);
+        }
+    }
+
+    #[test]
+    fn test_pipelined_responses_in_order() {
+        let pool = CasPool::connect(&echo_server(), fast_config()).unwrap();
+        // Far more than the socket buffers hold in each direction
+        let requests: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 256 * 1024]).collect();
+        let responses = pool.pipeline(CasCommand::Read, &requests).unwrap();
+        assert_eq!(responses.len(), requests.len());
+        for (request, (command, data)) in requests.iter().zip(responses) {
+            assert_eq!(command, CasCommand::Read);
+            assert_eq!(&data, request);
+        }
+        assert_eq!(pool.idle_connections(), 1);
+        assert!(pool.pipeline::<&[u8]>(CasCommand::Read, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_gives_up_when_server_is_gone() {
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        assert!(CasPool::connect(&addr, fast_config()).is_err());
+    }
+
+    #[test]
+    fn test_delete_keeps_recent_writes() {
+        let temp = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let grace = Duration::from_millis(300);
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: addr.clone(),
+            storage_path: temp.path().to_string_lossy().into_owned(),
+        })
+        .unwrap()
+        .with_delete_grace(grace);
+        thread::spawn(move || server.serve(listener));
+        let pool = CasPool::connect(&addr, fast_config()).unwrap();
+
+        // Nothing is deleted until the server has been up for the grace period
+        let old = pool.write(&[1; 4096]).unwrap();
+        thread::sleep(grace * 2);
+        let new = pool.write(&[2; 4096]).unwrap();
+        assert_eq!(pool.delete(&old).unwrap(), DeleteOutcome::Deleted);
+        assert_eq!(pool.delete(&old).unwrap(), DeleteOutcome::NotFound);
+        assert_eq!(pool.delete(&new).unwrap(), DeleteOutcome::Kept);
+
+        // A write that dedups against the block counts as a write too
+        thread::sleep(grace * 2);
+        pool.write(&[2; 4096]).unwrap();
+        assert_eq!(pool.delete(&new).unwrap(), DeleteOutcome::Kept);
+        thread::sleep(grace * 2);
+        assert_eq!(pool.delete(&new).unwrap(), DeleteOutcome::Deleted);
+    }
+
+    #[test]
+    fn test_server_limits() {
+        let temp = tempfile::tempdir().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: addr.to_string(),
+            storage_path: temp.path().to_string_lossy().into_owned(),
+        })
+        .unwrap()
+        .with_limits(CasServerLimits {
+            max_connections: 1,
+            max_request_size: 1024,
+            idle_timeout: Some(Duration::from_millis(300)),
+        });
+        thread::spawn(move || server.serve(listener));
+
+        let mut first = std::net::TcpStream::connect(addr).unwrap();
+        write_frame(&mut first, CasCommand::Ping, &[]).unwrap();
+        assert_eq!(read_frame(&mut first).unwrap().0, CasCommand::Ping);
+
+        // Refused while the only slot is taken, until the first connection
+        // idles out
+        let mut refused = std::net::TcpStream::connect(addr).unwrap();
+        let (command, message) = read_frame(&mut refused).unwrap();
+        assert_eq!(command, CasCommand::Error);
+        let error = server_error(&message);
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(read_frame(&mut first).is_err());
+
+        // Pools retry a refusal
+        let pool = CasPool::connect(&addr.to_string(), fast_config()).unwrap();
+        assert_eq!(pool.write(&[1; 512]).unwrap(), hash(&[1; 512]));
+
+        // Too large a request gets the client disconnected
+        assert!(pool.write(&[0; 2048]).is_err());
+    }
+
+    #[test]
+    fn test_storage_quota() {
+        let temp = tempfile::tempdir().unwrap();
+        let serve = |dir: &str| {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let addr = listener.local_addr().unwrap().to_string();
+            let server = CasServer::new(CasServerConfig {
+                bind_addr: addr.clone(),
+                storage_path: temp.path().join(dir).to_string_lossy().into_owned(),
+            })
+            .unwrap()
+            .with_delete_grace(Duration::ZERO)
+            .with_quota(CasQuota {
+                max_bytes: 4 * 4096,
+            })
+            .unwrap();
+            let usage = server.usage_handle().unwrap();
+            thread::spawn(move || server.serve(listener));
+            (CasPool::connect(&addr, fast_config()).unwrap(), usage)
+        };
+
+        let (pool, usage) = serve("sequential");
+        for n in 0..4 {
+            pool.write(&[n; 4096]).unwrap();
+        }
+        let err = pool.write(&[4; 4096]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StorageFull);
+        // Blocks already stored still dedup, and deleting makes room
+        let first = pool.write(&[0; 4096]).unwrap();
+        assert_eq!(pool.delete(&first).unwrap(), DeleteOutcome::Deleted);
+        pool.write(&[4; 4096]).unwrap();
+        let stats = usage.usage();
+        assert_eq!((stats.blocks, stats.bytes), (4, 4 * 4096));
+        assert_eq!(stats.refused_writes, 1);
+
+        // Concurrent writes can't overshoot the quota
+        let (pool, usage) = serve("concurrent");
+        let pool = &pool;
+        let stored = thread::scope(|scope| {
+            let writers: Vec<_> = (0..8u8)
+                .map(|n| scope.spawn(move || pool.write(&[n; 4096]).is_ok()))
+                .collect();
+            let stored = writers.into_iter().map(|writer| writer.join().unwrap());
+            stored.filter(|&ok| ok).count()
+        });
+        assert_eq!(stored, 4);
+        let stats = usage.usage();
+        assert_eq!(stats.refused_writes, 4);
+        assert_eq!((stats.blocks, stats.bytes), (4, 4 * 4096));
+    }
+}
@@ -1,14 +1,24 @@
 //! Content-Addressable Storage (CAS) module
 //!
-//! Provides a standalone CAS service with a simple TCP protocol.
+//! Provides a standalone CAS service with a simple TCP protocol, which can
+//! also serve a blob store to aoe-server heads (`blob::RemoteBlobStore`).
 
+pub mod cache;
+pub mod client;
 pub mod protocol;
 pub mod storage;
 pub mod server;
 
-pub use protocol::{CasCommand, CasResponse};
+pub use cache::BlockCache;
+pub use client::{CasPool, CasPoolConfig};
+pub use protocol::{CasCommand, CasResponse, DeleteOutcome, QUOTA_EXCEEDED};
 pub use storage::CasStorage;
-pub use server::{CasServer, CasServerConfig};
+pub use server::{CasQuota, CasServer, CasServerConfig, CasServerLimits, StorageUsage, UsageHandle};
 
 /// Hash type used for content addressing (xxHash3-128)
 pub type Hash = [u8; 16];
+
+/// The hash a CAS server stores `data` under
+pub fn hash(data: &[u8]) -> Hash {
+    xxhash_rust::xxh3::xxh3_128(data).to_le_bytes()
+}
@@ -2,9 +2,30 @@
 //!
 //! Simple binary protocol:
 //! [1 byte: command] [4 bytes: length] [data...]
+//!
+//! A server backed by CAS storage names blocks by their 16-byte xxHash3
+//! and hashes what it's sent. A server backed by a blob store names blobs
+//! by 32-byte hashes, which clients give with `Put` (an encrypting client
+//! stores blobs under a locator rather than their hash), answers a read of
+//! a missing blob with `Exists` false, and answers `Ping` with the name of
+//! the hash algorithm the store keys blobs by, and `Space` with the free
+//! space where the store keeps them (nothing if it can't tell). Either
+//! answers a failed request with an `Error` frame carrying the message.
+//!
+//! A `Delete` is answered with one byte, a `DeleteOutcome`. A server
+//! backed by CAS storage keeps blocks written less than its delete grace
+//! period ago, since the client that wrote them may not have recorded
+//! them in an index yet, and answers `Kept`.
+//!
+//! A write that would take CAS storage over its quota is answered with an
+//! `Error` starting with `QUOTA_EXCEEDED`, which clients turn into an
+//! `io::ErrorKind::StorageFull` error. A server already serving as many
+//! connections as it may sends a new one an `Error` starting with
+//! `SERVER_BUSY` and hangs up; clients retry later.
 
-use std::io::{self, Read, Write};
 use super::Hash;
+use crate::storage::SpaceStatus;
+use std::io::{self, Read, Write};
 
 /// CAS protocol commands
 #[repr(u8)]
@@ -20,6 +41,14 @@ pub enum CasCommand {
     Ping = 0x04,
     /// Delete data by hash
     Delete = 0x05,
+    /// Store data under the hash preceding it (blob stores)
+    Put = 0x06,
+    /// Make stored data durable (blob stores)
+    Sync = 0x07,
+    /// Free space where data is kept (blob stores)
+    Space = 0x08,
+    /// Request failed; the data is the message
+    Error = 0xFF,
 }
 
 impl TryFrom<u8> for CasCommand {
@@ -32,6 +61,10 @@ impl TryFrom<u8> for CasCommand {
             0x03 => Ok(CasCommand::Exists),
             0x04 => Ok(CasCommand::Ping),
             0x05 => Ok(CasCommand::Delete),
+            0x06 => Ok(CasCommand::Put),
+            0x07 => Ok(CasCommand::Sync),
+            0x08 => Ok(CasCommand::Space),
+            0xFF => Ok(CasCommand::Error),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 format!("unknown command: {}", value),
@@ -40,6 +73,40 @@ impl TryFrom<u8> for CasCommand {
     }
 }
 
+/// Start of the `Error` message answering a write that would take CAS
+/// storage over its quota
+pub const QUOTA_EXCEEDED: &str = "quota exceeded";
+
+/// Start of the `Error` message a server sends a connection it has no
+/// room for
+pub const SERVER_BUSY: &str = "server busy";
+
+/// What a `Delete` did
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeleteOutcome {
+    NotFound = 0,
+    Deleted = 1,
+    /// Written too recently to delete; try again later
+    Kept = 2,
+}
+
+impl TryFrom<u8> for DeleteOutcome {
+    type Error = io::Error;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(DeleteOutcome::NotFound),
+            1 => Ok(DeleteOutcome::Deleted),
+            2 => Ok(DeleteOutcome::Kept),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown delete outcome: {}", value),
+            )),
+        }
+    }
+}
+
 /// CAS protocol responses
 #[derive(Debug, Clone)]
 pub enum CasResponse {
@@ -51,14 +118,31 @@ pub enum CasResponse {
     Exists(bool),
     /// Pong response
     Pong,
+    /// Pong from a blob store, naming its hash algorithm
+    KeyedPong(&'static str),
     /// Deletion confirmation
-    Deleted(bool),
+    Deleted(DeleteOutcome),
+    /// Put confirmation
+    Stored,
+    /// Sync confirmation
+    Synced,
+    /// Free space, if the store can tell
+    Space(Option<SpaceStatus>),
     /// Error response
     Error(String),
 }
 
 /// Read a frame from the stream
 pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<(CasCommand, Vec<u8>)> {
+    read_frame_limited(reader, usize::MAX)
+}
+
+/// Read a frame, failing with `InvalidData` instead of allocating for one
+/// longer than `max_len`
+pub fn read_frame_limited<R: Read>(
+    reader: &mut R,
+    max_len: usize,
+) -> io::Result<(CasCommand, Vec<u8>)> {
     // Read command byte
     let mut cmd_buf = [0u8; 1];
     reader.read_exact(&mut cmd_buf)?;
@@ -68,6 +152,15 @@ pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<(CasCommand, Vec<u8>)>
     let mut len_buf = [0u8; 4];
     reader.read_exact(&mut len_buf)?;
     let length = u32::from_le_bytes(len_buf) as usize;
+    if length > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{:?} request of {} bytes exceeds the {} byte limit",
+                command, length, max_len
+            ),
+        ));
+    }
 
     // Read data
     let mut data = vec![0u8; length];
@@ -99,6 +192,30 @@ pub fn write_frame<W: Write>(
     writer.flush()
 }
 
+/// Length of a `Space` answer from a store that can tell
+const SPACE_LEN: usize = 33;
+
+/// Free space from the data of a `Space` answer
+pub fn parse_space(data: &[u8]) -> io::Result<Option<SpaceStatus>> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    if data.len() != SPACE_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("space answer of {} bytes", data.len()),
+        ));
+    }
+    let count = |i: usize| u64::from_le_bytes(data[i * 8..i * 8 + 8].try_into().unwrap());
+    Ok(Some(SpaceStatus {
+        total_bytes: count(0),
+        available_bytes: count(1),
+        reserve_bytes: count(2),
+        alarm_bytes: count(3),
+        alarm: data[32] != 0,
+    }))
+}
+
 /// Write a response to the stream
 pub fn write_response<W: Write>(writer: &mut W, response: &CasResponse) -> io::Result<()> {
     match response {
@@ -118,18 +235,38 @@ pub fn write_response<W: Write>(writer: &mut W, response: &CasResponse) -> io::R
             // Command 0x04 (pong), length 0
             write_frame(writer, CasCommand::Ping, &[])?;
         }
-        CasResponse::Deleted(deleted) => {
-            // Command 0x05 (delete response), length 1, boolean byte
-            write_frame(writer, CasCommand::Delete, &[*deleted as u8])?;
+        CasResponse::KeyedPong(algorithm) => {
+            // Command 0x04 (pong), length, algorithm name
+            write_frame(writer, CasCommand::Ping, algorithm.as_bytes())?;
+        }
+        CasResponse::Deleted(outcome) => {
+            // Command 0x05 (delete response), length 1, outcome byte
+            write_frame(writer, CasCommand::Delete, &[*outcome as u8])?;
+        }
+        CasResponse::Stored => {
+            // Command 0x06 (put response), length 0
+            write_frame(writer, CasCommand::Put, &[])?;
+        }
+        CasResponse::Synced => {
+            // Command 0x07 (sync response), length 0
+            write_frame(writer, CasCommand::Sync, &[])?;
+        }
+        CasResponse::Space(space) => {
+            // Command 0x08 (space response), length 0 or 33, four byte
+            // counts and the alarm flag
+            let mut data = Vec::with_capacity(SPACE_LEN);
+            if let Some(space) = space {
+                data.extend_from_slice(&space.total_bytes.to_le_bytes());
+                data.extend_from_slice(&space.available_bytes.to_le_bytes());
+                data.extend_from_slice(&space.reserve_bytes.to_le_bytes());
+                data.extend_from_slice(&space.alarm_bytes.to_le_bytes());
+                data.push(space.alarm as u8);
+            }
+            write_frame(writer, CasCommand::Space, &data)?;
         }
         CasResponse::Error(msg) => {
             // Command 0xFF (error), length, error message bytes
-            writer.write_all(&[0xFF])?;
-            let msg_bytes = msg.as_bytes();
-            let length = msg_bytes.len() as u32;
-            writer.write_all(&length.to_le_bytes())?;
-            writer.write_all(msg_bytes)?;
-            writer.flush()?;
+            write_frame(writer, CasCommand::Error, msg.as_bytes())?;
         }
     }
     Ok(())
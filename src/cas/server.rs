@@ -1,16 +1,57 @@
 //! CAS TCP server
 //!
-//! Accepts client connections and handles CAS protocol requests.
+//! Accepts client connections and handles CAS protocol requests, for CAS
+//! storage (iSCSI targets) or for a blob store (aoe-server targets whose
+//! `blob_store` is `remote`).
+//!
+//! GC decides what to delete from indexes, but a block a client has just
+//! written may not be in any index yet: the iSCSI device writes blocks to
+//! CAS before committing its index. CAS storage therefore refuses to delete
+//! a block written (or deduplicated against) within its delete grace
+//! period, or any block until it has been up that long, and answers
+//! `DeleteOutcome::Kept` so GC tries again next time.
+//!
+//! CAS storage can be given a quota (`CasQuota`). A write that would take
+//! it over the quota is refused with an error starting `QUOTA_EXCEEDED`;
+//! space is only freed by GC, since the server keeps no reference counts
+//! and can't tell which blocks are still in use. A new block's space is
+//! reserved before it is written, so concurrent writes can't overshoot the
+//! quota. Usage is tracked from a walk of the store at startup, see
+//! `UsageHandle`.
+//!
+//! With `with_tls`, clients must complete a mutual TLS handshake (see
+//! `crate::tls`) before they are served.
+//!
+//! Each connection is served by a thread of its own, up to
+//! `CasServerLimits::max_connections`; further clients are refused with an
+//! `Error` starting with `SERVER_BUSY` (TLS clients, which can't be
+//! answered before their handshake, are just disconnected) and retry.
+//! Requests on different connections run in parallel: CAS storage only
+//! serializes requests for the same hash.
 
-use super::protocol::{read_frame, write_response, CasCommand, CasResponse};
+use super::protocol::{
+    read_frame_limited, write_response, CasCommand, CasResponse, DeleteOutcome, QUOTA_EXCEEDED,
+    SERVER_BUSY,
+};
 use super::storage::CasStorage;
+use super::{hash, Hash};
+use crate::blob::{self, BlobError, BlobStore};
+use crate::net::{Listener, Stream};
+use crate::tls::TlsServer;
+use serde::Serialize;
 use std::io;
-use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long CAS storage keeps newly written blocks from being deleted
+pub const DEFAULT_DELETE_GRACE: Duration = Duration::from_secs(5 * 60);
 
 /// CAS server configuration
 pub struct CasServerConfig {
+    /// `host:port`, or `unix:/path` for a UNIX domain socket
     pub bind_addr: String,
     pub storage_path: String,
 }
@@ -24,33 +65,419 @@ impl Default for CasServerConfig {
     }
 }
 
+/// Bits in each generation of `RecentWrites`: 16 MiB, which keeps false
+/// positives to a few percent for up to 20 million writes per grace period
+const RECENT_WRITES_BITS: usize = 1 << 27;
+
+/// Bloom filter of block hashes. Content hashes are already uniform, so
+/// the bits set for a hash are taken from its own bytes.
+struct WriteFilter {
+    words: Vec<u64>,
+}
+
+impl WriteFilter {
+    fn new() -> Self {
+        Self {
+            words: vec![0; RECENT_WRITES_BITS / 64],
+        }
+    }
+
+    fn bits(hash: &Hash) -> impl Iterator<Item = usize> + '_ {
+        hash.chunks_exact(4).map(|bytes| {
+            u32::from_le_bytes(bytes.try_into().unwrap()) as usize % RECENT_WRITES_BITS
+        })
+    }
+
+    fn insert(&mut self, hash: &Hash) {
+        for bit in Self::bits(hash) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        Self::bits(hash).all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
+
+/// Blocks written within the delete grace period, in two generations of
+/// one grace period each, so a block is remembered for at least the grace
+/// period and at most twice it. Each generation is a fixed-size Bloom
+/// filter, so memory use doesn't grow with the write rate; a false
+/// positive only keeps a block until GC asks again.
+struct RecentWrites {
+    grace: Duration,
+    started: Instant,
+    rotated: Instant,
+    /// None while the grace period is zero
+    generations: Option<[WriteFilter; 2]>,
+}
+
+impl RecentWrites {
+    fn new(grace: Duration) -> Self {
+        let now = Instant::now();
+        Self {
+            grace,
+            started: now,
+            rotated: now,
+            generations: (!grace.is_zero()).then(|| [WriteFilter::new(), WriteFilter::new()]),
+        }
+    }
+
+    fn rotate(&mut self) -> Option<&mut [WriteFilter; 2]> {
+        let generations = self.generations.as_mut()?;
+        let elapsed = self.rotated.elapsed();
+        if elapsed >= self.grace {
+            let [current, previous] = generations;
+            if elapsed < self.grace * 2 {
+                mem::swap(current, previous);
+            } else {
+                *previous = WriteFilter::new();
+            }
+            *current = WriteFilter::new();
+            self.rotated = Instant::now();
+        }
+        Some(generations)
+    }
+
+    fn record(&mut self, hash: Hash) {
+        if let Some([current, _]) = self.rotate() {
+            current.insert(&hash);
+        }
+    }
+
+    /// Whether `hash` may have been written within the grace period,
+    /// including before this server started
+    fn contains(&mut self, hash: &Hash) -> bool {
+        if self.started.elapsed() < self.grace {
+            return true;
+        }
+        match self.rotate() {
+            Some([current, previous]) => current.contains(hash) || previous.contains(hash),
+            None => false,
+        }
+    }
+}
+
+/// CAS storage and the blocks recently written to it. A write and its
+/// record, or a grace check and the delete, happen under the hash's stripe
+/// lock, so a delete can't slip in between.
+struct CasBlocks {
+    storage: CasStorage,
+    stripes: Vec<Mutex<()>>,
+    recent: Mutex<RecentWrites>,
+    /// Tracked once a quota is set or usage is asked for
+    usage: Option<UsageCounters>,
+    quota: Option<CasQuota>,
+}
+
+impl CasBlocks {
+    fn lock(&self, hash: &Hash) -> MutexGuard<'_, ()> {
+        self.stripes[hash[0] as usize % HASH_STRIPES].lock().unwrap()
+    }
+
+    fn used_bytes(&self) -> u64 {
+        self.usage.as_ref().map_or(0, |usage| usage.bytes.load(Ordering::Relaxed))
+    }
+
+    /// Count a write of `bytes`, releasing its reservation (see `reserve`)
+    /// if the block was already stored or the write failed
+    fn count_stored(&self, bytes: u64, new: bool, reserved: bool) {
+        let Some(usage) = &self.usage else {
+            return;
+        };
+        if new {
+            usage.blocks.fetch_add(1, Ordering::Relaxed);
+        }
+        match (new, reserved) {
+            (true, false) => usage.bytes.fetch_add(bytes, Ordering::Relaxed),
+            (false, true) => usage.bytes.fetch_sub(bytes, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    /// Delete a block, keeping usage up to date. The caller holds its lock.
+    fn remove(&self, hash: &Hash) -> io::Result<Option<u64>> {
+        let Some(size) = self.storage.size(hash)? else {
+            return Ok(None);
+        };
+        if !self.storage.delete(hash)? {
+            return Ok(None);
+        }
+        if let Some(usage) = &self.usage {
+            usage.blocks.fetch_sub(1, Ordering::Relaxed);
+            usage.bytes.fetch_sub(size, Ordering::Relaxed);
+        }
+        Ok(Some(size))
+    }
+
+    /// Reserve `len` bytes of the quota for a new block, in one atomic
+    /// step so concurrent writes can't all fit in the same space. Returns
+    /// whether space was reserved: a block already stored needs none.
+    /// Called before taking the block's lock.
+    fn reserve(&self, hash: &Hash, len: u64) -> Result<bool, String> {
+        let (Some(quota), Some(usage)) = (self.quota, &self.usage) else {
+            return Ok(false);
+        };
+        if self.storage.exists(hash) {
+            return Ok(false);
+        }
+        let fits = |used: u64| (used + len <= quota.max_bytes).then_some(used + len);
+        let relaxed = Ordering::Relaxed;
+        match usage.bytes.fetch_update(relaxed, relaxed, fits) {
+            Ok(_) => Ok(true),
+            Err(used) => {
+                usage.refused_writes.fetch_add(1, relaxed);
+                Err(format!(
+                    "{}: {} of {} bytes used",
+                    QUOTA_EXCEEDED, used, quota.max_bytes
+                ))
+            }
+        }
+    }
+}
+
+/// Limits on the load clients can put on a server
+#[derive(Debug, Clone)]
+pub struct CasServerLimits {
+    /// Connections served at once; more are refused
+    pub max_connections: usize,
+    /// Largest request accepted. A client sending a larger one is
+    /// disconnected, since the rest of its stream can't be trusted.
+    pub max_request_size: usize,
+    /// Connections idle this long are closed, freeing their slot. Pooled
+    /// clients reconnect when they next need one.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for CasServerLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 256,
+            max_request_size: 16 * 1024 * 1024,
+            idle_timeout: Some(Duration::from_secs(10 * 60)),
+        }
+    }
+}
+
+/// Cap on the space CAS storage may use
+#[derive(Debug, Clone, Copy)]
+pub struct CasQuota {
+    /// Total size of the stored blocks
+    pub max_bytes: u64,
+}
+
+/// Space used by CAS storage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct StorageUsage {
+    pub blocks: u64,
+    pub bytes: u64,
+    pub max_bytes: Option<u64>,
+    /// Writes refused for going over the quota
+    pub refused_writes: u64,
+}
+
+#[derive(Default)]
+struct UsageCounters {
+    blocks: AtomicU64,
+    bytes: AtomicU64,
+    refused_writes: AtomicU64,
+}
+
+/// Reads a server's storage usage while it serves
+#[derive(Clone)]
+pub struct UsageHandle(Arc<CasBlocks>);
+
+impl UsageHandle {
+    pub fn usage(&self) -> StorageUsage {
+        let counters = self.0.usage.as_ref().expect("usage handle without tracking");
+        StorageUsage {
+            blocks: counters.blocks.load(Ordering::Relaxed),
+            bytes: counters.bytes.load(Ordering::Relaxed),
+            max_bytes: self.0.quota.map(|quota| quota.max_bytes),
+            refused_writes: counters.refused_writes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Counting semaphore for connection slots
+struct Slots {
+    free: Mutex<usize>,
+}
+
+impl Slots {
+    fn new(count: usize) -> Self {
+        Self {
+            free: Mutex::new(count.max(1)),
+        }
+    }
+
+    /// Take a free slot, if there is one, returning it when the guard
+    /// drops
+    fn try_acquire(self: &Arc<Self>) -> Option<SlotGuard> {
+        let mut free = self.free.lock().unwrap();
+        if *free == 0 {
+            return None;
+        }
+        *free -= 1;
+        Some(SlotGuard(self.clone()))
+    }
+}
+
+struct SlotGuard(Arc<Slots>);
+
+impl Drop for SlotGuard {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+    }
+}
+
+/// Locks serializing requests for the same hash
+const HASH_STRIPES: usize = 256;
+
+/// What a server keeps blocks in
+#[derive(Clone)]
+enum Store {
+    /// CAS storage, keyed by xxHash3-128 of the data
+    Cas(Arc<CasBlocks>),
+    /// Blob store, keyed by BLAKE3 hash (or an encrypting client's locator)
+    Blobs(Arc<dyn BlobStore>),
+}
+
 /// CAS TCP server
 pub struct CasServer {
     config: CasServerConfig,
-    storage: Arc<Mutex<CasStorage>>,
+    store: Store,
+    limits: CasServerLimits,
+    tls: Option<Arc<TlsServer>>,
 }
 
 impl CasServer {
     /// Create a new CAS server
     pub fn new(config: CasServerConfig) -> io::Result<Self> {
         let storage = CasStorage::new(&config.storage_path)?;
+        let blocks = CasBlocks {
+            storage,
+            stripes: (0..HASH_STRIPES).map(|_| Mutex::new(())).collect(),
+            recent: Mutex::new(RecentWrites::new(DEFAULT_DELETE_GRACE)),
+            usage: None,
+            quota: None,
+        };
         Ok(Self {
             config,
-            storage: Arc::new(Mutex::new(storage)),
+            store: Store::Cas(Arc::new(blocks)),
+            limits: CasServerLimits::default(),
+            tls: None,
         })
     }
 
+    /// Limit connections and requests (see `CasServerLimits`)
+    pub fn with_limits(mut self, limits: CasServerLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Only serve clients that complete a mutual TLS handshake
+    pub fn with_tls(mut self, tls: Arc<TlsServer>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Keep blocks written less than `grace` ago (and every block for
+    /// `grace` after starting) when asked to delete them. Zero turns the
+    /// check off. Blob stores don't have one: their clients keep their own
+    /// reference counts.
+    pub fn with_delete_grace(self, grace: Duration) -> Self {
+        if let Store::Cas(blocks) = &self.store {
+            *blocks.recent.lock().unwrap() = RecentWrites::new(grace);
+        }
+        self
+    }
+
+    /// Track the space CAS storage uses, for `usage_handle`. Walks the
+    /// store once to count what is already there.
+    pub fn track_usage(mut self) -> io::Result<Self> {
+        if let Store::Cas(blocks) = &mut self.store {
+            let blocks = Arc::get_mut(blocks).expect("track_usage called while serving");
+            if blocks.usage.is_none() {
+                let (count, bytes) = blocks.storage.usage()?;
+                log::info!("CAS storage holds {} blocks, {} bytes", count, bytes);
+                let usage = UsageCounters::default();
+                usage.blocks.store(count, Ordering::Relaxed);
+                usage.bytes.store(bytes, Ordering::Relaxed);
+                blocks.usage = Some(usage);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Limit the space CAS storage uses (see `CasQuota`). Blob stores
+    /// aren't limited.
+    pub fn with_quota(mut self, quota: CasQuota) -> io::Result<Self> {
+        self = self.track_usage()?;
+        if let Store::Cas(blocks) = &mut self.store {
+            Arc::get_mut(blocks).expect("with_quota called while serving").quota = Some(quota);
+        }
+        Ok(self)
+    }
+
+    /// Read storage usage while serving; None for a blob store or before
+    /// `track_usage`
+    pub fn usage_handle(&self) -> Option<UsageHandle> {
+        match &self.store {
+            Store::Cas(blocks) if blocks.usage.is_some() => Some(UsageHandle(blocks.clone())),
+            _ => None,
+        }
+    }
+
+    /// Create a server for a blob store rather than CAS storage. The
+    /// store is used as given; `config.storage_path` is only reported.
+    pub fn for_blob_store(config: CasServerConfig, store: Arc<dyn BlobStore>) -> Self {
+        Self {
+            config,
+            store: Store::Blobs(store),
+            limits: CasServerLimits::default(),
+            tls: None,
+        }
+    }
+
     /// Run the server
     pub fn run(&self) -> io::Result<()> {
-        let listener = TcpListener::bind(&self.config.bind_addr)?;
-        log::info!("CAS server listening on {}", self.config.bind_addr);
+        let listener = Listener::bind(&self.config.bind_addr)?;
+        self.serve(listener)
+    }
 
-        for stream in listener.incoming() {
-            match stream {
+    /// Serve clients on an already-bound listener (e.g. socket activation)
+    pub fn serve(&self, listener: impl Into<Listener>) -> io::Result<()> {
+        let listener = listener.into();
+        log::info!("CAS server listening on {}", listener.local_addr()?);
+
+        let slots = Arc::new(Slots::new(self.limits.max_connections));
+        loop {
+            match listener.accept() {
                 Ok(stream) => {
-                    let storage = Arc::clone(&self.storage);
+                    let Some(slot) = slots.try_acquire() else {
+                        self.refuse(stream);
+                        continue;
+                    };
+                    let store = self.store.clone();
+                    let limits = self.limits.clone();
+                    let tls = self.tls.clone();
                     thread::spawn(move || {
-                        if let Err(e) = handle_client(stream, storage) {
+                        let _slot = slot;
+                        let stream = match tls {
+                            Some(tls) => {
+                                let peer = stream.peer_addr().unwrap_or_default();
+                                match tls.accept(stream) {
+                                    Ok(stream) => stream,
+                                    Err(e) => {
+                                        log::warn!("TLS handshake with {} failed: {}", peer, e);
+                                        return;
+                                    }
+                                }
+                            }
+                            None => stream,
+                        };
+                        if let Err(e) = handle_client(stream, store, &limits) {
                             log::warn!("Client handler error: {}", e);
                         }
                     });
@@ -60,24 +487,39 @@ impl CasServer {
                 }
             }
         }
+    }
 
-        Ok(())
+    /// Turn away a client arriving while `max_connections` are served. It
+    /// reads the error as the answer to its first request.
+    fn refuse(&self, mut stream: Stream) {
+        let peer = stream.peer_addr().unwrap_or_default();
+        let max = self.limits.max_connections;
+        log::warn!("Refusing connection from {}: serving {} already", peer, max);
+        if self.tls.is_none() {
+            let message = format!("{}: serving {} connections already", SERVER_BUSY, max);
+            let _ = write_response(&mut stream, &CasResponse::Error(message));
+        }
     }
 }
 
 /// Handle a client connection
-fn handle_client(mut stream: TcpStream, storage: Arc<Mutex<CasStorage>>) -> io::Result<()> {
+fn handle_client(mut stream: Stream, store: Store, limits: &CasServerLimits) -> io::Result<()> {
     let peer = stream.peer_addr()?;
     log::info!("New connection from {}", peer);
+    stream.set_read_timeout(limits.idle_timeout)?;
 
     loop {
         // Read frame
-        let (command, data) = match read_frame(&mut stream) {
+        let (command, data) = match read_frame_limited(&mut stream, limits.max_request_size) {
             Ok(frame) => frame,
             Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
                 log::info!("Client {} disconnected", peer);
                 return Ok(());
             }
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                log::info!("Closing idle connection from {}", peer);
+                return Ok(());
+            }
             Err(e) => {
                 log::warn!("Error reading frame from {}: {}", peer, e);
                 return Err(e);
@@ -85,57 +527,156 @@ fn handle_client(mut stream: TcpStream, storage: Arc<Mutex<CasStorage>>) -> io::
         };
 
         // Process command
-        let response = match command {
-            CasCommand::Write => {
-                let storage = storage.lock().unwrap();
-                match storage.write(&data) {
-                    Ok(hash) => CasResponse::Hash(hash),
-                    Err(e) => CasResponse::Error(format!("write failed: {}", e)),
+        let response = match &store {
+            Store::Cas(blocks) => cas_request(blocks, command, &data),
+            Store::Blobs(blobs) => blob_request(blobs.as_ref(), command, &data),
+        };
+
+        // Send response
+        if let Err(e) = write_response(&mut stream, &response) {
+            log::warn!("Error writing response to {}: {}", peer, e);
+            return Err(e);
+        }
+    }
+}
+
+/// Handle a request against CAS storage
+fn cas_request(blocks: &CasBlocks, command: CasCommand, data: &[u8]) -> CasResponse {
+    let requested = || -> Option<Hash> { data.try_into().ok() };
+    let invalid = || CasResponse::Error("invalid hash length".to_string());
+    match command {
+        CasCommand::Write => {
+            let hash = hash(data);
+            let len = data.len() as u64;
+            let reserved = match blocks.reserve(&hash, len) {
+                Ok(reserved) => reserved,
+                Err(message) => return CasResponse::Error(message),
+            };
+            let _lock = blocks.lock(&hash);
+            match blocks.storage.store(&hash, data) {
+                Ok(new) => {
+                    blocks.recent.lock().unwrap().record(hash);
+                    blocks.count_stored(len, new, reserved);
+                    CasResponse::Hash(hash)
                 }
-            }
-            CasCommand::Read => {
-                if data.len() != 16 {
-                    CasResponse::Error("invalid hash length".to_string())
-                } else {
-                    let mut hash = [0u8; 16];
-                    hash.copy_from_slice(&data);
-                    let storage = storage.lock().unwrap();
-                    match storage.read(&hash) {
-                        Ok(content) => CasResponse::Data(content),
-                        Err(e) => CasResponse::Error(format!("read failed: {}", e)),
-                    }
+                Err(e) => {
+                    blocks.count_stored(len, false, reserved);
+                    CasResponse::Error(format!("write failed: {}", e))
                 }
             }
-            CasCommand::Exists => {
-                if data.len() != 16 {
-                    CasResponse::Error("invalid hash length".to_string())
-                } else {
-                    let mut hash = [0u8; 16];
-                    hash.copy_from_slice(&data);
-                    let storage = storage.lock().unwrap();
-                    CasResponse::Exists(storage.exists(&hash))
+        }
+        CasCommand::Read => match requested() {
+            Some(hash) => match blocks.storage.read(&hash) {
+                Ok(content) => CasResponse::Data(content),
+                Err(e) => CasResponse::Error(format!("read failed: {}", e)),
+            },
+            None => invalid(),
+        },
+        CasCommand::Exists => match requested() {
+            Some(hash) => CasResponse::Exists(blocks.storage.exists(&hash)),
+            None => invalid(),
+        },
+        CasCommand::Delete => match requested() {
+            Some(hash) => {
+                let _lock = blocks.lock(&hash);
+                if blocks.recent.lock().unwrap().contains(&hash) {
+                    log::debug!("Kept recently written CAS block: {}", hex::encode(hash));
+                    return CasResponse::Deleted(DeleteOutcome::Kept);
                 }
-            }
-            CasCommand::Delete => {
-                if data.len() != 16 {
-                    CasResponse::Error("invalid hash length".to_string())
-                } else {
-                    let mut hash = [0u8; 16];
-                    hash.copy_from_slice(&data);
-                    let storage = storage.lock().unwrap();
-                    match storage.delete(&hash) {
-                        Ok(deleted) => CasResponse::Deleted(deleted),
-                        Err(e) => CasResponse::Error(format!("delete failed: {}", e)),
-                    }
+                match blocks.remove(&hash) {
+                    Ok(Some(_)) => CasResponse::Deleted(DeleteOutcome::Deleted),
+                    Ok(None) => CasResponse::Deleted(DeleteOutcome::NotFound),
+                    Err(e) => CasResponse::Error(format!("delete failed: {}", e)),
                 }
             }
-            CasCommand::Ping => CasResponse::Pong,
-        };
+            None => invalid(),
+        },
+        CasCommand::Ping => CasResponse::Pong,
+        CasCommand::Put | CasCommand::Sync | CasCommand::Space | CasCommand::Error => {
+            CasResponse::Error(format!("{:?} is not supported by CAS storage", command))
+        }
+    }
+}
 
-        // Send response
-        if let Err(e) = write_response(&mut stream, &response) {
-            log::warn!("Error writing response to {}: {}", peer, e);
-            return Err(e);
+/// A blob hash at the front of a request, and the rest of the request
+fn blob_hash(data: &[u8]) -> Option<(blob::Hash, &[u8])> {
+    let (hash, rest) = data.split_first_chunk::<32>()?;
+    Some((blob::Hash::from_bytes(*hash), rest))
+}
+
+/// Handle a request against a blob store
+fn blob_request(store: &dyn BlobStore, command: CasCommand, data: &[u8]) -> CasResponse {
+    let invalid = || CasResponse::Error("invalid hash length".to_string());
+    match command {
+        CasCommand::Put => match blob_hash(data) {
+            Some((hash, blob)) => match store.put(&hash, blob) {
+                Ok(()) => CasResponse::Stored,
+                Err(e) => CasResponse::Error(format!("put failed: {}", e)),
+            },
+            None => invalid(),
+        },
+        CasCommand::Read => match blob_hash(data) {
+            Some((hash, [])) => match store.get(&hash) {
+                Ok(content) => CasResponse::Data(content),
+                Err(BlobError::NotFound(_)) => CasResponse::Exists(false),
+                Err(e) => CasResponse::Error(format!("read failed: {}", e)),
+            },
+            _ => invalid(),
+        },
+        CasCommand::Exists => match blob_hash(data) {
+            Some((hash, [])) => match store.exists(&hash) {
+                Ok(exists) => CasResponse::Exists(exists),
+                Err(e) => CasResponse::Error(format!("exists failed: {}", e)),
+            },
+            _ => invalid(),
+        },
+        CasCommand::Delete => match blob_hash(data) {
+            Some((hash, [])) => match store.delete(&hash) {
+                Ok(()) => CasResponse::Deleted(DeleteOutcome::Deleted),
+                Err(e) => CasResponse::Error(format!("delete failed: {}", e)),
+            },
+            _ => invalid(),
+        },
+        CasCommand::Sync => match store.sync() {
+            Ok(()) => CasResponse::Synced,
+            Err(e) => CasResponse::Error(format!("sync failed: {}", e)),
+        },
+        CasCommand::Ping => CasResponse::KeyedPong(store.hash_algorithm().name()),
+        CasCommand::Space => match store.space() {
+            Ok(space) => CasResponse::Space(space),
+            Err(e) => CasResponse::Error(format!("space failed: {}", e)),
+        },
+        // Blob stores are keyed by the client, which may encrypt
+        CasCommand::Write | CasCommand::Error => {
+            CasResponse::Error(format!("{:?} is not supported by a blob store", command))
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_writes_bounded() {
+        let mut recent = RecentWrites::new(Duration::from_secs(60));
+        recent.started -= Duration::from_secs(60);
+        for i in 0..100_000u32 {
+            recent.record(hash(&i.to_le_bytes()));
+        }
+        assert!((0..100_000u32).all(|i| recent.contains(&hash(&i.to_le_bytes()))));
+        let false_positives = (100_000..200_000u32)
+            .filter(|i| recent.contains(&hash(&i.to_le_bytes())))
+            .count();
+        assert!(false_positives < 10, "{} false positives", false_positives);
+
+        // Forgotten once two grace periods have passed
+        recent.rotated -= Duration::from_secs(120);
+        assert!(!recent.contains(&hash(&0u32.to_le_bytes())));
+
+        let mut off = RecentWrites::new(Duration::ZERO);
+        off.record(hash(b"block"));
+        assert!(off.generations.is_none());
+        assert!(!off.contains(&hash(b"block")));
+    }
+}
@@ -2,11 +2,11 @@
 //!
 //! Handles content-addressable storage with xxHash3-128 hashing.
 
-use super::Hash;
-use xxhash_rust::xxh3::xxh3_128;
+use super::{hash, Hash};
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 /// Content-addressable storage
 pub struct CasStorage {
@@ -23,24 +23,39 @@ impl CasStorage {
 
     /// Write data and return its hash
     pub fn write(&self, data: &[u8]) -> io::Result<Hash> {
-        // Calculate hash using xxHash3-128
-        let hash_u128 = xxh3_128(data);
-        let hash: Hash = hash_u128.to_le_bytes();
+        let hash = hash(data);
+        self.store(&hash, data)?;
+        Ok(hash)
+    }
+
+    /// Write data under a hash the caller has already computed with
+    /// `cas::hash`, returning whether it is new. The block is written to a
+    /// temporary file and renamed into place, so concurrent readers never
+    /// see it half-written.
+    pub fn store(&self, hash: &Hash, data: &[u8]) -> io::Result<bool> {
+        static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
 
         // Write to file (organized in subdirectories by first 2 hex chars)
-        let path = self.hash_to_path(&hash);
+        let path = self.hash_to_path(hash);
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
 
         // Only write if doesn't exist (content-addressable = immutable)
         if !path.exists() {
-            let mut file = File::create(&path)?;
-            file.write_all(data)?;
-            file.sync_all()?;
+            let n = NEXT_TMP.fetch_add(1, Ordering::Relaxed);
+            let tmp = path.with_extension(format!("tmp.{}.{}", std::process::id(), n));
+            let written = File::create(&tmp)
+                .and_then(|mut file| file.write_all(data).and_then(|()| file.sync_all()))
+                .and_then(|()| fs::rename(&tmp, &path));
+            if written.is_err() {
+                let _ = fs::remove_file(&tmp);
+            }
+            written?;
+            return Ok(true);
         }
 
-        Ok(hash)
+        Ok(false)
     }
 
     /// Read data by hash
@@ -70,6 +85,53 @@ impl CasStorage {
         }
     }
 
+    /// Size of a stored block, or None if it isn't stored
+    pub fn size(&self, hash: &Hash) -> io::Result<Option<u64>> {
+        match fs::metadata(self.hash_to_path(hash)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Number and total size of the stored blocks. Walks the whole store.
+    pub fn usage(&self) -> io::Result<(u64, u64)> {
+        let (mut blocks, mut bytes) = (0, 0);
+        for prefix in 0..=u8::MAX {
+            self.for_each_block_in(prefix, |size| {
+                blocks += 1;
+                bytes += size;
+            })?;
+        }
+        Ok((blocks, bytes))
+    }
+
+    /// Call `f` with the size of each block whose hash starts with `prefix`
+    fn for_each_block_in(&self, prefix: u8, mut f: impl FnMut(u64)) -> io::Result<()> {
+        let dir = self.base_path.join(hex::encode([prefix]));
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = format!("{:02x}{}", prefix, entry.file_name().to_string_lossy());
+            // Temporary files of writes in progress don't parse
+            if !matches!(hex::decode(&name).map(Hash::try_from), Ok(Ok(_))) {
+                continue;
+            }
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                // Deleted since the directory was listed
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            f(metadata.len());
+        }
+        Ok(())
+    }
+
     /// Convert hash to file path (organized as base/XX/YYYYYYYY...)
     fn hash_to_path(&self, hash: &Hash) -> PathBuf {
         let hex = hex::encode(hash);
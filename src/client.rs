@@ -0,0 +1,676 @@
+//! AoE initiator
+//!
+//! The client side of the protocol: find targets with a broadcast Config
+//! query, then issue ATA commands to one of them. Each request gets a new
+//! tag and its response is matched on tag and source MAC. A request left
+//! unanswered within the timeout is retransmitted with the same tag, so the
+//! server replays its cached response instead of running a write twice.
+//!
+//! Frames go through a `Transport`: `EthernetTransport` uses a raw socket
+//! on a network interface (root or CAP_NET_RAW), while `LoopbackTransport`
+//! hands them straight to an in-process `TargetManager`.
+//!
+//! Config strings are free-form bytes. Many setups keep `key=value` fields
+//! in them, such as `host=db1 lun=3`; `config_fields` reads those.
+
+use crate::protocol::{
+    build_response, parse_frame, AoeCommand, AoeFlags, AoeFrame, AoeHeader, AoePayload,
+    AtaCommand, AtaFlags, ConfigCommand, ParseError, AOE_ETHERTYPE, AOE_VERSION, BROADCAST_MAC,
+    BROADCAST_SHELF, BROADCAST_SLOT,
+};
+use crate::server::{format_mac, TargetManager};
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender};
+use std::collections::VecDeque;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// Client errors
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("no response to tag {tag:#010x} after {attempts} attempt(s)")]
+    Timeout { tag: u32, attempts: u32 },
+
+    #[error("target e{shelf}.{slot} not found")]
+    NotFound { shelf: u16, slot: u8 },
+
+    #[error("target returned AoE error {0}")]
+    Aoe(u8),
+
+    #[error("ATA command failed: status {status:#04x}, error {error:#04x}")]
+    Ata { status: u8, error: u8 },
+
+    #[error("malformed response: {0}")]
+    Parse(#[from] ParseError),
+
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+}
+
+pub type ClientResult<T> = Result<T, ClientError>;
+
+/// Sends and receives raw Ethernet frames
+pub trait Transport: Send {
+    /// MAC address requests are sent from
+    fn local_mac(&self) -> [u8; 6];
+
+    /// Send one frame
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+
+    /// Next AoE frame addressed to us, or None once `timeout` passes
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Raw Ethernet transport on a network interface
+pub struct EthernetTransport {
+    mac: [u8; 6],
+    tx: Box<dyn DataLinkSender>,
+    rx: Box<dyn DataLinkReceiver>,
+}
+
+impl EthernetTransport {
+    /// Open a raw socket on `interface`
+    pub fn open(interface: &str) -> io::Result<Self> {
+        let iface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("interface not found: {}", interface),
+                )
+            })?;
+        let mac = iface
+            .mac
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("interface has no MAC: {}", interface),
+                )
+            })?
+            .octets();
+
+        let config = datalink::Config {
+            read_timeout: Some(Duration::from_millis(20)),
+            ..Default::default()
+        };
+        match datalink::channel(&iface, config)? {
+            Channel::Ethernet(tx, rx) => Ok(Self { mac, tx, rx }),
+            _ => Err(io::Error::other("unsupported channel type")),
+        }
+    }
+}
+
+impl Transport for EthernetTransport {
+    fn local_mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send_to(frame, None)
+            .unwrap_or_else(|| Err(io::Error::other("send failed")))
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match self.rx.next() {
+                Ok(packet) => {
+                    let is_aoe = packet.len() >= AoeHeader::SIZE
+                        && packet[12..14] == AOE_ETHERTYPE.to_be_bytes();
+                    if is_aoe && packet[0..6] == self.mac {
+                        return Ok(Some(packet.to_vec()));
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// In-process transport answering from a `TargetManager`, for tests and
+/// tools that don't need a network
+pub struct LoopbackTransport {
+    targets: Arc<TargetManager>,
+    responses: VecDeque<Vec<u8>>,
+}
+
+impl LoopbackTransport {
+    /// MAC the loopback "server" answers from
+    pub const SERVER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x01];
+    /// MAC of the loopback client
+    pub const CLIENT_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x02];
+
+    pub fn new(targets: Arc<TargetManager>) -> Self {
+        Self {
+            targets,
+            responses: VecDeque::new(),
+        }
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn local_mac(&self) -> [u8; 6] {
+        Self::CLIENT_MAC
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let mut request =
+            parse_frame(frame).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        // As if it had arrived at the server's NIC
+        request.header.dst_mac = Self::SERVER_MAC;
+
+        for addr in self.targets.matching_targets(&request) {
+            if let Ok(response) = self.targets.handle_target_frame(&request, addr) {
+                self.responses
+                    .push_back(build_response(&request, response, addr.shelf, addr.slot));
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, _timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        Ok(self.responses.pop_front())
+    }
+}
+
+/// A target that answered a Config query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteTarget {
+    pub shelf: u16,
+    pub slot: u8,
+    /// MAC the target answered from
+    pub mac: [u8; 6],
+    /// Requests the target can queue
+    pub buffer_count: u16,
+    /// Sectors per ATA request
+    pub max_sectors: u8,
+    pub firmware_version: u16,
+    pub config_string: Vec<u8>,
+}
+
+impl RemoteTarget {
+    /// Name in the usual `e<shelf>.<slot>` form
+    pub fn name(&self) -> String {
+        format!("e{}.{}", self.shelf, self.slot)
+    }
+
+    /// MAC the target answered from, as `aa:bb:cc:dd:ee:ff`
+    pub fn mac_string(&self) -> String {
+        format_mac(&self.mac)
+    }
+
+    /// Config string as text, with bytes that aren't printable ASCII
+    /// escaped
+    pub fn config_text(&self) -> String {
+        self.config_string.escape_ascii().to_string()
+    }
+}
+
+/// The `key=value` fields of a config string, in order, if it consists of
+/// nothing else. Fields are separated by spaces, commas or semicolons.
+pub fn config_fields(config_string: &[u8]) -> Option<Vec<(String, String)>> {
+    let text = std::str::from_utf8(config_string).ok()?;
+    let fields: Vec<_> = text
+        .split(|c: char| c.is_ascii_whitespace() || c == ',' || c == ';')
+        .filter(|field| !field.is_empty())
+        .map(|field| match field.split_once('=') {
+            Some((key, value)) if !key.is_empty() => Some((key.to_string(), value.to_string())),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    (!fields.is_empty()).then_some(fields)
+}
+
+/// A target opened for I/O
+#[derive(Debug, Clone)]
+pub struct Disk {
+    pub target: RemoteTarget,
+    pub total_sectors: u64,
+    pub sector_size: u32,
+    pub model: String,
+    pub serial: String,
+    /// ATA firmware revision
+    pub firmware: String,
+}
+
+/// Client timeouts
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// How long to wait for a response before retransmitting
+    pub timeout: Duration,
+    /// Retransmissions before giving up
+    pub retries: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            retries: 4,
+        }
+    }
+}
+
+/// AoE initiator
+pub struct AoeClient<T: Transport> {
+    transport: T,
+    config: ClientConfig,
+    next_tag: u32,
+}
+
+impl<T: Transport> AoeClient<T> {
+    pub fn new(transport: T) -> Self {
+        Self::with_config(transport, ClientConfig::default())
+    }
+
+    pub fn with_config(transport: T, config: ClientConfig) -> Self {
+        Self {
+            transport,
+            config,
+            // Random start so a new client can't hit responses the server
+            // cached for an earlier one
+            next_tag: rand::random::<u32>() & 0x7FFF_FFFF,
+        }
+    }
+
+    pub fn transport_mut(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Broadcast a Config query and collect every target that answers
+    /// within `wait`
+    pub fn discover(&mut self, wait: Duration) -> ClientResult<Vec<RemoteTarget>> {
+        let tag = self.tag();
+        let query = self.config_query(BROADCAST_MAC, BROADCAST_SHELF, BROADCAST_SLOT, tag);
+        self.transport.send(&query)?;
+
+        let mut found: Vec<RemoteTarget> = Vec::new();
+        let deadline = Instant::now() + wait;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            let Some(frame) = self.transport.recv(remaining)? else {
+                break;
+            };
+            let Ok(frame) = parse_frame(&frame) else {
+                continue;
+            };
+            if frame.header.tag != tag || !frame.header.flags.response || frame.header.flags.error {
+                continue;
+            }
+            if let Some(target) = remote_target(&frame) {
+                if !found.contains(&target) {
+                    found.push(target);
+                }
+            }
+        }
+
+        found.sort_by_key(|t| (t.shelf, t.slot));
+        Ok(found)
+    }
+
+    /// Locate one target by address
+    pub fn find(&mut self, shelf: u16, slot: u8) -> ClientResult<RemoteTarget> {
+        let tag = self.tag();
+        let query = self.config_query(BROADCAST_MAC, shelf, slot, tag);
+        match self.transact(tag, &query, |frame| frame.header.command == AoeCommand::Config) {
+            Ok(frame) => remote_target(&frame).ok_or(ClientError::NotFound { shelf, slot }),
+            Err(ClientError::Timeout { .. }) => Err(ClientError::NotFound { shelf, slot }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// IDENTIFY a target to learn its size, ready for I/O
+    pub fn open(&mut self, target: RemoteTarget) -> ClientResult<Disk> {
+        let data = self.ata(&target, AtaCommand::IdentifyDevice, 0, 1, &[])?;
+        if data.len() < 512 {
+            return Err(ClientError::InvalidArgument(format!(
+                "short IDENTIFY response: {} bytes",
+                data.len()
+            )));
+        }
+
+        let word = |n: usize| u16::from_le_bytes([data[n * 2], data[n * 2 + 1]]);
+        let lba48 = u64::from_le_bytes(data[200..208].try_into().unwrap());
+        let lba28 = u32::from_le_bytes(data[120..124].try_into().unwrap()) as u64;
+        // Word 106 bit 12: logical sector size is in words 117-118
+        let sector_size = if word(106) & 0xC000 == 0x4000 && word(106) & 0x1000 != 0 {
+            u32::from_le_bytes(data[234..238].try_into().unwrap()) * 2
+        } else {
+            512
+        };
+
+        Ok(Disk {
+            target,
+            total_sectors: if lba48 > 0 { lba48 } else { lba28 },
+            sector_size,
+            model: ata_string(&data[54..94]),
+            serial: ata_string(&data[20..40]),
+            firmware: ata_string(&data[46..54]),
+        })
+    }
+
+    /// Read `count` sectors starting at `lba`
+    pub fn read(&mut self, disk: &Disk, lba: u64, count: u64) -> ClientResult<Vec<u8>> {
+        check_range(disk, lba, count)?;
+        let mut out = Vec::with_capacity((count * disk.sector_size as u64) as usize);
+        let mut done = 0;
+        while done < count {
+            let n = (count - done).min(disk.target.max_sectors.max(1) as u64) as u8;
+            let data = self.ata(&disk.target, AtaCommand::ReadSectorsExt, lba + done, n, &[])?;
+            out.extend_from_slice(&data);
+            done += n as u64;
+        }
+        Ok(out)
+    }
+
+    /// Write whole sectors starting at `lba`
+    pub fn write(&mut self, disk: &Disk, lba: u64, data: &[u8]) -> ClientResult<()> {
+        let sector_size = disk.sector_size as usize;
+        if !data.len().is_multiple_of(sector_size) {
+            return Err(ClientError::InvalidArgument(format!(
+                "write of {} bytes is not a multiple of {}-byte sectors",
+                data.len(),
+                sector_size
+            )));
+        }
+        let count = (data.len() / sector_size) as u64;
+        check_range(disk, lba, count)?;
+
+        let per_request = disk.target.max_sectors.max(1) as usize;
+        for (i, chunk) in data.chunks(per_request * sector_size).enumerate() {
+            let n = (chunk.len() / sector_size) as u8;
+            let lba = lba + (i * per_request) as u64;
+            self.ata(&disk.target, AtaCommand::WriteSectorsExt, lba, n, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Ask the target to flush its write cache
+    pub fn flush(&mut self, disk: &Disk) -> ClientResult<()> {
+        self.ata(&disk.target, AtaCommand::FlushCacheExt, 0, 0, &[])?;
+        Ok(())
+    }
+
+    /// Issue one ATA command, returning the data the target sent back
+    fn ata(
+        &mut self,
+        target: &RemoteTarget,
+        command: AtaCommand,
+        lba: u64,
+        count: u8,
+        data: &[u8],
+    ) -> ClientResult<Vec<u8>> {
+        let tag = self.tag();
+        let request = self.ata_request(target, command, lba, count, data, tag);
+        let frame = self.transact(tag, &request, |frame| {
+            frame.header.command == AoeCommand::Ata
+                && frame.header.src_mac == target.mac
+                && frame.header.shelf == target.shelf
+                && frame.header.slot == target.slot
+        })?;
+
+        match frame.payload {
+            AoePayload::Ata { header, data } => {
+                if header.cmd_status & crate::protocol::ata_status::ERR != 0 {
+                    return Err(ClientError::Ata {
+                        status: header.cmd_status,
+                        error: header.err_feature,
+                    });
+                }
+                Ok(data)
+            }
+            AoePayload::Config(_) => Err(ClientError::InvalidArgument(
+                "Config response to ATA request".to_string(),
+            )),
+        }
+    }
+
+    /// Send a request, retransmitting it until a matching response arrives
+    fn transact<M>(&mut self, tag: u32, request: &[u8], matches: M) -> ClientResult<AoeFrame>
+    where
+        M: Fn(&AoeFrame) -> bool,
+    {
+        let attempts = self.config.retries + 1;
+
+        for attempt in 0..attempts {
+            if attempt > 0 {
+                log::debug!("Retransmitting tag {:#010x} (attempt {})", tag, attempt + 1);
+            }
+            self.transport.send(request)?;
+
+            let deadline = Instant::now() + self.config.timeout;
+            loop {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining.is_zero() {
+                    break;
+                }
+                let Some(raw) = self.transport.recv(remaining)? else {
+                    break;
+                };
+                let Ok(frame) = parse_frame(&raw) else {
+                    continue;
+                };
+                if !frame.header.flags.response || frame.header.tag != tag || !matches(&frame) {
+                    continue;
+                }
+                if frame.header.flags.error {
+                    return Err(ClientError::Aoe(frame.header.error));
+                }
+                return Ok(frame);
+            }
+        }
+
+        Err(ClientError::Timeout { tag, attempts })
+    }
+
+    fn tag(&mut self) -> u32 {
+        let tag = self.next_tag;
+        self.next_tag = self.next_tag.wrapping_add(1) & 0x7FFF_FFFF;
+        tag
+    }
+
+    /// Ethernet + AoE common header
+    fn header(&self, dst: [u8; 6], shelf: u16, slot: u8, command: AoeCommand, tag: u32) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(AoeHeader::SIZE);
+        frame.extend_from_slice(&dst);
+        frame.extend_from_slice(&self.transport.local_mac());
+        frame.extend_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+        frame.push(AoeFlags::default().to_byte(AOE_VERSION));
+        frame.push(0);
+        frame.extend_from_slice(&shelf.to_be_bytes());
+        frame.push(slot);
+        frame.push(command as u8);
+        frame.extend_from_slice(&tag.to_be_bytes());
+        frame
+    }
+
+    fn config_query(&self, dst: [u8; 6], shelf: u16, slot: u8, tag: u32) -> Vec<u8> {
+        let mut frame = self.header(dst, shelf, slot, AoeCommand::Config, tag);
+        frame.extend_from_slice(&0u16.to_be_bytes()); // buffer count
+        frame.extend_from_slice(&0u16.to_be_bytes()); // firmware version
+        frame.push(0); // sector count
+        frame.push((AOE_VERSION << 4) | ConfigCommand::Read as u8);
+        frame.extend_from_slice(&0u16.to_be_bytes()); // config string length
+        frame
+    }
+
+    fn ata_request(
+        &self,
+        target: &RemoteTarget,
+        command: AtaCommand,
+        lba: u64,
+        count: u8,
+        data: &[u8],
+        tag: u32,
+    ) -> Vec<u8> {
+        let mut frame = self.header(target.mac, target.shelf, target.slot, AoeCommand::Ata, tag);
+        let flags = AtaFlags {
+            extended: true,
+            write: !data.is_empty(),
+            ..Default::default()
+        };
+        frame.push(flags.to_byte());
+        frame.push(0); // feature
+        frame.push(count);
+        frame.push(command as u8);
+        frame.extend_from_slice(&lba.to_le_bytes()[..6]);
+        frame.extend_from_slice(&[0, 0]); // reserved
+        frame.extend_from_slice(data);
+        frame
+    }
+}
+
+fn remote_target(frame: &AoeFrame) -> Option<RemoteTarget> {
+    match &frame.payload {
+        AoePayload::Config(config) => Some(RemoteTarget {
+            shelf: frame.header.shelf,
+            slot: frame.header.slot,
+            mac: frame.header.src_mac,
+            buffer_count: config.buffer_count,
+            max_sectors: config.sector_count,
+            firmware_version: config.firmware_version,
+            config_string: config.config_string.clone(),
+        }),
+        AoePayload::Ata { .. } => None,
+    }
+}
+
+fn check_range(disk: &Disk, lba: u64, count: u64) -> ClientResult<()> {
+    if lba.checked_add(count).is_none_or(|end| end > disk.total_sectors) {
+        return Err(ClientError::InvalidArgument(format!(
+            "sectors {}..{} beyond end of disk ({} sectors)",
+            lba,
+            lba.saturating_add(count),
+            disk.total_sectors
+        )));
+    }
+    Ok(())
+}
+
+/// Decode a word-swapped, space-padded ATA string
+fn ata_string(raw: &[u8]) -> String {
+    let bytes: Vec<u8> = raw.chunks(2).flat_map(|w| [w[1], w[0]]).collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemBackend;
+
+    /// Loses the first `drop` frames sent through it
+    struct Lossy {
+        inner: LoopbackTransport,
+        drop: usize,
+        sent: usize,
+    }
+
+    impl Transport for Lossy {
+        fn local_mac(&self) -> [u8; 6] {
+            self.inner.local_mac()
+        }
+
+        fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+            self.sent += 1;
+            if self.drop > 0 {
+                self.drop -= 1;
+                return Ok(());
+            }
+            self.inner.send(frame)
+        }
+
+        fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+            self.inner.recv(timeout)
+        }
+    }
+
+    fn manager() -> Arc<TargetManager> {
+        let mut manager = TargetManager::new();
+        manager.add_target(1, 0, Box::new(MemBackend::new(1024 * 1024)), "one".to_string());
+        manager.add_target(1, 1, Box::new(MemBackend::new(2 * 1024 * 1024)), String::new());
+        Arc::new(manager)
+    }
+
+    #[test]
+    fn test_discover_read_write() {
+        let mut client = AoeClient::new(LoopbackTransport::new(manager()));
+
+        let targets = client.discover(Duration::from_millis(50)).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[0].config_string, b"one");
+        assert_eq!(targets[0].name(), "e1.0");
+        assert_eq!(targets[1].mac_string(), "02:00:00:00:00:01");
+        assert_eq!(targets[1].mac, LoopbackTransport::SERVER_MAC);
+
+        let target = client.find(1, 1).unwrap();
+        let disk = client.open(target).unwrap();
+        assert_eq!(disk.total_sectors, 4096);
+        assert_eq!(disk.sector_size, 512);
+        assert_eq!(disk.model, "AoE Memory Backend");
+        assert_eq!(disk.firmware, env!("CARGO_PKG_VERSION"));
+
+        // Spans several requests at the advertised sectors per frame
+        let data: Vec<u8> = (0..5 * 512).map(|i| (i % 251) as u8).collect();
+        client.write(&disk, 10, &data).unwrap();
+        client.flush(&disk).unwrap();
+        assert_eq!(client.read(&disk, 10, 5).unwrap(), data);
+
+        assert!(matches!(
+            client.read(&disk, 4095, 2),
+            Err(ClientError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            client.find(2, 0),
+            Err(ClientError::NotFound { shelf: 2, slot: 0 })
+        ));
+    }
+
+    #[test]
+    fn test_retransmits_lost_requests() {
+        let config = ClientConfig {
+            timeout: Duration::from_millis(10),
+            retries: 2,
+        };
+        let lossy = Lossy {
+            inner: LoopbackTransport::new(manager()),
+            drop: 2,
+            sent: 0,
+        };
+        let mut client = AoeClient::with_config(lossy, config);
+
+        let target = client.find(1, 0).unwrap();
+        assert_eq!(client.transport.sent, 3);
+
+        client.transport.drop = 3;
+        assert!(matches!(client.open(target), Err(ClientError::Timeout { attempts: 3, .. })));
+    }
+
+    #[test]
+    fn test_config_fields() {
+        let fields = config_fields(b"host=db1 lun=3;rack=a,").unwrap();
+        let expected = [("host", "db1"), ("lun", "3"), ("rack", "a")];
+        assert!(fields.iter().map(|(k, v)| (k.as_str(), v.as_str())).eq(expected));
+        assert_eq!(config_fields(b"key="), Some(vec![("key".to_string(), String::new())]));
+
+        assert_eq!(config_fields(b"aoe-disk-1"), None);
+        assert_eq!(config_fields(b"host=db1 spare"), None);
+        assert_eq!(config_fields(b"=x"), None);
+        assert_eq!(config_fields(b""), None);
+        assert_eq!(config_fields(b"k=\xff"), None);
+    }
+}
@@ -1,11 +1,36 @@
 //! Configuration file parsing
 //!
 //! Parses TOML configuration files for the AoE server.
+//!
+//! Config files carry a schema `version`. Files without one are version 1,
+//! which silently ignored keys it didn't know; from version 2 an unknown
+//! key is an error, so a typo or an option from a newer release can't go
+//! unnoticed. Version 1 files still load, with the keys they would lose
+//! listed in `Config::ignored_keys`, and `upgrade` rewrites a file to the
+//! current version, keeping its comments and layout.
+//!
+//! String values may refer to environment variables as `${NAME}` (or
+//! `${NAME:-default}`), so secrets such as encryption keys needn't live in
+//! the file; `$${` is a literal `${`. A top-level `include` list of paths
+//! or file name globs, relative to the config file, adds the `[[target]]`
+//! tables of each matching file, so per-target definitions can be kept in
+//! a directory such as `targets.d/`.
 
-use serde::Deserialize;
-use std::path::Path;
+use crate::blob::{BlobStoreSpec, HashAlgorithm};
+use crate::ha::HaConfig;
+use crate::logging::LogFormat;
+use crate::protocol::{Addressing, LBA48_MAX_SECTORS};
+use crate::qos::QosLimits;
+use crate::storage::{DeviceStrings, RetentionRule, TargetUuid};
+use crate::tls::TlsConfig;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
+/// Config schema version written by `upgrade`
+pub const CONFIG_VERSION: u32 = 2;
+
 /// Configuration errors
 #[derive(Debug, Error)]
 pub enum ConfigError {
@@ -20,25 +45,91 @@ pub enum ConfigError {
 }
 
 /// Server configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Config {
+    /// Schema version (1 if unset)
+    #[serde(default = "default_version")]
+    pub version: u32,
+
+    /// Files whose targets are added to this config (globs allowed in the
+    /// file name)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+
     /// Server settings
     pub server: ServerConfig,
 
     /// Target configurations
     #[serde(default)]
     pub target: Vec<TargetConfig>,
+
+    /// Active/passive failover with another server
+    #[serde(default)]
+    pub ha: Option<HaConfig>,
+
+    /// Unknown keys in a version 1 file, which are ignored
+    #[serde(skip)]
+    pub ignored_keys: Vec<String>,
+}
+
+fn default_version() -> u32 {
+    1
 }
 
 /// Server settings
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ServerConfig {
-    /// Network interface to listen on
+    /// Network interface to listen on, or "any" or a glob such as "en*"
+    /// for every matching interface
     pub interface: String,
 
     /// Log level (trace, debug, info, warn, error)
     #[serde(default = "default_log_level")]
     pub log_level: String,
+
+    /// Log line format: "text" or "json"
+    #[serde(default)]
+    pub log_format: LogFormat,
+
+    /// Bind address for the HTTP management API (disabled if unset)
+    #[serde(default)]
+    pub api: Option<String>,
+
+    /// Bearer token the HTTP API requires on every request. Without one
+    /// the API only binds to loopback addresses.
+    #[serde(default)]
+    pub api_token: Option<String>,
+
+    /// File keeping config strings set by initiators across restarts.
+    /// Without it they last until the server stops.
+    #[serde(default)]
+    pub state_file: Option<String>,
+
+    /// Interface MTU, which bounds the sectors per request targets
+    /// advertise. Read from the interface if unset.
+    #[serde(default)]
+    pub mtu: Option<u32>,
+
+    /// Seconds between backend health checks; targets whose backend is
+    /// unhealthy answer I/O with Device Unavailable. 0 disables the checks.
+    #[serde(default)]
+    pub health_check_secs: Option<u64>,
+
+    /// Milliseconds after which a single storage or blob store operation
+    /// is logged as slow (default 1000). 0 disables slow logging.
+    #[serde(default)]
+    pub slow_request_ms: Option<u64>,
+
+    /// Receive and send frames through memory-mapped AF_PACKET rings
+    /// instead of a copy per frame (Linux, single interface only)
+    #[serde(default)]
+    pub packet_ring: bool,
+
+    /// Handle requests on this many workers sharded by initiator and tag
+    /// (0 for one per CPU) instead of one worker per target. With
+    /// `packet_ring`, also the number of receive queues.
+    #[serde(default)]
+    pub workers: Option<usize>,
 }
 
 fn default_log_level() -> String {
@@ -46,7 +137,7 @@ fn default_log_level() -> String {
 }
 
 /// Target configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TargetConfig {
     /// Shelf address (0-65534)
     pub shelf: u16,
@@ -65,79 +156,506 @@ pub struct TargetConfig {
     #[serde(default)]
     pub cas: Option<CasBackendConfig>,
 
+    /// Block device backend settings
+    #[serde(default)]
+    pub device: Option<DeviceBackendConfig>,
+
+    /// Memory backend settings
+    #[serde(default)]
+    pub memory: Option<MemoryBackendConfig>,
+
     /// Config string for discovery
     #[serde(default)]
     pub config_string: String,
+
+    /// Logical sector size in bytes (512 or 4096). Devices default to their
+    /// own logical sector size, everything else to 512.
+    #[serde(default)]
+    pub sector_size: Option<u32>,
+
+    /// Read bandwidth limit in MiB/s
+    #[serde(default)]
+    pub max_read_mbps: Option<u32>,
+
+    /// Write bandwidth limit in MiB/s
+    #[serde(default)]
+    pub max_write_mbps: Option<u32>,
+
+    /// Operations per second limit (reads and writes)
+    #[serde(default)]
+    pub max_iops: Option<u32>,
+
+    /// Write MiB in flight before new writes wait (backpressure)
+    #[serde(default)]
+    pub max_inflight_write_mb: Option<u32>,
+
+    /// Requests the target queues, advertised to initiators (default 16)
+    #[serde(default)]
+    pub buffer_count: Option<u16>,
+
+    /// Sectors per request advertised to initiators (default: as many as
+    /// fit in the MTU)
+    #[serde(default)]
+    pub max_sectors: Option<u8>,
+
+    /// "lba48" (default), or "lba28" for initiators that only speak LBA28
+    /// or CHS; capacity beyond 128 GiB is then hidden
+    #[serde(default)]
+    pub addressing: Addressing,
+
+    /// Identity to report (serial and world wide name). File and CAS
+    /// stores are given it when first created and must not already have
+    /// another; set the same UUID on two servers exporting one store so
+    /// multipath sees a single disk.
+    #[serde(default)]
+    pub uuid: Option<TargetUuid>,
+
+    /// Model reported by IDENTIFY DEVICE instead of the backend's own (at
+    /// most 40 printable ASCII characters)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+
+    /// Serial number to report (at most 20 characters), instead of the one
+    /// derived from the target's identity
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+
+    /// Firmware revision to report (at most 8 characters)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<String>,
+}
+
+impl TargetConfig {
+    /// QoS limits for this target
+    pub fn qos(&self) -> QosLimits {
+        QosLimits {
+            max_read_mbps: self.max_read_mbps,
+            max_write_mbps: self.max_write_mbps,
+            max_iops: self.max_iops,
+            max_inflight_write_mb: self.max_inflight_write_mb,
+        }
+    }
+
+    /// Strings reported by IDENTIFY DEVICE in place of the backend's own
+    pub fn device_strings(&self) -> DeviceStrings {
+        DeviceStrings {
+            model: self.model.clone(),
+            serial: self.serial.clone(),
+            firmware: self.firmware.clone(),
+        }
+    }
 }
 
 /// Backend type
-#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum BackendType {
     File,
     Cas,
+    Device,
+    Memory,
+}
+
+impl BackendType {
+    /// Name as written in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            BackendType::File => "file",
+            BackendType::Cas => "cas",
+            BackendType::Device => "device",
+            BackendType::Memory => "memory",
+        }
+    }
 }
 
 /// File backend configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct FileBackendConfig {
     /// Path to the file
     pub path: String,
 
     /// Size in bytes (for creation)
     pub size: Option<u64>,
+
+    /// Use io_uring for I/O (requires the `uring` feature)
+    #[serde(default)]
+    pub uring: bool,
+
+    /// Refuse writes while the filesystem has less free space than this
+    #[serde(default)]
+    pub min_free_bytes: Option<u64>,
+}
+
+/// Block device backend configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct DeviceBackendConfig {
+    /// Path to the block device (e.g. /dev/sdb, /dev/zvol/pool/vol)
+    pub path: String,
+
+    /// Open the device read-only
+    #[serde(default)]
+    pub read_only: bool,
+
+    /// Issue write barriers (fdatasync) on flush
+    #[serde(default = "default_barrier")]
+    pub barrier: bool,
+}
+
+fn default_barrier() -> bool {
+    true
+}
+
+/// Memory backend configuration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MemoryBackendConfig {
+    /// Size in bytes
+    pub size: u64,
 }
 
 /// CAS backend configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CasBackendConfig {
-    /// Block size in bytes
-    #[serde(default = "default_block_size")]
-    pub block_size: u32,
+    /// Data block size in bytes, a power of two from the sector size to
+    /// 64 KiB (default: one sector, the layout of existing stores)
+    #[serde(default)]
+    pub block_size: Option<u32>,
 
     /// Total sectors
     pub total_sectors: u64,
 
     /// Blob store configuration
     pub blob_store: BlobStoreConfig,
+
+    /// Compression for new blocks (defaults to lz4)
+    #[serde(default)]
+    pub compression: CompressionConfig,
+
+    /// Encrypt blobs at rest
+    #[serde(default)]
+    pub encryption: Option<EncryptionConfig>,
+
+    /// Content-defined chunking for archival targets
+    #[serde(default)]
+    pub chunking: Option<ChunkingConfig>,
+
+    /// Store blocks that nearly match a recently stored one as deltas
+    #[serde(default)]
+    pub similarity: Option<SimilarityConfig>,
+
+    /// Blocks to prefetch ahead of sequential reads (default: none)
+    #[serde(default)]
+    pub readahead_blocks: usize,
+
+    /// Threads compressing and hashing the blocks of a large write
+    /// (default: one, the writer's)
+    #[serde(default)]
+    pub write_threads: usize,
+
+    /// When the root hash is recorded for restarts
+    #[serde(default)]
+    pub persist_root: PersistRootConfig,
+
+    /// Seconds between root syncs with `persist_root = "interval"`
+    #[serde(default = "default_persist_root_interval")]
+    pub persist_root_interval_secs: u64,
+
+    /// Snapshot retention by tag, applied whenever a snapshot is taken
+    #[serde(default)]
+    pub retention: Vec<RetentionRule>,
+
+    /// Demote least recently used blobs from `blob_store` to a cold store
+    #[serde(default)]
+    pub tiering: Option<TieringConfig>,
+
+    /// Most bytes of new blobs this target may add to a (shared) blob store
+    #[serde(default)]
+    pub quota_bytes: Option<u64>,
+}
+
+impl CasBackendConfig {
+    /// Snapshots file, kept beside the blob store (the head, root journal
+    /// and identity are kept beside it)
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.blob_store.state_dir().join("snapshots.json")
+    }
+}
+
+/// Hot/cold blob tiering; `blob_store` is the hot tier
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TieringConfig {
+    /// Bytes of blobs to keep in the hot tier
+    pub hot_capacity_bytes: u64,
+
+    /// Where least recently used blobs are demoted to
+    pub cold_store: BlobStoreConfig,
+}
+
+/// When a CAS target records its root hash, apart from snapshots
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistRootConfig {
+    /// Journal every write's root and sync it on flush
+    #[default]
+    OnFlush,
+    /// Also sync the root periodically, for initiators that seldom flush
+    Interval,
+    /// Only snapshots persist; a restart resumes from the latest one
+    Manual,
+}
+
+fn default_persist_root_interval() -> u64 {
+    30
+}
+
+/// Content-defined chunking settings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChunkingConfig {
+    /// Average chunk size in bytes (power of two, 1 KiB - 1 MiB)
+    #[serde(default = "default_avg_chunk")]
+    pub avg_size: u32,
+}
+
+fn default_avg_chunk() -> u32 {
+    crate::storage::cas::DEFAULT_AVG_CHUNK
+}
+
+/// Similarity (delta) compression settings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SimilarityConfig {
+    /// Recently stored blocks that new blocks are compared against
+    #[serde(default = "default_index_blocks")]
+    pub index_blocks: usize,
+}
+
+fn default_index_blocks() -> usize {
+    crate::storage::cas::DEFAULT_INDEX_BLOCKS
+}
+
+/// Blob encryption settings
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EncryptionConfig {
+    /// Cipher for blob contents
+    #[serde(default)]
+    pub cipher: CipherConfig,
+
+    /// 256-bit key as 64 hex characters
+    #[serde(default)]
+    pub key: Option<String>,
+
+    /// File holding the key (32 raw bytes or 64 hex characters)
+    #[serde(default)]
+    pub key_file: Option<String>,
+}
+
+/// Blob encryption cipher
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CipherConfig {
+    #[default]
+    Aes256Gcm,
+    Xchacha20Poly1305,
+}
+
+/// CAS block compression
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum CompressionConfig {
+    /// Store blocks uncompressed
+    None,
+    /// Fast lz4 compression
+    #[default]
+    Lz4,
+    /// zstd, optionally with a trained dictionary for small blocks
+    Zstd {
+        /// Compression level (1-22)
+        #[serde(default = "default_zstd_level")]
+        level: i32,
+        /// Path to a zstd dictionary file
+        #[serde(default)]
+        dictionary: Option<String>,
+    },
 }
 
-fn default_block_size() -> u32 {
-    4096
+fn default_zstd_level() -> i32 {
+    crate::storage::cas::DEFAULT_ZSTD_LEVEL
 }
 
 /// Blob store configuration
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum BlobStoreConfig {
     /// File-based blob store
     File {
         /// Directory path
         path: String,
+
+        /// More directories, typically on other disks, that blobs are
+        /// spread across along with `path`. Only append to the list.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        shards: Vec<String>,
+
+        /// Refuse new blobs while the filesystem has less free space than this
+        #[serde(default)]
+        min_free_bytes: Option<u64>,
+
+        /// Warn once the filesystem has less free space than this
+        #[serde(default)]
+        alarm_free_bytes: Option<u64>,
+
+        /// Hash blobs are keyed by, recorded when the store is created; a
+        /// store can't be reopened with another
+        #[serde(default)]
+        hash: HashAlgorithm,
+    },
+
+    /// Blob store on a `cas-server --blob-store` node
+    Remote {
+        /// Server address, `host:port` or `unix:/path`
+        addr: String,
+
+        /// Local directory for the target's snapshots, root journal and
+        /// identity, which file stores keep beside their blobs
+        state_dir: String,
+
+        /// Connections kept open for reuse (default 4); more are opened
+        /// while that many requests are in flight
+        #[serde(default)]
+        idle_connections: Option<usize>,
+
+        /// Reach the server over mutual TLS
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tls: Option<TlsConfig>,
     },
     // Future: S3, Azure, etc.
 }
 
+impl BlobStoreConfig {
+    /// Directories the blobs are kept in: the path, then its shards
+    pub fn dirs(&self) -> Vec<PathBuf> {
+        match self {
+            BlobStoreConfig::File { path, shards, .. } => std::iter::once(path)
+                .chain(shards)
+                .map(PathBuf::from)
+                .collect(),
+            BlobStoreConfig::Remote { .. } => Vec::new(),
+        }
+    }
+
+    /// Directory for the files a target keeps beside its blobs: the
+    /// parent of a file store's path, or a remote store's `state_dir`
+    pub fn state_dir(&self) -> PathBuf {
+        match self {
+            BlobStoreConfig::File { path, .. } => {
+                Path::new(path).parent().unwrap_or(Path::new(".")).to_path_buf()
+            }
+            BlobStoreConfig::Remote { state_dir, .. } => PathBuf::from(state_dir),
+        }
+    }
+
+    /// The blob directory or server address, for messages
+    pub fn location(&self) -> &str {
+        match self {
+            BlobStoreConfig::File { path, .. } => path,
+            BlobStoreConfig::Remote { addr, .. } => addr,
+        }
+    }
+
+    /// The store as migrations name it. A sharded store is named by its
+    /// first directory.
+    pub fn spec(&self) -> BlobStoreSpec {
+        match self {
+            BlobStoreConfig::File { path, .. } => BlobStoreSpec::File(PathBuf::from(path)),
+            BlobStoreConfig::Remote { addr, .. } => BlobStoreSpec::Remote(addr.clone()),
+        }
+    }
+}
+
 impl Config {
     /// Load configuration from a file
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
         let content = std::fs::read_to_string(path)?;
-        let config: Config = toml::from_str(&content)?;
-        config.validate()?;
-        Ok(config)
+        Self::parse_in(&content, path.parent().unwrap_or(Path::new(".")))
     }
 
-    /// Parse configuration from a string
+    /// Parse configuration from a string, with includes relative to the
+    /// current directory
     pub fn parse(content: &str) -> Result<Self, ConfigError> {
-        let config: Config = toml::from_str(content)?;
+        Self::parse_in(content, Path::new("."))
+    }
+
+    /// Parse configuration, resolving includes relative to `dir`
+    fn parse_in(content: &str, dir: &Path) -> Result<Self, ConfigError> {
+        let mut input: toml::Value = toml::from_str(content)?;
+        include_targets(&mut input, dir)?;
+        interpolate(&mut input)?;
+        Self::from_value(input)
+    }
+
+    /// Build and check the configuration from parsed, expanded input
+    fn from_value(input: toml::Value) -> Result<Self, ConfigError> {
+        let mut config: Config = input.clone().try_into()?;
+        if config.version == 0 || config.version > CONFIG_VERSION {
+            return Err(ConfigError::Invalid(format!(
+                "config version {} is not supported (this server reads 1 to {})",
+                config.version, CONFIG_VERSION
+            )));
+        }
+        let unknown = config.unknown_keys(&input)?;
+        let unknown: Vec<String> = unknown.iter().map(|path| KeyPath(path).to_string()).collect();
+        if config.version >= 2 && !unknown.is_empty() {
+            return Err(ConfigError::Invalid(format!(
+                "unknown key(s): {}",
+                unknown.join(", ")
+            )));
+        }
+        config.ignored_keys = unknown;
         config.validate()?;
         Ok(config)
     }
 
+    /// Keys in `input`, the file this config was read from, that no
+    /// setting reads
+    fn unknown_keys(&self, input: &toml::Value) -> Result<Vec<Vec<Segment>>, ConfigError> {
+        let known = toml::Value::try_from(self)
+            .map_err(|e| ConfigError::Invalid(format!("cannot serialize config: {}", e)))?;
+        let mut unknown = Vec::new();
+        diff_keys(input, &known, &mut Vec::new(), &mut unknown);
+        Ok(unknown)
+    }
+
     /// Validate the configuration
     fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.packet_ring {
+            if !cfg!(target_os = "linux") {
+                return Err(ConfigError::Invalid(
+                    "packet_ring is only supported on Linux".to_string(),
+                ));
+            }
+            if crate::server::interface_set::is_interface_pattern(&self.server.interface) {
+                return Err(ConfigError::Invalid(format!(
+                    "packet_ring needs a single interface, not {:?}",
+                    self.server.interface
+                )));
+            }
+        }
+
+        if let Some(mtu) = self.server.mtu {
+            if !(576..=65535).contains(&mtu) {
+                return Err(ConfigError::Invalid(format!(
+                    "mtu {} must be between 576 and 65535",
+                    mtu
+                )));
+            }
+        }
+
+        if let Some(ha) = &self.ha {
+            ha.validate().map_err(ConfigError::Invalid)?;
+        }
+
         // Check for duplicate shelf/slot
         let mut seen = std::collections::HashSet::new();
+        let mut uuids = std::collections::HashSet::new();
         for target in &self.target {
             let key = (target.shelf, target.slot);
             if !seen.insert(key) {
@@ -147,31 +665,472 @@ impl Config {
                 )));
             }
 
+            if let Some(uuid) = target.uuid {
+                if !uuids.insert(uuid) {
+                    return Err(ConfigError::Invalid(format!(
+                        "uuid {} for shelf {} slot {} is used by another target",
+                        uuid, target.shelf, target.slot
+                    )));
+                }
+            }
+
+            if let Err(e) = target.device_strings().validate() {
+                return Err(ConfigError::Invalid(format!(
+                    "{} for shelf {} slot {}",
+                    e, target.shelf, target.slot
+                )));
+            }
+
+            if let Some(sector_size) = target.sector_size {
+                if !crate::storage::is_valid_sector_size(sector_size) {
+                    return Err(ConfigError::Invalid(format!(
+                        "sector_size {} for shelf {} slot {} must be 512 or 4096",
+                        sector_size, target.shelf, target.slot
+                    )));
+                }
+            }
+
+            let qos = target.qos();
+            let limits = [
+                qos.max_read_mbps,
+                qos.max_write_mbps,
+                qos.max_iops,
+                qos.max_inflight_write_mb,
+            ];
+            if limits.contains(&Some(0)) {
+                return Err(ConfigError::Invalid(format!(
+                    "QoS limits for shelf {} slot {} must be greater than zero",
+                    target.shelf, target.slot
+                )));
+            }
+
+            if target.buffer_count == Some(0) || target.max_sectors == Some(0) {
+                return Err(ConfigError::Invalid(format!(
+                    "buffer_count and max_sectors for shelf {} slot {} must be greater than zero",
+                    target.shelf, target.slot
+                )));
+            }
+
             // Validate backend config
             match target.backend {
                 BackendType::File => {
-                    if target.file.is_none() {
+                    let Some(file) = &target.file else {
                         return Err(ConfigError::Invalid(format!(
                             "file backend requires [target.file] section for shelf {} slot {}",
                             target.shelf, target.slot
                         )));
+                    };
+                    if file.uring && !cfg!(all(target_os = "linux", feature = "uring")) {
+                        return Err(ConfigError::Invalid(format!(
+                            "uring requested for shelf {} slot {} but built without the `uring` feature",
+                            target.shelf, target.slot
+                        )));
+                    }
+                    if let Some(size) = file.size {
+                        check_size(target, "file size", size)?;
                     }
                 }
                 BackendType::Cas => {
-                    if target.cas.is_none() {
+                    let Some(cas) = &target.cas else {
                         return Err(ConfigError::Invalid(format!(
                             "cas backend requires [target.cas] section for shelf {} slot {}",
                             target.shelf, target.slot
                         )));
+                    };
+                    check_size(target, "total_sectors", cas.total_sectors.saturating_mul(512))?;
+                    if let Some(block_size) = cas.block_size {
+                        let sector_size = target.sector_size.unwrap_or(512);
+                        if !crate::storage::cas::is_valid_block_size(block_size, sector_size) {
+                            return Err(ConfigError::Invalid(format!(
+                                "CAS block_size {} for shelf {} slot {} must be a power of two \
+                                 from the sector size ({}) to 64 KiB",
+                                block_size, target.shelf, target.slot, sector_size
+                            )));
+                        }
+                    }
+                    match &cas.blob_store {
+                        BlobStoreConfig::File {
+                            path,
+                            shards,
+                            min_free_bytes,
+                            alarm_free_bytes,
+                            ..
+                        } => {
+                            let mut dirs = std::collections::HashSet::new();
+                            let repeated = std::iter::once(path)
+                                .chain(shards)
+                                .find(|dir| !dirs.insert(*dir));
+                            if let Some(dir) = repeated {
+                                return Err(ConfigError::Invalid(format!(
+                                    "blob store directory {} for shelf {} slot {} is listed twice",
+                                    dir, target.shelf, target.slot
+                                )));
+                            }
+                            if let (Some(reserve), Some(alarm)) = (min_free_bytes, alarm_free_bytes)
+                            {
+                                if alarm <= reserve {
+                                    return Err(ConfigError::Invalid(format!(
+                                        "alarm_free_bytes for shelf {} slot {} must be above \
+                                         min_free_bytes",
+                                        target.shelf, target.slot
+                                    )));
+                                }
+                            }
+                        }
+                        BlobStoreConfig::Remote { addr, state_dir, .. } => {
+                            if addr.is_empty() || state_dir.is_empty() {
+                                return Err(ConfigError::Invalid(format!(
+                                    "remote blob store for shelf {} slot {} needs an addr and a \
+                                     state_dir",
+                                    target.shelf, target.slot
+                                )));
+                            }
+                        }
+                    }
+                    if cas.persist_root == PersistRootConfig::Interval
+                        && cas.persist_root_interval_secs == 0
+                    {
+                        return Err(ConfigError::Invalid(format!(
+                            "persist_root_interval_secs for shelf {} slot {} must be above zero",
+                            target.shelf, target.slot
+                        )));
+                    }
+                    if let Some(tiering) = &cas.tiering {
+                        let hot = &cas.blob_store;
+                        let cold = &tiering.cold_store;
+                        if tiering.hot_capacity_bytes == 0 || hot.location() == cold.location() {
+                            return Err(ConfigError::Invalid(format!(
+                                "tiering for shelf {} slot {} needs a hot_capacity_bytes above \
+                                 zero and a cold_store apart from blob_store",
+                                target.shelf, target.slot
+                            )));
+                        }
+                        // Demotion walks the hot tier's directories
+                        let BlobStoreConfig::File { hash, .. } = hot else {
+                            return Err(ConfigError::Invalid(format!(
+                                "tiering for shelf {} slot {} needs a file blob_store as its \
+                                 hot tier",
+                                target.shelf, target.slot
+                            )));
+                        };
+                        // Blobs move between tiers under the same hash
+                        if let BlobStoreConfig::File { hash: cold_hash, .. } = cold {
+                            if cold_hash != hash {
+                                return Err(ConfigError::Invalid(format!(
+                                    "tiering for shelf {} slot {} needs the same hash in both \
+                                     tiers",
+                                    target.shelf, target.slot
+                                )));
+                            }
+                        }
+                    }
+                    if let Some(rule) = cas.retention.iter().find(|rule| rule.keep == 0) {
+                        return Err(ConfigError::Invalid(format!(
+                            "retention for tag {:?} on shelf {} slot {} must keep at least one",
+                            rule.tag, target.shelf, target.slot
+                        )));
+                    }
+                    if let Some(chunking) = &cas.chunking {
+                        if cas.block_size.is_none() {
+                            return Err(ConfigError::Invalid(format!(
+                                "chunking for shelf {} slot {} needs a block_size (e.g. 65536)",
+                                target.shelf, target.slot
+                            )));
+                        }
+                        if let Err(e) = crate::storage::cas::ChunkerConfig::new(chunking.avg_size) {
+                            return Err(ConfigError::Invalid(format!(
+                                "chunking for shelf {} slot {}: {}",
+                                target.shelf, target.slot, e
+                            )));
+                        }
+                    }
+                    let similarity = cas.similarity.as_ref();
+                    if similarity.is_some_and(|similarity| similarity.index_blocks == 0) {
+                        return Err(ConfigError::Invalid(format!(
+                            "similarity index_blocks for shelf {} slot {} must be above zero",
+                            target.shelf, target.slot
+                        )));
+                    }
+                    if let Some(encryption) = &cas.encryption {
+                        if encryption.key.is_some() == encryption.key_file.is_some() {
+                            return Err(ConfigError::Invalid(format!(
+                                "encryption for shelf {} slot {} needs exactly one of key or key_file",
+                                target.shelf, target.slot
+                            )));
+                        }
+                    }
+                    if let CompressionConfig::Zstd { level, .. } = cas.compression {
+                        if !(1..=22).contains(&level) {
+                            return Err(ConfigError::Invalid(format!(
+                                "zstd level {} for shelf {} slot {} must be 1-22",
+                                level, target.shelf, target.slot
+                            )));
+                        }
+                    }
+                }
+                BackendType::Device => {
+                    if target.device.is_none() {
+                        return Err(ConfigError::Invalid(format!(
+                            "device backend requires [target.device] section for shelf {} slot {}",
+                            target.shelf, target.slot
+                        )));
+                    }
+                }
+                BackendType::Memory => {
+                    let Some(memory) = &target.memory else {
+                        return Err(ConfigError::Invalid(format!(
+                            "memory backend requires [target.memory] section for shelf {} slot {}",
+                            target.shelf, target.slot
+                        )));
+                    };
+                    check_size(target, "memory size", memory.size)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check a target's capacity of `bytes` is whole sectors, not zero, and
+/// within LBA48
+fn check_size(target: &TargetConfig, what: &str, bytes: u64) -> Result<(), ConfigError> {
+    let sector_size = u64::from(target.sector_size.unwrap_or(512));
+    if bytes == 0 || bytes % sector_size != 0 {
+        return Err(ConfigError::Invalid(format!(
+            "{} for shelf {} slot {} must be a non-zero multiple of the {}-byte sector size",
+            what, target.shelf, target.slot, sector_size
+        )));
+    }
+    if bytes / sector_size > LBA48_MAX_SECTORS {
+        return Err(ConfigError::Invalid(format!(
+            "{} for shelf {} slot {} is {} sectors, beyond the LBA48 limit of {}",
+            what,
+            target.shelf,
+            target.slot,
+            bytes / sector_size,
+            LBA48_MAX_SECTORS
+        )));
+    }
+    Ok(())
+}
+
+/// Rewrite a config file's contents to the current version, returning
+/// the new contents and a description of each change. Comments and
+/// layout are kept; a current file comes back unchanged. Only this file
+/// is rewritten: includes and `${...}` references are left unexpanded.
+/// A version 1 file with unknown keys is refused, listing them, since
+/// they may be typos of keys that were meant to take effect.
+pub fn upgrade(content: &str) -> Result<(String, Vec<String>), ConfigError> {
+    let config = Config::from_value(toml::from_str(content)?)?;
+    if config.version == CONFIG_VERSION {
+        return Ok((content.to_string(), Vec::new()));
+    }
+    let mut doc: toml_edit::DocumentMut = content
+        .parse()
+        .map_err(|e| ConfigError::Invalid(format!("cannot edit config: {}", e)))?;
+    let mut changes = Vec::new();
+
+    for from in config.version..CONFIG_VERSION {
+        match from {
+            // Version 2 rejects the unknown keys version 1 ignored
+            1 if !config.ignored_keys.is_empty() => {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown key(s) {}: correct or remove them, then upgrade again",
+                    config.ignored_keys.join(", ")
+                )));
+            }
+            1 => {}
+            _ => unreachable!("no migration from config version {}", from),
+        }
+    }
+    doc["version"] = toml_edit::value(i64::from(CONFIG_VERSION));
+    changes.push(format!(
+        "set version = {} (was {})",
+        CONFIG_VERSION, config.version
+    ));
+
+    let upgraded = doc.to_string();
+    Config::from_value(toml::from_str(&upgraded)?)?;
+    Ok((upgraded, changes))
+}
+
+/// Append the targets of each file `input`'s `include` list matches
+fn include_targets(input: &mut toml::Value, dir: &Path) -> Result<(), ConfigError> {
+    let Some(patterns) = input.get("include") else {
+        return Ok(());
+    };
+    let patterns: Vec<String> = patterns.clone().try_into()?;
+    let mut targets = Vec::new();
+    for pattern in &patterns {
+        for path in included_files(&dir.join(pattern))? {
+            let content = std::fs::read_to_string(&path).map_err(|e| {
+                ConfigError::Invalid(format!("cannot read include {}: {}", path.display(), e))
+            })?;
+            let included: toml::Table = toml::from_str(&content).map_err(|e| {
+                ConfigError::Invalid(format!("cannot parse include {}: {}", path.display(), e))
+            })?;
+            for (key, value) in included {
+                match (key.as_str(), value) {
+                    ("target", toml::Value::Array(more)) => targets.extend(more),
+                    _ => {
+                        return Err(ConfigError::Invalid(format!(
+                            "include {} may only define [[target]] tables, not {}",
+                            path.display(),
+                            key
+                        )))
                     }
                 }
             }
         }
+    }
+    if !targets.is_empty() {
+        let table = input.as_table_mut().expect("config is a table");
+        match table.entry("target").or_insert_with(|| toml::Value::Array(Vec::new())) {
+            toml::Value::Array(existing) => existing.extend(targets),
+            _ => return Err(ConfigError::Invalid("target must be an array of tables".into())),
+        }
+    }
+    Ok(())
+}
+
+/// The files an include names: the path itself, or every file in its
+/// directory matching a glob file name, in name order
+fn included_files(path: &Path) -> Result<Vec<PathBuf>, ConfigError> {
+    let name = path.file_name().and_then(|name| name.to_str()).unwrap_or("");
+    if !name.contains(['*', '?']) {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let entries = std::fs::read_dir(dir).map_err(|e| {
+        ConfigError::Invalid(format!("cannot read include directory {}: {}", dir.display(), e))
+    })?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let matched = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|file| crate::server::interface_set::matches_pattern(name, file));
+        if matched && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Replace `${NAME}` and `${NAME:-default}` in every string value with
+/// the environment variable's value
+fn interpolate(value: &mut toml::Value) -> Result<(), ConfigError> {
+    match value {
+        toml::Value::String(s) if s.contains('$') => {
+            *s = expand_env(s, |name| std::env::var(name).ok())?
+        }
+        toml::Value::Array(values) => {
+            for value in values {
+                interpolate(value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for value in table.values_mut() {
+                interpolate(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Expand variable references in `s`, looking names up with `var`
+fn expand_env(s: &str, var: impl Fn(&str) -> Option<String>) -> Result<String, ConfigError> {
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| {
+                ConfigError::Invalid(format!("unterminated ${{ in {:?}", s))
+            })?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            match var(name).or_else(|| default.map(str::to_string)) {
+                Some(value) => out.push_str(&value),
+                None => {
+                    return Err(ConfigError::Invalid(format!(
+                        "environment variable {} is not set",
+                        name
+                    )))
+                }
+            }
+            rest = &after[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// One step of a key's path through the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
 
+/// Key path display, as in `target[0].cas.quota`
+struct KeyPath<'a>(&'a [Segment]);
+
+impl fmt::Display for KeyPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Key(key) if i == 0 => write!(f, "{}", key)?,
+                Segment::Key(key) => write!(f, ".{}", key)?,
+                Segment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
         Ok(())
     }
 }
 
+/// Collect the paths of keys in `input` missing from `known`
+fn diff_keys(
+    input: &toml::Value,
+    known: &toml::Value,
+    path: &mut Vec<Segment>,
+    unknown: &mut Vec<Vec<Segment>>,
+) {
+    match (input, known) {
+        (toml::Value::Table(input), toml::Value::Table(known)) => {
+            for (key, value) in input {
+                path.push(Segment::Key(key.clone()));
+                match known.get(key) {
+                    Some(known) => diff_keys(value, known, path, unknown),
+                    None => unknown.push(path.clone()),
+                }
+                path.pop();
+            }
+        }
+        (toml::Value::Array(input), toml::Value::Array(known)) => {
+            for (index, (value, known)) in input.iter().zip(known).enumerate() {
+                path.push(Segment::Index(index));
+                diff_keys(value, known, path, unknown);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -225,6 +1184,315 @@ path = "/data/blobs"
         assert_eq!(config.target[0].backend, BackendType::Cas);
         let cas = config.target[0].cas.as_ref().unwrap();
         assert_eq!(cas.total_sectors, 2097152);
+        assert_eq!(cas.block_size, Some(4096));
+        assert_eq!(cas.compression, CompressionConfig::Lz4);
+        assert!(cas.chunking.is_none());
+        assert_eq!(cas.quota_bytes, None);
+        assert_eq!(cas.persist_root, PersistRootConfig::OnFlush);
+
+        let interval = config_str.replace(
+            "total_sectors = 2097152",
+            "total_sectors = 2097152\npersist_root = \"interval\"\npersist_root_interval_secs = 5",
+        );
+        let config = Config::parse(&interval).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        assert_eq!(cas.persist_root, PersistRootConfig::Interval);
+        assert_eq!(cas.persist_root_interval_secs, 5);
+        assert!(Config::parse(&interval.replace("secs = 5", "secs = 0")).is_err());
+
+        let retained = config_str.replace(
+            "total_sectors = 2097152",
+            "total_sectors = 2097152\nretention = [{ tag = \"nightly\", keep = 7 }]",
+        );
+        let config = Config::parse(&retained).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        assert_eq!(cas.retention, vec![RetentionRule { tag: "nightly".into(), keep: 7 }]);
+        assert!(Config::parse(&retained.replace("keep = 7", "keep = 0")).is_err());
+
+        let tiered = format!(
+            "{}\n[target.cas.tiering]\nhot_capacity_bytes = 1073741824\n\n\
+             [target.cas.tiering.cold_store]\ntype = \"file\"\npath = \"/archive/blobs\"\n",
+            config_str
+        );
+        let config = Config::parse(&tiered).unwrap();
+        let tiering = config.target[0].cas.as_ref().unwrap().tiering.as_ref().unwrap();
+        assert_eq!(tiering.hot_capacity_bytes, 1 << 30);
+        assert!(Config::parse(&tiered.replace("/archive/blobs", "/data/blobs")).is_err());
+
+        let chunked = config_str.replace(
+            "total_sectors = 2097152",
+            "total_sectors = 2097152\nchunking = { avg_size = 16384 }",
+        );
+        let config = Config::parse(&chunked).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        assert_eq!(cas.chunking.as_ref().unwrap().avg_size, 16384);
+        let no_block_size = chunked.replace("block_size = 4096\n", "");
+        assert!(Config::parse(&no_block_size).is_err());
+
+        let similar = config_str.replace(
+            "total_sectors = 2097152",
+            "total_sectors = 2097152\nsimilarity = {}",
+        );
+        let config = Config::parse(&similar).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        let similarity = cas.similarity.as_ref().unwrap();
+        assert_eq!(similarity.index_blocks, crate::storage::cas::DEFAULT_INDEX_BLOCKS);
+        let disabled = similar.replace("similarity = {}", "similarity = { index_blocks = 0 }");
+        assert!(Config::parse(&disabled).is_err());
+
+        let sharded = config_str.replace(
+            "path = \"/data/blobs\"",
+            "path = \"/data/blobs\"\nshards = [\"/disk2/blobs\", \"/disk3/blobs\"]",
+        );
+        let config = Config::parse(&sharded).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        let BlobStoreConfig::File { shards, .. } = &cas.blob_store else {
+            panic!("expected a file blob store");
+        };
+        assert_eq!(shards, &["/disk2/blobs", "/disk3/blobs"]);
+        assert!(Config::parse(&sharded.replace("/disk3/blobs", "/data/blobs")).is_err());
+
+        let file_store = "type = \"file\"\npath = \"/data/blobs\"";
+        let remote_store = "type = \"remote\"\naddr = \"storage1:3001\"\nstate_dir = \"/var/e2\"";
+        let remote = config_str.replace(file_store, remote_store);
+        let config = Config::parse(&remote).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        assert!(cas.blob_store.dirs().is_empty());
+        assert_eq!(cas.blob_store.location(), "storage1:3001");
+        assert_eq!(cas.snapshot_path(), Path::new("/var/e2/snapshots.json"));
+        assert!(Config::parse(&remote.replace("/var/e2", "")).is_err());
+        // A remote cold tier is fine, but the hot tier has to be local
+        let remote_cold =
+            tiered.replace("type = \"file\"\npath = \"/archive/blobs\"", remote_store);
+        assert!(Config::parse(&remote_cold).is_ok());
+        assert!(Config::parse(&tiered.replacen(file_store, remote_store, 1)).is_err());
+
+        let hashed = config_str.replace(file_store, &format!("{}\nhash = \"xxh3\"", file_store));
+        let config = Config::parse(&hashed).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        let BlobStoreConfig::File { hash, .. } = &cas.blob_store else {
+            panic!("not a file blob store");
+        };
+        assert_eq!(*hash, HashAlgorithm::Xxh3);
+        assert!(Config::parse(&hashed.replace("xxh3", "md5")).is_err());
+        // Tiers have to share a hash
+        let mixed = tiered.replacen(file_store, &format!("{}\nhash = \"sha256\"", file_store), 1);
+        assert!(Config::parse(&mixed).is_err());
+    }
+
+    #[test]
+    fn test_parse_zstd_compression() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "cas"
+
+[target.cas]
+total_sectors = 2048
+
+[target.cas.blob_store]
+type = "file"
+path = "/data/blobs"
+
+[target.cas.compression]
+type = "zstd"
+level = 19
+dictionary = "/data/sectors.dict"
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        let cas = config.target[0].cas.as_ref().unwrap();
+        assert_eq!(
+            cas.compression,
+            CompressionConfig::Zstd {
+                level: 19,
+                dictionary: Some("/data/sectors.dict".to_string()),
+            }
+        );
+
+        let invalid = config_str.replace("level = 19", "level = 40");
+        assert!(matches!(Config::parse(&invalid), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_device_config() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 2
+slot = 0
+backend = "device"
+
+[target.device]
+path = "/dev/zvol/tank/vm1"
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        assert_eq!(config.target[0].backend, BackendType::Device);
+        let device = config.target[0].device.as_ref().unwrap();
+        assert_eq!(device.path, "/dev/zvol/tank/vm1");
+        assert!(device.barrier);
+        assert!(!device.read_only);
+    }
+
+    #[test]
+    fn test_sector_size_config() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+sector_size = 4096
+
+[target.memory]
+size = 1048576
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        assert_eq!(config.target[0].sector_size, Some(4096));
+        assert!(config.target[0].qos().is_unlimited());
+
+        let invalid = config_str.replace("4096", "1024");
+        let result = Config::parse(&invalid);
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_encryption_config() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "cas"
+
+[target.cas]
+total_sectors = 2048
+
+[target.cas.blob_store]
+type = "file"
+path = "/data/blobs"
+
+[target.cas.encryption]
+cipher = "xchacha20-poly1305"
+key_file = "/etc/aoe/blob.key"
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        let encryption = config.target[0].cas.as_ref().unwrap().encryption.as_ref().unwrap();
+        assert_eq!(encryption.cipher, CipherConfig::Xchacha20Poly1305);
+        assert_eq!(encryption.key_file.as_deref(), Some("/etc/aoe/blob.key"));
+
+        // No key at all is an error
+        let invalid = config_str.replace("key_file = \"/etc/aoe/blob.key\"", "");
+        assert!(matches!(Config::parse(&invalid), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_qos_limits() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+max_read_mbps = 200
+max_iops = 5000
+max_inflight_write_mb = 64
+
+[target.memory]
+size = 1048576
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        let qos = config.target[0].qos();
+        assert_eq!(qos.max_read_mbps, Some(200));
+        assert_eq!(qos.max_write_mbps, None);
+        assert_eq!(qos.max_iops, Some(5000));
+        assert_eq!(qos.max_inflight_write_mb, Some(64));
+
+        let invalid = config_str.replace("5000", "0");
+        assert!(matches!(Config::parse(&invalid), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_parse_advertised_limits() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+mtu = 9000
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+buffer_count = 64
+max_sectors = 16
+
+[target.memory]
+size = 1048576
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        assert_eq!(config.server.mtu, Some(9000));
+        assert_eq!(config.target[0].buffer_count, Some(64));
+        assert_eq!(config.target[0].max_sectors, Some(16));
+
+        let invalid = config_str.replace("buffer_count = 64", "buffer_count = 0");
+        assert!(matches!(Config::parse(&invalid), Err(ConfigError::Invalid(_))));
+        let invalid = config_str.replace("mtu = 9000", "mtu = 100");
+        assert!(matches!(Config::parse(&invalid), Err(ConfigError::Invalid(_))));
+
+        let ring = config_str.replace("mtu = 9000", "mtu = 9000\npacket_ring = true");
+        assert!(!Config::parse(config_str).unwrap().server.packet_ring);
+        let sharded = config_str.replace("mtu = 9000", "mtu = 9000\nworkers = 0");
+        assert_eq!(Config::parse(&sharded).unwrap().server.workers, Some(0));
+        assert_eq!(Config::parse(&ring).is_ok(), cfg!(target_os = "linux"));
+        let invalid = ring.replace("\"eth0\"", "\"any\"");
+        assert!(matches!(Config::parse(&invalid), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_target_size_errors() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+sector_size = 4096
+
+[target.memory]
+size = 1048576
+"#;
+        assert!(Config::parse(config_str).is_ok());
+        for size in ["0", "1049088", "1152921504606846976"] {
+            let invalid = config_str.replace("1048576", size);
+            let result = Config::parse(&invalid);
+            assert!(matches!(result, Err(ConfigError::Invalid(_))), "size {}", size);
+        }
+
+        let cas = config_str.replace("backend = \"memory\"", "backend = \"cas\"").replace(
+            "[target.memory]\nsize = 1048576",
+            "[target.cas]\ntotal_sectors = 0\nblob_store = { type = \"file\", path = \"/b\" }",
+        );
+        assert!(matches!(Config::parse(&cas), Err(ConfigError::Invalid(_))));
+        assert!(Config::parse(&cas.replace("total_sectors = 0", "total_sectors = 2048")).is_ok());
     }
 
     #[test]
@@ -254,6 +1522,83 @@ path = "/data/disk2.img"
         assert!(matches!(result, Err(ConfigError::Invalid(_))));
     }
 
+    #[test]
+    fn test_target_uuid() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+uuid = "6f1c2a3b-4d5e-4f60-8a7b-9c0d1e2f3a4b"
+
+[target.memory]
+size = 1048576
+
+[[target]]
+shelf = 1
+slot = 1
+backend = "memory"
+uuid = "6f1c2a3b-4d5e-4f60-8a7b-9c0d1e2f3a4b"
+
+[target.memory]
+size = 1048576
+"#;
+
+        let result = Config::parse(config_str);
+        assert!(matches!(result, Err(ConfigError::Invalid(_))));
+
+        let config = Config::parse(&config_str.replacen("9c0d", "9c0e", 1)).unwrap();
+        assert_eq!(
+            config.target[0].uuid.unwrap().to_string(),
+            "6f1c2a3b-4d5e-4f60-8a7b-9c0e1e2f3a4b"
+        );
+        assert_eq!(config.target[1].uuid.unwrap().serial(), "6F1C2A3B4D5E4F60");
+
+        let bad = config_str.replace("-9c0d-", "-");
+        assert!(matches!(Config::parse(&bad), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn test_device_strings() {
+        let config_str = r#"
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+model = "ST4000NM0033"
+serial = "Z1Z0ABCD"
+firmware = "GA0A"
+
+[target.memory]
+size = 1048576
+"#;
+
+        let config = Config::parse(config_str).unwrap();
+        let strings = config.target[0].device_strings();
+        assert_eq!(strings.model.as_deref(), Some("ST4000NM0033"));
+        assert_eq!(strings.serial.as_deref(), Some("Z1Z0ABCD"));
+        assert_eq!(strings.firmware.as_deref(), Some("GA0A"));
+
+        // ATA field lengths: 40, 20 and 8 characters, printable ASCII
+        let long_model = config_str.replace("ST4000NM0033", &"M".repeat(41));
+        assert!(matches!(Config::parse(&long_model), Err(ConfigError::Invalid(_))));
+        assert!(Config::parse(&config_str.replace("ST4000NM0033", &"M".repeat(40))).is_ok());
+        let long_serial = config_str.replace("Z1Z0ABCD", &"1".repeat(21));
+        assert!(matches!(Config::parse(&long_serial), Err(ConfigError::Invalid(_))));
+        let long_firmware = config_str.replace("GA0A", "GA0A.1234");
+        assert!(matches!(Config::parse(&long_firmware), Err(ConfigError::Invalid(_))));
+        let unicode = config_str.replace("GA0A", "GA0Ä");
+        assert!(matches!(Config::parse(&unicode), Err(ConfigError::Invalid(_))));
+        let blank = config_str.replace("Z1Z0ABCD", "  ");
+        assert!(matches!(Config::parse(&blank), Err(ConfigError::Invalid(_))));
+    }
+
     #[test]
     fn test_missing_backend_config_error() {
         let config_str = r#"
@@ -269,4 +1614,101 @@ backend = "file"
         let result = Config::parse(config_str);
         assert!(matches!(result, Err(ConfigError::Invalid(_))));
     }
+
+    #[test]
+    fn test_config_versions() {
+        let v1 = r#"# Old config
+[server]
+interface = "eth0"
+log_colour = "auto"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+cache_mb = 64 # never read
+
+[target.memory]
+size = 1048576
+"#;
+        let config = Config::parse(v1).unwrap();
+        assert_eq!(config.version, 1);
+        assert_eq!(config.ignored_keys, ["server.log_colour", "target[0].cache_mb"]);
+
+        // Unknown keys may be typos, so they are left for the user to fix
+        let err = upgrade(v1).unwrap_err().to_string();
+        assert!(err.contains("log_colour, target[0].cache_mb"), "{}", err);
+        let v1 = v1.replace("log_colour = \"auto\"\n", "");
+        let v1 = v1.replace("cache_mb = 64 # never read\n", "");
+
+        let (v2, changes) = upgrade(&v1).unwrap();
+        assert_eq!(changes, ["set version = 2 (was 1)"]);
+        assert!(v2.starts_with("# Old config\nversion = 2\n"));
+        let config = Config::parse(&v2).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert!(config.ignored_keys.is_empty());
+        assert_eq!(upgrade(&v2).unwrap(), (v2.clone(), Vec::new()));
+
+        // Version 2 rejects unknown keys, and newer versions are refused
+        let typo = v2.replace("size = 1048576", "size = 1048576\nsise = 1");
+        let err = Config::parse(&typo).unwrap_err().to_string();
+        assert!(err.contains("target[0].memory.sise"), "{}", err);
+        let future = v2.replace("version = 2", "version = 3");
+        assert!(Config::parse(&future).is_err());
+    }
+
+    #[test]
+    fn test_env_interpolation() {
+        let var = |name: &str| (name == "BLOB_KEY").then(|| "s3cret".to_string());
+        assert_eq!(expand_env("${BLOB_KEY}", var).unwrap(), "s3cret");
+        assert_eq!(expand_env("k=${BLOB_KEY}!", var).unwrap(), "k=s3cret!");
+        assert_eq!(expand_env("${OTHER:-/etc/aoe}/key", var).unwrap(), "/etc/aoe/key");
+        assert_eq!(expand_env("$${X} costs $5", var).unwrap(), "${X} costs $5");
+        assert!(expand_env("${OTHER}", var).is_err());
+        assert!(expand_env("${BLOB_KEY", var).is_err());
+    }
+
+    #[test]
+    fn test_include_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("targets.d")).unwrap();
+        for slot in 1..3 {
+            std::fs::write(
+                dir.path().join(format!("targets.d/e1.{}.toml", slot)),
+                format!(
+                    "[[target]]\nshelf = 1\nslot = {}\nbackend = \"memory\"\n\n[target.memory]\nsize = 1048576\n",
+                    slot
+                ),
+            )
+            .unwrap();
+        }
+        std::fs::write(dir.path().join("targets.d/README"), "not toml").unwrap();
+        let main = r#"version = 2
+include = ["targets.d/*.toml"]
+
+[server]
+interface = "eth0"
+
+[[target]]
+shelf = 1
+slot = 0
+backend = "memory"
+
+[target.memory]
+size = 1048576
+"#;
+        let path = dir.path().join("aoe.toml");
+        std::fs::write(&path, main).unwrap();
+        let config = Config::load(&path).unwrap();
+        let slots: Vec<u8> = config.target.iter().map(|t| t.slot).collect();
+        assert_eq!(slots, [0, 1, 2]);
+
+        // Includes only add targets
+        std::fs::write(
+            dir.path().join("targets.d/e1.3.toml"),
+            "[server]\ninterface = \"eth1\"\n",
+        )
+        .unwrap();
+        assert!(matches!(Config::load(&path), Err(ConfigError::Invalid(_))));
+    }
 }
@@ -0,0 +1,90 @@
+//! Protocol front-ends
+//!
+//! A front-end exposes block devices over one network protocol. AoE
+//! (`AoeListener`), NBD (`NbdServer`) and iSCSI (`IscsiFrontend`) all
+//! implement `Frontend`, so a daemon or admin API can attach targets and
+//! start/stop every protocol the same way.
+//!
+//! Target names are protocol-specific: `e<shelf>.<slot>` for AoE, the
+//! export name for NBD and the IQN for iSCSI.
+
+use crate::storage::BlockStorage;
+use std::io;
+use thiserror::Error;
+
+/// Front-end errors
+#[derive(Debug, Error)]
+pub enum FrontendError {
+    #[error("front-end is already running")]
+    AlreadyRunning,
+
+    #[error("front-end is not running")]
+    NotRunning,
+
+    #[error("invalid target name: {0}")]
+    InvalidName(String),
+
+    #[error("target already attached: {0}")]
+    DuplicateTarget(String),
+
+    #[error("not supported: {0}")]
+    Unsupported(String),
+
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("protocol error: {0}")]
+    Protocol(String),
+}
+
+pub type FrontendResult<T> = Result<T, FrontendError>;
+
+/// A network protocol serving block devices
+pub trait Frontend: Send {
+    /// Protocol name ("aoe", "nbd", "iscsi")
+    fn protocol(&self) -> &'static str;
+
+    /// Expose a block device under a protocol-specific name.
+    /// Front-ends may only accept targets while stopped.
+    fn attach(&mut self, name: &str, storage: Box<dyn BlockStorage>) -> FrontendResult<()>;
+
+    /// Names of the attached targets
+    fn targets(&self) -> Vec<String>;
+
+    /// Start serving on background threads
+    fn start(&mut self) -> FrontendResult<()>;
+
+    /// Stop serving: reject new work, drain in-flight requests, flush
+    /// backends and wait for the serving threads to exit
+    fn stop(&mut self) -> FrontendResult<()>;
+
+    /// True between `start` and `stop`
+    fn is_running(&self) -> bool;
+}
+
+/// Parse an AoE target name (`e<shelf>.<slot>`)
+pub fn parse_aoe_name(name: &str) -> FrontendResult<(u16, u8)> {
+    let invalid = || FrontendError::InvalidName(name.to_string());
+    let (shelf, slot) = name
+        .strip_prefix('e')
+        .and_then(|rest| rest.split_once('.'))
+        .ok_or_else(invalid)?;
+    Ok((
+        shelf.parse().map_err(|_| invalid())?,
+        slot.parse().map_err(|_| invalid())?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_aoe_name() {
+        assert_eq!(parse_aoe_name("e1.0").unwrap(), (1, 0));
+        assert_eq!(parse_aoe_name("e65534.254").unwrap(), (65534, 254));
+        assert!(parse_aoe_name("1.0").is_err());
+        assert!(parse_aoe_name("e1").is_err());
+        assert!(parse_aoe_name("e1.256").is_err());
+    }
+}
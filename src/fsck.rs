@@ -0,0 +1,432 @@
+//! Blob store consistency checks
+//!
+//! Cross-checks what a blob store holds against what refers to it, and
+//! reports three kinds of damage:
+//!
+//! - missing: referenced but not in the store (a dangling reference,
+//!   reads of it fail)
+//! - corrupt: stored, but the contents don't hash to the name
+//! - unreachable: stored, but nothing refers to it (garbage; harmless, and
+//!   reclaimable by GC)
+//!
+//! There are two stores to check. An AoE CAS store (`FileBlobStore`
+//! directories, BLAKE3 unless the store records another hash) is
+//! referenced by the Merkle trees of every snapshot, the head and the
+//! journaled current root of its targets; trees are walked down to the
+//! data blocks and the chunks of chunked blocks. An iSCSI CAS
+//! store (`CasStorage` directory behind `cas-server`, xxHash3-128) is
+//! referenced by the targets' LBA indexes and snapshot layers.
+//!
+//! Given a replica, missing and corrupt blobs that are referenced are
+//! copied back from it (and checked) before being counted as repaired.
+//! Unreachable blobs are only reported.
+//!
+//! Blobs written while a check runs can show up as unreachable, since the
+//! roots are read first. LBA indexes can't be opened while their target is
+//! running, so iSCSI checks need the targets stopped.
+
+use crate::blob::{BlobError, BlobStore, FileBlobStore, Hash, ShardedBlobStore};
+use crate::cas::{self, CasPool, CasStorage};
+use crate::iscsi::index::LbaIndex;
+use crate::storage::cas::{
+    calculate_depth, journal_path, node_children, read_root, referenced_blobs, SnapshotManager,
+};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_128;
+
+/// What a check found, blobs named by their hex hash
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    /// Blobs in the store
+    pub blobs: u64,
+    pub blob_bytes: u64,
+    /// Distinct blobs referenced
+    pub referenced: u64,
+    /// Stored but not referenced
+    pub unreachable: Vec<String>,
+    /// Referenced but not stored, and not repaired
+    pub missing: Vec<String>,
+    /// Contents don't match the hash, and not repaired
+    pub corrupt: Vec<String>,
+    /// Missing or corrupt, and copied back from the replica
+    pub repaired: Vec<String>,
+}
+
+impl FsckReport {
+    /// No missing or corrupt blobs left (unreachable ones are only garbage)
+    pub fn is_clean(&self) -> bool {
+        self.missing.is_empty() && self.corrupt.is_empty()
+    }
+}
+
+/// An AoE CAS target whose trees reference a blob store
+#[derive(Debug, Clone)]
+pub struct CasTree {
+    /// Snapshots file; the head and root journal are found beside it
+    pub snapshots: PathBuf,
+    /// Data blocks (tree leaves) of the target
+    pub blocks: u64,
+}
+
+impl CasTree {
+    /// Roots to walk: every snapshot, the head and the journaled root
+    fn roots(&self) -> Result<Vec<Hash>> {
+        let manager = SnapshotManager::new(&self.snapshots)
+            .with_context(|| format!("Failed to load snapshots {:?}", self.snapshots))?;
+        let mut roots: Vec<Hash> = manager
+            .list()
+            .iter()
+            .filter_map(|snapshot| manager.get(&snapshot.id))
+            .collect();
+        roots.extend(manager.head());
+        let journal = journal_path(&self.snapshots);
+        roots.extend(
+            read_root(&journal).with_context(|| format!("Failed to read {:?}", journal))?,
+        );
+        Ok(roots)
+    }
+}
+
+/// Check the AoE CAS blob store kept in `dirs` (every shard, and the cold
+/// tier if there is one) against the trees of the targets using it.
+/// Stores of encrypted targets name blobs by locator and can't be checked.
+pub fn check_cas_store(
+    dirs: &[PathBuf],
+    trees: &[CasTree],
+    replica: Option<&dyn BlobStore>,
+) -> Result<FsckReport> {
+    let stores = dirs
+        .iter()
+        .map(|dir| {
+            FileBlobStore::new(dir).with_context(|| format!("Failed to open blob store {:?}", dir))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let store = ShardedBlobStore::new(stores);
+    if let Some(replica) = replica {
+        if replica.hash_algorithm() != store.hash_algorithm() {
+            anyhow::bail!(
+                "Replica keys blobs by {}, the store by {}",
+                replica.hash_algorithm(),
+                store.hash_algorithm()
+            );
+        }
+    }
+    let mut report = FsckReport::default();
+
+    // Walk every tree, reading (and so verifying) each referenced blob once
+    let mut referenced = HashSet::new();
+    for tree in trees {
+        let depth = calculate_depth(tree.blocks);
+        let mut pending: Vec<(Hash, u8)> = tree
+            .roots()?
+            .into_iter()
+            .filter(|root| !root.is_zero())
+            .map(|root| (root, 0))
+            .collect();
+
+        while let Some((hash, level)) = pending.pop() {
+            if !referenced.insert(hash) {
+                continue;
+            }
+            let Some(data) = fetch_cas_blob(&store, replica, &hash, &mut report)? else {
+                continue;
+            };
+            if level < depth - 1 {
+                pending.extend(
+                    node_children(&data)
+                        .filter(|child| !child.is_zero())
+                        .map(|child| (child, level + 1)),
+                );
+            } else if level == depth - 1 {
+                // Chunks and delta bases refer to nothing, so sit below the leaves
+                let chunks = referenced_blobs(&data).unwrap_or_else(|_| {
+                    log::warn!("Blob {} looks like a manifest but doesn't parse", hash);
+                    Vec::new()
+                });
+                pending.extend(chunks.into_iter().map(|chunk| (chunk, depth)));
+            }
+        }
+    }
+    report.referenced = referenced.len() as u64;
+
+    // Everything else in the store is garbage, though it may be bad garbage
+    for (hash, size) in store.blobs().context("Failed to list the blob store")? {
+        report.blobs += 1;
+        report.blob_bytes += size;
+        if referenced.contains(&hash) {
+            continue;
+        }
+        match store.get(&hash) {
+            Ok(_) => report.unreachable.push(hash.to_hex()),
+            Err(BlobError::Corrupted(_)) => report.corrupt.push(hash.to_hex()),
+            // Deleted since it was listed
+            Err(BlobError::NotFound(_)) => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read blob {}", hash)),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Read a referenced blob, repairing it from `replica` if it's missing or
+/// corrupt. None if it couldn't be had.
+fn fetch_cas_blob(
+    store: &dyn BlobStore,
+    replica: Option<&dyn BlobStore>,
+    hash: &Hash,
+    report: &mut FsckReport,
+) -> Result<Option<Vec<u8>>> {
+    let damage = match store.get(hash) {
+        Ok(data) => return Ok(Some(data)),
+        Err(BlobError::NotFound(_)) => &mut report.missing,
+        Err(BlobError::Corrupted(_)) => &mut report.corrupt,
+        Err(e) => return Err(e).with_context(|| format!("Failed to read blob {}", hash)),
+    };
+    // The replica's copy is verified as it's read
+    let copy = replica.and_then(|replica| match replica.get(hash) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            log::warn!("Blob {} can't be repaired from the replica: {}", hash, e);
+            None
+        }
+    });
+    let Some(data) = copy else {
+        damage.push(hash.to_hex());
+        return Ok(None);
+    };
+
+    // A corrupt copy has to go before the good one can be stored
+    store
+        .delete(hash)
+        .and_then(|()| store.put(hash, &data))
+        .with_context(|| format!("Failed to repair blob {}", hash))?;
+    log::info!("Repaired blob {} from the replica", hash);
+    report.repaired.push(hash.to_hex());
+    Ok(Some(data))
+}
+
+/// Check the iSCSI CAS store in `dir` against the LBA indexes (targets'
+/// indexes and snapshot layers) at `indexes`, repairing referenced blobs
+/// from the CAS server `replica` if given
+pub fn check_iscsi_store(
+    dir: &Path,
+    indexes: &[PathBuf],
+    replica: Option<&CasPool>,
+) -> Result<FsckReport> {
+    let mut referenced = HashSet::new();
+    for path in indexes {
+        let index = LbaIndex::open(path)
+            .with_context(|| format!("Failed to open index {:?} (is its target running?)", path))?;
+        if let Some(index) = index {
+            referenced.insert(index.zero_block_hash);
+            referenced.extend(
+                index.hashes().with_context(|| format!("Failed to read index {:?}", path))?,
+            );
+        }
+    }
+
+    let storage = CasStorage::new(dir)
+        .with_context(|| format!("Failed to open CAS storage {:?}", dir))?;
+    let mut report = FsckReport {
+        referenced: referenced.len() as u64,
+        ..Default::default()
+    };
+
+    // Verify every stored block, noting those that are sound
+    let mut sound = HashSet::new();
+    let mut damaged = Vec::new();
+    for (hash, path) in stored_cas_blocks(dir)? {
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            // Deleted by GC since the directory was listed
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", path)),
+        };
+        report.blobs += 1;
+        report.blob_bytes += data.len() as u64;
+        let intact = xxh3_128(&data).to_le_bytes() == hash;
+        match (intact, referenced.contains(&hash)) {
+            (true, true) => {
+                sound.insert(hash);
+            }
+            (true, false) => report.unreachable.push(hex::encode(hash)),
+            (false, true) => damaged.push(hash),
+            (false, false) => report.corrupt.push(hex::encode(hash)),
+        }
+    }
+
+    let mut missing: Vec<cas::Hash> = referenced.difference(&sound).copied().collect();
+    missing.retain(|hash| !damaged.contains(hash));
+    for (hash, corrupt) in missing
+        .into_iter()
+        .map(|hash| (hash, false))
+        .chain(damaged.into_iter().map(|hash| (hash, true)))
+    {
+        let repaired = match replica {
+            Some(replica) => repair_cas_block(&storage, replica, &hash, corrupt)?,
+            None => false,
+        };
+        let list = match (repaired, corrupt) {
+            (true, _) => &mut report.repaired,
+            (false, true) => &mut report.corrupt,
+            (false, false) => &mut report.missing,
+        };
+        list.push(hex::encode(hash));
+    }
+
+    Ok(report)
+}
+
+/// Copy a block from the replica CAS server into `storage`, replacing a
+/// corrupt copy. False if the replica doesn't have a good one.
+fn repair_cas_block(
+    storage: &CasStorage,
+    replica: &CasPool,
+    hash: &cas::Hash,
+    corrupt: bool,
+) -> Result<bool> {
+    let data = match replica.read(hash) {
+        Ok(data) if xxh3_128(&data).to_le_bytes() == *hash => data,
+        Ok(_) => {
+            log::warn!("Replica's copy of block {} is corrupt too", hex::encode(hash));
+            return Ok(false);
+        }
+        Err(e) => {
+            log::warn!("Block {} can't be repaired from the replica: {}", hex::encode(hash), e);
+            return Ok(false);
+        }
+    };
+    if corrupt {
+        storage.delete(hash)?;
+    }
+    storage
+        .write(&data)
+        .with_context(|| format!("Failed to repair block {}", hex::encode(hash)))?;
+    log::info!("Repaired block {} from the replica", hex::encode(hash));
+    Ok(true)
+}
+
+/// Every block file in a `CasStorage` directory, with the hash it's named by
+fn stored_cas_blocks(dir: &Path) -> Result<Vec<(cas::Hash, PathBuf)>> {
+    let mut blocks = Vec::new();
+    let entries = fs::read_dir(dir).with_context(|| format!("Failed to list {:?}", dir))?;
+    for prefix in entries {
+        let prefix = prefix?;
+        let prefix_name = prefix.file_name().to_string_lossy().into_owned();
+        if prefix_name.len() != 2 || !prefix.file_type()?.is_dir() {
+            continue;
+        }
+        for file in fs::read_dir(prefix.path())? {
+            let file = file?;
+            let name = format!("{}{}", prefix_name, file.file_name().to_string_lossy());
+            let Ok(Ok(hash)) = hex::decode(&name).map(cas::Hash::try_from) else {
+                continue;
+            };
+            blocks.push((hash, file.path()));
+        }
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::cas::{CasBackend, Compression};
+    use crate::storage::{ArchivalStorage, BlockStorage};
+    use tempfile::TempDir;
+
+    /// Hash of a sector as stored uncompressed
+    fn stored_hash(data: &[u8]) -> Hash {
+        let mut stored = vec![0x00];
+        stored.extend_from_slice(data);
+        Hash::from_data(&stored)
+    }
+
+    #[test]
+    fn test_cas_store_damage_and_repair() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("blobs");
+        let snapshots = temp.path().join("snapshots.json");
+        {
+            let store = Box::new(FileBlobStore::new(&dir).unwrap());
+            let backend = CasBackend::new(store, 1024, &snapshots)
+                .unwrap()
+                .with_compression(Compression::None);
+            backend.write(0, &[0xAA; 512]).unwrap();
+            backend.write(5, &[0xBB; 512]).unwrap();
+            backend.snapshot(None).unwrap();
+            backend.write(9, &[0xCC; 512]).unwrap();
+            backend.flush().unwrap();
+        }
+
+        // A replica of everything, then damage and garbage in the original
+        let store = FileBlobStore::new(&dir).unwrap();
+        let replica = FileBlobStore::new(temp.path().join("replica")).unwrap();
+        for (hash, _) in store.blobs().unwrap() {
+            replica.put(&hash, &store.get(&hash).unwrap()).unwrap();
+        }
+        let (lost, damaged) = (stored_hash(&[0xAA; 512]), stored_hash(&[0xCC; 512]));
+        let path = |hash: &Hash| dir.join(&hash.to_hex()[..2]).join(&hash.to_hex()[2..]);
+        fs::remove_file(path(&lost)).unwrap();
+        fs::write(path(&damaged), b"bit rot").unwrap();
+        let garbage = Hash::from_data(b"garbage");
+        store.put(&garbage, b"garbage").unwrap();
+
+        let trees = [CasTree {
+            snapshots,
+            blocks: 1024,
+        }];
+        let report = check_cas_store(&[dir.clone()], &trees, None).unwrap();
+        assert_eq!(report.missing, vec![lost.to_hex()]);
+        assert_eq!(report.corrupt, vec![damaged.to_hex()]);
+        // Along with tree nodes replaced by later writes
+        assert!(report.unreachable.contains(&garbage.to_hex()));
+        assert!(!report.is_clean());
+
+        let report = check_cas_store(&[dir.clone()], &trees, Some(&replica)).unwrap();
+        assert!(report.is_clean());
+        assert_eq!(report.repaired.len(), 2);
+
+        let report = check_cas_store(&[dir], &trees, None).unwrap();
+        assert!(report.is_clean() && report.repaired.is_empty());
+        assert_eq!(report.blobs, report.referenced + report.unreachable.len() as u64);
+        assert_eq!(store.get(&lost).unwrap()[1..], [0xAA; 512]);
+    }
+
+    #[test]
+    fn test_iscsi_store_damage() {
+        let temp = TempDir::new().unwrap();
+        let dir = temp.path().join("cas");
+        let storage = CasStorage::new(&dir).unwrap();
+        let index_path = temp.path().join("targets/disk");
+        let zero = vec![0u8; 4096];
+        let kept = storage.write(b"kept").unwrap();
+        let lost = xxh3_128(b"lost").to_le_bytes();
+        let damaged = storage.write(b"damaged").unwrap();
+        let garbage = storage.write(b"garbage").unwrap();
+        {
+            let index = LbaIndex::open_or_create(&index_path, || storage.write(&zero)).unwrap();
+            for (lba, hash) in [kept, lost, damaged].iter().enumerate() {
+                index.insert(lba as u64, hash).unwrap();
+            }
+            index.flush().unwrap();
+        }
+        let hex_path = |hash: &cas::Hash| {
+            let hex = hex::encode(hash);
+            dir.join(&hex[..2]).join(&hex[2..])
+        };
+        fs::write(hex_path(&damaged), b"bit rot").unwrap();
+
+        let report = check_iscsi_store(&dir, &[index_path], None).unwrap();
+        assert_eq!(report.referenced, 4);
+        assert_eq!(report.blobs, 4);
+        assert_eq!(report.missing, vec![hex::encode(lost)]);
+        assert_eq!(report.corrupt, vec![hex::encode(damaged)]);
+        assert_eq!(report.unreachable, vec![hex::encode(garbage)]);
+    }
+}
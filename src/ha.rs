@@ -0,0 +1,476 @@
+//! Active/passive failover pair
+//!
+//! Two servers are set up to export the same store (on a shared filesystem,
+//! or replicated) and only the active one serves it. They exchange UDP
+//! heartbeats. A node starts passive and opens nothing until it becomes
+//! active, so on taking over it loads whatever root the old active node last
+//! persisted.
+//!
+//! Fencing is an exclusive lock on a lease file on the shared store, which
+//! the active node holds for as long as it serves. The filesystem's lock
+//! manager (NFSv4, CephFS, GFS2 and the like) grants it to one node at a
+//! time and revokes it from a node it can no longer reach, so the lease
+//! file must be on a filesystem shared by both nodes even when the store
+//! itself is replicated. A passive node that hasn't heard from an active
+//! peer for `failover_ms` tries to take the lock, and only once it has it
+//! writes the next epoch and starts serving. The active node rewrites its
+//! lease through the locked file every heartbeat and exits at once, without
+//! flushing, if that fails (its lock is gone) or the peer is active at a
+//! later epoch.
+
+use crate::shutdown;
+use crate::systemd;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Exit status of an active node that has been fenced, so a supervisor can
+/// tell it apart from a crash before restarting it as passive
+pub const FENCED_EXIT_CODE: i32 = 3;
+
+/// Failover pair settings (`[ha]`)
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct HaConfig {
+    /// This node's name, recorded in the lease
+    pub node: String,
+
+    /// Address heartbeats are received on (e.g. "0.0.0.0:7400")
+    pub listen: String,
+
+    /// The other node's heartbeat address
+    pub peer: String,
+
+    /// Lease file on the shared store, locked by the active node
+    pub lease: PathBuf,
+
+    /// Time between heartbeats and lease checks
+    #[serde(default = "default_heartbeat_ms")]
+    pub heartbeat_ms: u64,
+
+    /// Silence from the active node before the passive one takes over
+    #[serde(default = "default_failover_ms")]
+    pub failover_ms: u64,
+}
+
+fn default_heartbeat_ms() -> u64 {
+    500
+}
+
+fn default_failover_ms() -> u64 {
+    5000
+}
+
+impl HaConfig {
+    /// Check the timings leave room for a missed heartbeat or two
+    pub fn validate(&self) -> Result<(), String> {
+        if self.heartbeat_ms == 0 {
+            return Err("ha heartbeat_ms must be greater than zero".to_string());
+        }
+        if self.failover_ms < 3 * self.heartbeat_ms {
+            return Err(format!(
+                "ha failover_ms {} must be at least three heartbeats ({} ms)",
+                self.failover_ms,
+                3 * self.heartbeat_ms
+            ));
+        }
+        if self.node.is_empty() {
+            return Err("ha node must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    fn heartbeat(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_ms)
+    }
+
+    fn failover(&self) -> Duration {
+        Duration::from_millis(self.failover_ms)
+    }
+}
+
+/// Who may serve the store
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lease {
+    pub epoch: u64,
+    pub node: String,
+}
+
+/// Read the lease last written, if one has been taken
+pub fn read_lease(path: &Path) -> io::Result<Option<Lease>> {
+    match fs::read(path) {
+        Ok(data) if data.is_empty() => Ok(None),
+        Ok(data) => serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// A lease this node holds the lock of; dropping it releases the lock
+pub struct HeldLease {
+    file: File,
+    pub lease: Lease,
+}
+
+impl HeldLease {
+    /// Lock the lease file at `path` and take the lease at the epoch after
+    /// the last one written. `None` while another node holds the lock.
+    pub fn claim(path: &Path, node: &str) -> io::Result<Option<Self>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // SAFETY: flock only reads the descriptor, which `file` keeps open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(None),
+                _ => Err(e),
+            };
+        }
+        let epoch = read_lease(path)?.map_or(1, |lease| lease.epoch + 1);
+        let held = Self {
+            file,
+            lease: Lease {
+                epoch,
+                node: node.to_string(),
+            },
+        };
+        held.renew()?;
+        Ok(Some(held))
+    }
+
+    /// Write the lease through the locked file and sync it, which fails
+    /// once the filesystem has revoked the lock
+    pub fn renew(&self) -> io::Result<()> {
+        let record = serde_json::to_vec(&self.lease)?;
+        self.file.set_len(0)?;
+        self.file.write_all_at(&record, 0)?;
+        self.file.sync_all()
+    }
+}
+
+/// What a node tells its peer every heartbeat
+#[derive(Debug, Deserialize, Serialize)]
+struct Heartbeat {
+    node: String,
+    active: bool,
+    epoch: u64,
+}
+
+/// The last heartbeat heard from the peer
+#[derive(Debug, Default)]
+struct PeerState {
+    last_active: Option<Instant>,
+    epoch: u64,
+}
+
+impl PeerState {
+    /// Take in a heartbeat heard at `now`
+    fn heard(&mut self, heartbeat: &Heartbeat, now: Instant) {
+        if heartbeat.active {
+            self.last_active = Some(now);
+        }
+        self.epoch = heartbeat.epoch;
+    }
+
+    /// Whether the peer was heard active within `failover` of `now`
+    fn active_at(&self, now: Instant, failover: Duration) -> bool {
+        self.last_active
+            .is_some_and(|seen| now.saturating_duration_since(seen) < failover)
+    }
+}
+
+/// When a passive node may try to take over: once no active peer has been
+/// heard for the whole failover time
+struct Takeover {
+    failover: Duration,
+    quiet_since: Instant,
+}
+
+impl Takeover {
+    /// Start counting at `now`, which gives a running active node a full
+    /// failover time to be heard
+    fn new(failover: Duration, now: Instant) -> Self {
+        Self {
+            failover,
+            quiet_since: now,
+        }
+    }
+
+    /// Whether to try for the lease at `now`
+    fn due(&mut self, peer: &PeerState, now: Instant) -> bool {
+        if peer.active_at(now, self.failover) {
+            self.quiet_since = now;
+            return false;
+        }
+        now.saturating_duration_since(self.quiet_since) >= self.failover
+    }
+}
+
+/// One node of a failover pair
+pub struct HaNode {
+    config: HaConfig,
+    peer: Arc<Mutex<PeerState>>,
+    active: Arc<AtomicBool>,
+    epoch: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+}
+
+impl HaNode {
+    /// Bind the heartbeat socket and start exchanging heartbeats, as passive
+    pub fn start(config: &HaConfig) -> io::Result<Self> {
+        let socket = UdpSocket::bind(&config.listen)?;
+        socket.set_read_timeout(Some(shutdown::POLL_INTERVAL.min(config.heartbeat())))?;
+        let peer_addr = config
+            .peer
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "ha peer has no address"))?;
+        log::info!(
+            "HA node {} exchanging heartbeats on {} with {}",
+            config.node,
+            socket.local_addr()?,
+            peer_addr
+        );
+
+        let node = Self {
+            config: config.clone(),
+            peer: Arc::new(Mutex::new(PeerState::default())),
+            active: Arc::new(AtomicBool::new(false)),
+            epoch: Arc::new(AtomicU64::new(0)),
+            stop: Arc::new(AtomicBool::new(false)),
+        };
+        node.spawn_sender(socket.try_clone()?, peer_addr);
+        node.spawn_receiver(socket);
+        Ok(node)
+    }
+
+    fn running(stop: &AtomicBool) -> bool {
+        !stop.load(Ordering::SeqCst) && !shutdown::requested()
+    }
+
+    fn spawn_sender(&self, socket: UdpSocket, peer: SocketAddr) {
+        let (name, interval) = (self.config.node.clone(), self.config.heartbeat());
+        let (active, epoch, stop) = (self.active.clone(), self.epoch.clone(), self.stop.clone());
+        thread::spawn(move || {
+            while Self::running(&stop) {
+                let heartbeat = Heartbeat {
+                    node: name.clone(),
+                    active: active.load(Ordering::SeqCst),
+                    epoch: epoch.load(Ordering::SeqCst),
+                };
+                let message = serde_json::to_vec(&heartbeat).expect("heartbeat serializes");
+                if let Err(e) = socket.send_to(&message, peer) {
+                    log::debug!("Failed to send heartbeat to {}: {}", peer, e);
+                }
+                thread::sleep(interval);
+            }
+        });
+    }
+
+    fn spawn_receiver(&self, socket: UdpSocket) {
+        let name = self.config.node.clone();
+        let (peer, stop) = (self.peer.clone(), self.stop.clone());
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while Self::running(&stop) {
+                let len = match socket.recv_from(&mut buf) {
+                    Ok((len, _)) => len,
+                    Err(e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        continue
+                    }
+                    Err(e) => {
+                        log::warn!("Heartbeat receive failed: {}", e);
+                        continue;
+                    }
+                };
+                let heartbeat: Heartbeat = match serde_json::from_slice(&buf[..len]) {
+                    Ok(heartbeat) => heartbeat,
+                    Err(e) => {
+                        log::debug!("Ignoring malformed heartbeat: {}", e);
+                        continue;
+                    }
+                };
+                if heartbeat.node == name {
+                    log::warn!("Heartbeat from another node named {}; check the ha config", name);
+                    continue;
+                }
+                peer.lock().unwrap().heard(&heartbeat, Instant::now());
+            }
+        });
+    }
+
+    /// Whether an active peer has been heard from within the failover time
+    pub fn peer_active(&self) -> bool {
+        let peer = self.peer.lock().unwrap();
+        peer.active_at(Instant::now(), self.config.failover())
+    }
+
+    /// Stay passive until the peer stops heartbeating as active, then take
+    /// the lease. Returns `None` if shutdown is requested first.
+    pub fn wait_until_active(&self) -> io::Result<Option<HeldLease>> {
+        log::info!("HA node {} is passive", self.config.node);
+        let _ = systemd::notify("STATUS=Passive, waiting for the active node to fail");
+        let mut takeover = Takeover::new(self.config.failover(), Instant::now());
+        while Self::running(&self.stop) {
+            let now = Instant::now();
+            if takeover.due(&self.peer.lock().unwrap(), now) {
+                match HeldLease::claim(&self.config.lease, &self.config.node)? {
+                    Some(held) => {
+                        self.epoch.store(held.lease.epoch, Ordering::SeqCst);
+                        self.active.store(true, Ordering::SeqCst);
+                        log::warn!(
+                            "No active peer for {:?}; HA node {} is active (epoch {})",
+                            self.config.failover(),
+                            held.lease.node,
+                            held.lease.epoch
+                        );
+                        let _ = systemd::notify("STATUS=Active");
+                        return Ok(Some(held));
+                    }
+                    None => {
+                        log::warn!("The HA lease is still locked by another node; staying passive");
+                        takeover = Takeover::new(self.config.failover(), now);
+                    }
+                }
+            }
+            thread::sleep(self.config.heartbeat());
+        }
+        Ok(None)
+    }
+
+    /// Keep serving under `held`; exit with `FENCED_EXIT_CODE`, without
+    /// flushing anything, once it is lost
+    pub fn hold(&self, held: HeldLease) {
+        let (config, peer, stop) = (self.config.clone(), self.peer.clone(), self.stop.clone());
+        thread::spawn(move || {
+            while Self::running(&stop) {
+                thread::sleep(config.heartbeat());
+                if let Some(reason) = fenced(&held, &peer.lock().unwrap()) {
+                    log::error!("Fenced: {}; exiting without flushing", reason);
+                    std::process::exit(FENCED_EXIT_CODE);
+                }
+            }
+        });
+    }
+}
+
+impl Drop for HaNode {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Why the holder of `held` must stop serving, if it must
+fn fenced(held: &HeldLease, peer: &PeerState) -> Option<String> {
+    if peer.last_active.is_some() && peer.epoch > held.lease.epoch {
+        return Some(format!("the peer is active at epoch {}", peer.epoch));
+    }
+    held.renew()
+        .err()
+        .map(|e| format!("the lease can't be renewed ({})", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(node: &str, listen: &str, peer: &str, lease: &Path) -> HaConfig {
+        HaConfig {
+            node: node.to_string(),
+            listen: listen.to_string(),
+            peer: peer.to_string(),
+            lease: lease.to_path_buf(),
+            heartbeat_ms: 20,
+            failover_ms: 200,
+        }
+    }
+
+    #[test]
+    fn test_lease_lock() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("ha.lease");
+        assert_eq!(read_lease(&path).unwrap(), None);
+
+        let a = HeldLease::claim(&path, "a").unwrap().unwrap();
+        assert_eq!(a.lease.epoch, 1);
+        assert_eq!(read_lease(&path).unwrap(), Some(a.lease.clone()));
+        let quiet = PeerState::default();
+        assert_eq!(fenced(&a, &quiet), None);
+
+        // Locked until the holder lets go
+        assert!(HeldLease::claim(&path, "b").unwrap().is_none());
+        drop(a);
+        let b = HeldLease::claim(&path, "b").unwrap().unwrap();
+        assert_eq!((b.lease.epoch, b.lease.node.as_str()), (2, "b"));
+        assert_eq!(fenced(&b, &quiet), None);
+
+        // An active peer at a later epoch fences too
+        let mut peer = PeerState::default();
+        let heartbeat = Heartbeat {
+            node: "a".to_string(),
+            active: true,
+            epoch: 3,
+        };
+        peer.heard(&heartbeat, Instant::now());
+        assert!(fenced(&b, &peer).unwrap().contains("epoch 3"));
+    }
+
+    #[test]
+    fn test_takeover() {
+        let failover = Duration::from_millis(200);
+        let ms = Duration::from_millis;
+        let start = Instant::now();
+        let mut takeover = Takeover::new(failover, start);
+        let mut peer = PeerState::default();
+
+        // Nobody heard from for the failover time
+        assert!(!takeover.due(&peer, start + ms(199)));
+        assert!(takeover.due(&peer, start + ms(200)));
+
+        // An active peer holds it off, and its silence is counted from
+        // when it was last seen active
+        let heartbeat = Heartbeat {
+            node: "b".to_string(),
+            active: true,
+            epoch: 1,
+        };
+        peer.heard(&heartbeat, start + ms(200));
+        assert!(!takeover.due(&peer, start + ms(300)));
+        assert!(!takeover.due(&peer, start + ms(450)));
+        assert!(takeover.due(&peer, start + ms(500)));
+
+        // A passive peer doesn't
+        let mut takeover = Takeover::new(failover, start);
+        let mut peer = PeerState::default();
+        let heartbeat = Heartbeat {
+            active: false,
+            ..heartbeat
+        };
+        peer.heard(&heartbeat, start + ms(100));
+        assert!(takeover.due(&peer, start + ms(200)));
+    }
+
+    #[test]
+    fn test_config_timings() {
+        let mut config = config("a", "127.0.0.1:0", "127.0.0.1:1", Path::new("ha.lease"));
+        assert!(config.validate().is_ok());
+        config.failover_ms = 40;
+        assert!(config.validate().is_err());
+    }
+}
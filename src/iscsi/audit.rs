@@ -0,0 +1,232 @@
+//! Audit log of target management operations
+//!
+//! Every create, clone, snapshot, restore, flatten, delete and GC made
+//! through `CloneManager`, and every start and stop of a target by
+//! `iscsi-server`, is appended to `<registry path>.audit` as one JSON line:
+//! who did it, when, with which parameters and whether it worked. Entries
+//! are never rewritten, so the log outlives the targets it mentions and
+//! shows how a clone tree came to be (`iscsi-clone history`).
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use super::registry::TargetRegistry;
+
+/// A management operation on a target
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOperation {
+    Create,
+    /// Recorded against the source; the new target is `params.dest_iqn`
+    Clone,
+    Snapshot,
+    Restore,
+    Flatten,
+    Delete,
+    Gc,
+    /// A server started serving the target
+    Start,
+    /// The server stopped serving it
+    Stop,
+}
+
+impl fmt::Display for AuditOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(match self {
+            AuditOperation::Create => "create",
+            AuditOperation::Clone => "clone",
+            AuditOperation::Snapshot => "snapshot",
+            AuditOperation::Restore => "restore",
+            AuditOperation::Flatten => "flatten",
+            AuditOperation::Delete => "delete",
+            AuditOperation::Gc => "gc",
+            AuditOperation::Start => "start",
+            AuditOperation::Stop => "stop",
+        })
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix epoch seconds
+    pub timestamp: u64,
+    /// User (or web client) that asked for the operation
+    pub actor: String,
+    pub operation: AuditOperation,
+    /// Target IQN
+    pub target: String,
+    /// Operation parameters, e.g. a clone's destination
+    #[serde(default)]
+    pub params: Value,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
+/// Append-only audit log kept next to a registry
+#[derive(Debug, Clone)]
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// The audit log of the registry at `registry_path`
+    pub fn for_registry(registry_path: &Path) -> Self {
+        let mut path = registry_path.as_os_str().to_owned();
+        path.push(".audit");
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an entry. Each entry is one write to a file opened for
+    /// appending, so concurrent writers (the CLI, the web UI and the
+    /// server) don't interleave.
+    pub fn append(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_data()
+    }
+
+    /// Append an entry for an operation that has finished, logging rather
+    /// than returning a failure to write it
+    pub fn record<T, E: std::fmt::Display>(
+        &self,
+        actor: &str,
+        operation: AuditOperation,
+        target: &str,
+        params: Value,
+        result: &Result<T, E>,
+    ) {
+        let entry = AuditEntry {
+            timestamp: TargetRegistry::now(),
+            actor: actor.to_string(),
+            operation,
+            target: target.to_string(),
+            params,
+            success: result.is_ok(),
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        };
+        if let Err(e) = self.append(&entry) {
+            log::warn!("Failed to write audit log {:?}: {}", self.path, e);
+        }
+    }
+
+    /// Every entry, oldest first. Lines that don't parse (a write cut
+    /// short by a crash) are skipped.
+    pub fn entries(&self) -> Result<Vec<AuditEntry>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read audit log {:?}", self.path))
+            }
+        };
+        Ok(content
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(entry) => Some(entry),
+                Err(e) => {
+                    log::warn!("Skipping unreadable audit log line: {}", e);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Entries about a target and the targets it was cloned from, oldest
+    /// first, so the history shows everything that led to it
+    pub fn history(&self, iqn: &str) -> Result<Vec<AuditEntry>> {
+        let entries = self.entries()?;
+
+        let mut lineage = HashSet::from([iqn.to_string()]);
+        let mut current = iqn.to_string();
+        while let Some(source) = entries.iter().find_map(|entry| {
+            (entry.success && cloned_to(entry) == Some(current.as_str()))
+                .then(|| entry.target.clone())
+        }) {
+            if !lineage.insert(source.clone()) {
+                break;
+            }
+            current = source;
+        }
+
+        Ok(entries
+            .into_iter()
+            .filter(|entry| {
+                lineage.contains(&entry.target)
+                    || cloned_to(entry).is_some_and(|dest| lineage.contains(dest))
+            })
+            .collect())
+    }
+}
+
+/// The target a clone entry created
+fn cloned_to(entry: &AuditEntry) -> Option<&str> {
+    match entry.operation {
+        AuditOperation::Clone => entry.params["dest_iqn"].as_str(),
+        _ => None,
+    }
+}
+
+/// The local user running this process, for CLI and server entries
+pub fn local_actor() -> String {
+    match std::env::var("SUDO_USER") {
+        Ok(user) => format!("{} (sudo)", user),
+        Err(_) => std::env::var("USER").unwrap_or_else(|_| "unknown".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_history_follows_clones() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let log = AuditLog::for_registry(&temp_dir.path().join("registry.json"));
+        assert!(log.entries()?.is_empty());
+
+        let ok: Result<(), String> = Ok(());
+        let failed: Result<(), String> = Err("Target is currently running".to_string());
+        log.record("alice", AuditOperation::Create, "base", json!({"size_mb": 100}), &ok);
+        log.record("alice", AuditOperation::Create, "other", json!({}), &ok);
+        log.record("bob", AuditOperation::Clone, "base", json!({"dest_iqn": "child"}), &ok);
+        log.record("bob", AuditOperation::Clone, "child", json!({"dest_iqn": "grand"}), &ok);
+        log.record("bob", AuditOperation::Delete, "base", json!({}), &failed);
+        log.record("bob", AuditOperation::Clone, "child", json!({"dest_iqn": "sibling"}), &ok);
+
+        // A torn last line is skipped
+        OpenOptions::new().append(true).open(log.path())?.write_all(b"{\"timest")?;
+        assert_eq!(log.entries()?.len(), 6);
+
+        let history = log.history("grand")?;
+        let ops: Vec<_> = history.iter().map(|e| (e.operation, e.target.as_str())).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (AuditOperation::Create, "base"),
+                (AuditOperation::Clone, "base"),
+                (AuditOperation::Clone, "child"),
+                (AuditOperation::Delete, "base"),
+                (AuditOperation::Clone, "child"),
+            ]
+        );
+        assert_eq!(history[3].error.as_deref(), Some("Target is currently running"));
+        assert_eq!(log.history("other")?.len(), 1);
+        Ok(())
+    }
+}
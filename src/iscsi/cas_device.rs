@@ -3,14 +3,26 @@
 //! Implements ScsiBlockDevice trait with CAS backend for direct iSCSI → CAS integration.
 
 use std::collections::HashMap;
-use std::io::{BufReader, BufWriter};
-use std::net::TcpStream;
-use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
-use sled::Db;
-
-use crate::cas::protocol::{read_frame, write_frame, CasCommand};
-use crate::cas::Hash;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread;
+
+use serde::Serialize;
+
+use crate::cas::cache::BlockCache;
+use crate::cas::client::{CasPool, CasPoolConfig};
+use crate::cas::{self, Hash};
+use crate::iscsi::index::LbaIndex;
+use crate::iscsi::journal::WriteJournal;
+use crate::iscsi::scsi;
+use crate::qos::{QosLimits, RateLimiter};
+use crate::storage::cas::{request_prefetch, spawn_prefetcher, SequentialDetector};
+use crate::storage::{is_all_zero, TargetUuid};
+use crate::tls::TlsClient;
 use iscsi_target::{IscsiError, ScsiBlockDevice, ScsiResult};
 
 const BLOCK_SIZE: u32 = 4096;  // 4KB blocks - good balance for CAS dedup
@@ -31,6 +43,16 @@ pub struct CasScsiDeviceConfig {
     pub product_id: String,
     /// SCSI product revision (4 chars)
     pub product_rev: String,
+    /// Size of the local hash -> block read cache in MiB (0 disables it)
+    pub read_cache_mb: usize,
+    /// Journal cached writes to `<index_path>.journal` before
+    /// acknowledging them, so a crash can't lose them
+    pub journal: bool,
+    /// Reach the CAS server over mutual TLS
+    pub cas_tls: Option<Arc<TlsClient>>,
+    /// Identity to give the device, which must not already have another;
+    /// a new one is generated if unset
+    pub uuid: Option<TargetUuid>,
 }
 
 impl Default for CasScsiDeviceConfig {
@@ -42,189 +64,436 @@ impl Default for CasScsiDeviceConfig {
             vendor_id: "VoE     ".to_string(),
             product_id: "CAS Block Device".to_string(),
             product_rev: "1.0 ".to_string(),
+            read_cache_mb: 64,
+            journal: true,
+            cas_tls: None,
+            uuid: None,
         }
     }
 }
 
-/// Persistent index of LBA to hash mappings using sled
-struct LbaIndex {
-    db: Arc<Db>,
-    zero_block_hash: Hash,
+/// Runtime counters of one device since it was opened
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DeviceStats {
+    /// Read commands
+    pub reads: u64,
+    /// Write commands
+    pub writes: u64,
+    pub blocks_read: u64,
+    pub blocks_written: u64,
+    /// Blocks read from the write cache before they were flushed
+    pub write_cache_hits: u64,
+    /// Blocks found in the read cache (which may be shared with other
+    /// devices; these count only this device's lookups)
+    pub read_cache_hits: u64,
+    pub read_cache_misses: u64,
+    /// Write cache flushes that had blocks to write
+    pub flushes: u64,
+    pub blocks_flushed: u64,
+    /// Requests sent to the CAS server, readahead included
+    pub cas_requests: u64,
 }
 
-// Special key for storing zero block hash
-const ZERO_BLOCK_KEY: &[u8] = b"__ZERO_BLOCK__";
-
-impl LbaIndex {
-    fn new(db_path: &PathBuf, zero_block_hash: Hash) -> std::io::Result<Self> {
-        // Create parent directory if needed
-        if let Some(parent) = db_path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-
-        let db = sled::open(db_path)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+impl DeviceStats {
+    /// Fraction of read cache lookups that hit, if there were any
+    pub fn read_cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.read_cache_hits + self.read_cache_misses;
+        (lookups > 0).then(|| self.read_cache_hits as f64 / lookups as f64)
+    }
+}
 
-        let db = Arc::new(db);
+/// Counters behind `DeviceStats`, updated without the state lock
+#[derive(Default)]
+struct DeviceCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    blocks_read: AtomicU64,
+    blocks_written: AtomicU64,
+    write_cache_hits: AtomicU64,
+    read_cache_hits: AtomicU64,
+    read_cache_misses: AtomicU64,
+    flushes: AtomicU64,
+    blocks_flushed: AtomicU64,
+    cas_requests: AtomicU64,
+}
 
-        // Store zero block hash
-        db.insert(ZERO_BLOCK_KEY, &zero_block_hash)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+impl DeviceCounters {
+    fn add(counter: &AtomicU64, n: u64) {
+        counter.fetch_add(n, Ordering::Relaxed);
+    }
 
-        Ok(Self { db, zero_block_hash })
+    fn snapshot(&self) -> DeviceStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        DeviceStats {
+            reads: load(&self.reads),
+            writes: load(&self.writes),
+            blocks_read: load(&self.blocks_read),
+            blocks_written: load(&self.blocks_written),
+            write_cache_hits: load(&self.write_cache_hits),
+            read_cache_hits: load(&self.read_cache_hits),
+            read_cache_misses: load(&self.read_cache_misses),
+            flushes: load(&self.flushes),
+            blocks_flushed: load(&self.blocks_flushed),
+            cas_requests: load(&self.cas_requests),
+        }
     }
+}
 
-    fn open(db_path: &PathBuf) -> std::io::Result<Self> {
-        let db = sled::open(db_path)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+/// Internal state protected by mutex
+struct CasScsiDeviceState {
+    cas: Arc<CasPool>,
+    counters: Arc<DeviceCounters>,
+    index: LbaIndex,
+    /// Write cache: LBA -> (data, dirty flag)
+    write_cache: HashMap<u64, Vec<u8>>,
+    /// Durable copy of the write cache, replayed after a crash
+    journal: Option<WriteJournal>,
+    /// The Caching mode page's WCE: acknowledge writes from the write
+    /// cache alone. Off, each write is journaled, or written through to
+    /// CAS without a journal, before it is acknowledged.
+    write_back: bool,
+    /// While a GC fence is up, the hash of every block is appended here
+    /// before the block is written to CAS (see `live`)
+    gc_log: Option<File>,
+}
+
+/// Handle that flushes a device's write cache after the device has been
+/// handed to the iSCSI server (used for shutdown and live snapshots), and
+/// reads its statistics
+#[derive(Clone)]
+pub struct CasScsiFlushHandle {
+    state: Arc<Mutex<CasScsiDeviceState>>,
+    counters: Arc<DeviceCounters>,
+    uuid: TargetUuid,
+}
 
-        let db = Arc::new(db);
+impl CasScsiFlushHandle {
+    /// Write cached blocks to CAS and persist the index
+    pub fn flush(&self) -> std::io::Result<usize> {
+        CasScsiDevice::flush_cache(&mut self.state.lock().unwrap())
+    }
 
-        // Load zero block hash
-        let zero_block_hash = db.get(ZERO_BLOCK_KEY)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
-            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "Missing zero block hash"))?;
+    /// Flush, then copy the index to a new layer for a point-in-time
+    /// clone. Writes wait only for the flush, not the copy.
+    pub fn snapshot(&self, layer_path: &Path) -> std::io::Result<usize> {
+        let copy = {
+            let mut state = self.state.lock().unwrap();
+            CasScsiDevice::flush_cache(&mut state)?;
+            state.index.begin_snapshot()?
+        };
+        copy.write_to(layer_path)
+    }
 
-        let mut hash = [0u8; 16];
-        hash.copy_from_slice(&zero_block_hash);
+    /// The device's counters; doesn't wait for I/O in progress
+    pub fn stats(&self) -> DeviceStats {
+        self.counters.snapshot()
+    }
 
-        Ok(Self { db, zero_block_hash: hash })
+    /// The device's persistent identity
+    pub fn uuid(&self) -> TargetUuid {
+        self.uuid
     }
 
-    fn get(&self, lba: u64) -> std::io::Result<Option<Hash>> {
-        let key = lba.to_le_bytes();
-        match self.db.get(&key) {
-            Ok(Some(value)) => {
-                if value.len() == 16 {
-                    let mut hash = [0u8; 16];
-                    hash.copy_from_slice(&value);
-                    Ok(Some(hash))
-                } else {
-                    Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid hash size"))
-                }
-            }
-            Ok(None) => Ok(None),
-            Err(e) => Err(std::io::Error::new(std::io::ErrorKind::Other, e)),
-        }
+    /// Log the hash of every block written to CAS to `log` from now on,
+    /// or stop logging with None
+    pub fn log_written_hashes(&self, log: Option<File>) {
+        self.state.lock().unwrap().gc_log = log;
     }
+}
 
-    fn insert(&self, lba: u64, hash: &Hash) -> std::io::Result<()> {
-        let key = lba.to_le_bytes();
-        self.db.insert(&key, hash)
-            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-        Ok(())
+/// Prefetches blocks into the read cache ahead of sequential reads
+struct Readahead {
+    blocks: u64,
+    detector: Mutex<SequentialDetector>,
+    sender: SyncSender<u64>,
+}
+
+/// Flushes the write cache in the background once writes reach the
+/// in-flight cap, while further writes are refused with TASK SET FULL
+struct Drain {
+    sender: SyncSender<()>,
+    /// Set when writes were refused, until the next flush finishes
+    full: Arc<AtomicBool>,
+}
+
+impl Drain {
+    /// Start a flush unless one is already queued
+    fn start(&self) {
+        self.full.store(true, Ordering::Release);
+        let _ = self.sender.try_send(());
     }
 }
 
-/// Internal state protected by mutex
-struct CasScsiDeviceState {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
-    index: LbaIndex,
-    /// Write cache: LBA -> (data, dirty flag)
-    write_cache: HashMap<u64, Vec<u8>>,
+/// Error for a write refused at the in-flight cap. The initiator is
+/// meant to retry it once the cache has drained to CAS.
+fn task_set_full(cached: usize) -> IscsiError {
+    IscsiError::Scsi(format!("TASK SET FULL: {} blocks waiting for CAS", cached))
 }
 
 /// CAS-backed SCSI block device
 pub struct CasScsiDevice {
     config: CasScsiDeviceConfig,
+    /// Persistent identity, kept beside the index
+    uuid: TargetUuid,
     state: Arc<Mutex<CasScsiDeviceState>>,
+    counters: Arc<DeviceCounters>,
+    limiter: Option<RateLimiter>,
+    /// Present when the limiter caps writes in flight
+    drain: Option<Drain>,
+    read_cache: Option<Arc<BlockCache>>,
+    readahead: Option<Readahead>,
 }
 
 impl CasScsiDevice {
     /// Create a new CAS SCSI device
     pub fn new(config: CasScsiDeviceConfig) -> std::io::Result<Self> {
         log::info!("Connecting to CAS server at {}", config.cas_server_addr);
-        let stream = TcpStream::connect(&config.cas_server_addr)?;
-
-        let mut reader = BufReader::new(stream.try_clone()?);
-        let mut writer = BufWriter::new(stream);
-
-        // Try to open existing index, or create new
-        let index = if config.index_path.exists() {
-            log::info!("Opening existing RocksDB index at {:?}", config.index_path);
-            LbaIndex::open(&config.index_path)?
-        } else {
-            log::info!("Creating new RocksDB index, initializing zero block");
-            // Initialize zero block
-            let zero_block = vec![0u8; BLOCK_SIZE as usize];
-            let zero_hash = Self::write_to_cas_static(&mut writer, &mut reader, &zero_block)?;
-            log::info!("Zero block hash: {}", hex::encode(&zero_hash));
-            LbaIndex::new(&config.index_path, zero_hash)?
+        let pool_config = CasPoolConfig {
+            tls: config.cas_tls.clone(),
+            ..CasPoolConfig::default()
         };
+        let cas = CasPool::connect(&config.cas_server_addr, pool_config)?;
+
+        // Open the index (and any layers it shares with other clones),
+        // storing the zero block if it's new
+        let index = LbaIndex::open_or_create(&config.index_path, || {
+            let zero_hash = cas.write(&vec![0u8; BLOCK_SIZE as usize])?;
+            log::info!("Zero block hash: {}", hex::encode(zero_hash));
+            Ok(zero_hash)
+        })?;
+        let identity = identity_path(&config.index_path);
+        if let Some(uuid) = config.uuid {
+            TargetUuid::pin(&identity, uuid)?;
+        }
+        let uuid = TargetUuid::load_or_create(&identity, TargetUuid::generate)?;
+        log::info!("Target identity {} (serial {})", uuid, uuid.serial());
 
-        let state = CasScsiDeviceState {
-            reader,
-            writer,
+        let counters = Arc::new(DeviceCounters::default());
+        let mut state = CasScsiDeviceState {
+            cas: Arc::new(cas),
+            counters: Arc::clone(&counters),
             index,
             write_cache: HashMap::new(),
+            journal: None,
+            write_back: !config.journal,
+            gc_log: None,
         };
 
+        if config.journal {
+            let (journal, records) = WriteJournal::open(journal_path(&config.index_path))?;
+            state.journal = Some(journal);
+            if !records.is_empty() {
+                log::warn!("Replaying {} journaled write(s) from an unclean shutdown", records.len());
+                for (lba, data) in &records {
+                    Self::cache_blocks(&mut state, *lba, data);
+                }
+                let flushed = Self::flush_cache(&mut state)?;
+                log::info!("Recovered {} blocks from the write journal", flushed);
+            }
+        }
+
+        let read_cache = (config.read_cache_mb > 0)
+            .then(|| Arc::new(BlockCache::new(config.read_cache_mb)));
+
         Ok(Self {
             config,
+            uuid,
             state: Arc::new(Mutex::new(state)),
+            counters,
+            limiter: None,
+            drain: None,
+            read_cache,
+            readahead: None,
         })
     }
 
-    /// Write data to CAS and get hash (static version for initialization)
-    fn write_to_cas_static(
-        writer: &mut BufWriter<TcpStream>,
-        reader: &mut BufReader<TcpStream>,
-        data: &[u8],
-    ) -> std::io::Result<Hash> {
-        write_frame(writer, CasCommand::Write, data)?;
+    /// Whether writes are acknowledged before they are stable: the Caching
+    /// mode page's WCE. Initially only without a journal, since journaled
+    /// writes survive a crash even while they wait in the write cache.
+    pub fn write_cache_enabled(&self) -> bool {
+        self.state.lock().unwrap().write_back
+    }
+
+    /// Set WCE. Cached writes are flushed first, so none is left relying on
+    /// a journal that writes stop going to, or missing from one they start
+    /// going to.
+    pub fn set_write_cache_enabled(&self, enabled: bool) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.write_back != enabled {
+            Self::flush_cache(&mut state)?;
+            state.write_back = enabled;
+            let setting = if enabled { "enabled" } else { "disabled" };
+            log::info!("Write cache {}", setting);
+        }
+        Ok(())
+    }
+
+    /// MODE SENSE for this device: its Caching and Control pages
+    pub fn mode_sense(&self, cdb: &[u8]) -> Option<Vec<u8>> {
+        scsi::handle_mode_sense(cdb, self.write_cache_enabled())
+    }
 
-        let (cmd, hash_data) = read_frame(reader)?;
+    /// MODE SELECT for this device, applying the WCE it sets
+    pub fn mode_select(&self, cdb: &[u8], data: &[u8]) -> std::io::Result<()> {
+        let enabled = scsi::handle_mode_select(cdb, data, self.write_cache_enabled())?;
+        self.set_write_cache_enabled(enabled)
+    }
 
-        if let CasCommand::Write = cmd {
-            if hash_data.len() == 16 {
-                let mut hash = [0u8; 16];
-                hash.copy_from_slice(&hash_data);
-                return Ok(hash);
-            }
+    /// Handle for flushing this device from outside the iSCSI server
+    pub fn flush_handle(&self) -> CasScsiFlushHandle {
+        CasScsiFlushHandle {
+            state: Arc::clone(&self.state),
+            counters: Arc::clone(&self.counters),
+            uuid: self.uuid,
         }
+    }
 
-        Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "invalid CAS write response",
-        ))
+    /// Reads, writes, cache and CAS traffic since the device was opened
+    pub fn stats(&self) -> DeviceStats {
+        self.counters.snapshot()
     }
 
-    /// Write data to CAS and get hash
-    fn write_to_cas(state: &mut CasScsiDeviceState, data: &[u8]) -> std::io::Result<Hash> {
-        write_frame(&mut state.writer, CasCommand::Write, data)?;
+    /// Write all cached blocks to CAS, returning how many were flushed.
+    /// Only once every block is in CAS are the new mappings committed to
+    /// the index, in one atomic batch, and flushed to disk; a crash
+    /// mid-flush leaves the index as it was. Blocks stay cached (and
+    /// journaled) until the whole batch is in.
+    fn flush_cache(state: &mut CasScsiDeviceState) -> std::io::Result<usize> {
+        let mut entries = Vec::with_capacity(state.write_cache.len());
+        for (lba, block_data) in &state.write_cache {
+            // Zero blocks map to the zero block without a trip to CAS
+            let hash = if is_all_zero(block_data) {
+                state.index.zero_block_hash
+            } else {
+                // Logged first, so a GC round checking the log before each
+                // delete spares a block this dedups against; a delete
+                // already sent is refused by the CAS server's delete grace
+                if let Some(log) = &mut state.gc_log {
+                    log.write_all(&cas::hash(block_data))?;
+                }
+                DeviceCounters::add(&state.counters.cas_requests, 1);
+                state.cas.write(block_data)?
+            };
+            entries.push((*lba, hash));
+        }
+        state.index.insert_batch(&entries)?;
+        state.index.flush()?;
 
-        let (cmd, hash_data) = read_frame(&mut state.reader)?;
+        if let Some(journal) = &mut state.journal {
+            journal.clear()?;
+        }
+        let flushed = state.write_cache.len();
+        state.write_cache.clear();
+        if flushed > 0 {
+            DeviceCounters::add(&state.counters.flushes, 1);
+            DeviceCounters::add(&state.counters.blocks_flushed, flushed as u64);
+        }
+        Ok(flushed)
+    }
 
-        if let CasCommand::Write = cmd {
-            if hash_data.len() == 16 {
-                let mut hash = [0u8; 16];
-                hash.copy_from_slice(&hash_data);
-                return Ok(hash);
-            }
+    /// Split a write into zero-padded blocks in the write cache
+    fn cache_blocks(state: &mut CasScsiDeviceState, lba: u64, data: &[u8]) {
+        for (i, chunk) in data.chunks(BLOCK_SIZE as usize).enumerate() {
+            let mut block_data = vec![0u8; BLOCK_SIZE as usize];
+            block_data[..chunk.len()].copy_from_slice(chunk);
+            state.write_cache.insert(lba + i as u64, block_data);
         }
+    }
 
-        Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "invalid CAS write response",
-        ))
+    /// Use a read cache shared with other devices, e.g. clones of the
+    /// same base image
+    pub fn with_read_cache(mut self, cache: Arc<BlockCache>) -> Self {
+        self.read_cache = Some(cache);
+        self
     }
 
-    /// Read data from CAS by hash
-    fn read_from_cas(state: &mut CasScsiDeviceState, hash: &Hash) -> std::io::Result<Vec<u8>> {
-        write_frame(&mut state.writer, CasCommand::Read, hash)?;
+    /// Fetch the next `blocks` blocks into the read cache in the background
+    /// when reads turn sequential. Needs a read cache; set it first.
+    pub fn with_readahead(mut self, blocks: usize) -> Self {
+        let Some(cache) = self.read_cache.clone().filter(|_| blocks > 0) else {
+            self.readahead = None;
+            return self;
+        };
+        let state = Arc::clone(&self.state);
+        let counters = Arc::clone(&self.counters);
+        // Index lookups happen here too, not on the request thread
+        let sender = spawn_prefetcher("iscsi-readahead", blocks, move |lba: u64| {
+            let (cas, hash) = {
+                let state = state.lock().unwrap();
+                match state.index.get(lba) {
+                    // Unwritten and zeroed blocks read as zeros without CAS
+                    Ok(Some(hash)) if hash != state.index.zero_block_hash => {
+                        (Arc::clone(&state.cas), hash)
+                    }
+                    _ => return,
+                }
+            };
+            if cache.contains(&hash) {
+                return;
+            }
+            DeviceCounters::add(&counters.cas_requests, 1);
+            match cas.read(&hash) {
+                Ok(data) if data.len() == BLOCK_SIZE as usize => cache.insert(hash, &data),
+                Ok(_) => {}
+                Err(e) => log::debug!("Readahead of {} failed: {}", hex::encode(hash), e),
+            }
+        });
+        self.readahead = Some(Readahead {
+            blocks: blocks as u64,
+            detector: Mutex::new(SequentialDetector::default()),
+            sender,
+        });
+        self
+    }
 
-        let (cmd, data) = read_frame(&mut state.reader)?;
+    /// Throttle reads and writes to the given limits. With a cap on writes
+    /// in flight, writes beyond it are refused with TASK SET FULL while the
+    /// write cache drains to CAS in the background.
+    pub fn with_qos(mut self, limits: &QosLimits) -> Self {
+        self.limiter = (!limits.is_unlimited()).then(|| RateLimiter::new(limits));
+        let capped = self
+            .limiter
+            .as_ref()
+            .and_then(|l| l.max_write_in_flight())
+            .is_some();
+        self.drain = if capped { self.spawn_drain() } else { None };
+        self
+    }
 
-        match cmd {
-            CasCommand::Read => Ok(data),
-            _ => Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "invalid CAS read response",
-            )),
+    fn spawn_drain(&self) -> Option<Drain> {
+        let (sender, receiver) = mpsc::sync_channel::<()>(1);
+        let full = Arc::new(AtomicBool::new(false));
+        let state = Arc::clone(&self.state);
+        let drained = Arc::clone(&full);
+        let spawned = thread::Builder::new()
+            .name("iscsi-drain".to_string())
+            .spawn(move || {
+                for () in receiver {
+                    let flushed = Self::flush_cache(&mut state.lock().unwrap());
+                    if let Err(e) = flushed {
+                        log::error!("Draining the write cache failed: {}", e);
+                    }
+                    drained.store(false, Ordering::Release);
+                }
+            });
+        match spawned {
+            Ok(_) => Some(Drain { sender, full }),
+            Err(e) => {
+                log::warn!("Cannot start iscsi-drain, writes will wait: {}", e);
+                None
+            }
         }
     }
 
+    /// Blocks the write cache may hold before it is flushed
+    fn max_cached(&self) -> usize {
+        self.limiter
+            .as_ref()
+            .and_then(|limiter| limiter.max_write_in_flight())
+            .map_or(MAX_CACHED_BLOCKS, |bytes| {
+                (bytes / BLOCK_SIZE as u64).clamp(1, MAX_CACHED_BLOCKS as u64) as usize
+            })
+    }
 }
 
 impl ScsiBlockDevice for CasScsiDevice {
@@ -234,15 +503,22 @@ impl ScsiBlockDevice for CasScsiDevice {
         }
 
         let total_size = blocks as usize * BLOCK_SIZE as usize;
+        if let Some(limiter) = &self.limiter {
+            limiter.throttle_read(total_size);
+        }
+        let counters = &self.counters;
+        DeviceCounters::add(&counters.reads, 1);
+        DeviceCounters::add(&counters.blocks_read, blocks as u64);
         let mut buffer = Vec::with_capacity(total_size);
 
-        let mut state = self.state.lock().unwrap();
+        let state = self.state.lock().unwrap();
 
         for i in 0..blocks {
             let block_lba = lba + i as u64;
 
             // Check write cache first
             if let Some(cached_data) = state.write_cache.get(&block_lba) {
+                DeviceCounters::add(&counters.write_cache_hits, 1);
                 buffer.extend_from_slice(cached_data);
                 continue;
             }
@@ -253,10 +529,24 @@ impl ScsiBlockDevice for CasScsiDevice {
                 Ok(None) => state.index.zero_block_hash,
                 Err(e) => return Err(IscsiError::Io(e)),
             };
+            if hash == state.index.zero_block_hash {
+                buffer.resize(buffer.len() + BLOCK_SIZE as usize, 0);
+                continue;
+            }
+
+            if let Some(cache) = &self.read_cache {
+                if let Some(data) = cache.get(&hash) {
+                    DeviceCounters::add(&counters.read_cache_hits, 1);
+                    buffer.extend_from_slice(&data);
+                    continue;
+                }
+                DeviceCounters::add(&counters.read_cache_misses, 1);
+            }
 
             // Read from CAS
-            let data = Self::read_from_cas(&mut state, &hash)
-                .map_err(|e| IscsiError::Io(e))?;
+            DeviceCounters::add(&counters.cas_requests, 1);
+            let data = state.cas.read(&hash)
+                .map_err(IscsiError::Io)?;
 
             if data.len() != BLOCK_SIZE as usize {
                 return Err(IscsiError::Scsi(format!(
@@ -266,9 +556,27 @@ impl ScsiBlockDevice for CasScsiDevice {
                 )));
             }
 
+            if let Some(cache) = &self.read_cache {
+                cache.insert(hash, &data);
+            }
             buffer.extend_from_slice(&data);
         }
 
+        if let Some(readahead) = &self.readahead {
+            let end = lba + blocks as u64;
+            let window = readahead.blocks;
+            let ahead = readahead
+                .detector
+                .lock()
+                .unwrap()
+                .observe(lba, end, window, self.config.capacity_blocks);
+            for block_lba in ahead.into_iter().flatten() {
+                if !state.write_cache.contains_key(&block_lba) {
+                    request_prefetch(&readahead.sender, block_lba);
+                }
+            }
+        }
+
         Ok(buffer)
     }
 
@@ -277,26 +585,57 @@ impl ScsiBlockDevice for CasScsiDevice {
             return Err(IscsiError::Scsi(format!("unsupported block size: {}", block_size)));
         }
 
-        let blocks = (data.len() + BLOCK_SIZE as usize - 1) / BLOCK_SIZE as usize;
-        let mut state = self.state.lock().unwrap();
+        if let Some(limiter) = &self.limiter {
+            limiter.throttle_write(data.len());
+        }
+        DeviceCounters::add(&self.counters.writes, 1);
+        let blocks = data.len().div_ceil(BLOCK_SIZE as usize) as u64;
+        DeviceCounters::add(&self.counters.blocks_written, blocks);
+
+        // Don't queue behind a drain that writes are already refused for
+        let full = self
+            .drain
+            .as_ref()
+            .is_some_and(|drain| drain.full.load(Ordering::Acquire));
+        let mut state = match self.state.try_lock() {
+            Ok(state) => state,
+            Err(TryLockError::WouldBlock) if full => return Err(task_set_full(self.max_cached())),
+            Err(_) => self.state.lock().unwrap(),
+        };
 
-        // Store all blocks in write cache - return immediately without CAS I/O!
-        for i in 0..blocks {
-            let block_lba = lba + i as u64;
-            let offset = i * BLOCK_SIZE as usize;
-            let end = (offset + BLOCK_SIZE as usize).min(data.len());
+        // At the in-flight cap, refuse the write and drain the cache
+        let max_cached = self.max_cached();
+        let cached = state.write_cache.len();
+        if let Some(drain) = &self.drain {
+            if cached > 0 && cached + blocks as usize > max_cached {
+                drain.start();
+                return Err(task_set_full(cached));
+            }
+        }
 
-            // Prepare block data, padding if necessary
-            let mut block_data = vec![0u8; BLOCK_SIZE as usize];
-            block_data[..end - offset].copy_from_slice(&data[offset..end]);
+        // Durable before acknowledged unless WCE allows otherwise; the CAS
+        // write can wait
+        let write_back = state.write_back;
+        if let Some(journal) = state.journal.as_mut().filter(|_| !write_back) {
+            journal.append(lba, data).map_err(IscsiError::Io)?;
+        }
+
+        // Store all blocks in write cache - return immediately without CAS I/O!
+        Self::cache_blocks(&mut state, lba, data);
 
-            // Store in write cache
-            state.write_cache.insert(block_lba, block_data);
+        // Without WCE or a journal, only CAS makes the write stable
+        if !write_back && state.journal.is_none() {
+            drop(state);
+            return self.flush();
         }
 
-        // Auto-flush if cache exceeds threshold
+        // Auto-flush if cache exceeds threshold, or the in-flight write cap
         let cache_size = state.write_cache.len();
-        if cache_size >= MAX_CACHED_BLOCKS {
+        if cache_size >= max_cached {
+            if let Some(drain) = &self.drain {
+                let _ = drain.sender.try_send(());
+                return Ok(());
+            }
             log::info!("Cache has {} blocks, triggering auto-flush", cache_size);
             drop(state); // Release lock before calling flush()
             return self.flush();
@@ -324,17 +663,7 @@ impl ScsiBlockDevice for CasScsiDevice {
 
         log::info!("flush() called with {} cached blocks - actually flushing to CAS", cached_count);
 
-        // Flush all cached blocks to CAS
-        let cache = std::mem::take(&mut state.write_cache);
-        for (lba, block_data) in cache.iter() {
-            // Write block to CAS and get hash
-            let hash = Self::write_to_cas(&mut state, block_data)
-                .map_err(|e| IscsiError::Io(e))?;
-
-            // Update index with hash for this LBA (RocksDB writes immediately)
-            state.index.insert(*lba, &hash)
-                .map_err(|e| IscsiError::Io(e))?;
-        }
+        Self::flush_cache(&mut state).map_err(IscsiError::Io)?;
 
         log::info!("Flushed {} blocks to CAS and index", cached_count);
         Ok(())
@@ -365,21 +694,246 @@ impl Drop for CasScsiDevice {
 
         log::warn!("Device being dropped with {} cached blocks - flushing to CAS", cached_count);
 
-        // Flush all cached blocks to CAS
-        let cache = std::mem::take(&mut state.write_cache);
-        for (lba, block_data) in cache.iter() {
-            match CasScsiDevice::write_to_cas(&mut state, block_data) {
-                Ok(hash) => {
-                    if let Err(e) = state.index.insert(*lba, &hash) {
-                        log::error!("Failed to update index for block {}: {}", lba, e);
-                    }
-                }
-                Err(e) => {
-                    log::error!("Failed to flush block {} to CAS: {}", lba, e);
-                }
+        match Self::flush_cache(&mut state) {
+            Ok(_) => log::info!("Successfully flushed {} blocks to CAS and index on drop", cached_count),
+            Err(e) if state.journal.is_some() => {
+                log::error!("Failed to flush on drop ({}); writes will be replayed from the journal", e)
             }
+            Err(e) => log::error!("Failed to flush {} cached blocks on drop: {}", cached_count, e),
+        }
+    }
+}
+
+/// Journal file kept next to the index
+fn journal_path(index_path: &Path) -> PathBuf {
+    let mut path = index_path.as_os_str().to_owned();
+    path.push(".journal");
+    PathBuf::from(path)
+}
+
+/// A device's identity is kept next to its index, in `<index>.uuid`, so
+/// restoring a snapshot (which replaces the index) keeps it
+pub fn identity_path(index_path: &Path) -> PathBuf {
+    let mut path = index_path.as_os_str().to_owned();
+    path.push(".uuid");
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{CasServer, CasServerConfig};
+    use std::net::TcpListener;
+    use tempfile::TempDir;
+
+    fn start_cas_server(temp: &TempDir) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: addr.clone(),
+            storage_path: temp.path().join("cas").to_string_lossy().into_owned(),
+        })
+        .unwrap();
+        std::thread::spawn(move || server.serve(listener));
+        addr
+    }
+
+    #[test]
+    fn test_journal_replays_unflushed_writes() {
+        let temp = TempDir::new().unwrap();
+        let config = CasScsiDeviceConfig {
+            cas_server_addr: start_cas_server(&temp),
+            capacity_blocks: 256,
+            index_path: temp.path().join("index"),
+            ..CasScsiDeviceConfig::default()
+        };
+
+        {
+            let mut device = CasScsiDevice::new(config.clone()).unwrap();
+            device.write(3, &[0x5A; 8192], BLOCK_SIZE).unwrap();
+
+            // Crash: the acknowledged writes never reach CAS
+            device.state.lock().unwrap().write_cache.clear();
         }
 
-        log::info!("Successfully flushed {} blocks to CAS and index on drop", cached_count);
+        let device = CasScsiDevice::new(config.clone()).unwrap();
+        assert!(device.state.lock().unwrap().write_cache.is_empty());
+        assert_eq!(device.read(3, 2, BLOCK_SIZE).unwrap(), vec![0x5A; 8192]);
+        assert_eq!(device.read(5, 1, BLOCK_SIZE).unwrap(), vec![0; 4096]);
+        assert!(!device.write_cache_enabled());
+        drop(device);
+
+        // Unjournaled writes are only in RAM until flushed
+        let unjournaled = CasScsiDevice::new(CasScsiDeviceConfig {
+            journal: false,
+            ..config
+        })
+        .unwrap();
+        assert!(unjournaled.write_cache_enabled());
+    }
+
+    #[test]
+    fn test_mode_select_sets_write_cache() {
+        let temp = TempDir::new().unwrap();
+        let config = CasScsiDeviceConfig {
+            cas_server_addr: start_cas_server(&temp),
+            capacity_blocks: 256,
+            index_path: temp.path().join("index"),
+            journal: false,
+            ..CasScsiDeviceConfig::default()
+        };
+        let mut device = CasScsiDevice::new(config).unwrap();
+        use scsi::{mode_pages, opcodes};
+        let sense = [opcodes::MODE_SENSE_6, 0, mode_pages::CACHING, 0, 255, 0];
+        assert_eq!(device.mode_sense(&sense).unwrap()[6], 0x04);
+
+        // WCE off: each write reaches CAS before it is acknowledged
+        let mut caching = vec![0u8; 4];
+        caching.extend(&device.mode_sense(&sense).unwrap()[4..]);
+        caching[6] = 0x00;
+        let length = caching.len() as u8;
+        let select = [opcodes::MODE_SELECT_6, 0x10, 0, 0, length, 0];
+        device.mode_select(&select, &caching).unwrap();
+        assert!(!device.write_cache_enabled());
+        assert_eq!(device.mode_sense(&sense).unwrap()[6], 0x00);
+        device.write(0, &[0x5A; 4096], BLOCK_SIZE).unwrap();
+        assert!(device.state.lock().unwrap().write_cache.is_empty());
+
+        // WCE back on: writes wait in the cache for a flush
+        caching[6] = 0x04;
+        device.mode_select(&select, &caching).unwrap();
+        device.write(1, &[0x5B; 4096], BLOCK_SIZE).unwrap();
+        assert_eq!(device.state.lock().unwrap().write_cache.len(), 1);
+    }
+
+    #[test]
+    fn test_task_set_full_at_write_cap() {
+        let temp = TempDir::new().unwrap();
+        let config = CasScsiDeviceConfig {
+            cas_server_addr: start_cas_server(&temp),
+            capacity_blocks: 512,
+            index_path: temp.path().join("index"),
+            ..CasScsiDeviceConfig::default()
+        };
+        let limits = QosLimits {
+            max_inflight_write_mb: Some(1),
+            ..QosLimits::default()
+        };
+        let mut device = CasScsiDevice::new(config).unwrap().with_qos(&limits);
+
+        // 1 MiB holds 256 blocks; a write past that is refused
+        device.write(0, &vec![1; 255 * 4096], BLOCK_SIZE).unwrap();
+        let refused = device.write(255, &[2; 8192], BLOCK_SIZE).unwrap_err();
+        assert!(matches!(refused, IscsiError::Scsi(ref m) if m.starts_with("TASK SET FULL")));
+
+        // Accepted again once the cache has drained to CAS
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !device.state.lock().unwrap().write_cache.is_empty() {
+            assert!(std::time::Instant::now() < deadline, "cache never drained");
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        device.write(255, &[2; 8192], BLOCK_SIZE).unwrap();
+        assert_eq!(device.read(0, 1, BLOCK_SIZE).unwrap(), vec![1; 4096]);
+    }
+
+    #[test]
+    fn test_identity_kept_beside_index() {
+        let temp = TempDir::new().unwrap();
+        let config = CasScsiDeviceConfig {
+            cas_server_addr: start_cas_server(&temp),
+            capacity_blocks: 256,
+            index_path: temp.path().join("index"),
+            ..CasScsiDeviceConfig::default()
+        };
+        let uuid = CasScsiDevice::new(config.clone()).unwrap().uuid;
+        let kept = TargetUuid::load(&identity_path(&config.index_path)).unwrap();
+        assert_eq!(kept, Some(uuid));
+
+        // Restoring a snapshot replaces the index, not the identity
+        std::fs::remove_dir_all(&config.index_path).unwrap();
+        let reopened = CasScsiDevice::new(config.clone()).unwrap();
+        assert_eq!(reopened.flush_handle().uuid(), uuid);
+        drop(reopened);
+
+        // A configured identity must match the one kept
+        let other = CasScsiDeviceConfig {
+            uuid: Some(TargetUuid::generate()),
+            ..config.clone()
+        };
+        assert!(CasScsiDevice::new(other).is_err());
+        let same = CasScsiDeviceConfig {
+            uuid: Some(uuid),
+            ..config
+        };
+        assert_eq!(CasScsiDevice::new(same).unwrap().uuid, uuid);
+    }
+
+    #[test]
+    fn test_device_stats() {
+        let temp = TempDir::new().unwrap();
+        let config = CasScsiDeviceConfig {
+            cas_server_addr: start_cas_server(&temp),
+            capacity_blocks: 256,
+            index_path: temp.path().join("index"),
+            ..CasScsiDeviceConfig::default()
+        };
+        let mut device = CasScsiDevice::new(config).unwrap();
+        let handle = device.flush_handle();
+
+        // Two blocks, one of them zeros, read back from the write cache
+        let mut data = vec![0x11; 4096];
+        data.resize(8192, 0);
+        device.write(0, &data, BLOCK_SIZE).unwrap();
+        device.read(0, 2, BLOCK_SIZE).unwrap();
+        device.flush().unwrap();
+
+        // Then from CAS, then from the read cache; the zero block from neither
+        device.read(0, 2, BLOCK_SIZE).unwrap();
+        device.read(0, 1, BLOCK_SIZE).unwrap();
+
+        let stats = handle.stats();
+        assert_eq!((stats.writes, stats.blocks_written), (1, 2));
+        assert_eq!((stats.reads, stats.blocks_read), (3, 5));
+        assert_eq!(stats.write_cache_hits, 2);
+        assert_eq!((stats.read_cache_hits, stats.read_cache_misses), (1, 1));
+        assert_eq!(stats.read_cache_hit_rate(), Some(0.5));
+        assert_eq!((stats.flushes, stats.blocks_flushed), (1, 2));
+        // One write of the non-zero block, one read of it
+        assert_eq!(stats.cas_requests, 2);
+        assert_eq!(device.stats(), stats);
+    }
+
+    #[test]
+    fn test_sequential_readahead() {
+        let temp = TempDir::new().unwrap();
+        let config = CasScsiDeviceConfig {
+            cas_server_addr: start_cas_server(&temp),
+            capacity_blocks: 256,
+            index_path: temp.path().join("index"),
+            read_cache_mb: 0,
+            ..CasScsiDeviceConfig::default()
+        };
+        let cache = Arc::new(BlockCache::new(4));
+        let mut device = CasScsiDevice::new(config)
+            .unwrap()
+            .with_read_cache(Arc::clone(&cache))
+            .with_readahead(4);
+        for lba in 0..16u64 {
+            device.write(lba, &[lba as u8 + 1; 4096], BLOCK_SIZE).unwrap();
+        }
+        device.flush().unwrap();
+        let hash = |lba| device.state.lock().unwrap().index.get(lba).unwrap().unwrap();
+
+        // The second read in a row fetches the next four blocks
+        device.read(0, 1, BLOCK_SIZE).unwrap();
+        device.read(1, 1, BLOCK_SIZE).unwrap();
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !cache.contains(&hash(5)) {
+            assert!(std::time::Instant::now() < deadline, "readahead never arrived");
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(cache.contains(&hash(2)));
+        assert!(!cache.contains(&hash(6)));
+        assert_eq!(device.read(2, 4, BLOCK_SIZE).unwrap()[3 * 4096..], [6u8; 4096]);
     }
 }
@@ -1,17 +1,41 @@
 //! Target cloning operations
 //!
-//! Handles creation, cloning, and deletion of iSCSI targets using sled database
-//! export/import for safe, version-agnostic cloning.
+//! Handles creation, cloning, and deletion of iSCSI targets. Clones share
+//! their source's index as a copy-on-write layer (see `index`) instead of
+//! copying it. Each operation is recorded in the registry's audit log.
 
 use anyhow::{Context, Result};
+use serde::Serialize;
+use serde_json::{json, Value};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-
-use super::registry::{TargetMetadata, TargetRegistry};
-
-/// Lock file name to prevent cloning running targets
-const LOCK_FILE_NAME: &str = ".serving.lock";
+use std::sync::Arc;
+
+use super::audit::{self, AuditLog, AuditOperation};
+use super::cas_device::identity_path;
+use super::hashlist::{HashList, HashSorter};
+use super::index::{self, LbaIndex};
+use super::live::{self, LOCK_FILE_NAME};
+use super::registry::{TargetMetadata, TargetRegistry, TargetSnapshot};
+use crate::cas::{CasPool, CasPoolConfig, DeleteOutcome};
+use crate::tls::TlsClient;
+
+/// Directory under the targets base holding frozen index layers
+const LAYERS_DIR: &str = ".layers";
+
+/// Directory under the targets base for GC's sorted hash lists
+const GC_DIR: &str = ".gc";
+
+/// Outcome of deleting a target's unique blocks from CAS
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GcReport {
+    pub deleted: usize,
+    pub not_found: usize,
+    /// Written too recently for the CAS server to delete
+    pub kept: usize,
+    pub errors: usize,
+}
 
 /// Clone manager for target operations
 pub struct CloneManager {
@@ -23,6 +47,15 @@ pub struct CloneManager {
 
     /// CAS server address
     pub cas_server: String,
+
+    /// Mutual TLS to the CAS server
+    pub cas_tls: Option<Arc<TlsClient>>,
+
+    /// Log of the operations made on the registry's targets
+    pub audit_log: AuditLog,
+
+    /// Who the audit log says made the operations
+    pub actor: String,
 }
 
 impl CloneManager {
@@ -35,14 +68,60 @@ impl CloneManager {
             .with_context(|| format!("Failed to create targets directory: {:?}", targets_base_dir))?;
 
         Ok(Self {
+            audit_log: AuditLog::for_registry(&registry_path),
+            actor: audit::local_actor(),
             registry,
             targets_base_dir,
             cas_server,
+            cas_tls: None,
         })
     }
 
+    /// Record operations in the audit log as made by `actor` rather than
+    /// the local user
+    pub fn with_actor(mut self, actor: impl Into<String>) -> Self {
+        self.actor = actor.into();
+        self
+    }
+
+    /// Reach the CAS server over mutual TLS
+    pub fn with_cas_tls(mut self, tls: Arc<TlsClient>) -> Self {
+        self.cas_tls = Some(tls);
+        self
+    }
+
+    /// Connect to the CAS server
+    pub(super) fn connect_cas(&self) -> Result<CasPool> {
+        let config = CasPoolConfig {
+            tls: self.cas_tls.clone(),
+            ..CasPoolConfig::default()
+        };
+        CasPool::connect(&self.cas_server, config)
+            .with_context(|| format!("Failed to connect to CAS server: {}", self.cas_server))
+    }
+
+    /// Record a finished operation in the audit log. `CloneManager`'s own
+    /// operations record themselves; this is for ones built on them (GC).
+    pub fn audit<T>(
+        &self,
+        operation: AuditOperation,
+        target: &str,
+        params: Value,
+        result: &Result<T>,
+    ) {
+        self.audit_log.record(&self.actor, operation, target, params, result);
+    }
+
     /// Create a new target
     pub fn create_target(&mut self, name: &str, size_mb: u64, description: Option<String>) -> Result<String> {
+        let params = json!({"name": name, "size_mb": size_mb, "description": description});
+        let result = self.try_create_target(name, size_mb, description);
+        let iqn = TargetRegistry::generate_iqn(name);
+        self.audit(AuditOperation::Create, &iqn, params, &result);
+        result
+    }
+
+    fn try_create_target(&mut self, name: &str, size_mb: u64, description: Option<String>) -> Result<String> {
         log::info!("Creating target: {} ({} MB)", name, size_mb);
 
         // Generate IQN
@@ -68,6 +147,7 @@ impl CloneManager {
             children: vec![],
             created_at: TargetRegistry::now(),
             description,
+            snapshots: vec![],
         };
 
         // Add to registry
@@ -77,8 +157,16 @@ impl CloneManager {
         Ok(iqn)
     }
 
-    /// Clone a target (source must not be running)
+    /// Clone a target. A running source is snapshotted by its server;
+    /// otherwise its index is frozen as a layer directly.
     pub fn clone_target(&mut self, source_iqn: &str, dest_name: &str) -> Result<String> {
+        let result = self.try_clone_target(source_iqn, dest_name);
+        let params = json!({"dest_name": dest_name, "dest_iqn": result.as_ref().ok()});
+        self.audit(AuditOperation::Clone, source_iqn, params, &result);
+        result
+    }
+
+    fn try_clone_target(&mut self, source_iqn: &str, dest_name: &str) -> Result<String> {
         log::info!("Cloning target: {} -> {}", source_iqn, dest_name);
 
         // Check source exists
@@ -86,11 +174,6 @@ impl CloneManager {
             .ok_or_else(|| anyhow::anyhow!("Source target not found: {}", source_iqn))?
             .clone();
 
-        // Check source is not running
-        if self.is_target_running(source_iqn)? {
-            anyhow::bail!("Source target is currently running: {}", source_iqn);
-        }
-
         // Generate destination IQN
         let dest_iqn = TargetRegistry::generate_iqn(dest_name);
 
@@ -104,9 +187,20 @@ impl CloneManager {
         fs::create_dir_all(&dest_index_path)
             .with_context(|| format!("Failed to create destination index directory: {:?}", dest_index_path))?;
 
-        // Clone the sled database using export/import
-        log::info!("Exporting source database: {:?}", source.index_path);
-        self.clone_sled_database(&source.index_path, &dest_index_path)?;
+        let layer_path = self.get_layer_path(source_iqn);
+        if self.is_target_running(source_iqn)? {
+            // The server copies the live index under the device lock
+            log::info!("Source is running, requesting snapshot {:?}", layer_path);
+            live::request_snapshot(&source.index_path, &layer_path, live::SNAPSHOT_TIMEOUT)
+                .with_context(|| format!("Failed to snapshot running target {}", source_iqn))?;
+            index::attach(&dest_index_path, &layer_path)
+                .with_context(|| format!("Failed to attach clone to layer {:?}", layer_path))?;
+        } else {
+            // Freeze the source index as a shared layer under both targets
+            log::info!("Freezing source index {:?} as layer {:?}", source.index_path, layer_path);
+            index::fork(&source.index_path, &layer_path, &[&dest_index_path])
+                .with_context(|| format!("Failed to fork index {:?}", source.index_path))?;
+        }
 
         // Create destination metadata
         let dest_metadata = TargetMetadata {
@@ -118,6 +212,7 @@ impl CloneManager {
             children: vec![],
             created_at: TargetRegistry::now(),
             description: Some(format!("Clone of {}", source.name)),
+            snapshots: vec![],
         };
 
         // Add to registry (this also updates parent's children list)
@@ -127,40 +222,173 @@ impl CloneManager {
         Ok(dest_iqn)
     }
 
-    /// Clone a sled database by copying all key-value pairs
-    fn clone_sled_database(&self, source_path: &Path, dest_path: &Path) -> Result<()> {
-        // Open source database
-        let source_db = sled::open(source_path)
-            .with_context(|| format!("Failed to open source database: {:?}", source_path))?;
+    /// Copy the mappings a target inherits from its layers into its own
+    /// index, so it no longer depends on them
+    pub fn flatten_target(&self, iqn: &str) -> Result<usize> {
+        let result = self.try_flatten_target(iqn);
+        let params = json!({"copied": result.as_ref().ok()});
+        self.audit(AuditOperation::Flatten, iqn, params, &result);
+        result
+    }
+
+    fn try_flatten_target(&self, iqn: &str) -> Result<usize> {
+        let metadata = self.registry.get_target(iqn)
+            .ok_or_else(|| anyhow::anyhow!("Target not found: {}", iqn))?;
+
+        if self.is_target_running(iqn)? {
+            anyhow::bail!("Target is currently running: {}", iqn);
+        }
+
+        let copied = index::flatten(&metadata.index_path)
+            .with_context(|| format!("Failed to flatten index {:?}", metadata.index_path))?;
 
-        log::debug!("Source database opened, {} entries", source_db.len());
+        log::info!("Flattened target {}: copied {} entries from layers", iqn, copied);
+        self.prune_layers_logged();
+        Ok(copied)
+    }
 
-        // Create destination database
-        let dest_db = sled::open(dest_path)
-            .with_context(|| format!("Failed to create destination database: {:?}", dest_path))?;
+    /// Freeze the target's current contents as a snapshot it can later
+    /// be restored to. A running target is snapshotted by its server.
+    pub fn snapshot_target(&mut self, iqn: &str, description: Option<String>) -> Result<TargetSnapshot> {
+        let params = json!({"description": description});
+        let result = self.try_snapshot_target(iqn, description);
+        let params = match &result {
+            Ok(snapshot) => json!({"id": snapshot.id, "description": snapshot.description}),
+            Err(_) => params,
+        };
+        self.audit(AuditOperation::Snapshot, iqn, params, &result);
+        result
+    }
 
-        // Copy all key-value pairs
-        let mut count = 0;
-        for result in source_db.iter() {
-            let (key, value) = result.context("Failed to read entry from source database")?;
-            dest_db.insert(&key, &value)
-                .context("Failed to write entry to destination database")?;
-            count += 1;
+    fn try_snapshot_target(&mut self, iqn: &str, description: Option<String>) -> Result<TargetSnapshot> {
+        let metadata = self.registry.get_target(iqn)
+            .ok_or_else(|| anyhow::anyhow!("Target not found: {}", iqn))?
+            .clone();
+
+        let layer_path = self.get_layer_path(iqn);
+        if self.is_target_running(iqn)? {
+            live::request_snapshot(&metadata.index_path, &layer_path, live::SNAPSHOT_TIMEOUT)
+                .with_context(|| format!("Failed to snapshot running target {}", iqn))?;
+        } else {
+            if LbaIndex::open(&metadata.index_path)?.is_none() {
+                anyhow::bail!("Target has no data to snapshot yet: {}", iqn);
+            }
+            index::fork(&metadata.index_path, &layer_path, &[])
+                .with_context(|| format!("Failed to fork index {:?}", metadata.index_path))?;
         }
 
-        // Flush destination database
-        dest_db.flush()
-            .context("Failed to flush destination database")?;
+        let snapshot = TargetSnapshot {
+            id: layer_path.file_name().unwrap().to_string_lossy().into_owned(),
+            layer_path: layer_path.canonicalize()?,
+            created_at: TargetRegistry::now(),
+            description,
+        };
+        self.registry.get_target_mut(iqn).unwrap().snapshots.push(snapshot.clone());
+        self.registry.save()?;
+
+        log::info!("Snapshotted target {} as {}", iqn, snapshot.id);
+        Ok(snapshot)
+    }
+
+    /// Discard the target's changes since a snapshot and continue from it
+    pub fn restore_snapshot(&self, iqn: &str, snapshot_id: &str) -> Result<()> {
+        let result = self.try_restore_snapshot(iqn, snapshot_id);
+        self.audit(AuditOperation::Restore, iqn, json!({"snapshot": snapshot_id}), &result);
+        result
+    }
 
-        drop(source_db);
-        drop(dest_db);
+    fn try_restore_snapshot(&self, iqn: &str, snapshot_id: &str) -> Result<()> {
+        let metadata = self.registry.get_target(iqn)
+            .ok_or_else(|| anyhow::anyhow!("Target not found: {}", iqn))?;
+        let snapshot = metadata.snapshots.iter()
+            .find(|s| s.id == snapshot_id)
+            .ok_or_else(|| anyhow::anyhow!("Snapshot not found: {}", snapshot_id))?;
 
-        log::info!("Successfully cloned database: {} entries from {:?} to {:?}", count, source_path, dest_path);
+        if self.is_target_running(iqn)? {
+            anyhow::bail!("Target is currently running: {}", iqn);
+        }
+
+        let index_path = &metadata.index_path;
+        log::info!("Restoring target {} to snapshot {}", iqn, snapshot_id);
+        fs::remove_dir_all(index_path)
+            .with_context(|| format!("Failed to remove index {:?}", index_path))?;
+        // Writes journaled after the snapshot must not be replayed
+        let mut journal = index_path.as_os_str().to_owned();
+        journal.push(".journal");
+        if let Err(e) = fs::remove_file(&journal) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                return Err(e).context("Failed to remove write journal");
+            }
+        }
+
+        index::attach(index_path, &snapshot.layer_path)
+            .with_context(|| format!("Failed to attach index to {:?}", snapshot.layer_path))?;
         Ok(())
     }
 
+    /// Hashes only this target uses, which `delete_blocks` can reclaim.
+    /// Blocks reachable from another target or from any snapshot are kept.
+    pub fn gc_candidates(&self, iqn: &str) -> Result<HashList> {
+        let metadata = self.registry.get_target(iqn)
+            .ok_or_else(|| anyhow::anyhow!("Target not found: {}", iqn))?;
+
+        if self.is_target_running(iqn)? {
+            anyhow::bail!("Target is currently running: {}. Stop it first.", iqn);
+        }
+
+        let work_dir = self.gc_work_dir();
+        let mut target_hashes = HashSorter::new(&work_dir);
+        sort_index_hashes(&metadata.index_path, &mut target_hashes)?;
+        let target_hashes = target_hashes.finish().context("Failed to sort target hashes")?;
+        log::info!("Target {} references {} unique hashes", iqn, target_hashes.len());
+
+        let mut keep = HashSorter::new(&work_dir);
+        for other in self.registry.targets.values() {
+            if other.iqn != iqn {
+                sort_index_hashes(&other.index_path, &mut keep)?;
+            }
+            for snapshot in &other.snapshots {
+                sort_index_hashes(&snapshot.layer_path, &mut keep)?;
+            }
+        }
+        let keep = keep.finish().context("Failed to sort hashes to keep")?;
+
+        target_hashes.difference(&keep, &work_dir)
+            .context("Failed to compare hash lists")
+    }
+
+    /// Delete blocks from CAS, calling `progress` with the number done
+    pub fn delete_blocks<F>(&self, hashes: &HashList, mut progress: F) -> Result<GcReport>
+    where
+        F: FnMut(usize),
+    {
+        let cas = self.connect_cas()?;
+
+        let mut report = GcReport::default();
+        for (i, hash) in hashes.iter()?.enumerate() {
+            let hash = hash.context("Failed to read hash list")?;
+            match cas.delete(&hash) {
+                Ok(DeleteOutcome::Deleted) => report.deleted += 1,
+                Ok(DeleteOutcome::NotFound) => report.not_found += 1,
+                Ok(DeleteOutcome::Kept) => report.kept += 1,
+                Err(e) => {
+                    log::warn!("Failed to delete block {}: {}", hex::encode(hash), e);
+                    report.errors += 1;
+                }
+            }
+            progress(i + 1);
+        }
+        Ok(report)
+    }
+
     /// Delete a target
     pub fn delete_target(&mut self, iqn: &str, remove_data: bool) -> Result<()> {
+        let result = self.try_delete_target(iqn, remove_data);
+        self.audit(AuditOperation::Delete, iqn, json!({"remove_data": remove_data}), &result);
+        result
+    }
+
+    fn try_delete_target(&mut self, iqn: &str, remove_data: bool) -> Result<()> {
         log::info!("Deleting target: {} (remove_data={})", iqn, remove_data);
 
         // Check target is not running
@@ -189,12 +417,66 @@ impl CloneManager {
                 fs::remove_dir_all(&metadata.index_path)
                     .with_context(|| format!("Failed to remove target directory: {:?}", metadata.index_path))?;
             }
+            // A target created later in its place is a different disk
+            if let Err(e) = fs::remove_file(identity_path(&metadata.index_path)) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    return Err(e).context("Failed to remove target identity");
+                }
+            }
+            self.prune_layers_logged();
         }
 
         log::info!("Deleted target: {}", iqn);
         Ok(())
     }
 
+    /// Remove the layers no target or snapshot reads through any more,
+    /// returning how many were removed.
+    ///
+    /// A target's layer chain is read from its index, which a running
+    /// target's server holds locked; if any chain can't be read, every
+    /// layer is kept.
+    pub fn prune_layers(&self) -> Result<usize> {
+        let layers_dir = self.targets_base_dir.join(LAYERS_DIR);
+        if !layers_dir.is_dir() {
+            return Ok(0);
+        }
+
+        let mut used = std::collections::HashSet::new();
+        for target in self.registry.targets.values() {
+            let chain = index::layer_chain(&target.index_path)
+                .with_context(|| format!("Failed to read layers of {}", target.iqn))?;
+            used.extend(chain);
+            for layer_path in target.snapshots.iter().map(|s| &s.layer_path) {
+                let chain = index::layer_chain(layer_path)
+                    .with_context(|| format!("Failed to read layers of {:?}", layer_path))?;
+                used.extend(chain);
+                used.insert(layer_path.clone());
+            }
+        }
+
+        let mut removed = 0;
+        for entry in fs::read_dir(&layers_dir)? {
+            let path = entry?.path().canonicalize()?;
+            if used.contains(&path) {
+                continue;
+            }
+            log::info!("Removing unused index layer {:?}", path);
+            fs::remove_dir_all(&path)
+                .with_context(|| format!("Failed to remove layer {:?}", path))?;
+            removed += 1;
+        }
+        Ok(removed)
+    }
+
+    /// `prune_layers` after an operation that already succeeded, which a
+    /// failure to prune shouldn't turn into an error
+    fn prune_layers_logged(&self) {
+        if let Err(e) = self.prune_layers() {
+            log::warn!("Keeping index layers: {:#}", e);
+        }
+    }
+
     /// Check if a target is currently running (has a lock file with valid PID)
     pub fn is_target_running(&self, iqn: &str) -> Result<bool> {
         let metadata = self.registry.get_target(iqn)
@@ -231,13 +513,41 @@ impl CloneManager {
     /// Get the index path for a target
     fn get_index_path(&self, iqn: &str) -> PathBuf {
         // Extract the target name from IQN (last component after :)
-        let name = iqn.split(':').last().unwrap_or(iqn);
+        let name = iqn.split(':').next_back().unwrap_or(iqn);
         self.targets_base_dir.join(name).join("index")
     }
+
+    /// Path for a new frozen layer of a target's index
+    pub(super) fn get_layer_path(&self, iqn: &str) -> PathBuf {
+        let name = iqn.split(':').next_back().unwrap_or(iqn);
+        let base = format!("{}-{}", name, TargetRegistry::now());
+        let layers = self.targets_base_dir.join(LAYERS_DIR);
+        (0..)
+            .map(|n| if n == 0 { layers.join(&base) } else { layers.join(format!("{}.{}", base, n)) })
+            .find(|path| !path.exists())
+            .unwrap()
+    }
+
+    /// Scratch directory for GC's hash lists, on the same disk as the
+    /// indexes rather than in a RAM-backed /tmp
+    pub(super) fn gc_work_dir(&self) -> PathBuf {
+        self.targets_base_dir.join(GC_DIR)
+    }
+}
+
+/// Add every hash an index can see, including its layers, to `sorter`
+pub(super) fn sort_index_hashes(index_path: &Path, sorter: &mut HashSorter) -> Result<()> {
+    let Some(index) = LbaIndex::open(index_path)
+        .with_context(|| format!("Failed to open index {:?}", index_path))?
+    else {
+        return Ok(());
+    };
+    index.for_each_hash(|hash| sorter.push(hash))
+        .with_context(|| format!("Failed to read hashes from {:?}", index_path))
 }
 
 /// Check if a process with the given PID is running
-fn is_process_running(pid: u32) -> bool {
+pub(super) fn is_process_running(pid: u32) -> bool {
     #[cfg(unix)]
     {
         // Send signal 0 to check if process exists
@@ -294,6 +604,113 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_operations_are_audited() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut manager = CloneManager::new(
+            temp_dir.path().join("registry.json"),
+            temp_dir.path().join("targets"),
+            "127.0.0.1:3000".to_string(),
+        )?
+        .with_actor("tester");
+
+        let base = manager.create_target("base", 100, None)?;
+        let clone = manager.clone_target(&base, "clone")?;
+        assert!(manager.create_target("base", 100, None).is_err());
+        manager.delete_target(&clone, false)?;
+
+        let entries = manager.audit_log.entries()?;
+        let ops: Vec<_> = entries.iter().map(|e| (e.operation, e.success)).collect();
+        assert_eq!(
+            ops,
+            vec![
+                (AuditOperation::Create, true),
+                (AuditOperation::Clone, true),
+                (AuditOperation::Create, false),
+                (AuditOperation::Delete, true),
+            ]
+        );
+        assert!(entries.iter().all(|e| e.actor == "tester"));
+        assert_eq!(entries[1].params["dest_iqn"], clone.as_str());
+        assert!(entries[2].error.as_deref().unwrap().contains("already exists"));
+
+        // The deleted clone's history still shows where it came from
+        let history = manager.audit_log.history(&clone)?;
+        assert_eq!(history.len(), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_restore_and_gc_candidates() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut manager = CloneManager::new(
+            temp_dir.path().join("registry.json"),
+            temp_dir.path().join("targets"),
+            "127.0.0.1:3000".to_string(),
+        )?;
+        let iqn = manager.create_target("snap", 100, None)?;
+        let index_path = manager.registry.get_target(&iqn).unwrap().index_path.clone();
+
+        {
+            let index = LbaIndex::open_or_create(&index_path, || Ok([0; 16]))?;
+            index.insert(1, &[1; 16])?;
+        }
+        let snapshot = manager.snapshot_target(&iqn, Some("before".to_string()))?;
+        {
+            let index = LbaIndex::open(&index_path)?.unwrap();
+            index.insert(1, &[2; 16])?;
+            index.insert(2, &[3; 16])?;
+            index.flush()?;
+        }
+
+        // Blocks the snapshot still needs are not collected
+        let candidates = manager.gc_candidates(&iqn)?;
+        let candidates: Vec<_> = candidates.iter()?.collect::<std::io::Result<_>>()?;
+        assert_eq!(candidates, vec![[2; 16], [3; 16]]);
+
+        let reloaded = CloneManager::new(
+            manager.registry.registry_path.clone(),
+            manager.targets_base_dir.clone(),
+            manager.cas_server.clone(),
+        )?;
+        assert_eq!(reloaded.registry.get_target(&iqn).unwrap().snapshots.len(), 1);
+        reloaded.restore_snapshot(&iqn, &snapshot.id)?;
+
+        let index = LbaIndex::open(&index_path)?.unwrap();
+        assert_eq!(index.get(1)?, Some([1; 16]));
+        assert_eq!(index.get(2)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unused_layers_are_removed() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut manager = CloneManager::new(
+            temp_dir.path().join("registry.json"),
+            temp_dir.path().join("targets"),
+            "127.0.0.1:3000".to_string(),
+        )?;
+        let base = manager.create_target("base", 100, None)?;
+        let index_path = manager.registry.get_target(&base).unwrap().index_path.clone();
+        {
+            let index = LbaIndex::open_or_create(&index_path, || Ok([0; 16]))?;
+            index.insert(1, &[1; 16])?;
+            index.flush()?;
+        }
+        let clone = manager.clone_target(&base, "clone")?;
+        let layers_dir = manager.targets_base_dir.join(LAYERS_DIR);
+        let layer_count = || fs::read_dir(&layers_dir).map(|dir| dir.count());
+        assert_eq!(layer_count()?, 1);
+
+        // The base still reads through the layer
+        manager.delete_target(&clone, true)?;
+        assert_eq!(layer_count()?, 1);
+
+        manager.delete_target(&base, true)?;
+        assert_eq!(layer_count()?, 0);
+        Ok(())
+    }
+
     #[test]
     fn test_process_detection() {
         // Current process should be running
@@ -0,0 +1,534 @@
+//! iSCSI discovery sessions
+//!
+//! An initiator that doesn't know our IQNs logs in with
+//! `SessionType=Discovery` and no `TargetName`, then sends a Text request
+//! carrying `SendTargets=All`. The answer is a `TargetName` key for each
+//! target followed by the `TargetAddress` portals it is reachable on
+//! (RFC 3720 appendix D), which is what `iscsiadm -m discovery` lists.
+//!
+//! `spawn` serves discovery sessions on a portal of their own: it takes
+//! the login (no authentication), answers SendTargets with the target
+//! portal, and logs out. Normal sessions are refused there; initiators go
+//! on to log in to the portal they were given.
+
+use super::pdu::{Digests, Opcode, Pdu};
+use crate::shutdown;
+use std::collections::HashMap;
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Portal group tag for every portal; all targets share one group
+pub const PORTAL_GROUP_TAG: u16 = 1;
+
+/// Longest data segment we accept, declared at login
+const MAX_RECV_DATA_SEGMENT: usize = 8192;
+
+/// What the initiator may send before declaring its own limit
+const DEFAULT_DATA_SEGMENT: usize = 8192;
+
+/// Commands the initiator may have outstanding
+const CMD_WINDOW: u32 = 8;
+
+/// Target transfer tag asking for the rest of a long SendTargets answer
+const CONTINUE_TAG: u32 = 1;
+
+/// Unanswered tags in a PDU header
+const NO_TAG: u32 = 0xffff_ffff;
+
+/// An idle discovery session is dropped after this long
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Keys RFC 3720 makes irrelevant in discovery sessions
+const IRRELEVANT: &[&str] = &[
+    "InitialR2T",
+    "ImmediateData",
+    "MaxBurstLength",
+    "FirstBurstLength",
+    "MaxOutstandingR2T",
+    "DataPDUInOrder",
+    "DataSequenceInOrder",
+];
+
+/// Login status: success
+const LOGIN_SUCCESS: (u8, u8) = (0, 0);
+/// Login status: the initiator can't authenticate the way we need
+const LOGIN_AUTH_FAILED: (u8, u8) = (2, 1);
+/// Login status: this portal only takes discovery sessions
+const LOGIN_SESSION_TYPE_UNSUPPORTED: (u8, u8) = (2, 9);
+
+/// True if login parameters start a discovery session
+pub fn is_discovery_login(params: &HashMap<String, String>) -> bool {
+    params.get("SessionType").map(String::as_str) == Some("Discovery")
+}
+
+/// `TargetAddress` value for a portal: `ip:port,tag`, IPv6 in brackets
+pub fn portal_address(addr: SocketAddr) -> String {
+    format!("{},{}", addr, PORTAL_GROUP_TAG)
+}
+
+/// Answer a `SendTargets` request. "All" lists every target, an IQN
+/// lists just that one and anything else lists nothing. Targets are
+/// sorted so initiators see a stable order.
+pub fn send_targets<'a>(
+    request: &str,
+    targets: impl IntoIterator<Item = &'a str>,
+    portals: &[SocketAddr],
+) -> Vec<(String, String)> {
+    let mut names: Vec<&str> = targets
+        .into_iter()
+        .filter(|name| request == "All" || request == *name)
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+
+    let mut response = Vec::new();
+    for name in names {
+        response.push(("TargetName".to_string(), name.to_string()));
+        for portal in portals {
+            response.push(("TargetAddress".to_string(), portal_address(*portal)));
+        }
+    }
+    response
+}
+
+/// Serve discovery sessions on `bind` until shutdown is requested,
+/// answering SendTargets with `targets` on port `portal_port` of whichever
+/// local address the initiator reached us on
+pub fn spawn(
+    bind: &str,
+    targets: Vec<String>,
+    portal_port: u16,
+) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = TcpListener::bind(bind)?;
+    let addr = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    let targets: Arc<[String]> = targets.into();
+    let thread = thread::Builder::new()
+        .name("iscsi-discovery".to_string())
+        .spawn(move || {
+            while !shutdown::requested() {
+                match listener.accept() {
+                    Ok((stream, peer)) => {
+                        let targets = Arc::clone(&targets);
+                        thread::spawn(move || {
+                            if let Err(e) = serve_session(stream, &targets, portal_port) {
+                                log::debug!("Discovery session from {} ended: {}", peer, e);
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        thread::sleep(shutdown::POLL_INTERVAL);
+                    }
+                    Err(e) => log::error!("Discovery connection error: {}", e),
+                }
+            }
+        })?;
+    Ok((addr, thread))
+}
+
+/// Run one discovery session to logout or disconnect
+fn serve_session(stream: TcpStream, targets: &[String], portal_port: u16) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let portal = SocketAddr::new(stream.local_addr()?.ip(), portal_port);
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream.try_clone()?);
+    let mut session = DiscoverySession::new(targets, portal);
+
+    while !session.finished {
+        let request = Pdu::read_with(&mut reader, session.digests)?;
+        let digests = session.digests;
+        if let Some(response) = session.handle(&request)? {
+            response.write_with(&mut writer, digests)?;
+            writer.flush()?;
+        }
+    }
+    stream.shutdown(Shutdown::Both)
+}
+
+/// State of one discovery session
+struct DiscoverySession<'a> {
+    targets: &'a [String],
+    portal: SocketAddr,
+    /// Digests in force once login completes
+    digests: Digests,
+    negotiated: Digests,
+    /// Longest data segment the initiator takes
+    max_send: usize,
+    stat_sn: u32,
+    exp_cmd_sn: u32,
+    /// SessionType=Discovery was seen
+    discovery: bool,
+    logged_in: bool,
+    /// Rest of a SendTargets answer longer than one PDU
+    pending: Vec<u8>,
+    /// First login PDU seen, setting the StatSN to start from
+    started: bool,
+    /// Logged out, refused or misbehaving; close after any reply
+    finished: bool,
+}
+
+impl<'a> DiscoverySession<'a> {
+    fn new(targets: &'a [String], portal: SocketAddr) -> Self {
+        Self {
+            targets,
+            portal,
+            digests: Digests::default(),
+            negotiated: Digests::default(),
+            max_send: DEFAULT_DATA_SEGMENT,
+            stat_sn: 0,
+            exp_cmd_sn: 0,
+            discovery: false,
+            logged_in: false,
+            pending: Vec::new(),
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// The response to `request`, if it gets one
+    fn handle(&mut self, request: &Pdu) -> io::Result<Option<Pdu>> {
+        let opcode = request.opcode()?;
+        if opcode != Opcode::LoginRequest && !self.logged_in {
+            log::debug!("Discovery session sent {:?} before logging in", opcode);
+            self.finished = true;
+            return Ok(None);
+        }
+        // Immediate commands don't take a command sequence number
+        if request.bhs.opcode & 0x40 == 0 {
+            self.exp_cmd_sn = request.bhs.cmd_sn.wrapping_add(1);
+        } else {
+            self.exp_cmd_sn = request.bhs.cmd_sn;
+        }
+        match opcode {
+            Opcode::LoginRequest if !self.logged_in => Ok(Some(self.login(request))),
+            Opcode::TextRequest => Ok(Some(self.text(request))),
+            Opcode::LogoutRequest => {
+                self.finished = true;
+                let mut response = self.response(Opcode::LogoutResponse, request);
+                response.bhs.flags = 0x80;
+                Ok(Some(response))
+            }
+            Opcode::Nop if request.bhs.initiator_task_tag != NO_TAG => {
+                let mut response = self.response(Opcode::NopIn, request);
+                response.bhs.flags = 0x80;
+                response.bhs.lun = request.bhs.lun;
+                response.bhs.data_segment_length = request.data.len() as u32;
+                response.data = request.data.clone();
+                Ok(Some(response))
+            }
+            // Answers a ping of ours, and we send none
+            Opcode::Nop => Ok(None),
+            _ => {
+                log::debug!("Discovery session sent {:?}; closing it", opcode);
+                self.finished = true;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Target PDU answering `request`, numbered in the session's sequence
+    fn response(&mut self, opcode: Opcode, request: &Pdu) -> Pdu {
+        let mut response = Pdu::new(opcode);
+        response.bhs.initiator_task_tag = request.bhs.initiator_task_tag;
+        // StatSN, ExpCmdSN and MaxCmdSN sit where requests carry CmdSN,
+        // ExpStatSN and MaxCmdSN
+        response.bhs.cmd_sn = self.stat_sn;
+        response.bhs.exp_stat_sn = self.exp_cmd_sn;
+        response.bhs.max_cmd_sn = self.exp_cmd_sn.wrapping_add(CMD_WINDOW);
+        self.stat_sn = self.stat_sn.wrapping_add(1);
+        response
+    }
+
+    fn login(&mut self, request: &Pdu) -> Pdu {
+        if !self.started {
+            self.started = true;
+            self.stat_sn = request.bhs.exp_stat_sn;
+        }
+        let flags = request.bhs.flags;
+        let transit = flags & 0x80 != 0;
+        let current_stage = (flags >> 2) & 3;
+        let next_stage = flags & 3;
+
+        let params = parse_keys(&request.data);
+        let mut answers = Vec::new();
+        let mut status = LOGIN_SUCCESS;
+        for (key, value) in &params {
+            match key.as_str() {
+                "SessionType" => self.discovery = value == "Discovery",
+                "InitiatorName" | "InitiatorAlias" => {}
+                "MaxRecvDataSegmentLength" => {
+                    self.max_send = value.parse().unwrap_or(DEFAULT_DATA_SEGMENT).max(512);
+                    answers.push((key.clone(), MAX_RECV_DATA_SEGMENT.to_string()));
+                }
+                "AuthMethod" if value.split(',').any(|method| method == "None") => {
+                    answers.push((key.clone(), "None".to_string()))
+                }
+                "AuthMethod" => status = LOGIN_AUTH_FAILED,
+                "HeaderDigest" | "DataDigest" => {}
+                "ErrorRecoveryLevel" => answers.push((key.clone(), "0".to_string())),
+                "MaxConnections" => answers.push((key.clone(), "1".to_string())),
+                "IFMarker" | "OFMarker" => answers.push((key.clone(), "No".to_string())),
+                "DefaultTime2Wait" | "DefaultTime2Retain" => {
+                    answers.push((key.clone(), value.clone()))
+                }
+                key if IRRELEVANT.contains(&key) => {
+                    answers.push((key.to_string(), "Irrelevant".to_string()))
+                }
+                key => answers.push((key.to_string(), "NotUnderstood".to_string())),
+            }
+        }
+        let offered: HashMap<String, String> = params.iter().cloned().collect();
+        let (negotiated, digest_answers) = Digests::negotiate(&offered);
+        if offered.contains_key("HeaderDigest") {
+            self.negotiated.header = negotiated.header;
+        }
+        if offered.contains_key("DataDigest") {
+            self.negotiated.data = negotiated.data;
+        }
+        answers.extend(digest_answers);
+        if status == LOGIN_SUCCESS && !self.discovery {
+            log::info!("Refusing a normal session login on the discovery portal");
+            status = LOGIN_SESSION_TYPE_UNSUPPORTED;
+        }
+
+        let mut response = self.response(Opcode::LoginResponse, request);
+        // ISID from the initiator; a TSIH of our own once login completes
+        response.bhs.lun = request.bhs.lun;
+        response.bhs.target_transfer_tag = 0;
+        response.bhs.specific[2] = status.0;
+        response.bhs.specific[3] = status.1;
+        if status != LOGIN_SUCCESS {
+            self.finished = true;
+            return response;
+        }
+        response.bhs.flags = current_stage << 2;
+        if transit {
+            response.bhs.flags |= 0x80 | next_stage;
+            if next_stage == 3 {
+                response.bhs.lun = (request.bhs.lun & !0xffff) | 1;
+                self.logged_in = true;
+                // Digests start with the first PDU after this response
+                self.digests = self.negotiated;
+            }
+        }
+        set_data(&mut response, encode_keys(&answers));
+        response
+    }
+
+    fn text(&mut self, request: &Pdu) -> Pdu {
+        if request.bhs.target_transfer_tag != CONTINUE_TAG {
+            let params = parse_keys(&request.data);
+            let mut answers = Vec::new();
+            for (key, value) in &params {
+                match key.as_str() {
+                    "SendTargets" => answers.extend(send_targets(
+                        value,
+                        self.targets.iter().map(String::as_str),
+                        &[self.portal],
+                    )),
+                    key => answers.push((key.to_string(), "NotUnderstood".to_string())),
+                }
+            }
+            self.pending = encode_keys(&answers);
+        }
+
+        let mut response = self.response(Opcode::TextResponse, request);
+        response.bhs.lun = request.bhs.lun;
+        let rest = self
+            .pending
+            .split_off(self.pending.len().min(self.max_send));
+        let data = std::mem::replace(&mut self.pending, rest);
+        if self.pending.is_empty() {
+            response.bhs.flags = 0x80;
+            response.bhs.target_transfer_tag = NO_TAG;
+        } else {
+            // More to come: the initiator asks for it with our tag
+            response.bhs.flags = 0x40;
+            response.bhs.target_transfer_tag = CONTINUE_TAG;
+        }
+        set_data(&mut response, data);
+        response
+    }
+}
+
+/// `key=value` pairs of a text or login data segment, in order
+fn parse_keys(data: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(data)
+        .split('\0')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+fn encode_keys(keys: &[(String, String)]) -> Vec<u8> {
+    let mut data = Vec::new();
+    for (key, value) in keys {
+        data.extend_from_slice(format!("{}={}\0", key, value).as_bytes());
+    }
+    data
+}
+
+fn set_data(pdu: &mut Pdu, data: Vec<u8>) {
+    pdu.bhs.data_segment_length = data.len() as u32;
+    pdu.data = data;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_targets() {
+        let portals: Vec<SocketAddr> =
+            vec!["192.0.2.1:3260".parse().unwrap(), "[2001:db8::1]:3261".parse().unwrap()];
+        let targets = ["iqn.2025-12.local.voe:b", "iqn.2025-12.local.voe:a"];
+
+        let all = send_targets("All", targets, &portals);
+        let keys: Vec<(&str, &str)> = all.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(
+            keys,
+            vec![
+                ("TargetName", "iqn.2025-12.local.voe:a"),
+                ("TargetAddress", "192.0.2.1:3260,1"),
+                ("TargetAddress", "[2001:db8::1]:3261,1"),
+                ("TargetName", "iqn.2025-12.local.voe:b"),
+                ("TargetAddress", "192.0.2.1:3260,1"),
+                ("TargetAddress", "[2001:db8::1]:3261,1"),
+            ]
+        );
+
+        let one = send_targets("iqn.2025-12.local.voe:b", targets, &portals[..1]);
+        assert_eq!(one.len(), 2);
+        assert_eq!(one[0].1, "iqn.2025-12.local.voe:b");
+        assert!(send_targets("iqn.2025-12.local.voe:missing", targets, &portals).is_empty());
+
+        let mut params = HashMap::new();
+        assert!(!is_discovery_login(&params));
+        params.insert("SessionType".to_string(), "Discovery".to_string());
+        assert!(is_discovery_login(&params));
+    }
+
+    /// Initiator PDU carrying `keys`; login PDUs are immediate
+    fn request(opcode: Opcode, flags: u8, cmd_sn: u32, keys: &[(&str, &str)]) -> Pdu {
+        let mut pdu = Pdu::new(opcode);
+        if opcode == Opcode::LoginRequest {
+            pdu.bhs.opcode |= 0x40;
+            pdu.bhs.lun = 0x0023_0000_0001_0000;
+        }
+        pdu.bhs.flags = flags;
+        pdu.bhs.initiator_task_tag = cmd_sn;
+        pdu.bhs.cmd_sn = cmd_sn;
+        pdu.bhs.exp_stat_sn = 5;
+        let keys: Vec<(String, String)> = keys
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        set_data(&mut pdu, encode_keys(&keys));
+        pdu
+    }
+
+    fn has_key(pdu: &Pdu, key: &str, value: &str) -> bool {
+        parse_keys(&pdu.data).contains(&(key.to_string(), value.to_string()))
+    }
+
+    #[test]
+    fn test_discovery_session() {
+        let targets: Vec<String> = (0..20)
+            .map(|i| format!("iqn.2025-12.local.voe:t{}", i))
+            .collect();
+        let portal: SocketAddr = "192.0.2.1:3260".parse().unwrap();
+        let mut session = DiscoverySession::new(&targets, portal);
+
+        // Security stage, then operational, then full feature phase
+        let keys = [
+            ("InitiatorName", "iqn.1993-08.org.debian:01:host"),
+            ("SessionType", "Discovery"),
+            ("AuthMethod", "CHAP,None"),
+        ];
+        let login = request(Opcode::LoginRequest, 0x81, 0, &keys);
+        let response = session.handle(&login).unwrap().unwrap();
+        assert_eq!(response.opcode().unwrap(), Opcode::LoginResponse);
+        assert_eq!((response.bhs.flags, response.bhs.specific[2]), (0x81, 0));
+        assert_eq!(response.bhs.cmd_sn, 5);
+        assert!(has_key(&response, "AuthMethod", "None"));
+
+        let keys = [
+            ("HeaderDigest", "None"),
+            ("MaxRecvDataSegmentLength", "512"),
+            ("MaxBurstLength", "262144"),
+            ("ErrorRecoveryLevel", "2"),
+        ];
+        let login = request(Opcode::LoginRequest, 0x87, 0, &keys);
+        let response = session.handle(&login).unwrap().unwrap();
+        assert_eq!((response.bhs.flags, response.bhs.specific[2]), (0x87, 0));
+        assert_eq!(response.bhs.lun >> 16, login.bhs.lun >> 16);
+        assert_ne!(response.bhs.lun & 0xffff, 0);
+        assert!(has_key(&response, "MaxBurstLength", "Irrelevant"));
+        assert!(has_key(&response, "ErrorRecoveryLevel", "0"));
+
+        // The answer is longer than the initiator takes, so comes in parts
+        let text = request(Opcode::TextRequest, 0x80, 1, &[("SendTargets", "All")]);
+        let mut response = session.handle(&text).unwrap().unwrap();
+        let mut answer = Vec::new();
+        while response.bhs.flags & 0x80 == 0 {
+            assert_eq!(response.bhs.target_transfer_tag, CONTINUE_TAG);
+            assert!(response.data.len() <= 512);
+            answer.extend_from_slice(&response.data);
+            let mut next = request(Opcode::TextRequest, 0x80, 2, &[]);
+            next.bhs.target_transfer_tag = CONTINUE_TAG;
+            response = session.handle(&next).unwrap().unwrap();
+        }
+        answer.extend_from_slice(&response.data);
+        let expected = send_targets("All", targets.iter().map(String::as_str), &[portal]);
+        assert_eq!(parse_keys(&answer), expected);
+
+        let logout = request(Opcode::LogoutRequest, 0x80, 3, &[]);
+        let response = session.handle(&logout).unwrap().unwrap();
+        assert_eq!(response.opcode().unwrap(), Opcode::LogoutResponse);
+        assert!(session.finished);
+
+        // Normal sessions belong on the target portal
+        let mut session = DiscoverySession::new(&targets, portal);
+        let keys = [
+            ("SessionType", "Normal"),
+            ("TargetName", targets[0].as_str()),
+        ];
+        let login = request(Opcode::LoginRequest, 0x87, 0, &keys);
+        let response = session.handle(&login).unwrap().unwrap();
+        assert_eq!(&response.bhs.specific[2..4], &[2, 9]);
+        assert!(session.finished);
+    }
+
+    #[test]
+    fn test_discovery_portal() {
+        let targets = vec!["iqn.2025-12.local.voe:a".to_string()];
+        let (addr, _) = spawn("127.0.0.1:0", targets, 3260).unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+
+        let login = request(
+            Opcode::LoginRequest,
+            0x87,
+            0,
+            &[("SessionType", "Discovery")],
+        );
+        login.write(&mut stream).unwrap();
+        let response = Pdu::read(&mut stream).unwrap();
+        assert_eq!((response.bhs.flags, response.bhs.specific[2]), (0x87, 0));
+
+        let text = request(Opcode::TextRequest, 0x80, 1, &[("SendTargets", "All")]);
+        text.write(&mut stream).unwrap();
+        let response = Pdu::read(&mut stream).unwrap();
+        assert!(has_key(&response, "TargetName", "iqn.2025-12.local.voe:a"));
+        assert!(has_key(&response, "TargetAddress", "127.0.0.1:3260,1"));
+
+        request(Opcode::LogoutRequest, 0x80, 2, &[])
+            .write(&mut stream)
+            .unwrap();
+        let response = Pdu::read(&mut stream).unwrap();
+        assert_eq!(response.opcode().unwrap(), Opcode::LogoutResponse);
+    }
+}
@@ -0,0 +1,319 @@
+//! iSCSI front-end
+//!
+//! Wraps the multi-target `IscsiServer` in the `Frontend` trait. Any
+//! `BlockStorage` backend can be attached through `StorageScsiDevice`, so
+//! the same backends served over AoE and NBD are reachable over iSCSI.
+
+use super::TracedScsiDevice;
+use crate::frontend::{Frontend, FrontendError, FrontendResult};
+use crate::shutdown;
+use crate::storage::{BlockStorage, HealthCheck, DEFAULT_HEALTH_INTERVAL};
+use iscsi_target::{IscsiError, IscsiServer, ScsiBlockDevice, ScsiResult};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long `stop` waits for sessions to log out before closing them
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Largest transfer a single `BlockStorage` call accepts
+const MAX_SECTORS_PER_CALL: usize = 255;
+
+/// SCSI block device over any `BlockStorage` backend
+pub struct StorageScsiDevice {
+    storage: Box<dyn BlockStorage>,
+    product_id: String,
+    health: HealthCheck,
+}
+
+impl StorageScsiDevice {
+    pub fn new(storage: Box<dyn BlockStorage>) -> Self {
+        // INQUIRY product identification is 16 space-padded bytes
+        let mut product_id: String = storage.info().model.chars().take(16).collect();
+        while product_id.len() < 16 {
+            product_id.push(' ');
+        }
+        let health = HealthCheck::new(storage.info().model.clone(), DEFAULT_HEALTH_INTERVAL);
+        Self {
+            storage,
+            product_id,
+            health,
+        }
+    }
+
+    /// Refuse I/O while the backend is unhealthy. `iscsi_target` reports
+    /// every device error as a MEDIUM ERROR; it has no way to return
+    /// NOT READY from a `ScsiBlockDevice`.
+    fn check_ready(&self) -> ScsiResult<()> {
+        self.health
+            .check(self.storage.as_ref())
+            .map_err(|reason| IscsiError::Scsi(format!("device not ready: {}", reason)))
+    }
+
+    fn check_block_size(&self, block_size: u32) -> ScsiResult<usize> {
+        let sector_size = self.storage.info().sector_size;
+        if block_size != sector_size {
+            return Err(IscsiError::Scsi(format!(
+                "block size {} does not match sector size {}",
+                block_size, sector_size
+            )));
+        }
+        Ok(sector_size as usize)
+    }
+}
+
+impl ScsiBlockDevice for StorageScsiDevice {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        self.check_ready()?;
+        let sector_size = self.check_block_size(block_size)?;
+        let mut data = Vec::with_capacity(blocks as usize * sector_size);
+        let mut done = 0u64;
+        while done < blocks as u64 {
+            let count = (blocks as u64 - done).min(MAX_SECTORS_PER_CALL as u64) as u8;
+            let chunk = self
+                .storage
+                .read(lba + done, count)
+                .map_err(|e| IscsiError::Scsi(e.to_string()))?;
+            data.extend_from_slice(&chunk);
+            done += count as u64;
+        }
+        Ok(data)
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        self.check_ready()?;
+        let sector_size = self.check_block_size(block_size)?;
+        if !data.len().is_multiple_of(sector_size) {
+            return Err(IscsiError::Scsi(format!(
+                "write of {} bytes is not a multiple of the block size",
+                data.len()
+            )));
+        }
+        for (i, chunk) in data.chunks(MAX_SECTORS_PER_CALL * sector_size).enumerate() {
+            let chunk_lba = lba + (i * MAX_SECTORS_PER_CALL) as u64;
+            self.storage
+                .write(chunk_lba, chunk)
+                .map_err(|e| IscsiError::Scsi(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> u64 {
+        self.storage.info().total_sectors
+    }
+
+    fn block_size(&self) -> u32 {
+        self.storage.info().sector_size
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        self.check_ready()?;
+        self.storage
+            .flush()
+            .map_err(|e| IscsiError::Scsi(e.to_string()))
+    }
+
+    fn vendor_id(&self) -> &str {
+        "VoE     "
+    }
+
+    fn product_id(&self) -> &str {
+        &self.product_id
+    }
+}
+
+struct PendingTarget {
+    iqn: String,
+    device: Box<dyn ScsiBlockDevice + Send>,
+    alias: Option<String>,
+}
+
+/// iSCSI server as a `Frontend`
+///
+/// The underlying server takes ownership of its devices when built, so
+/// targets must be attached before `start` and a stopped front-end cannot
+/// be started again.
+pub struct IscsiFrontend {
+    bind_addr: String,
+    pending: Vec<PendingTarget>,
+    names: Vec<String>,
+    server: Option<Arc<IscsiServer>>,
+    thread: Option<JoinHandle<ScsiResult<()>>>,
+    stopped: bool,
+}
+
+impl IscsiFrontend {
+    pub fn new(bind_addr: &str) -> Self {
+        Self {
+            bind_addr: bind_addr.to_string(),
+            pending: Vec::new(),
+            names: Vec::new(),
+            server: None,
+            thread: None,
+            stopped: false,
+        }
+    }
+
+    /// Attach a SCSI device directly, e.g. a `CasScsiDevice`
+    pub fn attach_device(
+        &mut self,
+        iqn: &str,
+        device: Box<dyn ScsiBlockDevice + Send>,
+        alias: Option<String>,
+    ) -> FrontendResult<()> {
+        if self.server.is_some() || self.stopped {
+            return Err(FrontendError::AlreadyRunning);
+        }
+        if !iqn.starts_with("iqn.") && !iqn.starts_with("eui.") && !iqn.starts_with("naa.") {
+            return Err(FrontendError::InvalidName(iqn.to_string()));
+        }
+        if self.names.iter().any(|name| name == iqn) {
+            return Err(FrontendError::DuplicateTarget(iqn.to_string()));
+        }
+        self.names.push(iqn.to_string());
+        self.pending.push(PendingTarget {
+            iqn: iqn.to_string(),
+            device,
+            alias,
+        });
+        Ok(())
+    }
+
+    /// Active iSCSI sessions, 0 when stopped
+    pub fn active_sessions(&self) -> usize {
+        self.server
+            .as_ref()
+            .map_or(0, |server| server.active_session_count())
+    }
+}
+
+impl Frontend for IscsiFrontend {
+    fn protocol(&self) -> &'static str {
+        "iscsi"
+    }
+
+    fn attach(&mut self, name: &str, storage: Box<dyn BlockStorage>) -> FrontendResult<()> {
+        self.attach_device(name, Box::new(StorageScsiDevice::new(storage)), None)
+    }
+
+    fn targets(&self) -> Vec<String> {
+        self.names.clone()
+    }
+
+    fn start(&mut self) -> FrontendResult<()> {
+        if self.server.is_some() {
+            return Err(FrontendError::AlreadyRunning);
+        }
+        if self.stopped {
+            return Err(FrontendError::Unsupported(
+                "an iSCSI front-end cannot be restarted".to_string(),
+            ));
+        }
+
+        let mut builder = IscsiServer::builder().bind_addr(&self.bind_addr);
+        for target in self.pending.drain(..) {
+            let device = Box::new(TracedScsiDevice::new(target.iqn.as_str(), target.device));
+            builder = builder.add_target(target.iqn, device, target.alias);
+        }
+        let server = Arc::new(
+            builder
+                .build()
+                .map_err(|e| FrontendError::Protocol(e.to_string()))?,
+        );
+
+        let runner = Arc::clone(&server);
+        let thread = thread::Builder::new()
+            .name("iscsi-accept".to_string())
+            .spawn(move || runner.run())?;
+
+        // `run` binds before raising its running flag, and a `stop` that
+        // lands earlier would be overwritten, so wait until it's listening
+        while !server.is_running() {
+            if thread.is_finished() {
+                return match thread.join() {
+                    Ok(Err(e)) => Err(FrontendError::Protocol(e.to_string())),
+                    _ => Err(FrontendError::Protocol(
+                        "iSCSI accept loop exited during startup".to_string(),
+                    )),
+                };
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.server = Some(server);
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    fn stop(&mut self) -> FrontendResult<()> {
+        let server = self.server.take().ok_or(FrontendError::NotRunning)?;
+        self.stopped = true;
+
+        server.shutdown_gracefully();
+        let deadline = Instant::now() + DRAIN_TIMEOUT;
+        while server.active_session_count() > 0 && Instant::now() < deadline {
+            thread::sleep(shutdown::POLL_INTERVAL);
+        }
+        if server.active_session_count() > 0 {
+            log::warn!(
+                "Closing {} iSCSI session(s) that did not log out",
+                server.active_session_count()
+            );
+        }
+        server.stop();
+
+        match self.thread.take().map(JoinHandle::join) {
+            Some(Ok(result)) => result.map_err(|e| FrontendError::Protocol(e.to_string())),
+            Some(Err(_)) => Err(FrontendError::Protocol(
+                "iSCSI accept loop panicked".to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.server.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemBackend;
+
+    #[test]
+    fn test_storage_device_roundtrip() {
+        let mut device = StorageScsiDevice::new(Box::new(MemBackend::new(1024 * 1024)));
+        assert_eq!(device.capacity(), 2048);
+        assert_eq!(device.block_size(), 512);
+        assert_eq!(device.product_id().len(), 16);
+
+        // Larger than one BlockStorage call
+        let data: Vec<u8> = (0..300 * 512).map(|i| (i % 251) as u8).collect();
+        device.write(10, &data, 512).unwrap();
+        assert_eq!(device.read(10, 300, 512).unwrap(), data);
+
+        assert!(device.read(0, 1, 4096).is_err());
+        assert!(device.read(2047, 2, 512).is_err());
+    }
+
+    #[test]
+    fn test_frontend_lifecycle() {
+        let mut frontend = IscsiFrontend::new("127.0.0.1:0");
+        let storage = || Box::new(MemBackend::new(1024 * 1024)) as Box<dyn BlockStorage>;
+        assert!(matches!(
+            frontend.attach("disk0", storage()),
+            Err(FrontendError::InvalidName(_))
+        ));
+        frontend.attach("iqn.2024-01.test:disk0", storage()).unwrap();
+        assert!(matches!(
+            frontend.attach("iqn.2024-01.test:disk0", storage()),
+            Err(FrontendError::DuplicateTarget(_))
+        ));
+
+        frontend.start().unwrap();
+        assert!(frontend.is_running());
+        frontend.stop().unwrap();
+        assert!(!frontend.is_running());
+        assert!(frontend.start().is_err());
+    }
+}
@@ -0,0 +1,390 @@
+//! Garbage collection across all targets
+//!
+//! `iscsi-clone gc` reclaims the blocks of one stopped target. The GC
+//! daemon (`iscsi-clone gc-daemon`) instead runs rounds over every target
+//! in the registry, running or not:
+//!
+//! 1. Running targets are fenced (`live::GcFence`): their servers log the
+//!    hash of each block before writing it to CAS.
+//! 2. Every hash reachable from a target's index or one of its snapshots
+//!    is marked live. Running targets' indexes are locked by their server,
+//!    so the server snapshots each one to a temporary layer that is read
+//!    and removed, as for a live clone.
+//! 3. The CAS protocol can't list blocks, so the candidates are the
+//!    hashes that were live after the previous round and aren't now, e.g.
+//!    the blocks of deleted targets and overwritten data. The live set is
+//!    kept in `<registry path>.gc-live` between rounds; the first round
+//!    only records it. Live sets and candidates are sorted lists on disk
+//!    (`hashlist`), so memory use is bounded however many blocks there are.
+//! 4. Candidates are deleted one by one, each spared if a fenced target
+//!    has written it since the fence went up. The CAS server also keeps
+//!    blocks written within its delete grace period, which covers writes
+//!    not yet in any index and a write racing the delete. A target started
+//!    after the fences went up isn't fenced, so between batches the round
+//!    checks for one and, if it finds one, stops deleting until the next
+//!    round fences it.
+//!
+//! The daemon can serve its totals and last round's report as metrics
+//! (`GET /stats`, in the `{success, data, error}` envelope), see
+//! `spawn_status`.
+//!
+//! Blocks written and overwritten between two rounds are never seen live
+//! and are left to `iscsi-clone gc` and `cas-server` tooling.
+
+use anyhow::{Context, Result};
+use axum::{extract::State, response::Json, routing::get, Router};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use super::clone::{sort_index_hashes, CloneManager};
+use super::hashlist::{HashList, HashListWriter, HashSorter};
+use super::live::{self, GcFence};
+use super::registry::{TargetMetadata, TargetRegistry};
+use super::status;
+use crate::cas::DeleteOutcome;
+use crate::server::api::ApiResponse;
+use crate::shutdown;
+
+/// Default number of blocks deleted between checks for targets started
+/// mid-round
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// Bytes reclaimed per deleted block, as `iscsi-clone gc` estimates
+const BLOCK_SIZE: u64 = 4096;
+
+/// Outcome of one GC round
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GcRoundReport {
+    /// Unix epoch seconds
+    pub started_at: u64,
+    pub finished_at: u64,
+    /// Targets marked, and how many of them were running
+    pub targets: usize,
+    pub running_targets: usize,
+    /// Hashes reachable from any target or snapshot
+    pub live: usize,
+    /// Hashes live last round and not this one
+    pub candidates: usize,
+    /// Candidates a running target wrote again during the round
+    pub spared: usize,
+    pub deleted: usize,
+    pub not_found: usize,
+    /// Written too recently for the CAS server to delete; tried again
+    /// next round
+    pub kept: usize,
+    pub errors: usize,
+    /// Approximate, at 4 KiB per deleted block
+    pub bytes_reclaimed: u64,
+    /// Whether this was a dry run that deleted nothing
+    pub dry_run: bool,
+}
+
+/// Totals over the rounds a daemon has run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct GcDaemonStats {
+    pub rounds: u64,
+    pub failed_rounds: u64,
+    pub total_deleted: u64,
+    pub total_bytes_reclaimed: u64,
+    pub last_round: Option<GcRoundReport>,
+    pub last_error: Option<String>,
+    /// Unix epoch seconds
+    pub next_round_at: Option<u64>,
+}
+
+impl GcDaemonStats {
+    pub fn record(&mut self, result: &Result<GcRoundReport>) {
+        self.rounds += 1;
+        match result {
+            Ok(report) => {
+                self.total_deleted += report.deleted as u64;
+                self.total_bytes_reclaimed += report.bytes_reclaimed;
+                self.last_round = Some(report.clone());
+                self.last_error = None;
+            }
+            Err(e) => {
+                self.failed_rounds += 1;
+                self.last_error = Some(format!("{:#}", e));
+            }
+        }
+    }
+}
+
+/// Serve a daemon's statistics on `bind` until shutdown is requested
+pub fn spawn_status(
+    bind: &str,
+    stats: Arc<Mutex<GcDaemonStats>>,
+) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+    let router = Router::new().route("/stats", get(daemon_stats)).with_state(stats);
+    status::serve(bind, "gc-status", router)
+}
+
+async fn daemon_stats(
+    State(stats): State<Arc<Mutex<GcDaemonStats>>>,
+) -> Json<ApiResponse<GcDaemonStats>> {
+    ApiResponse::success(stats.lock().unwrap().clone())
+}
+
+/// File keeping the live set between rounds, as a `HashList`
+fn state_path(registry_path: &Path) -> PathBuf {
+    let mut path = registry_path.as_os_str().to_owned();
+    path.push(".gc-live");
+    PathBuf::from(path)
+}
+
+/// Run one round over every target in `manager`'s registry. A dry run
+/// marks and counts candidates without deleting them.
+pub fn run_round(
+    manager: &CloneManager,
+    dry_run: bool,
+    batch_size: usize,
+) -> Result<GcRoundReport> {
+    let mut report = GcRoundReport {
+        started_at: TargetRegistry::now(),
+        dry_run,
+        ..GcRoundReport::default()
+    };
+    let work_dir = manager.gc_work_dir();
+
+    let mut running = Vec::new();
+    let mut stopped = Vec::new();
+    for target in manager.registry.list_targets() {
+        if manager.is_target_running(&target.iqn)? {
+            running.push(target);
+        } else {
+            stopped.push(target);
+        }
+    }
+    report.targets = running.len() + stopped.len();
+    report.running_targets = running.len();
+
+    // Up before marking, so nothing written after the mark goes unseen
+    let mut fences = Vec::new();
+    for target in &running {
+        let fence = GcFence::raise(&target.index_path, live::SNAPSHOT_TIMEOUT)
+            .with_context(|| format!("Failed to fence running target {}", target.iqn))?;
+        fences.push(fence);
+    }
+
+    let mut live = HashSorter::new(&work_dir);
+    for target in &stopped {
+        sort_index_hashes(&target.index_path, &mut live)?;
+    }
+    for target in &running {
+        sort_running_target_hashes(manager, &target.iqn, &target.index_path, &mut live)?;
+    }
+    for target in manager.registry.targets.values() {
+        for snapshot in &target.snapshots {
+            sort_index_hashes(&snapshot.layer_path, &mut live)?;
+        }
+    }
+    let live = live.finish().context("Failed to sort live hashes")?;
+    report.live = live.len();
+
+    let state = state_path(&manager.registry.registry_path);
+    if !state.exists() {
+        log::info!("First GC round: recorded {} live hashes", live.len());
+        live.persist(&state)
+            .with_context(|| format!("Failed to write GC state {:?}", state))?;
+        report.finished_at = TargetRegistry::now();
+        return Ok(report);
+    }
+    let previous = HashList::open(&state)
+        .with_context(|| format!("Failed to read GC state {:?}", state))?;
+    let candidates = previous.difference(&live, &work_dir)
+        .context("Failed to find GC candidates")?;
+    report.candidates = candidates.len();
+    log::info!(
+        "GC round: {} live hashes, {} candidates across {} target(s)",
+        live.len(),
+        candidates.len(),
+        report.targets
+    );
+
+    // Whatever isn't deleted stays tracked for the next round
+    let kept = if dry_run {
+        HashList::union(&[&live, &candidates], &work_dir)?
+    } else {
+        let leftover = delete_candidates(
+            manager,
+            &candidates,
+            &mut fences,
+            &stopped,
+            batch_size,
+            &mut report,
+        )?;
+        HashList::union(&[&live, &leftover], &work_dir)?
+    };
+    drop(fences);
+
+    kept.persist(&state)
+        .with_context(|| format!("Failed to write GC state {:?}", state))?;
+    report.bytes_reclaimed = report.deleted as u64 * BLOCK_SIZE;
+    report.finished_at = TargetRegistry::now();
+    Ok(report)
+}
+
+/// Delete candidates, sparing what the fenced targets have written, until
+/// shutdown or a target in `not_running` starts. Returns the candidates
+/// that weren't deleted.
+fn delete_candidates(
+    manager: &CloneManager,
+    candidates: &HashList,
+    fences: &mut [GcFence],
+    not_running: &[&TargetMetadata],
+    batch_size: usize,
+    report: &mut GcRoundReport,
+) -> Result<HashList> {
+    let cas = manager.connect_cas()?;
+    let mut leftover = HashListWriter::create(&manager.gc_work_dir())?;
+
+    let batch_size = batch_size.max(1);
+    let mut stopped = false;
+    for (i, hash) in candidates.iter()?.enumerate() {
+        let hash = hash.context("Failed to read GC candidates")?;
+        if i % batch_size == 0 && !stopped {
+            let left = candidates.len() - i;
+            if shutdown::requested() {
+                log::info!("GC round stopped with {} candidates left", left);
+                stopped = true;
+            } else if let Some(target) = started_target(manager, not_running)? {
+                log::info!(
+                    "GC round stopped with {} candidates left: {} started mid-round",
+                    left,
+                    target
+                );
+                stopped = true;
+            }
+        }
+        if stopped {
+            leftover.push(hash)?;
+            continue;
+        }
+        let mut written = false;
+        for fence in fences.iter_mut() {
+            written |= fence.written()?.contains(&hash);
+        }
+        if written {
+            report.spared += 1;
+            leftover.push(hash)?;
+            continue;
+        }
+        match cas.delete(&hash) {
+            Ok(DeleteOutcome::Deleted) => report.deleted += 1,
+            Ok(DeleteOutcome::NotFound) => report.not_found += 1,
+            Ok(DeleteOutcome::Kept) => {
+                report.kept += 1;
+                leftover.push(hash)?;
+            }
+            Err(e) => {
+                log::warn!("Failed to delete block {}: {}", hex::encode(hash), e);
+                report.errors += 1;
+                leftover.push(hash)?;
+            }
+        }
+    }
+    Ok(leftover.finish()?)
+}
+
+/// The first of `targets` that is now running, if any
+fn started_target<'a>(
+    manager: &CloneManager,
+    targets: &[&'a TargetMetadata],
+) -> Result<Option<&'a str>> {
+    for target in targets {
+        if manager.is_target_running(&target.iqn)? {
+            return Ok(Some(&target.iqn));
+        }
+    }
+    Ok(None)
+}
+
+/// Add every hash a running target's index reaches to `sorter`, read
+/// from a temporary snapshot taken by its server
+fn sort_running_target_hashes(
+    manager: &CloneManager,
+    iqn: &str,
+    index_path: &Path,
+    sorter: &mut HashSorter,
+) -> Result<()> {
+    let layer_path = manager.get_layer_path(iqn);
+    live::request_snapshot(index_path, &layer_path, live::SNAPSHOT_TIMEOUT)
+        .with_context(|| format!("Failed to snapshot running target {}", iqn))?;
+    let result = sort_index_hashes(&layer_path, sorter);
+    if let Err(e) = fs::remove_dir_all(&layer_path) {
+        log::warn!("Failed to remove GC snapshot {:?}: {}", layer_path, e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::AdminClient;
+    use crate::cas::{CasPool, CasPoolConfig, CasServer, CasServerConfig};
+    use crate::iscsi::index::LbaIndex;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_round_deletes_blocks_no_longer_live() -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let cas_addr = listener.local_addr()?.to_string();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: cas_addr.clone(),
+            storage_path: temp_dir.path().join("cas").to_string_lossy().into_owned(),
+        })?
+        .with_delete_grace(Duration::ZERO);
+        std::thread::spawn(move || server.serve(listener));
+
+        let mut manager = CloneManager::new(
+            temp_dir.path().join("registry.json"),
+            temp_dir.path().join("targets"),
+            cas_addr.clone(),
+        )?;
+        let iqn = manager.create_target("gc", 100, None)?;
+        let index_path = manager.registry.get_target(&iqn).unwrap().index_path.clone();
+
+        let cas = CasPool::connect(&cas_addr, CasPoolConfig::default())?;
+        let old = cas.write(&[1; 4096])?;
+        let kept = cas.write(&[2; 4096])?;
+        {
+            let index = LbaIndex::open_or_create(&index_path, || Ok([0; 16]))?;
+            index.insert(0, &old)?;
+            index.insert(1, &kept)?;
+            index.flush()?;
+        }
+
+        // The first round only records what is live
+        let report = run_round(&manager, false, DEFAULT_BATCH_SIZE)?;
+        assert_eq!((report.live, report.candidates), (2, 0));
+
+        LbaIndex::open(&index_path)?.unwrap().insert(0, &kept)?;
+        let report = run_round(&manager, true, DEFAULT_BATCH_SIZE)?;
+        assert_eq!((report.candidates, report.deleted), (1, 0));
+        assert_eq!(cas.read(&old)?, vec![1; 4096]);
+
+        let report = run_round(&manager, false, DEFAULT_BATCH_SIZE)?;
+        assert_eq!((report.candidates, report.deleted), (1, 1));
+        assert_eq!(report.bytes_reclaimed, 4096);
+        assert!(cas.read(&old).is_err());
+        assert_eq!(cas.read(&kept)?, vec![2; 4096]);
+
+        let stats = Arc::new(Mutex::new(GcDaemonStats::default()));
+        let (addr, _thread) = spawn_status("127.0.0.1:0", stats.clone())?;
+        stats.lock().unwrap().record(&Ok(report));
+        stats.lock().unwrap().record(&run_round(&manager, false, DEFAULT_BATCH_SIZE));
+
+        let metrics = AdminClient::new(&addr.to_string())?.get("/stats")?;
+        assert_eq!(metrics["rounds"], 2);
+        assert_eq!(metrics["total_bytes_reclaimed"], 4096);
+        assert_eq!(metrics["last_round"]["candidates"], 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+//! Sorted hash lists on disk
+//!
+//! GC compares the hashes of every target, which for large targets don't
+//! fit in memory as `HashSet`s. Instead, hashes are collected by a
+//! `HashSorter`, which sorts them in bounded runs spilled to disk and
+//! merges the runs into one sorted, deduplicated `HashList` file. Lists
+//! are then compared by streaming through them side by side, so GC memory
+//! depends on the run length and the number of runs, not on target sizes.
+//!
+//! A list file is just its hashes back to back, 16 bytes each.
+
+use crate::cas::Hash;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering as AtomicOrdering};
+
+/// Hashes sorted in memory before a run is spilled (16 MiB)
+pub const DEFAULT_RUN_LEN: usize = 1 << 20;
+
+/// Bytes per buffered read or write
+const IO_BUFFER: usize = 64 * 1024;
+
+/// Most runs merged at once, bounding the read buffers held by a merge
+const MAX_MERGE: usize = 64;
+
+/// A unique path for a new list in `dir`
+fn new_path(dir: &Path) -> PathBuf {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let n = NEXT.fetch_add(1, AtomicOrdering::Relaxed);
+    dir.join(format!("{}-{}.hashes", std::process::id(), n))
+}
+
+/// A sorted, deduplicated list of hashes in a file. Lists made by a
+/// sorter or a set operation are removed when dropped, unless persisted.
+pub struct HashList {
+    path: PathBuf,
+    len: usize,
+    temporary: bool,
+}
+
+impl HashList {
+    /// Open an existing list, e.g. one persisted by an earlier run
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let size = fs::metadata(path)?.len();
+        if size % 16 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("hash list {:?} is {} bytes, not a multiple of 16", path, size),
+            ));
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            len: (size / 16) as usize,
+            temporary: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Stream the hashes in order
+    pub fn iter(&self) -> io::Result<HashListIter> {
+        Ok(HashListIter {
+            reader: BufReader::with_capacity(IO_BUFFER, File::open(&self.path)?),
+        })
+    }
+
+    /// Hashes in this list and not in `other`, as a new list in `dir`
+    pub fn difference(&self, other: &HashList, dir: &Path) -> io::Result<HashList> {
+        let mut writer = HashListWriter::create(dir)?;
+        let mut others = other.iter()?.peekable();
+        for hash in self.iter()? {
+            let hash = hash?;
+            loop {
+                match others.peek() {
+                    Some(Ok(next)) if *next < hash => {
+                        others.next();
+                    }
+                    Some(Ok(_)) | None => break,
+                    Some(Err(_)) => return Err(others.next().unwrap().unwrap_err()),
+                }
+            }
+            if !matches!(others.peek(), Some(Ok(next)) if *next == hash) {
+                writer.push(hash)?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Hashes in any of `lists`, as a new list in `dir`
+    pub fn union(lists: &[&HashList], dir: &Path) -> io::Result<HashList> {
+        let iters = lists.iter().map(|list| list.iter()).collect::<io::Result<_>>()?;
+        let mut writer = HashListWriter::create(dir)?;
+        merge(iters, &mut writer)?;
+        writer.finish()
+    }
+
+    /// Move the list to `path`, replacing any file there, and keep it
+    pub fn persist(mut self, path: &Path) -> io::Result<HashList> {
+        File::open(&self.path)?.sync_all()?;
+        fs::rename(&self.path, path)?;
+        self.path = path.to_path_buf();
+        self.temporary = false;
+        Ok(self)
+    }
+}
+
+impl Drop for HashList {
+    fn drop(&mut self) {
+        if self.temporary {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// The hashes of a `HashList`, in order
+pub struct HashListIter {
+    reader: BufReader<File>,
+}
+
+impl Iterator for HashListIter {
+    type Item = io::Result<Hash>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut hash = [0u8; 16];
+        match self.reader.read_exact(&mut hash) {
+            Ok(()) => Some(Ok(hash)),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Writes hashes that arrive in order, dropping duplicates
+pub struct HashListWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    last: Option<Hash>,
+    len: usize,
+}
+
+impl HashListWriter {
+    /// Start a new temporary list in `dir`
+    pub fn create(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = new_path(dir);
+        Ok(Self {
+            writer: BufWriter::with_capacity(IO_BUFFER, File::create(&path)?),
+            path,
+            last: None,
+            len: 0,
+        })
+    }
+
+    pub fn push(&mut self, hash: Hash) -> io::Result<()> {
+        match self.last.map(|last| last.cmp(&hash)) {
+            Some(Ordering::Equal) => return Ok(()),
+            Some(Ordering::Greater) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "hashes written to a list out of order",
+                ))
+            }
+            _ => {}
+        }
+        self.writer.write_all(&hash)?;
+        self.last = Some(hash);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<HashList> {
+        let list = HashList {
+            path: self.path,
+            len: self.len,
+            temporary: true,
+        };
+        self.writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(list)
+    }
+}
+
+/// Collects hashes in any order into a `HashList`, holding at most
+/// `run_len` of them in memory
+pub struct HashSorter {
+    dir: PathBuf,
+    run_len: usize,
+    buffer: Vec<Hash>,
+    runs: Vec<HashList>,
+}
+
+impl HashSorter {
+    /// Sort with runs spilled to `dir`, which should be on disk rather
+    /// than a RAM-backed /tmp
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+            run_len: DEFAULT_RUN_LEN,
+            buffer: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn with_run_len(mut self, run_len: usize) -> Self {
+        self.run_len = run_len.max(1);
+        self
+    }
+
+    pub fn push(&mut self, hash: Hash) -> io::Result<()> {
+        self.buffer.push(hash);
+        if self.buffer.len() >= self.run_len {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        self.buffer.sort_unstable();
+        let mut writer = HashListWriter::create(&self.dir)?;
+        for hash in self.buffer.drain(..) {
+            writer.push(hash)?;
+        }
+        self.runs.push(writer.finish()?);
+        Ok(())
+    }
+
+    /// Merge everything pushed into one list
+    pub fn finish(mut self) -> io::Result<HashList> {
+        if !self.buffer.is_empty() || self.runs.is_empty() {
+            self.spill()?;
+        }
+        while self.runs.len() > 1 {
+            let mut merged = Vec::new();
+            for chunk in self.runs.chunks(MAX_MERGE) {
+                let runs: Vec<&HashList> = chunk.iter().collect();
+                merged.push(HashList::union(&runs, &self.dir)?);
+            }
+            self.runs = merged;
+        }
+        Ok(self.runs.pop().unwrap())
+    }
+}
+
+/// K-way merge of sorted streams into `writer`
+fn merge(mut iters: Vec<HashListIter>, writer: &mut HashListWriter) -> io::Result<()> {
+    let mut heap = BinaryHeap::with_capacity(iters.len());
+    for (i, iter) in iters.iter_mut().enumerate() {
+        if let Some(hash) = iter.next() {
+            heap.push(Reverse((hash?, i)));
+        }
+    }
+    while let Some(Reverse((hash, i))) = heap.pop() {
+        writer.push(hash)?;
+        if let Some(next) = iters[i].next() {
+            heap.push(Reverse((next?, i)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(n: u32) -> Hash {
+        let mut hash = [0; 16];
+        hash[12..].copy_from_slice(&n.to_be_bytes());
+        hash
+    }
+
+    fn collect(list: &HashList) -> Vec<Hash> {
+        list.iter().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_sort_and_set_operations() -> io::Result<()> {
+        let temp = tempfile::tempdir()?;
+        let dir = temp.path();
+
+        // Small runs, so the sort spills and merges
+        let mut sorter = HashSorter::new(dir).with_run_len(7);
+        for n in (0..100).rev().chain(0..50) {
+            sorter.push(hash(n))?;
+        }
+        let all = sorter.finish()?;
+        assert_eq!(collect(&all), (0..100).map(hash).collect::<Vec<_>>());
+        assert_eq!(fs::read_dir(dir)?.count(), 1);
+
+        let mut sorter = HashSorter::new(dir).with_run_len(7);
+        for n in (0..100).step_by(3).chain(200..210) {
+            sorter.push(hash(n))?;
+        }
+        let thirds = sorter.finish()?;
+
+        let rest = all.difference(&thirds, dir)?;
+        let expected: Vec<Hash> = (0..100).filter(|n| n % 3 != 0).map(hash).collect();
+        assert_eq!(collect(&rest), expected);
+
+        let union = HashList::union(&[&rest, &thirds], dir)?;
+        assert_eq!(union.len(), 110);
+
+        let kept = union.persist(&dir.join("kept"))?;
+        drop((all, thirds, rest));
+        assert_eq!(HashList::open(&dir.join("kept"))?.len(), 110);
+        assert_eq!(kept.len(), 110);
+        assert_eq!(fs::read_dir(dir)?.count(), 1);
+
+        assert!(HashSorter::new(dir).finish()?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,552 @@
+//! Layered LBA → hash index
+//!
+//! Each target keeps a sled database mapping LBAs to CAS hashes. Cloning
+//! doesn't copy that database: the source's index is frozen as a read-only
+//! layer, and the source and the clone each get an empty delta database
+//! that falls back to the layer on a miss. Layers stack when clones are
+//! cloned, and `flatten` folds a target's layers back into its own
+//! database.
+//!
+//! Sled is the only index engine: the device, `CloneManager` and
+//! `iscsi-clone` all open indexes through this module.
+
+use crate::cas::Hash;
+use sled::Db;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+
+/// Metadata key holding the zero block's hash
+const ZERO_BLOCK_KEY: &[u8] = b"__ZERO_BLOCK__";
+
+/// Metadata key holding the path of the layer below this one
+const PARENT_KEY: &[u8] = b"__PARENT__";
+
+/// Longest layer chain followed before assuming a cycle
+const MAX_DEPTH: usize = 64;
+
+/// Refuse directories written by another engine. Sled would happily
+/// create an empty database inside an old RocksDB index directory, and the
+/// target would come up blank instead of failing.
+fn check_engine(path: &Path) -> io::Result<()> {
+    let is_rocksdb = path.join("CURRENT").is_file()
+        && std::fs::read_dir(path)?
+            .filter_map(Result::ok)
+            .any(|entry| entry.file_name().to_string_lossy().starts_with("MANIFEST-"));
+    if is_rocksdb {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "index at {:?} is a RocksDB database; only sled indexes are supported",
+                path
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Open sled databases by path. Sled locks a database per process, and
+/// many clones share the same layers, so each path is opened only once.
+fn open_shared(path: &Path) -> io::Result<Arc<Db>> {
+    static OPEN: OnceLock<Mutex<HashMap<PathBuf, Weak<Db>>>> = OnceLock::new();
+    let mut open = OPEN.get_or_init(Default::default).lock().unwrap();
+
+    if let Some(db) = open.get(path).and_then(Weak::upgrade) {
+        return Ok(db);
+    }
+    if path.is_dir() {
+        check_engine(path)?;
+    }
+    let db = Arc::new(sled::open(path).map_err(io::Error::other)?);
+    open.retain(|_, db| db.strong_count() > 0);
+    open.insert(path.to_path_buf(), Arc::downgrade(&db));
+    Ok(db)
+}
+
+fn parent_of(db: &Db) -> io::Result<Option<PathBuf>> {
+    let parent = db.get(PARENT_KEY).map_err(io::Error::other)?;
+    Ok(parent.map(|path| PathBuf::from(String::from_utf8_lossy(&path).into_owned())))
+}
+
+fn decode_hash(value: &[u8]) -> io::Result<Hash> {
+    value
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid hash size"))
+}
+
+/// LBA entries are the 8-byte keys; longer keys are metadata
+fn is_lba_key(key: &[u8]) -> bool {
+    key.len() == 8
+}
+
+/// Entries as they were before a snapshot began, for the keys written
+/// since (None for keys that didn't exist)
+type Preimages = HashMap<[u8; 8], Option<sled::IVec>>;
+
+/// Persistent index of LBA to hash mappings
+pub struct LbaIndex {
+    db: Arc<Db>,
+    /// Read-only layers below `db`, nearest first
+    layers: Vec<Arc<Db>>,
+    /// Set while a snapshot is being copied
+    preimages: Arc<Mutex<Option<Preimages>>>,
+    pub zero_block_hash: Hash,
+}
+
+impl LbaIndex {
+    /// Open an index and its layers, initializing a new one with the hash
+    /// returned by `zero_block`
+    pub fn open_or_create<F>(path: &Path, zero_block: F) -> io::Result<Self>
+    where
+        F: FnOnce() -> io::Result<Hash>,
+    {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let db = open_shared(path)?;
+
+        let zero_block_hash = match db.get(ZERO_BLOCK_KEY).map_err(io::Error::other)? {
+            Some(hash) => decode_hash(&hash)?,
+            None => {
+                log::info!("Initializing new index at {:?}", path);
+                let hash = zero_block()?;
+                db.insert(ZERO_BLOCK_KEY, &hash).map_err(io::Error::other)?;
+                hash
+            }
+        };
+
+        Self::with_layers(path, db, zero_block_hash)
+    }
+
+    /// Open an existing index, or None if it has never been written
+    pub fn open(path: &Path) -> io::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let db = open_shared(path)?;
+        match db.get(ZERO_BLOCK_KEY).map_err(io::Error::other)? {
+            Some(hash) => {
+                let zero_block_hash = decode_hash(&hash)?;
+                Self::with_layers(path, db, zero_block_hash).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn with_layers(path: &Path, db: Arc<Db>, zero_block_hash: Hash) -> io::Result<Self> {
+        let mut layers = Vec::new();
+        let mut next = parent_of(&db)?;
+        while let Some(layer_path) = next {
+            if layers.len() >= MAX_DEPTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("index layer chain under {:?} is too deep", path),
+                ));
+            }
+            let layer = open_shared(&layer_path)?;
+            next = parent_of(&layer)?;
+            layers.push(layer);
+        }
+        if !layers.is_empty() {
+            log::info!("Index {:?} has {} shared layer(s)", path, layers.len());
+        }
+
+        Ok(Self {
+            db,
+            layers,
+            preimages: Arc::default(),
+            zero_block_hash,
+        })
+    }
+
+    /// Look up an LBA in this index, then in each layer
+    pub fn get(&self, lba: u64) -> io::Result<Option<Hash>> {
+        let key = lba.to_le_bytes();
+        for db in std::iter::once(&self.db).chain(&self.layers) {
+            if let Some(value) = db.get(key).map_err(io::Error::other)? {
+                return decode_hash(&value).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record an LBA's hash; layers are never written
+    pub fn insert(&self, lba: u64, hash: &Hash) -> io::Result<()> {
+        self.keep_preimages(&[lba])?;
+        self.db
+            .insert(lba.to_le_bytes(), hash)
+            .map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Record several LBAs' hashes atomically: after a crash the index
+    /// has all of them or none
+    pub fn insert_batch(&self, entries: &[(u64, Hash)]) -> io::Result<()> {
+        let lbas: Vec<u64> = entries.iter().map(|(lba, _)| *lba).collect();
+        self.keep_preimages(&lbas)?;
+        let mut batch = sled::Batch::default();
+        for (lba, hash) in entries {
+            batch.insert(&lba.to_le_bytes(), hash);
+        }
+        self.db.apply_batch(batch).map_err(io::Error::other)
+    }
+
+    /// Make the entries inserted so far durable
+    pub fn flush(&self) -> io::Result<()> {
+        self.db.flush().map_err(io::Error::other)?;
+        Ok(())
+    }
+
+    /// Number of layers below this index
+    pub fn depth(&self) -> usize {
+        self.layers.len()
+    }
+
+    /// LBA entries stored in this index itself, excluding layers
+    pub fn own_entries(&self) -> usize {
+        self.db
+            .iter()
+            .keys()
+            .filter(|key| key.as_ref().is_ok_and(|key| is_lba_key(key)))
+            .count()
+    }
+
+    /// While a snapshot is being copied, keep the entries `lbas` had when
+    /// it began before they are overwritten
+    fn keep_preimages(&self, lbas: &[u64]) -> io::Result<()> {
+        let mut preimages = self.preimages.lock().unwrap();
+        let Some(preimages) = preimages.as_mut() else {
+            return Ok(());
+        };
+        for lba in lbas {
+            let key = lba.to_le_bytes();
+            if !preimages.contains_key(&key) {
+                let old = self.db.get(key).map_err(io::Error::other)?;
+                preimages.insert(key, old);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fix this index's current contents for a point-in-time copy. The
+    /// copy is made by `SnapshotCopy::write_to`, which doesn't hold up
+    /// writes: until it is done, entries are kept aside before being
+    /// overwritten. The caller must hold off writes until this returns.
+    pub fn begin_snapshot(&self) -> io::Result<SnapshotCopy> {
+        let mut preimages = self.preimages.lock().unwrap();
+        if preimages.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                "a snapshot of this index is already being taken",
+            ));
+        }
+        *preimages = Some(Preimages::new());
+        Ok(SnapshotCopy {
+            db: Arc::clone(&self.db),
+            preimages: Arc::clone(&self.preimages),
+        })
+    }
+
+    /// Every hash reachable from this index, layers included
+    pub fn hashes(&self) -> io::Result<HashSet<Hash>> {
+        let mut hashes = HashSet::new();
+        self.for_each_hash(|hash| {
+            hashes.insert(hash);
+            Ok(())
+        })?;
+        Ok(hashes)
+    }
+
+    /// Call `f` with each hash reachable from this index, layers included,
+    /// without collecting them. A hash used by several LBAs or layers is
+    /// passed once for each.
+    pub fn for_each_hash<F>(&self, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(Hash) -> io::Result<()>,
+    {
+        for db in std::iter::once(&self.db).chain(&self.layers) {
+            for entry in db.iter() {
+                let (key, value) = entry.map_err(io::Error::other)?;
+                if is_lba_key(&key) {
+                    f(decode_hash(&value)?)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A point-in-time copy of an index in progress, from `begin_snapshot`
+pub struct SnapshotCopy {
+    db: Arc<Db>,
+    preimages: Arc<Mutex<Option<Preimages>>>,
+}
+
+impl SnapshotCopy {
+    /// Copy the index's own entries (not its layers) as they were when the
+    /// snapshot began to a new layer at `layer_path` that shares the same
+    /// layers below it, returning how many LBA entries were copied
+    pub fn write_to(self, layer_path: &Path) -> io::Result<usize> {
+        if layer_path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("layer {:?} already exists", layer_path),
+            ));
+        }
+        if let Some(parent) = layer_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let layer = open_shared(layer_path)?;
+        let mut copied = 0;
+        for entry in self.db.iter() {
+            let (key, value) = entry.map_err(io::Error::other)?;
+            // Looked up after reading the entry: a write that replaced it
+            // kept the old value first
+            let value = match <[u8; 8]>::try_from(key.as_ref()) {
+                Ok(lba) => match self.preimages.lock().unwrap().as_ref().unwrap().get(&lba) {
+                    Some(old) => old.clone(),
+                    None => Some(value),
+                },
+                Err(_) => Some(value),
+            };
+            let Some(value) = value else {
+                continue;
+            };
+            layer.insert(&key, value).map_err(io::Error::other)?;
+            if is_lba_key(&key) {
+                copied += 1;
+            }
+        }
+        layer.flush().map_err(io::Error::other)?;
+        Ok(copied)
+    }
+}
+
+impl Drop for SnapshotCopy {
+    fn drop(&mut self) {
+        *self.preimages.lock().unwrap() = None;
+    }
+}
+
+/// Freeze the index at `path` as a layer at `layer_path`, and give it and
+/// each of `clones` an empty index on top of that layer.
+///
+/// The index must not be in use. An index that was never written has
+/// nothing to share, so the clones simply start empty.
+pub fn fork(path: &Path, layer_path: &Path, clones: &[&Path]) -> io::Result<()> {
+    let zero_block_hash = {
+        let db = open_shared(path)?;
+        let hash = db.get(ZERO_BLOCK_KEY).map_err(io::Error::other)?;
+        db.flush().map_err(io::Error::other)?;
+        hash
+    };
+    let Some(zero_block_hash) = zero_block_hash else {
+        for clone in clones {
+            std::fs::create_dir_all(clone)?;
+        }
+        return Ok(());
+    };
+
+    if let Some(parent) = layer_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(path, layer_path)?;
+    let layer_path = layer_path.canonicalize()?;
+
+    for delta in std::iter::once(&path).chain(clones) {
+        create_delta(delta, &layer_path, &zero_block_hash)?;
+    }
+    Ok(())
+}
+
+/// Create an empty index at `path` on top of an existing layer
+pub fn attach(path: &Path, layer_path: &Path) -> io::Result<()> {
+    let layer = open_shared(layer_path)?;
+    let zero_block_hash = layer
+        .get(ZERO_BLOCK_KEY)
+        .map_err(io::Error::other)?
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("layer {:?} has no zero block hash", layer_path),
+            )
+        })?;
+    drop(layer);
+    create_delta(path, &layer_path.canonicalize()?, &zero_block_hash)
+}
+
+fn create_delta(path: &Path, layer_path: &Path, zero_block_hash: &[u8]) -> io::Result<()> {
+    let db = open_shared(path)?;
+    db.insert(ZERO_BLOCK_KEY, zero_block_hash)
+        .map_err(io::Error::other)?;
+    db.insert(PARENT_KEY, layer_path.to_string_lossy().as_bytes())
+        .map_err(io::Error::other)?;
+    db.flush().map_err(io::Error::other)?;
+    Ok(())
+}
+
+/// Paths of the layers an index at `path` reads through, nearest first.
+/// Works for layers as well as targets' indexes.
+pub fn layer_chain(path: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut chain = Vec::new();
+    if !path.exists() {
+        return Ok(chain);
+    }
+    let mut next = parent_of(&open_shared(path)?)?;
+    while let Some(layer_path) = next {
+        if chain.len() >= MAX_DEPTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("index layer chain under {:?} is too deep", path),
+            ));
+        }
+        next = parent_of(&open_shared(&layer_path)?)?;
+        chain.push(layer_path);
+    }
+    Ok(chain)
+}
+
+/// Copy every mapping visible through the index's layers into the index
+/// itself and detach it from them, returning how many entries were copied.
+///
+/// The layers are left in place for other clones that still use them.
+pub fn flatten(path: &Path) -> io::Result<usize> {
+    let Some(index) = LbaIndex::open(path)? else {
+        return Ok(0);
+    };
+
+    let mut copied = 0;
+    for layer in &index.layers {
+        for entry in layer.iter() {
+            let (key, value) = entry.map_err(io::Error::other)?;
+            if !is_lba_key(&key) {
+                continue;
+            }
+            let inserted = index
+                .db
+                .compare_and_swap(&key, None as Option<&[u8]>, Some(value))
+                .map_err(io::Error::other)?;
+            if inserted.is_ok() {
+                copied += 1;
+            }
+        }
+    }
+
+    index.db.remove(PARENT_KEY).map_err(io::Error::other)?;
+    index.flush()?;
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn hash(byte: u8) -> Hash {
+        [byte; 16]
+    }
+
+    #[test]
+    fn test_clone_shares_layer_and_flattens() {
+        let temp = TempDir::new().unwrap();
+        let base = temp.path().join("base/index");
+        let clone = temp.path().join("clone/index");
+        let layer = temp.path().join(".layers/base-1");
+
+        {
+            let index = LbaIndex::open_or_create(&base, || Ok(hash(0))).unwrap();
+            index.insert(1, &hash(1)).unwrap();
+            index.insert(2, &hash(2)).unwrap();
+            index.flush().unwrap();
+        }
+        fork(&base, &layer, &[&clone]).unwrap();
+
+        let open = |path: &Path| LbaIndex::open(path).unwrap().unwrap();
+        {
+            let base = open(&base);
+            let clone = open(&clone);
+            assert_eq!(clone.depth(), 1);
+            assert_eq!(clone.own_entries(), 0);
+            assert_eq!(clone.zero_block_hash, hash(0));
+            assert_eq!(clone.get(1).unwrap(), Some(hash(1)));
+
+            // Writes to either side stay private
+            clone.insert(1, &hash(9)).unwrap();
+            base.insert(2, &hash(8)).unwrap();
+            assert_eq!(clone.get(1).unwrap(), Some(hash(9)));
+            assert_eq!(clone.get(2).unwrap(), Some(hash(2)));
+            assert_eq!(base.get(1).unwrap(), Some(hash(1)));
+            assert_eq!(base.get(2).unwrap(), Some(hash(8)));
+            assert_eq!(clone.get(3).unwrap(), None);
+            clone.flush().unwrap();
+        }
+
+        assert_eq!(flatten(&clone).unwrap(), 1);
+        let clone = open(&clone);
+        assert_eq!(clone.depth(), 0);
+        assert_eq!(clone.own_entries(), 2);
+        assert_eq!(clone.get(1).unwrap(), Some(hash(9)));
+        assert_eq!(clone.get(2).unwrap(), Some(hash(2)));
+        assert_eq!(clone.hashes().unwrap(), HashSet::from([hash(9), hash(2)]));
+    }
+
+    #[test]
+    fn test_snapshot_is_point_in_time() {
+        let temp = TempDir::new().unwrap();
+        let index = LbaIndex::open_or_create(&temp.path().join("index"), || Ok(hash(0))).unwrap();
+        index.insert(1, &hash(1)).unwrap();
+        index.insert(2, &hash(2)).unwrap();
+
+        let copy = index.begin_snapshot().unwrap();
+        assert!(index.begin_snapshot().is_err());
+        // Written while the copy is being made
+        index.insert(1, &hash(7)).unwrap();
+        index.insert_batch(&[(2, hash(8)), (3, hash(9))]).unwrap();
+        let layer_path = temp.path().join(".layers/index-1");
+        assert_eq!(copy.write_to(&layer_path).unwrap(), 2);
+
+        let clone_path = temp.path().join("clone");
+        attach(&clone_path, &layer_path).unwrap();
+        let clone = LbaIndex::open(&clone_path).unwrap().unwrap();
+        assert_eq!(clone.get(1).unwrap(), Some(hash(1)));
+        assert_eq!(clone.get(2).unwrap(), Some(hash(2)));
+        assert_eq!(clone.get(3).unwrap(), None);
+        assert_eq!(index.get(1).unwrap(), Some(hash(7)));
+
+        // Writes stop keeping entries aside once the copy is done
+        assert!(index.preimages.lock().unwrap().is_none());
+        assert!(index.begin_snapshot().is_ok());
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("index");
+        {
+            let index = LbaIndex::open_or_create(&path, || Ok(hash(0))).unwrap();
+            index.insert(1, &hash(1)).unwrap();
+            index.insert_batch(&[(1, hash(7)), (2, hash(8)), (9, hash(9))]).unwrap();
+            index.flush().unwrap();
+        }
+        let index = LbaIndex::open(&path).unwrap().unwrap();
+        assert_eq!(index.own_entries(), 3);
+        assert_eq!(index.get(1).unwrap(), Some(hash(7)));
+        assert_eq!(index.get(9).unwrap(), Some(hash(9)));
+    }
+
+    #[test]
+    fn test_refuses_rocksdb_index() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("index");
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("CURRENT"), "MANIFEST-000004\n").unwrap();
+        std::fs::write(path.join("MANIFEST-000004"), b"").unwrap();
+
+        let err = LbaIndex::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(LbaIndex::open_or_create(&path, || Ok(hash(0))).is_err());
+        assert!(!path.join("conf").exists());
+    }
+}
@@ -0,0 +1,379 @@
+//! iSNS registration (RFC 4171)
+//!
+//! With an iSNS server configured, `iscsi-server` registers a network
+//! entity holding its portal and every target, so Windows and ESXi
+//! initiators pointed at the iSNS server find the targets without
+//! knowing our address. Registrations lapse after `REGISTRATION_PERIOD`,
+//! so they are refreshed well before then, and the entity is deregistered
+//! on shutdown.
+//!
+//! Each exchange opens a short TCP connection to the server. iSNS only
+//! describes iSCSI and iFCP storage, so AoE targets are not registered.
+
+use crate::shutdown;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Well-known iSNS port
+pub const ISNS_PORT: u16 = 3205;
+
+/// How long the server keeps a registration without a refresh
+pub const REGISTRATION_PERIOD: Duration = Duration::from_secs(900);
+
+/// Time between refreshes
+pub const REFRESH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Connect, send and receive timeout for one exchange
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+const ISNSP_VERSION: u16 = 0x0001;
+const HEADER_LEN: usize = 12;
+
+// Function IDs (RFC 4171); a response has the high bit set
+const DEV_ATTR_REG: u16 = 0x0001;
+const DEV_DEREG: u16 = 0x0004;
+const RESPONSE: u16 = 0x8000;
+
+// Header flags
+const FLAG_CLIENT: u16 = 0x8000;
+const FLAG_REPLACE: u16 = 0x1000;
+const FLAG_LAST_PDU: u16 = 0x0800;
+const FLAG_FIRST_PDU: u16 = 0x0400;
+
+// Attribute tags
+const TAG_DELIMITER: u32 = 0;
+const TAG_ENTITY_IDENTIFIER: u32 = 1;
+const TAG_ENTITY_PROTOCOL: u32 = 2;
+const TAG_REGISTRATION_PERIOD: u32 = 6;
+const TAG_PORTAL_IP_ADDRESS: u32 = 16;
+const TAG_PORTAL_PORT: u32 = 17;
+const TAG_ISCSI_NAME: u32 = 32;
+const TAG_ISCSI_NODE_TYPE: u32 = 33;
+const TAG_ISCSI_ALIAS: u32 = 34;
+
+const ENTITY_PROTOCOL_ISCSI: u32 = 2;
+const NODE_TYPE_TARGET: u32 = 1;
+
+/// A target to register
+#[derive(Debug, Clone)]
+pub struct IsnsTarget {
+    pub iqn: String,
+    pub alias: Option<String>,
+}
+
+/// Registers one network entity with an iSNS server
+pub struct IsnsClient {
+    server: String,
+    entity: String,
+    /// Address initiators connect to; an unspecified IP is replaced by
+    /// the address we reach the iSNS server from
+    portal: SocketAddr,
+    targets: Vec<IsnsTarget>,
+    transaction: u16,
+}
+
+impl IsnsClient {
+    /// `server` is `host` or `host:port`
+    pub fn new(server: &str, entity: &str, portal: SocketAddr, targets: Vec<IsnsTarget>) -> Self {
+        Self {
+            server: with_default_port(server),
+            entity: entity.to_string(),
+            portal,
+            targets,
+            transaction: 0,
+        }
+    }
+
+    /// Register (or re-register) the entity, its portal and targets
+    pub fn register(&mut self) -> io::Result<()> {
+        let mut stream = self.connect()?;
+        let portal_ip = match self.portal.ip() {
+            ip if ip.is_unspecified() => stream.local_addr()?.ip(),
+            ip => ip,
+        };
+
+        let mut message = Vec::new();
+        self.source(&mut message);
+        put_string(&mut message, TAG_ENTITY_IDENTIFIER, &self.entity);
+        put(&mut message, TAG_DELIMITER, &[]);
+        put_string(&mut message, TAG_ENTITY_IDENTIFIER, &self.entity);
+        put_u32(&mut message, TAG_ENTITY_PROTOCOL, ENTITY_PROTOCOL_ISCSI);
+        put_u32(&mut message, TAG_REGISTRATION_PERIOD, REGISTRATION_PERIOD.as_secs() as u32);
+        put(&mut message, TAG_PORTAL_IP_ADDRESS, &ipv6(portal_ip).octets());
+        put_u32(&mut message, TAG_PORTAL_PORT, self.portal.port() as u32);
+        for target in &self.targets {
+            put_string(&mut message, TAG_ISCSI_NAME, &target.iqn);
+            put_u32(&mut message, TAG_ISCSI_NODE_TYPE, NODE_TYPE_TARGET);
+            if let Some(alias) = &target.alias {
+                put_string(&mut message, TAG_ISCSI_ALIAS, alias);
+            }
+        }
+        self.exchange(&mut stream, DEV_ATTR_REG, FLAG_REPLACE, &message)
+    }
+
+    /// Remove the entity, and with it the portal and targets
+    pub fn deregister(&mut self) -> io::Result<()> {
+        let mut stream = self.connect()?;
+        let mut message = Vec::new();
+        self.source(&mut message);
+        put(&mut message, TAG_DELIMITER, &[]);
+        put_string(&mut message, TAG_ENTITY_IDENTIFIER, &self.entity);
+        self.exchange(&mut stream, DEV_DEREG, 0, &message)
+    }
+
+    /// The Source attribute: a node of the entity being changed
+    fn source(&self, message: &mut Vec<u8>) {
+        let name = self.targets.first().map_or(self.entity.as_str(), |t| t.iqn.as_str());
+        put_string(message, TAG_ISCSI_NAME, name);
+    }
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let addr = self.server.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", self.server))
+        })?;
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        Ok(stream)
+    }
+
+    /// Send a single-PDU request and check the response's status
+    fn exchange(
+        &mut self,
+        stream: &mut TcpStream,
+        function: u16,
+        flags: u16,
+        message: &[u8],
+    ) -> io::Result<()> {
+        self.transaction = self.transaction.wrapping_add(1);
+        let length = u16::try_from(message.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "iSNS message too long"))?;
+        let flags = flags | FLAG_CLIENT | FLAG_FIRST_PDU | FLAG_LAST_PDU;
+        let mut pdu = Vec::with_capacity(HEADER_LEN + message.len());
+        for field in [ISNSP_VERSION, function, length, flags, self.transaction, 0] {
+            pdu.extend_from_slice(&field.to_be_bytes());
+        }
+        pdu.extend_from_slice(message);
+        stream.write_all(&pdu)?;
+
+        let mut header = [0u8; HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+        let mut payload = vec![0u8; field(4) as usize];
+        stream.read_exact(&mut payload)?;
+        if field(2) != function | RESPONSE || field(8) != self.transaction || payload.len() < 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected iSNS response",
+            ));
+        }
+        match u32::from_be_bytes(payload[..4].try_into().unwrap()) {
+            0 => Ok(()),
+            status => Err(io::Error::other(format!(
+                "iSNS server refused request: status {}",
+                status
+            ))),
+        }
+    }
+}
+
+/// Add the well-known port to a server address that has none
+fn with_default_port(server: &str) -> String {
+    if let Ok(ip) = server.parse::<IpAddr>() {
+        return SocketAddr::new(ip, ISNS_PORT).to_string();
+    }
+    match server.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => server.to_string(),
+        _ => format!("{}:{}", server, ISNS_PORT),
+    }
+}
+
+/// Portal addresses are always 16 bytes, IPv4 mapped into IPv6
+fn ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(v4) => v4.to_ipv6_mapped(),
+        IpAddr::V6(v6) => v6,
+    }
+}
+
+/// Append a TLV attribute, padding its value to 4 bytes
+fn put(message: &mut Vec<u8>, tag: u32, value: &[u8]) {
+    let padded = value.len().div_ceil(4) * 4;
+    message.extend_from_slice(&tag.to_be_bytes());
+    message.extend_from_slice(&(padded as u32).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len() + padded - value.len(), 0);
+}
+
+fn put_u32(message: &mut Vec<u8>, tag: u32, value: u32) {
+    put(message, tag, &value.to_be_bytes());
+}
+
+/// Strings are NUL-terminated
+fn put_string(message: &mut Vec<u8>, tag: u32, value: &str) {
+    let mut bytes = value.as_bytes().to_vec();
+    bytes.push(0);
+    put(message, tag, &bytes);
+}
+
+/// Keeps a registration fresh until dropped, then deregisters
+pub struct IsnsRegistration {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl IsnsRegistration {
+    /// Register in the background, retrying failures at each refresh, so
+    /// an unreachable iSNS server doesn't hold up serving
+    pub fn start(mut client: IsnsClient) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+        let thread = thread::spawn(move || {
+            let mut registered = false;
+            loop {
+                match client.register() {
+                    Ok(()) if !registered => {
+                        log::info!("Registered with iSNS server {}", client.server);
+                        registered = true;
+                    }
+                    Ok(()) => {}
+                    Err(e) => {
+                        log::warn!("iSNS registration with {} failed: {}", client.server, e);
+                        registered = false;
+                    }
+                }
+                let next = Instant::now() + REFRESH_INTERVAL;
+                while Instant::now() < next && !stopped.load(Ordering::SeqCst) {
+                    thread::sleep(shutdown::POLL_INTERVAL);
+                }
+                if stopped.load(Ordering::SeqCst) {
+                    break;
+                }
+            }
+            if registered {
+                match client.deregister() {
+                    Ok(()) => log::info!("Deregistered from iSNS server {}", client.server),
+                    Err(e) => log::warn!("iSNS deregistration failed: {}", e),
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for IsnsRegistration {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, TcpListener};
+
+    /// Function ID and attributes of a request
+    type Request = (u16, Vec<(u32, Vec<u8>)>);
+
+    /// Accept `count` requests, answering each with `status`
+    fn fake_server(listener: TcpListener, count: usize, status: u32) -> Vec<Request> {
+        let mut requests = Vec::new();
+        for _ in 0..count {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut header = [0u8; HEADER_LEN];
+            stream.read_exact(&mut header).unwrap();
+            let field = |i: usize| u16::from_be_bytes([header[i], header[i + 1]]);
+            assert_eq!(field(0), ISNSP_VERSION);
+            assert_ne!(field(6) & FLAG_CLIENT, 0);
+            let mut payload = vec![0u8; field(4) as usize];
+            stream.read_exact(&mut payload).unwrap();
+
+            let mut attributes = Vec::new();
+            let mut rest = &payload[..];
+            while !rest.is_empty() {
+                let tag = u32::from_be_bytes(rest[..4].try_into().unwrap());
+                let len = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+                attributes.push((tag, rest[8..8 + len].to_vec()));
+                rest = &rest[8 + len..];
+            }
+            requests.push((field(2), attributes));
+
+            let mut response = Vec::new();
+            for value in [ISNSP_VERSION, field(2) | RESPONSE, 4, 0, field(8), 0] {
+                response.extend_from_slice(&value.to_be_bytes());
+            }
+            response.extend_from_slice(&status.to_be_bytes());
+            stream.write_all(&response).unwrap();
+        }
+        requests
+    }
+
+    #[test]
+    fn test_isns_register_and_deregister() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || fake_server(listener, 3, 0));
+
+        let targets = vec![
+            IsnsTarget {
+                iqn: "iqn.2025-12.local.voe:a".to_string(),
+                alias: Some("disk a".to_string()),
+            },
+            IsnsTarget {
+                iqn: "iqn.2025-12.local.voe:b".to_string(),
+                alias: None,
+            },
+        ];
+        let portal: SocketAddr = "0.0.0.0:3260".parse().unwrap();
+        let mut client = IsnsClient::new(&server, "voe-test", portal, targets);
+        client.register().unwrap();
+        client.register().unwrap();
+        client.deregister().unwrap();
+
+        let requests = handle.join().unwrap();
+        // Function IDs as on the wire: DevAttrReg, then DevDereg
+        let (function, attributes) = &requests[0];
+        assert_eq!(*function, 0x0001);
+        let tags: Vec<u32> = attributes.iter().map(|(tag, _)| *tag).collect();
+        assert_eq!(tags, vec![32, 1, 0, 1, 2, 6, 16, 17, 32, 33, 34, 32, 33]);
+        assert_eq!(attributes[0].1, b"iqn.2025-12.local.voe:a\0");
+        // The unspecified bind address became the address facing the server
+        let ip: [u8; 16] = attributes[6].1[..].try_into().unwrap();
+        assert_eq!(Ipv6Addr::from(ip), Ipv4Addr::LOCALHOST.to_ipv6_mapped());
+        assert_eq!(attributes[7].1, 3260u32.to_be_bytes());
+        // "disk a\0" padded to 8 bytes
+        assert_eq!(attributes[10].1, b"disk a\0\0");
+
+        let (function, attributes) = &requests[2];
+        assert_eq!(*function, 0x0004);
+        assert_eq!(attributes[2], (TAG_ENTITY_IDENTIFIER, b"voe-test\0\0\0\0".to_vec()));
+    }
+
+    #[test]
+    fn test_isns_refusal() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = listener.local_addr().unwrap().to_string();
+        let handle = thread::spawn(move || fake_server(listener, 1, 3));
+
+        let portal: SocketAddr = "192.0.2.1:3260".parse().unwrap();
+        let mut client = IsnsClient::new(&server, "voe-test", portal, Vec::new());
+        let err = client.register().unwrap_err();
+        assert!(err.to_string().contains("status 3"), "{}", err);
+        handle.join().unwrap();
+
+        assert_eq!(with_default_port("isns.example"), "isns.example:3205");
+        assert_eq!(with_default_port("isns.example:3300"), "isns.example:3300");
+        assert_eq!(with_default_port("::1"), "[::1]:3205");
+        assert_eq!(with_default_port("[::1]"), "[::1]:3205");
+    }
+}
@@ -0,0 +1,162 @@
+//! Write-ahead journal for the CAS device write cache
+//!
+//! `CasScsiDevice` acknowledges writes once they are in its RAM cache and
+//! only pushes them to CAS in batches. The iSCSI layer doesn't pass
+//! SYNCHRONIZE CACHE or FUA down to the device, so instead every write is
+//! appended here and fsynced before it is acknowledged. After a crash the
+//! journal is replayed into the cache on startup; after each successful
+//! flush to CAS it is truncated.
+//!
+//! Record layout (little-endian):
+//! `[magic u32][lba u64][len u32][xxh3-64 of lba+len+data u64][data]`
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::Xxh3;
+
+const RECORD_MAGIC: u32 = 0x4A45_4F56; // "VOEJ"
+const HEADER_LEN: usize = 24;
+
+/// A journaled write: starting LBA and the data as written
+pub type JournalRecord = (u64, Vec<u8>);
+
+/// Append-only, fsynced journal of acknowledged writes
+pub struct WriteJournal {
+    file: File,
+    path: PathBuf,
+}
+
+impl WriteJournal {
+    /// Open (or create) a journal, returning the writes it holds.
+    ///
+    /// A torn or corrupt record ends the replay: the initiator never saw
+    /// that write acknowledged, so it and anything after it are dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Vec<JournalRecord>)> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let (records, valid_len) = read_records(&mut file)?;
+        if valid_len < file.metadata()?.len() {
+            log::warn!(
+                "Discarding torn tail of write journal {:?} after {} record(s)",
+                path,
+                records.len()
+            );
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        Ok((Self { file, path }, records))
+    }
+
+    /// Append a write and fsync it
+    pub fn append(&mut self, lba: u64, data: &[u8]) -> io::Result<()> {
+        let mut record = Vec::with_capacity(HEADER_LEN + data.len());
+        record.extend_from_slice(&RECORD_MAGIC.to_le_bytes());
+        record.extend_from_slice(&lba.to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(&checksum(lba, data).to_le_bytes());
+        record.extend_from_slice(data);
+
+        self.file.write_all(&record)?;
+        self.file.sync_data()
+    }
+
+    /// Drop all records once their writes are safely in CAS
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn checksum(lba: u64, data: &[u8]) -> u64 {
+    let mut hasher = Xxh3::new();
+    hasher.update(&lba.to_le_bytes());
+    hasher.update(&(data.len() as u32).to_le_bytes());
+    hasher.update(data);
+    hasher.digest()
+}
+
+/// Read valid records from the start of the file, returning them and the
+/// length of the valid prefix
+fn read_records(file: &mut File) -> io::Result<(Vec<JournalRecord>, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut reader = BufReader::new(&*file);
+    let mut records = Vec::new();
+    let mut valid_len = 0u64;
+
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        if reader.read_exact(&mut header).is_err() {
+            break;
+        }
+        let magic = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let lba = u64::from_le_bytes(header[4..12].try_into().unwrap());
+        let len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
+        let sum = u64::from_le_bytes(header[16..24].try_into().unwrap());
+        if magic != RECORD_MAGIC {
+            break;
+        }
+
+        let mut data = Vec::new();
+        (&mut reader).take(len as u64).read_to_end(&mut data)?;
+        if data.len() != len || checksum(lba, &data) != sum {
+            break;
+        }
+
+        valid_len += (HEADER_LEN + len) as u64;
+        records.push((lba, data));
+    }
+
+    Ok((records, valid_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_replay_stops_at_torn_record() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("index.journal");
+
+        {
+            let (mut journal, records) = WriteJournal::open(&path).unwrap();
+            assert!(records.is_empty());
+            journal.append(7, &[0xAA; 4096]).unwrap();
+            journal.append(9, &[0xBB; 8192]).unwrap();
+        }
+
+        // Simulate a crash halfway through a third append
+        let full_len = std::fs::metadata(&path).unwrap().len();
+        {
+            let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+            file.write_all(&RECORD_MAGIC.to_le_bytes()).unwrap();
+            file.write_all(&[0u8; 30]).unwrap();
+        }
+
+        let (mut journal, records) = WriteJournal::open(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0], (7, vec![0xAA; 4096]));
+        assert_eq!(records[1], (9, vec![0xBB; 8192]));
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), full_len);
+
+        journal.clear().unwrap();
+        drop(journal);
+        let (_, records) = WriteJournal::open(&path).unwrap();
+        assert!(records.is_empty());
+    }
+}
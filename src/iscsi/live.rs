@@ -0,0 +1,458 @@
+//! Coordination with a running iSCSI server
+//!
+//! While `iscsi-server` serves a target it keeps a PID lock file in the
+//! target's index directory, so `CloneManager` knows not to touch the
+//! index. To clone a running target, `iscsi-clone` instead asks the server
+//! for a snapshot through a request file next to the index; the server
+//! flushes the device's write cache under the device lock and copies its
+//! index as of that point to a new layer while writes carry on, then
+//! reports back through a result file.
+//!
+//! The GC daemon fences running targets the same way: while a fence file
+//! is next to the index, the server appends the hash of every block it
+//! writes to CAS to a log next to it, before writing the block, so blocks
+//! written during a GC round are never deleted by it. The fence holds the
+//! GC daemon's PID; a server lifts a fence whose daemon has exited, so the
+//! log doesn't grow forever after a daemon dies mid-round.
+
+use super::cas_device::CasScsiFlushHandle;
+use super::clone::is_process_running;
+use crate::cas::Hash;
+use crate::shutdown;
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Lock file (inside the index directory) holding the serving PID
+pub const LOCK_FILE_NAME: &str = ".serving.lock";
+
+/// How long a client waits for the server to take a snapshot
+pub const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn sibling(index_path: &Path, suffix: &str) -> PathBuf {
+    let mut path = index_path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn request_path(index_path: &Path) -> PathBuf {
+    sibling(index_path, ".snapshot-request")
+}
+
+fn result_path(index_path: &Path) -> PathBuf {
+    sibling(index_path, ".snapshot-result")
+}
+
+fn fence_path(index_path: &Path) -> PathBuf {
+    sibling(index_path, ".gc-fence")
+}
+
+/// Written by the server once it is logging written hashes
+fn fenced_path(index_path: &Path) -> PathBuf {
+    sibling(index_path, ".gc-fenced")
+}
+
+fn written_path(index_path: &Path) -> PathBuf {
+    sibling(index_path, ".gc-written")
+}
+
+/// Write a file atomically so the other side never sees it half-written
+fn write_atomic(path: &Path, contents: &str) -> io::Result<()> {
+    let tmp = sibling(path, ".tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(&tmp, path)
+}
+
+/// Marks a target as served by this process until dropped
+pub struct ServingLock {
+    path: PathBuf,
+}
+
+impl ServingLock {
+    /// Take the lock, replacing one left by a process that has exited but
+    /// refusing one held by a running process
+    pub fn acquire(index_path: &Path) -> io::Result<Self> {
+        fs::create_dir_all(index_path)?;
+        let path = index_path.join(LOCK_FILE_NAME);
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&path) {
+                Ok(mut file) => {
+                    file.write_all(std::process::id().to_string().as_bytes())?;
+                    return Ok(Self { path });
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(e) => return Err(e),
+            }
+
+            let holder = fs::read_to_string(&path)?.trim().parse::<u32>().ok();
+            if let Some(pid) = holder {
+                if pid != std::process::id() && is_process_running(pid) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{:?} is already being served by PID {}", index_path, pid),
+                    ));
+                }
+            }
+            log::warn!("Removing stale serving lock {:?} ({:?})", path, holder);
+            fs::remove_file(&path)?;
+        }
+    }
+}
+
+impl Drop for ServingLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Ask the server running the target at `index_path` to snapshot its index
+/// into `layer_path`, waiting up to `timeout` for it to finish
+pub fn request_snapshot(index_path: &Path, layer_path: &Path, timeout: Duration) -> io::Result<()> {
+    let result = result_path(index_path);
+    let _ = fs::remove_file(&result);
+    write_atomic(&request_path(index_path), &layer_path.to_string_lossy())?;
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Ok(outcome) = fs::read_to_string(&result) {
+            let _ = fs::remove_file(&result);
+            return match outcome.strip_prefix("error: ") {
+                Some(message) => Err(io::Error::other(message.to_string())),
+                None => Ok(()),
+            };
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let _ = fs::remove_file(request_path(index_path));
+    Err(io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("server did not snapshot {:?} within {:?}", index_path, timeout),
+    ))
+}
+
+/// Blocks the server at `index_path` writes are logged while held; the
+/// fence is lifted when dropped
+pub struct GcFence {
+    index_path: PathBuf,
+    /// Hashes read from the log so far
+    written: HashSet<Hash>,
+    /// Inode of the log and how far into it has been read
+    log: Option<(u64, u64)>,
+}
+
+impl GcFence {
+    /// Fence the target served at `index_path`, waiting up to `timeout`
+    /// for its server to start logging
+    pub fn raise(index_path: &Path, timeout: Duration) -> io::Result<Self> {
+        let _ = fs::remove_file(fenced_path(index_path));
+        fs::write(fence_path(index_path), std::process::id().to_string())?;
+        let fence = Self {
+            index_path: index_path.to_path_buf(),
+            written: HashSet::new(),
+            log: None,
+        };
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if fenced_path(index_path).exists() {
+                return Ok(fence);
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("server did not fence {:?} within {:?}", index_path, timeout),
+        ))
+    }
+
+    /// Hashes of the blocks written since the fence went up, reading only
+    /// what has been logged since the last call
+    pub fn written(&mut self) -> io::Result<&HashSet<Hash>> {
+        let mut log = match File::open(written_path(&self.index_path)) {
+            Ok(log) => log,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(&self.written),
+            Err(e) => return Err(e),
+        };
+        let metadata = log.metadata()?;
+        // A server restarted under the fence starts a new log
+        let mut read = match self.log {
+            Some((inode, read)) if inode == metadata.ino() => read,
+            _ => 0,
+        };
+        if metadata.len() > read {
+            log.seek(SeekFrom::Start(read))?;
+            let mut tail = Vec::new();
+            log.read_to_end(&mut tail)?;
+            // A hash still being appended is left for the next read
+            let whole = tail.len() - tail.len() % 16;
+            let hashes = tail[..whole]
+                .chunks_exact(16)
+                .map(|hash| -> Hash { hash.try_into().unwrap() });
+            self.written.extend(hashes);
+            read += whole as u64;
+        }
+        self.log = Some((metadata.ino(), read));
+        Ok(&self.written)
+    }
+}
+
+impl Drop for GcFence {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(fence_path(&self.index_path));
+    }
+}
+
+/// Serve snapshot requests and GC fences for the given targets until
+/// shutdown
+pub fn spawn_snapshot_watcher(targets: Vec<(PathBuf, CasScsiFlushHandle)>) -> io::Result<()> {
+    thread::Builder::new()
+        .name("snapshot-watcher".to_string())
+        .spawn(move || {
+            let mut fenced = vec![false; targets.len()];
+            while !shutdown::requested() {
+                for ((index_path, handle), fenced) in targets.iter().zip(&mut fenced) {
+                    handle_request(index_path, handle);
+                    handle_fence(index_path, handle, fenced);
+                }
+                thread::sleep(shutdown::POLL_INTERVAL);
+            }
+        })?;
+    Ok(())
+}
+
+/// Start logging written hashes when a fence appears, stop when it goes
+/// or the GC daemon holding it has exited
+fn handle_fence(index_path: &Path, handle: &CasScsiFlushHandle, fenced: &mut bool) {
+    let Ok(holder) = fs::read_to_string(fence_path(index_path)) else {
+        if *fenced {
+            lift_fence(index_path, handle, fenced);
+        }
+        return;
+    };
+    if let Ok(pid) = holder.trim().parse::<u32>() {
+        if !is_process_running(pid) {
+            log::warn!("GC fence on {:?} abandoned by PID {}", index_path, pid);
+            let _ = fs::remove_file(fence_path(index_path));
+            lift_fence(index_path, handle, fenced);
+            return;
+        }
+    }
+    // Raised again after a GC daemon died holding it, the log starts over
+    if *fenced && fenced_path(index_path).exists() {
+        return;
+    }
+
+    let _ = fs::remove_file(written_path(index_path));
+    match OpenOptions::new().create(true).append(true).open(written_path(index_path)) {
+        Ok(log) => handle.log_written_hashes(Some(log)),
+        Err(e) => {
+            log::error!("Failed to open GC write log for {:?}: {}", index_path, e);
+            return;
+        }
+    }
+    match fs::write(fenced_path(index_path), "") {
+        Ok(()) => {
+            *fenced = true;
+            log::info!("GC fence on {:?} raised", index_path);
+        }
+        Err(e) => {
+            handle.log_written_hashes(None);
+            log::error!("Failed to acknowledge GC fence on {:?}: {}", index_path, e);
+        }
+    }
+}
+
+fn lift_fence(index_path: &Path, handle: &CasScsiFlushHandle, fenced: &mut bool) {
+    handle.log_written_hashes(None);
+    let _ = fs::remove_file(written_path(index_path));
+    let _ = fs::remove_file(fenced_path(index_path));
+    if *fenced {
+        *fenced = false;
+        log::info!("GC fence on {:?} lifted", index_path);
+    }
+}
+
+fn handle_request(index_path: &Path, handle: &CasScsiFlushHandle) {
+    let request = request_path(index_path);
+    let Ok(layer) = fs::read_to_string(&request) else {
+        return;
+    };
+    let _ = fs::remove_file(&request);
+    let layer_path = PathBuf::from(layer.trim());
+
+    let outcome = match handle.snapshot(&layer_path) {
+        Ok(entries) => {
+            log::info!("Snapshot of {:?} taken as {:?} ({} entries)", index_path, layer_path, entries);
+            "ok".to_string()
+        }
+        Err(e) => {
+            log::error!("Snapshot of {:?} failed: {}", index_path, e);
+            format!("error: {}", e)
+        }
+    };
+    if let Err(e) = write_atomic(&result_path(index_path), &outcome) {
+        log::error!("Failed to report snapshot result for {:?}: {}", index_path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{CasServer, CasServerConfig};
+    use crate::iscsi::index::{self, LbaIndex};
+    use crate::iscsi::{CasScsiDevice, CasScsiDeviceConfig};
+    use iscsi_target::ScsiBlockDevice;
+    use std::net::TcpListener;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_snapshot_running_target() {
+        let temp = TempDir::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cas_addr = listener.local_addr().unwrap().to_string();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: cas_addr.clone(),
+            storage_path: temp.path().join("cas").to_string_lossy().into_owned(),
+        })
+        .unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let index_path = temp.path().join("base/index");
+        let mut device = CasScsiDevice::new(CasScsiDeviceConfig {
+            cas_server_addr: cas_addr,
+            capacity_blocks: 64,
+            index_path: index_path.clone(),
+            ..CasScsiDeviceConfig::default()
+        })
+        .unwrap();
+        let _lock = ServingLock::acquire(&index_path).unwrap();
+        assert!(index_path.join(LOCK_FILE_NAME).exists());
+
+        // Still in the write cache when the snapshot is requested
+        device.write(1, &[0x11; 4096], 4096).unwrap();
+
+        let handle = device.flush_handle();
+        let watched = index_path.clone();
+        let server = thread::spawn(move || {
+            while !request_path(&watched).exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            handle_request(&watched, &handle);
+        });
+        let layer = temp.path().join(".layers/base-1");
+        request_snapshot(&index_path, &layer, Duration::from_secs(10)).unwrap();
+        server.join().unwrap();
+
+        // Later writes to the source don't reach the clone
+        device.write(2, &[0x22; 4096], 4096).unwrap();
+        device.flush().unwrap();
+
+        let clone_path = temp.path().join("clone/index");
+        index::attach(&clone_path, &layer).unwrap();
+        let clone = LbaIndex::open(&clone_path).unwrap().unwrap();
+        assert_eq!(clone.depth(), 1);
+        assert!(clone.get(1).unwrap().is_some());
+        assert!(clone.get(2).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_serving_lock_liveness() {
+        let temp = TempDir::new().unwrap();
+        let index_path = temp.path().join("index");
+        let lock_path = index_path.join(LOCK_FILE_NAME);
+
+        // Left by a process that has exited
+        fs::create_dir_all(&index_path).unwrap();
+        fs::write(&lock_path, "999999").unwrap();
+        let lock = ServingLock::acquire(&index_path).unwrap();
+        let pid = std::process::id().to_string();
+        assert_eq!(fs::read_to_string(&lock_path).unwrap(), pid);
+        drop(lock);
+        assert!(!lock_path.exists());
+
+        // Held by a running process
+        let mut holder = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        fs::write(&lock_path, holder.id().to_string()).unwrap();
+        let err = ServingLock::acquire(&index_path).err();
+        holder.kill().unwrap();
+        holder.wait().unwrap();
+        assert_eq!(err.unwrap().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(fs::read_to_string(&lock_path).unwrap(), holder.id().to_string());
+    }
+
+    #[test]
+    fn test_gc_fence_logs_writes() {
+        let temp = TempDir::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cas_addr = listener.local_addr().unwrap().to_string();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: cas_addr.clone(),
+            storage_path: temp.path().join("cas").to_string_lossy().into_owned(),
+        })
+        .unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let index_path = temp.path().join("base/index");
+        let mut device = CasScsiDevice::new(CasScsiDeviceConfig {
+            cas_server_addr: cas_addr,
+            capacity_blocks: 64,
+            index_path: index_path.clone(),
+            ..CasScsiDeviceConfig::default()
+        })
+        .unwrap();
+
+        // Not logged: written before the fence
+        device.write(0, &[0x11; 4096], 4096).unwrap();
+        device.flush().unwrap();
+
+        let handle = device.flush_handle();
+        let watched = index_path.clone();
+        let watcher = handle.clone();
+        let server = thread::spawn(move || {
+            let handle = watcher;
+            let mut fenced = false;
+            while !fenced {
+                handle_fence(&watched, &handle, &mut fenced);
+                thread::sleep(Duration::from_millis(10));
+            }
+            while fence_path(&watched).exists() {
+                thread::sleep(Duration::from_millis(10));
+            }
+            handle_fence(&watched, &handle, &mut fenced);
+        });
+
+        let mut fence = GcFence::raise(&index_path, Duration::from_secs(10)).unwrap();
+        assert!(fence.written().unwrap().is_empty());
+        device.write(1, &[0x22; 4096], 4096).unwrap();
+        device.flush().unwrap();
+        let first = crate::cas::hash(&[0x22; 4096]);
+        assert_eq!(*fence.written().unwrap(), HashSet::from([first]));
+
+        // Later reads pick up only what was appended since
+        device.write(2, &[0x33; 4096], 4096).unwrap();
+        device.flush().unwrap();
+        let second = crate::cas::hash(&[0x33; 4096]);
+        assert_eq!(*fence.written().unwrap(), HashSet::from([first, second]));
+
+        drop(fence);
+        server.join().unwrap();
+        assert!(!written_path(&index_path).exists());
+        assert!(!fenced_path(&index_path).exists());
+
+        // A fence left by a GC daemon that has exited is lifted
+        let mut fenced = false;
+        fs::write(fence_path(&index_path), std::process::id().to_string()).unwrap();
+        handle_fence(&index_path, &handle, &mut fenced);
+        assert!(fenced && written_path(&index_path).exists());
+        fs::write(fence_path(&index_path), "999999").unwrap();
+        handle_fence(&index_path, &handle, &mut fenced);
+        assert!(!fenced);
+        assert!(!fence_path(&index_path).exists());
+        assert!(!written_path(&index_path).exists());
+    }
+}
@@ -2,14 +2,30 @@
 //!
 //! Implements RFC 3720 iSCSI protocol for Windows/Linux block storage access.
 
+pub mod audit;
 pub mod cas_device;
 pub mod clone;
+pub mod discovery;
+pub mod frontend;
+pub mod gc;
+pub mod hashlist;
+pub mod index;
+pub mod isns;
+pub mod journal;
+pub mod live;
 pub mod pdu;
 pub mod registry;
+pub mod scsi;
+pub mod status;
+pub mod traced;
 // pub mod session;  // TODO: Update to use BlockStorage trait methods
 // pub mod target;  // TODO: Implement iSCSI target
 
-pub use cas_device::{CasScsiDevice, CasScsiDeviceConfig};
-pub use clone::CloneManager;
-pub use registry::{TargetRegistry, TargetMetadata};
+pub use audit::{AuditEntry, AuditLog, AuditOperation};
+pub use cas_device::{CasScsiDevice, CasScsiDeviceConfig, CasScsiFlushHandle, DeviceStats};
+pub use clone::{CloneManager, GcReport};
+pub use gc::GcRoundReport;
+pub use frontend::{IscsiFrontend, StorageScsiDevice};
+pub use registry::{TargetRegistry, TargetMetadata, TargetSnapshot};
+pub use traced::TracedScsiDevice;
 // pub use target::{IscsiTarget, IscsiTargetConfig};
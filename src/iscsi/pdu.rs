@@ -1,10 +1,103 @@
 //! iSCSI Protocol Data Unit (PDU) definitions
 //!
-//! Based on RFC 3720
+//! Based on RFC 3720. Once login has negotiated them, a CRC32C header
+//! digest follows the BHS and AHS and a data digest follows the padded
+//! data segment. Digests are written least significant byte first, as
+//! Linux open-iscsi and tgt put them on the wire (and as `iscsi-target`
+//! does), rather than in network order.
 
+use std::collections::HashMap;
 use std::io::{self, Read, Write};
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
 
+/// Size of the Basic Header Segment
+pub const BHS_SIZE: usize = 48;
+
+/// Digest protecting a PDU's header or data segment
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DigestType {
+    #[default]
+    None,
+    Crc32c,
+}
+
+impl DigestType {
+    /// Pick from an initiator's offer, a list in order of preference such
+    /// as "CRC32C,None". None if no offered value is supported.
+    pub fn negotiate(offer: &str) -> Option<Self> {
+        offer.split(',').find_map(|value| match value.trim() {
+            v if v.eq_ignore_ascii_case("CRC32C") => Some(DigestType::Crc32c),
+            v if v.eq_ignore_ascii_case("None") => Some(DigestType::None),
+            _ => None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DigestType::None => "None",
+            DigestType::Crc32c => "CRC32C",
+        }
+    }
+}
+
+/// Digests in force on a connection
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Digests {
+    pub header: DigestType,
+    pub data: DigestType,
+}
+
+impl Digests {
+    /// Negotiate HeaderDigest and DataDigest from login parameters,
+    /// returning the digests and the keys to answer with. An offer with
+    /// nothing supported is answered "Reject" and leaves digests off.
+    pub fn negotiate(params: &HashMap<String, String>) -> (Self, Vec<(String, String)>) {
+        let mut digests = Self::default();
+        let mut response = Vec::new();
+        for (key, slot) in [
+            ("HeaderDigest", &mut digests.header),
+            ("DataDigest", &mut digests.data),
+        ] {
+            let Some(offer) = params.get(key) else {
+                continue;
+            };
+            let answer = match DigestType::negotiate(offer) {
+                Some(digest) => {
+                    *slot = digest;
+                    digest.as_str()
+                }
+                None => "Reject",
+            };
+            response.push((key.to_string(), answer.to_string()));
+        }
+        (digests, response)
+    }
+}
+
+/// CRC32C of `bytes` in wire order
+fn digest(bytes: &[u8]) -> [u8; 4] {
+    crc32c::crc32c(bytes).to_le_bytes()
+}
+
+/// Read a digest and check it against `bytes`
+fn verify_digest<R: Read>(reader: &mut R, bytes: &[u8], what: &str) -> io::Result<()> {
+    let mut received = [0u8; 4];
+    reader.read_exact(&mut received)?;
+    let expected = digest(bytes);
+    if received != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "{} digest mismatch: expected {:#010x}, got {:#010x}",
+                what,
+                u32::from_le_bytes(expected),
+                u32::from_le_bytes(received)
+            ),
+        ));
+    }
+    Ok(())
+}
+
 /// iSCSI Opcode
 #[repr(u8)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -69,7 +162,7 @@ pub struct BasicHeaderSegment {
     pub cmd_sn: u32,
     pub exp_stat_sn: u32,
     pub max_cmd_sn: u32,
-    pub specific: [u8; 12], // Opcode-specific fields
+    pub specific: [u8; 14], // Opcode-specific fields, filling the BHS to 48 bytes
 }
 
 impl BasicHeaderSegment {
@@ -85,7 +178,7 @@ impl BasicHeaderSegment {
             cmd_sn: 0,
             exp_stat_sn: 0,
             max_cmd_sn: 0,
-            specific: [0; 12],
+            specific: [0; 14],
         }
     }
 
@@ -97,13 +190,14 @@ impl BasicHeaderSegment {
         let spec_byte1 = reader.read_u8()?;
         let spec_byte2 = reader.read_u8()?;
 
+        let total_ahs_length = reader.read_u8()?;
+
         // Data segment length is 24-bit
         let dsl_high = reader.read_u8()?;
         let dsl_mid = reader.read_u8()?;
         let dsl_low = reader.read_u8()?;
         let data_segment_length = ((dsl_high as u32) << 16) | ((dsl_mid as u32) << 8) | (dsl_low as u32);
 
-        let total_ahs_length = reader.read_u8()?;
         let lun = reader.read_u64::<BigEndian>()?;
         let initiator_task_tag = reader.read_u32::<BigEndian>()?;
         let target_transfer_tag = reader.read_u32::<BigEndian>()?;
@@ -111,7 +205,7 @@ impl BasicHeaderSegment {
         let exp_stat_sn = reader.read_u32::<BigEndian>()?;
         let max_cmd_sn = reader.read_u32::<BigEndian>()?;
 
-        let mut specific = [0u8; 12];
+        let mut specific = [0u8; 14];
         specific[0] = spec_byte1;
         specific[1] = spec_byte2;
         reader.read_exact(&mut specific[2..])?;
@@ -137,12 +231,12 @@ impl BasicHeaderSegment {
         writer.write_u8(self.specific[0])?;
         writer.write_u8(self.specific[1])?;
 
+        writer.write_u8(self.total_ahs_length)?;
+
         // Data segment length (24-bit big-endian)
         writer.write_u8(((self.data_segment_length >> 16) & 0xff) as u8)?;
         writer.write_u8(((self.data_segment_length >> 8) & 0xff) as u8)?;
         writer.write_u8((self.data_segment_length & 0xff) as u8)?;
-
-        writer.write_u8(self.total_ahs_length)?;
         writer.write_u64::<BigEndian>(self.lun)?;
         writer.write_u32::<BigEndian>(self.initiator_task_tag)?;
         writer.write_u32::<BigEndian>(self.target_transfer_tag)?;
@@ -173,7 +267,14 @@ impl Pdu {
     }
 
     pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
-        let bhs = BasicHeaderSegment::read(reader)?;
+        Self::read_with(reader, Digests::default())
+    }
+
+    /// Read a PDU, checking the digests in force
+    pub fn read_with<R: Read>(reader: &mut R, digests: Digests) -> io::Result<Self> {
+        let mut header = vec![0u8; BHS_SIZE];
+        reader.read_exact(&mut header)?;
+        let bhs = BasicHeaderSegment::read(&mut &header[..])?;
 
         // Read AHS if present
         let ahs_len = bhs.total_ahs_length as usize * 4;
@@ -181,13 +282,25 @@ impl Pdu {
         if ahs_len > 0 {
             reader.read_exact(&mut ahs)?;
         }
+        if digests.header == DigestType::Crc32c {
+            // The header digest covers the BHS and AHS
+            header.extend_from_slice(&ahs);
+            verify_digest(reader, &header, "header")?;
+        }
 
         // Read data segment with padding
         let data_len = bhs.data_segment_length as usize;
         let padded_len = (data_len + 3) & !3; // Round up to 4-byte boundary
-        let mut data = vec![0u8; padded_len];
-        if padded_len > 0 {
-            reader.read_exact(&mut data)?;
+        // Grow with the bytes actually received rather than trusting the
+        // 24-bit length up front
+        let mut data = Vec::new();
+        reader.take(padded_len as u64).read_to_end(&mut data)?;
+        if data.len() < padded_len {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated data segment"));
+        }
+        // The data digest covers the padding too, and is only sent with data
+        if digests.data == DigestType::Crc32c && data_len > 0 {
+            verify_digest(reader, &data, "data")?;
         }
         data.truncate(data_len); // Remove padding
 
@@ -195,22 +308,34 @@ impl Pdu {
     }
 
     pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
-        self.bhs.write(writer)?;
+        self.write_with(writer, Digests::default())
+    }
+
+    /// Write a PDU with the digests in force
+    pub fn write_with<W: Write>(&self, writer: &mut W, digests: Digests) -> io::Result<()> {
+        let mut header = Vec::with_capacity(BHS_SIZE + self.ahs.len() + 4);
+        self.bhs.write(&mut header)?;
 
         // Write AHS if present
-        if !self.ahs.is_empty() {
-            writer.write_all(&self.ahs)?;
+        header.extend_from_slice(&self.ahs);
+        if digests.header == DigestType::Crc32c {
+            let header_digest = digest(&header);
+            header.extend_from_slice(&header_digest);
         }
+        writer.write_all(&header)?;
 
         // Write data segment with padding
         if !self.data.is_empty() {
-            writer.write_all(&self.data)?;
-
             // Add padding to 4-byte boundary
             let padding = (4 - (self.data.len() % 4)) % 4;
-            for _ in 0..padding {
-                writer.write_u8(0)?;
+            let mut data = Vec::with_capacity(self.data.len() + padding + 4);
+            data.extend_from_slice(&self.data);
+            data.resize(self.data.len() + padding, 0);
+            if digests.data == DigestType::Crc32c {
+                let data_digest = digest(&data);
+                data.extend_from_slice(&data_digest);
             }
+            writer.write_all(&data)?;
         }
 
         Ok(())
@@ -241,3 +366,71 @@ pub enum ScsiStatus {
     AcaActive = 0x30,
     TaskAborted = 0x40,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Pdu {
+        let mut pdu = Pdu::new(Opcode::ScsiDataOut);
+        pdu.bhs.initiator_task_tag = 7;
+        pdu.ahs = vec![1, 2, 3, 4];
+        pdu.bhs.total_ahs_length = 1;
+        pdu.data = b"hello, digests".to_vec();
+        pdu.bhs.data_segment_length = pdu.data.len() as u32;
+        pdu
+    }
+
+    #[test]
+    fn test_digest_roundtrip_and_corruption() {
+        let both = Digests {
+            header: DigestType::Crc32c,
+            data: DigestType::Crc32c,
+        };
+        let mut wire = Vec::new();
+        sample().write_with(&mut wire, both).unwrap();
+        // BHS, AHS, header digest, padded data, data digest
+        assert_eq!(wire.len(), BHS_SIZE + 4 + 4 + 16 + 4);
+        // TotalAHSLength, then the 24-bit DataSegmentLength
+        assert_eq!(wire[4..8], [1, 0, 0, 14]);
+
+        let pdu = Pdu::read_with(&mut &wire[..], both).unwrap();
+        assert_eq!((pdu.bhs.initiator_task_tag, pdu.ahs.clone()), (7, vec![1, 2, 3, 4]));
+        assert_eq!(pdu.data, b"hello, digests");
+
+        // Standard CRC32C, least significant byte first
+        assert_eq!(digest(b"123456789"), 0xE3069283u32.to_le_bytes());
+
+        for (at, what) in [(20, "header"), (BHS_SIZE + 2, "header"), (BHS_SIZE + 10, "data")] {
+            let mut corrupt = wire.clone();
+            corrupt[at] ^= 0x40;
+            let err = Pdu::read_with(&mut &corrupt[..], both).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().starts_with(what), "{}", err);
+        }
+
+        // Without data, no data digest is sent
+        let mut nop = Vec::new();
+        Pdu::new(Opcode::NopIn).write_with(&mut nop, both).unwrap();
+        assert_eq!(nop.len(), BHS_SIZE + 4);
+    }
+
+    #[test]
+    fn test_negotiate_digests() {
+        let params: HashMap<String, String> = [
+            ("HeaderDigest", "CRC32C,None"),
+            ("DataDigest", "None,CRC32C"),
+        ]
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+        let (digests, response) = Digests::negotiate(&params);
+        assert_eq!(digests.header, DigestType::Crc32c);
+        assert_eq!(digests.data, DigestType::None);
+        assert!(response.contains(&("HeaderDigest".to_string(), "CRC32C".to_string())));
+        assert!(response.contains(&("DataDigest".to_string(), "None".to_string())));
+
+        assert_eq!(DigestType::negotiate("MD5"), None);
+        assert_eq!(Digests::negotiate(&HashMap::new()).1, vec![]);
+    }
+}
@@ -49,6 +49,26 @@ pub struct TargetMetadata {
 
     /// Optional description
     pub description: Option<String>,
+
+    /// Point-in-time snapshots of the target's index, oldest first
+    #[serde(default)]
+    pub snapshots: Vec<TargetSnapshot>,
+}
+
+/// A frozen copy of a target's index that it can be restored to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetSnapshot {
+    /// Snapshot ID (the layer's directory name)
+    pub id: String,
+
+    /// Path to the frozen index layer
+    pub layer_path: PathBuf,
+
+    /// Creation timestamp (Unix epoch seconds)
+    pub created_at: u64,
+
+    /// Optional description
+    pub description: Option<String>,
 }
 
 impl TargetRegistry {
@@ -296,6 +316,7 @@ mod tests {
             children: vec![],
             created_at: TargetRegistry::now(),
             description: Some("Test target".to_string()),
+            snapshots: vec![],
         };
 
         registry.add_target(metadata.clone())?;
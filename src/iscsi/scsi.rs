@@ -1,13 +1,22 @@
 //! SCSI command handling
 //!
 //! Implements essential SCSI commands for block device operations
+//!
+//! `iscsi-server` serves through `iscsi_target`, which answers INQUIRY and
+//! READ CAPACITY itself from what a `ScsiBlockDevice` reports and only
+//! hands the device reads, writes and flushes. The responses built here,
+//! including UNMAP and the thin provisioning it lets them advertise, are
+//! for a session that parses its own CDBs.
 
+use crate::storage::{self, BlockStorage, TargetUuid};
 use std::io;
 
 /// SCSI opcodes
 pub mod opcodes {
     pub const TEST_UNIT_READY: u8 = 0x00;
     pub const INQUIRY: u8 = 0x12;
+    pub const MODE_SELECT_6: u8 = 0x15;
+    pub const MODE_SELECT_10: u8 = 0x55;
     pub const MODE_SENSE_6: u8 = 0x1a;
     pub const MODE_SENSE_10: u8 = 0x5a;
     pub const READ_CAPACITY_10: u8 = 0x25;
@@ -16,11 +25,47 @@ pub mod opcodes {
     pub const READ_16: u8 = 0x88;
     pub const WRITE_10: u8 = 0x2a;
     pub const WRITE_16: u8 = 0x8a;
+    pub const UNMAP: u8 = 0x42;
     pub const REPORT_LUNS: u8 = 0xa0;
+    /// Vendor specific: flush and snapshot the device (see `handle_snapshot`)
+    pub const VOE_SNAPSHOT: u8 = 0xc8;
+}
+
+/// Largest READ/WRITE the Block Limits page allows, in sectors (1 MiB)
+pub const MAX_TRANSFER_SECTORS: u32 = 2048;
+
+/// Preferred transfer size in sectors: one CAS block of `block_size` bytes
+pub fn optimal_transfer_sectors(block_size: u32) -> u32 {
+    (block_size / 512).max(1)
 }
 
-/// Generate SCSI INQUIRY response
-pub fn handle_inquiry(evpd: bool, page_code: u8) -> Vec<u8> {
+/// Most sectors one UNMAP may release
+pub const MAX_UNMAP_SECTORS: u32 = 0x40_0000;
+
+/// Most block descriptors one UNMAP may carry
+pub const MAX_UNMAP_DESCRIPTORS: u32 = 256;
+
+/// NAA locally assigned identifier (type 3): the target's world wide
+/// name, the same one AoE reports in IDENTIFY DEVICE
+pub fn naa_identifier(uuid: &TargetUuid) -> [u8; 8] {
+    uuid.wwn().to_be_bytes()
+}
+
+/// Append a designation descriptor to a Device Identification page
+fn push_designator(page: &mut Vec<u8>, code_set: u8, flags: u8, designator: &[u8]) {
+    page.extend_from_slice(&[code_set, flags, 0x00, designator.len() as u8]);
+    page.extend_from_slice(designator);
+}
+
+/// Generate SCSI INQUIRY response for the logical unit with `uuid`,
+/// stored in blocks of `block_size` bytes
+pub fn handle_inquiry(
+    evpd: bool,
+    page_code: u8,
+    target_name: &str,
+    uuid: &TargetUuid,
+    block_size: u32,
+) -> Vec<u8> {
     if evpd {
         // Vital Product Data pages
         match page_code {
@@ -29,28 +74,72 @@ pub fn handle_inquiry(evpd: bool, page_code: u8) -> Vec<u8> {
                 vec![
                     0x00, // Peripheral qualifier, device type (direct access)
                     0x00, // Page code
-                    0x00, 0x03, // Page length
-                    0x00, 0x80, 0x83, // Supported pages
+                    0x00, 0x05, // Page length
+                    0x00, 0x80, 0x83, 0xb0, 0xb2, // Supported pages
                 ]
             }
             0x80 => {
                 // Unit serial number
-                let serial = b"VoE-CAS-001";
+                let serial = uuid.serial();
                 let mut response = vec![
                     0x00, // Device type
                     0x80, // Page code
                     0x00,
                     serial.len() as u8, // Page length
                 ];
-                response.extend_from_slice(serial);
+                response.extend_from_slice(serial.as_bytes());
                 response
             }
             0x83 => {
                 // Device identification
-                vec![
+                let mut response = vec![
                     0x00, // Device type
                     0x83, // Page code
-                    0x00, 0x00, // Page length (TODO: implement properly)
+                    0x00, 0x00, // Page length, filled in below
+                ];
+
+                // Logical unit: binary NAA identifier
+                push_designator(&mut response, 0x01, 0x03, &naa_identifier(uuid));
+
+                // Target device: the IQN as a SCSI name string, iSCSI
+                // protocol, UTF-8, NUL-terminated and padded to 4 bytes
+                let mut name = target_name.as_bytes().to_vec();
+                name.push(0);
+                name.resize(name.len().div_ceil(4) * 4, 0);
+                push_designator(&mut response, 0x53, 0xa8, &name);
+
+                let page_length = (response.len() - 4) as u16;
+                response[2..4].copy_from_slice(&page_length.to_be_bytes());
+                response
+            }
+            0xb0 => {
+                // Block limits
+                let optimal = optimal_transfer_sectors(block_size);
+                let mut response = vec![0u8; 64];
+                response[1] = 0xb0; // Page code
+                response[2..4].copy_from_slice(&60u16.to_be_bytes()); // Page length
+                response[6..8].copy_from_slice(&(optimal.min(0xffff) as u16).to_be_bytes());
+                response[8..12].copy_from_slice(&MAX_TRANSFER_SECTORS.to_be_bytes());
+                response[12..16].copy_from_slice(&optimal.to_be_bytes());
+                response[20..24].copy_from_slice(&MAX_UNMAP_SECTORS.to_be_bytes());
+                response[24..28].copy_from_slice(&MAX_UNMAP_DESCRIPTORS.to_be_bytes());
+                // Unmap whole CAS blocks; UGAVALID with alignment 0
+                response[28..32].copy_from_slice(&optimal.to_be_bytes());
+                response[32] = 0x80;
+                response
+            }
+            0xb2 => {
+                // Logical block provisioning: thin, with UNMAP. Discards are
+                // advisory, so unmapped blocks needn't read as zeros (no
+                // LBPRZ).
+                vec![
+                    0x00, // Device type
+                    0xb2, // Page code
+                    0x00, 0x04, // Page length
+                    0x00, // Threshold exponent
+                    0x80, // LBPU
+                    0x02, // Provisioning type: thin
+                    0x00,
                 ]
             }
             _ => {
@@ -121,15 +210,125 @@ pub fn handle_read_capacity_16(total_sectors: u64) -> Vec<u8> {
     // Pad to 32 bytes
     response.resize(32, 0);
 
+    // Thin provisioned (LBPME); UNMAP is handled by `handle_unmap`
+    response[14] = 0x80;
+
     response
 }
 
-/// Generate SCSI MODE SENSE response
-pub fn handle_mode_sense() -> Vec<u8> {
-    vec![
-        0x00, 0x06, 0x00, 0x00, // Mode parameter header
-        0x00, 0x00, 0x00, 0x00, // Block descriptor (empty)
-    ]
+/// Mode page codes
+pub mod mode_pages {
+    pub const CACHING: u8 = 0x08;
+    pub const CONTROL: u8 = 0x0a;
+    pub const ALL: u8 = 0x3f;
+}
+
+/// Caching mode page. `write_cache` is WCE: writes are acknowledged
+/// before they are stable, which for `CasScsiDevice` is
+/// `write_cache_enabled()`. `changeable` gives the mask of bits MODE
+/// SELECT may change, of which WCE is the only one.
+fn caching_page(write_cache: bool, changeable: bool) -> Vec<u8> {
+    let mut page = vec![0u8; 20];
+    page[0] = mode_pages::CACHING;
+    page[1] = 0x12; // Page length
+    if write_cache || changeable {
+        page[2] = 0x04; // WCE
+    }
+    page
+}
+
+/// Control mode page: unrestricted reordering, fixed format sense data.
+/// Nothing in it can be changed.
+fn control_page(changeable: bool) -> Vec<u8> {
+    let mut page = vec![0u8; 12];
+    page[0] = mode_pages::CONTROL;
+    page[1] = 0x0a; // Page length
+    if !changeable {
+        page[3] = 0x10; // Queue algorithm modifier: unrestricted
+    }
+    page
+}
+
+/// Generate SCSI MODE SENSE (6) or (10) response for the caching and
+/// control pages, or None for a page we don't have
+pub fn handle_mode_sense(cdb: &[u8], write_cache: bool) -> Option<Vec<u8>> {
+    let ten = cdb.first() == Some(&opcodes::MODE_SENSE_10);
+    if cdb.len() < if ten { 10 } else { 6 } {
+        return None;
+    }
+    let page_control = cdb[2] >> 6;
+    let page_code = cdb[2] & 0x3f;
+    // Saved values are the current ones; defaults have the cache off
+    let changeable = page_control == 1;
+    let write_cache = write_cache && page_control != 2;
+
+    let mut pages = Vec::new();
+    if matches!(page_code, mode_pages::CACHING | mode_pages::ALL) {
+        pages.extend(caching_page(write_cache, changeable));
+    }
+    if matches!(page_code, mode_pages::CONTROL | mode_pages::ALL) {
+        pages.extend(control_page(changeable));
+    }
+    if pages.is_empty() {
+        return None;
+    }
+
+    // Mode parameter header without block descriptors
+    let mut response = if ten {
+        let length = (pages.len() + 6) as u16;
+        let mut header = length.to_be_bytes().to_vec();
+        header.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        header
+    } else {
+        vec![(pages.len() + 3) as u8, 0x00, 0x00, 0x00]
+    };
+    response.extend(pages);
+
+    let allocation_length = if ten {
+        u16::from_be_bytes([cdb[7], cdb[8]]) as usize
+    } else {
+        cdb[4] as usize
+    };
+    response.truncate(allocation_length);
+    Some(response)
+}
+
+/// Apply a MODE SELECT (6) or (10) parameter list, returning the new
+/// write cache setting. Only WCE in the caching page may change; the
+/// control page is accepted as long as it is unchanged.
+pub fn handle_mode_select(cdb: &[u8], data: &[u8], write_cache: bool) -> io::Result<bool> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let ten = cdb.first() == Some(&opcodes::MODE_SELECT_10);
+    let header_len = if ten { 8 } else { 4 };
+    if data.len() < header_len {
+        return Err(invalid("mode parameter list too short"));
+    }
+    let descriptors_len = if ten {
+        u16::from_be_bytes([data[6], data[7]]) as usize
+    } else {
+        data[3] as usize
+    };
+
+    let mut write_cache = write_cache;
+    let mut pages = data
+        .get(header_len + descriptors_len..)
+        .ok_or_else(|| invalid("truncated block descriptors"))?;
+    while !pages.is_empty() {
+        if pages.len() < 2 {
+            return Err(invalid("truncated mode page"));
+        }
+        let page_len = pages[1] as usize + 2;
+        let page = pages.get(..page_len).ok_or_else(|| invalid("truncated mode page"))?;
+        match page[0] & 0x3f {
+            mode_pages::CACHING if page_len >= 3 => write_cache = page[2] & 0x04 != 0,
+            mode_pages::CONTROL if page[1..] == control_page(false)[1..] => {}
+            code => {
+                return Err(invalid(&format!("mode page 0x{:02x} cannot be changed", code)));
+            }
+        }
+        pages = &pages[page_len..];
+    }
+    Ok(write_cache)
 }
 
 /// Generate SCSI REPORT LUNS response
@@ -174,3 +373,243 @@ pub fn parse_read_write_cdb(cdb: &[u8]) -> io::Result<(u64, u32)> {
         )),
     }
 }
+
+/// Handle the vendor-specific VOE SNAPSHOT command: flush and snapshot
+/// `storage`, returning the snapshot id as Data-In. The CDB is 10 bytes
+/// with the allocation length in bytes 7-8; the Data-Out buffer, if any,
+/// is the snapshot's description. Unsupported if the backend keeps no
+/// snapshots.
+pub fn handle_snapshot(
+    cdb: &[u8],
+    description: &[u8],
+    storage: &dyn BlockStorage,
+) -> io::Result<Vec<u8>> {
+    if cdb.len() < 10 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "CDB too short"));
+    }
+    let description = String::from_utf8_lossy(description);
+    let description = description.trim_end_matches('\0');
+    let description = (!description.is_empty()).then_some(description);
+    let id = storage::consistent_snapshot(storage, description)
+        .ok_or_else(|| {
+            io::Error::new(io::ErrorKind::Unsupported, "backend does not keep snapshots")
+        })?
+        .map_err(|e| io::Error::other(format!("snapshot failed: {}", e)))?;
+
+    let mut response = id.into_bytes();
+    response.truncate(u16::from_be_bytes([cdb[7], cdb[8]]) as usize);
+    Ok(response)
+}
+
+/// Parse an UNMAP parameter list into (LBA, sector count) ranges
+pub fn parse_unmap_parameters(data: &[u8]) -> io::Result<Vec<(u64, u32)>> {
+    if data.len() < 8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "UNMAP parameters too short",
+        ));
+    }
+    let descriptors_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+    let descriptors = data.get(8..8 + descriptors_len).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated UNMAP block descriptors",
+        )
+    })?;
+    if descriptors.len() / 16 > MAX_UNMAP_DESCRIPTORS as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too many UNMAP block descriptors",
+        ));
+    }
+    Ok(descriptors
+        .chunks_exact(16)
+        .map(|d| {
+            let lba = u64::from_be_bytes(d[..8].try_into().unwrap());
+            let count = u32::from_be_bytes(d[8..12].try_into().unwrap());
+            (lba, count)
+        })
+        .collect())
+}
+
+/// Handle UNMAP: discard every range in the parameter list from
+/// `storage`. The whole list is checked against the device and the Block
+/// Limits before anything is discarded.
+pub fn handle_unmap(cdb: &[u8], data: &[u8], storage: &dyn BlockStorage) -> io::Result<()> {
+    if cdb.len() < 10 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "CDB too short"));
+    }
+    let ranges = parse_unmap_parameters(data)?;
+    let total_sectors = storage.info().total_sectors;
+    let mut sectors = 0u64;
+    for &(lba, count) in &ranges {
+        let end = lba.checked_add(count as u64);
+        if end.is_none_or(|end| end > total_sectors) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("UNMAP of {} sectors at LBA {} is out of range", count, lba),
+            ));
+        }
+        sectors += count as u64;
+    }
+    if sectors > MAX_UNMAP_SECTORS as u64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("UNMAP of {} sectors exceeds the limit", sectors),
+        ));
+    }
+
+    for (lba, count) in ranges {
+        if count > 0 {
+            storage
+                .discard(lba, count as u64)
+                .map_err(|e| io::Error::other(format!("UNMAP at LBA {} failed: {}", lba, e)))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IQN: &str = "iqn.2025-12.local.voe:storage.disk1";
+
+    #[test]
+    fn test_vpd_pages() {
+        let uuid = TargetUuid::generate();
+        let supported = handle_inquiry(true, 0x00, IQN, &uuid, 4096);
+        assert_eq!(&supported[4..], &[0x00, 0x80, 0x83, 0xb0, 0xb2]);
+
+        let serial = handle_inquiry(true, 0x80, IQN, &uuid, 4096);
+        assert_eq!(&serial[4..], uuid.serial().as_bytes());
+
+        let ident = handle_inquiry(true, 0x83, IQN, &uuid, 4096);
+        assert_eq!(u16::from_be_bytes([ident[2], ident[3]]) as usize, ident.len() - 4);
+        // NAA locally assigned, stable and distinct per target
+        assert_eq!(&ident[4..8], &[0x01, 0x03, 0x00, 0x08]);
+        assert_eq!(&ident[8..16], &naa_identifier(&uuid));
+        assert_eq!(ident[8] >> 4, 0x3);
+        assert_ne!(naa_identifier(&uuid), naa_identifier(&TargetUuid::generate()));
+        // SCSI name string with the IQN
+        assert_eq!(&ident[16..18], &[0x53, 0xa8]);
+        let name_len = ident[19] as usize;
+        assert_eq!(name_len % 4, 0);
+        assert_eq!(&ident[20..20 + IQN.len()], IQN.as_bytes());
+        assert_eq!(ident.len(), 20 + name_len);
+
+        // Granularity follows the CAS block size
+        let limits = handle_inquiry(true, 0xb0, IQN, &uuid, 65536);
+        assert_eq!(limits.len(), 64);
+        assert_eq!(u16::from_be_bytes([limits[6], limits[7]]), 128);
+        assert_eq!(u32::from_be_bytes(limits[8..12].try_into().unwrap()), MAX_TRANSFER_SECTORS);
+        assert_eq!(u32::from_be_bytes(limits[12..16].try_into().unwrap()), 128);
+        let max_unmap = u32::from_be_bytes(limits[20..24].try_into().unwrap());
+        assert_eq!(max_unmap, MAX_UNMAP_SECTORS);
+        assert_eq!(u32::from_be_bytes(limits[28..32].try_into().unwrap()), 128);
+
+        // Thin provisioned with UNMAP, but unmapped blocks aren't zeroed
+        let provisioning = handle_inquiry(true, 0xb2, IQN, &uuid, 4096);
+        assert_eq!(provisioning[5], 0x80); // LBPU without LBPRZ
+        assert_eq!(provisioning[6] & 0x07, 0x02); // Thin
+        assert_eq!(handle_read_capacity_16(1000)[14], 0x80);
+    }
+
+    #[test]
+    fn test_mode_sense_and_select() {
+        // MODE SENSE(6), all pages, current values
+        let all = handle_mode_sense(&[opcodes::MODE_SENSE_6, 0, 0x3f, 0, 255, 0], true).unwrap();
+        assert_eq!(all[0] as usize, all.len() - 1);
+        assert_eq!(all.len(), 4 + 20 + 12);
+        assert_eq!(&all[4..6], &[0x08, 0x12]);
+        assert_eq!(all[6] & 0x04, 0x04); // WCE
+        assert_eq!(&all[24..26], &[0x0a, 0x0a]);
+
+        // MODE SENSE(10), caching page, changeable and default values
+        let cdb = |pc: u8| [opcodes::MODE_SENSE_10, 0, (pc << 6) | 0x08, 0, 0, 0, 0, 0, 255, 0];
+        let changeable = handle_mode_sense(&cdb(1), false).unwrap();
+        let length = u16::from_be_bytes([changeable[0], changeable[1]]) as usize;
+        assert_eq!(length, changeable.len() - 2);
+        assert_eq!(changeable[8 + 2], 0x04);
+        assert_eq!(handle_mode_sense(&cdb(2), true).unwrap()[8 + 2], 0x00);
+
+        // Truncated to the allocation length; unknown pages refused
+        let short = handle_mode_sense(&[opcodes::MODE_SENSE_6, 0, 0x08, 0, 4, 0], true);
+        assert_eq!(short.unwrap().len(), 4);
+        assert!(handle_mode_sense(&[opcodes::MODE_SENSE_6, 0, 0x1c, 0, 255, 0], true).is_none());
+
+        // MODE SELECT(6) turning the write cache off, then on with (10)
+        let mut data = vec![0u8; 4];
+        data.extend(caching_page(false, false));
+        data.extend(control_page(false));
+        let select_6 = |len: u8| [opcodes::MODE_SELECT_6, 0x10, 0, 0, len, 0];
+        assert!(!handle_mode_select(&select_6(36), &data, true).unwrap());
+        let mut data = vec![0u8; 8];
+        data.extend(caching_page(true, false));
+        let select_10 = [opcodes::MODE_SELECT_10, 0x10, 0, 0, 0, 0, 0, 0, 28, 0];
+        assert!(handle_mode_select(&select_10, &data, false).unwrap());
+
+        // Changing the control page is refused
+        let mut data = vec![0u8; 4];
+        let mut control = control_page(false);
+        control[2] = 0x04; // D_SENSE
+        data.extend(control);
+        assert!(handle_mode_select(&select_6(16), &data, true).is_err());
+    }
+
+    #[test]
+    fn test_unmap() {
+        use crate::storage::MemBackend;
+
+        let parameters = |ranges: &[(u64, u32)]| {
+            let descriptors_len = 16 * ranges.len() as u16;
+            let mut data = (descriptors_len + 6).to_be_bytes().to_vec();
+            data.extend_from_slice(&descriptors_len.to_be_bytes());
+            data.extend_from_slice(&[0; 4]);
+            for &(lba, count) in ranges {
+                data.extend_from_slice(&lba.to_be_bytes());
+                data.extend_from_slice(&count.to_be_bytes());
+                data.extend_from_slice(&[0; 4]);
+            }
+            data
+        };
+        let data = parameters(&[(8, 16), (1 << 33, 8)]);
+        assert_eq!(&data[..4], &[0x00, 0x26, 0x00, 0x20]);
+        let ranges = parse_unmap_parameters(&data).unwrap();
+        assert_eq!(ranges, vec![(8, 16), (1 << 33, 8)]);
+        assert!(parse_unmap_parameters(&data[..20]).is_err());
+
+        let storage = MemBackend::new(64 * 512);
+        storage.write(0, &[0xaa; 64 * 512]).unwrap();
+        let mut cdb = [0u8; 10];
+        cdb[0] = opcodes::UNMAP;
+        // The second range is past the end, so nothing is discarded
+        assert!(handle_unmap(&cdb, &data, &storage).is_err());
+        assert_eq!(storage.read(8, 1).unwrap(), vec![0xaa; 512]);
+
+        handle_unmap(&cdb, &parameters(&[(8, 16)]), &storage).unwrap();
+        assert_eq!(storage.read(8, 16).unwrap(), vec![0; 16 * 512]);
+        assert_eq!(storage.read(24, 1).unwrap(), vec![0xaa; 512]);
+    }
+
+    #[test]
+    fn test_vendor_snapshot() {
+        use crate::blob::FileBlobStore;
+        use crate::storage::{CasBackend, MemBackend};
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path().join("blobs")).unwrap();
+        let cas = CasBackend::new(Box::new(store), 1024, &temp.path().join("snapshots.json"))
+            .unwrap();
+        cas.write(0, &[5u8; 512]).unwrap();
+
+        let cdb = [opcodes::VOE_SNAPSHOT, 0, 0, 0, 0, 0, 0, 0, 255, 0];
+        let id = String::from_utf8(handle_snapshot(&cdb, b"pre-upgrade\0", &cas).unwrap()).unwrap();
+        let snapshots = cas.as_archival().unwrap().list_snapshots().unwrap();
+        assert_eq!(snapshots.last().unwrap().id, id);
+        assert_eq!(snapshots.last().unwrap().description.as_deref(), Some("pre-upgrade"));
+
+        let err = handle_snapshot(&cdb, &[], &MemBackend::new(4096)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+}
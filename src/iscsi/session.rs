@@ -2,11 +2,12 @@
 //!
 //! Handles login, text negotiation, and SCSI command processing.
 
+use super::discovery;
 use super::pdu::{BasicHeaderSegment, Opcode, Pdu, ScsiStatus};
 use crate::storage::BlockStorage;
 use std::collections::HashMap;
 use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
+use std::net::{SocketAddr, TcpStream};
 
 const SECTOR_SIZE: usize = 512;
 
@@ -19,6 +20,8 @@ pub struct Session {
     pub cmd_sn: u32,
     pub exp_stat_sn: u32,
     pub max_cmd_sn: u32,
+    /// Logged in with SessionType=Discovery: Text requests only, no I/O
+    pub discovery: bool,
 }
 
 impl Session {
@@ -30,6 +33,7 @@ impl Session {
             cmd_sn: 0,
             exp_stat_sn: 1,
             max_cmd_sn: 64,
+            discovery: false,
         }
     }
 }
@@ -74,10 +78,18 @@ pub fn handle_login(
         session.initiator_name = Some(name.clone());
     }
 
+    // A discovery session names no target
+    session.discovery = discovery::is_discovery_login(&params);
+
     // Build response parameters
     let mut response_params = HashMap::new();
-    response_params.insert("TargetName".to_string(), session.target_name.clone());
-    response_params.insert("TargetPortalGroupTag".to_string(), "1".to_string());
+    if !session.discovery {
+        response_params.insert("TargetName".to_string(), session.target_name.clone());
+    }
+    response_params.insert(
+        "TargetPortalGroupTag".to_string(),
+        discovery::PORTAL_GROUP_TAG.to_string(),
+    );
 
     // Auth parameters - accept without authentication for simplicity
     if params.contains_key("AuthMethod") {
@@ -116,6 +128,42 @@ pub fn handle_login(
     Ok(response)
 }
 
+/// Handle iSCSI Text request: `SendTargets` lists `targets` at `portals`
+pub fn handle_text_request(
+    pdu: &Pdu,
+    session: &mut Session,
+    targets: &[String],
+    portals: &[SocketAddr],
+) -> io::Result<Pdu> {
+    let params = parse_text_params(&pdu.data);
+
+    // Key order matters: each TargetName is followed by its addresses
+    let mut response_data = Vec::new();
+    if let Some(request) = params.get("SendTargets") {
+        // All is only for discovery sessions; a normal one sees itself
+        let request = match request.as_str() {
+            "" | "All" if !session.discovery => session.target_name.as_str(),
+            request => request,
+        };
+        let names = targets.iter().map(String::as_str);
+        for (key, value) in discovery::send_targets(request, names, portals) {
+            response_data.extend_from_slice(format!("{}={}\0", key, value).as_bytes());
+        }
+    }
+
+    let mut response = Pdu::new(Opcode::TextResponse);
+    response.bhs.flags = 0x80; // Final bit
+    response.bhs.data_segment_length = response_data.len() as u32;
+    response.bhs.initiator_task_tag = pdu.bhs.initiator_task_tag;
+    response.bhs.exp_stat_sn = session.exp_stat_sn;
+    response.bhs.max_cmd_sn = session.max_cmd_sn;
+    response.data = response_data;
+
+    session.exp_stat_sn += 1;
+
+    Ok(response)
+}
+
 /// Handle SCSI Read command (READ(10), READ(16))
 pub fn handle_scsi_read<S: BlockStorage>(
     pdu: &Pdu,
@@ -124,7 +172,7 @@ pub fn handle_scsi_read<S: BlockStorage>(
 ) -> io::Result<Vec<Pdu>> {
     // Parse SCSI CDB from specific fields
     let cdb = &pdu.data;
-    if cdb.is_empty() {
+    if cdb.len() < 16 {
         return Ok(vec![create_scsi_response(pdu, session, ScsiStatus::CheckCondition)]);
     }
 
@@ -176,50 +224,6 @@ pub fn handle_scsi_read<S: BlockStorage>(
     Ok(vec![data_in])
 }
 
-/// Handle SCSI Write command (WRITE(10), WRITE(16))
-pub fn handle_scsi_write<S: BlockStorage>(
-    pdu: &Pdu,
-    session: &mut Session,
-    storage: &mut S,
-) -> io::Result<Pdu> {
-    let cdb = &pdu.data[..16]; // First 16 bytes are CDB
-    let opcode = cdb[0];
-
-    let (lba, transfer_length) = match opcode {
-        0x2a => {
-            // WRITE(10)
-            let lba = u32::from_be_bytes([cdb[2], cdb[3], cdb[4], cdb[5]]) as u64;
-            let transfer_length = u16::from_be_bytes([cdb[7], cdb[8]]) as u32;
-            (lba, transfer_length)
-        }
-        0x8a => {
-            // WRITE(16)
-            let lba = u64::from_be_bytes([
-                cdb[2], cdb[3], cdb[4], cdb[5], cdb[6], cdb[7], cdb[8], cdb[9],
-            ]);
-            let transfer_length = u32::from_be_bytes([cdb[10], cdb[11], cdb[12], cdb[13]]);
-            (lba, transfer_length)
-        }
-        _ => {
-            log::warn!("Unsupported SCSI write opcode: 0x{:02x}", opcode);
-            return Ok(create_scsi_response(pdu, session, ScsiStatus::CheckCondition));
-        }
-    };
-
-    log::debug!("SCSI WRITE: LBA={}, length={} sectors", lba, transfer_length);
-
-    // Write data (comes after CDB in immediate data)
-    let data_offset = 16;
-    let write_data = &pdu.data[data_offset..];
-
-    if let Err(e) = storage.write_sectors(lba, transfer_length as u8, write_data) {
-        log::error!("Storage write error: {}", e);
-        return Ok(create_scsi_response(pdu, session, ScsiStatus::CheckCondition));
-    }
-
-    Ok(create_scsi_response(pdu, session, ScsiStatus::Good))
-}
-
 /// Create SCSI response PDU
 fn create_scsi_response(request: &Pdu, session: &Session, status: ScsiStatus) -> Pdu {
     let mut response = Pdu::new(Opcode::ScsiResponse);
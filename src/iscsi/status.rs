@@ -0,0 +1,183 @@
+//! Runtime statistics of a running iSCSI server
+//!
+//! `iscsi-server` can serve each target's `DeviceStats` over HTTP (the
+//! `status` server option, `--status` in single-target mode), for
+//! `iscsi-web` to scrape and show alongside its targets:
+//!
+//! - `GET /stats`: every target's statistics, by IQN
+//! - `GET /targets/{iqn}/stats`: one target's statistics
+//!
+//! Responses use the same `{success, data, error}` envelope as `iscsi-web`
+//! and the AoE API.
+//! The counters start from zero when the server starts. Each target's
+//! persistent UUID and the serial derived from it are reported with them.
+
+use super::{CasScsiFlushHandle, DeviceStats};
+use crate::server::api::ApiResponse;
+use crate::shutdown;
+use crate::storage::TargetUuid;
+use axum::{
+    extract::{Path, State},
+    response::Json,
+    routing::get,
+    Router,
+};
+use serde::Serialize;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// Targets served, by IQN
+type Targets = Arc<Vec<(String, CasScsiFlushHandle)>>;
+
+#[derive(Serialize)]
+pub struct TargetStats {
+    pub iqn: String,
+    pub uuid: TargetUuid,
+    pub serial: String,
+    #[serde(flatten)]
+    pub stats: DeviceStats,
+    /// Fraction of read cache lookups that hit
+    pub read_cache_hit_rate: Option<f64>,
+}
+
+impl TargetStats {
+    fn new(iqn: &str, handle: &CasScsiFlushHandle) -> Self {
+        let stats = handle.stats();
+        let uuid = handle.uuid();
+        Self {
+            iqn: iqn.to_string(),
+            uuid,
+            serial: uuid.serial(),
+            stats,
+            read_cache_hit_rate: stats.read_cache_hit_rate(),
+        }
+    }
+}
+
+/// Build the status router for a set of targets
+pub fn router(targets: Vec<(String, CasScsiFlushHandle)>) -> Router {
+    Router::new()
+        .route("/stats", get(all_stats))
+        .route("/targets/{iqn}/stats", get(target_stats))
+        .with_state(Arc::new(targets))
+}
+
+/// Serve target statistics on `bind` from a thread of its own until
+/// shutdown is requested
+pub fn spawn(
+    bind: &str,
+    targets: Vec<(String, CasScsiFlushHandle)>,
+) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+    serve(bind, "iscsi-status", router(targets))
+}
+
+/// Serve `router` on `bind` from a thread named `name` until shutdown is
+/// requested
+pub fn serve(bind: &str, name: &str, router: Router) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = std::net::TcpListener::bind(bind)?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let thread = thread::Builder::new()
+        .name(name.to_string())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let result = async {
+                    let listener = tokio::net::TcpListener::from_std(listener)?;
+                    axum::serve(listener, router)
+                        .with_graceful_shutdown(async {
+                            while !shutdown::requested() {
+                                tokio::time::sleep(shutdown::POLL_INTERVAL).await;
+                            }
+                        })
+                        .await
+                }
+                .await;
+                if let Err(e) = result {
+                    log::error!("Status server error: {}", e);
+                }
+            })
+        })?;
+
+    Ok((addr, thread))
+}
+
+async fn all_stats(State(targets): State<Targets>) -> Json<ApiResponse<Vec<TargetStats>>> {
+    let stats = targets
+        .iter()
+        .map(|(iqn, handle)| TargetStats::new(iqn, handle))
+        .collect();
+    ApiResponse::success(stats)
+}
+
+async fn target_stats(
+    State(targets): State<Targets>,
+    Path(iqn): Path<String>,
+) -> Json<ApiResponse<TargetStats>> {
+    match targets.iter().find(|(name, _)| *name == iqn) {
+        Some((name, handle)) => ApiResponse::success(TargetStats::new(name, handle)),
+        None => ApiResponse::error(format!("Target not found: {}", iqn)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admin::{AdminClient, AdminError};
+    use crate::cas::{CasServer, CasServerConfig};
+    use crate::iscsi::{CasScsiDevice, CasScsiDeviceConfig};
+    use iscsi_target::ScsiBlockDevice;
+    use std::net::TcpListener;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_status_server() {
+        let temp = TempDir::new().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let cas_addr = listener.local_addr().unwrap().to_string();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: cas_addr.clone(),
+            storage_path: temp.path().join("cas").to_string_lossy().into_owned(),
+        })
+        .unwrap();
+        thread::spawn(move || server.serve(listener));
+
+        let iqn = "iqn.2025-12.local.voe:status";
+        let mut device = CasScsiDevice::new(CasScsiDeviceConfig {
+            cas_server_addr: cas_addr,
+            capacity_blocks: 64,
+            index_path: temp.path().join("index"),
+            ..CasScsiDeviceConfig::default()
+        })
+        .unwrap();
+        let (addr, _thread) =
+            spawn("127.0.0.1:0", vec![(iqn.to_string(), device.flush_handle())]).unwrap();
+
+        device.write(0, &[7; 4096], 4096).unwrap();
+        device.flush().unwrap();
+        device.read(0, 1, 4096).unwrap();
+
+        let client = AdminClient::new(&addr.to_string()).unwrap();
+        let all = client.get("/stats").unwrap();
+        assert_eq!(all[0]["iqn"], iqn);
+        let uuid = device.flush_handle().uuid();
+        assert_eq!(all[0]["uuid"], uuid.to_string());
+        assert_eq!(all[0]["serial"], uuid.serial());
+        assert_eq!(all[0]["writes"], 1);
+        assert_eq!(all[0]["flushes"], 1);
+
+        let one = client.get(&format!("/targets/{}/stats", iqn)).unwrap();
+        assert_eq!(one["reads"], 1);
+        assert_eq!(one["read_cache_misses"], 1);
+        assert_eq!(one["read_cache_hit_rate"], 0.0);
+        assert_eq!(one["cas_requests"], 2);
+
+        let missing = client.get("/targets/iqn.2025-12.local.voe:other/stats");
+        assert!(matches!(missing, Err(AdminError::Api(_))));
+    }
+}
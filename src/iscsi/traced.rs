@@ -0,0 +1,136 @@
+//! Request spans for SCSI devices
+//!
+//! `iscsi_target` parses the PDUs and only hands the device its reads,
+//! writes and flushes, so this is where iSCSI requests get their spans.
+//! The initiator isn't visible at this level; the target IQN is.
+
+use crate::logging::next_request_id;
+use iscsi_target::{ScsiBlockDevice, ScsiResult};
+use std::time::Instant;
+
+/// Runs each I/O on the wrapped device in a debug-level `iscsi` span
+pub struct TracedScsiDevice {
+    target: String,
+    inner: Box<dyn ScsiBlockDevice + Send>,
+}
+
+impl TracedScsiDevice {
+    pub fn new(target: impl Into<String>, inner: Box<dyn ScsiBlockDevice + Send>) -> Self {
+        Self {
+            target: target.into(),
+            inner,
+        }
+    }
+}
+
+/// Span for one request; `lba` is absent for flushes
+fn request_span(target: &str, op: &str, lba: Option<u64>) -> tracing::Span {
+    tracing::debug_span!("iscsi", req = next_request_id(), target, op, lba)
+}
+
+/// Log a finished request's latency inside its span
+fn request_done<T>(started: Instant, result: &ScsiResult<T>) {
+    tracing::debug!(
+        latency_us = started.elapsed().as_micros() as u64,
+        ok = result.is_ok(),
+        "request done"
+    );
+}
+
+impl ScsiBlockDevice for TracedScsiDevice {
+    fn read(&self, lba: u64, blocks: u32, block_size: u32) -> ScsiResult<Vec<u8>> {
+        let _entered = request_span(&self.target, "read", Some(lba)).entered();
+        let started = Instant::now();
+        let result = self.inner.read(lba, blocks, block_size);
+        request_done(started, &result);
+        result
+    }
+
+    fn write(&mut self, lba: u64, data: &[u8], block_size: u32) -> ScsiResult<()> {
+        let _entered = request_span(&self.target, "write", Some(lba)).entered();
+        let started = Instant::now();
+        let result = self.inner.write(lba, data, block_size);
+        request_done(started, &result);
+        result
+    }
+
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+
+    fn block_size(&self) -> u32 {
+        self.inner.block_size()
+    }
+
+    fn flush(&mut self) -> ScsiResult<()> {
+        let _entered = request_span(&self.target, "flush", None).entered();
+        let started = Instant::now();
+        let result = self.inner.flush();
+        request_done(started, &result);
+        result
+    }
+
+    fn vendor_id(&self) -> &str {
+        self.inner.vendor_id()
+    }
+
+    fn product_id(&self) -> &str {
+        self.inner.product_id()
+    }
+
+    fn product_rev(&self) -> &str {
+        self.inner.product_rev()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::iscsi::StorageScsiDevice;
+    use crate::storage::MemBackend;
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_request_span_fields() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let storage = Box::new(MemBackend::new(64 * 512));
+        let device = TracedScsiDevice::new(
+            "iqn.2025-01.test:disk",
+            Box::new(StorageScsiDevice::new(storage)),
+        );
+        tracing::subscriber::with_default(subscriber, || {
+            device.read(7, 1, 512).unwrap();
+        });
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line: serde_json::Value = serde_json::from_str(output.lines().last().unwrap()).unwrap();
+        assert!(line["fields"]["latency_us"].is_u64());
+        let span = &line["spans"][0];
+        assert_eq!(span["target"], "iqn.2025-01.test:disk");
+        assert_eq!(span["op"], "read");
+        assert_eq!(span["lba"], 7);
+        assert!(span["req"].is_u64());
+    }
+}
@@ -4,14 +4,26 @@
 //! It supports multiple storage backends including simple files and content-addressed
 //! storage (CAS) with automatic deduplication.
 
+pub mod admin;
 pub mod blob;
 pub mod cas;
+pub mod client;
 pub mod config;
+pub mod frontend;
+pub mod fsck;
+pub mod ha;
 pub mod iscsi;
+pub mod logging;
 pub mod nbd;
+pub mod net;
+pub mod openapi;
 pub mod protocol;
+pub mod qos;
 pub mod server;
+pub mod shutdown;
+pub mod systemd;
 pub mod storage;
+pub mod tls;
 
 pub use cas::{CasServer, CasServerConfig};
 pub use config::Config;
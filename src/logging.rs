@@ -0,0 +1,85 @@
+//! Logging setup
+//!
+//! Log output goes through `tracing`; the `log` macros used across the
+//! crate are bridged in. Each AoE frame, NBD request and iSCSI I/O runs in
+//! a span carrying a request id, the target, the initiator where known,
+//! the opcode and the LBA, so every line logged while serving it can be
+//! correlated, and a debug event with its latency closes it. Output is
+//! plain text or one JSON object per line. `RUST_LOG` overrides the level.
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing_subscriber::EnvFilter;
+
+/// Log line format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the enclosing spans' fields
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("unknown log format '{}' (expected text or json)", s)),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// Install the global logger. `level` is a filter such as "info" or
+/// "aoe_server=debug", used unless `RUST_LOG` is set.
+pub fn init(level: &str, format: LogFormat) {
+    let level = if level.eq_ignore_ascii_case("warning") { "warn" } else { level };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| {
+        EnvFilter::try_new(level).unwrap_or_else(|e| {
+            eprintln!("Invalid log level '{}' ({}), defaulting to 'info'", level, e);
+            EnvFilter::new("info")
+        })
+    });
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_current_span(false).init(),
+    }
+}
+
+/// Process-wide id tying together the log lines of one request
+pub fn next_request_id() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    NEXT.fetch_add(1, Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_format_parse() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("Text".parse::<LogFormat>().unwrap(), LogFormat::Text);
+        assert!("xml".parse::<LogFormat>().is_err());
+        assert!(next_request_id() < next_request_id());
+    }
+}
@@ -2,49 +2,128 @@
 //!
 //! Usage:
 //!   aoe-server [OPTIONS] <CONFIG>
+//!   aoe-server config check <CONFIG>
+//!   aoe-server config upgrade [--write] <CONFIG>
+//!
+//! Options:
+//!   --trace-pcap <PATH>  Capture all AoE frames to a pcap file
 //!
 //! Example:
 //!   aoe-server /etc/aoe-server.toml
 
-use aoe_server::blob::FileBlobStore;
-use aoe_server::config::{BackendType, BlobStoreConfig, Config};
-use aoe_server::server::{AoeListener, TargetManager};
-use aoe_server::storage::{CasBackend, FileBackend};
+use aoe_server::blob::{
+    migrate, BlobStore, Cipher, EncryptedBlobStore, FileBlobStore, HashAlgorithm,
+    MigratingBlobStore, QuotaBlobStore, RemoteBlobStore, ShardedBlobStore, TieredBlobStore,
+    TimedBlobStore,
+};
+use aoe_server::cas::CasPoolConfig;
+use aoe_server::config::{
+    BackendType, BlobStoreConfig, CipherConfig, CompressionConfig, Config, EncryptionConfig,
+    FileBackendConfig, PersistRootConfig, CONFIG_VERSION,
+};
+use aoe_server::ha::HaNode;
+use aoe_server::protocol::{Addressing, LBA48_MAX_SECTORS};
+use aoe_server::server::pcap::PcapWriter;
+use aoe_server::server::interface_set::{is_interface_pattern, matching_interfaces};
+use aoe_server::server::transport::{interface_mtu, FrameTransport};
+use aoe_server::server::{
+    max_sectors_per_frame, AoeListener, InterfaceSet, TargetAddr, TargetManager, DEFAULT_MTU,
+};
+use aoe_server::storage::cas::{self, ChunkerConfig, PersistRoot};
+use aoe_server::storage::{
+    file, CasBackend, Compression, DeviceBackend, DeviceStrings, FileBackend, MemBackend,
+    SpaceReserve, TargetUuid, DEFAULT_SLOW_THRESHOLD,
+};
+use aoe_server::tls::{self, TlsClient};
 use aoe_server::BlockStorage;
 use anyhow::{Context, Result};
 use std::env;
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 fn main() -> Result<()> {
     // Parse command line arguments
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <CONFIG>", args[0]);
+    if args.get(1).map(String::as_str) == Some("config") {
+        return config_command(&args[2..]);
+    }
+    let mut trace_pcap = None;
+    let mut positional = Vec::new();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--trace-pcap" => {
+                trace_pcap = Some(iter.next().context("--trace-pcap requires a path")?.clone())
+            }
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() != 1 {
+        eprintln!("Usage: {} [OPTIONS] <CONFIG>", args[0]);
+        eprintln!("       {} config check <CONFIG>", args[0]);
+        eprintln!("       {} config upgrade [--write] <CONFIG>", args[0]);
         eprintln!();
         eprintln!("Arguments:");
         eprintln!("  CONFIG    Path to configuration file (TOML)");
         eprintln!();
+        eprintln!("Options:");
+        eprintln!("  --trace-pcap <PATH>  Capture all AoE frames to a pcap file");
+        eprintln!();
         eprintln!("Environment:");
         eprintln!("  RUST_LOG  Log level (trace, debug, info, warn, error)");
         std::process::exit(1);
     }
 
-    let config_path = &args[1];
+    let config_path = positional[0];
 
     // Load configuration
     let config = Config::load(config_path)
         .with_context(|| format!("failed to load config from {}", config_path))?;
 
     // Initialize logging
-    env_logger::Builder::new()
-        .filter_level(parse_log_level(&config.server.log_level))
-        .init();
+    aoe_server::logging::init(&config.server.log_level, config.server.log_format);
 
     log::info!("AoE Server v{}", env!("CARGO_PKG_VERSION"));
     log::info!("Loaded configuration from {}", config_path);
+    if !config.ignored_keys.is_empty() {
+        log::warn!(
+            "Ignoring unknown config key(s) {}; correct or remove them before \
+             `aoe-server config upgrade` moves the file to version {}",
+            config.ignored_keys.join(", "),
+            CONFIG_VERSION
+        );
+    }
+
+    aoe_server::shutdown::install().context("failed to install signal handlers")?;
+
+    // A passive failover node opens no backends until it takes over, so it
+    // loads the root the active node last persisted
+    let _ha = match &config.ha {
+        Some(ha_config) => {
+            let node = HaNode::start(ha_config).with_context(|| {
+                format!("failed to start HA heartbeats on {}", ha_config.listen)
+            })?;
+            match node.wait_until_active().context("failed to take the HA lease")? {
+                Some(lease) => node.hold(lease),
+                None => {
+                    log::info!("AoE server stopped while passive");
+                    return Ok(());
+                }
+            }
+            Some(node)
+        }
+        None => None,
+    };
 
     // Create target manager
     let mut targets = TargetManager::new();
+    let slow_threshold = config
+        .server
+        .slow_request_ms
+        .map_or(DEFAULT_SLOW_THRESHOLD, Duration::from_millis);
+    targets.set_slow_threshold(slow_threshold);
 
     // Initialize backends
     for target_config in &config.target {
@@ -54,6 +133,12 @@ fn main() -> Result<()> {
             target_config.slot
         );
 
+        // Explicit sector size, or 512 (devices keep their detected size)
+        let sector_size = target_config.sector_size.unwrap_or(512);
+        let strings = target_config.device_strings();
+        // Blob store of a CAS target, kept by the target manager for migrations
+        let mut migrating = None;
+
         let storage: Box<dyn aoe_server::BlockStorage> = match target_config.backend {
             BackendType::File => {
                 let file_config = target_config
@@ -61,24 +146,60 @@ fn main() -> Result<()> {
                     .as_ref()
                     .expect("file config validated");
 
-                let backend = if let Some(size) = file_config.size {
-                    FileBackend::open_or_create(&file_config.path, size)
-                        .with_context(|| {
-                            format!("failed to create file backend at {}", file_config.path)
-                        })?
+                check_file_size(file_config, sector_size).with_context(|| {
+                    format!(
+                        "file backend for shelf {} slot {}",
+                        target_config.shelf, target_config.slot
+                    )
+                })?;
+                if let Some(uuid) = target_config.uuid {
+                    let path = file::identity_path(Path::new(&file_config.path));
+                    TargetUuid::pin(&path, uuid).context("failed to pin target identity")?;
+                }
+
+                let reserve = file_config
+                    .min_free_bytes
+                    .map(|bytes| SpaceReserve::new(&file_config.path, bytes));
+
+                let backend: Box<dyn BlockStorage> = if file_config.uring {
+                    open_uring_backend(
+                        &file_config.path,
+                        file_config.size,
+                        sector_size,
+                        reserve,
+                        &strings,
+                    )?
                 } else {
-                    FileBackend::open(&file_config.path).with_context(|| {
-                        format!("failed to open file backend at {}", file_config.path)
-                    })?
+                    let backend = match file_config.size {
+                        Some(size) => FileBackend::open_or_create(&file_config.path, size)
+                            .with_context(|| {
+                                format!("failed to create file backend at {}", file_config.path)
+                            })?,
+                        None => FileBackend::open(&file_config.path).with_context(|| {
+                            format!("failed to open file backend at {}", file_config.path)
+                        })?,
+                    };
+                    let backend = backend
+                        .with_sector_size(sector_size)?
+                        .with_device_strings(&strings);
+                    match reserve {
+                        Some(reserve) => Box::new(backend.with_reserve(reserve)),
+                        None => Box::new(backend),
+                    }
                 };
 
                 log::info!(
-                    "  File backend: {} ({} sectors)",
+                    "  File backend: {} ({} sectors{}{})",
                     file_config.path,
-                    backend.info().total_sectors
+                    backend.info().total_sectors,
+                    if file_config.uring { ", io_uring" } else { "" },
+                    match file_config.min_free_bytes {
+                        Some(bytes) => format!(", {} bytes reserved", bytes),
+                        None => String::new(),
+                    }
                 );
 
-                Box::new(backend)
+                backend
             }
             BackendType::Cas => {
                 let cas_config = target_config
@@ -87,25 +208,93 @@ fn main() -> Result<()> {
                     .expect("cas config validated");
 
                 // Create blob store
-                let blob_store: Box<dyn aoe_server::blob::BlobStore> =
-                    match &cas_config.blob_store {
-                        BlobStoreConfig::File { path } => {
-                            std::fs::create_dir_all(path).with_context(|| {
-                                format!("failed to create blob store directory: {}", path)
-                            })?;
-                            Box::new(FileBlobStore::new(path).with_context(|| {
-                                format!("failed to create file blob store at {}", path)
-                            })?)
+                // Encrypted blobs are keyed by a locator, not their content hash
+                let verify = cas_config.encryption.is_none();
+                let blob_store: Box<dyn aoe_server::blob::BlobStore> = match &cas_config.tiering
+                {
+                    Some(tiering) => {
+                        let stores = open_file_blob_stores(&cas_config.blob_store, verify)?;
+                        let resident = ShardedBlobStore::blobs_of(&stores)
+                            .context("failed to scan the hot tier")?;
+                        let store = ShardedBlobStore::boxed(stores);
+                        let cold = open_blob_store(&tiering.cold_store, verify)?;
+                        if cold.hash_algorithm() != store.hash_algorithm() {
+                            anyhow::bail!(
+                                "cold tier {} keys blobs by {}, the hot tier by {}",
+                                tiering.cold_store.location(),
+                                cold.hash_algorithm(),
+                                store.hash_algorithm()
+                            );
                         }
-                    };
-
-                // Determine snapshot file path (alongside blob store)
-                let snapshot_path = match &cas_config.blob_store {
-                    BlobStoreConfig::File { path } => {
-                        Path::new(path).parent().unwrap_or(Path::new(".")).join("snapshots.json")
+                        let tiered = TieredBlobStore::new(store, cold, tiering.hot_capacity_bytes)
+                            .with_resident(resident);
+                        log::info!(
+                            "  Blob tiering: {} of {} hot bytes in use",
+                            tiered.hot_bytes(),
+                            tiering.hot_capacity_bytes
+                        );
+                        Box::new(tiered)
+                    }
+                    None => {
+                        let name = format!("e{}.{}", target_config.shelf, target_config.slot);
+                        let record =
+                            migrate::record_path(&cas_config.blob_store.state_dir(), &name);
+                        let moved = migrate::load_record(&record)
+                            .with_context(|| format!("failed to load {:?}", record))?;
+                        let configured = cas_config.blob_store.spec();
+                        let (spec, store) = match moved {
+                            Some(spec) if spec != configured => {
+                                log::info!(
+                                    "  Blobs moved to {} (configured: {})",
+                                    spec,
+                                    configured
+                                );
+                                let store = spec.open(None, verify).with_context(|| {
+                                    format!("failed to open blob store {}", spec)
+                                })?;
+                                (spec, store)
+                            }
+                            _ => (configured, open_blob_store(&cas_config.blob_store, verify)?),
+                        };
+                        let store = MigratingBlobStore::new(spec, store, verify, &record);
+                        migrating = Some(store.clone());
+                        Box::new(store)
+                    }
+                };
+                // With a quota, new blobs are attributed to the target and
+                // capped by it
+                let blob_store: Box<dyn BlobStore> = match cas_config.quota_bytes {
+                    Some(quota_bytes) => {
+                        let usage_path = cas_config.blob_store.state_dir().join(format!(
+                            "e{}.{}.usage",
+                            target_config.shelf, target_config.slot
+                        ));
+                        Box::new(
+                            QuotaBlobStore::new(blob_store, Some(quota_bytes), &usage_path)
+                                .with_context(|| format!("failed to load {:?}", usage_path))?,
+                        )
                     }
+                    None => blob_store,
                 };
 
+                let blob_store: Box<dyn aoe_server::blob::BlobStore> = Box::new(
+                    TimedBlobStore::new(
+                        format!("e{}.{} blob store", target_config.shelf, target_config.slot),
+                        blob_store,
+                        slow_threshold,
+                    ),
+                );
+                let blob_store = match &cas_config.encryption {
+                    Some(encryption) => encrypt_blob_store(blob_store, encryption)?,
+                    None => blob_store,
+                };
+
+                let snapshot_path = cas_config.snapshot_path();
+                if let Some(uuid) = target_config.uuid {
+                    TargetUuid::pin(&cas::identity_path(&snapshot_path), uuid)
+                        .context("failed to pin target identity")?;
+                }
+
                 let backend = CasBackend::new(
                     blob_store,
                     cas_config.total_sectors,
@@ -116,27 +305,179 @@ fn main() -> Result<()> {
                         "failed to create CAS backend for shelf {} slot {}",
                         target_config.shelf, target_config.slot
                     )
-                })?;
+                })?
+                .with_sector_size(sector_size)?;
+                let backend = match cas_config.block_size {
+                    Some(block_size) => backend.with_block_size(block_size)?,
+                    None => backend,
+                };
+                let backend = apply_compression(backend, &cas_config.compression)?;
+                let backend = match &cas_config.chunking {
+                    Some(chunking) => {
+                        backend.with_chunking(ChunkerConfig::new(chunking.avg_size)?)
+                    }
+                    None => backend,
+                };
+                let backend = match &cas_config.similarity {
+                    Some(similarity) => backend.with_similarity(similarity.index_blocks),
+                    None => backend,
+                };
+                let backend = backend
+                    .with_readahead(cas_config.readahead_blocks)
+                    .with_write_threads(cas_config.write_threads)
+                    .with_persist_root(match cas_config.persist_root {
+                        PersistRootConfig::OnFlush => PersistRoot::OnFlush,
+                        PersistRootConfig::Interval => PersistRoot::Interval(
+                            Duration::from_secs(cas_config.persist_root_interval_secs),
+                        ),
+                        PersistRootConfig::Manual => PersistRoot::Manual,
+                    })
+                    .with_retention(cas_config.retention.clone())
+                    .with_device_strings(&strings);
 
                 log::info!(
-                    "  CAS backend: {} ({} sectors, snapshots at {})",
-                    match &cas_config.blob_store {
-                        BlobStoreConfig::File { path } => path.as_str(),
-                    },
+                    "  CAS backend: {} ({} sectors, {} byte blocks, snapshots at {})",
+                    cas_config.blob_store.location(),
                     cas_config.total_sectors,
+                    backend.block_size(),
                     snapshot_path.display()
                 );
 
+                Box::new(backend)
+            }
+            BackendType::Device => {
+                let device_config = target_config
+                    .device
+                    .as_ref()
+                    .expect("device config validated");
+
+                let mut backend = if device_config.read_only {
+                    DeviceBackend::open_read_only(&device_config.path)
+                } else {
+                    DeviceBackend::open(&device_config.path)
+                }
+                .with_context(|| format!("failed to open device {}", device_config.path))?
+                .with_barrier(device_config.barrier);
+
+                if let Some(sector_size) = target_config.sector_size {
+                    backend = backend.with_sector_size(sector_size);
+                }
+                if let Some(uuid) = target_config.uuid {
+                    backend = backend.with_uuid(uuid);
+                }
+                let backend = backend.with_device_strings(&strings);
+
+                log::info!(
+                    "  Device backend: {} ({} sectors{}{})",
+                    device_config.path,
+                    backend.info().total_sectors,
+                    if device_config.read_only { ", read-only" } else { "" },
+                    if device_config.barrier { "" } else { ", no barriers" }
+                );
+
+                Box::new(backend)
+            }
+            BackendType::Memory => {
+                let memory_config = target_config
+                    .memory
+                    .as_ref()
+                    .expect("memory config validated");
+
+                let mut backend = MemBackend::new(memory_config.size).with_sector_size(sector_size);
+                if let Some(uuid) = target_config.uuid {
+                    backend = backend.with_uuid(uuid);
+                }
+                let backend = backend.with_device_strings(&strings);
+
+                log::info!(
+                    "  Memory backend: {} sectors (contents lost on exit)",
+                    backend.info().total_sectors
+                );
+
                 Box::new(backend)
             }
         };
 
+        if let Some(uuid) = storage.info().uuid {
+            log::info!(
+                "  Identity {} (serial {}, WWN {:016x})",
+                uuid,
+                storage.info().serial,
+                uuid.wwn()
+            );
+        }
+
         targets.add_target(
             target_config.shelf,
             target_config.slot,
             storage,
             target_config.config_string.clone(),
         );
+        targets.set_backend(
+            target_config.shelf,
+            target_config.slot,
+            target_config.backend.name(),
+        );
+        if let Some(store) = migrating {
+            targets.set_blob_store(target_config.shelf, target_config.slot, store);
+        }
+
+        if target_config.addressing == Addressing::Lba28 {
+            log::info!("  LBA28/CHS compatibility mode (capacity capped at 128 GiB)");
+        }
+        targets.set_addressing(target_config.shelf, target_config.slot, target_config.addressing);
+        targets.set_limits(
+            target_config.shelf,
+            target_config.slot,
+            target_config.buffer_count,
+            target_config.max_sectors,
+        );
+
+        let qos = target_config.qos();
+        if !qos.is_unlimited() {
+            log::info!("  QoS limits: {:?}", qos);
+            targets.set_qos(target_config.shelf, target_config.slot, &qos);
+        }
+    }
+
+    let interface = &config.server.interface;
+    let mtu = config
+        .server
+        .mtu
+        .or_else(|| {
+            if is_interface_pattern(interface) {
+                // Interfaces appearing later are assumed to be no smaller
+                let names = matching_interfaces(interface);
+                names.iter().filter_map(|name| interface_mtu(name)).min()
+            } else {
+                interface_mtu(interface)
+            }
+        })
+        .unwrap_or(DEFAULT_MTU);
+    targets.set_mtu(mtu);
+    if let Some(secs) = config.server.health_check_secs {
+        targets.set_health_interval(Duration::from_secs(secs));
+    }
+    for target_config in &config.target {
+        let addr = TargetAddr::new(target_config.shelf, target_config.slot);
+        if let (Some(max), Some(target)) = (target_config.max_sectors, targets.target(addr)) {
+            let fits = max_sectors_per_frame(mtu, target.storage.info().sector_size);
+            if max > fits {
+                log::warn!(
+                    "Target {} advertises {} sectors per request but only {} fit in MTU {}",
+                    addr.name(),
+                    max,
+                    fits,
+                    mtu
+                );
+            }
+        }
+    }
+
+    if let Some(path) = &config.server.state_file {
+        targets
+            .set_state_file(Path::new(path))
+            .with_context(|| format!("failed to load state file {}", path))?;
     }
 
     log::info!(
@@ -146,27 +487,308 @@ fn main() -> Result<()> {
     );
 
     // Create and run listener
-    let mut listener = AoeListener::new(&config.server.interface, targets)
-        .context("failed to create AoE listener")?;
+    #[cfg(target_os = "linux")]
+    if config.server.packet_ring {
+        let transport = aoe_server::server::RingTransport::new(interface)
+            .context("failed to create AoE listener")?;
+        return serve(
+            AoeListener::with_transport(transport, targets),
+            &config,
+            trace_pcap.as_deref(),
+        );
+    }
+    if is_interface_pattern(interface) {
+        let listener = AoeListener::with_transport(InterfaceSet::new(interface), targets);
+        serve(listener, &config, trace_pcap.as_deref())
+    } else {
+        let listener =
+            AoeListener::new(interface, targets).context("failed to create AoE listener")?;
+        serve(listener, &config, trace_pcap.as_deref())
+    }
+}
+
+/// Run a listener until shutdown, with the pcap trace and HTTP API if set
+/// `config check` validates a config file; `config upgrade` prints it
+/// rewritten to the current version, or with `--write` replaces it
+fn config_command(args: &[String]) -> Result<()> {
+    match args {
+        [cmd, path] if cmd == "check" => {
+            let config =
+                Config::load(path).with_context(|| format!("invalid config {}", path))?;
+            for key in &config.ignored_keys {
+                println!("{}: unknown key {} is ignored", path, key);
+            }
+            if config.version < CONFIG_VERSION {
+                println!(
+                    "{}: version {}, `aoe-server config upgrade` moves it to {}",
+                    path, config.version, CONFIG_VERSION
+                );
+            }
+            println!("{}: OK ({} targets)", path, config.target.len());
+            Ok(())
+        }
+        [cmd, rest @ ..] if cmd == "upgrade" => {
+            let (write, path) = match rest {
+                [flag, path] if flag == "--write" => (true, path),
+                [path] => (false, path),
+                _ => anyhow::bail!("usage: aoe-server config upgrade [--write] <CONFIG>"),
+            };
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path))?;
+            let (upgraded, changes) = aoe_server::config::upgrade(&content)
+                .with_context(|| format!("invalid config {}", path))?;
+            if !write {
+                print!("{}", upgraded);
+                return Ok(());
+            }
+            for change in &changes {
+                eprintln!("{}: {}", path, change);
+            }
+            if !changes.is_empty() {
+                let backup = format!("{}.bak", path);
+                std::fs::copy(path, &backup)
+                    .with_context(|| format!("failed to back up {} to {}", path, backup))?;
+                std::fs::write(path, upgraded)
+                    .with_context(|| format!("failed to write {}", path))?;
+                eprintln!("{}: upgraded to version {} ({} kept)", path, CONFIG_VERSION, backup);
+            } else {
+                eprintln!("{}: already version {}", path, CONFIG_VERSION);
+            }
+            Ok(())
+        }
+        _ => anyhow::bail!(
+            "usage: aoe-server config check <CONFIG> | config upgrade [--write] <CONFIG>"
+        ),
+    }
+}
+
+fn serve<T: FrameTransport>(
+    mut listener: AoeListener<T>,
+    config: &Config,
+    trace_pcap: Option<&str>,
+) -> Result<()> {
+    if let Some(workers) = config.server.workers {
+        listener = listener.with_workers(workers);
+    }
+    if let Some(path) = trace_pcap {
+        let pcap = PcapWriter::create(path)
+            .with_context(|| format!("failed to create pcap trace {}", path))?;
+        log::info!("Tracing AoE frames to {}", path);
+        listener = listener.with_pcap(pcap);
+    }
+
+    if let Some(bind) = &config.server.api {
+        let token = config.server.api_token.clone();
+        let (addr, _) = aoe_server::server::api::spawn(bind, listener.target_manager(), token)
+            .with_context(|| format!("failed to start HTTP API on {}", bind))?;
+        log::info!("HTTP API listening on http://{}", addr);
+    }
 
     log::info!("Starting AoE server...");
+    aoe_server::systemd::notify_ready();
     listener.run().context("server error")?;
+    log::info!("AoE server stopped");
 
     Ok(())
 }
 
-/// Parse log level string
-fn parse_log_level(level: &str) -> log::LevelFilter {
-    match level.to_lowercase().as_str() {
-        "trace" => log::LevelFilter::Trace,
-        "debug" => log::LevelFilter::Debug,
-        "info" => log::LevelFilter::Info,
-        "warn" | "warning" => log::LevelFilter::Warn,
-        "error" => log::LevelFilter::Error,
-        "off" => log::LevelFilter::Off,
-        _ => {
-            eprintln!("Unknown log level '{}', defaulting to 'info'", level);
-            log::LevelFilter::Info
+/// Open a file backend using io_uring
+#[cfg(all(target_os = "linux", feature = "uring"))]
+fn open_uring_backend(
+    path: &str,
+    size: Option<u64>,
+    sector_size: u32,
+    reserve: Option<SpaceReserve>,
+    strings: &DeviceStrings,
+) -> Result<Box<dyn BlockStorage>> {
+    use aoe_server::storage::UringFileBackend;
+
+    let backend = match size {
+        Some(size) => UringFileBackend::open_or_create(path, size),
+        None => UringFileBackend::open(path),
+    }
+    .with_context(|| format!("failed to open io_uring file backend at {}", path))?;
+
+    let backend = backend
+        .with_sector_size(sector_size)?
+        .with_device_strings(strings);
+    match reserve {
+        Some(reserve) => Ok(Box::new(backend.with_reserve(reserve))),
+        None => Ok(Box::new(backend)),
+    }
+}
+
+/// Open a file backend using io_uring (unavailable in this build)
+#[cfg(not(all(target_os = "linux", feature = "uring")))]
+fn open_uring_backend(
+    _path: &str,
+    _size: Option<u64>,
+    _sector_size: u32,
+    _reserve: Option<SpaceReserve>,
+    _strings: &DeviceStrings,
+) -> Result<Box<dyn BlockStorage>> {
+    anyhow::bail!("io_uring support requires building with the `uring` feature")
+}
+
+/// Check an existing backing file against its config: it must be whole
+/// sectors within LBA48, and a configured size only applies when creating
+/// it, so a larger file is served whole
+fn check_file_size(config: &FileBackendConfig, sector_size: u32) -> Result<()> {
+    let len = match std::fs::metadata(&config.path) {
+        Ok(metadata) if metadata.is_file() => metadata.len(),
+        // Created below, or the open reports why it can't be
+        _ => return Ok(()),
+    };
+    let sector_size = u64::from(sector_size);
+    match config.size {
+        // Extended to the configured size, which is already checked
+        Some(size) if len <= size => return Ok(()),
+        Some(size) => log::warn!(
+            "{} is {} bytes, more than its configured size of {}; serving the whole file",
+            config.path,
+            len,
+            size
+        ),
+        None => {}
+    }
+    if len == 0 || len % sector_size != 0 {
+        anyhow::bail!(
+            "{} is {} bytes, not a non-zero multiple of the {}-byte sector size",
+            config.path,
+            len,
+            sector_size
+        );
+    }
+    if len / sector_size > LBA48_MAX_SECTORS {
+        anyhow::bail!(
+            "{} is {} sectors, beyond the LBA48 limit of {}",
+            config.path,
+            len / sector_size,
+            LBA48_MAX_SECTORS
+        );
+    }
+    Ok(())
+}
+
+/// Open a blob store of any type
+fn open_blob_store(
+    config: &BlobStoreConfig,
+    verify: bool,
+) -> Result<Box<dyn aoe_server::blob::BlobStore>> {
+    match config {
+        BlobStoreConfig::File { .. } => Ok(ShardedBlobStore::boxed(open_file_blob_stores(
+            config, verify,
+        )?)),
+        BlobStoreConfig::Remote {
+            addr,
+            state_dir,
+            idle_connections,
+            tls: tls_config,
+        } => {
+            std::fs::create_dir_all(state_dir)
+                .with_context(|| format!("failed to create state directory: {}", state_dir))?;
+            let mut pool = CasPoolConfig::default();
+            if let Some(idle) = idle_connections {
+                pool.max_idle = *idle;
+            }
+            if let Some(tls_config) = tls_config {
+                tls::install_reload_signal()?;
+                let client = TlsClient::new(tls_config.clone())
+                    .with_context(|| format!("failed to load TLS certificates for {}", addr))?;
+                pool.tls = Some(Arc::new(client));
+            }
+            let store = if verify {
+                RemoteBlobStore::new(addr, pool)
+            } else {
+                RemoteBlobStore::unverified(addr, pool)
+            }
+            .with_context(|| format!("failed to connect to blob server {}", addr))?;
+            Ok(Box::new(store))
+        }
+    }
+}
+
+/// Open a file blob store's directory and its shard directories, if any
+fn open_file_blob_stores(config: &BlobStoreConfig, verify: bool) -> Result<Vec<FileBlobStore>> {
+    let BlobStoreConfig::File {
+        path,
+        shards,
+        min_free_bytes,
+        alarm_free_bytes,
+        hash,
+    } = config
+    else {
+        anyhow::bail!("{} is not a file blob store", config.location());
+    };
+    let algorithm = *hash;
+    let mut stores = Vec::new();
+    for path in std::iter::once(path).chain(shards) {
+        std::fs::create_dir_all(path)
+            .with_context(|| format!("failed to create blob store directory: {}", path))?;
+        let store = if verify {
+            FileBlobStore::new(path)
+        } else {
+            FileBlobStore::unverified(path)
+        }
+        .and_then(|store| store.with_hash_algorithm(algorithm))
+        .with_context(|| format!("failed to create file blob store at {}", path))?;
+        stores.push(if min_free_bytes.is_none() && alarm_free_bytes.is_none() {
+            store
+        } else {
+            let reserve = SpaceReserve::new(path, min_free_bytes.unwrap_or(0))
+                .with_alarm(alarm_free_bytes.unwrap_or(0));
+            store.with_reserve(reserve)
+        });
+    }
+    if !shards.is_empty() {
+        log::info!("  Blobs sharded across {} directories", stores.len());
+    }
+    if algorithm != HashAlgorithm::Blake3 {
+        log::info!("  Blobs keyed by {}", algorithm);
+    }
+    Ok(stores)
+}
+
+/// Wrap a blob store with at-rest encryption
+fn encrypt_blob_store(
+    inner: Box<dyn aoe_server::blob::BlobStore>,
+    config: &EncryptionConfig,
+) -> Result<Box<dyn aoe_server::blob::BlobStore>> {
+    let material = match (&config.key, &config.key_file) {
+        (Some(key), _) => key.clone().into_bytes(),
+        (None, Some(path)) => std::fs::read(path)
+            .with_context(|| format!("failed to read encryption key file {}", path))?,
+        (None, None) => anyhow::bail!("encryption requires key or key_file"),
+    };
+    let key = aoe_server::blob::encrypted::parse_key(&material)
+        .context("invalid blob encryption key")?;
+
+    let cipher = match config.cipher {
+        CipherConfig::Aes256Gcm => Cipher::Aes256Gcm,
+        CipherConfig::Xchacha20Poly1305 => Cipher::XChaCha20Poly1305,
+    };
+    log::info!("  Blob encryption: {:?}", cipher);
+
+    Ok(Box::new(EncryptedBlobStore::new(inner, key, cipher)))
+}
+
+/// Configure CAS block compression, loading a zstd dictionary if given
+fn apply_compression(backend: CasBackend, config: &CompressionConfig) -> Result<CasBackend> {
+    match config {
+        CompressionConfig::None => Ok(backend.with_compression(Compression::None)),
+        CompressionConfig::Lz4 => Ok(backend.with_compression(Compression::Lz4)),
+        CompressionConfig::Zstd { level, dictionary } => {
+            let backend = backend.with_compression(Compression::Zstd { level: *level });
+            match dictionary {
+                Some(path) => {
+                    let data = std::fs::read(path)
+                        .with_context(|| format!("failed to read zstd dictionary {}", path))?;
+                    backend
+                        .with_dictionary(data)
+                        .with_context(|| format!("invalid zstd dictionary {}", path))
+                }
+                None => Ok(backend),
+            }
         }
     }
 }
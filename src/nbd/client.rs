@@ -0,0 +1,411 @@
+//! NBD client
+//!
+//! Fixed newstyle negotiation with NBD_OPT_GO, then simple-reply
+//! read/write/flush/trim requests, one at a time. Used by the tests to
+//! drive `NbdServer` end to end and to pull remote images into local
+//! storage.
+
+use super::protocol::*;
+use crate::net::Stream;
+use crate::storage::BlockStorage;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::time::Duration;
+
+/// Largest request sent by default
+const DEFAULT_MAX_REQUEST: u32 = 64 * 1024;
+
+/// Give up on TCP connects after this long
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection to one NBD export
+pub struct NbdClient {
+    reader: BufReader<Stream>,
+    writer: BufWriter<Stream>,
+    size: u64,
+    flags: u16,
+    block_size: u32,
+    max_request: u32,
+    next_handle: u64,
+    description: Option<String>,
+}
+
+impl NbdClient {
+    /// Connect to `host:port` or `unix:/path` and negotiate `export`
+    pub fn connect(addr: &str, export: &str) -> io::Result<Self> {
+        let stream = Stream::connect(addr, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = BufWriter::new(stream);
+
+        let magic = reader.read_u64::<BigEndian>()?;
+        let opts_magic = reader.read_u64::<BigEndian>()?;
+        if magic != NBD_MAGIC || opts_magic != NBD_OPTS_MAGIC {
+            return Err(invalid("server does not speak newstyle NBD".to_string()));
+        }
+        let handshake_flags = reader.read_u16::<BigEndian>()?;
+        if handshake_flags & NBD_FLAG_FIXED_NEWSTYLE == 0 {
+            return Err(invalid("server does not support fixed newstyle".to_string()));
+        }
+        let mut client_flags = NBD_FLAG_C_FIXED_NEWSTYLE;
+        if handshake_flags & NBD_FLAG_NO_ZEROES != 0 {
+            client_flags |= NBD_FLAG_C_NO_ZEROES;
+        }
+        writer.write_u32::<BigEndian>(client_flags)?;
+
+        // NBD_OPT_GO: export name, then no info requests
+        let mut data = Vec::with_capacity(6 + export.len());
+        data.write_u32::<BigEndian>(export.len() as u32)?;
+        data.extend_from_slice(export.as_bytes());
+        data.write_u16::<BigEndian>(0)?;
+        writer.write_u64::<BigEndian>(NBD_OPTS_MAGIC)?;
+        writer.write_u32::<BigEndian>(NBD_OPT_GO)?;
+        writer.write_u32::<BigEndian>(data.len() as u32)?;
+        writer.write_all(&data)?;
+        writer.flush()?;
+
+        let mut client = Self {
+            reader,
+            writer,
+            size: 0,
+            flags: 0,
+            block_size: 1,
+            max_request: DEFAULT_MAX_REQUEST,
+            next_handle: 1,
+            description: None,
+        };
+        client.read_go_replies()?;
+        Ok(client)
+    }
+
+    fn read_go_replies(&mut self) -> io::Result<()> {
+        let mut have_export = false;
+        loop {
+            let magic = self.reader.read_u64::<BigEndian>()?;
+            if magic != NBD_OPT_REPLY_MAGIC {
+                return Err(invalid(format!("invalid option reply magic: 0x{:016x}", magic)));
+            }
+            let _option = self.reader.read_u32::<BigEndian>()?;
+            let reply_type = self.reader.read_u32::<BigEndian>()?;
+            let len = self.reader.read_u32::<BigEndian>()?;
+            let mut data = vec![0u8; len as usize];
+            self.reader.read_exact(&mut data)?;
+
+            match reply_type {
+                NBD_REP_ACK if have_export => return Ok(()),
+                NBD_REP_ACK => return Err(invalid("server sent no export info".to_string())),
+                NBD_REP_INFO => {
+                    let mut info = &data[..];
+                    match info.read_u16::<BigEndian>()? {
+                        NBD_INFO_EXPORT => {
+                            self.size = info.read_u64::<BigEndian>()?;
+                            self.flags = info.read_u16::<BigEndian>()?;
+                            have_export = true;
+                        }
+                        NBD_INFO_BLOCK_SIZE => {
+                            let _min = info.read_u32::<BigEndian>()?;
+                            self.block_size = info.read_u32::<BigEndian>()?.max(1);
+                            let max = info.read_u32::<BigEndian>()?;
+                            self.max_request = DEFAULT_MAX_REQUEST.min(max).max(self.block_size);
+                        }
+                        NBD_INFO_DESCRIPTION => {
+                            self.description = Some(String::from_utf8_lossy(info).into_owned());
+                        }
+                        _ => {}
+                    }
+                }
+                t if t & (1 << 31) != 0 => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!(
+                            "server refused export (error {:#x}): {}",
+                            t,
+                            String::from_utf8_lossy(&data)
+                        ),
+                    ));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Export size in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Preferred block size
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// The server's description of the export, if it sent one
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.flags & NBD_FLAG_READ_ONLY != 0
+    }
+
+    /// Read `len` bytes at `offset`
+    pub fn read(&mut self, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(len);
+        while data.len() < len {
+            let n = (len - data.len()).min(self.max_request as usize);
+            let start = data.len();
+            data.resize(start + n, 0);
+            self.request(NbdCommand::Read, offset + start as u64, n as u32, &[])?;
+            self.reader.read_exact(&mut data[start..])?;
+        }
+        Ok(data)
+    }
+
+    /// Write `data` at `offset`
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let mut done = 0;
+        for chunk in data.chunks(self.max_request as usize) {
+            self.request(NbdCommand::Write, offset + done, chunk.len() as u32, chunk)?;
+            done += chunk.len() as u64;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.request(NbdCommand::Flush, 0, 0, &[])
+    }
+
+    /// Discard `len` bytes at `offset`
+    pub fn trim(&mut self, offset: u64, len: u32) -> io::Result<()> {
+        self.request(NbdCommand::Trim, offset, len, &[])
+    }
+
+    /// Zero `len` bytes at `offset` without sending the zeroes
+    pub fn write_zeroes(&mut self, offset: u64, len: u32) -> io::Result<()> {
+        self.request(NbdCommand::WriteZeroes, offset, len, &[])
+    }
+
+    /// Flush the export and snapshot it on the server (a VoE extension),
+    /// returning the snapshot id. Fails with EOPNOTSUPP if the export's
+    /// backend keeps no snapshots.
+    pub fn snapshot(&mut self, description: Option<&str>) -> io::Result<String> {
+        let description = description.unwrap_or_default().as_bytes();
+        if description.len() > NBD_SNAPSHOT_MAX_DESCRIPTION as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "snapshot description too long",
+            ));
+        }
+        self.request(NbdCommand::Snapshot, 0, description.len() as u32, description)?;
+        let mut id = vec![0u8; NBD_SNAPSHOT_ID_LEN];
+        self.reader.read_exact(&mut id)?;
+        let end = id.iter().position(|&b| b == 0).unwrap_or(id.len());
+        String::from_utf8(id[..end].to_vec())
+            .map_err(|_| invalid("snapshot id is not UTF-8".to_string()))
+    }
+
+    /// Copy the whole export into `storage`, calling `progress` with the
+    /// bytes copied so far. Returns the number of bytes copied.
+    pub fn copy_to(
+        &mut self,
+        storage: &dyn BlockStorage,
+        mut progress: impl FnMut(u64),
+    ) -> io::Result<u64> {
+        let info = storage.info();
+        let sector_size = info.sector_size as u64;
+        if self.size > info.size_bytes() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "export is {} bytes but the destination only holds {}",
+                    self.size,
+                    info.size_bytes()
+                ),
+            ));
+        }
+        if !self.size.is_multiple_of(sector_size) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("export size {} is not a multiple of {}", self.size, sector_size),
+            ));
+        }
+
+        // Whole sectors, and no more than the storage takes in one write
+        let chunk = (self.max_request as u64 / sector_size).clamp(1, 255) * sector_size;
+        let mut offset = 0;
+        while offset < self.size {
+            let len = chunk.min(self.size - offset);
+            let data = self.read(offset, len as usize)?;
+            storage
+                .write(offset / sector_size, &data)
+                .map_err(|e| io::Error::other(format!("write at {}: {}", offset, e)))?;
+            offset += len;
+            progress(offset);
+        }
+        storage
+            .flush()
+            .map_err(|e| io::Error::other(format!("flush failed: {}", e)))?;
+        Ok(offset)
+    }
+
+    /// Tell the server we're done and close the connection
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send_request(NbdCommand::Disc, 0, 0, &[])?;
+        self.writer.flush()
+    }
+
+    /// Send a request and wait for its reply header. Read payloads are
+    /// left for the caller.
+    fn request(&mut self, command: NbdCommand, offset: u64, len: u32, data: &[u8]) -> io::Result<()> {
+        let handle = self.send_request(command, offset, len, data)?;
+        self.writer.flush()?;
+
+        let magic = self.reader.read_u32::<BigEndian>()?;
+        if magic != NBD_SIMPLE_REPLY_MAGIC {
+            return Err(invalid(format!("invalid reply magic: 0x{:08x}", magic)));
+        }
+        let error = self.reader.read_u32::<BigEndian>()?;
+        let reply_handle = self.reader.read_u64::<BigEndian>()?;
+        if reply_handle != handle {
+            return Err(invalid(format!(
+                "reply for handle {:#x}, expected {:#x}",
+                reply_handle, handle
+            )));
+        }
+        if error != 0 {
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+        Ok(())
+    }
+
+    fn send_request(
+        &mut self,
+        command: NbdCommand,
+        offset: u64,
+        len: u32,
+        data: &[u8],
+    ) -> io::Result<u64> {
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.writer.write_u32::<BigEndian>(NBD_REQUEST_MAGIC)?;
+        self.writer.write_u32::<BigEndian>(command as u32)?;
+        self.writer.write_u64::<BigEndian>(handle)?;
+        self.writer.write_u64::<BigEndian>(offset)?;
+        self.writer.write_u32::<BigEndian>(len)?;
+        self.writer.write_all(data)?;
+        Ok(handle)
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nbd::{NbdServer, NbdServerConfig};
+    use crate::storage::MemBackend;
+    use std::net::TcpListener;
+    use std::thread;
+
+    fn serve(storage: MemBackend, read_only: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = NbdServer::new(
+            NbdServerConfig {
+                read_only,
+                ..NbdServerConfig::default()
+            },
+            storage,
+        );
+        thread::spawn(move || server.serve(listener));
+        addr
+    }
+
+    #[test]
+    fn test_read_write_and_copy() {
+        let addr = serve(MemBackend::new(1024 * 1024), false);
+        let mut client = NbdClient::connect(&addr, "cas-disk").unwrap();
+        assert_eq!(client.size(), 1024 * 1024);
+        assert_eq!(client.block_size(), 512);
+        assert!(!client.is_read_only());
+        assert!(client.description().unwrap().starts_with("AoE Memory Backend MEM-"));
+
+        // Larger than one request
+        let data: Vec<u8> = (0..300 * 1024).map(|i| (i % 253) as u8).collect();
+        client.write(4096, &data).unwrap();
+        client.flush().unwrap();
+        assert_eq!(client.read(4096, data.len()).unwrap(), data);
+        // Unaligned reads are fine
+        assert_eq!(client.read(4097, 10).unwrap(), data[1..11]);
+        client.trim(0, 4096).unwrap();
+        client.write_zeroes(8192, 1024).unwrap();
+        assert_eq!(client.read(7680, 512).unwrap(), data[3584..4096]);
+        assert_eq!(client.read(8192, 1024).unwrap(), vec![0u8; 1024]);
+        assert_eq!(client.read(9216, 512).unwrap(), data[5120..5632]);
+        let err = client.write_zeroes(8192, 100).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+
+        let local = MemBackend::new(2 * 1024 * 1024);
+        let mut last = 0;
+        let copied = client.copy_to(&local, |done| last = done).unwrap();
+        assert_eq!(copied, 1024 * 1024);
+        assert_eq!(last, copied);
+        assert_eq!(local.read(8, 255).unwrap()[..512], data[..512]);
+        client.disconnect().unwrap();
+
+        // Same export over a UNIX domain socket
+        let temp = tempfile::TempDir::new().unwrap();
+        let unix_addr = format!("unix:{}", temp.path().join("nbd.sock").display());
+        let listener = crate::net::Listener::bind(&unix_addr).unwrap();
+        let server = NbdServer::new(NbdServerConfig::default(), local);
+        thread::spawn(move || server.serve(listener));
+        let mut unix = NbdClient::connect(&unix_addr, "cas-disk").unwrap();
+        assert_eq!(unix.read(4096, 512).unwrap(), data[..512]);
+        unix.disconnect().unwrap();
+
+        let ro_addr = serve(MemBackend::new(64 * 1024), true);
+        let mut ro = NbdClient::connect(&ro_addr, "cas-disk").unwrap();
+        assert!(ro.is_read_only());
+        let err = ro.write(0, &[0u8; 512]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EPERM));
+        // The connection is still usable after an error reply
+        assert_eq!(ro.read(0, 512).unwrap(), vec![0u8; 512]);
+        assert!(ro.copy_to(&MemBackend::new(4096), |_| {}).is_err());
+        let err = ro.snapshot(None).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EOPNOTSUPP));
+        assert_eq!(ro.read(0, 512).unwrap(), vec![0u8; 512]);
+    }
+
+    #[test]
+    fn test_snapshot_command() {
+        use crate::blob::FileBlobStore;
+        use crate::storage::{ArchivalStorage, CasBackend};
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let open = || {
+            let store = FileBlobStore::new(temp.path().join("blobs")).unwrap();
+            CasBackend::new(Box::new(store), 2048, &temp.path().join("snapshots.json")).unwrap()
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = NbdServer::new(NbdServerConfig::default(), open());
+        thread::spawn(move || server.serve(listener));
+
+        let mut client = NbdClient::connect(&addr, "cas-disk").unwrap();
+        client.write(0, &[9u8; 4096]).unwrap();
+        let id = client.snapshot(Some("before upgrade")).unwrap();
+        client.write(0, &[1u8; 4096]).unwrap();
+        client.disconnect().unwrap();
+
+        // The snapshot holds the data written before it, flushed
+        let cas = open();
+        let taken = cas.list_snapshots().unwrap();
+        assert_eq!(taken.last().unwrap().id, id);
+        assert_eq!(taken.last().unwrap().description.as_deref(), Some("before upgrade"));
+        cas.restore(&id).unwrap();
+        assert_eq!(cas.read(0, 8).unwrap(), vec![9u8; 4096]);
+    }
+}
@@ -1,9 +1,11 @@
-//! NBD (Network Block Device) server implementation
+//! NBD (Network Block Device) server and client
 //!
 //! NBD protocol is simpler than iSCSI and works well with our CAS backend.
 //! Linux has native NBD support, Windows needs third-party drivers.
 
+pub mod client;
 pub mod protocol;
 pub mod server;
 
-pub use server::{NbdServer, NbdServerConfig};
+pub use client::NbdClient;
+pub use server::{NbdServer, NbdServerConfig, DEFAULT_QUEUE_DEPTH};
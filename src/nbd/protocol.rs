@@ -15,22 +15,60 @@ pub const NBD_SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
 pub const NBD_OPT_REPLY_MAGIC: u64 = 0x3e889045565a9; // Option reply magic
 
 /// NBD handshake flags
-pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = (1 << 0);
-pub const NBD_FLAG_NO_ZEROES: u16 = (1 << 1);
+pub const NBD_FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+pub const NBD_FLAG_NO_ZEROES: u16 = 1 << 1;
 
 /// NBD client flags
-pub const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = (1 << 0);
-pub const NBD_FLAG_C_NO_ZEROES: u32 = (1 << 1);
+pub const NBD_FLAG_C_FIXED_NEWSTYLE: u32 = 1 << 0;
+pub const NBD_FLAG_C_NO_ZEROES: u32 = 1 << 1;
 
 /// NBD options
 pub const NBD_OPT_EXPORT_NAME: u32 = 1;
 pub const NBD_OPT_ABORT: u32 = 2;
 pub const NBD_OPT_LIST: u32 = 3;
+pub const NBD_OPT_INFO: u32 = 6;
+pub const NBD_OPT_GO: u32 = 7;
+pub const NBD_OPT_STRUCTURED_REPLY: u32 = 8;
+pub const NBD_OPT_LIST_META_CONTEXT: u32 = 9;
+pub const NBD_OPT_SET_META_CONTEXT: u32 = 10;
 
 /// NBD option replies
 pub const NBD_REP_ACK: u32 = 1;
 pub const NBD_REP_SERVER: u32 = 2;
+pub const NBD_REP_INFO: u32 = 3;
+pub const NBD_REP_META_CONTEXT: u32 = 4;
 pub const NBD_REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+pub const NBD_REP_ERR_INVALID: u32 = (1 << 31) | 3;
+
+/// NBD info types (for NBD_OPT_INFO / NBD_OPT_GO)
+pub const NBD_INFO_EXPORT: u16 = 0;
+pub const NBD_INFO_DESCRIPTION: u16 = 2;
+pub const NBD_INFO_BLOCK_SIZE: u16 = 3;
+
+/// Structured replies (NBD_OPT_STRUCTURED_REPLY)
+pub const NBD_STRUCTURED_REPLY_MAGIC: u32 = 0x668e33ef;
+pub const NBD_REPLY_FLAG_DONE: u16 = 1 << 0;
+pub const NBD_REPLY_TYPE_NONE: u16 = 0;
+pub const NBD_REPLY_TYPE_OFFSET_DATA: u16 = 1;
+pub const NBD_REPLY_TYPE_BLOCK_STATUS: u16 = 5;
+pub const NBD_REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+
+/// Command flags (upper 16 bits of the request's command field)
+pub const NBD_CMD_FLAG_REQ_ONE: u16 = 1 << 3;
+
+/// Standard allocation context: holes are flagged `NBD_STATE_HOLE` and,
+/// as they read back as zeroes, `NBD_STATE_ZERO`
+pub const NBD_META_BASE_ALLOCATION: &str = "base:allocation";
+pub const NBD_STATE_HOLE: u32 = 1 << 0;
+pub const NBD_STATE_ZERO: u32 = 1 << 1;
+
+/// Metadata context namespace for changed blocks: `voe:dirty:<snapshot>`
+/// marks extents that differ from the snapshot with `NBD_STATE_DIRTY`
+pub const VOE_DIRTY_CONTEXT_PREFIX: &str = "voe:dirty:";
+pub const NBD_STATE_DIRTY: u32 = 1 << 0;
+
+/// Largest payload we accept in one request
+pub const NBD_MAX_PAYLOAD: u32 = 32 * 1024 * 1024;
 
 /// NBD commands
 #[repr(u32)]
@@ -41,7 +79,13 @@ pub enum NbdCommand {
     Disc = 2, // Disconnect
     Flush = 3,
     Trim = 4,
+    Cache = 5,
     WriteZeroes = 6,
+    BlockStatus = 7,
+    /// VoE extension: flush and snapshot the export. The payload is an
+    /// optional description; the reply carries `NBD_SNAPSHOT_ID_LEN`
+    /// bytes holding the snapshot id, NUL padded.
+    Snapshot = 0x5653,
 }
 
 impl NbdCommand {
@@ -52,18 +96,28 @@ impl NbdCommand {
             2 => Some(NbdCommand::Disc),
             3 => Some(NbdCommand::Flush),
             4 => Some(NbdCommand::Trim),
+            5 => Some(NbdCommand::Cache),
             6 => Some(NbdCommand::WriteZeroes),
+            7 => Some(NbdCommand::BlockStatus),
+            0x5653 => Some(NbdCommand::Snapshot),
             _ => None,
         }
     }
 }
 
+/// Size of a snapshot reply's payload
+pub const NBD_SNAPSHOT_ID_LEN: usize = 128;
+
+/// Longest snapshot description accepted
+pub const NBD_SNAPSHOT_MAX_DESCRIPTION: u32 = 1024;
+
 /// NBD transmission flags
-pub const NBD_FLAG_HAS_FLAGS: u16 = (1 << 0);
-pub const NBD_FLAG_READ_ONLY: u16 = (1 << 1);
-pub const NBD_FLAG_SEND_FLUSH: u16 = (1 << 2);
-pub const NBD_FLAG_SEND_TRIM: u16 = (1 << 5);
-pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = (1 << 6);
+pub const NBD_FLAG_HAS_FLAGS: u16 = 1 << 0;
+pub const NBD_FLAG_READ_ONLY: u16 = 1 << 1;
+pub const NBD_FLAG_SEND_FLUSH: u16 = 1 << 2;
+pub const NBD_FLAG_SEND_TRIM: u16 = 1 << 5;
+pub const NBD_FLAG_SEND_WRITE_ZEROES: u16 = 1 << 6;
+pub const NBD_FLAG_SEND_CACHE: u16 = 1 << 10;
 
 /// NBD request
 #[derive(Debug)]
@@ -102,6 +156,10 @@ impl NbdRequest {
     pub fn command_type(&self) -> Option<NbdCommand> {
         NbdCommand::from_u32(self.command & 0xffff)
     }
+
+    pub fn flags(&self) -> u16 {
+        (self.command >> 16) as u16
+    }
 }
 
 /// NBD simple reply
@@ -128,6 +186,80 @@ impl NbdReply {
     }
 }
 
+/// Write one structured reply chunk
+pub fn write_structured_chunk<W: Write>(
+    writer: &mut W,
+    flags: u16,
+    reply_type: u16,
+    handle: u64,
+    payload: &[u8],
+) -> io::Result<()> {
+    writer.write_u32::<BigEndian>(NBD_STRUCTURED_REPLY_MAGIC)?;
+    writer.write_u16::<BigEndian>(flags)?;
+    writer.write_u16::<BigEndian>(reply_type)?;
+    writer.write_u64::<BigEndian>(handle)?;
+    writer.write_u32::<BigEndian>(payload.len() as u32)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Write a final error chunk with no message
+pub fn write_structured_error<W: Write>(writer: &mut W, handle: u64, error: u32) -> io::Result<()> {
+    let mut payload = Vec::with_capacity(6);
+    payload.write_u32::<BigEndian>(error)?;
+    payload.write_u16::<BigEndian>(0)?;
+    write_structured_chunk(writer, NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_ERROR, handle, &payload)
+}
+
+/// What option negotiation settled on
+#[derive(Debug, Default, Clone)]
+pub struct Negotiated {
+    /// Client asked for structured replies
+    pub structured: bool,
+    /// Metadata contexts selected with NBD_OPT_SET_META_CONTEXT, by id
+    pub contexts: Vec<(u32, String)>,
+}
+
+/// Queries from a LIST/SET_META_CONTEXT payload (the export name is ignored)
+fn parse_meta_queries(data: &[u8]) -> io::Result<Vec<String>> {
+    let mut reader = data;
+    let name_len = reader.read_u32::<BigEndian>()? as usize;
+    if name_len > reader.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "export name overruns option"));
+    }
+    reader = &reader[name_len..];
+
+    let count = reader.read_u32::<BigEndian>()?;
+    let mut queries = Vec::new();
+    for _ in 0..count {
+        let len = reader.read_u32::<BigEndian>()? as usize;
+        if len > reader.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "query overruns option"));
+        }
+        queries.push(String::from_utf8_lossy(&reader[..len]).into_owned());
+        reader = &reader[len..];
+    }
+    Ok(queries)
+}
+
+/// Contexts (id, name) matching `queries`: LIST matches by prefix and an
+/// empty query list means all, SET needs exact names
+fn match_meta_contexts(contexts: &[String], queries: &[String], list: bool) -> Vec<(u32, String)> {
+    let matches = |name: &str| {
+        if list {
+            queries.is_empty() || queries.iter().any(|q| name.starts_with(q.as_str()))
+        } else {
+            queries.iter().any(|q| q == name)
+        }
+    };
+    contexts
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| matches(name))
+        .map(|(i, name)| (i as u32 + 1, name.clone()))
+        .collect()
+}
+
 /// Send NBD handshake (oldstyle) - DEPRECATED
 #[allow(dead_code)]
 pub fn send_handshake_oldstyle<W: Write>(writer: &mut W, size: u64, flags: u16) -> io::Result<()> {
@@ -141,13 +273,70 @@ pub fn send_handshake_oldstyle<W: Write>(writer: &mut W, size: u64, flags: u16)
     Ok(())
 }
 
-/// Send NBD newstyle handshake and handle option negotiation
+/// Write an option reply header and payload
+fn write_option_reply<W: Write>(
+    writer: &mut W,
+    option: u32,
+    reply_type: u32,
+    data: &[u8],
+) -> io::Result<()> {
+    writer.write_u64::<BigEndian>(NBD_OPT_REPLY_MAGIC)?;
+    writer.write_u32::<BigEndian>(option)?;
+    writer.write_u32::<BigEndian>(reply_type)?;
+    writer.write_u32::<BigEndian>(data.len() as u32)?;
+    writer.write_all(data)?;
+    Ok(())
+}
+
+/// Reply to NBD_OPT_INFO / NBD_OPT_GO with export, description and block
+/// size info
+fn send_export_info<W: Write>(
+    writer: &mut W,
+    option: u32,
+    size: u64,
+    trans_flags: u16,
+    block_size: u32,
+    description: &str,
+) -> io::Result<()> {
+    let mut export = Vec::with_capacity(12);
+    export.write_u16::<BigEndian>(NBD_INFO_EXPORT)?;
+    export.write_u64::<BigEndian>(size)?;
+    export.write_u16::<BigEndian>(trans_flags)?;
+    write_option_reply(writer, option, NBD_REP_INFO, &export)?;
+
+    // Identifies the device behind the export; clients ignore it unless
+    // they asked for it
+    let mut info = Vec::with_capacity(2 + description.len());
+    info.write_u16::<BigEndian>(NBD_INFO_DESCRIPTION)?;
+    info.extend_from_slice(description.as_bytes());
+    write_option_reply(writer, option, NBD_REP_INFO, &info)?;
+
+    // Minimum and preferred block size are the device sector size
+    let mut block = Vec::with_capacity(14);
+    block.write_u16::<BigEndian>(NBD_INFO_BLOCK_SIZE)?;
+    block.write_u32::<BigEndian>(block_size)?;
+    block.write_u32::<BigEndian>(block_size)?;
+    block.write_u32::<BigEndian>(NBD_MAX_PAYLOAD)?;
+    write_option_reply(writer, option, NBD_REP_INFO, &block)?;
+
+    write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+    writer.flush()
+}
+
+/// Send NBD newstyle handshake and handle option negotiation, offering
+/// `meta_contexts` to clients that negotiate structured replies and
+/// describing the export with `description`
 pub fn send_newstyle_handshake<R: Read, W: Write>(
     reader: &mut R,
     writer: &mut W,
     size: u64,
     trans_flags: u16,
-) -> io::Result<()> {
+    block_size: u32,
+    description: &str,
+    meta_contexts: &[String],
+) -> io::Result<Negotiated> {
+    let mut negotiated = Negotiated::default();
+
     // Send initial greeting
     writer.write_u64::<BigEndian>(NBD_MAGIC)?;
     writer.write_u64::<BigEndian>(NBD_OPTS_MAGIC)?;
@@ -198,7 +387,67 @@ pub fn send_newstyle_handshake<R: Read, W: Write>(
                 writer.flush()?;
 
                 // EXPORT_NAME ends negotiation
-                return Ok(());
+                return Ok(negotiated);
+            }
+
+            NBD_OPT_INFO | NBD_OPT_GO => {
+                // Export name and info requests are ignored: we always send
+                // export and block size info
+                let mut option_data = vec![0u8; option_len as usize];
+                reader.read_exact(&mut option_data)?;
+
+                send_export_info(writer, option, size, trans_flags, block_size, description)?;
+
+                // GO ends negotiation, INFO does not
+                if option == NBD_OPT_GO {
+                    return Ok(negotiated);
+                }
+            }
+
+            NBD_OPT_STRUCTURED_REPLY => {
+                let mut option_data = vec![0u8; option_len as usize];
+                reader.read_exact(&mut option_data)?;
+
+                negotiated.structured = true;
+                write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                writer.flush()?;
+            }
+
+            NBD_OPT_LIST_META_CONTEXT | NBD_OPT_SET_META_CONTEXT => {
+                if option_len > NBD_MAX_PAYLOAD {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("meta context option too long: {}", option_len),
+                    ));
+                }
+                let mut option_data = vec![0u8; option_len as usize];
+                reader.read_exact(&mut option_data)?;
+
+                let queries = match parse_meta_queries(&option_data) {
+                    Ok(queries) if negotiated.structured => queries,
+                    // Contexts are only usable with structured replies
+                    _ => {
+                        write_option_reply(writer, option, NBD_REP_ERR_INVALID, &[])?;
+                        writer.flush()?;
+                        continue;
+                    }
+                };
+
+                let list = option == NBD_OPT_LIST_META_CONTEXT;
+                let matched = match_meta_contexts(meta_contexts, &queries, list);
+                for (id, name) in &matched {
+                    let mut context = Vec::with_capacity(4 + name.len());
+                    context.write_u32::<BigEndian>(if list { 0 } else { *id })?;
+                    context.extend_from_slice(name.as_bytes());
+                    write_option_reply(writer, option, NBD_REP_META_CONTEXT, &context)?;
+                }
+                write_option_reply(writer, option, NBD_REP_ACK, &[])?;
+                writer.flush()?;
+
+                // The last SET replaces any earlier selection
+                if !list {
+                    negotiated.contexts = matched;
+                }
             }
 
             NBD_OPT_ABORT => {
@@ -215,10 +464,7 @@ pub fn send_newstyle_handshake<R: Read, W: Write>(
                 reader.read_exact(&mut option_data)?;
 
                 // Send unsupported reply
-                writer.write_u64::<BigEndian>(NBD_OPT_REPLY_MAGIC)?;
-                writer.write_u32::<BigEndian>(option)?;
-                writer.write_u32::<BigEndian>(NBD_REP_ERR_UNSUP)?;
-                writer.write_u32::<BigEndian>(0)?; // No reply data
+                write_option_reply(writer, option, NBD_REP_ERR_UNSUP, &[])?;
                 writer.flush()?;
             }
         }
@@ -1,26 +1,53 @@
 //! NBD server implementation
+//!
+//! Each connection has a reader thread that parses requests and a pool of
+//! `queue_depth` workers that run them against the shared storage, so one
+//! client can keep several requests in flight. Replies may go out in any
+//! order; clients match them to requests by handle. Each request runs in
+//! a debug-level `nbd` span. A worker that fails or panics closes the
+//! connection, and the queue closes with the last worker, so the reader
+//! never blocks on a queue nobody is draining.
 
 use super::protocol::*;
-use crate::storage::BlockStorage;
-use byteorder::{BigEndian, ReadBytesExt};
+use crate::frontend::{Frontend, FrontendError, FrontendResult};
+use crate::logging::next_request_id;
+use crate::net::{Listener, Stream};
+use crate::qos::{QosLimits, RateLimiter};
+use crate::shutdown;
+use crate::storage::{self, BlockStorage, StorageError};
 use std::io::{self, BufReader, BufWriter, Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::net::Shutdown;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
 use std::sync::{Arc, Mutex};
-use std::thread;
-
-const SECTOR_SIZE: usize = 512;
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
 
 /// NBD server configuration
 pub struct NbdServerConfig {
+    /// `host:port`, or `unix:/path` for a UNIX domain socket
     pub bind_addr: String,
     pub export_name: String,
+    /// Advertise the export read-only and reject writes
+    pub read_only: bool,
+    /// Rate limits shared by all connections to the export
+    pub qos: QosLimits,
+    /// Requests each connection runs concurrently
+    pub queue_depth: usize,
 }
 
+/// Default requests in flight per connection
+pub const DEFAULT_QUEUE_DEPTH: usize = 8;
+
 impl Default for NbdServerConfig {
     fn default() -> Self {
         Self {
             bind_addr: "127.0.0.1:10809".to_string(),
             export_name: "cas-disk".to_string(),
+            read_only: false,
+            qos: QosLimits::default(),
+            queue_depth: DEFAULT_QUEUE_DEPTH,
         }
     }
 }
@@ -28,250 +55,1155 @@ impl Default for NbdServerConfig {
 /// NBD server
 pub struct NbdServer<S: BlockStorage> {
     config: NbdServerConfig,
-    storage: Arc<Mutex<S>>,
+    storage: Arc<S>,
+    limiter: Option<Arc<RateLimiter>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<io::Result<()>>>,
 }
 
 impl<S: BlockStorage + Send + 'static> NbdServer<S> {
     pub fn new(config: NbdServerConfig, storage: S) -> Self {
+        let limiter = (!config.qos.is_unlimited()).then(|| Arc::new(RateLimiter::new(&config.qos)));
         Self {
             config,
-            storage: Arc::new(Mutex::new(storage)),
+            storage: Arc::new(storage),
+            limiter,
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
         }
     }
 
+    /// Bind the configured address and serve clients
     pub fn run(&self) -> io::Result<()> {
-        let listener = TcpListener::bind(&self.config.bind_addr)?;
-        log::info!("NBD server listening on {}", self.config.bind_addr);
+        let listener = Listener::bind(&self.config.bind_addr)?;
+        self.serve(listener)
+    }
+
+    /// Serve clients on an already-bound listener until shutdown is
+    /// requested, then drain and flush
+    pub fn serve(&self, listener: impl Into<Listener>) -> io::Result<()> {
         log::info!("Export name: {}", self.config.export_name);
+        serve_clients(
+            listener.into(),
+            self.config.export_name.clone(),
+            Arc::clone(&self.storage),
+            self.config.read_only,
+            self.config.queue_depth,
+            self.limiter.clone(),
+            Arc::clone(&self.stop),
+        )
+    }
+}
 
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let storage = Arc::clone(&self.storage);
-                    thread::spawn(move || {
-                        if let Err(e) = handle_client(stream, storage) {
-                            log::warn!("Client handler error: {}", e);
-                        }
-                    });
-                }
-                Err(e) => log::error!("Connection error: {}", e),
-            }
+impl<S: BlockStorage + Send + 'static> Frontend for NbdServer<S> {
+    fn protocol(&self) -> &'static str {
+        "nbd"
+    }
+
+    fn attach(&mut self, _name: &str, _storage: Box<dyn BlockStorage>) -> FrontendResult<()> {
+        Err(FrontendError::Unsupported(
+            "an NBD server exports the single device it was created with".to_string(),
+        ))
+    }
+
+    fn targets(&self) -> Vec<String> {
+        vec![self.config.export_name.clone()]
+    }
+
+    fn start(&mut self) -> FrontendResult<()> {
+        if self.thread.is_some() {
+            return Err(FrontendError::AlreadyRunning);
         }
+        let listener = Listener::bind(&self.config.bind_addr)?;
+        log::info!("Export name: {}", self.config.export_name);
 
+        self.stop.store(false, Ordering::SeqCst);
+        let export = self.config.export_name.clone();
+        let storage = Arc::clone(&self.storage);
+        let read_only = self.config.read_only;
+        let queue_depth = self.config.queue_depth;
+        let limiter = self.limiter.clone();
+        let stop = Arc::clone(&self.stop);
+        let thread = thread::Builder::new()
+            .name("nbd-accept".to_string())
+            .spawn(move || {
+                serve_clients(listener, export, storage, read_only, queue_depth, limiter, stop)
+            })?;
+        self.thread = Some(thread);
         Ok(())
     }
+
+    fn stop(&mut self) -> FrontendResult<()> {
+        let thread = self.thread.take().ok_or(FrontendError::NotRunning)?;
+        self.stop.store(true, Ordering::SeqCst);
+        match thread.join() {
+            Ok(result) => result.map_err(FrontendError::from),
+            Err(_) => Err(FrontendError::Protocol("NBD accept loop panicked".to_string())),
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+}
+
+/// Accept clients until shutdown or stop is requested, then drain the
+/// connections and flush the export
+fn serve_clients<S: BlockStorage + 'static>(
+    listener: Listener,
+    export: String,
+    storage: Arc<S>,
+    read_only: bool,
+    queue_depth: usize,
+    limiter: Option<Arc<RateLimiter>>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    // Non-blocking so the accept loop can notice shutdown requests
+    listener.set_nonblocking(true)?;
+    log::info!("NBD server listening on {}", listener.local_addr()?);
+
+    let mut clients: Vec<(Stream, JoinHandle<()>)> = Vec::new();
+    while !shutdown::requested() && !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok(stream) => {
+                stream.set_nonblocking(false)?;
+                let export = export.clone();
+                let storage = Arc::clone(&storage);
+                let limiter = limiter.clone();
+                let control = stream.try_clone()?;
+                let handle = thread::spawn(move || {
+                    let result =
+                        handle_client(stream, &export, storage, read_only, queue_depth, limiter);
+                    if let Err(e) = result {
+                        log::warn!("Client handler error: {}", e);
+                    }
+                });
+                clients.push((control, handle));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                clients.retain(|(_, handle)| !handle.is_finished());
+                thread::sleep(shutdown::POLL_INTERVAL);
+            }
+            Err(e) => log::error!("Connection error: {}", e),
+        }
+    }
+
+    log::info!("Shutting down: draining {} client(s)", clients.len());
+    for (control, handle) in clients {
+        // Clients see EOF on their next request; the current one completes
+        let _ = control.shutdown(Shutdown::Read);
+        if handle.join().is_err() {
+            log::error!("NBD client handler panicked during shutdown");
+        }
+    }
+
+    storage
+        .flush()
+        .map_err(|e| io::Error::other(format!("flush on shutdown failed: {}", e)))
+}
+
+/// A parsed request waiting for a worker
+struct Job {
+    request: NbdRequest,
+    /// Write payload or snapshot description
+    data: Vec<u8>,
 }
 
 /// Handle NBD client connection
 fn handle_client<S: BlockStorage>(
-    stream: TcpStream,
-    storage: Arc<Mutex<S>>,
+    stream: Stream,
+    export: &str,
+    storage: Arc<S>,
+    read_only: bool,
+    queue_depth: usize,
+    limiter: Option<Arc<RateLimiter>>,
 ) -> io::Result<()> {
     let peer_addr = stream.peer_addr()?;
     log::info!("Client connected: {}", peer_addr);
 
     let mut reader = BufReader::new(stream.try_clone()?);
-    let mut writer = BufWriter::new(stream);
+    let mut writer = BufWriter::new(stream.try_clone()?);
 
     // Get device info
-    let device_info = {
-        let storage = storage.lock().unwrap();
-        storage.info().clone()
-    };
+    let device_info = storage.info().clone();
 
-    let size_bytes = device_info.total_sectors * SECTOR_SIZE as u64;
-    let flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH;
+    let size_bytes = device_info.size_bytes();
+    let mut flags = NBD_FLAG_HAS_FLAGS | NBD_FLAG_SEND_FLUSH | NBD_FLAG_SEND_CACHE;
+    if read_only {
+        flags |= NBD_FLAG_READ_ONLY;
+    } else {
+        flags |= NBD_FLAG_SEND_TRIM | NBD_FLAG_SEND_WRITE_ZEROES;
+    }
+
+    // The export is described by its device's persistent identity
+    let description = match device_info.uuid {
+        Some(uuid) => format!("{} {}", device_info.model, uuid),
+        None => format!("{} {}", device_info.model, device_info.serial),
+    };
 
     // Send newstyle handshake and negotiate options
-    send_newstyle_handshake(&mut reader, &mut writer, size_bytes, flags)?;
+    let negotiated = send_newstyle_handshake(
+        &mut reader,
+        &mut writer,
+        size_bytes,
+        flags,
+        device_info.sector_size,
+        &description,
+        &meta_contexts(&*storage),
+    )?;
 
     log::info!(
-        "Completed handshake: size={} bytes ({} sectors)",
+        "Completed handshake: size={} bytes ({} x {}-byte sectors)",
         size_bytes,
-        device_info.total_sectors
+        device_info.total_sectors,
+        device_info.sector_size
     );
 
-    // Handle requests
-    loop {
-        let request = match NbdRequest::read(&mut reader) {
-            Ok(req) => req,
-            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                log::info!("Client disconnected: {}", peer_addr);
-                break;
+    let writer = Mutex::new(writer);
+    let (jobs, queue) = mpsc::sync_channel::<Job>(queue_depth);
+    // Held only by the workers: once they have all exited, `jobs.send`
+    // fails instead of blocking
+    let queue = Arc::new(Mutex::new(queue));
+
+    thread::scope(|scope| {
+        let peer = peer_addr.as_str();
+        let (writer, stream, negotiated) = (&writer, &stream, &negotiated);
+        let storage = &*storage;
+        for _ in 0..queue_depth.max(1) {
+            let queue = Arc::clone(&queue);
+            scope.spawn(move || {
+                let _closer = CloseOnPanic(stream);
+                let result = run_jobs(&queue, writer, storage, read_only, negotiated, export, peer);
+                if let Err(e) = result {
+                    log::warn!("Failed to reply to {}: {}", peer, e);
+                    // Wake the reader so the connection closes
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+            });
+        }
+        drop(queue);
+
+        // Handle requests; dropping `jobs` lets the workers drain and exit
+        loop {
+            let request = match NbdRequest::read(&mut reader) {
+                Ok(req) => req,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    log::info!("Client disconnected: {}", peer_addr);
+                    break;
+                }
+                Err(e) => {
+                    log::error!("Failed to read request: {}", e);
+                    break;
+                }
+            };
+
+            let cmd = request.command_type();
+            log::debug!(
+                "Request: {:?}, handle={:016x}, offset={}, length={}",
+                cmd,
+                request.handle,
+                request.offset,
+                request.length
+            );
+
+            let mut data = Vec::new();
+            match cmd {
+                Some(NbdCommand::Disc) => {
+                    log::info!("Client requested disconnect: {}", peer_addr);
+                    break;
+                }
+                Some(NbdCommand::Read) => {
+                    if let Some(limiter) = &limiter {
+                        limiter.throttle_read(request.length as usize);
+                    }
+                }
+                Some(NbdCommand::Write) => {
+                    // Rejected writes still carry a payload to drain
+                    data = vec![0u8; request.length as usize];
+                    if let Err(e) = reader.read_exact(&mut data) {
+                        log::error!("Failed to read write payload: {}", e);
+                        break;
+                    }
+                    if !read_only {
+                        if let Some(limiter) = &limiter {
+                            limiter.throttle_write(request.length as usize);
+                        }
+                    }
+                }
+                Some(NbdCommand::Snapshot) => {
+                    if request.length > NBD_SNAPSHOT_MAX_DESCRIPTION {
+                        log::error!("Snapshot description too long: {}", request.length);
+                        break;
+                    }
+                    data = vec![0u8; request.length as usize];
+                    if let Err(e) = reader.read_exact(&mut data) {
+                        log::error!("Failed to read snapshot description: {}", e);
+                        break;
+                    }
+                }
+                _ => {}
             }
-            Err(e) => {
-                log::error!("Failed to read request: {}", e);
+
+            if jobs.send(Job { request, data }).is_err() {
+                log::error!("No workers left for {}; closing the connection", peer_addr);
+                let _ = stream.shutdown(Shutdown::Both);
                 break;
             }
-        };
+        }
+        drop(jobs);
+    });
 
-        let cmd = request.command_type();
-        log::debug!(
-            "Request: {:?}, handle={:016x}, offset={}, length={}",
-            cmd,
-            request.handle,
-            request.offset,
-            request.length
-        );
+    Ok(())
+}
 
-        match cmd {
-            Some(NbdCommand::Read) => {
-                handle_read(&request, &mut writer, &storage)?;
-            }
-            Some(NbdCommand::Write) => {
-                handle_write(&request, &mut reader, &mut writer, &storage)?;
-            }
-            Some(NbdCommand::Flush) => {
-                handle_flush(&request, &mut writer, &storage)?;
+/// Closes a connection if the worker holding it panics, so the client
+/// isn't left waiting for a reply that will never come
+struct CloseOnPanic<'a>(&'a Stream);
+
+impl Drop for CloseOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            let _ = self.0.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Metadata contexts on offer: allocation, then one changed-block context
+/// per snapshot of an archival export
+fn meta_contexts<S: BlockStorage + ?Sized>(storage: &S) -> Vec<String> {
+    let mut contexts = vec![NBD_META_BASE_ALLOCATION.to_string()];
+    let Some(archival) = storage.as_archival() else {
+        return contexts;
+    };
+    match archival.list_snapshots() {
+        Ok(snapshots) => contexts.extend(
+            snapshots
+                .into_iter()
+                .map(|s| format!("{}{}", VOE_DIRTY_CONTEXT_PREFIX, s.id)),
+        ),
+        Err(e) => log::warn!("Cannot list snapshots for metadata contexts: {}", e),
+    }
+    contexts
+}
+
+/// What a finished request sends back
+enum Reply {
+    /// Simple reply, followed by read data or a snapshot id
+    Simple(u32, Vec<u8>),
+    /// Structured chunks of one type; an error replaces them all
+    Chunks(u32, u16, Vec<Vec<u8>>),
+}
+
+impl Reply {
+    fn error(&self) -> u32 {
+        match self {
+            Reply::Simple(error, _) | Reply::Chunks(error, _, _) => *error,
+        }
+    }
+
+    fn write<W: Write>(&self, writer: &mut W, handle: u64) -> io::Result<()> {
+        match self {
+            Reply::Simple(error, data) => {
+                NbdReply::new(handle, *error).write(writer)?;
+                writer.write_all(data)
             }
-            Some(NbdCommand::Disc) => {
-                log::info!("Client requested disconnect: {}", peer_addr);
-                break;
+            Reply::Chunks(error, _, _) if *error != 0 => {
+                write_structured_error(writer, handle, *error)
             }
-            Some(NbdCommand::Trim) => {
-                // Send success (trim not implemented)
-                let reply = NbdReply::new(request.handle, 0);
-                reply.write(&mut writer)?;
-                writer.flush()?;
+            Reply::Chunks(_, _, chunks) if chunks.is_empty() => {
+                let flags = NBD_REPLY_FLAG_DONE;
+                write_structured_chunk(writer, flags, NBD_REPLY_TYPE_NONE, handle, &[])
             }
-            _ => {
-                log::warn!("Unsupported command: {}", request.command);
-                let reply = NbdReply::new(request.handle, libc::EINVAL as u32);
-                reply.write(&mut writer)?;
-                writer.flush()?;
+            Reply::Chunks(_, reply_type, chunks) => {
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let flags = if i + 1 == chunks.len() { NBD_REPLY_FLAG_DONE } else { 0 };
+                    write_structured_chunk(writer, flags, *reply_type, handle, chunk)?;
+                }
+                Ok(())
             }
         }
     }
-
-    Ok(())
 }
 
-/// Handle NBD read request
-fn handle_read<S: BlockStorage, W: Write>(
-    request: &NbdRequest,
-    writer: &mut W,
-    storage: &Arc<Mutex<S>>,
+/// Run queued requests until the reader hangs up, replying to each
+fn run_jobs<S: BlockStorage + ?Sized>(
+    queue: &Mutex<Receiver<Job>>,
+    writer: &Mutex<BufWriter<Stream>>,
+    storage: &S,
+    read_only: bool,
+    negotiated: &Negotiated,
+    export: &str,
+    peer: &str,
 ) -> io::Result<()> {
-    let lba = request.offset / SECTOR_SIZE as u64;
-    let sector_count = (request.length as usize + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let sector_size = storage.info().sector_size as u64;
+    loop {
+        let job = match queue.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => return Ok(()),
+        };
+        let request = &job.request;
+        let span = tracing::debug_span!(
+            "nbd",
+            req = next_request_id(),
+            target = export,
+            initiator = peer,
+            op = %request_op(request),
+            lba = request.offset / sector_size,
+            len = request.length,
+        );
+        let _entered = span.enter();
+        let started = Instant::now();
 
-    if sector_count > 255 {
-        let reply = NbdReply::new(request.handle, libc::EINVAL as u32);
-        reply.write(writer)?;
+        let reply = match request.command_type() {
+            // Structured replies carry read data in an offset chunk
+            Some(NbdCommand::Read) if negotiated.structured => {
+                let (error, data) = handle_read(request, storage);
+                let mut chunks = Vec::new();
+                if !data.is_empty() {
+                    let mut chunk = request.offset.to_be_bytes().to_vec();
+                    chunk.extend_from_slice(&data);
+                    chunks.push(chunk);
+                }
+                Reply::Chunks(error, NBD_REPLY_TYPE_OFFSET_DATA, chunks)
+            }
+            Some(NbdCommand::BlockStatus) if negotiated.structured => {
+                let (error, chunks) = handle_block_status(request, storage, &negotiated.contexts);
+                Reply::Chunks(error, NBD_REPLY_TYPE_BLOCK_STATUS, chunks)
+            }
+            command => {
+                let (error, data) = match command {
+                    Some(NbdCommand::Write)
+                    | Some(NbdCommand::Trim)
+                    | Some(NbdCommand::WriteZeroes)
+                        if read_only =>
+                    {
+                        (libc::EPERM as u32, Vec::new())
+                    }
+                    Some(NbdCommand::Read) => handle_read(request, storage),
+                    Some(NbdCommand::Write) => {
+                        (handle_write(request, job.data, storage), Vec::new())
+                    }
+                    Some(NbdCommand::Flush) => (handle_flush(storage), Vec::new()),
+                    Some(NbdCommand::Trim) => (handle_trim(request, storage), Vec::new()),
+                    Some(NbdCommand::WriteZeroes) => {
+                        (handle_write_zeroes(request, storage), Vec::new())
+                    }
+                    Some(NbdCommand::Cache) => (handle_cache(request, storage), Vec::new()),
+                    Some(NbdCommand::Snapshot) => handle_snapshot(&job.data, storage),
+                    _ => {
+                        log::warn!("Unsupported command: {}", request.command);
+                        (libc::EINVAL as u32, Vec::new())
+                    }
+                };
+                Reply::Simple(error, data)
+            }
+        };
+
+        let error = reply.error();
+        let mut writer = writer.lock().unwrap();
+        reply.write(&mut *writer, request.handle)?;
         writer.flush()?;
-        return Ok(());
+        drop(writer);
+        tracing::debug!(latency_us = started.elapsed().as_micros() as u64, error, "request done");
     }
+}
 
-    let result = {
-        let storage = storage.lock().unwrap();
-        storage.read(lba, sector_count as u8)
-    };
+/// Command name (or raw number) for request spans
+fn request_op(request: &NbdRequest) -> String {
+    match request.command_type() {
+        Some(cmd) => format!("{:?}", cmd).to_lowercase(),
+        None => request.command.to_string(),
+    }
+}
 
-    let (error, data) = match result {
-        Ok(data) => (0, data),
+/// Handle NBD read request, returning the error and the requested bytes
+fn handle_read<S: BlockStorage + ?Sized>(request: &NbdRequest, storage: &S) -> (u32, Vec<u8>) {
+    let sector_size = storage.info().sector_size as usize;
+    let lba = request.offset / sector_size as u64;
+    // Offset into the first sector for reads that aren't sector aligned
+    let skip = (request.offset % sector_size as u64) as usize;
+    let sector_count = (skip + request.length as usize).div_ceil(sector_size);
+
+    match storage.read_runs(&sector_runs(lba, sector_count)) {
+        // Only send requested bytes
+        Ok(runs) => {
+            let data = runs.concat();
+            (0, data[skip..skip + request.length as usize].to_vec())
+        }
         Err(e) => {
             log::error!("Read error at LBA {}: {}", lba, e);
             (libc::EIO as u32, Vec::new())
         }
-    };
+    }
+}
 
-    let reply = NbdReply::new(request.handle, error);
-    reply.write(writer)?;
+/// Most sectors one storage read or write carries
+const MAX_RUN: usize = u8::MAX as usize;
 
-    if error == 0 {
-        // Only send requested bytes
-        writer.write_all(&data[..request.length as usize])?;
+/// Split `count` sectors from `lba` into runs of at most `MAX_RUN`
+fn sector_runs(lba: u64, count: usize) -> Vec<(u64, u8)> {
+    (0..count)
+        .step_by(MAX_RUN)
+        .map(|start| (lba + start as u64, (count - start).min(MAX_RUN) as u8))
+        .collect()
+}
+
+/// Handle a block status request: one chunk per selected context, with
+/// extents covering the request flagged as holes where nothing is
+/// allocated, or dirty where they differ from the context's snapshot
+fn handle_block_status<S: BlockStorage + ?Sized>(
+    request: &NbdRequest,
+    storage: &S,
+    contexts: &[(u32, String)],
+) -> (u32, Vec<Vec<u8>>) {
+    let info = storage.info();
+    let start = request.offset;
+    let end = start + request.length as u64;
+    if contexts.is_empty() || request.length == 0 || end > info.size_bytes() {
+        return (libc::EINVAL as u32, Vec::new());
     }
 
-    writer.flush()?;
-    Ok(())
+    let sector_size = info.sector_size as u64;
+    let sectors = start / sector_size..end.div_ceil(sector_size);
+    let only_one = request.flags() & NBD_CMD_FLAG_REQ_ONE != 0;
+    let mut chunks = Vec::with_capacity(contexts.len());
+    for (id, name) in contexts {
+        let marked = if name == NBD_META_BASE_ALLOCATION {
+            holes(storage, sectors.clone()).map(|holes| (holes, NBD_STATE_HOLE | NBD_STATE_ZERO))
+        } else {
+            dirty(storage, name, sectors.clone()).map(|dirty| (dirty, NBD_STATE_DIRTY))
+        };
+        let (ranges, flags) = match marked {
+            Ok(marked) => marked,
+            Err(error) => return (error, Vec::new()),
+        };
+        let bytes = ranges
+            .into_iter()
+            .map(|sectors| sectors.start * sector_size..sectors.end * sector_size);
+        let mut extents = flag_extents(start..end, bytes, flags);
+        if only_one {
+            extents.truncate(1);
+        }
+
+        let mut chunk = Vec::with_capacity(4 + 8 * extents.len());
+        chunk.extend_from_slice(&id.to_be_bytes());
+        for (length, flags) in extents {
+            chunk.extend_from_slice(&length.to_be_bytes());
+            chunk.extend_from_slice(&flags.to_be_bytes());
+        }
+        chunks.push(chunk);
+    }
+    (0, chunks)
 }
 
-/// Handle NBD write request
-fn handle_write<S: BlockStorage, R: Read, W: Write>(
-    request: &NbdRequest,
-    reader: &mut R,
-    writer: &mut W,
-    storage: &Arc<Mutex<S>>,
-) -> io::Result<()> {
-    let lba = request.offset / SECTOR_SIZE as u64;
-    let sector_count = (request.length as usize + SECTOR_SIZE - 1) / SECTOR_SIZE;
+/// Unallocated sector ranges within `sectors`, or the errno to reply with
+fn holes<S: BlockStorage + ?Sized>(
+    storage: &S,
+    sectors: Range<u64>,
+) -> Result<Vec<Range<u64>>, u32> {
+    let allocated = storage
+        .allocated_ranges(sectors.start, sectors.end - sectors.start)
+        .map_err(|e| {
+            log::error!("Allocation lookup at LBA {} failed: {}", sectors.start, e);
+            libc::EIO as u32
+        })?;
+    let mut holes = Vec::new();
+    let mut pos = sectors.start;
+    for (lba, count) in allocated {
+        if lba > pos {
+            holes.push(pos..lba);
+        }
+        pos = pos.max(lba + count);
+    }
+    if pos < sectors.end {
+        holes.push(pos..sectors.end);
+    }
+    Ok(holes)
+}
 
-    if sector_count > 255 {
-        // Read and discard data
-        let mut discard = vec![0u8; request.length as usize];
-        reader.read_exact(&mut discard)?;
+/// Sector ranges within `sectors` changed since the snapshot named by a
+/// `voe:dirty:` context, or the errno to reply with
+fn dirty<S: BlockStorage + ?Sized>(
+    storage: &S,
+    context: &str,
+    sectors: Range<u64>,
+) -> Result<Vec<Range<u64>>, u32> {
+    let Some(archival) = storage.as_archival() else {
+        return Err(libc::EOPNOTSUPP as u32);
+    };
+    // Only the part of the tree under the request is walked
+    let snapshot = &context[VOE_DIRTY_CONTEXT_PREFIX.len()..];
+    archival.changed_since(snapshot, sectors).map_err(|e| {
+        log::error!("Changed blocks since {} failed: {}", snapshot, e);
+        libc::EIO as u32
+    })
+}
 
-        let reply = NbdReply::new(request.handle, libc::EINVAL as u32);
-        reply.write(writer)?;
-        writer.flush()?;
-        return Ok(());
+/// `(length, flags)` extents covering `window`, flagged `marked_flags`
+/// where it overlaps the sorted, disjoint byte ranges in `marked`
+fn flag_extents(
+    window: Range<u64>,
+    marked: impl IntoIterator<Item = Range<u64>>,
+    marked_flags: u32,
+) -> Vec<(u32, u32)> {
+    fn push(extents: &mut Vec<(u32, u32)>, length: u64, flags: u32) {
+        match extents.last_mut() {
+            _ if length == 0 => {}
+            Some(last) if last.1 == flags => last.0 += length as u32,
+            _ => extents.push((length as u32, flags)),
+        }
     }
 
-    // Read write data
-    let mut data = vec![0u8; sector_count * SECTOR_SIZE];
-    reader.read_exact(&mut data[..request.length as usize])?;
+    let mut extents = Vec::new();
+    let mut pos = window.start;
+    for range in marked {
+        let start = range.start.max(pos);
+        let end = range.end.min(window.end);
+        if start >= end {
+            continue;
+        }
+        push(&mut extents, start - pos, 0);
+        push(&mut extents, end - start, marked_flags);
+        pos = end;
+    }
+    push(&mut extents, window.end - pos, 0);
+    extents
+}
+
+/// Handle NBD write request with its payload
+fn handle_write<S: BlockStorage + ?Sized>(
+    request: &NbdRequest,
+    mut data: Vec<u8>,
+    storage: &S,
+) -> u32 {
+    let sector_size = storage.info().sector_size as usize;
+    let lba = request.offset / sector_size as u64;
+    let sector_count = (request.length as usize).div_ceil(sector_size);
+
+    // Writes must start on a sector boundary (we advertise it as the minimum block size)
+    if !request.offset.is_multiple_of(sector_size as u64) {
+        return libc::EINVAL as u32;
+    }
 
     // Pad to sector boundary if needed
-    if request.length as usize % SECTOR_SIZE != 0 {
+    if !(request.length as usize).is_multiple_of(sector_size) {
         // Partial sector write - need to read-modify-write
+        data.resize(sector_count * sector_size, 0);
         let last_sector_lba = lba + (sector_count - 1) as u64;
 
-        let last_sector_result = {
-            let storage = storage.lock().unwrap();
-            storage.read(last_sector_lba, 1)
-        };
+        let last_sector_result = storage.read(last_sector_lba, 1);
 
         if let Ok(last_sector) = last_sector_result {
-            let partial_bytes = request.length as usize % SECTOR_SIZE;
-            data[(sector_count - 1) * SECTOR_SIZE + partial_bytes..].copy_from_slice(
+            let partial_bytes = request.length as usize % sector_size;
+            data[(sector_count - 1) * sector_size + partial_bytes..].copy_from_slice(
                 &last_sector[partial_bytes..],
             );
         }
     }
 
-    let result = {
-        let mut storage = storage.lock().unwrap();
-        storage.write(lba, &data)
+    // Runs the storage API can carry, queued together where the backend can
+    let runs: Vec<(u64, &[u8])> = data
+        .chunks(MAX_RUN * sector_size)
+        .enumerate()
+        .map(|(i, run)| (lba + (i * MAX_RUN) as u64, run))
+        .collect();
+    match storage.write_runs(&runs) {
+        Ok(_) => 0,
+        Err(e) => {
+            log::error!("Write error at LBA {}: {}", lba, e);
+            write_errno(&e)
+        }
+    }
+}
+
+/// Handle NBD trim request
+fn handle_trim<S: BlockStorage + ?Sized>(request: &NbdRequest, storage: &S) -> u32 {
+    // Only whole sectors inside the range can be discarded
+    let sector_size = storage.info().sector_size as u64;
+    let start = request.offset.div_ceil(sector_size);
+    let end = (request.offset + request.length as u64) / sector_size;
+
+    let result = if end > start {
+        storage.discard(start, end - start)
+    } else {
+        Ok(())
     };
 
-    let error = match result {
+    match result {
         Ok(_) => 0,
         Err(e) => {
-            log::error!("Write error at LBA {}: {}", lba, e);
+            log::error!("Trim error at LBA {}: {}", start, e);
             libc::EIO as u32
         }
-    };
+    }
+}
 
-    let reply = NbdReply::new(request.handle, error);
-    reply.write(writer)?;
-    writer.flush()?;
+/// Handle NBD cache request. Only a hint: the backends have no cache
+/// worth warming, but a range past the end is still refused.
+fn handle_cache<S: BlockStorage + ?Sized>(request: &NbdRequest, storage: &S) -> u32 {
+    let end = request.offset.checked_add(request.length as u64);
+    if end.is_none_or(|end| end > storage.info().size_bytes()) {
+        log::warn!("Cache beyond end: {} + {}", request.offset, request.length);
+        return libc::EINVAL as u32;
+    }
+    0
+}
 
-    Ok(())
+/// Handle NBD write zeroes request
+fn handle_write_zeroes<S: BlockStorage + ?Sized>(request: &NbdRequest, storage: &S) -> u32 {
+    // Whole sectors only, like writes
+    let sector_size = storage.info().sector_size as u64;
+    if !request.offset.is_multiple_of(sector_size)
+        || !(request.length as u64).is_multiple_of(sector_size)
+    {
+        return libc::EINVAL as u32;
+    }
+    let lba = request.offset / sector_size;
+
+    match storage.write_zeroes(lba, request.length as u64 / sector_size) {
+        Ok(_) => 0,
+        Err(e) => {
+            log::error!("Write zeroes error at LBA {}: {}", lba, e);
+            write_errno(&e)
+        }
+    }
 }
 
-/// Handle NBD flush request
-fn handle_flush<S: BlockStorage, W: Write>(
-    request: &NbdRequest,
-    writer: &mut W,
-    storage: &Arc<Mutex<S>>,
-) -> io::Result<()> {
-    let result = {
-        let mut storage = storage.lock().unwrap();
-        (*storage).flush()
-    };
+/// Handle a snapshot request, returning the error and the padded id
+fn handle_snapshot<S: BlockStorage + ?Sized>(description: &[u8], storage: &S) -> (u32, Vec<u8>) {
+    let description = String::from_utf8_lossy(description);
+    let description = (!description.is_empty()).then_some(description.as_ref());
+    match storage::consistent_snapshot(storage, description) {
+        Some(Ok(id)) if id.len() <= NBD_SNAPSHOT_ID_LEN => {
+            log::info!("Snapshot {} taken on request", id);
+            let mut data = id.into_bytes();
+            data.resize(NBD_SNAPSHOT_ID_LEN, 0);
+            (0, data)
+        }
+        Some(Ok(id)) => {
+            log::error!("Snapshot id {} is too long to return", id);
+            (libc::EIO as u32, Vec::new())
+        }
+        Some(Err(e)) => {
+            log::error!("Snapshot failed: {}", e);
+            (write_errno(&e), Vec::new())
+        }
+        None => (libc::EOPNOTSUPP as u32, Vec::new()),
+    }
+}
 
-    let error = match result {
+/// NBD error for a failed write: ENOSPC when out of space or quota, else EIO
+fn write_errno(e: &StorageError) -> u32 {
+    match e {
+        StorageError::NoSpace { .. } | StorageError::QuotaExceeded { .. } => libc::ENOSPC as u32,
+        _ => libc::EIO as u32,
+    }
+}
+
+/// Handle NBD flush request
+fn handle_flush<S: BlockStorage + ?Sized>(storage: &S) -> u32 {
+    match storage.flush() {
         Ok(_) => 0,
         Err(e) => {
             log::error!("Flush error: {}", e);
             libc::EIO as u32
         }
-    };
+    }
+}
 
-    let reply = NbdReply::new(request.handle, error);
-    reply.write(writer)?;
-    writer.flush()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{DeviceInfo, FileBackend, MemBackend, StorageResult};
+    use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+    use std::collections::HashSet;
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::atomic::AtomicUsize;
+    use std::time::Duration;
 
-    Ok(())
+    /// Slow reads that record how many ran at once
+    struct Slow {
+        inner: MemBackend,
+        in_flight: AtomicUsize,
+        peak: Arc<AtomicUsize>,
+    }
+
+    impl BlockStorage for Slow {
+        fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+            let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.peak.fetch_max(now, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            self.inner.read(lba, count)
+        }
+
+        fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+            self.inner.write(lba, data)
+        }
+
+        fn flush(&self) -> StorageResult<()> {
+            self.inner.flush()
+        }
+
+        fn info(&self) -> &DeviceInfo {
+            self.inner.info()
+        }
+    }
+
+    #[test]
+    fn test_frontend_start_stop() {
+        let config = NbdServerConfig {
+            bind_addr: "127.0.0.1:0".to_string(),
+            ..NbdServerConfig::default()
+        };
+        let mut server = NbdServer::new(config, MemBackend::new(1024 * 1024));
+        assert_eq!(server.targets(), vec![server.config.export_name.clone()]);
+        assert!(matches!(
+            server.attach("other", Box::new(MemBackend::new(512))),
+            Err(FrontendError::Unsupported(_))
+        ));
+
+        server.start().unwrap();
+        assert!(server.is_running());
+        assert!(matches!(server.start(), Err(FrontendError::AlreadyRunning)));
+        server.stop().unwrap();
+        assert!(!server.is_running());
+        assert!(matches!(server.stop(), Err(FrontendError::NotRunning)));
+    }
+
+    #[test]
+    fn test_pipelined_requests() {
+        let peak = Arc::new(AtomicUsize::new(0));
+        let storage = Slow {
+            inner: MemBackend::new(1024 * 1024),
+            in_flight: AtomicUsize::new(0),
+            peak: Arc::clone(&peak),
+        };
+        storage.write(4, &[7u8; 512]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = NbdServer::new(NbdServerConfig::default(), storage);
+        thread::spawn(move || server.serve(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = BufWriter::new(stream);
+        let mut greeting = [0u8; 18];
+        reader.read_exact(&mut greeting).unwrap();
+        writer
+            .write_u32::<BigEndian>(NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES)
+            .unwrap();
+        writer.write_u64::<BigEndian>(NBD_OPTS_MAGIC).unwrap();
+        writer.write_u32::<BigEndian>(NBD_OPT_EXPORT_NAME).unwrap();
+        writer.write_u32::<BigEndian>(0).unwrap();
+        writer.flush().unwrap();
+        let mut export = [0u8; 10];
+        reader.read_exact(&mut export).unwrap();
+
+        // Four reads sent back to back, before any reply
+        for handle in 0..4u64 {
+            writer.write_u32::<BigEndian>(NBD_REQUEST_MAGIC).unwrap();
+            writer.write_u32::<BigEndian>(NbdCommand::Read as u32).unwrap();
+            writer.write_u64::<BigEndian>(handle).unwrap();
+            writer.write_u64::<BigEndian>(handle * 2048).unwrap();
+            writer.write_u32::<BigEndian>(512).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let mut handles = HashSet::new();
+        for _ in 0..4 {
+            assert_eq!(reader.read_u32::<BigEndian>().unwrap(), NBD_SIMPLE_REPLY_MAGIC);
+            assert_eq!(reader.read_u32::<BigEndian>().unwrap(), 0);
+            let handle = reader.read_u64::<BigEndian>().unwrap();
+            let mut data = vec![0u8; 512];
+            reader.read_exact(&mut data).unwrap();
+            let fill = if handle == 1 { 7 } else { 0 };
+            assert_eq!(data, vec![fill; 512], "handle {}", handle);
+            handles.insert(handle);
+        }
+        assert_eq!(handles, (0..4).collect());
+        assert!(peak.load(Ordering::SeqCst) > 1);
+    }
+
+    /// Storage whose flush panics, killing the worker running it
+    struct PanicOnFlush(MemBackend);
+
+    impl BlockStorage for PanicOnFlush {
+        fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+            self.0.read(lba, count)
+        }
+
+        fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+            self.0.write(lba, data)
+        }
+
+        fn flush(&self) -> StorageResult<()> {
+            panic!("flush failed");
+        }
+
+        fn info(&self) -> &DeviceInfo {
+            self.0.info()
+        }
+    }
+
+    #[test]
+    fn test_dead_worker_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = NbdServerConfig {
+            queue_depth: 1,
+            ..NbdServerConfig::default()
+        };
+        let server = NbdServer::new(config, PanicOnFlush(MemBackend::new(1024 * 1024)));
+        thread::spawn(move || server.serve(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let timeout = Some(Duration::from_secs(10));
+        stream.set_read_timeout(timeout).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = BufWriter::new(stream);
+        let mut greeting = [0u8; 18];
+        reader.read_exact(&mut greeting).unwrap();
+        writer
+            .write_u32::<BigEndian>(NBD_FLAG_C_FIXED_NEWSTYLE | NBD_FLAG_C_NO_ZEROES)
+            .unwrap();
+        writer.write_u64::<BigEndian>(NBD_OPTS_MAGIC).unwrap();
+        writer.write_u32::<BigEndian>(NBD_OPT_EXPORT_NAME).unwrap();
+        writer.write_u32::<BigEndian>(0).unwrap();
+        writer.flush().unwrap();
+        let mut export = [0u8; 10];
+        reader.read_exact(&mut export).unwrap();
+
+        // The flush kills the only worker; the reads behind it would fill
+        // the queue and block the reader if nothing noticed
+        for (handle, command) in [NbdCommand::Flush, NbdCommand::Read, NbdCommand::Read]
+            .into_iter()
+            .enumerate()
+        {
+            writer.write_u32::<BigEndian>(NBD_REQUEST_MAGIC).unwrap();
+            writer.write_u32::<BigEndian>(command as u32).unwrap();
+            writer.write_u64::<BigEndian>(handle as u64).unwrap();
+            writer.write_u64::<BigEndian>(0).unwrap();
+            writer.write_u32::<BigEndian>(512).unwrap();
+        }
+        writer.flush().unwrap();
+
+        let err = reader.read_u32::<BigEndian>().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_cache_range_checked() {
+        let storage = MemBackend::new(1024 * 1024);
+        let cache = |offset, length| NbdRequest {
+            magic: NBD_REQUEST_MAGIC,
+            command: NbdCommand::Cache as u32,
+            handle: 0,
+            offset,
+            length,
+        };
+        let einval = libc::EINVAL as u32;
+        assert_eq!(handle_cache(&cache(0, 1024 * 1024), &storage), 0);
+        assert_eq!(handle_cache(&cache(1024 * 1024, 1), &storage), einval);
+        assert_eq!(handle_cache(&cache(u64::MAX, 1), &storage), einval);
+    }
+
+    #[test]
+    fn test_large_requests_split() {
+        let storage = MemBackend::new(1024 * 1024);
+        let request = |command: NbdCommand, offset, length| NbdRequest {
+            magic: NBD_REQUEST_MAGIC,
+            command: command as u32,
+            handle: 0,
+            offset,
+            length,
+        };
+        assert_eq!(sector_runs(10, 600), vec![(10, 255), (265, 255), (520, 90)]);
+
+        // 600 sectors, more than one storage write carries
+        let data: Vec<u8> = (0..600 * 512).map(|i| (i / 512) as u8).collect();
+        let write = request(NbdCommand::Write, 512, data.len() as u32);
+        assert_eq!(handle_write(&write, data.clone(), &storage), 0);
+
+        let read = request(NbdCommand::Read, 512, data.len() as u32);
+        assert_eq!(handle_read(&read, &storage), (0, data.clone()));
+        // Unaligned reads spanning runs
+        let read = request(NbdCommand::Read, 700, 300 * 512);
+        assert_eq!(handle_read(&read, &storage).1, data[188..188 + 300 * 512]);
+    }
+
+    #[test]
+    fn test_dirty_extents() {
+        let dirty = NBD_STATE_DIRTY;
+        assert_eq!(flag_extents(0..100, vec![], dirty), vec![(100, 0)]);
+        assert_eq!(
+            flag_extents(10..100, vec![0..20, 20..30, 50..60, 90..200], dirty),
+            vec![(20, dirty), (20, 0), (10, dirty), (30, 0), (10, dirty)]
+        );
+    }
+
+    #[test]
+    fn test_block_status_allocation() {
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let storage = FileBackend::open_or_create(temp.path(), 4 * 1024 * 1024).unwrap();
+        storage.write(0, &[0x11; 512]).unwrap();
+        let contexts = [(1, NBD_META_BASE_ALLOCATION.to_string())];
+        let status = |offset, length| {
+            let request = NbdRequest {
+                magic: NBD_REQUEST_MAGIC,
+                command: NbdCommand::BlockStatus as u32,
+                handle: 0,
+                offset,
+                length,
+            };
+            let (error, chunks) = handle_block_status(&request, &storage, &contexts);
+            assert_eq!(error, 0);
+            let be_u32 = |bytes: &[u8]| u32::from_be_bytes(bytes.try_into().unwrap());
+            let extents: Vec<(u32, u32)> = chunks[0][4..]
+                .chunks(8)
+                .map(|e| (be_u32(&e[..4]), be_u32(&e[4..])))
+                .collect();
+            extents
+        };
+
+        // The written sector is allocated and the extents cover the request
+        let extents = status(0, 1024 * 1024);
+        assert_eq!(extents[0].1, 0);
+        assert_eq!(extents.iter().map(|e| e.0).sum::<u32>(), 1024 * 1024);
+        // Anything unallocated reads as zeroes
+        let hole = NBD_STATE_HOLE | NBD_STATE_ZERO;
+        assert!(extents.iter().all(|e| e.1 == 0 || e.1 == hole));
+
+        // Backends that can't tell report everything allocated
+        let storage = MemBackend::new(1024 * 1024);
+        let request = NbdRequest {
+            magic: NBD_REQUEST_MAGIC,
+            command: NbdCommand::BlockStatus as u32,
+            handle: 0,
+            offset: 0,
+            length: 4096,
+        };
+        let (_, chunks) = handle_block_status(&request, &storage, &contexts);
+        assert_eq!(chunks, vec![vec![0, 0, 0, 1, 0, 0, 16, 0, 0, 0, 0, 0]]);
+    }
+
+    /// Send an option with its payload
+    fn send_option(writer: &mut impl Write, option: u32, data: &[u8]) {
+        writer.write_u64::<BigEndian>(NBD_OPTS_MAGIC).unwrap();
+        writer.write_u32::<BigEndian>(option).unwrap();
+        writer.write_u32::<BigEndian>(data.len() as u32).unwrap();
+        writer.write_all(data).unwrap();
+        writer.flush().unwrap();
+    }
+
+    /// Read an option reply's type and payload
+    fn read_option_reply(reader: &mut impl Read) -> (u32, Vec<u8>) {
+        assert_eq!(reader.read_u64::<BigEndian>().unwrap(), NBD_OPT_REPLY_MAGIC);
+        reader.read_u32::<BigEndian>().unwrap();
+        let reply_type = reader.read_u32::<BigEndian>().unwrap();
+        let mut data = vec![0u8; reader.read_u32::<BigEndian>().unwrap() as usize];
+        reader.read_exact(&mut data).unwrap();
+        (reply_type, data)
+    }
+
+    /// Read a structured chunk's flags, type and payload
+    fn read_chunk(reader: &mut impl Read, handle: u64) -> (u16, u16, Vec<u8>) {
+        assert_eq!(reader.read_u32::<BigEndian>().unwrap(), NBD_STRUCTURED_REPLY_MAGIC);
+        let flags = reader.read_u16::<BigEndian>().unwrap();
+        let reply_type = reader.read_u16::<BigEndian>().unwrap();
+        assert_eq!(reader.read_u64::<BigEndian>().unwrap(), handle);
+        let mut data = vec![0u8; reader.read_u32::<BigEndian>().unwrap() as usize];
+        reader.read_exact(&mut data).unwrap();
+        (flags, reply_type, data)
+    }
+
+    #[test]
+    fn test_block_status_dirty_context() {
+        use crate::blob::FileBlobStore;
+        use crate::storage::CasBackend;
+
+        let temp = tempfile::TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path().join("blobs")).unwrap();
+        let cas = CasBackend::new(Box::new(store), 2048, &temp.path().join("snapshots.json"))
+            .unwrap();
+        cas.write(0, &[1u8; 4096]).unwrap();
+        let id = storage::consistent_snapshot(&cas, None).unwrap().unwrap();
+        cas.write(128, &[2u8; 4096]).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = NbdServer::new(NbdServerConfig::default(), cas);
+        thread::spawn(move || server.serve(listener));
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = BufWriter::new(stream);
+        let mut greeting = [0u8; 18];
+        reader.read_exact(&mut greeting).unwrap();
+        writer.write_u32::<BigEndian>(NBD_FLAG_C_FIXED_NEWSTYLE).unwrap();
+
+        // Contexts need structured replies
+        let context = format!("{}{}", VOE_DIRTY_CONTEXT_PREFIX, id);
+        let mut query = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        query.extend_from_slice(&(context.len() as u32).to_be_bytes());
+        query.extend_from_slice(context.as_bytes());
+        send_option(&mut writer, NBD_OPT_SET_META_CONTEXT, &query);
+        assert_eq!(read_option_reply(&mut reader).0, NBD_REP_ERR_INVALID);
+
+        send_option(&mut writer, NBD_OPT_STRUCTURED_REPLY, &[]);
+        assert_eq!(read_option_reply(&mut reader).0, NBD_REP_ACK);
+
+        // Listing by namespace finds the snapshot
+        let mut list = vec![0, 0, 0, 0, 0, 0, 0, 1];
+        list.extend_from_slice(&(VOE_DIRTY_CONTEXT_PREFIX.len() as u32).to_be_bytes());
+        list.extend_from_slice(VOE_DIRTY_CONTEXT_PREFIX.as_bytes());
+        send_option(&mut writer, NBD_OPT_LIST_META_CONTEXT, &list);
+        let (reply_type, data) = read_option_reply(&mut reader);
+        assert_eq!(reply_type, NBD_REP_META_CONTEXT);
+        assert_eq!(&data[4..], context.as_bytes());
+        assert_eq!(read_option_reply(&mut reader).0, NBD_REP_ACK);
+
+        send_option(&mut writer, NBD_OPT_SET_META_CONTEXT, &query);
+        let (reply_type, data) = read_option_reply(&mut reader);
+        assert_eq!(reply_type, NBD_REP_META_CONTEXT);
+        let context_id = u32::from_be_bytes(data[..4].try_into().unwrap());
+        assert_eq!(read_option_reply(&mut reader).0, NBD_REP_ACK);
+
+        send_option(&mut writer, NBD_OPT_GO, &[0, 0, 0, 0, 0, 0]);
+        while read_option_reply(&mut reader).0 != NBD_REP_ACK {}
+
+        let request = |writer: &mut BufWriter<TcpStream>, command: u32, offset: u64, length| {
+            writer.write_u32::<BigEndian>(NBD_REQUEST_MAGIC).unwrap();
+            writer.write_u32::<BigEndian>(command).unwrap();
+            writer.write_u64::<BigEndian>(command as u64).unwrap();
+            writer.write_u64::<BigEndian>(offset).unwrap();
+            writer.write_u32::<BigEndian>(length).unwrap();
+            writer.flush().unwrap();
+        };
+
+        // Only the block written after the snapshot is dirty
+        let status = NbdCommand::BlockStatus as u32;
+        request(&mut writer, status, 0, 1024 * 1024);
+        let (flags, reply_type, data) = read_chunk(&mut reader, status as u64);
+        assert_eq!((flags, reply_type), (NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_BLOCK_STATUS));
+        let words: Vec<u32> = data
+            .chunks(4)
+            .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+            .collect();
+        let dirty = NBD_STATE_DIRTY;
+        assert_eq!(words, vec![context_id, 65536, 0, 4096, dirty, 1024 * 1024 - 69632, 0]);
+
+        let one = status | ((NBD_CMD_FLAG_REQ_ONE as u32) << 16);
+        request(&mut writer, one, 65536, 8192);
+        let (_, _, data) = read_chunk(&mut reader, one as u64);
+        assert_eq!(data.len(), 12);
+        assert_eq!(&data[4..], &[0, 0, 16, 0, 0, 0, 0, 1]);
+
+        // Reads come back as offset chunks
+        request(&mut writer, NbdCommand::Read as u32, 65536, 512);
+        let (flags, reply_type, data) = read_chunk(&mut reader, NbdCommand::Read as u64);
+        assert_eq!((flags, reply_type), (NBD_REPLY_FLAG_DONE, NBD_REPLY_TYPE_OFFSET_DATA));
+        assert_eq!(&data[..8], &65536u64.to_be_bytes());
+        assert_eq!(&data[8..], &[2u8; 512]);
+    }
 }
@@ -0,0 +1,269 @@
+//! TCP and UNIX domain socket endpoints
+//!
+//! Addresses are `host:port` for TCP or `unix:/path/to/socket` for a UNIX
+//! domain socket, so colocated clients (qemu, a local NBD or CAS client)
+//! can skip the TCP stack. `Listener` and `Stream` wrap either kind behind
+//! the calls the servers and clients need. A `Stream` may also carry TLS
+//! (see `crate::tls`) over either kind.
+
+use crate::tls::TlsStream;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Prefix that marks an address as a UNIX socket path
+pub const UNIX_PREFIX: &str = "unix:";
+
+/// Socket path of a `unix:` address
+pub fn unix_path(addr: &str) -> Option<&Path> {
+    addr.strip_prefix(UNIX_PREFIX).map(Path::new)
+}
+
+/// Listening socket
+pub enum Listener {
+    Tcp(TcpListener),
+    /// With the path of a socket file `bind` created, which is removed
+    /// when the listener is dropped
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+impl Listener {
+    /// Bind `addr`. A stale socket file left by a previous run is
+    /// replaced; a socket another process is listening on, or any other
+    /// file at the path, is an error.
+    pub fn bind(addr: &str) -> io::Result<Self> {
+        let Some(path) = unix_path(addr) else {
+            return TcpListener::bind(addr).map(Listener::Tcp);
+        };
+        if let Ok(meta) = fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and is not a socket", path.display()),
+                ));
+            }
+            match UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another server", path.display()),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e) => return Err(e),
+            }
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        Ok(Listener::Unix(listener, Some(path.to_path_buf())))
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Stream::Tcp(s)),
+            Listener::Unix(listener, _) => listener.accept().map(|(s, _)| Stream::Unix(s)),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.set_nonblocking(nonblocking),
+            Listener::Unix(listener, _) => listener.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// Bound address, in the same form `bind` takes
+    pub fn local_addr(&self) -> io::Result<String> {
+        match self {
+            Listener::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            Listener::Unix(listener, _) => Ok(unix_addr(&listener.local_addr()?)),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Listener::Unix(listener, None)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, Some(path)) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Connected socket
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    /// Connect to `addr`, giving up on TCP connects after `timeout`
+    pub fn connect(addr: &str, timeout: Duration) -> io::Result<Self> {
+        if let Some(path) = unix_path(addr) {
+            return UnixStream::connect(path).map(Stream::Unix);
+        }
+        let socket_addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no address for {}", addr),
+            )
+        })?;
+        TcpStream::connect_timeout(&socket_addr, timeout).map(Stream::Tcp)
+    }
+
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::Tls(stream) => Ok(Stream::Tls(stream.clone())),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+            Stream::Tls(stream) => stream.shutdown(how),
+        }
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Stream::Tls(stream) => stream.socket().set_nonblocking(nonblocking),
+        }
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::Tls(stream) => stream.socket().set_read_timeout(timeout),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            Stream::Tls(stream) => stream.socket().set_write_timeout(timeout),
+        }
+    }
+
+    /// Disable Nagle's algorithm; UNIX sockets don't batch anyway
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nodelay(nodelay),
+            Stream::Unix(_) => Ok(()),
+            Stream::Tls(stream) => stream.socket().set_nodelay(nodelay),
+        }
+    }
+
+    /// Peer address for log messages
+    pub fn peer_addr(&self) -> io::Result<String> {
+        match self {
+            Stream::Tcp(stream) => Ok(stream.peer_addr()?.to_string()),
+            Stream::Unix(stream) => Ok(unix_addr(&stream.peer_addr()?)),
+            Stream::Tls(stream) => stream.socket().peer_addr(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Unix(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// `unix:` address of a UNIX socket; clients usually have no path
+fn unix_addr(addr: &std::os::unix::net::SocketAddr) -> String {
+    match addr.as_pathname() {
+        Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+        None => format!("{}(unnamed)", UNIX_PREFIX),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_unix_listener() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("test.sock");
+        let addr = format!("unix:{}", path.display());
+
+        let listener = Listener::bind(&addr).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), addr);
+        let server = thread::spawn(move || {
+            let mut stream = listener.accept().unwrap();
+            let mut buf = [0u8; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let mut stream = Stream::connect(&addr, Duration::from_secs(1)).unwrap();
+        stream.write_all(b"ping").unwrap();
+        let mut buf = [0u8; 4];
+        stream.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        server.join().unwrap();
+
+        // A live socket is kept, and removed when its listener goes
+        let listener = Listener::bind(&addr).unwrap();
+        let err = Listener::bind(&addr).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        drop(listener);
+        assert!(!path.exists());
+
+        // A stale socket is replaced, other files are left alone
+        drop(UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        drop(Listener::bind(&addr).unwrap());
+        let file = temp.path().join("file");
+        fs::write(&file, b"data").unwrap();
+        assert!(Listener::bind(&format!("unix:{}", file.display())).is_err());
+        assert_eq!(fs::read(&file).unwrap(), b"data");
+    }
+}
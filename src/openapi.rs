@@ -0,0 +1,222 @@
+//! OpenAPI documents for the management APIs
+//!
+//! `iscsi-web` and the AoE server API describe their endpoints with
+//! `ApiDoc` and serve the result at `/api/openapi.json`, so clients can be
+//! generated instead of written against the handlers. Every response uses
+//! the `{success, data, error}` envelope; operations only describe `data`.
+
+use serde_json::{json, Map, Value};
+
+/// Builder for an OpenAPI 3.0 document
+pub struct ApiDoc {
+    title: String,
+    description: String,
+    paths: Map<String, Value>,
+    schemas: Map<String, Value>,
+}
+
+/// Schema for a string
+pub fn string() -> Value {
+    json!({ "type": "string" })
+}
+
+/// Schema for a string that is one of `values`
+pub fn string_enum(values: &[&str]) -> Value {
+    json!({ "type": "string", "enum": values })
+}
+
+/// Schema for an unsigned integer
+pub fn integer() -> Value {
+    json!({ "type": "integer", "format": "int64", "minimum": 0 })
+}
+
+/// Schema for a floating-point number
+pub fn number() -> Value {
+    json!({ "type": "number" })
+}
+
+/// Schema for a boolean
+pub fn boolean() -> Value {
+    json!({ "type": "boolean" })
+}
+
+/// Schema for an array of `items`
+pub fn array(items: Value) -> Value {
+    json!({ "type": "array", "items": items })
+}
+
+/// Make a schema nullable
+pub fn nullable(mut schema: Value) -> Value {
+    if let Some(ref_path) = schema.get("$ref").cloned() {
+        return json!({ "allOf": [{ "$ref": ref_path }], "nullable": true });
+    }
+    schema["nullable"] = Value::Bool(true);
+    schema
+}
+
+/// Reference to a named component schema
+pub fn schema_ref(name: &str) -> Value {
+    json!({ "$ref": format!("#/components/schemas/{}", name) })
+}
+
+/// Schema for an object of any shape
+pub fn any_object() -> Value {
+    json!({ "type": "object" })
+}
+
+/// Object schema; every listed property is required unless nullable
+pub fn object(properties: &[(&str, Value)]) -> Value {
+    let required: Vec<&str> = properties
+        .iter()
+        .filter(|(_, schema)| schema.get("nullable").is_none())
+        .map(|(name, _)| *name)
+        .collect();
+    let properties: Map<String, Value> = properties
+        .iter()
+        .map(|(name, schema)| (name.to_string(), schema.clone()))
+        .collect();
+    json!({ "type": "object", "properties": properties, "required": required })
+}
+
+/// `base`, an `object` schema, with more properties
+pub fn extend(base: &Value, properties: &[(&str, Value)]) -> Value {
+    let mut extended = base.clone();
+    for (name, schema) in properties {
+        extended["properties"][*name] = schema.clone();
+        if schema.get("nullable").is_none() {
+            extended["required"]
+                .as_array_mut()
+                .unwrap()
+                .push(json!(name));
+        }
+    }
+    extended
+}
+
+impl ApiDoc {
+    pub fn new(title: &str, description: &str) -> Self {
+        Self {
+            title: title.to_string(),
+            description: description.to_string(),
+            paths: Map::new(),
+            schemas: Map::new(),
+        }
+    }
+
+    /// Add a named component schema
+    pub fn schema(mut self, name: &str, schema: Value) -> Self {
+        self.schemas.insert(name.to_string(), schema);
+        self
+    }
+
+    /// Add an operation. `{name}` segments in `path` become string path
+    /// parameters; `data` is the schema of the envelope's `data` field.
+    pub fn operation(
+        mut self,
+        method: &str,
+        path: &str,
+        summary: &str,
+        body: Option<Value>,
+        data: Value,
+    ) -> Self {
+        let parameters: Vec<Value> = path
+            .split('/')
+            .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": string() }))
+            .collect();
+
+        let envelope = object(&[
+            ("success", boolean()),
+            ("data", nullable(data)),
+            ("error", nullable(string())),
+        ]);
+        let mut operation = json!({
+            "summary": summary,
+            "operationId": operation_id(method, path),
+            "responses": {
+                "200": {
+                    "description": "Result envelope; `success` is false on error",
+                    "content": { "application/json": { "schema": envelope } }
+                }
+            }
+        });
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        if let Some(body) = body {
+            operation["requestBody"] = json!({
+                "required": false,
+                "content": { "application/json": { "schema": body } }
+            });
+        }
+
+        let item = self
+            .paths
+            .entry(path.to_string())
+            .or_insert_with(|| Value::Object(Map::new()));
+        item[method.to_lowercase()] = operation;
+        self
+    }
+
+    /// Render the document
+    pub fn to_json(&self) -> Value {
+        json!({
+            "openapi": "3.0.3",
+            "info": {
+                "title": self.title,
+                "description": self.description,
+                "version": env!("CARGO_PKG_VERSION"),
+            },
+            "paths": self.paths,
+            "components": { "schemas": self.schemas },
+        })
+    }
+}
+
+/// `get /api/targets/{iqn}/gc` -> `get_api_targets_iqn_gc`
+fn operation_id(method: &str, path: &str) -> String {
+    let mut id = method.to_lowercase();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        id.push('_');
+        id.extend(segment.chars().filter(|c| c.is_alphanumeric() || *c == '_'));
+    }
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operation_document() {
+        let doc = ApiDoc::new("Test", "Test API")
+            .schema("Job", object(&[("id", integer()), ("message", nullable(string()))]))
+            .operation("GET", "/api/jobs/{id}", "Get a job", None, schema_ref("Job"))
+            .operation("POST", "/api/jobs/{id}", "Retry a job", Some(object(&[])), boolean())
+            .to_json();
+
+        let item = &doc["paths"]["/api/jobs/{id}"];
+        assert_eq!(item["get"]["operationId"], "get_api_jobs_id");
+        assert_eq!(item["get"]["parameters"][0]["name"], "id");
+        assert!(item["get"].get("requestBody").is_none());
+        assert!(item["post"]["requestBody"].is_object());
+
+        let envelope = &item["get"]["responses"]["200"]["content"]["application/json"]["schema"];
+        assert_eq!(envelope["required"], json!(["success"]));
+        assert_eq!(
+            envelope["properties"]["data"]["allOf"][0]["$ref"],
+            "#/components/schemas/Job"
+        );
+        assert_eq!(doc["components"]["schemas"]["Job"]["required"], json!(["id"]));
+    }
+
+    #[test]
+    fn test_extend_object() {
+        let base = object(&[("id", string())]);
+        let more = [("children", array(string())), ("note", nullable(string()))];
+        let extended = extend(&base, &more);
+        assert_eq!(extended["required"], json!(["id", "children"]));
+        assert_eq!(extended["properties"]["note"]["nullable"], true);
+        assert_eq!(base["required"], json!(["id"]));
+    }
+}
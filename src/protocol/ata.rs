@@ -2,11 +2,72 @@
 //!
 //! Dispatches ATA commands to storage backends and builds responses.
 
+use super::smart::{handle_smart, SmartCounters};
 use super::types::*;
-use crate::storage::{BlockStorage, DeviceInfo};
+use crate::storage::{
+    BlockStorage, DeviceInfo, StorageError, ATA_FIRMWARE_LEN, ATA_MODEL_LEN, ATA_SERIAL_LEN,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Opcodes already warned about, so unknown commands are logged once each
+static UNKNOWN_WARNED: [AtomicBool; 256] = [const { AtomicBool::new(false) }; 256];
+
+/// Highest sector count LBA28 can address (128 GiB of 512-byte sectors)
+pub const LBA28_MAX_SECTORS: u64 = 0x0FFF_FFFF;
+
+/// Highest sector count LBA48 can address
+pub const LBA48_MAX_SECTORS: u64 = (1 << 48) - 1;
+
+/// CHS geometry reported for every disk, as drives over 8.4 GB do
+const CHS_HEADS: u64 = 16;
+const CHS_SECTORS_PER_TRACK: u64 = 63;
+const CHS_MAX_CYLINDERS: u64 = 16383;
+
+/// Addressing a target offers initiators
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Addressing {
+    /// LBA28 and LBA48
+    #[default]
+    Lba48,
+    /// For old firmware and PXE initiators that only speak LBA28 or CHS:
+    /// capacity is capped at 128 GiB, LBA48 isn't advertised, and
+    /// CHS-addressed commands are accepted
+    Lba28,
+}
+
+impl Addressing {
+    /// Sectors initiators can address
+    pub fn capacity(&self, info: &DeviceInfo) -> u64 {
+        match self {
+            Addressing::Lba48 => info.total_sectors,
+            Addressing::Lba28 => info.total_sectors.min(LBA28_MAX_SECTORS),
+        }
+    }
+
+    /// Starting sector of a command, or None for an invalid CHS address
+    fn lba(&self, header: &AtaHeader) -> Option<u64> {
+        if header.flags.extended {
+            return Some(header.lba48());
+        }
+        // Device register (LBA byte 3) bit 6 selects LBA over CHS
+        let device = (header.lba >> 24) as u8;
+        if *self == Addressing::Lba48 || device & 0x40 != 0 {
+            return Some(header.lba28() as u64);
+        }
+        let sector = header.lba & 0xFF;
+        let cylinder = (header.lba >> 8) & 0xFFFF;
+        let head = (device & 0x0F) as u64;
+        if sector == 0 || sector > CHS_SECTORS_PER_TRACK {
+            return None;
+        }
+        Some((cylinder * CHS_HEADS + head) * CHS_SECTORS_PER_TRACK + sector - 1)
+    }
+}
 
 /// ATA command response
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct AtaResponse {
     /// Status register
     pub status: u8,
@@ -16,6 +77,8 @@ pub struct AtaResponse {
     pub sector_count: u8,
     /// Data payload (for reads)
     pub data: Option<Vec<u8>>,
+    /// LBA registers to return (None echoes the request)
+    pub lba: Option<u64>,
 }
 
 impl AtaResponse {
@@ -26,6 +89,7 @@ impl AtaResponse {
             error: 0,
             sector_count,
             data: Some(data),
+            lba: None,
         }
     }
 
@@ -36,6 +100,7 @@ impl AtaResponse {
             error: 0,
             sector_count: 0,
             data: None,
+            lba: None,
         }
     }
 
@@ -46,20 +111,27 @@ impl AtaResponse {
             error: error_code,
             sector_count: 0,
             data: None,
+            lba: None,
         }
     }
 }
 
 /// Handle an ATA command
 pub fn handle_ata_command(
-    storage: &mut dyn BlockStorage,
+    storage: &dyn BlockStorage,
+    smart: &SmartCounters,
+    addressing: Addressing,
     header: &AtaHeader,
     data: &[u8],
 ) -> AtaResponse {
     let cmd = match AtaCommand::try_from(header.cmd_status) {
         Ok(cmd) => cmd,
         Err(_) => {
-            log::warn!("Unknown ATA command: 0x{:02X}", header.cmd_status);
+            if !UNKNOWN_WARNED[header.cmd_status as usize].swap(true, Ordering::Relaxed) {
+                log::warn!("Unknown ATA command: 0x{:02X}", header.cmd_status);
+            } else {
+                log::debug!("Unknown ATA command: 0x{:02X}", header.cmd_status);
+            }
             return AtaResponse::error(ata_error::ABRT);
         }
     };
@@ -71,48 +143,68 @@ pub fn handle_ata_command(
         header.sector_count
     );
 
+    // DMA and multiple-sector variants move the same data over AoE as PIO
+    if cmd.is_read() {
+        return handle_read(storage, smart, addressing, header);
+    }
+    if cmd.is_write() {
+        return handle_write(storage, smart, addressing, header, data);
+    }
+
     match cmd {
-        AtaCommand::ReadSectors | AtaCommand::ReadSectorsExt => {
-            handle_read(storage, header)
-        }
-        AtaCommand::WriteSectors | AtaCommand::WriteSectorsExt => {
-            handle_write(storage, header, data)
-        }
-        AtaCommand::IdentifyDevice => handle_identify(storage),
+        AtaCommand::IdentifyDevice => handle_identify(storage, addressing),
         AtaCommand::FlushCache | AtaCommand::FlushCacheExt => {
-            handle_flush(storage)
+            handle_flush(storage, smart)
         }
+        AtaCommand::Smart => handle_smart(smart, header),
+        AtaCommand::ReadVerifySectors | AtaCommand::ReadVerifySectorsExt => {
+            handle_read_verify(storage, addressing, header)
+        }
+        // Nothing to configure or position on a virtual disk
+        AtaCommand::SetFeatures | AtaCommand::Seek => AtaResponse::success(),
+        _ => unreachable!("read/write commands handled above"),
+    }
+}
+
+/// Handle READ VERIFY SECTORS: no data transfer, only the range is checked
+fn handle_read_verify(
+    storage: &dyn BlockStorage,
+    addressing: Addressing,
+    header: &AtaHeader,
+) -> AtaResponse {
+    let Some(lba) = addressing.lba(header) else {
+        return AtaResponse::error(ata_error::IDNF);
+    };
+    let count = if header.sector_count == 0 { 256 } else { header.sector_count as u64 };
+
+    if lba + count > addressing.capacity(storage.info()) {
+        return AtaResponse::error(ata_error::IDNF);
     }
+    AtaResponse::success()
 }
 
 /// Handle READ SECTORS command
-fn handle_read(storage: &dyn BlockStorage, header: &AtaHeader) -> AtaResponse {
-    let lba = if header.flags.extended {
-        header.lba48()
-    } else {
-        header.lba28() as u64
+fn handle_read(
+    storage: &dyn BlockStorage,
+    smart: &SmartCounters,
+    addressing: Addressing,
+    header: &AtaHeader,
+) -> AtaResponse {
+    let Some(lba) = addressing.lba(header) else {
+        return AtaResponse::error(ata_error::IDNF);
     };
 
+    // 0 means 256 sectors (AoE carries only the low byte of the LBA48 count)
     let count = if header.sector_count == 0 {
-        // 0 means 256 sectors for LBA28, or use extended count for LBA48
-        if header.flags.extended {
-            256
-        } else {
-            256
-        }
+        256
     } else {
         header.sector_count as u16
     };
 
     // Validate range
-    let info = storage.info();
-    if lba + count as u64 > info.total_sectors {
-        log::warn!(
-            "Read beyond end: LBA {} + {} > {}",
-            lba,
-            count,
-            info.total_sectors
-        );
+    let capacity = addressing.capacity(storage.info());
+    if lba + count as u64 > capacity {
+        log::warn!("Read beyond end: LBA {} + {} > {}", lba, count, capacity);
         return AtaResponse::error(ata_error::IDNF);
     }
 
@@ -121,6 +213,7 @@ fn handle_read(storage: &dyn BlockStorage, header: &AtaHeader) -> AtaResponse {
         Ok(data) => AtaResponse::success_with_data(data, header.sector_count),
         Err(e) => {
             log::error!("Read error at LBA {}: {}", lba, e);
+            smart.record_read_error(matches!(e, StorageError::Corrupted));
             AtaResponse::error(ata_error::UNC)
         }
     }
@@ -128,18 +221,18 @@ fn handle_read(storage: &dyn BlockStorage, header: &AtaHeader) -> AtaResponse {
 
 /// Handle WRITE SECTORS command
 fn handle_write(
-    storage: &mut dyn BlockStorage,
+    storage: &dyn BlockStorage,
+    smart: &SmartCounters,
+    addressing: Addressing,
     header: &AtaHeader,
     data: &[u8],
 ) -> AtaResponse {
-    let lba = if header.flags.extended {
-        header.lba48()
-    } else {
-        header.lba28() as u64
+    let Some(lba) = addressing.lba(header) else {
+        return AtaResponse::error(ata_error::IDNF);
     };
 
     let count = if header.sector_count == 0 { 256 } else { header.sector_count as u16 };
-    let expected_len = count as usize * SECTOR_SIZE;
+    let expected_len = count as usize * storage.info().sector_size as usize;
 
     if data.len() != expected_len {
         log::warn!(
@@ -151,37 +244,69 @@ fn handle_write(
     }
 
     // Validate range
-    let info = storage.info();
-    if lba + count as u64 > info.total_sectors {
-        log::warn!(
-            "Write beyond end: LBA {} + {} > {}",
-            lba,
-            count,
-            info.total_sectors
-        );
+    let capacity = addressing.capacity(storage.info());
+    if lba + count as u64 > capacity {
+        log::warn!("Write beyond end: LBA {} + {} > {}", lba, count, capacity);
         return AtaResponse::error(ata_error::IDNF);
     }
 
     // Perform write
     match storage.write(lba, data) {
-        Ok(()) => AtaResponse::success(),
+        Ok(()) => {}
+        Err(e @ (StorageError::NoSpace { .. } | StorageError::QuotaExceeded { .. })) => {
+            log::warn!("Write at LBA {} refused: {}", lba, e);
+            smart.record_no_space();
+            return AtaResponse::error(ata_error::ABRT);
+        }
         Err(e) => {
             log::error!("Write error at LBA {}: {}", lba, e);
-            AtaResponse::error(ata_error::UNC)
+            smart.record_write_error();
+            return AtaResponse::error(ata_error::UNC);
+        }
+    }
+
+    // Synchronous writes must be on stable storage before we respond;
+    // async writes may stay buffered until the next FLUSH CACHE
+    if !header.flags.async_write {
+        if let Err(e) = storage.flush() {
+            log::error!("Flush after write at LBA {} failed: {}", lba, e);
+            smart.record_write_error();
+            return AtaResponse::error(ata_error::UNC);
         }
     }
+
+    AtaResponse::success()
 }
 
 /// Handle IDENTIFY DEVICE command
-fn handle_identify(storage: &dyn BlockStorage) -> AtaResponse {
+fn handle_identify(storage: &dyn BlockStorage, addressing: Addressing) -> AtaResponse {
     let info = storage.info();
-    let data = build_identify_data(info);
+    let data = build_identify_data(info, addressing);
     AtaResponse::success_with_data(data, 1)
 }
 
+/// Default CHS geometry (cylinders, heads, sectors per track) for a
+/// capacity, limited to what CHS can express
+fn chs_geometry(sectors: u64) -> (u64, u64, u64) {
+    let cylinders = (sectors / (CHS_HEADS * CHS_SECTORS_PER_TRACK)).min(CHS_MAX_CYLINDERS);
+    (cylinders, CHS_HEADS, CHS_SECTORS_PER_TRACK)
+}
+
+/// Store a little-endian word at word offset `word`
+fn set_word(data: &mut [u8], word: usize, value: u16) {
+    data[word * 2..word * 2 + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+/// `value` space-padded or cut to an ATA string field of `len` characters
+fn ata_field(value: &str, len: usize) -> String {
+    format!("{:len$}", &value[..value.len().min(len)], len = len)
+}
+
 /// Build 512-byte IDENTIFY DEVICE response
-fn build_identify_data(info: &DeviceInfo) -> Vec<u8> {
+fn build_identify_data(info: &DeviceInfo, addressing: Addressing) -> Vec<u8> {
     let mut data = vec![0u8; 512];
+    let capacity = addressing.capacity(info);
+    let lba48 = info.lba48 && addressing == Addressing::Lba48;
 
     // Word 0: General configuration
     // Bit 15: 0 = ATA device
@@ -189,16 +314,22 @@ fn build_identify_data(info: &DeviceInfo) -> Vec<u8> {
     data[0] = 0x00;
     data[1] = 0x00;
 
+    // Words 1, 3, 6: Default cylinders, heads, sectors per track
+    let (cylinders, heads, sectors) = chs_geometry(capacity);
+    set_word(&mut data, 1, cylinders as u16);
+    set_word(&mut data, 3, heads as u16);
+    set_word(&mut data, 6, sectors as u16);
+
     // Words 10-19: Serial number (20 ASCII chars, space-padded)
-    let serial = format!("{:20}", &info.serial[..info.serial.len().min(20)]);
+    let serial = ata_field(&info.serial, ATA_SERIAL_LEN);
     copy_ata_string(&mut data[20..40], &serial);
 
     // Words 23-26: Firmware revision (8 ASCII chars)
-    let firmware = format!("{:8}", &info.firmware[..info.firmware.len().min(8)]);
+    let firmware = ata_field(&info.firmware, ATA_FIRMWARE_LEN);
     copy_ata_string(&mut data[46..54], &firmware);
 
     // Words 27-46: Model number (40 ASCII chars)
-    let model = format!("{:40}", &info.model[..info.model.len().min(40)]);
+    let model = ata_field(&info.model, ATA_MODEL_LEN);
     copy_ata_string(&mut data[54..94], &model);
 
     // Word 47: Max sectors per interrupt (R/W multiple)
@@ -212,30 +343,63 @@ fn build_identify_data(info: &DeviceInfo) -> Vec<u8> {
     data[99] = 0x03; // LBA + DMA
 
     // Word 53: Field validity
+    // Bit 0: Words 54-58 valid
     // Bit 1: Words 64-70 valid
     // Bit 2: Word 88 valid
-    data[106] = 0x00;
-    data[107] = 0x06;
+    data[106] = 0x07;
+    data[107] = 0x00;
+
+    // Words 54-58: Current geometry (the default one) and its capacity
+    set_word(&mut data, 54, cylinders as u16);
+    set_word(&mut data, 55, heads as u16);
+    set_word(&mut data, 56, sectors as u16);
+    let chs_capacity = (cylinders * heads * sectors) as u32;
+    data[114..118].copy_from_slice(&chs_capacity.to_le_bytes());
 
     // Words 60-61: Total addressable sectors (LBA28)
-    let lba28_sectors = info.total_sectors.min(0x0FFF_FFFF) as u32;
+    let lba28_sectors = capacity.min(LBA28_MAX_SECTORS) as u32;
     data[120] = (lba28_sectors & 0xFF) as u8;
     data[121] = ((lba28_sectors >> 8) & 0xFF) as u8;
     data[122] = ((lba28_sectors >> 16) & 0xFF) as u8;
     data[123] = ((lba28_sectors >> 24) & 0xFF) as u8;
 
+    // Word 63: Multiword DMA modes 0-2 supported, mode 2 selected
+    data[126] = 0x07;
+    data[127] = 0x04;
+
+    // Word 82: Command set supported (1)
+    // Bit 0: SMART supported
+    data[164] = 0x01;
+
     // Word 83: Command set supported (2)
     // Bit 10: LBA48 supported
     data[166] = 0x00;
-    data[167] = 0x04;
+    data[167] = if lba48 { 0x04 } else { 0x00 };
+
+    // Word 84: Command set supported (3)
+    // Bit 14: Word valid
+    // Bit 8: World wide name supported
+    let wwn_bit = if info.uuid.is_some() { 0x0100 } else { 0 };
+    set_word(&mut data, 84, 0x4000 | wwn_bit);
+
+    // Word 85: Command set enabled (1)
+    // Bit 0: SMART enabled
+    data[170] = 0x01;
 
     // Word 86: Command set enabled (2)
     // Bit 10: LBA48 enabled
     data[172] = 0x00;
-    data[173] = 0x04;
+    data[173] = if lba48 { 0x04 } else { 0x00 };
+
+    // Word 87: Command set enabled (3), mirrors word 84
+    set_word(&mut data, 87, 0x4000 | wwn_bit);
+
+    // Word 88: Ultra DMA modes 0-6 supported, mode 6 selected
+    data[176] = 0x7F;
+    data[177] = 0x40;
 
     // Words 100-103: Total addressable sectors (LBA48)
-    if info.lba48 {
+    if lba48 {
         let sectors = info.total_sectors;
         data[200] = (sectors & 0xFF) as u8;
         data[201] = ((sectors >> 8) & 0xFF) as u8;
@@ -248,11 +412,25 @@ fn build_identify_data(info: &DeviceInfo) -> Vec<u8> {
     }
 
     // Word 106: Physical/Logical sector size
+    // Bit 14: Word valid
     // Bit 12: Device logical sector size > 256 words
     // Bits 3:0: 2^X logical sectors per physical sector
-    if info.sector_size == 4096 {
+    if info.sector_size > 512 {
         data[212] = 0x00;
-        data[213] = 0x10; // 4K logical sectors
+        data[213] = 0x50;
+
+        // Words 117-118: Logical sector size in words
+        let words = info.sector_size / 2;
+        data[234..238].copy_from_slice(&words.to_le_bytes());
+    }
+
+    // Words 108-111: World wide name, most significant word first, so
+    // multipath matches it with the NAA the iSCSI export reports
+    if let Some(uuid) = &info.uuid {
+        let wwn = uuid.wwn();
+        for i in 0..4 {
+            set_word(&mut data, 108 + i, (wwn >> (48 - 16 * i)) as u16);
+        }
     }
 
     data
@@ -277,11 +455,12 @@ fn copy_ata_string(dest: &mut [u8], src: &str) {
 }
 
 /// Handle FLUSH CACHE command
-fn handle_flush(storage: &mut dyn BlockStorage) -> AtaResponse {
+fn handle_flush(storage: &dyn BlockStorage, smart: &SmartCounters) -> AtaResponse {
     match storage.flush() {
         Ok(()) => AtaResponse::success(),
         Err(e) => {
             log::error!("Flush error: {}", e);
+            smart.record_write_error();
             AtaResponse::error(ata_error::ABRT)
         }
     }
@@ -290,6 +469,146 @@ fn handle_flush(storage: &mut dyn BlockStorage) -> AtaResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::{DeviceStrings, MemBackend, StorageResult, TargetUuid};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const LBA48: Addressing = Addressing::Lba48;
+
+    /// MemBackend that counts flushes
+    struct FlushCounter {
+        inner: MemBackend,
+        flushes: AtomicUsize,
+    }
+
+    impl BlockStorage for FlushCounter {
+        fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+            self.inner.read(lba, count)
+        }
+
+        fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+            self.inner.write(lba, data)
+        }
+
+        fn flush(&self) -> StorageResult<()> {
+            self.flushes.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn info(&self) -> &DeviceInfo {
+            self.inner.info()
+        }
+    }
+
+    fn write_header(async_write: bool) -> AtaHeader {
+        AtaHeader {
+            flags: AtaFlags {
+                extended: true,
+                async_write,
+                write: true,
+                ..Default::default()
+            },
+            err_feature: 0,
+            sector_count: 1,
+            cmd_status: AtaCommand::WriteSectorsExt as u8,
+            lba: 3,
+        }
+    }
+
+    #[test]
+    fn test_sync_write_flushes() {
+        let storage = FlushCounter {
+            inner: MemBackend::new(64 * 512),
+            flushes: AtomicUsize::new(0),
+        };
+        let data = vec![0x42; 512];
+
+        // Async writes are left buffered
+        let smart = SmartCounters::new();
+        let resp = handle_ata_command(&storage, &smart, LBA48, &write_header(true), &data);
+        assert_eq!(resp.status, ata_status::DRDY);
+        assert_eq!(storage.flushes.load(Ordering::SeqCst), 0);
+
+        // Synchronous writes reach stable storage before the response
+        let resp = handle_ata_command(&storage, &smart, LBA48, &write_header(false), &data);
+        assert_eq!(resp.status, ata_status::DRDY);
+        assert_eq!(storage.flushes.load(Ordering::SeqCst), 1);
+        assert_eq!(storage.read(3, 1).unwrap(), data);
+    }
+
+    #[test]
+    fn test_write_below_reserve_aborts() {
+        use crate::storage::{FileBackend, SpaceReserve};
+
+        let temp = tempfile::NamedTempFile::new().unwrap();
+        let storage = FileBackend::open_or_create(temp.path(), 64 * 512)
+            .unwrap()
+            .with_reserve(SpaceReserve::new("test", u64::MAX));
+        let smart = SmartCounters::new();
+
+        let resp = handle_ata_command(&storage, &smart, LBA48, &write_header(false), &[0x42; 512]);
+        assert_eq!(resp.error, ata_error::ABRT);
+        let stats = smart.stats();
+        assert_eq!((stats.no_space_errors, stats.write_errors), (1, 0));
+
+        // Reads still work
+        assert_eq!(storage.read(3, 1).unwrap(), vec![0; 512]);
+    }
+
+    #[test]
+    fn test_dma_and_multiple_transfer_data() {
+        let storage = MemBackend::new(64 * 512);
+        let smart = SmartCounters::new();
+        let header = |cmd: AtaCommand, extended: bool| AtaHeader {
+            flags: AtaFlags {
+                extended,
+                write: cmd.is_write(),
+                ..Default::default()
+            },
+            err_feature: 0,
+            sector_count: 2,
+            cmd_status: cmd as u8,
+            lba: 10,
+        };
+        let data = vec![0x5A; 1024];
+
+        for (write, read, extended) in [
+            (AtaCommand::WriteDma, AtaCommand::ReadDma, false),
+            (AtaCommand::WriteDmaExt, AtaCommand::ReadDmaExt, true),
+            (AtaCommand::WriteMultiple, AtaCommand::ReadMultiple, false),
+            (AtaCommand::WriteMultipleExt, AtaCommand::ReadMultipleExt, true),
+        ] {
+            storage.write(10, &[0; 1024]).unwrap();
+            let resp = handle_ata_command(&storage, &smart, LBA48, &header(write, extended), &data);
+            assert_eq!(resp.status, ata_status::DRDY, "{}", write);
+
+            let resp = handle_ata_command(&storage, &smart, LBA48, &header(read, extended), &[]);
+            assert_eq!(resp.data.as_deref(), Some(&data[..]), "{}", read);
+        }
+    }
+
+    #[test]
+    fn test_bring_up_commands_succeed() {
+        let storage = MemBackend::new(64 * 512);
+        let smart = SmartCounters::new();
+        let header = |cmd: AtaCommand, lba: u64| AtaHeader {
+            flags: AtaFlags::default(),
+            err_feature: 0x03,
+            sector_count: 8,
+            cmd_status: cmd as u8,
+            lba,
+        };
+
+        for cmd in [AtaCommand::SetFeatures, AtaCommand::Seek, AtaCommand::ReadVerifySectors] {
+            let resp = handle_ata_command(&storage, &smart, LBA48, &header(cmd, 0), &[]);
+            assert_eq!(resp.status, ata_status::DRDY, "{}", cmd);
+            assert!(resp.data.is_none());
+        }
+
+        // READ VERIFY past the end of the device
+        let verify = header(AtaCommand::ReadVerifySectors, 60);
+        let resp = handle_ata_command(&storage, &smart, LBA48, &verify, &[]);
+        assert_eq!(resp.error, ata_error::IDNF);
+    }
 
     #[test]
     fn test_copy_ata_string() {
@@ -320,4 +639,141 @@ mod tests {
         assert_eq!(resp.status, ata_status::ERR | ata_status::DRDY);
         assert_eq!(resp.error, ata_error::ABRT);
     }
+
+    #[test]
+    fn test_identify_4kn_sector_size() {
+        let info = DeviceInfo {
+            sector_size: 4096,
+            total_sectors: 1024,
+            ..Default::default()
+        };
+        let data = build_identify_data(&info, LBA48);
+
+        let word106 = u16::from_le_bytes([data[212], data[213]]);
+        assert_eq!(word106 & 0xD000, 0x5000);
+        let words = u32::from_le_bytes([data[234], data[235], data[236], data[237]]);
+        assert_eq!(words, 2048);
+
+        // 512-byte devices leave word 106 clear
+        let data = build_identify_data(&DeviceInfo::default(), LBA48);
+        assert_eq!(&data[212..214], &[0, 0]);
+    }
+
+    #[test]
+    fn test_identify_wwn() {
+        let word = |data: &[u8], n: usize| u16::from_le_bytes([data[n * 2], data[n * 2 + 1]]);
+        let uuid = TargetUuid::generate();
+        let info = DeviceInfo {
+            uuid: Some(uuid),
+            ..Default::default()
+        };
+        let data = build_identify_data(&info, LBA48);
+        assert_eq!(word(&data, 84), 0x4100);
+        assert_eq!(word(&data, 87), 0x4100);
+        let wwn = (108..112).fold(0u64, |wwn, n| wwn << 16 | word(&data, n) as u64);
+        assert_eq!(wwn.to_be_bytes(), crate::iscsi::scsi::naa_identifier(&uuid));
+
+        // No identity, no WWN
+        let data = build_identify_data(&DeviceInfo::default(), LBA48);
+        assert_eq!(word(&data, 84), 0x4000);
+        assert_eq!(&data[216..224], &[0; 8]);
+    }
+
+    #[test]
+    fn test_identify_device_strings() {
+        let mut info = DeviceInfo::default();
+        info.set_strings(&DeviceStrings {
+            model: Some("ST4000NM0033".to_string()),
+            serial: Some("Z1Z0ABCD".to_string()),
+            firmware: None,
+        });
+        let data = build_identify_data(&info, LBA48);
+        // Byte-swapped within each word
+        let text = |bytes: &[u8]| {
+            let swapped: Vec<u8> = bytes.chunks(2).flat_map(|w| [w[1], w[0]]).collect();
+            String::from_utf8(swapped).unwrap().trim_end().to_string()
+        };
+        assert_eq!(text(&data[20..40]), "Z1Z0ABCD");
+        assert_eq!(text(&data[46..54]), "1.0");
+        assert_eq!(text(&data[54..94]), "ST4000NM0033");
+    }
+
+    #[test]
+    fn test_lba28_compat_identify() {
+        let word = |data: &[u8], n: usize| u16::from_le_bytes([data[n * 2], data[n * 2 + 1]]);
+        // 200 GiB
+        let info = DeviceInfo {
+            total_sectors: 200 * 1024 * 1024 * 2,
+            ..Default::default()
+        };
+
+        let data = build_identify_data(&info, LBA48);
+        assert_ne!(word(&data, 83) & 0x0400, 0);
+        let sectors = u64::from_le_bytes(data[200..208].try_into().unwrap());
+        assert_eq!(sectors, info.total_sectors);
+
+        let data = build_identify_data(&info, Addressing::Lba28);
+        assert_eq!(word(&data, 83) & 0x0400, 0);
+        assert_eq!(word(&data, 86) & 0x0400, 0);
+        assert_eq!(&data[200..208], &[0; 8]);
+        assert_eq!(u32::from_le_bytes(data[120..124].try_into().unwrap()), 0x0FFF_FFFF);
+        // Largest CHS geometry, valid in words 54-58
+        assert_eq!((word(&data, 1), word(&data, 3), word(&data, 6)), (16383, 16, 63));
+        assert_ne!(word(&data, 53) & 1, 0);
+        assert_eq!((word(&data, 54), word(&data, 55), word(&data, 56)), (16383, 16, 63));
+        assert_eq!(u32::from_le_bytes(data[114..118].try_into().unwrap()), 16383 * 16 * 63);
+
+        // Small disks get a geometry that fits
+        let small = DeviceInfo {
+            total_sectors: 2048 * 1008,
+            ..Default::default()
+        };
+        let data = build_identify_data(&small, Addressing::Lba28);
+        assert_eq!(word(&data, 1), 2048);
+    }
+
+    #[test]
+    fn test_chs_addressing() {
+        let storage = MemBackend::new(4096 * 512);
+        let smart = SmartCounters::new();
+        let header = |cmd: AtaCommand, lba: u64| AtaHeader {
+            flags: AtaFlags {
+                write: cmd.is_write(),
+                ..Default::default()
+            },
+            err_feature: 0,
+            sector_count: 1,
+            cmd_status: cmd as u8,
+            lba,
+        };
+        let data = vec![0x33; 512];
+
+        // Cylinder 1, head 2, sector 5 = (1 * 16 + 2) * 63 + 4
+        let chs = 0x02 << 24 | 0x0001 << 8 | 5;
+        let resp = handle_ata_command(
+            &storage,
+            &smart,
+            Addressing::Lba28,
+            &header(AtaCommand::WriteSectors, chs),
+            &data,
+        );
+        assert_eq!(resp.status, ata_status::DRDY);
+        assert_eq!(storage.read(18 * 63 + 4, 1).unwrap(), data);
+
+        // The same sector by LBA
+        let lba = 0x40 << 24 | (18 * 63 + 4);
+        let read = header(AtaCommand::ReadSectors, lba);
+        let resp = handle_ata_command(&storage, &smart, Addressing::Lba28, &read, &[]);
+        assert_eq!(resp.data.as_deref(), Some(&data[..]));
+
+        // Sector numbers start at 1
+        let resp = handle_ata_command(
+            &storage,
+            &smart,
+            Addressing::Lba28,
+            &header(AtaCommand::ReadSectors, 0x0001 << 8),
+            &[],
+        );
+        assert_eq!(resp.error, ata_error::IDNF);
+    }
 }
@@ -64,11 +64,11 @@ fn build_ata_response(request: &AoeFrame, response: AtaResponse, target_shelf: u
     frame.push(response.sector_count);
     frame.push(response.status);
 
-    // LBA (6 bytes) - echo back from request
-    let lba = if let AoePayload::Ata { header, .. } = &request.payload {
-        header.lba
-    } else {
-        0
+    // LBA (6 bytes) - echo back from request unless the handler set it
+    let lba = match (&request.payload, response.lba) {
+        (_, Some(lba)) => lba,
+        (AoePayload::Ata { header, .. }, None) => header.lba,
+        _ => 0,
     };
     frame.push((lba & 0xFF) as u8);
     frame.push(((lba >> 8) & 0xFF) as u8);
@@ -113,7 +113,7 @@ fn build_config_response(request: &AoeFrame, response: ConfigResponse, target_sh
     frame.extend_from_slice(&response.buffer_count.to_be_bytes());
     frame.extend_from_slice(&response.firmware_version.to_be_bytes());
     frame.push(response.sector_count);
-    frame.push((1 << 4) | 0); // AoE version 1 in high nibble, ccmd=0 in low nibble (response)
+    frame.push(1 << 4); // AoE version 1 in high nibble, ccmd=0 in low nibble (response)
     frame.extend_from_slice(&(config_len as u16).to_be_bytes());
     frame.extend_from_slice(&response.config_string);
 
@@ -211,16 +211,17 @@ mod tests {
             error: 0,
             sector_count: 1,
             data: Some(vec![0xAA; 512]),
+            lba: None,
         };
 
-        let frame = build_ata_response(&request, response);
+        let frame = build_ata_response(&request, response, 1, 0);
 
         // Check MACs are swapped
         assert_eq!(&frame[0..6], &[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
         assert_eq!(&frame[6..12], &[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
 
-        // Check response flag is set
-        assert_eq!(frame[14] & 0x80, 0x80);
+        // Check response flag is set (flags live in the low nibble)
+        assert_eq!(frame[14] & 0x08, 0x08);
 
         // Check tag is preserved
         let tag = u32::from_be_bytes([frame[20], frame[21], frame[22], frame[23]]);
@@ -233,10 +234,10 @@ mod tests {
     #[test]
     fn test_build_error_response() {
         let request = make_test_request();
-        let frame = build_error_response(&request, 3); // device unavailable
+        let frame = build_error_response(&request, 3, 1, 0); // device unavailable
 
         // Check error flag is set
-        assert_eq!(frame[14] & 0x40, 0x40);
+        assert_eq!(frame[14] & 0x04, 0x04);
 
         // Check error code
         assert_eq!(frame[15], 3);
@@ -6,11 +6,13 @@
 mod ata;
 mod build;
 mod parse;
+mod smart;
 mod types;
 
-pub use ata::{handle_ata_command, AtaResponse};
+pub use ata::{handle_ata_command, Addressing, AtaResponse, LBA28_MAX_SECTORS, LBA48_MAX_SECTORS};
 pub use build::{build_response, ConfigResponse, ResponseData};
 pub use parse::{parse_frame, ParseError};
+pub use smart::{SmartCounters, SmartStats};
 pub use types::*;
 
 use thiserror::Error;
@@ -226,4 +226,31 @@ mod tests {
             Err(ParseError::InvalidEtherType { .. })
         ));
     }
+
+    #[test]
+    fn test_parse_never_panics_on_garbage() {
+        // Cheap stand-in for the fuzz target: truncations of a config frame
+        // claiming a long config string, plus xorshift noise behind an AoE header
+        let mut frame = [0u8; AoeHeader::SIZE + ConfigHeader::MIN_SIZE + 4];
+        frame[12..14].copy_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+        frame[14] = 0x10;
+        frame[19] = AoeCommand::Config as u8;
+        frame[30..32].copy_from_slice(&u16::MAX.to_be_bytes());
+        for len in 0..=frame.len() {
+            let _ = parse_frame(&frame[..len]);
+        }
+
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        for _ in 0..10_000 {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let len = (state % 96) as usize;
+            let mut garbage: Vec<u8> = (0..len).map(|i| (state >> (i % 8 * 8)) as u8).collect();
+            if garbage.len() >= 14 {
+                garbage[12..14].copy_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+            }
+            let _ = parse_frame(&garbage);
+        }
+    }
 }
@@ -0,0 +1,273 @@
+//! ATA SMART emulation
+//!
+//! Reports synthetic health data so initiators that probe SMART (0xB0)
+//! don't log aborted commands. Attributes come from I/O errors seen on the
+//! target; there is no temperature or spin-up data to report.
+
+use super::ata::AtaResponse;
+use super::types::*;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// SMART subcommands (feature register)
+pub mod smart_feature {
+    pub const READ_DATA: u8 = 0xD0;
+    pub const READ_THRESHOLDS: u8 = 0xD1;
+    pub const ENABLE_AUTOSAVE: u8 = 0xD2;
+    pub const ENABLE_OPERATIONS: u8 = 0xD8;
+    pub const DISABLE_OPERATIONS: u8 = 0xD9;
+    pub const RETURN_STATUS: u8 = 0xDA;
+}
+
+/// LBA mid/high signature required on every SMART command
+const SMART_SIGNATURE: u64 = 0xC2_4F00;
+/// LBA mid/high returned by RETURN STATUS when a threshold is exceeded
+const SMART_THRESHOLD_EXCEEDED: u64 = 0x2C_F400;
+const SIGNATURE_MASK: u64 = 0xFF_FF00;
+
+const ATTR_READ_ERROR_RATE: u8 = 0x01;
+const ATTR_POWER_ON_HOURS: u8 = 0x09;
+const ATTR_POWER_CYCLE_COUNT: u8 = 0x0C;
+const ATTR_REPORTED_UNCORRECTABLE: u8 = 0xBB;
+const ATTR_WRITE_ERROR_RATE: u8 = 0xC8;
+
+/// Normalized value of the uncorrectable attribute once corruption is seen
+const FAILING_VALUE: u8 = 1;
+/// Threshold for the uncorrectable attribute
+const UNCORRECTABLE_THRESHOLD: u8 = 10;
+
+/// Per-target health counters feeding the SMART attributes
+pub struct SmartCounters {
+    started: Instant,
+    enabled: AtomicBool,
+    read_errors: AtomicU64,
+    write_errors: AtomicU64,
+    corrupted_blocks: AtomicU64,
+    no_space_errors: AtomicU64,
+}
+
+/// Point-in-time copy of a target's health counters
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SmartStats {
+    pub read_errors: u64,
+    pub write_errors: u64,
+    pub corrupted_blocks: u64,
+    /// Writes refused because free space was below the reserve
+    pub no_space_errors: u64,
+    pub uptime_secs: u64,
+}
+
+impl SmartCounters {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            enabled: AtomicBool::new(true),
+            read_errors: AtomicU64::new(0),
+            write_errors: AtomicU64::new(0),
+            corrupted_blocks: AtomicU64::new(0),
+            no_space_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Record a failed read; `corrupted` if the backend detected bad data
+    pub fn record_read_error(&self, corrupted: bool) {
+        self.read_errors.fetch_add(1, Ordering::Relaxed);
+        if corrupted {
+            self.corrupted_blocks.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record a failed write or flush
+    pub fn record_write_error(&self) {
+        self.write_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a write refused for lack of space or quota; the medium is
+    /// fine, so this doesn't count against the write error rate
+    pub fn record_no_space(&self) {
+        self.no_space_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current counter values
+    pub fn stats(&self) -> SmartStats {
+        SmartStats {
+            read_errors: self.read_errors.load(Ordering::Relaxed),
+            write_errors: self.write_errors.load(Ordering::Relaxed),
+            corrupted_blocks: self.corrupted_blocks.load(Ordering::Relaxed),
+            no_space_errors: self.no_space_errors.load(Ordering::Relaxed),
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+
+    /// True once any corrupted data has been read
+    pub fn threshold_exceeded(&self) -> bool {
+        self.corrupted_blocks.load(Ordering::Relaxed) > 0
+    }
+
+    /// (id, normalized value, raw value) for each reported attribute
+    fn attributes(&self) -> [(u8, u8, u64); 5] {
+        let corrupted = self.corrupted_blocks.load(Ordering::Relaxed);
+        let uncorrectable_value = if corrupted > 0 { FAILING_VALUE } else { 100 };
+        [
+            (ATTR_READ_ERROR_RATE, 100, self.read_errors.load(Ordering::Relaxed)),
+            (ATTR_POWER_ON_HOURS, 100, self.started.elapsed().as_secs() / 3600),
+            (ATTR_POWER_CYCLE_COUNT, 100, 1),
+            (ATTR_REPORTED_UNCORRECTABLE, uncorrectable_value, corrupted),
+            (ATTR_WRITE_ERROR_RATE, 100, self.write_errors.load(Ordering::Relaxed)),
+        ]
+    }
+}
+
+impl Default for SmartCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Handle SMART (0xB0), dispatching on the feature register
+pub fn handle_smart(counters: &SmartCounters, header: &AtaHeader) -> AtaResponse {
+    if header.lba & SIGNATURE_MASK != SMART_SIGNATURE {
+        log::debug!("SMART command without signature: LBA {:#x}", header.lba);
+        return AtaResponse::error(ata_error::ABRT);
+    }
+
+    let enabled = counters.enabled.load(Ordering::Relaxed);
+    match header.err_feature {
+        smart_feature::ENABLE_OPERATIONS => {
+            counters.enabled.store(true, Ordering::Relaxed);
+            AtaResponse::success()
+        }
+        smart_feature::DISABLE_OPERATIONS => {
+            counters.enabled.store(false, Ordering::Relaxed);
+            AtaResponse::success()
+        }
+        _ if !enabled => AtaResponse::error(ata_error::ABRT),
+        smart_feature::ENABLE_AUTOSAVE => AtaResponse::success(),
+        smart_feature::READ_DATA => AtaResponse::success_with_data(build_smart_data(counters), 1),
+        smart_feature::READ_THRESHOLDS => AtaResponse::success_with_data(build_thresholds(), 1),
+        smart_feature::RETURN_STATUS => {
+            let mut response = AtaResponse::success();
+            if counters.threshold_exceeded() {
+                let lba = (header.lba & !SIGNATURE_MASK) | SMART_THRESHOLD_EXCEEDED;
+                response.lba = Some(lba);
+            }
+            response
+        }
+        other => {
+            log::debug!("Unsupported SMART feature: 0x{:02X}", other);
+            AtaResponse::error(ata_error::ABRT)
+        }
+    }
+}
+
+/// Build the 512-byte SMART READ DATA structure
+fn build_smart_data(counters: &SmartCounters) -> Vec<u8> {
+    let mut data = vec![0u8; 512];
+
+    // Bytes 0-1: data structure revision
+    data[0] = 0x10;
+
+    // Bytes 2-361: 30 attribute entries of 12 bytes
+    for (i, (id, value, raw)) in counters.attributes().into_iter().enumerate() {
+        let entry = &mut data[2 + i * 12..2 + (i + 1) * 12];
+        entry[0] = id;
+        entry[1] = 0x02; // flags: online collection
+        entry[3] = value;
+        entry[4] = value; // worst
+        entry[5..11].copy_from_slice(&raw.to_le_bytes()[..6]);
+    }
+
+    // Byte 368-369: SMART capability (saves data on power-saving mode entry, autosave)
+    data[368] = 0x03;
+    // Byte 370: error logging supported
+    data[370] = 0x01;
+
+    set_checksum(&mut data);
+    data
+}
+
+/// Build the 512-byte SMART READ THRESHOLDS structure
+fn build_thresholds() -> Vec<u8> {
+    let mut data = vec![0u8; 512];
+    data[0] = 0x10;
+
+    let ids = [
+        ATTR_READ_ERROR_RATE,
+        ATTR_POWER_ON_HOURS,
+        ATTR_POWER_CYCLE_COUNT,
+        ATTR_REPORTED_UNCORRECTABLE,
+        ATTR_WRITE_ERROR_RATE,
+    ];
+    for (i, id) in ids.into_iter().enumerate() {
+        data[2 + i * 12] = id;
+        if id == ATTR_REPORTED_UNCORRECTABLE {
+            data[3 + i * 12] = UNCORRECTABLE_THRESHOLD;
+        }
+    }
+
+    set_checksum(&mut data);
+    data
+}
+
+/// Byte 511: two's complement checksum so all bytes sum to zero
+fn set_checksum(data: &mut [u8]) {
+    let sum = data[..511].iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    data[511] = sum.wrapping_neg();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn smart_header(feature: u8) -> AtaHeader {
+        AtaHeader {
+            flags: AtaFlags::default(),
+            err_feature: feature,
+            sector_count: 1,
+            cmd_status: AtaCommand::Smart as u8,
+            lba: SMART_SIGNATURE,
+        }
+    }
+
+    #[test]
+    fn test_smart_read_data() {
+        let counters = SmartCounters::new();
+        counters.record_read_error(false);
+
+        let resp = handle_smart(&counters, &smart_header(smart_feature::READ_DATA));
+        let data = resp.data.unwrap();
+        assert_eq!(data.len(), 512);
+        assert_eq!(data.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)), 0);
+
+        // First attribute is the read error count
+        assert_eq!(data[2], ATTR_READ_ERROR_RATE);
+        assert_eq!(data[7], 1);
+    }
+
+    #[test]
+    fn test_smart_return_status() {
+        let counters = SmartCounters::new();
+        let resp = handle_smart(&counters, &smart_header(smart_feature::RETURN_STATUS));
+        assert_eq!(resp.status, ata_status::DRDY);
+        assert_eq!(resp.lba, None);
+
+        counters.record_read_error(true);
+        let resp = handle_smart(&counters, &smart_header(smart_feature::RETURN_STATUS));
+        assert_eq!(resp.lba, Some(SMART_THRESHOLD_EXCEEDED));
+    }
+
+    #[test]
+    fn test_smart_requires_signature() {
+        let counters = SmartCounters::new();
+        let mut header = smart_header(smart_feature::READ_DATA);
+        header.lba = 0;
+        let resp = handle_smart(&counters, &header);
+        assert_eq!(resp.error, ata_error::ABRT);
+
+        // Disabled SMART aborts everything but ENABLE
+        handle_smart(&counters, &smart_header(smart_feature::DISABLE_OPERATIONS));
+        let resp = handle_smart(&counters, &smart_header(smart_feature::READ_DATA));
+        assert_eq!(resp.error, ata_error::ABRT);
+    }
+}
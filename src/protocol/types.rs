@@ -154,9 +154,22 @@ pub enum AtaCommand {
     ReadSectorsExt = 0x24,
     WriteSectors = 0x30,
     WriteSectorsExt = 0x34,
+    ReadDma = 0xC8,
+    ReadDmaExt = 0x25,
+    WriteDma = 0xCA,
+    WriteDmaExt = 0x35,
+    ReadMultiple = 0xC4,
+    ReadMultipleExt = 0x29,
+    WriteMultiple = 0xC5,
+    WriteMultipleExt = 0x39,
     IdentifyDevice = 0xEC,
     FlushCache = 0xE7,
     FlushCacheExt = 0xEA,
+    Smart = 0xB0,
+    SetFeatures = 0xEF,
+    ReadVerifySectors = 0x40,
+    ReadVerifySectorsExt = 0x42,
+    Seek = 0x70,
 }
 
 impl TryFrom<u8> for AtaCommand {
@@ -168,14 +181,55 @@ impl TryFrom<u8> for AtaCommand {
             0x24 => Ok(AtaCommand::ReadSectorsExt),
             0x30 => Ok(AtaCommand::WriteSectors),
             0x34 => Ok(AtaCommand::WriteSectorsExt),
+            0xC8 => Ok(AtaCommand::ReadDma),
+            0x25 => Ok(AtaCommand::ReadDmaExt),
+            0xCA => Ok(AtaCommand::WriteDma),
+            0x35 => Ok(AtaCommand::WriteDmaExt),
+            0xC4 => Ok(AtaCommand::ReadMultiple),
+            0x29 => Ok(AtaCommand::ReadMultipleExt),
+            0xC5 => Ok(AtaCommand::WriteMultiple),
+            0x39 => Ok(AtaCommand::WriteMultipleExt),
             0xEC => Ok(AtaCommand::IdentifyDevice),
             0xE7 => Ok(AtaCommand::FlushCache),
             0xEA => Ok(AtaCommand::FlushCacheExt),
+            0xB0 => Ok(AtaCommand::Smart),
+            0xEF => Ok(AtaCommand::SetFeatures),
+            0x40 => Ok(AtaCommand::ReadVerifySectors),
+            0x42 => Ok(AtaCommand::ReadVerifySectorsExt),
+            0x70 => Ok(AtaCommand::Seek),
             other => Err(other),
         }
     }
 }
 
+impl AtaCommand {
+    /// True for commands that transfer sectors from the device
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            AtaCommand::ReadSectors
+                | AtaCommand::ReadSectorsExt
+                | AtaCommand::ReadDma
+                | AtaCommand::ReadDmaExt
+                | AtaCommand::ReadMultiple
+                | AtaCommand::ReadMultipleExt
+        )
+    }
+
+    /// True for commands that transfer sectors to the device
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            AtaCommand::WriteSectors
+                | AtaCommand::WriteSectorsExt
+                | AtaCommand::WriteDma
+                | AtaCommand::WriteDmaExt
+                | AtaCommand::WriteMultiple
+                | AtaCommand::WriteMultipleExt
+        )
+    }
+}
+
 impl fmt::Display for AtaCommand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -183,9 +237,22 @@ impl fmt::Display for AtaCommand {
             AtaCommand::ReadSectorsExt => write!(f, "READ SECTORS EXT"),
             AtaCommand::WriteSectors => write!(f, "WRITE SECTORS"),
             AtaCommand::WriteSectorsExt => write!(f, "WRITE SECTORS EXT"),
+            AtaCommand::ReadDma => write!(f, "READ DMA"),
+            AtaCommand::ReadDmaExt => write!(f, "READ DMA EXT"),
+            AtaCommand::WriteDma => write!(f, "WRITE DMA"),
+            AtaCommand::WriteDmaExt => write!(f, "WRITE DMA EXT"),
+            AtaCommand::ReadMultiple => write!(f, "READ MULTIPLE"),
+            AtaCommand::ReadMultipleExt => write!(f, "READ MULTIPLE EXT"),
+            AtaCommand::WriteMultiple => write!(f, "WRITE MULTIPLE"),
+            AtaCommand::WriteMultipleExt => write!(f, "WRITE MULTIPLE EXT"),
             AtaCommand::IdentifyDevice => write!(f, "IDENTIFY DEVICE"),
             AtaCommand::FlushCache => write!(f, "FLUSH CACHE"),
             AtaCommand::FlushCacheExt => write!(f, "FLUSH CACHE EXT"),
+            AtaCommand::Smart => write!(f, "SMART"),
+            AtaCommand::SetFeatures => write!(f, "SET FEATURES"),
+            AtaCommand::ReadVerifySectors => write!(f, "READ VERIFY SECTORS"),
+            AtaCommand::ReadVerifySectorsExt => write!(f, "READ VERIFY SECTORS EXT"),
+            AtaCommand::Seek => write!(f, "SEEK"),
         }
     }
 }
@@ -218,6 +285,15 @@ impl AtaHeader {
     pub fn lba28(&self) -> u32 {
         (self.lba & 0x0FFF_FFFF) as u32
     }
+
+    /// Sectors the command covers (a count of 0 means 256)
+    pub fn sectors(&self) -> usize {
+        if self.sector_count == 0 {
+            256
+        } else {
+            self.sector_count as usize
+        }
+    }
 }
 
 /// Config command types
@@ -0,0 +1,302 @@
+//! Per-target I/O throttling (QoS)
+//!
+//! Token buckets limiting read/write bandwidth and IOPS. Callers block until
+//! their request fits, so a throttled target only slows its own worker
+//! thread or connection, never the others sharing the blob store.
+//!
+//! A cap on write bytes in flight adds backpressure: when the blob store
+//! slows down, new writes wait for earlier ones instead of piling up.
+
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// Optional rate limits for one target
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QosLimits {
+    /// Read bandwidth in MiB/s
+    pub max_read_mbps: Option<u32>,
+    /// Write bandwidth in MiB/s
+    pub max_write_mbps: Option<u32>,
+    /// Read plus write operations per second
+    pub max_iops: Option<u32>,
+    /// Write MiB accepted but not yet stored
+    pub max_inflight_write_mb: Option<u32>,
+}
+
+impl QosLimits {
+    /// True when no limit is set
+    pub fn is_unlimited(&self) -> bool {
+        self.max_read_mbps.is_none()
+            && self.max_write_mbps.is_none()
+            && self.max_iops.is_none()
+            && self.max_inflight_write_mb.is_none()
+    }
+}
+
+/// Token bucket allowing one second of burst.
+///
+/// Requests larger than the bucket are admitted by going into debt, which
+/// delays the next caller instead of rejecting the request.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            last: Instant::now(),
+        }
+    }
+
+    /// Take `amount` tokens, returning how long the caller must wait
+    fn take(&mut self, amount: f64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+
+        self.tokens -= amount;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Caps the bytes held by requests in flight.
+///
+/// A request larger than the cap is admitted once nothing else is in
+/// flight, so it waits rather than failing.
+pub struct InFlightLimit {
+    max_bytes: u64,
+    bytes: Mutex<u64>,
+    released: Condvar,
+}
+
+impl InFlightLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            bytes: Mutex::new(0),
+            released: Condvar::new(),
+        }
+    }
+
+    fn fits(&self, in_flight: u64, bytes: u64) -> bool {
+        in_flight == 0 || in_flight + bytes <= self.max_bytes
+    }
+
+    /// Block until `bytes` fit, holding them until the guard drops
+    pub fn acquire(&self, bytes: usize) -> InFlightGuard<'_> {
+        let mut in_flight = self.bytes.lock().unwrap();
+        while !self.fits(*in_flight, bytes as u64) {
+            in_flight = self.released.wait(in_flight).unwrap();
+        }
+        *in_flight += bytes as u64;
+        InFlightGuard {
+            limit: Some(self),
+            bytes: bytes as u64,
+        }
+    }
+
+    /// Hold `bytes` if they fit now
+    pub fn try_acquire(&self, bytes: usize) -> Option<InFlightGuard<'_>> {
+        let mut in_flight = self.bytes.lock().unwrap();
+        if !self.fits(*in_flight, bytes as u64) {
+            return None;
+        }
+        *in_flight += bytes as u64;
+        Some(InFlightGuard {
+            limit: Some(self),
+            bytes: bytes as u64,
+        })
+    }
+
+    /// The cap in bytes
+    pub fn max_bytes(&self) -> u64 {
+        self.max_bytes
+    }
+
+    /// Bytes currently held
+    pub fn in_flight(&self) -> u64 {
+        *self.bytes.lock().unwrap()
+    }
+}
+
+/// Bytes held against an InFlightLimit, released on drop
+pub struct InFlightGuard<'a> {
+    limit: Option<&'a InFlightLimit>,
+    bytes: u64,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(limit) = self.limit {
+            *limit.bytes.lock().unwrap() -= self.bytes;
+            limit.released.notify_all();
+        }
+    }
+}
+
+/// Enforces a target's QosLimits
+pub struct RateLimiter {
+    read: Option<Mutex<TokenBucket>>,
+    write: Option<Mutex<TokenBucket>>,
+    iops: Option<Mutex<TokenBucket>>,
+    write_in_flight: Option<InFlightLimit>,
+}
+
+impl RateLimiter {
+    /// Create a limiter for the given limits
+    pub fn new(limits: &QosLimits) -> Self {
+        let bucket = |rate: f64| Mutex::new(TokenBucket::new(rate));
+        Self {
+            read: limits.max_read_mbps.map(|mbps| bucket(mbps as f64 * MIB)),
+            write: limits.max_write_mbps.map(|mbps| bucket(mbps as f64 * MIB)),
+            iops: limits.max_iops.map(|iops| bucket(iops as f64)),
+            write_in_flight: limits
+                .max_inflight_write_mb
+                .map(|mb| InFlightLimit::new(mb as u64 * MIB as u64)),
+        }
+    }
+
+    /// Block until a read of `bytes` is allowed
+    pub fn throttle_read(&self, bytes: usize) {
+        self.throttle(self.read.as_ref(), bytes);
+    }
+
+    /// Block until a write of `bytes` is allowed
+    pub fn throttle_write(&self, bytes: usize) {
+        self.throttle(self.write.as_ref(), bytes);
+    }
+
+    /// Block until a write of `bytes` fits under the in-flight limit;
+    /// the guard holds it until the write is stored
+    pub fn begin_write(&self, bytes: usize) -> InFlightGuard<'_> {
+        match &self.write_in_flight {
+            Some(limit) => limit.acquire(bytes),
+            None => InFlightGuard { limit: None, bytes: 0 },
+        }
+    }
+
+    /// Like `begin_write`, but None instead of waiting when it doesn't fit
+    pub fn try_begin_write(&self, bytes: usize) -> Option<InFlightGuard<'_>> {
+        match &self.write_in_flight {
+            Some(limit) => limit.try_acquire(bytes),
+            None => Some(InFlightGuard { limit: None, bytes: 0 }),
+        }
+    }
+
+    /// The in-flight write cap in bytes, for callers that buffer writes
+    pub fn max_write_in_flight(&self) -> Option<u64> {
+        self.write_in_flight.as_ref().map(InFlightLimit::max_bytes)
+    }
+
+    fn throttle(&self, bandwidth: Option<&Mutex<TokenBucket>>, bytes: usize) {
+        let bandwidth_wait = bandwidth
+            .map(|bucket| bucket.lock().unwrap().take(bytes as f64))
+            .unwrap_or_default();
+        let iops_wait = self
+            .iops
+            .as_ref()
+            .map(|bucket| bucket.lock().unwrap().take(1.0))
+            .unwrap_or_default();
+
+        let wait = bandwidth_wait.max(iops_wait);
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited_does_not_block() {
+        let limiter = RateLimiter::new(&QosLimits::default());
+        let start = Instant::now();
+        for _ in 0..1000 {
+            limiter.throttle_read(1024 * 1024);
+            limiter.throttle_write(1024 * 1024);
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_iops_limit() {
+        let limiter = RateLimiter::new(&QosLimits {
+            max_iops: Some(100),
+            ..Default::default()
+        });
+
+        // 100 ops of burst, then 20 more at 100/s ~= 200ms
+        let start = Instant::now();
+        for _ in 0..120 {
+            limiter.throttle_read(512);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn test_bandwidth_limit_per_direction() {
+        let limiter = RateLimiter::new(&QosLimits {
+            max_write_mbps: Some(4),
+            ..Default::default()
+        });
+
+        // Reads are unlimited
+        let start = Instant::now();
+        limiter.throttle_read(64 * 1024 * 1024);
+        assert!(start.elapsed() < Duration::from_millis(50));
+
+        // 4 MiB burst + 1 MiB over at 4 MiB/s ~= 250ms
+        let start = Instant::now();
+        limiter.throttle_write(5 * 1024 * 1024);
+        limiter.throttle_write(1);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_write_in_flight_limit() {
+        let limiter = RateLimiter::new(&QosLimits {
+            max_inflight_write_mb: Some(1),
+            ..Default::default()
+        });
+
+        let first = limiter.begin_write(768 * 1024);
+        assert!(limiter.try_begin_write(512 * 1024).is_none());
+        let small = limiter.try_begin_write(256 * 1024).unwrap();
+        drop(small);
+
+        // A waiting write goes ahead once the first is stored
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| {
+                let start = Instant::now();
+                let _guard = limiter.begin_write(512 * 1024);
+                start.elapsed()
+            });
+            std::thread::sleep(Duration::from_millis(100));
+            drop(first);
+            assert!(waiter.join().unwrap() >= Duration::from_millis(50));
+        });
+
+        // Oversized writes still get through alone
+        let limit = InFlightLimit::new(1024);
+        let big = limit.try_acquire(4096).unwrap();
+        assert_eq!(limit.in_flight(), 4096);
+        assert!(limit.try_acquire(1).is_none());
+        drop(big);
+        assert_eq!(limit.in_flight(), 0);
+    }
+}
@@ -0,0 +1,951 @@
+//! HTTP management API
+//!
+//! Optional axum-based API mirroring the one `iscsi-web` offers for iSCSI
+//! targets, so tooling can manage AoE targets the same way. Targets are
+//! named `e<shelf>.<slot>`:
+//!
+//! - `GET /targets`: address, size, backend and health of every target
+//! - `GET /targets/{id}/stats`: SMART counters, dedup statistics and the
+//!   traffic of each initiator MAC
+//! - `GET /targets/{id}/snapshots`: snapshots of a CAS target
+//! - `GET /targets/{id}/snapshots/tree`: the same, arranged by parent
+//! - `POST /targets/{id}/snapshot`: snapshot a CAS target, optionally
+//!   with a name and tags
+//! - `PATCH /targets/{id}/snapshots/{snapshot}`: rename or retag a
+//!   snapshot, given by id or name
+//! - `POST /blob-stores/migrate`: move the blobs of every CAS target using
+//!   one store to another while they stay online (see `blob::migrate`)
+//! - `GET /blob-stores/migration`: progress of the latest migration
+//! - `POST /targets/{id}/import`: replace a CAS target's contents with a
+//!   raw image on the server's host
+//! - `POST /targets/{id}/export`: write a target to a new raw image
+//! - `POST /targets/{id}/replicate`: copy an NBD export into a target
+//! - `GET /targets/{id}/transfer`: progress of the target's latest import,
+//!   export or replication (see `server::transfer`)
+//! - `GET /api/openapi.json`: OpenAPI document for the above
+//!
+//! Responses use the same `{success, data, error}` envelope as `iscsi-web`.
+//!
+//! With `api_token` set, every request must carry it as
+//! `Authorization: Bearer <token>`. Without one the API can only be bound
+//! to a loopback address.
+
+use super::{InitiatorStats, TargetAddr, TargetManager, TransferKind, TransferStatus};
+use crate::blob::{BlobStoreSpec, MigrationStatus};
+use crate::frontend::parse_aoe_name;
+use crate::openapi::{
+    array, boolean, extend, integer, nullable, object, schema_ref, string, ApiDoc,
+};
+use crate::protocol::SmartStats;
+use crate::shutdown;
+use crate::storage::{
+    DedupStats, LatencyStats, SnapshotInfo, SnapshotTree, StorageError, UsageStats,
+};
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{get, patch, post},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[derive(Serialize)]
+pub struct ApiResponse<T> {
+    pub success: bool,
+    pub data: Option<T>,
+    pub error: Option<String>,
+}
+
+impl<T> ApiResponse<T> {
+    pub fn success(data: T) -> Json<Self> {
+        Json(Self {
+            success: true,
+            data: Some(data),
+            error: None,
+        })
+    }
+
+    pub fn error(message: impl ToString) -> Json<Self> {
+        Json(Self {
+            success: false,
+            data: None,
+            error: Some(message.to_string()),
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct TargetInfo {
+    pub id: String,
+    pub shelf: u16,
+    pub slot: u8,
+    pub size_bytes: u64,
+    pub total_sectors: u64,
+    pub sector_size: u32,
+    pub backend: Option<&'static str>,
+    pub model: String,
+    pub config_string: String,
+    pub snapshots: bool,
+    /// Blob store of a CAS target, as `file:<dir>` or `remote:<addr>`
+    pub blob_store: Option<String>,
+    /// False while the backend is unhealthy and I/O is refused
+    pub online: bool,
+    pub offline_reason: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct TargetStats {
+    pub smart: SmartStats,
+    pub dedup: Option<DedupStats>,
+    pub latency: Option<LatencyStats>,
+    pub usage: Option<UsageStats>,
+    pub initiators: Vec<InitiatorStats>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct SnapshotRequest {
+    pub description: Option<String>,
+    pub name: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// Changes to a snapshot; absent fields are left alone
+#[derive(Deserialize, Default)]
+pub struct SnapshotUpdate {
+    pub name: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+/// Blob store migration to start; stores are `file:<dir>` or `remote:<addr>`
+#[derive(Deserialize)]
+pub struct MigrationRequest {
+    pub from: String,
+    pub to: String,
+}
+
+/// Raw image on the server's host to import from or export to
+#[derive(Deserialize)]
+pub struct ImageRequest {
+    pub image: PathBuf,
+}
+
+/// NBD export to replicate into a target
+#[derive(Deserialize)]
+pub struct ReplicateRequest {
+    /// `host:port` or `unix:/path`
+    pub source: String,
+    #[serde(default)]
+    pub export: String,
+}
+
+/// Build the API router for a set of targets
+pub fn router(targets: Arc<TargetManager>) -> Router {
+    Router::new()
+        .route("/targets", get(list_targets))
+        .route("/targets/{id}/stats", get(target_stats))
+        .route("/targets/{id}/snapshots", get(list_snapshots))
+        .route("/targets/{id}/snapshots/tree", get(snapshot_tree))
+        .route("/targets/{id}/snapshot", post(create_snapshot))
+        .route("/targets/{id}/snapshots/{snapshot}", patch(update_snapshot))
+        .route("/blob-stores/migrate", post(migrate_blobs))
+        .route("/blob-stores/migration", get(blob_migration))
+        .route("/targets/{id}/import", post(import_image))
+        .route("/targets/{id}/export", post(export_image))
+        .route("/targets/{id}/replicate", post(replicate))
+        .route("/targets/{id}/transfer", get(target_transfer))
+        .route("/api/openapi.json", get(|| async { Json(openapi_doc()) }))
+        .with_state(targets)
+}
+
+/// OpenAPI document describing `router`
+pub fn openapi_doc() -> serde_json::Value {
+    let dedup = object(&[
+        ("logical_bytes", integer()),
+        ("blocks_written", integer()),
+        ("zero_blocks", integer()),
+        ("duplicate_blocks", integer()),
+        ("unique_blocks", integer()),
+        ("delta_blocks", integer()),
+        ("unique_bytes", integer()),
+        ("stored_bytes", integer()),
+    ]);
+    let op_latency = || {
+        object(&[
+            ("count", integer()),
+            ("mean_us", integer()),
+            ("p50_us", integer()),
+            ("p99_us", integer()),
+            ("max_us", integer()),
+        ])
+    };
+    let latency = object(&[
+        ("read", op_latency()),
+        ("write", op_latency()),
+        ("flush", op_latency()),
+    ]);
+    let space = object(&[
+        ("total_bytes", integer()),
+        ("available_bytes", integer()),
+        ("reserve_bytes", integer()),
+        ("alarm_bytes", integer()),
+        ("alarm", boolean()),
+    ]);
+    let initiator = object(&[
+        ("mac", string()),
+        ("requests", integer()),
+        ("bytes_read", integer()),
+        ("bytes_written", integer()),
+        ("errors", integer()),
+        ("last_seen", integer()),
+    ]);
+    let snapshot = object(&[
+        ("id", string()),
+        ("timestamp", integer()),
+        ("description", nullable(string())),
+        ("name", nullable(string())),
+        ("tags", array(string())),
+        ("parent", nullable(string())),
+    ]);
+    let usage = object(&[
+        ("capacity_bytes", integer()),
+        ("allocated_bytes", integer()),
+        ("space", nullable(space)),
+        ("overcommitted", boolean()),
+        (
+            "quota",
+            nullable(object(&[
+                ("attributed_bytes", integer()),
+                ("quota_bytes", nullable(integer())),
+            ])),
+        ),
+    ]);
+
+    ApiDoc::new("VoE AoE server", "Manage the targets of a running aoe-server")
+        .schema(
+            "TargetInfo",
+            object(&[
+                ("id", string()),
+                ("shelf", integer()),
+                ("slot", integer()),
+                ("size_bytes", integer()),
+                ("total_sectors", integer()),
+                ("sector_size", integer()),
+                ("backend", nullable(string())),
+                ("model", string()),
+                ("config_string", string()),
+                ("snapshots", boolean()),
+                ("blob_store", nullable(string())),
+                ("online", boolean()),
+                ("offline_reason", nullable(string())),
+            ]),
+        )
+        .schema(
+            "TargetStats",
+            object(&[
+                (
+                    "smart",
+                    object(&[
+                        ("read_errors", integer()),
+                        ("write_errors", integer()),
+                        ("corrupted_blocks", integer()),
+                        ("no_space_errors", integer()),
+                        ("uptime_secs", integer()),
+                    ]),
+                ),
+                ("dedup", nullable(dedup)),
+                ("latency", nullable(latency)),
+                ("usage", nullable(usage)),
+                ("initiators", array(initiator)),
+            ]),
+        )
+        .schema(
+            "SnapshotTree",
+            extend(&snapshot, &[("children", array(schema_ref("SnapshotTree")))]),
+        )
+        .schema("SnapshotInfo", snapshot)
+        .schema(
+            "MigrationStatus",
+            object(&[
+                ("from", string()),
+                ("to", string()),
+                ("targets", array(string())),
+                ("state", string()),
+                ("blobs", integer()),
+                ("copied", integer()),
+                ("copied_bytes", integer()),
+                ("verified", integer()),
+                ("error", nullable(string())),
+            ]),
+        )
+        .schema(
+            "TransferStatus",
+            object(&[
+                ("kind", string()),
+                ("from", string()),
+                ("to", string()),
+                ("state", string()),
+                ("total_bytes", integer()),
+                ("copied_bytes", integer()),
+                ("error", nullable(string())),
+            ]),
+        )
+        .operation("GET", "/targets", "List targets", None, array(schema_ref("TargetInfo")))
+        .operation(
+            "GET",
+            "/targets/{id}/stats",
+            "Health, deduplication and per-initiator statistics",
+            None,
+            schema_ref("TargetStats"),
+        )
+        .operation(
+            "GET",
+            "/targets/{id}/snapshots",
+            "List a CAS target's snapshots",
+            None,
+            array(schema_ref("SnapshotInfo")),
+        )
+        .operation(
+            "GET",
+            "/targets/{id}/snapshots/tree",
+            "A CAS target's snapshots arranged by parent, one tree per history",
+            None,
+            array(schema_ref("SnapshotTree")),
+        )
+        .operation(
+            "POST",
+            "/targets/{id}/snapshot",
+            "Snapshot a CAS target, returning the snapshot ID",
+            Some(object(&[
+                ("description", nullable(string())),
+                ("name", nullable(string())),
+                ("tags", array(string())),
+            ])),
+            string(),
+        )
+        .operation(
+            "PATCH",
+            "/targets/{id}/snapshots/{snapshot}",
+            "Rename or retag a snapshot, given by ID or name",
+            Some(object(&[
+                ("name", nullable(string())),
+                ("tags", nullable(array(string()))),
+            ])),
+            schema_ref("SnapshotInfo"),
+        )
+        .operation(
+            "POST",
+            "/blob-stores/migrate",
+            "Move the blobs of the CAS targets using one store to another, online",
+            Some(object(&[("from", string()), ("to", string())])),
+            schema_ref("MigrationStatus"),
+        )
+        .operation(
+            "GET",
+            "/blob-stores/migration",
+            "Progress of the latest blob store migration",
+            None,
+            schema_ref("MigrationStatus"),
+        )
+        .operation(
+            "POST",
+            "/targets/{id}/import",
+            "Replace a CAS target's contents with a raw image on the server's host",
+            Some(object(&[("image", string())])),
+            schema_ref("TransferStatus"),
+        )
+        .operation(
+            "POST",
+            "/targets/{id}/export",
+            "Write a target to a new raw image on the server's host",
+            Some(object(&[("image", string())])),
+            schema_ref("TransferStatus"),
+        )
+        .operation(
+            "POST",
+            "/targets/{id}/replicate",
+            "Copy an NBD export into a target",
+            Some(object(&[("source", string()), ("export", string())])),
+            schema_ref("TransferStatus"),
+        )
+        .operation(
+            "GET",
+            "/targets/{id}/transfer",
+            "Progress of a target's latest import, export or replication",
+            None,
+            schema_ref("TransferStatus"),
+        )
+        .to_json()
+}
+
+/// Serve the API on a background thread until shutdown is requested,
+/// requiring `token` on every request if given. Binds before returning so
+/// address errors are reported to the caller; a non-loopback address is
+/// refused without a token.
+pub fn spawn(
+    bind: &str,
+    targets: Arc<TargetManager>,
+    token: Option<String>,
+) -> io::Result<(SocketAddr, JoinHandle<()>)> {
+    let listener = std::net::TcpListener::bind(bind)?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    if token.is_none() && !addr.ip().is_loopback() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("refusing to serve the API on {} without api_token", addr),
+        ));
+    }
+    let app = match token {
+        Some(token) => {
+            let token = Arc::<str>::from(token);
+            router(targets).layer(middleware::from_fn_with_state(token, require_token))
+        }
+        None => router(targets),
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    let thread = thread::Builder::new()
+        .name("aoe-api".to_string())
+        .spawn(move || {
+            runtime.block_on(async move {
+                let result = async {
+                    let listener = tokio::net::TcpListener::from_std(listener)?;
+                    axum::serve(listener, app)
+                        .with_graceful_shutdown(async {
+                            while !shutdown::requested() {
+                                tokio::time::sleep(shutdown::POLL_INTERVAL).await;
+                            }
+                        })
+                        .await
+                }
+                .await;
+                if let Err(e) = result {
+                    log::error!("HTTP API error: {}", e);
+                }
+            })
+        })?;
+
+    Ok((addr, thread))
+}
+
+/// Refuse requests without the bearer token
+async fn require_token(State(token): State<Arc<str>>, request: Request, next: Next) -> Response {
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match presented {
+        Some(presented) if token_matches(presented.as_bytes(), token.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => {
+            let body = ApiResponse::<()>::error("missing or wrong API token");
+            (StatusCode::UNAUTHORIZED, body).into_response()
+        }
+    }
+}
+
+/// Compare tokens in time independent of where they differ
+fn token_matches(presented: &[u8], token: &[u8]) -> bool {
+    let diff = presented
+        .iter()
+        .zip(token)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    presented.len() == token.len() && diff == 0
+}
+
+fn lookup(id: &str) -> Result<TargetAddr, String> {
+    parse_aoe_name(id)
+        .map(|(shelf, slot)| TargetAddr::new(shelf, slot))
+        .map_err(|e| e.to_string())
+}
+
+async fn list_targets(State(targets): State<Arc<TargetManager>>) -> Json<ApiResponse<Vec<TargetInfo>>> {
+    let mut addrs = targets.addrs();
+    addrs.sort_by_key(|addr| (addr.shelf, addr.slot));
+
+    let list = addrs
+        .into_iter()
+        .filter_map(|addr| {
+            let target = targets.target(addr)?;
+            let info = target.storage.info();
+            let health = target.health.check(target.storage.as_ref());
+            Some(TargetInfo {
+                id: format!("e{}.{}", addr.shelf, addr.slot),
+                shelf: addr.shelf,
+                slot: addr.slot,
+                size_bytes: info.size_bytes(),
+                total_sectors: info.total_sectors,
+                sector_size: info.sector_size,
+                backend: target.backend,
+                model: info.model.clone(),
+                config_string: target.config_string(),
+                snapshots: target.storage.as_archival().is_some(),
+                blob_store: target.blob_store.as_ref().map(|store| store.spec().to_string()),
+                online: health.is_ok(),
+                offline_reason: health.err(),
+            })
+        })
+        .collect();
+    ApiResponse::success(list)
+}
+
+async fn target_stats(
+    State(targets): State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+) -> Json<ApiResponse<TargetStats>> {
+    let addr = match lookup(&id) {
+        Ok(addr) => addr,
+        Err(e) => return ApiResponse::error(e),
+    };
+    // Usage may walk a CAS tree and stat the filesystem; keep that off
+    // the async workers
+    let result = tokio::task::spawn_blocking(move || {
+        let target = targets
+            .target(addr)
+            .ok_or_else(|| format!("Target not found: {}", id))?;
+        Ok::<_, String>(TargetStats {
+            smart: target.smart.stats(),
+            dedup: targets.dedup_stats(addr),
+            latency: target.storage.latency_stats(),
+            usage: target.storage.usage(),
+            initiators: target.initiators.stats(),
+        })
+    })
+    .await;
+
+    match result {
+        Ok(Ok(stats)) => ApiResponse::success(stats),
+        Ok(Err(e)) => ApiResponse::error(e),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+async fn list_snapshots(
+    State(targets): State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+) -> Json<ApiResponse<Vec<SnapshotInfo>>> {
+    let addr = match lookup(&id) {
+        Ok(addr) => addr,
+        Err(e) => return ApiResponse::error(e),
+    };
+    let Some(target) = targets.target(addr) else {
+        return ApiResponse::error(format!("Target not found: {}", id));
+    };
+    match target.storage.as_archival() {
+        Some(archival) => match archival.list_snapshots() {
+            Ok(snapshots) => ApiResponse::success(snapshots),
+            Err(e) => ApiResponse::error(e),
+        },
+        None => ApiResponse::error(format!("Target does not support snapshots: {}", id)),
+    }
+}
+
+async fn snapshot_tree(
+    state: State<Arc<TargetManager>>,
+    id: Path<String>,
+) -> Json<ApiResponse<Vec<SnapshotTree>>> {
+    let Json(listed) = list_snapshots(state, id).await;
+    Json(ApiResponse {
+        success: listed.success,
+        data: listed.data.map(|snapshots| SnapshotTree::build(&snapshots)),
+        error: listed.error,
+    })
+}
+
+async fn create_snapshot(
+    State(targets): State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+    req: Option<Json<SnapshotRequest>>,
+) -> Json<ApiResponse<String>> {
+    let addr = match lookup(&id) {
+        Ok(addr) => addr,
+        Err(e) => return ApiResponse::error(e),
+    };
+    let Json(req) = req.unwrap_or_default();
+
+    // Snapshots persist metadata to disk; keep that off the async workers
+    let result = tokio::task::spawn_blocking(move || {
+        let target = targets
+            .target(addr)
+            .ok_or_else(|| format!("Target not found: {}", id))?;
+        let archival = target
+            .storage
+            .as_archival()
+            .ok_or_else(|| format!("Target does not support snapshots: {}", id))?;
+        match &req.name {
+            Some(name) => archival.snapshot_named(name, req.description.as_deref(), &req.tags),
+            None if !req.tags.is_empty() => Err(StorageError::Backend(
+                "tags need a snapshot name".to_string(),
+            )),
+            None => archival.snapshot(req.description.as_deref()),
+        }
+        .map_err(|e| e.to_string())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(snapshot)) => ApiResponse::success(snapshot),
+        Ok(Err(e)) => ApiResponse::error(e),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+async fn update_snapshot(
+    State(targets): State<Arc<TargetManager>>,
+    Path((id, snapshot)): Path<(String, String)>,
+    Json(update): Json<SnapshotUpdate>,
+) -> Json<ApiResponse<SnapshotInfo>> {
+    let addr = match lookup(&id) {
+        Ok(addr) => addr,
+        Err(e) => return ApiResponse::error(e),
+    };
+
+    let result = tokio::task::spawn_blocking(move || {
+        let target = targets
+            .target(addr)
+            .ok_or_else(|| format!("Target not found: {}", id))?;
+        let archival = target
+            .storage
+            .as_archival()
+            .ok_or_else(|| format!("Target does not support snapshots: {}", id))?;
+        archival
+            .update_snapshot(&snapshot, update.name.as_deref(), update.tags.as_deref())
+            .map_err(|e| e.to_string())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(info)) => ApiResponse::success(info),
+        Ok(Err(e)) => ApiResponse::error(e),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+async fn migrate_blobs(
+    State(targets): State<Arc<TargetManager>>,
+    Json(req): Json<MigrationRequest>,
+) -> Json<ApiResponse<MigrationStatus>> {
+    let (from, to) = match (req.from.parse::<BlobStoreSpec>(), req.to.parse::<BlobStoreSpec>()) {
+        (Ok(from), Ok(to)) => (from, to),
+        (Err(e), _) | (_, Err(e)) => return ApiResponse::error(e),
+    };
+    // Opening the new store may create directories or connect to a server
+    let result = tokio::task::spawn_blocking(move || {
+        targets.migrate_blobs(&from, to).map_err(|e| e.to_string())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(status)) => ApiResponse::success(status),
+        Ok(Err(e)) => ApiResponse::error(e),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+async fn blob_migration(
+    State(targets): State<Arc<TargetManager>>,
+) -> Json<ApiResponse<MigrationStatus>> {
+    match targets.blob_migration() {
+        Some(status) => ApiResponse::success(status),
+        None => ApiResponse::error("No blob store migration has run"),
+    }
+}
+
+async fn import_image(
+    targets: State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+    Json(req): Json<ImageRequest>,
+) -> Json<ApiResponse<TransferStatus>> {
+    start_transfer(targets, &id, TransferKind::Import { image: req.image }).await
+}
+
+async fn export_image(
+    targets: State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+    Json(req): Json<ImageRequest>,
+) -> Json<ApiResponse<TransferStatus>> {
+    start_transfer(targets, &id, TransferKind::Export { image: req.image }).await
+}
+
+async fn replicate(
+    targets: State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+    Json(req): Json<ReplicateRequest>,
+) -> Json<ApiResponse<TransferStatus>> {
+    let kind = TransferKind::Replicate {
+        source: req.source,
+        export: req.export,
+    };
+    start_transfer(targets, &id, kind).await
+}
+
+async fn start_transfer(
+    State(targets): State<Arc<TargetManager>>,
+    id: &str,
+    kind: TransferKind,
+) -> Json<ApiResponse<TransferStatus>> {
+    let addr = match lookup(id) {
+        Ok(addr) => addr,
+        Err(e) => return ApiResponse::error(e),
+    };
+    // Opening the image or connecting to the NBD server blocks
+    let result = tokio::task::spawn_blocking(move || {
+        targets
+            .start_transfer(addr, kind)
+            .map_err(|e| e.to_string())
+    })
+    .await;
+
+    match result {
+        Ok(Ok(status)) => ApiResponse::success(status),
+        Ok(Err(e)) => ApiResponse::error(e),
+        Err(e) => ApiResponse::error(e),
+    }
+}
+
+async fn target_transfer(
+    State(targets): State<Arc<TargetManager>>,
+    Path(id): Path<String>,
+) -> Json<ApiResponse<TransferStatus>> {
+    let addr = match lookup(&id) {
+        Ok(addr) => addr,
+        Err(e) => return ApiResponse::error(e),
+    };
+    match targets.transfer(addr) {
+        Some(status) => ApiResponse::success(status),
+        None => ApiResponse::error(format!("No transfer of {} has run", id)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::{FileBlobStore, MigratingBlobStore};
+    use crate::storage::{BlockStorage, CasBackend, MemBackend};
+    use std::io::{Read, Write};
+    use tempfile::TempDir;
+
+    fn request(addr: SocketAddr, method: &str, path: &str) -> serde_json::Value {
+        request_json(addr, method, path, "")
+    }
+
+    fn request_json(addr: SocketAddr, method: &str, path: &str, body: &str) -> serde_json::Value {
+        let mut stream = std::net::TcpStream::connect(addr).unwrap();
+        let content_type = if body.is_empty() { "" } else { "Content-Type: application/json\r\n" };
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            method,
+            path,
+            content_type,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_list_stats_and_snapshot() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path().join("blobs")).unwrap();
+        let cas = CasBackend::new(Box::new(store), 2048, &temp.path().join("snapshots.json")).unwrap();
+
+        let mut manager = TargetManager::new();
+        manager.add_target(1, 0, Box::new(MemBackend::new(1024 * 1024)), String::new());
+        manager.set_backend(1, 0, "memory");
+        manager.add_target(1, 1, Box::new(cas), String::new());
+        manager.set_backend(1, 1, "cas");
+
+        let (addr, _thread) = spawn("127.0.0.1:0", Arc::new(manager), None).unwrap();
+
+        let targets = request(addr, "GET", "/targets");
+        assert_eq!(targets["data"][0]["id"], "e1.0");
+        assert_eq!(targets["data"][0]["size_bytes"], 1024 * 1024);
+        assert_eq!(targets["data"][1]["backend"], "cas");
+        assert_eq!(targets["data"][1]["snapshots"], true);
+
+        let stats = request(addr, "GET", "/targets/e1.1/stats");
+        assert_eq!(stats["data"]["smart"]["read_errors"], 0);
+        assert!(stats["data"]["dedup"].is_object());
+        assert_eq!(stats["data"]["latency"]["read"]["count"], 0);
+        assert_eq!(stats["data"]["usage"]["capacity_bytes"], 2048 * 512);
+        assert_eq!(stats["data"]["usage"]["allocated_bytes"], 0);
+        assert_eq!(stats["data"]["initiators"], serde_json::json!([]));
+
+        assert_eq!(request(addr, "POST", "/targets/e1.0/snapshot")["success"], false);
+        assert_eq!(request(addr, "POST", "/targets/e1.1/snapshot")["success"], true);
+        let snapshots = request(addr, "GET", "/targets/e1.1/snapshots");
+        assert_eq!(snapshots["data"].as_array().unwrap().len(), 1);
+
+        let body = r#"{"name": "nightly", "tags": ["prod"]}"#;
+        let named = request_json(addr, "POST", "/targets/e1.1/snapshot", body);
+        assert_eq!(named["success"], true);
+        // Names are unique
+        assert_eq!(request_json(addr, "POST", "/targets/e1.1/snapshot", body)["success"], false);
+        let body = r#"{"name": "golden", "tags": []}"#;
+        let updated = request_json(addr, "PATCH", "/targets/e1.1/snapshots/nightly", body);
+        assert_eq!(updated["data"]["name"], "golden");
+        assert_eq!(updated["data"]["id"], named["data"]);
+        assert_eq!(updated["data"]["tags"], serde_json::json!([]));
+        // Taken one after the other: a single chain
+        let tree = request(addr, "GET", "/targets/e1.1/snapshots/tree");
+        assert_eq!(tree["data"].as_array().unwrap().len(), 1);
+        assert_eq!(tree["data"][0]["children"][0]["name"], "golden");
+
+        assert_eq!(request(addr, "GET", "/targets/x/stats")["success"], false);
+
+        let doc = request(addr, "GET", "/api/openapi.json");
+        assert!(doc["paths"]["/targets/{id}/snapshot"]["post"].is_object());
+    }
+
+    #[test]
+    fn test_blob_store_migration() {
+        let temp = TempDir::new().unwrap();
+        let old = BlobStoreSpec::File(temp.path().join("old"));
+        let store = MigratingBlobStore::new(
+            old.clone(),
+            old.open(None, true).unwrap(),
+            true,
+            &temp.path().join("e1.0.blob-store"),
+        );
+        let cas = CasBackend::new(
+            Box::new(store.clone()),
+            2048,
+            &temp.path().join("snapshots.json"),
+        )
+        .unwrap();
+        cas.write(0, &[7; 4096]).unwrap();
+        cas.flush().unwrap();
+
+        let mut manager = TargetManager::new();
+        manager.add_target(1, 0, Box::new(cas), String::new());
+        manager.set_blob_store(1, 0, store);
+        let (addr, _thread) = spawn("127.0.0.1:0", Arc::new(manager), None).unwrap();
+
+        let from = old.to_string();
+        let to = format!("file:{}", temp.path().join("new").display());
+        let body = serde_json::json!({ "from": from, "to": "rocksdb:/new" }).to_string();
+        let refused = request_json(addr, "POST", "/blob-stores/migrate", &body);
+        assert!(refused["error"].as_str().unwrap().contains("rocksdb"));
+        assert_eq!(request(addr, "GET", "/blob-stores/migration")["success"], false);
+
+        let body = serde_json::json!({ "from": from, "to": to }).to_string();
+        let started = request_json(addr, "POST", "/blob-stores/migrate", &body);
+        assert_eq!(started["data"]["targets"], serde_json::json!(["e1.0"]));
+        let status = loop {
+            let status = request(addr, "GET", "/blob-stores/migration");
+            if !matches!(status["data"]["state"].as_str(), Some("copying" | "verifying")) {
+                break status;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        assert_eq!(status["data"]["state"], "done");
+        assert!(status["data"]["copied"].as_u64().unwrap() > 0);
+        assert_eq!(request(addr, "GET", "/targets")["data"][0]["blob_store"], to);
+    }
+
+    #[test]
+    fn test_import_export_and_replicate() {
+        let temp = TempDir::new().unwrap();
+        let cas = |name: &str| {
+            let store = FileBlobStore::new(temp.path().join(name)).unwrap();
+            let snapshots = temp.path().join(format!("{}.json", name));
+            CasBackend::new(Box::new(store), 2048, &snapshots).unwrap()
+        };
+        let source = MemBackend::new(2048 * 512);
+        source.write(0, &[3; 4096]).unwrap();
+        source.write(1024, &[4; 512]).unwrap();
+
+        let mut manager = TargetManager::new();
+        manager.add_target(1, 0, Box::new(source), String::new());
+        manager.add_target(1, 1, Box::new(cas("imported")), String::new());
+        manager.add_target(1, 2, Box::new(cas("replica")), String::new());
+        let manager = Arc::new(manager);
+        let (addr, _thread) = spawn("127.0.0.1:0", Arc::clone(&manager), None).unwrap();
+        let wait = |id: &str| loop {
+            let status = request(addr, "GET", &format!("/targets/{}/transfer", id));
+            if status["data"]["state"] != "running" {
+                break status;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        let read = |shelf, slot, lba| {
+            let target = manager.target(TargetAddr::new(shelf, slot)).unwrap();
+            target.storage.read(lba, 1).unwrap()
+        };
+
+        let image = temp.path().join("e1.0.img");
+        let body = serde_json::json!({ "image": image }).to_string();
+        let started = request_json(addr, "POST", "/targets/e1.0/export", &body);
+        assert_eq!(started["data"]["kind"], "export");
+        assert_eq!(wait("e1.0")["data"]["state"], "done");
+        assert_eq!(std::fs::metadata(&image).unwrap().len(), 2048 * 512);
+        // Exports never overwrite
+        let refused = request_json(addr, "POST", "/targets/e1.0/export", &body);
+        assert_eq!(refused["success"], false);
+
+        // Only CAS targets import
+        let refused = request_json(addr, "POST", "/targets/e1.0/import", &body);
+        assert!(refused["error"]
+            .as_str()
+            .unwrap()
+            .contains("not a CAS target"));
+        let started = request_json(addr, "POST", "/targets/e1.1/import", &body);
+        assert_eq!(started["data"]["total_bytes"], 2048 * 512);
+        let done = wait("e1.1");
+        assert_eq!(done["data"]["copied_bytes"], 2048 * 512);
+        assert_eq!(read(1, 1, 7), vec![3; 512]);
+        assert_eq!(read(1, 1, 1024), vec![4; 512]);
+
+        let nbd = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let source = nbd.local_addr().unwrap().to_string();
+        let server = crate::nbd::NbdServer::new(Default::default(), cas("served"));
+        thread::spawn(move || server.serve(nbd));
+        let mut client = crate::nbd::NbdClient::connect(&source, "").unwrap();
+        client.write(512 * 100, &[5; 512]).unwrap();
+        client.disconnect().unwrap();
+        let body = serde_json::json!({ "source": source }).to_string();
+        let started = request_json(addr, "POST", "/targets/e1.2/replicate", &body);
+        assert_eq!(started["success"], true);
+        assert_eq!(wait("e1.2")["data"]["state"], "done");
+        assert_eq!(read(1, 2, 100), vec![5; 512]);
+
+        let latest = request(addr, "GET", "/targets/e1.0/transfer");
+        assert_eq!(latest["data"]["kind"], "export");
+        let never = request(addr, "GET", "/targets/e2.0/transfer");
+        assert_eq!(never["success"], false);
+    }
+
+    #[test]
+    fn test_token_required_off_loopback() {
+        let manager = Arc::new(TargetManager::new());
+        let refused = spawn("0.0.0.0:0", Arc::clone(&manager), None);
+        assert_eq!(refused.err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        let (addr, _thread) = spawn("127.0.0.1:0", manager, Some("secret".to_string())).unwrap();
+        let refused = request(addr, "GET", "/targets");
+        assert_eq!(refused["success"], false);
+        assert!(refused["error"].as_str().unwrap().contains("token"));
+
+        assert!(token_matches(b"secret", b"secret"));
+        assert!(!token_matches(b"secreT", b"secret"));
+        assert!(!token_matches(b"secret2", b"secret"));
+    }
+}
@@ -0,0 +1,100 @@
+//! Per-initiator statistics
+//!
+//! Counts the requests, bytes and errors each initiator MAC sends a
+//! target, and when it was last heard from, so the admin API can show
+//! which hosts use which shelf/slot and single out a misbehaving one.
+
+use crate::protocol::{ata_status, AoeError, AoeFrame, AoePayload, AtaCommand, ResponseData};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Initiators tracked per target before the least recently seen is dropped
+const MAX_INITIATORS: usize = 256;
+
+/// Traffic from one initiator to a target
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct InitiatorStats {
+    /// Source MAC, colon-separated hex
+    pub mac: String,
+    pub requests: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Requests refused or failed, including ATA error responses
+    pub errors: u64,
+    /// Unix time of the latest request
+    pub last_seen: u64,
+}
+
+/// Per-MAC counters for one target
+#[derive(Default)]
+pub struct InitiatorTable {
+    initiators: Mutex<HashMap<[u8; 6], InitiatorStats>>,
+}
+
+impl InitiatorTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for a request and the target's answer to it
+    pub fn record(&self, frame: &AoeFrame, result: &Result<ResponseData, AoeError>) {
+        let (mut read, mut written, mut failed) = (0, 0, false);
+        match (&frame.payload, result) {
+            (AoePayload::Ata { header, data }, Ok(ResponseData::Ata(response))) => {
+                if response.status & ata_status::ERR != 0 {
+                    failed = true;
+                } else {
+                    match AtaCommand::try_from(header.cmd_status) {
+                        Ok(cmd) if cmd.is_read() => {
+                            read = response.data.as_ref().map_or(0, |d| d.len() as u64)
+                        }
+                        Ok(cmd) if cmd.is_write() => written = data.len() as u64,
+                        _ => {}
+                    }
+                }
+            }
+            (_, Ok(ResponseData::Error { .. })) => failed = true,
+            // A config test that doesn't match is answered with silence
+            (AoePayload::Ata { .. }, Err(_)) => failed = true,
+            _ => {}
+        }
+
+        let mac = frame.header.src_mac;
+        let mut initiators = self.initiators.lock().unwrap();
+        if !initiators.contains_key(&mac) && initiators.len() >= MAX_INITIATORS {
+            let oldest = initiators
+                .iter()
+                .min_by_key(|(_, stats)| stats.last_seen)
+                .map(|(mac, _)| *mac);
+            if let Some(oldest) = oldest {
+                initiators.remove(&oldest);
+            }
+        }
+        let stats = initiators.entry(mac).or_insert_with(|| InitiatorStats {
+            mac: format_mac(&mac),
+            ..Default::default()
+        });
+        stats.requests += 1;
+        stats.bytes_read += read;
+        stats.bytes_written += written;
+        stats.errors += failed as u64;
+        stats.last_seen = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+    }
+
+    /// Every initiator seen, most recently active first
+    pub fn stats(&self) -> Vec<InitiatorStats> {
+        let mut stats: Vec<InitiatorStats> =
+            self.initiators.lock().unwrap().values().cloned().collect();
+        stats.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then_with(|| a.mac.cmp(&b.mac)));
+        stats
+    }
+}
+
+/// MAC address as colon-separated hex
+pub(crate) fn format_mac(mac: &[u8; 6]) -> String {
+    mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":")
+}
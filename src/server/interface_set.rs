@@ -0,0 +1,348 @@
+//! Listening on several interfaces
+//!
+//! `interface = "any"` or a glob such as `en*` selects every matching
+//! interface that is up, is not loopback and is not enslaved to a bond or
+//! bridge (its master is listened on instead, so frames aren't seen
+//! twice). The set is rescanned every few seconds: interfaces that appear
+//! later, such as a bond brought up after boot, are picked up, and ones
+//! that disappear are dropped.
+//!
+//! Each interface gets a reader thread that forwards AoE frames into one
+//! queue. A broadcast destination is replaced by the receiving interface's
+//! MAC, so responses go out from the address the initiator will answer,
+//! and responses are sent on the interface their initiator was last heard
+//! on.
+
+use super::transport::{open_channel, FrameReceiver, FrameSender, FrameTransport};
+use crate::protocol::{AOE_ETHERTYPE, BROADCAST_MAC};
+use crate::shutdown;
+use pnet::datalink::{self, DataLinkSender, NetworkInterface};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time between scans for new or removed interfaces
+pub const RESCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// True if an `interface` setting selects a set of interfaces rather
+/// than naming one
+pub fn is_interface_pattern(name: &str) -> bool {
+    name == "any" || name.contains(['*', '?'])
+}
+
+/// True if `name` matches `pattern`: "any", or a glob where `*` matches
+/// any run of characters and `?` any one
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    fn glob(pattern: &[u8], name: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => name.is_empty(),
+            Some((b'*', rest)) => (0..=name.len()).any(|skip| glob(rest, &name[skip..])),
+            Some((&c, rest)) => match name.split_first() {
+                Some((&n, name_rest)) => (c == b'?' || c == n) && glob(rest, name_rest),
+                None => false,
+            },
+        }
+    }
+    pattern == "any" || glob(pattern.as_bytes(), name.as_bytes())
+}
+
+/// Names of the interfaces `pattern` currently selects
+pub fn matching_interfaces(pattern: &str) -> Vec<String> {
+    let mut names: Vec<String> = datalink::interfaces()
+        .into_iter()
+        .filter(|iface| selected(pattern, iface))
+        .map(|iface| iface.name)
+        .collect();
+    names.sort();
+    names
+}
+
+fn selected(pattern: &str, iface: &NetworkInterface) -> bool {
+    iface.is_up()
+        && !iface.is_loopback()
+        && iface.mac.is_some_and(|mac| mac.octets() != [0; 6])
+        && !Path::new("/sys/class/net").join(&iface.name).join("master").exists()
+        && matches_pattern(pattern, &iface.name)
+}
+
+/// Every interface matching a pattern, through pnet
+pub struct InterfaceSet {
+    pattern: String,
+    /// State of the channels handed out by the latest `open`
+    shared: Option<Arc<Shared>>,
+}
+
+impl InterfaceSet {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.to_string(),
+            shared: None,
+        }
+    }
+}
+
+/// An open interface
+struct Link {
+    /// Interface index, which changes if it is removed and recreated
+    index: u32,
+    tx: Box<dyn DataLinkSender>,
+    /// Tells the reader thread to stop
+    gone: Arc<AtomicBool>,
+}
+
+struct Shared {
+    links: Mutex<HashMap<String, Link>>,
+    /// Interface each initiator MAC was last heard on
+    routes: Mutex<HashMap<[u8; 6], String>>,
+    closed: AtomicBool,
+}
+
+impl FrameTransport for InterfaceSet {
+    type Sender = SetSender;
+    type Receiver = SetReceiver;
+
+    fn name(&self) -> String {
+        format!("interfaces matching {:?}", self.pattern)
+    }
+
+    /// Broadcasts are rewritten per interface instead
+    fn local_mac(&self) -> Option<[u8; 6]> {
+        None
+    }
+
+    fn open(&mut self) -> io::Result<(Self::Sender, Self::Receiver)> {
+        if let Some(old) = self.shared.take() {
+            old.closed.store(true, Ordering::SeqCst);
+        }
+        let shared = Arc::new(Shared {
+            links: Mutex::new(HashMap::new()),
+            routes: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+        });
+        self.shared = Some(Arc::clone(&shared));
+
+        let (frames_tx, frames) = mpsc::channel();
+        let mut receiver = SetReceiver {
+            pattern: self.pattern.clone(),
+            shared: Arc::clone(&shared),
+            frames,
+            frames_tx,
+            next_scan: Instant::now(),
+            failed: HashSet::new(),
+        };
+        receiver.scan();
+        if shared.links.lock().unwrap().is_empty() {
+            log::warn!(
+                "No interface matches {:?} yet; rescanning every {:?}",
+                self.pattern,
+                RESCAN_INTERVAL
+            );
+        }
+        Ok((SetSender { shared }, receiver))
+    }
+}
+
+/// Sends each response on its initiator's interface
+pub struct SetSender {
+    shared: Arc<Shared>,
+}
+
+impl FrameSender for SetSender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        let dst: [u8; 6] = frame
+            .get(..6)
+            .and_then(|dst| dst.try_into().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "short frame"))?;
+        let route = self.shared.routes.lock().unwrap().get(&dst).cloned();
+        let mut links = self.shared.links.lock().unwrap();
+        let link = route.and_then(|name| links.get_mut(&name)).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no interface leads to {}", super::initiators::format_mac(&dst)),
+            )
+        })?;
+        link.tx
+            .send_to(frame, None)
+            .unwrap_or_else(|| Err(io::Error::other("send failed: no result")))
+    }
+}
+
+/// Frames from every interface's reader, plus the periodic rescan
+pub struct SetReceiver {
+    pattern: String,
+    shared: Arc<Shared>,
+    frames: Receiver<Vec<u8>>,
+    frames_tx: Sender<Vec<u8>>,
+    next_scan: Instant,
+    /// Interfaces that could not be opened, warned about once
+    failed: HashSet<String>,
+}
+
+impl SetReceiver {
+    /// Open newly matching interfaces and drop vanished ones
+    fn scan(&mut self) {
+        self.next_scan = Instant::now() + RESCAN_INTERVAL;
+        let present: HashMap<String, NetworkInterface> = datalink::interfaces()
+            .into_iter()
+            .filter(|iface| selected(&self.pattern, iface))
+            .map(|iface| (iface.name.clone(), iface))
+            .collect();
+
+        let mut links = self.shared.links.lock().unwrap();
+        links.retain(|name, link| {
+            let keep = present.get(name).is_some_and(|iface| iface.index == link.index);
+            if !keep {
+                log::info!("Interface {} went away; no longer listening on it", name);
+                link.gone.store(true, Ordering::SeqCst);
+            }
+            keep
+        });
+        self.failed.retain(|name| present.contains_key(name));
+
+        for (name, iface) in present {
+            if links.contains_key(&name) {
+                continue;
+            }
+            let (tx, rx) = match open_channel(&iface) {
+                Ok(channel) => channel,
+                Err(e) => {
+                    if self.failed.insert(name.clone()) {
+                        log::warn!("Cannot listen on {}: {}", name, e);
+                    }
+                    continue;
+                }
+            };
+            let mac = iface.mac.map(|m| m.octets()).unwrap_or_default();
+            let gone = Arc::new(AtomicBool::new(false));
+            let reader = Reader {
+                name: name.clone(),
+                mac,
+                rx,
+                frames: self.frames_tx.clone(),
+                shared: Arc::clone(&self.shared),
+                gone: Arc::clone(&gone),
+            };
+            let spawned = thread::Builder::new()
+                .name(format!("aoe-rx-{}", name))
+                .spawn(move || reader.run());
+            if let Err(e) = spawned {
+                log::error!("Failed to start reader for {}: {}", name, e);
+                continue;
+            }
+            log::info!(
+                "AoE server listening on {} ({})",
+                name,
+                super::initiators::format_mac(&mac)
+            );
+            self.failed.remove(&name);
+            links.insert(
+                name,
+                Link {
+                    index: iface.index,
+                    tx,
+                    gone,
+                },
+            );
+        }
+    }
+}
+
+impl FrameReceiver for SetReceiver {
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if Instant::now() >= self.next_scan {
+            self.scan();
+        }
+        match self.frames.recv_timeout(shutdown::POLL_INTERVAL) {
+            Ok(frame) => Ok(Some(frame)),
+            // The receiver holds a sender, so the queue never disconnects
+            Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+impl Drop for SetReceiver {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Receiving side of one interface
+struct Reader {
+    name: String,
+    mac: [u8; 6],
+    rx: Box<dyn datalink::DataLinkReceiver>,
+    frames: Sender<Vec<u8>>,
+    shared: Arc<Shared>,
+    gone: Arc<AtomicBool>,
+}
+
+impl Reader {
+    fn run(mut self) {
+        while !self.shared.closed.load(Ordering::SeqCst) && !self.gone.load(Ordering::SeqCst) {
+            let packet = match self.rx.next() {
+                Ok(packet) => packet,
+                Err(e)
+                    if matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::Interrupted) =>
+                {
+                    continue
+                }
+                Err(e) => {
+                    log::warn!("Error receiving on {}: {}", self.name, e);
+                    break;
+                }
+            };
+            if packet.len() < 14 || packet[12..14] != AOE_ETHERTYPE.to_be_bytes() {
+                continue;
+            }
+            let mut frame = packet.to_vec();
+            if frame[..6] == BROADCAST_MAC {
+                frame[..6].copy_from_slice(&self.mac);
+            }
+            let initiator: [u8; 6] = frame[6..12].try_into().unwrap();
+            self.shared
+                .routes
+                .lock()
+                .unwrap()
+                .insert(initiator, self.name.clone());
+            if self.frames.send(frame).is_err() {
+                break;
+            }
+        }
+
+        // Let a later scan open the interface again
+        let mut links = self.shared.links.lock().unwrap();
+        if links
+            .get(&self.name)
+            .is_some_and(|link| Arc::ptr_eq(&link.gone, &self.gone))
+        {
+            links.remove(&self.name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interface_patterns() {
+        assert!(is_interface_pattern("any"));
+        assert!(is_interface_pattern("en*"));
+        assert!(!is_interface_pattern("eth0"));
+
+        assert!(matches_pattern("any", "bond0"));
+        assert!(matches_pattern("en*", "enp3s0"));
+        assert!(matches_pattern("en*", "en"));
+        assert!(!matches_pattern("en*", "eth0"));
+        assert!(matches_pattern("eth?", "eth1"));
+        assert!(!matches_pattern("eth?", "eth10"));
+        assert!(matches_pattern("*0", "bond0"));
+        assert!(matches_pattern("br-*-lan", "br-office-lan"));
+        assert!(matches_pattern("eth0", "eth0"));
+    }
+}
@@ -1,74 +1,284 @@
 //! Ethernet listener for AoE frames
 //!
-//! Uses pnet to receive and send raw Ethernet frames.
+//! Receives and sends raw Ethernet frames through a `FrameTransport`:
+//! pnet on a real interface, or an in-memory channel in tests.
+//!
+//! The receive loop only parses frames and dispatches them to per-target
+//! queues. Each target has its own worker thread that runs the command and
+//! sends the response through the shared sender, so a slow backend only
+//! stalls its own target.
+//!
+//! With `with_workers`, a fixed pool of workers (one per CPU by default)
+//! replaces the per-target ones and frames are sharded across it by a hash
+//! of initiator MAC and tag, as NIC RSS spreads flows over queues, so one
+//! busy target can use several cores. A retransmit has the same MAC and
+//! tag, so it queues behind the original and is answered from the
+//! retransmit cache. Transports with several receive queues (the packet
+//! ring's fanout) get a receive thread per queue and a sender per queue,
+//! which the workers share out.
+//!
+//! The receive loop ends once shutdown (or `Frontend::stop`) is requested;
+//! queued frames are then drained by the workers and every backend is
+//! flushed before the loop exits.
+//!
+//! With a trace file set, every AoE frame in either direction is also
+//! written to a pcap capture, and parsed headers are logged at trace level.
+//! Each frame a worker handles runs in a debug-level `aoe` span.
 
-use crate::protocol::{build_response, parse_frame, AoeError, AOE_ETHERTYPE, BROADCAST_MAC};
+use super::initiators::format_mac;
+use super::pcap::PcapWriter;
+use super::target::TargetAddr;
+use super::transport::{FrameReceiver, FrameSender, FrameTransport, PnetTransport};
+use crate::frontend::{parse_aoe_name, Frontend, FrontendError, FrontendResult};
+use crate::logging::next_request_id;
+use crate::protocol::{
+    build_response, parse_frame, AoeError, AoeFrame, AoePayload, AtaCommand, ResponseData,
+    AOE_ETHERTYPE, BROADCAST_MAC,
+};
 use crate::server::TargetManager;
-use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use crate::shutdown;
+use crate::storage::BlockStorage;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Shared frame sender
+type SharedSender = Arc<Mutex<Box<dyn FrameSender>>>;
 
 /// AoE network listener
-pub struct AoeListener {
-    interface: NetworkInterface,
-    tx: Arc<Mutex<Box<dyn DataLinkSender>>>,
-    rx: Box<dyn DataLinkReceiver>,
-    targets: Arc<Mutex<TargetManager>>,
+pub struct AoeListener<T: FrameTransport = PnetTransport> {
+    transport: T,
+    targets: Arc<TargetManager>,
+    pcap: Option<Arc<PcapWriter>>,
+    /// Size of the sharded worker pool, if one replaces per-target workers
+    workers: Option<usize>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
 }
 
 impl AoeListener {
     /// Create a new listener on the specified interface
     pub fn new(interface_name: &str, targets: TargetManager) -> Result<Self, AoeError> {
-        let interfaces = datalink::interfaces();
-        let interface = interfaces
-            .into_iter()
-            .find(|iface| iface.name == interface_name)
-            .ok_or_else(|| {
-                AoeError::BadArgument(format!("interface not found: {}", interface_name))
-            })?;
-
-        let (tx, rx) = match datalink::channel(&interface, Default::default()) {
-            Ok(Channel::Ethernet(tx, rx)) => (tx, rx),
-            Ok(_) => {
-                return Err(AoeError::BadArgument(
-                    "unsupported channel type".to_string(),
-                ))
-            }
-            Err(e) => {
-                return Err(AoeError::BadArgument(format!(
-                    "failed to open channel: {}",
-                    e
-                )))
-            }
+        let transport =
+            PnetTransport::new(interface_name).map_err(|e| AoeError::BadArgument(e.to_string()))?;
+        Ok(Self::with_transport(transport, targets))
+    }
+}
+
+impl<T: FrameTransport> AoeListener<T> {
+    /// Create a listener on any frame transport
+    pub fn with_transport(transport: T, targets: TargetManager) -> Self {
+        Self {
+            transport,
+            targets: Arc::new(targets),
+            pcap: None,
+            workers: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            thread: None,
+        }
+    }
+
+    /// Handle frames on a pool of `workers` threads sharded by initiator
+    /// MAC and tag, receiving on as many queues as the transport offers.
+    /// Zero means one per CPU.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        let workers = match workers {
+            0 => thread::available_parallelism().map_or(1, |n| n.get()),
+            n => n,
         };
+        self.workers = Some(workers);
+        self
+    }
 
-        Ok(Self {
-            interface,
-            tx: Arc::new(Mutex::new(tx)),
-            rx,
-            targets: Arc::new(Mutex::new(targets)),
-        })
+    /// Capture all received and sent AoE frames to a pcap file
+    pub fn with_pcap(mut self, pcap: PcapWriter) -> Self {
+        self.pcap = Some(Arc::new(pcap));
+        self
     }
 
-    /// Run the main receive loop
+    /// Run the main receive loop until shutdown is requested
     pub fn run(&mut self) -> Result<(), AoeError> {
-        log::info!(
-            "AoE server listening on {} ({})",
-            self.interface.name,
-            self.interface
-                .mac
-                .map(|m| m.to_string())
-                .unwrap_or_else(|| "no MAC".to_string())
-        );
+        self.spawn()?;
+        self.join();
+        Ok(())
+    }
+
+    /// Start the receive loop and target workers in the background
+    fn spawn(&mut self) -> Result<(), AoeError> {
+        if self.thread.is_some() {
+            return Err(AoeError::BadArgument("listener already running".to_string()));
+        }
+
+        log::info!("AoE server listening on {}", self.transport.name());
+
+        let channels = self
+            .transport
+            .open_queues(self.workers.unwrap_or(1))
+            .map_err(|e| AoeError::BadArgument(e.to_string()))?;
+        self.stop.store(false, Ordering::SeqCst);
+
+        let (senders, mut receivers): (Vec<SharedSender>, Vec<_>) = channels
+            .into_iter()
+            .map(|(tx, rx)| {
+                let tx: SharedSender = Arc::new(Mutex::new(Box::new(tx)));
+                (tx, Box::new(rx) as Box<dyn FrameReceiver>)
+            })
+            .unzip();
+        if receivers.len() > 1 {
+            log::info!("Receiving on {} queues", receivers.len());
+        }
+        let (dispatch, workers) = spawn_workers(
+            &self.targets,
+            &senders,
+            self.pcap.as_ref(),
+            self.workers,
+        )?;
+
+        let new_loop = |rx| ReceiveLoop {
+            rx,
+            local_mac: self.transport.local_mac(),
+            targets: Arc::clone(&self.targets),
+            dispatch: dispatch.clone(),
+            workers: Vec::new(),
+            peers: Vec::new(),
+            pcap: self.pcap.clone(),
+            stop: Arc::clone(&self.stop),
+        };
+        let mut receiver = new_loop(receivers.remove(0));
+        for (i, rx) in receivers.into_iter().enumerate() {
+            let peer = new_loop(rx);
+            let thread = thread::Builder::new()
+                .name(format!("aoe-rx-{}", i + 1))
+                .spawn(move || peer.receive())
+                .map_err(|e| AoeError::BadArgument(format!("failed to spawn receiver: {}", e)))?;
+            receiver.peers.push(thread);
+        }
+        receiver.workers = workers;
+
+        let thread = thread::Builder::new()
+            .name("aoe-rx".to_string())
+            .spawn(move || receiver.run())
+            .map_err(|e| AoeError::BadArgument(format!("failed to spawn receiver: {}", e)))?;
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    /// Wait for the receive loop (and its drain) to finish
+    fn join(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                log::error!("AoE receive loop panicked");
+            }
+        }
+    }
+
+    /// The targets this listener serves, shared with its workers.
+    /// While a clone is held, `attach` fails.
+    pub fn target_manager(&self) -> Arc<TargetManager> {
+        Arc::clone(&self.targets)
+    }
+
+    /// Get the local MAC address
+    pub fn local_mac(&self) -> Option<[u8; 6]> {
+        self.transport.local_mac()
+    }
+}
+
+impl<T: FrameTransport> Frontend for AoeListener<T> {
+    fn protocol(&self) -> &'static str {
+        "aoe"
+    }
+
+    fn attach(&mut self, name: &str, storage: Box<dyn BlockStorage>) -> FrontendResult<()> {
+        let (shelf, slot) = parse_aoe_name(name)?;
+        // Workers hold references to the targets while running
+        let targets = Arc::get_mut(&mut self.targets).ok_or(FrontendError::AlreadyRunning)?;
+        if targets.addrs().contains(&TargetAddr::new(shelf, slot)) {
+            return Err(FrontendError::DuplicateTarget(name.to_string()));
+        }
+        targets.add_target(shelf, slot, storage, String::new());
+        Ok(())
+    }
+
+    fn targets(&self) -> Vec<String> {
+        let mut addrs = self.targets.addrs();
+        addrs.sort_by_key(|addr| (addr.shelf, addr.slot));
+        addrs
+            .into_iter()
+            .map(|addr| format!("e{}.{}", addr.shelf, addr.slot))
+            .collect()
+    }
+
+    fn start(&mut self) -> FrontendResult<()> {
+        if self.thread.is_some() {
+            return Err(FrontendError::AlreadyRunning);
+        }
+        self.spawn().map_err(|e| FrontendError::Protocol(e.to_string()))
+    }
+
+    fn stop(&mut self) -> FrontendResult<()> {
+        if self.thread.is_none() {
+            return Err(FrontendError::NotRunning);
+        }
+        self.stop.store(true, Ordering::SeqCst);
+        self.join();
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+}
+
+/// Where received frames are queued for the workers
+#[derive(Clone)]
+enum Dispatch {
+    /// One queue per target
+    PerTarget(HashMap<TargetAddr, SyncSender<AoeFrame>>),
+    /// Queues of the sharded pool, picked by initiator MAC and tag
+    Sharded(Vec<SyncSender<(TargetAddr, AoeFrame)>>),
+}
+
+/// Receive loop state, owned by a receiver thread
+struct ReceiveLoop {
+    rx: Box<dyn FrameReceiver>,
+    /// Answers to broadcasts are sent from this address
+    local_mac: Option<[u8; 6]>,
+    targets: Arc<TargetManager>,
+    dispatch: Dispatch,
+    /// Workers, joined by the first receive loop once the others are done
+    workers: Vec<JoinHandle<()>>,
+    /// Receive loops of the transport's other queues
+    peers: Vec<JoinHandle<()>>,
+    pcap: Option<Arc<PcapWriter>>,
+    stop: Arc<AtomicBool>,
+}
+
+impl ReceiveLoop {
+    /// Receive frames until shutdown or stop is requested, then drain
+    fn run(mut self) {
+        self.receive_until_stopped();
+        self.drain();
+    }
+
+    /// Receive loop of an additional queue; the first loop drains
+    fn receive(mut self) {
+        self.receive_until_stopped();
+    }
 
-        loop {
-            match self.rx.next() {
-                Ok(packet) => {
-                    // Copy packet to owned buffer to avoid borrow issues
-                    let packet = packet.to_vec();
+    fn receive_until_stopped(&mut self) {
+        while !shutdown::requested() && !self.stop.load(Ordering::SeqCst) {
+            match self.rx.recv() {
+                Ok(Some(packet)) => {
                     if let Err(e) = self.handle_packet(&packet) {
                         log::warn!("Error handling packet: {}", e);
                     }
                 }
+                Ok(None) => {}
                 Err(e) => {
                     log::error!("Error receiving packet: {}", e);
                 }
@@ -76,7 +286,27 @@ impl AoeListener {
         }
     }
 
-    /// Handle a received packet
+    /// Let workers finish queued frames, then flush every backend
+    fn drain(&mut self) {
+        log::info!("Shutting down: draining in-flight requests");
+
+        for peer in self.peers.drain(..) {
+            if peer.join().is_err() {
+                log::error!("AoE receive loop panicked during shutdown");
+            }
+        }
+        // Closing the queues ends each worker once its backlog is handled
+        self.dispatch = Dispatch::PerTarget(HashMap::new());
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                log::error!("AoE worker panicked during shutdown");
+            }
+        }
+
+        self.targets.flush_all();
+    }
+
+    /// Parse a received packet and dispatch it to the addressed targets
     fn handle_packet(&self, packet: &[u8]) -> Result<(), AoeError> {
         // Check minimum size and EtherType
         if packet.len() < 14 {
@@ -88,8 +318,12 @@ impl AoeListener {
             return Ok(()); // Not AoE, ignore
         }
 
+        if let Some(pcap) = &self.pcap {
+            pcap.record(packet);
+        }
+
         // Parse the frame
-        let frame = parse_frame(packet)?;
+        let mut frame = parse_frame(packet)?;
 
         // Skip responses
         if frame.header.flags.response {
@@ -103,39 +337,328 @@ impl AoeListener {
             frame.header.command,
             frame.header.tag
         );
+        trace_request(&frame);
 
-        // Check if we have a target for this address
-        let mut targets = self.targets.lock().unwrap();
-        let responses = targets.handle_frame(&frame)?;
-
-        // Send responses
-        let mut tx = self.tx.lock().unwrap();
-        for (target_addr, response_data) in responses {
-            let response_frame = build_response(&frame, response_data, target_addr.shelf, target_addr.slot);
-            match tx.send_to(&response_frame, None) {
-                Some(Ok(())) => {
-                    log::debug!("Sent response successfully");
-                }
-                Some(Err(e)) => {
-                    log::warn!("Error sending response: {}", e);
+        // Responses go out from the original destination address, which
+        // for a broadcast query must be ours
+        if is_broadcast_mac(&frame.header.dst_mac) {
+            if let Some(mac) = self.local_mac {
+                frame.header.dst_mac = mac;
+            }
+        }
+
+        for addr in self.targets.matching_targets(&frame) {
+            let queued = match &self.dispatch {
+                Dispatch::PerTarget(queues) => match queues.get(&addr) {
+                    Some(queue) => queue.try_send(frame.clone()).map_err(discard),
+                    None => continue,
+                },
+                Dispatch::Sharded(shards) => shards[shard_of(&frame, shards.len())]
+                    .try_send((addr, frame.clone()))
+                    .map_err(discard),
+            };
+
+            match queued {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    // Initiator exceeded the advertised buffer count; it will retransmit
+                    log::debug!(
+                        "Queue full for e{}.{}, dropping tag {}",
+                        addr.shelf,
+                        addr.slot,
+                        frame.header.tag
+                    );
                 }
-                None => {
-                    log::warn!("Failed to send response: no result");
+                Err(TrySendError::Disconnected(_)) => {
+                    log::error!("Worker for e{}.{} has exited", addr.shelf, addr.slot);
                 }
             }
         }
 
         Ok(())
     }
+}
 
-    /// Get the local MAC address
-    pub fn local_mac(&self) -> Option<[u8; 6]> {
-        self.interface.mac.map(|m| m.octets())
+/// Start the workers: one per target, or a pool of `shards` sharing the
+/// senders out between them
+fn spawn_workers(
+    targets: &Arc<TargetManager>,
+    senders: &[SharedSender],
+    pcap: Option<&Arc<PcapWriter>>,
+    shards: Option<usize>,
+) -> Result<(Dispatch, Vec<JoinHandle<()>>), AoeError> {
+    let spawn_failed = |e: std::io::Error| {
+        AoeError::BadArgument(format!("failed to spawn worker: {}", e))
+    };
+    let mut workers = Vec::new();
+
+    let Some(shards) = shards else {
+        let mut queues = HashMap::new();
+        for addr in targets.addrs() {
+            let depth = targets.target(addr).map_or(1, |t| t.buffer_count);
+            let (queue_tx, queue_rx) = mpsc::sync_channel(depth as usize);
+            let targets = Arc::clone(targets);
+            let tx = Arc::clone(&senders[0]);
+            let pcap = pcap.cloned();
+
+            let worker = thread::Builder::new()
+                .name(format!("aoe-e{}.{}", addr.shelf, addr.slot))
+                .spawn(move || worker_loop(addr, queue_rx, targets, tx, pcap))
+                .map_err(spawn_failed)?;
+            queues.insert(addr, queue_tx);
+            workers.push(worker);
+        }
+        return Ok((Dispatch::PerTarget(queues), workers));
+    };
+
+    // Room for every request all targets advertise, however they hash
+    let depth = targets
+        .addrs()
+        .into_iter()
+        .filter_map(|addr| targets.target(addr))
+        .map(|target| target.buffer_count as usize)
+        .sum::<usize>()
+        .max(1);
+    let mut queues = Vec::new();
+    for shard in 0..shards {
+        let (queue_tx, queue_rx) = mpsc::sync_channel(depth);
+        let targets = Arc::clone(targets);
+        let tx = Arc::clone(&senders[shard % senders.len()]);
+        let pcap = pcap.cloned();
+
+        let worker = thread::Builder::new()
+            .name(format!("aoe-worker-{}", shard))
+            .spawn(move || shard_loop(queue_rx, targets, tx, pcap))
+            .map_err(spawn_failed)?;
+        queues.push(queue_tx);
+        workers.push(worker);
+    }
+    log::info!("Handling requests on {} workers", shards);
+    Ok((Dispatch::Sharded(queues), workers))
+}
+
+/// Worker queue a frame is sharded to
+fn shard_of(frame: &AoeFrame, shards: usize) -> usize {
+    let mut key = [0u8; 10];
+    key[..6].copy_from_slice(&frame.header.src_mac);
+    key[6..].copy_from_slice(&frame.header.tag.to_be_bytes());
+    (xxh3_64(&key) % shards as u64) as usize
+}
+
+/// A failed queue send, without the frame it carried
+fn discard<T>(e: TrySendError<T>) -> TrySendError<()> {
+    match e {
+        TrySendError::Full(_) => TrySendError::Full(()),
+        TrySendError::Disconnected(_) => TrySendError::Disconnected(()),
+    }
+}
+
+/// Process queued frames for a single target
+fn worker_loop(
+    addr: TargetAddr,
+    queue: Receiver<AoeFrame>,
+    targets: Arc<TargetManager>,
+    tx: SharedSender,
+    pcap: Option<Arc<PcapWriter>>,
+) {
+    for frame in queue {
+        handle_queued(addr, &frame, &targets, &tx, pcap.as_deref());
+    }
+}
+
+/// Process the frames sharded to one worker of the pool
+fn shard_loop(
+    queue: Receiver<(TargetAddr, AoeFrame)>,
+    targets: Arc<TargetManager>,
+    tx: SharedSender,
+    pcap: Option<Arc<PcapWriter>>,
+) {
+    for (addr, frame) in queue {
+        handle_queued(addr, &frame, &targets, &tx, pcap.as_deref());
+    }
+}
+
+/// Run one queued frame and send the response
+fn handle_queued(
+    addr: TargetAddr,
+    frame: &AoeFrame,
+    targets: &TargetManager,
+    tx: &SharedSender,
+    pcap: Option<&PcapWriter>,
+) {
+    let span = tracing::debug_span!(
+        "aoe",
+        req = next_request_id(),
+        target = %addr.name(),
+        initiator = %format_mac(&frame.header.src_mac),
+        tag = frame.header.tag,
+        op = %request_op(frame),
+        lba = request_lba(frame),
+    );
+    let _entered = span.enter();
+    let started = Instant::now();
+
+    match targets.handle_target_frame(frame, addr) {
+        Ok(response) => send_response(tx, pcap, frame, addr, response),
+        Err(e) => log::warn!("Error handling packet: {}", e),
+    }
+    tracing::debug!(latency_us = started.elapsed().as_micros() as u64, "request done");
+}
+
+/// ATA command name (or raw opcode) or "config", for request spans
+fn request_op(frame: &AoeFrame) -> String {
+    match &frame.payload {
+        AoePayload::Ata { header, .. } => match AtaCommand::try_from(header.cmd_status) {
+            Ok(cmd) => cmd.to_string(),
+            Err(_) => format!("{:#04x}", header.cmd_status),
+        },
+        AoePayload::Config(_) => "config".to_string(),
+    }
+}
+
+/// Request LBA without the device/head bits that share its top byte
+fn request_lba(frame: &AoeFrame) -> Option<u64> {
+    match &frame.payload {
+        AoePayload::Ata { header, .. } if header.flags.extended => Some(header.lba48()),
+        AoePayload::Ata { header, .. } => Some(header.lba28() as u64),
+        AoePayload::Config(_) => None,
+    }
+}
+
+/// Build and send a response frame through the shared sender
+fn send_response(
+    tx: &SharedSender,
+    pcap: Option<&PcapWriter>,
+    frame: &AoeFrame,
+    addr: TargetAddr,
+    response: ResponseData,
+) {
+    trace_response(frame, addr, &response);
+    let response_frame = build_response(frame, response, addr.shelf, addr.slot);
+    if let Some(pcap) = pcap {
+        pcap.record(&response_frame);
+    }
+
+    let mut tx = tx.lock().unwrap();
+    match tx.send(&response_frame) {
+        Ok(()) => log::debug!("Sent response successfully"),
+        Err(e) => log::warn!("Error sending response: {}", e),
+    }
+}
+
+/// Log a parsed request's headers at trace level
+fn trace_request(frame: &AoeFrame) {
+    if !log::log_enabled!(log::Level::Trace) {
+        return;
+    }
+    let h = &frame.header;
+    match &frame.payload {
+        AoePayload::Ata { header, data } => log::trace!(
+            "rx tag={:#010x} src={:02x?} e{}.{} ATA cmd={:#04x} feature={:#04x} count={} lba={} flags={:?} data={}B",
+            h.tag, h.src_mac, h.shelf, h.slot, header.cmd_status, header.err_feature,
+            header.sector_count, header.lba, header.flags, data.len()
+        ),
+        AoePayload::Config(config) => log::trace!(
+            "rx tag={:#010x} src={:02x?} e{}.{} CONFIG {:?}",
+            h.tag, h.src_mac, h.shelf, h.slot, config
+        ),
+    }
+}
+
+/// Log a response summary at trace level
+fn trace_response(frame: &AoeFrame, addr: TargetAddr, response: &ResponseData) {
+    if !log::log_enabled!(log::Level::Trace) {
+        return;
+    }
+    let tag = frame.header.tag;
+    match response {
+        ResponseData::Ata(ata) => log::trace!(
+            "tx tag={:#010x} e{}.{} ATA status={:#04x} error={:#04x} data={}B",
+            tag, addr.shelf, addr.slot, ata.status, ata.error,
+            ata.data.as_ref().map_or(0, |d| d.len())
+        ),
+        ResponseData::Config(config) => log::trace!(
+            "tx tag={:#010x} e{}.{} CONFIG buffers={} sectors={} config_string={:?}",
+            tag, addr.shelf, addr.slot, config.buffer_count, config.sector_count,
+            String::from_utf8_lossy(&config.config_string)
+        ),
+        ResponseData::Error { code } => log::trace!(
+            "tx tag={:#010x} e{}.{} error={}",
+            tag, addr.shelf, addr.slot, code
+        ),
     }
 }
 
 /// Check if a MAC address is broadcast
-#[allow(dead_code)]
 pub fn is_broadcast_mac(mac: &[u8; 6]) -> bool {
     mac == &BROADCAST_MAC
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::AoeClient;
+    use crate::server::MemoryTransport;
+    use crate::storage::MemBackend;
+    use std::time::Duration;
+
+    #[test]
+    fn test_serves_over_memory_transport() {
+        let (transport, peer) = MemoryTransport::new();
+        let mut listener = AoeListener::with_transport(transport, TargetManager::new());
+        listener.attach("e1.0", Box::new(MemBackend::new(1024 * 1024))).unwrap();
+        listener.attach("e1.1", Box::new(MemBackend::new(1024 * 1024))).unwrap();
+        listener.start().unwrap();
+
+        let mut client = AoeClient::new(peer);
+        let found = client.discover(Duration::from_millis(200)).unwrap();
+        assert_eq!(found.len(), 2);
+        // Broadcast queries are answered from the listener's own address
+        assert!(found.iter().all(|t| t.mac == MemoryTransport::LISTENER_MAC));
+
+        let disk = client.open(found[1].clone()).unwrap();
+        let data = vec![0x5A; 16 * 512];
+        client.write(&disk, 100, &data).unwrap();
+        assert_eq!(client.read(&disk, 100, 16).unwrap(), data);
+
+        // Stopping flushes and a restart reopens the transport
+        listener.stop().unwrap();
+        listener.start().unwrap();
+        assert_eq!(client.read(&disk, 100, 16).unwrap(), data);
+        listener.stop().unwrap();
+    }
+
+    #[test]
+    fn test_sharded_workers() {
+        let (transport, peer) = MemoryTransport::new();
+        let mut listener =
+            AoeListener::with_transport(transport, TargetManager::new()).with_workers(4);
+        listener.attach("e1.0", Box::new(MemBackend::new(1024 * 1024))).unwrap();
+        listener.start().unwrap();
+
+        let mut client = AoeClient::new(peer);
+        let found = client.discover(Duration::from_millis(200)).unwrap();
+        let disk = client.open(found[0].clone()).unwrap();
+        for lba in 0..8u64 {
+            client.write(&disk, lba * 2, &[lba as u8 + 1; 1024]).unwrap();
+        }
+        for lba in 0..8u64 {
+            assert_eq!(client.read(&disk, lba * 2, 2).unwrap(), vec![lba as u8 + 1; 1024]);
+        }
+        listener.stop().unwrap();
+
+        // Successive tags from one initiator spread over the pool
+        let frame = |tag| {
+            let mut raw = vec![0u8; 36];
+            raw[6..12].copy_from_slice(&MemoryTransport::PEER_MAC);
+            raw[12..14].copy_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+            raw[14] = 0x10;
+            raw[20..24].copy_from_slice(&u32::to_be_bytes(tag));
+            parse_frame(&raw).unwrap()
+        };
+        let shards: std::collections::HashSet<usize> =
+            (0..64).map(|tag| shard_of(&frame(tag), 4)).collect();
+        assert_eq!(shards.len(), 4);
+        assert_eq!(shard_of(&frame(7), 4), shard_of(&frame(7), 4));
+    }
+}
@@ -2,8 +2,29 @@
 //!
 //! Contains the network listener and target manager.
 
+pub mod api;
+#[cfg(test)]
+mod e2e_tests;
+mod initiators;
+pub mod interface_set;
 mod listener;
+pub mod pcap;
+mod retransmit;
+#[cfg(target_os = "linux")]
+pub mod ring;
+pub mod state;
 mod target;
+mod transfer;
+pub mod transport;
 
+pub(crate) use initiators::format_mac;
+pub use initiators::{InitiatorStats, InitiatorTable};
+pub use interface_set::InterfaceSet;
 pub use listener::AoeListener;
-pub use target::TargetManager;
+#[cfg(target_os = "linux")]
+pub use ring::RingTransport;
+pub use target::{
+    max_sectors_per_frame, Target, TargetAddr, TargetManager, BUFFER_COUNT, DEFAULT_MTU,
+};
+pub use transfer::{Transfer, TransferKind, TransferState, TransferStatus};
+pub use transport::{FrameTransport, MemoryTransport, PnetTransport};
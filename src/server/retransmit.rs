@@ -0,0 +1,115 @@
+//! Retransmit cache
+//!
+//! Initiators retransmit ATA requests they think were lost, reusing the
+//! tag. Remembering the last few responses per initiator MAC lets a
+//! retransmit be answered without repeating the storage I/O, so a
+//! duplicated WRITE is never applied twice.
+
+use crate::protocol::{AtaHeader, AtaResponse};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Responses remembered per initiator (twice the advertised buffer count)
+const ENTRIES_PER_INITIATOR: usize = 32;
+
+/// Initiators tracked before the cache starts forgetting them
+const MAX_INITIATORS: usize = 256;
+
+struct Entry {
+    tag: u32,
+    cmd: u8,
+    lba: u64,
+    sector_count: u8,
+    response: AtaResponse,
+}
+
+impl Entry {
+    /// A tag can be reused for a new request, so the command must match too
+    fn matches(&self, tag: u32, header: &AtaHeader) -> bool {
+        self.tag == tag
+            && self.cmd == header.cmd_status
+            && self.lba == header.lba
+            && self.sector_count == header.sector_count
+    }
+}
+
+/// Recent ATA responses keyed by initiator MAC and tag
+#[derive(Default)]
+pub struct RetransmitCache {
+    initiators: Mutex<HashMap<[u8; 6], VecDeque<Entry>>>,
+}
+
+impl RetransmitCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cached response for a retransmitted request, if any
+    pub fn get(&self, mac: &[u8; 6], tag: u32, header: &AtaHeader) -> Option<AtaResponse> {
+        let initiators = self.initiators.lock().unwrap();
+        initiators
+            .get(mac)?
+            .iter()
+            .rev()
+            .find(|entry| entry.matches(tag, header))
+            .map(|entry| entry.response.clone())
+    }
+
+    /// Remember the response sent for a request
+    pub fn insert(&self, mac: [u8; 6], tag: u32, header: &AtaHeader, response: &AtaResponse) {
+        let mut initiators = self.initiators.lock().unwrap();
+        if !initiators.contains_key(&mac) && initiators.len() >= MAX_INITIATORS {
+            if let Some(evict) = initiators.keys().next().copied() {
+                initiators.remove(&evict);
+            }
+        }
+
+        let entries = initiators.entry(mac).or_default();
+        if entries.len() >= ENTRIES_PER_INITIATOR {
+            entries.pop_front();
+        }
+        entries.push_back(Entry {
+            tag,
+            cmd: header.cmd_status,
+            lba: header.lba,
+            sector_count: header.sector_count,
+            response: response.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::AtaFlags;
+
+    fn header(lba: u64) -> AtaHeader {
+        AtaHeader {
+            flags: AtaFlags::default(),
+            err_feature: 0,
+            sector_count: 1,
+            cmd_status: 0x24,
+            lba,
+        }
+    }
+
+    #[test]
+    fn test_retransmit_hit_and_eviction() {
+        let cache = RetransmitCache::new();
+        let mac = [0x02, 0, 0, 0, 0, 1];
+        let response = AtaResponse::success_with_data(vec![7; 512], 1);
+
+        cache.insert(mac, 42, &header(5), &response);
+        assert_eq!(cache.get(&mac, 42, &header(5)).unwrap().data, response.data);
+
+        // Same tag for a different request, or from another initiator
+        assert!(cache.get(&mac, 42, &header(6)).is_none());
+        assert!(cache.get(&[0x02, 0, 0, 0, 0, 2], 42, &header(5)).is_none());
+
+        // Oldest entries fall out
+        for tag in 0..ENTRIES_PER_INITIATOR as u32 {
+            cache.insert(mac, 100 + tag, &header(5), &response);
+        }
+        assert!(cache.get(&mac, 42, &header(5)).is_none());
+    }
+}
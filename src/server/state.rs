@@ -0,0 +1,31 @@
+//! Persisted target state
+//!
+//! Config strings set by initiators (Config Set/ForceSet) are saved to a
+//! small JSON file keyed by target name, e.g. `{"e1.0": "host-a"}`, and
+//! override the configured strings when the server starts again.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Config strings by target name
+pub type ConfigStrings = BTreeMap<String, String>;
+
+/// Load saved config strings; a missing file holds none
+pub fn load(path: &Path) -> io::Result<ConfigStrings> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(ConfigStrings::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Replace the saved config strings
+pub fn save(path: &Path, strings: &ConfigStrings) -> io::Result<()> {
+    let content = serde_json::to_string_pretty(strings)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)
+}
@@ -3,12 +3,38 @@
 //! Maps shelf/slot addresses to storage backends and handles frame routing.
 
 use crate::protocol::{
-    handle_ata_command, AoeCommand, AoeError, AoeFrame, AoePayload,
-    ConfigResponse, ResponseData, BROADCAST_SHELF, BROADCAST_SLOT,
-    MAX_SECTORS_STANDARD,
+    handle_ata_command, Addressing, AoeCommand, AoeError, AoeFrame, AoeHeader, AoePayload, AtaCommand,
+    AtaHeader, ConfigResponse, ResponseData, SmartCounters, BROADCAST_SHELF, BROADCAST_SLOT,
+    SECTOR_SIZE,
+};
+use super::initiators::InitiatorTable;
+use super::retransmit::RetransmitCache;
+use super::state;
+use super::transfer::{Transfer, TransferKind, TransferStatus};
+use crate::blob::{
+    BlobError, BlobMigration, BlobResult, BlobStoreSpec, MigratingBlobStore, MigrationStatus,
+};
+use crate::qos::{QosLimits, RateLimiter};
+use crate::storage::{
+    BlockStorage, DedupStats, HealthCheck, TimedStorage, DEFAULT_HEALTH_INTERVAL,
+    DEFAULT_SLOW_THRESHOLD,
 };
-use crate::storage::BlockStorage;
 use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+/// Outstanding requests each target can queue by default (advertised
+/// buffer count, as vblade)
+pub const BUFFER_COUNT: u16 = 16;
+
+/// Interface MTU assumed unless told otherwise
+pub const DEFAULT_MTU: u32 = 1500;
+
+/// Longest config string an initiator may set
+pub const MAX_CONFIG_STRING: usize = 1024;
 
 /// Target address (shelf, slot)
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -21,6 +47,11 @@ impl TargetAddr {
     pub fn new(shelf: u16, slot: u8) -> Self {
         Self { shelf, slot }
     }
+
+    /// Name in the usual `e<shelf>.<slot>` form
+    pub fn name(&self) -> String {
+        format!("e{}.{}", self.shelf, self.slot)
+    }
 }
 
 /// A storage target
@@ -28,13 +59,62 @@ pub struct Target {
     #[allow(dead_code)]
     pub addr: TargetAddr,
     pub storage: Box<dyn BlockStorage>,
-    pub config_string: String,
+    /// Set from the config file, or by initiators with Config Set
+    config_string: RwLock<String>,
+    /// The config string was set by an initiator, now or before a restart,
+    /// so it is kept in the state file
+    config_string_set: AtomicBool,
+    /// I/O throttling, if the target has QoS limits
+    pub limiter: Option<RateLimiter>,
+    /// Health counters reported through SMART
+    pub smart: SmartCounters,
+    /// Recent ATA responses, replayed for retransmitted requests
+    pub recent: RetransmitCache,
+    /// Backend type from the config, if known
+    pub backend: Option<&'static str>,
+    /// Requests the target queues, advertised to initiators
+    pub buffer_count: u16,
+    /// Sectors per request advertised to initiators, instead of as many
+    /// as fit in the MTU
+    pub max_sectors: Option<u8>,
+    /// LBA48, or LBA28/CHS for old initiators
+    pub addressing: Addressing,
+    /// Backend health; ATA requests are refused while it is unhealthy
+    pub health: HealthCheck,
+    /// Traffic per initiator MAC
+    pub initiators: InitiatorTable,
+    /// Blob store of a CAS target, which can be migrated while in use
+    pub blob_store: Option<MigratingBlobStore>,
+}
+
+impl Target {
+    pub fn config_string(&self) -> String {
+        self.config_string.read().unwrap().clone()
+    }
 }
 
 /// Manages multiple storage targets
+///
+/// The set of targets is fixed once frames are being handled and storage
+/// backends are internally synchronized, so frames can be handled
+/// concurrently through `&self`.
 pub struct TargetManager {
     targets: HashMap<TargetAddr, Target>,
     firmware_version: u16,
+    /// MTU of the interface, bounding the sectors per request
+    mtu: u32,
+    /// Where config strings set by initiators are saved
+    state_file: Option<PathBuf>,
+    /// Serializes saves of the state file
+    state_lock: Mutex<()>,
+    /// Time between backend health checks for targets added later
+    health_interval: Duration,
+    /// Storage operations slower than this are logged, for targets added later
+    slow_threshold: Duration,
+    /// The latest blob store migration
+    migration: Mutex<Option<Arc<BlobMigration>>>,
+    /// The latest transfer of each target
+    transfers: Mutex<HashMap<TargetAddr, Arc<Transfer>>>,
 }
 
 impl TargetManager {
@@ -43,6 +123,13 @@ impl TargetManager {
         Self {
             targets: HashMap::new(),
             firmware_version: 0x4019, // Match vblade's firmware version
+            mtu: DEFAULT_MTU,
+            state_file: None,
+            state_lock: Mutex::new(()),
+            health_interval: DEFAULT_HEALTH_INTERVAL,
+            slow_threshold: DEFAULT_SLOW_THRESHOLD,
+            migration: Mutex::new(None),
+            transfers: Mutex::new(HashMap::new()),
         }
     }
 
@@ -55,29 +142,201 @@ impl TargetManager {
         config_string: String,
     ) {
         let addr = TargetAddr::new(shelf, slot);
+        if storage.info().sector_size > SECTOR_SIZE as u32 {
+            log::warn!(
+                "Target e{}.{} uses {}-byte sectors; initiators need jumbo frames",
+                shelf,
+                slot,
+                storage.info().sector_size
+            );
+        }
+        let storage = TimedStorage::new(addr.name(), storage)
+            .with_slow_threshold(self.slow_threshold);
         self.targets.insert(
             addr,
             Target {
                 addr,
-                storage,
-                config_string,
+                storage: Box::new(storage),
+                config_string: RwLock::new(config_string),
+                config_string_set: AtomicBool::new(false),
+                limiter: None,
+                smart: SmartCounters::new(),
+                recent: RetransmitCache::new(),
+                backend: None,
+                buffer_count: BUFFER_COUNT,
+                max_sectors: None,
+                addressing: Addressing::default(),
+                health: HealthCheck::new(addr.name(), self.health_interval),
+                initiators: InitiatorTable::new(),
+                blob_store: None,
             },
         );
         log::info!("Added target at shelf {} slot {}", shelf, slot);
     }
 
+    /// Apply QoS limits to a target
+    pub fn set_qos(&mut self, shelf: u16, slot: u8, limits: &QosLimits) {
+        if let Some(target) = self.targets.get_mut(&TargetAddr::new(shelf, slot)) {
+            target.limiter = (!limits.is_unlimited()).then(|| RateLimiter::new(limits));
+        }
+    }
+
+    /// Record which kind of backend a target uses, for reporting
+    pub fn set_backend(&mut self, shelf: u16, slot: u8, backend: &'static str) {
+        if let Some(target) = self.targets.get_mut(&TargetAddr::new(shelf, slot)) {
+            target.backend = Some(backend);
+        }
+    }
+
+    /// Give a CAS target's blob store, so it can be migrated
+    pub fn set_blob_store(&mut self, shelf: u16, slot: u8, store: MigratingBlobStore) {
+        if let Some(target) = self.targets.get_mut(&TargetAddr::new(shelf, slot)) {
+            target.blob_store = Some(store);
+        }
+    }
+
+    /// Start moving the blobs of every target using `from` to `to`
+    pub fn migrate_blobs(
+        &self,
+        from: &BlobStoreSpec,
+        to: BlobStoreSpec,
+    ) -> BlobResult<MigrationStatus> {
+        let mut migration = self.migration.lock().unwrap();
+        if migration.as_ref().is_some_and(|m| m.status().is_running()) {
+            return Err(BlobError::Backend(
+                "a blob store migration is already running".to_string(),
+            ));
+        }
+        let mut addrs = self.addrs();
+        addrs.sort_by_key(|addr| (addr.shelf, addr.slot));
+        let targets: Vec<_> = addrs
+            .into_iter()
+            .filter_map(|addr| {
+                let store = self.targets[&addr].blob_store.as_ref()?;
+                (store.spec() == *from).then(|| (addr.name(), store.clone()))
+            })
+            .collect();
+        if targets.is_empty() {
+            return Err(BlobError::Backend(format!("no target uses blob store {}", from)));
+        }
+        let started = BlobMigration::start(targets, to)?;
+        let status = started.status();
+        *migration = Some(started);
+        Ok(status)
+    }
+
+    /// Progress of the latest blob store migration, if one has run
+    pub fn blob_migration(&self) -> Option<MigrationStatus> {
+        self.migration.lock().unwrap().as_ref().map(|m| m.status())
+    }
+
+    /// Start copying a target to or from an image or NBD export, unless a
+    /// transfer of it is already running
+    pub fn start_transfer(
+        self: &Arc<Self>,
+        addr: TargetAddr,
+        kind: TransferKind,
+    ) -> io::Result<TransferStatus> {
+        let mut transfers = self.transfers.lock().unwrap();
+        if transfers
+            .get(&addr)
+            .is_some_and(|t| t.status().is_running())
+        {
+            return Err(io::Error::other(format!(
+                "a transfer of {} is already running",
+                addr.name()
+            )));
+        }
+        let started = Transfer::start(Arc::clone(self), addr, kind)?;
+        let status = started.status();
+        transfers.insert(addr, started);
+        Ok(status)
+    }
+
+    /// Progress of a target's latest transfer, if one has run
+    pub fn transfer(&self, addr: TargetAddr) -> Option<TransferStatus> {
+        self.transfers
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .map(|t| t.status())
+    }
+
+    /// Set the interface MTU that sector counts are derived from
+    pub fn set_mtu(&mut self, mtu: u32) {
+        self.mtu = mtu;
+    }
+
+    /// Override the queue depth and sectors per request a target advertises
+    pub fn set_limits(
+        &mut self,
+        shelf: u16,
+        slot: u8,
+        buffer_count: Option<u16>,
+        max_sectors: Option<u8>,
+    ) {
+        if let Some(target) = self.targets.get_mut(&TargetAddr::new(shelf, slot)) {
+            target.buffer_count = buffer_count.unwrap_or(BUFFER_COUNT);
+            target.max_sectors = max_sectors;
+        }
+    }
+
+    /// Choose the addressing a target offers initiators
+    pub fn set_addressing(&mut self, shelf: u16, slot: u8, addressing: Addressing) {
+        if let Some(target) = self.targets.get_mut(&TargetAddr::new(shelf, slot)) {
+            target.addressing = addressing;
+        }
+    }
+
+    /// Set how often each target's backend health is checked; zero
+    /// disables the checks
+    pub fn set_health_interval(&mut self, interval: Duration) {
+        self.health_interval = interval;
+        for target in self.targets.values_mut() {
+            target.health.set_interval(interval);
+        }
+    }
+
+    /// Log storage operations of targets added from now on that take at
+    /// least `threshold`; zero disables slow operation logging
+    pub fn set_slow_threshold(&mut self, threshold: Duration) {
+        self.slow_threshold = threshold;
+    }
+
+    /// Sectors per request a target advertises
+    pub fn sector_count(&self, target: &Target) -> u8 {
+        let sector_size = target.storage.info().sector_size;
+        target
+            .max_sectors
+            .unwrap_or_else(|| max_sectors_per_frame(self.mtu, sector_size))
+    }
+
+    /// Persist config strings set by initiators in `path`, and apply any
+    /// saved there earlier to the targets added so far
+    pub fn set_state_file(&mut self, path: &Path) -> io::Result<()> {
+        let saved = state::load(path)?;
+        for (addr, target) in &self.targets {
+            if let Some(config_string) = saved.get(&addr.name()) {
+                log::info!("Target {} config string restored: {:?}", addr.name(), config_string);
+                *target.config_string.write().unwrap() = config_string.clone();
+                target.config_string_set.store(true, Ordering::Relaxed);
+            }
+        }
+        self.state_file = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    /// Look up a target by address
+    pub fn target(&self, addr: TargetAddr) -> Option<&Target> {
+        self.targets.get(&addr)
+    }
+
     /// Handle an AoE frame, returning responses for matching targets
     /// Returns (target_address, response_data) pairs
-    pub fn handle_frame(&mut self, frame: &AoeFrame) -> Result<Vec<(TargetAddr, ResponseData)>, AoeError> {
+    pub fn handle_frame(&self, frame: &AoeFrame) -> Result<Vec<(TargetAddr, ResponseData)>, AoeError> {
         let mut responses = Vec::new();
 
-        // Find matching targets
-        let matching: Vec<TargetAddr> = self
-            .targets
-            .keys()
-            .filter(|addr| self.address_matches(frame, addr))
-            .copied()
-            .collect();
+        let matching = self.matching_targets(frame);
 
         if matching.is_empty() {
             // No matching targets - don't respond
@@ -92,6 +351,20 @@ impl TargetManager {
         Ok(responses)
     }
 
+    /// Addresses of all targets a frame is addressed to
+    pub fn matching_targets(&self, frame: &AoeFrame) -> Vec<TargetAddr> {
+        self.targets
+            .keys()
+            .filter(|addr| self.address_matches(frame, addr))
+            .copied()
+            .collect()
+    }
+
+    /// Addresses of all configured targets
+    pub fn addrs(&self) -> Vec<TargetAddr> {
+        self.targets.keys().copied().collect()
+    }
+
     /// Check if a frame addresses a specific target
     fn address_matches(&self, frame: &AoeFrame, addr: &TargetAddr) -> bool {
         let shelf_match = frame.header.shelf == addr.shelf
@@ -102,34 +375,72 @@ impl TargetManager {
     }
 
     /// Handle a frame for a specific target
-    fn handle_target_frame(
-        &mut self,
+    pub fn handle_target_frame(
+        &self,
         frame: &AoeFrame,
         addr: TargetAddr,
     ) -> Result<ResponseData, AoeError> {
-        match frame.header.command {
+        let result = match frame.header.command {
             AoeCommand::Ata => self.handle_ata(frame, addr),
             AoeCommand::Config => self.handle_config(frame, addr),
+        };
+        if let Some(target) = self.targets.get(&addr) {
+            target.initiators.record(frame, &result);
         }
+        result
     }
 
     /// Handle an ATA command
     fn handle_ata(
-        &mut self,
+        &self,
         frame: &AoeFrame,
         addr: TargetAddr,
     ) -> Result<ResponseData, AoeError> {
-        let target = self
-            .targets
-            .get_mut(&addr)
-            .ok_or(AoeError::DeviceUnavailable)?;
+        let target = self.targets.get(&addr).ok_or(AoeError::DeviceUnavailable)?;
 
         let (header, data) = match &frame.payload {
             AoePayload::Ata { header, data } => (header, data),
             _ => return Err(AoeError::BadArgument("expected ATA payload".to_string())),
         };
 
-        let response = handle_ata_command(target.storage.as_mut(), header, data);
+        let mac = frame.header.src_mac;
+        let tag = frame.header.tag;
+        if let Some(response) = target.recent.get(&mac, tag, header) {
+            log::debug!("Replaying cached response for retransmitted tag {:#x}", tag);
+            return Ok(ResponseData::Ata(response));
+        }
+
+        // An unhealthy backend would only fail the request more slowly
+        if let Err(reason) = target.health.check(target.storage.as_ref()) {
+            log::debug!("Refusing ATA request to offline {}: {}", addr.name(), reason);
+            return Ok(ResponseData::Error {
+                code: AoeError::DeviceUnavailable.to_error_code(),
+            });
+        }
+
+        let mut in_flight = None;
+        if let Some(limiter) = &target.limiter {
+            let sector_size = target.storage.info().sector_size as usize;
+            match AtaCommand::try_from(header.cmd_status) {
+                Ok(cmd) if cmd.is_read() => limiter.throttle_read(header.sectors() * sector_size),
+                Ok(cmd) if cmd.is_write() => {
+                    limiter.throttle_write(data.len());
+                    // Past the in-flight cap the response waits for earlier writes
+                    in_flight = Some(limiter.begin_write(data.len()));
+                }
+                _ => {}
+            }
+        }
+
+        let response = handle_ata_command(
+            target.storage.as_ref(),
+            &target.smart,
+            target.addressing,
+            header,
+            data,
+        );
+        drop(in_flight);
+        target.recent.insert(mac, tag, header, &response);
         Ok(ResponseData::Ata(response))
     }
 
@@ -159,23 +470,13 @@ impl TargetManager {
         match ccmd {
             ConfigCommand::Read => {
                 // Return our config string
-                log::debug!("Config Read: responding with config_string='{}'", target.config_string);
-                Ok(ResponseData::Config(ConfigResponse {
-                    buffer_count: 16, // Match vblade - number of outstanding requests we can handle
-                    firmware_version: self.firmware_version,
-                    sector_count: MAX_SECTORS_STANDARD,
-                    config_string: target.config_string.as_bytes().to_vec(),
-                }))
+                log::debug!("Config Read: responding with config_string='{}'", target.config_string());
+                Ok(self.config_response(target))
             }
             ConfigCommand::TestExact => {
                 // Test if config string matches exactly
-                if config_header.config_string == target.config_string.as_bytes() {
-                    Ok(ResponseData::Config(ConfigResponse {
-                        buffer_count: 16, // Match vblade - number of outstanding requests we can handle
-                        firmware_version: self.firmware_version,
-                        sector_count: MAX_SECTORS_STANDARD,
-                        config_string: target.config_string.as_bytes().to_vec(),
-                    }))
+                if config_header.config_string == target.config_string().as_bytes() {
+                    Ok(self.config_response(target))
                 } else {
                     // Don't respond if no match
                     Err(AoeError::DeviceUnavailable)
@@ -184,25 +485,107 @@ impl TargetManager {
             ConfigCommand::TestPrefix => {
                 // Test if config string is a prefix
                 if target
-                    .config_string
+                    .config_string()
                     .as_bytes()
                     .starts_with(&config_header.config_string)
                 {
-                    Ok(ResponseData::Config(ConfigResponse {
-                        buffer_count: 16, // Match vblade - number of outstanding requests we can handle
-                        firmware_version: self.firmware_version,
-                        sector_count: MAX_SECTORS_STANDARD,
-                        config_string: target.config_string.as_bytes().to_vec(),
-                    }))
+                    Ok(self.config_response(target))
                 } else {
                     Err(AoeError::DeviceUnavailable)
                 }
             }
             ConfigCommand::Set | ConfigCommand::ForceSet => {
-                // We don't support changing config string
-                Err(AoeError::ConfigStringPresent)
+                // Refusals are reported to the initiator, unlike failed tests
+                let force = ccmd == ConfigCommand::ForceSet;
+                match self.set_config_string(addr, target, &config_header.config_string, force) {
+                    Ok(()) => Ok(self.config_response(target)),
+                    Err(e) => Ok(ResponseData::Error {
+                        code: e.to_error_code(),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Config Set takes an unset (or identical) config string; ForceSet
+    /// replaces whatever is there
+    fn set_config_string(
+        &self,
+        addr: TargetAddr,
+        target: &Target,
+        new: &[u8],
+        force: bool,
+    ) -> Result<(), AoeError> {
+        if new.len() > MAX_CONFIG_STRING {
+            return Err(AoeError::BadArgument(format!(
+                "config string of {} bytes exceeds {}",
+                new.len(),
+                MAX_CONFIG_STRING
+            )));
+        }
+        let new = std::str::from_utf8(new)
+            .map_err(|_| AoeError::BadArgument("config string is not UTF-8".to_string()))?;
+
+        {
+            let mut current = target.config_string.write().unwrap();
+            if *current == new {
+                return Ok(());
+            }
+            if !force && !current.is_empty() {
+                return Err(AoeError::ConfigStringPresent);
+            }
+            log::info!("Target {} config string set to {:?}", addr.name(), new);
+            *current = new.to_string();
+            target.config_string_set.store(true, Ordering::Relaxed);
+        }
+        self.save_state();
+        Ok(())
+    }
+
+    /// Save the config strings initiators have set to the state file, if
+    /// there is one. Strings from the config file are left out, so editing
+    /// the config file still changes them.
+    fn save_state(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let _guard = self.state_lock.lock().unwrap();
+        let strings = self
+            .targets
+            .iter()
+            .filter(|(_, target)| target.config_string_set.load(Ordering::Relaxed))
+            .map(|(addr, target)| (addr.name(), target.config_string()))
+            .collect();
+        if let Err(e) = state::save(path, &strings) {
+            log::error!("Failed to save config strings to {:?}: {}", path, e);
+        }
+    }
+
+    /// Build a config response describing a target
+    fn config_response(&self, target: &Target) -> ResponseData {
+        ResponseData::Config(ConfigResponse {
+            buffer_count: target.buffer_count,
+            firmware_version: self.firmware_version,
+            sector_count: self.sector_count(target),
+            config_string: target.config_string().into_bytes(),
+        })
+    }
+
+    /// Flush every target's backend, returning how many failed
+    pub fn flush_all(&self) -> usize {
+        let mut failures = 0;
+        for (addr, target) in &self.targets {
+            if let Err(e) = target.storage.flush() {
+                log::error!("Failed to flush e{}.{}: {}", addr.shelf, addr.slot, e);
+                failures += 1;
             }
         }
+        failures
+    }
+
+    /// Deduplication statistics for a target, if its backend deduplicates
+    pub fn dedup_stats(&self, addr: TargetAddr) -> Option<DedupStats> {
+        self.targets.get(&addr)?.storage.dedup_stats()
     }
 
     /// Get number of targets
@@ -211,8 +594,276 @@ impl TargetManager {
     }
 }
 
+/// Sectors that fit in a frame after the AoE and ATA headers.
+/// 4Kn targets need jumbo frames; they still advertise one sector.
+pub fn max_sectors_per_frame(mtu: u32, sector_size: u32) -> u8 {
+    // The MTU excludes the 14-byte Ethernet header
+    let headers = AoeHeader::SIZE - 14 + AtaHeader::SIZE;
+    let payload = (mtu as usize).saturating_sub(headers);
+    (payload / sector_size as usize).clamp(1, u8::MAX as usize) as u8
+}
+
 impl Default for TargetManager {
     fn default() -> Self {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{parse_frame, AoeHeader, AtaHeader, ConfigCommand, AOE_ETHERTYPE};
+    use crate::storage::MemBackend;
+
+    fn make_read_request(shelf: u16, slot: u8) -> AoeFrame {
+        let mut frame = vec![0u8; AoeHeader::SIZE + AtaHeader::SIZE];
+        frame[0..6].copy_from_slice(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        frame[6..12].copy_from_slice(&[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+        frame[12..14].copy_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+        frame[14] = 0x10; // version 1
+        frame[16..18].copy_from_slice(&shelf.to_be_bytes());
+        frame[18] = slot;
+        frame[19] = 0; // ATA
+        frame[24] = 0x40; // LBA48
+        frame[26] = 1; // one sector
+        frame[27] = 0x24; // READ SECTORS EXT
+        parse_frame(&frame).unwrap()
+    }
+
+    fn make_manager() -> TargetManager {
+        let mut manager = TargetManager::new();
+        for slot in 0..2u8 {
+            let storage = MemBackend::new(1024 * 1024);
+            manager.add_target(1, slot, Box::new(storage), String::new());
+        }
+        manager
+    }
+
+    #[test]
+    fn test_retransmitted_write_not_reapplied() {
+        let manager = make_manager();
+        let addr = TargetAddr::new(1, 0);
+        let storage = &manager.targets[&addr].storage;
+
+        let mut raw = vec![0u8; AoeHeader::SIZE + AtaHeader::SIZE + 512];
+        raw[6..12].copy_from_slice(&[0x02, 0, 0, 0, 0, 1]);
+        raw[12..14].copy_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+        raw[14] = 0x10;
+        raw[16..18].copy_from_slice(&1u16.to_be_bytes());
+        raw[20..24].copy_from_slice(&7u32.to_be_bytes());
+        raw[24] = 0x41; // LBA48 write
+        raw[26] = 1;
+        raw[27] = 0x34; // WRITE SECTORS EXT
+        raw[36..].fill(0x11);
+        let write = parse_frame(&raw).unwrap();
+
+        manager.handle_target_frame(&write, addr).unwrap();
+        assert_eq!(storage.read(0, 1).unwrap(), vec![0x11; 512]);
+
+        // Another writer changes the sector; the retransmit must not undo it
+        storage.write(0, &[0x22; 512]).unwrap();
+        match manager.handle_target_frame(&write, addr).unwrap() {
+            ResponseData::Ata(response) => assert_eq!(response.error, 0),
+            _ => panic!("expected ATA response"),
+        }
+        assert_eq!(storage.read(0, 1).unwrap(), vec![0x22; 512]);
+    }
+
+    #[test]
+    fn test_initiator_stats() {
+        let manager = make_manager();
+        let addr = TargetAddr::new(1, 0);
+
+        let read = make_read_request(1, 0);
+        manager.handle_target_frame(&read, addr).unwrap();
+        manager.handle_target_frame(&read, addr).unwrap();
+        let mut beyond = make_read_request(1, 0);
+        if let AoePayload::Ata { header, .. } = &mut beyond.payload {
+            header.lba = 1 << 40;
+        }
+        manager.handle_target_frame(&beyond, addr).unwrap();
+
+        let mut other = make_config_request(ConfigCommand::Read, b"");
+        other.header.src_mac = [0x02, 0, 0, 0, 0, 9];
+        manager.handle_target_frame(&other, addr).unwrap();
+
+        let stats = manager.targets[&addr].initiators.stats();
+        assert_eq!(stats.len(), 2);
+        let host = stats.iter().find(|s| s.mac == "aa:bb:cc:dd:ee:ff").unwrap();
+        assert_eq!((host.requests, host.bytes_read, host.errors), (3, 1024, 1));
+        let other = stats.iter().find(|s| s.mac == "02:00:00:00:00:09").unwrap();
+        assert_eq!((other.requests, other.bytes_read, other.errors), (1, 0, 0));
+        assert!(other.last_seen > 0);
+        assert!(manager.targets[&TargetAddr::new(1, 1)].initiators.stats().is_empty());
+    }
+
+    #[test]
+    fn test_matching_targets() {
+        let manager = make_manager();
+
+        assert_eq!(
+            manager.matching_targets(&make_read_request(1, 1)),
+            vec![TargetAddr::new(1, 1)]
+        );
+        assert_eq!(
+            manager
+                .matching_targets(&make_read_request(BROADCAST_SHELF, BROADCAST_SLOT))
+                .len(),
+            2
+        );
+        assert!(manager.matching_targets(&make_read_request(2, 0)).is_empty());
+    }
+
+    #[test]
+    fn test_targets_handled_concurrently() {
+        let manager = std::sync::Arc::new(make_manager());
+
+        let handles: Vec<_> = (0..2u8)
+            .map(|slot| {
+                let manager = std::sync::Arc::clone(&manager);
+                std::thread::spawn(move || {
+                    let frame = make_read_request(1, slot);
+                    manager.handle_target_frame(&frame, TargetAddr::new(1, slot))
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            match handle.join().unwrap().unwrap() {
+                ResponseData::Ata(response) => {
+                    assert_eq!(response.data.unwrap().len(), 512);
+                }
+                _ => panic!("expected ATA response"),
+            }
+        }
+    }
+
+    fn make_config_request(ccmd: ConfigCommand, config_string: &[u8]) -> AoeFrame {
+        let mut frame = vec![0u8; AoeHeader::SIZE + 8];
+        frame[6..12].copy_from_slice(&[0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0xFF]);
+        frame[12..14].copy_from_slice(&AOE_ETHERTYPE.to_be_bytes());
+        frame[14] = 0x10;
+        frame[16..18].copy_from_slice(&1u16.to_be_bytes());
+        frame[19] = 1; // Config
+        frame[29] = 0x10 | ccmd as u8;
+        frame[30..32].copy_from_slice(&(config_string.len() as u16).to_be_bytes());
+        frame.extend_from_slice(config_string);
+        parse_frame(&frame).unwrap()
+    }
+
+    fn config_of(response: ResponseData) -> Vec<u8> {
+        match response {
+            ResponseData::Config(config) => config.config_string,
+            _ => panic!("expected Config response"),
+        }
+    }
+
+    fn error_of(response: ResponseData) -> u8 {
+        match response {
+            ResponseData::Error { code } => code,
+            _ => panic!("expected error response"),
+        }
+    }
+
+    #[test]
+    fn test_config_set_and_force_set() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let state_file = temp.path().join("state.json");
+        let addr = TargetAddr::new(1, 0);
+
+        let mut manager = make_manager();
+        manager.set_state_file(&state_file).unwrap();
+
+        // Set only takes an empty (or identical) config string
+        let set = make_config_request(ConfigCommand::Set, b"host-a");
+        assert_eq!(config_of(manager.handle_target_frame(&set, addr).unwrap()), b"host-a");
+        assert_eq!(config_of(manager.handle_target_frame(&set, addr).unwrap()), b"host-a");
+        let other = make_config_request(ConfigCommand::Set, b"host-b");
+        assert_eq!(error_of(manager.handle_target_frame(&other, addr).unwrap()), 4);
+
+        let force = make_config_request(ConfigCommand::ForceSet, b"host-b");
+        assert_eq!(config_of(manager.handle_target_frame(&force, addr).unwrap()), b"host-b");
+        let too_long = make_config_request(ConfigCommand::ForceSet, &[b'x'; 1025]);
+        assert_eq!(error_of(manager.handle_target_frame(&too_long, addr).unwrap()), 2);
+
+        // Restored by the next server over the configured strings
+        let mut restarted = make_manager();
+        restarted.set_state_file(&state_file).unwrap();
+        assert_eq!(restarted.target(addr).unwrap().config_string(), "host-b");
+        assert_eq!(restarted.target(TargetAddr::new(1, 1)).unwrap().config_string(), "");
+
+        // Strings from the config file aren't saved, so editing it still
+        // takes effect for targets no initiator has set
+        let mut configured = TargetManager::new();
+        for (slot, config_string) in [(0, "old"), (1, "old")] {
+            let storage = Box::new(MemBackend::new(1024 * 1024));
+            configured.add_target(1, slot, storage, config_string.to_string());
+        }
+        configured.set_state_file(&state_file).unwrap();
+        let force = make_config_request(ConfigCommand::ForceSet, b"host-c");
+        configured.handle_target_frame(&force, addr).unwrap();
+        let saved = state::load(&state_file).unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved["e1.0"], "host-c");
+    }
+
+    #[test]
+    fn test_advertised_limits() {
+        let mut manager = make_manager();
+        let read = make_config_request(ConfigCommand::Read, b"");
+        let config = |manager: &TargetManager, slot| match manager
+            .handle_target_frame(&read, TargetAddr::new(1, slot))
+            .unwrap()
+        {
+            ResponseData::Config(config) => (config.buffer_count, config.sector_count),
+            _ => panic!("expected Config response"),
+        };
+
+        assert_eq!(config(&manager, 0), (BUFFER_COUNT, 2));
+
+        manager.set_mtu(9000);
+        manager.set_limits(1, 1, Some(64), Some(8));
+        assert_eq!(config(&manager, 0), (BUFFER_COUNT, 17));
+        assert_eq!(config(&manager, 1), (64, 8));
+
+        assert_eq!(max_sectors_per_frame(1500, 4096), 1);
+        assert_eq!(max_sectors_per_frame(9000, 4096), 2);
+        assert_eq!(max_sectors_per_frame(65535, 512), 127);
+    }
+
+    #[test]
+    fn test_unhealthy_target_goes_offline() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let blobs = temp.path().join("blobs");
+        let store = crate::blob::FileBlobStore::new(&blobs).unwrap();
+        let storage =
+            crate::storage::CasBackend::new(Box::new(store), 2048, &temp.path().join("snap.json"))
+                .unwrap();
+        let mut manager = TargetManager::new();
+        manager.add_target(1, 0, Box::new(storage), String::new());
+        manager.set_health_interval(Duration::from_millis(1));
+        let addr = TargetAddr::new(1, 0);
+        let read = |tag: u8| {
+            let mut frame = make_read_request(1, 0);
+            frame.header.tag = tag as u32;
+            manager.handle_target_frame(&frame, addr).unwrap()
+        };
+
+        assert!(matches!(read(1), ResponseData::Ata(_)));
+
+        std::fs::remove_dir_all(&blobs).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(read(2), ResponseData::Error { code: 3 }));
+        assert!(manager.target(addr).unwrap().health.failure().is_some());
+        // Discovery still finds it
+        let config = make_config_request(ConfigCommand::Read, b"");
+        assert!(matches!(
+            manager.handle_target_frame(&config, addr).unwrap(),
+            ResponseData::Config(_)
+        ));
+
+        std::fs::create_dir_all(&blobs).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(matches!(read(3), ResponseData::Ata(_)));
+    }
+}
@@ -0,0 +1,285 @@
+//! Image transfers
+//!
+//! Copies a whole target to or from somewhere else on a thread of its own,
+//! for the admin API (`voe-admin import`, `export` and `replicate`):
+//!
+//! - import: a raw image on the server's host replaces a CAS target's
+//!   contents, stored as `CasBackend::ingest_from` stores them
+//! - export: a target is written out to a new raw image, sparse where
+//!   the target holds no data
+//! - replicate: a remote NBD export is copied into a target with
+//!   `NbdClient::copy_to`
+//!
+//! Targets stay online throughout, so writes from initiators during a
+//! transfer may or may not be in the result.
+
+use super::{TargetAddr, TargetManager};
+use crate::nbd::NbdClient;
+use crate::shutdown;
+use crate::storage::BlockStorage;
+use serde::Serialize;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Sectors read per request when exporting
+const EXPORT_CHUNK_SECTORS: u64 = 128;
+
+/// Transfer to start; image paths are on the server's host
+#[derive(Debug, Clone)]
+pub enum TransferKind {
+    /// Replace a CAS target's contents with a raw image
+    Import { image: PathBuf },
+    /// Write a target to a raw image, which must not exist yet
+    Export { image: PathBuf },
+    /// Copy an NBD export (`host:port` or `unix:/path`) into a target
+    Replicate { source: String, export: String },
+}
+
+/// Stage a transfer is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferState {
+    Running,
+    Done,
+    Failed,
+}
+
+/// Progress of a transfer
+#[derive(Debug, Clone, Serialize)]
+pub struct TransferStatus {
+    /// `import`, `export` or `replicate`
+    pub kind: &'static str,
+    /// Image path, `nbd://<source>/<export>` or target name
+    pub from: String,
+    pub to: String,
+    pub state: TransferState,
+    pub total_bytes: u64,
+    pub copied_bytes: u64,
+    pub error: Option<String>,
+}
+
+impl TransferStatus {
+    pub fn is_running(&self) -> bool {
+        self.state == TransferState::Running
+    }
+}
+
+/// What the transfer thread copies, opened before it starts so that bad
+/// paths and unreachable servers are reported to the caller
+enum Job {
+    Import(File),
+    Export(File, PathBuf),
+    Replicate(NbdClient),
+}
+
+/// A copy to or from one target
+pub struct Transfer {
+    status: Mutex<TransferStatus>,
+}
+
+impl Transfer {
+    /// Start a transfer of the target at `addr`
+    pub fn start(
+        targets: Arc<TargetManager>,
+        addr: TargetAddr,
+        kind: TransferKind,
+    ) -> io::Result<Arc<Self>> {
+        let target = targets.target(addr).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Target not found: {}", addr.name()),
+            )
+        })?;
+        let capacity = target.storage.info().size_bytes();
+        let relative = |image: &PathBuf| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("image path must be absolute: {}", image.display()),
+            )
+        };
+
+        let (job, kind, from, to, total_bytes) = match kind {
+            TransferKind::Import { image } => {
+                if !image.is_absolute() {
+                    return Err(relative(&image));
+                }
+                if target.storage.as_archival().is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("{} is not a CAS target", addr.name()),
+                    ));
+                }
+                let mut file = File::open(&image)?;
+                // Block devices report no length in their metadata
+                let size = file.seek(SeekFrom::End(0))?;
+                file.seek(SeekFrom::Start(0))?;
+                if size > capacity {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "image is {} bytes but {} holds {}",
+                            size,
+                            addr.name(),
+                            capacity
+                        ),
+                    ));
+                }
+                let from = image.display().to_string();
+                (Job::Import(file), "import", from, addr.name(), size)
+            }
+            TransferKind::Export { image } => {
+                if !image.is_absolute() {
+                    return Err(relative(&image));
+                }
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&image)?;
+                let to = image.display().to_string();
+                (
+                    Job::Export(file, image),
+                    "export",
+                    addr.name(),
+                    to,
+                    capacity,
+                )
+            }
+            TransferKind::Replicate { source, export } => {
+                let client = NbdClient::connect(&source, &export)?;
+                let from = format!("nbd://{}/{}", source, export);
+                let size = client.size();
+                (Job::Replicate(client), "replicate", from, addr.name(), size)
+            }
+        };
+
+        let transfer = Arc::new(Self {
+            status: Mutex::new(TransferStatus {
+                kind,
+                from,
+                to,
+                state: TransferState::Running,
+                total_bytes,
+                copied_bytes: 0,
+                error: None,
+            }),
+        });
+        log::info!("Starting {} of {}", kind, addr.name());
+
+        let job_transfer = transfer.clone();
+        thread::Builder::new()
+            .name(format!("{}-{}", kind, addr.name()))
+            .spawn(move || job_transfer.run(&targets, addr, job))?;
+        Ok(transfer)
+    }
+
+    pub fn status(&self) -> TransferStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut TransferStatus)) {
+        f(&mut self.status.lock().unwrap());
+    }
+
+    fn run(&self, targets: &TargetManager, addr: TargetAddr, job: Job) {
+        let Some(target) = targets.target(addr) else {
+            return;
+        };
+        let storage = target.storage.as_ref();
+        let kind = self.status().kind;
+        let result = match job {
+            Job::Import(file) => self.import(storage, file),
+            Job::Export(file, image) => {
+                let exported = self.export(storage, file);
+                if exported.is_err() {
+                    let _ = fs::remove_file(&image);
+                }
+                exported
+            }
+            Job::Replicate(client) => self.replicate(storage, client),
+        };
+        match result {
+            Ok(bytes) => {
+                log::info!("{} of {} complete: {} bytes", kind, addr.name(), bytes);
+                self.update(|status| status.state = TransferState::Done);
+            }
+            Err(e) => {
+                log::error!("{} of {} failed: {}", kind, addr.name(), e);
+                self.update(|status| {
+                    status.state = TransferState::Failed;
+                    status.error = Some(e.to_string());
+                });
+            }
+        }
+    }
+
+    fn import(&self, storage: &dyn BlockStorage, file: File) -> io::Result<u64> {
+        let archival = storage.as_archival().expect("checked when started");
+        let mut reader = Progress {
+            inner: file,
+            transfer: self,
+        };
+        let imported = archival
+            .ingest(&mut reader)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        storage
+            .flush()
+            .map_err(|e| io::Error::other(format!("flush failed: {}", e)))?;
+        Ok(imported)
+    }
+
+    fn export(&self, storage: &dyn BlockStorage, mut file: File) -> io::Result<u64> {
+        let info = storage.info();
+        let sector_size = info.sector_size as u64;
+        let storage_error = |e| io::Error::other(format!("read failed: {}", e));
+        // Holes stay holes in the image
+        let ranges = storage
+            .allocated_ranges(0, info.total_sectors)
+            .map_err(storage_error)?;
+        for (start, count) in ranges {
+            let end = start + count;
+            let mut lba = start;
+            file.seek(SeekFrom::Start(lba * sector_size))?;
+            while lba < end {
+                if shutdown::requested() {
+                    return Err(io::Error::other("interrupted by shutdown"));
+                }
+                let sectors = (end - lba).min(EXPORT_CHUNK_SECTORS);
+                file.write_all(&storage.read(lba, sectors as u8).map_err(storage_error)?)?;
+                lba += sectors;
+                self.update(|status| status.copied_bytes = lba * sector_size);
+            }
+        }
+        file.set_len(info.size_bytes())?;
+        file.sync_all()?;
+        Ok(info.size_bytes())
+    }
+
+    fn replicate(&self, storage: &dyn BlockStorage, mut client: NbdClient) -> io::Result<u64> {
+        let copied = client.copy_to(storage, |copied| {
+            self.update(|status| status.copied_bytes = copied)
+        })?;
+        let _ = client.disconnect();
+        Ok(copied)
+    }
+}
+
+/// Reader counting the bytes an import has read
+struct Progress<'a> {
+    inner: File,
+    transfer: &'a Transfer,
+}
+
+impl Read for Progress<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if shutdown::requested() {
+            return Err(io::Error::other("interrupted by shutdown"));
+        }
+        let read = self.inner.read(buf)?;
+        self.transfer
+            .update(|status| status.copied_bytes += read as u64);
+        Ok(read)
+    }
+}
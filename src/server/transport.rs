@@ -0,0 +1,264 @@
+//! Frame transports for the AoE listener
+//!
+//! `AoeListener` receives and sends raw Ethernet frames through a
+//! `FrameTransport`. `PnetTransport` is a real interface via pnet and
+//! `InterfaceSet` every interface matching a pattern; the in-memory
+//! `MemoryTransport` lets tests run the full listener, workers included,
+//! without a NIC or root.
+
+use crate::client::Transport;
+use crate::shutdown;
+use pnet::datalink::{self, Channel, DataLinkReceiver, DataLinkSender, NetworkInterface};
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Sending half of a frame channel
+pub trait FrameSender: Send + 'static {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()>;
+}
+
+/// Receiving half of a frame channel
+pub trait FrameReceiver: Send + 'static {
+    /// Next frame, or None if nothing arrived within about
+    /// `shutdown::POLL_INTERVAL`, so the caller can check for shutdown
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>>;
+}
+
+/// Source of raw Ethernet frame channels
+pub trait FrameTransport: Send {
+    type Sender: FrameSender;
+    type Receiver: FrameReceiver;
+
+    /// Name for log messages
+    fn name(&self) -> String;
+
+    fn local_mac(&self) -> Option<[u8; 6]>;
+
+    /// Open the channel. Called again each time a stopped listener restarts.
+    fn open(&mut self) -> io::Result<(Self::Sender, Self::Receiver)>;
+
+    /// Open up to `queues` channels that share the incoming frames between
+    /// them, each with its own sender. Transports without multi-queue
+    /// receive open one.
+    fn open_queues(&mut self, queues: usize) -> io::Result<Vec<(Self::Sender, Self::Receiver)>> {
+        let _ = queues;
+        Ok(vec![self.open()?])
+    }
+}
+
+/// Raw Ethernet channel halves
+type EthernetChannel = (Box<dyn DataLinkSender>, Box<dyn DataLinkReceiver>);
+
+/// A network interface, through pnet
+pub struct PnetTransport {
+    interface: NetworkInterface,
+    /// Channel opened by `new`, handed out by the first `open`
+    channel: Option<EthernetChannel>,
+}
+
+impl PnetTransport {
+    /// Find `interface_name` and open a channel on it, so permission
+    /// problems surface at startup
+    pub fn new(interface_name: &str) -> io::Result<Self> {
+        let interface = datalink::interfaces()
+            .into_iter()
+            .find(|iface| iface.name == interface_name)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("interface not found: {}", interface_name),
+                )
+            })?;
+        let channel = open_channel(&interface)?;
+        Ok(Self {
+            interface,
+            channel: Some(channel),
+        })
+    }
+}
+
+impl FrameTransport for PnetTransport {
+    type Sender = Box<dyn DataLinkSender>;
+    type Receiver = Box<dyn DataLinkReceiver>;
+
+    fn name(&self) -> String {
+        format!(
+            "{} ({})",
+            self.interface.name,
+            self.interface
+                .mac
+                .map(|m| m.to_string())
+                .unwrap_or_else(|| "no MAC".to_string())
+        )
+    }
+
+    fn local_mac(&self) -> Option<[u8; 6]> {
+        self.interface.mac.map(|m| m.octets())
+    }
+
+    fn open(&mut self) -> io::Result<(Self::Sender, Self::Receiver)> {
+        match self.channel.take() {
+            Some(channel) => Ok(channel),
+            None => open_channel(&self.interface),
+        }
+    }
+}
+
+/// MTU of a network interface, if the system reports it
+pub fn interface_mtu(interface_name: &str) -> Option<u32> {
+    let path = format!("/sys/class/net/{}/mtu", interface_name);
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Open a raw Ethernet channel on an interface
+pub(super) fn open_channel(interface: &NetworkInterface) -> io::Result<EthernetChannel> {
+    // Wake periodically so the receive loop notices shutdown requests
+    let channel_config = datalink::Config {
+        read_timeout: Some(shutdown::POLL_INTERVAL),
+        ..Default::default()
+    };
+    match datalink::channel(interface, channel_config) {
+        Ok(Channel::Ethernet(tx, rx)) => Ok((tx, rx)),
+        Ok(_) => Err(io::Error::other("unsupported channel type")),
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("failed to open channel: {}", e),
+        )),
+    }
+}
+
+impl FrameSender for Box<dyn DataLinkSender> {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.send_to(frame, None)
+            .unwrap_or_else(|| Err(io::Error::other("send failed: no result")))
+    }
+}
+
+impl FrameReceiver for Box<dyn DataLinkReceiver> {
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.next() {
+            Ok(packet) => Ok(Some(packet.to_vec())),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+                ) =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// In-memory transport: frames sent by the `MemoryPeer` arrive at the
+/// listener and the listener's responses go back to the peer
+pub struct MemoryTransport {
+    mac: [u8; 6],
+    inbound: Arc<Mutex<Receiver<Vec<u8>>>>,
+    outbound: Sender<Vec<u8>>,
+}
+
+/// The other end of a `MemoryTransport`
+pub struct MemoryPeer {
+    mac: [u8; 6],
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+}
+
+impl MemoryTransport {
+    /// MAC the listener sends from
+    pub const LISTENER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x10];
+    /// MAC of the peer
+    pub const PEER_MAC: [u8; 6] = [0x02, 0, 0, 0, 0, 0x20];
+
+    pub fn new() -> (Self, MemoryPeer) {
+        let (peer_tx, inbound) = mpsc::channel();
+        let (outbound, peer_rx) = mpsc::channel();
+        let transport = Self {
+            mac: Self::LISTENER_MAC,
+            inbound: Arc::new(Mutex::new(inbound)),
+            outbound,
+        };
+        let peer = MemoryPeer {
+            mac: Self::PEER_MAC,
+            tx: peer_tx,
+            rx: peer_rx,
+        };
+        (transport, peer)
+    }
+}
+
+/// Receiving half of a `MemoryTransport`, shared across restarts
+pub struct MemoryReceiver(Arc<Mutex<Receiver<Vec<u8>>>>);
+
+/// Sending half of a `MemoryTransport`
+pub struct MemorySender(Sender<Vec<u8>>);
+
+impl FrameTransport for MemoryTransport {
+    type Sender = MemorySender;
+    type Receiver = MemoryReceiver;
+
+    fn name(&self) -> String {
+        "memory".to_string()
+    }
+
+    fn local_mac(&self) -> Option<[u8; 6]> {
+        Some(self.mac)
+    }
+
+    fn open(&mut self) -> io::Result<(Self::Sender, Self::Receiver)> {
+        Ok((
+            MemorySender(self.outbound.clone()),
+            MemoryReceiver(Arc::clone(&self.inbound)),
+        ))
+    }
+}
+
+impl FrameSender for MemorySender {
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.0
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "peer has gone"))
+    }
+}
+
+impl FrameReceiver for MemoryReceiver {
+    fn recv(&mut self) -> io::Result<Option<Vec<u8>>> {
+        match self.0.lock().unwrap().recv_timeout(shutdown::POLL_INTERVAL) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            // Like an idle wire: nothing more will arrive
+            Err(RecvTimeoutError::Disconnected) => {
+                std::thread::sleep(shutdown::POLL_INTERVAL);
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Lets an `AoeClient` talk to a listener over a `MemoryTransport`
+impl Transport for MemoryPeer {
+    fn local_mac(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn send(&mut self, frame: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(frame.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "listener has gone"))
+    }
+
+    fn recv(&mut self, timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "listener has gone",
+            )),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Graceful shutdown on SIGINT/SIGTERM
+//!
+//! `install` replaces the default (terminate) action with a handler that
+//! only raises a process-wide flag. Servers poll `requested()` from their
+//! accept/receive loops, stop taking new work, drain what is in flight and
+//! flush their backends before returning. A second signal exits at once.
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// How often blocking loops should wake to check for shutdown
+pub const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Install SIGINT and SIGTERM handlers
+#[cfg(unix)]
+pub fn install() -> io::Result<()> {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: the handler only touches an atomic and calls _exit, both
+        // async-signal-safe; the sigaction struct is fully initialized.
+        let result = unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = handle_signal as *const () as libc::sighandler_t;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signal, &action, std::ptr::null_mut())
+        };
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Signals are not supported here; only `request` triggers shutdown
+#[cfg(not(unix))]
+pub fn install() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn handle_signal(_signal: libc::c_int) {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        // Second signal: the operator doesn't want to wait for the drain
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// True once shutdown has been requested
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Request shutdown without a signal
+pub fn request() {
+    REQUESTED.store(true, Ordering::SeqCst);
+}
+
+/// Block until shutdown is requested
+pub fn wait() {
+    while !requested() {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    /// Set in the child process that actually takes the signal
+    const CHILD_ENV: &str = "VOE_SHUTDOWN_TEST_CHILD";
+
+    /// The flag is process-wide, so raising it here would stop every
+    /// listener and loop under test in this process; the signal is taken
+    /// in a copy of the test binary running only this test
+    #[test]
+    fn test_signal_requests_shutdown() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            install().unwrap();
+            assert!(!requested());
+
+            unsafe { libc::raise(libc::SIGTERM) };
+            assert!(requested());
+            return;
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "shutdown::tests::test_signal_requests_shutdown"])
+            .args(["--test-threads", "1"])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(output.status.success(), "{}", stdout);
+        // A filter that matched nothing would also exit successfully
+        assert!(stdout.contains("1 passed"), "{}", stdout);
+        assert!(!requested());
+    }
+}
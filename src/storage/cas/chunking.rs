@@ -0,0 +1,234 @@
+//! Content-defined chunking for archival CAS targets
+//!
+//! With fixed blocks, data that moves by a few bytes (a file rewritten with
+//! something inserted near its start) no longer lines up with any stored
+//! block and dedups not at all. In chunking mode each write is cut with
+//! FastCDC into variable-size chunks whose boundaries depend only on the
+//! nearby content, so shifted data yields the same chunks again.
+//!
+//! Chunks are stored as blobs of their own. The Merkle tree still maps each
+//! data block to one hash, but for a chunked block that hash names a small
+//! manifest blob listing the chunk ranges the block is made of. Manifests
+//! are content-addressed like everything else, so snapshots, the root
+//! journal and restores work unchanged. Chunking restarts at every write,
+//! so large sequential writes dedup best.
+
+use crate::blob::Hash;
+use crate::storage::{StorageError, StorageResult};
+
+/// Marker byte of a stored block manifest (data blocks use the codec markers)
+pub const MARKER_MANIFEST: u8 = 0x10;
+
+/// Bytes per manifest segment: hash, chunk length, offset, length
+const SEGMENT_SIZE: usize = 32 + 4 + 4 + 4;
+
+/// Default average chunk size
+pub const DEFAULT_AVG_CHUNK: u32 = 8192;
+
+/// FastCDC chunk size limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkerConfig {
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+impl ChunkerConfig {
+    /// Chunks averaging `avg_size` bytes (a power of two), between a
+    /// quarter of that and four times it
+    pub fn new(avg_size: u32) -> StorageResult<Self> {
+        if !avg_size.is_power_of_two() || !(1024..=1 << 20).contains(&avg_size) {
+            return Err(StorageError::Backend(format!(
+                "invalid average chunk size {}: must be a power of two from 1 KiB to 1 MiB",
+                avg_size
+            )));
+        }
+        let avg_size = avg_size as usize;
+        Ok(Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 4,
+        })
+    }
+
+    /// Split `data` into chunks, returning the end offset of each
+    pub fn boundaries(&self, data: &[u8]) -> Vec<usize> {
+        let mut ends = Vec::new();
+        let mut start = 0;
+        while start < data.len() {
+            start += self.cut(&data[start..]);
+            ends.push(start);
+        }
+        ends
+    }
+
+    /// Length of the first chunk of `data`. Normalized chunking: a harder
+    /// mask before the average size and an easier one after it keeps chunk
+    /// sizes close to the average.
+    fn cut(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let end = data.len().min(self.max_size);
+        let normal = end.min(self.avg_size);
+        let bits = self.avg_size.trailing_zeros();
+        // Gear hashes mix new bytes into the top bits, so mask those
+        let hard = !0u64 << (64 - (bits + 2));
+        let easy = !0u64 << (64 - (bits - 2));
+
+        let mut hash = 0u64;
+        for (i, &byte) in data.iter().enumerate().take(end).skip(self.min_size) {
+            hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+            let mask = if i < normal { hard } else { easy };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+}
+
+/// A range of one chunk making up part of a block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    /// Stored chunk (zero for an all-zero chunk)
+    pub chunk: Hash,
+    /// Full length of the chunk
+    pub chunk_len: u32,
+    /// Where the range starts within the chunk
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// Serialize a block manifest, marker byte first
+pub fn encode_manifest(segments: &[Segment]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + segments.len() * SEGMENT_SIZE);
+    out.push(MARKER_MANIFEST);
+    for segment in segments {
+        out.extend_from_slice(segment.chunk.as_bytes());
+        out.extend_from_slice(&segment.chunk_len.to_le_bytes());
+        out.extend_from_slice(&segment.offset.to_le_bytes());
+        out.extend_from_slice(&segment.len.to_le_bytes());
+    }
+    out
+}
+
+/// Parse a manifest produced by `encode_manifest`
+pub fn decode_manifest(stored: &[u8]) -> StorageResult<Vec<Segment>> {
+    let body = match stored.split_first() {
+        Some((&MARKER_MANIFEST, body)) if body.len() % SEGMENT_SIZE == 0 => body,
+        _ => return Err(StorageError::Corrupted),
+    };
+    let u32_at = |raw: &[u8], at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+    body.chunks(SEGMENT_SIZE)
+        .map(|raw| {
+            let segment = Segment {
+                chunk: Hash::from_bytes(raw[..32].try_into().unwrap()),
+                chunk_len: u32_at(raw, 32),
+                offset: u32_at(raw, 36),
+                len: u32_at(raw, 40),
+            };
+            if segment.offset as u64 + segment.len as u64 > segment.chunk_len as u64 {
+                return Err(StorageError::Corrupted);
+            }
+            Ok(segment)
+        })
+        .collect()
+}
+
+/// Chunks a stored block refers to: none for a data block, the stored
+/// (non-zero) chunks for a manifest
+pub fn referenced_chunks(stored: &[u8]) -> StorageResult<Vec<Hash>> {
+    if stored.first() != Some(&MARKER_MANIFEST) {
+        return Ok(Vec::new());
+    }
+    Ok(decode_manifest(stored)?
+        .into_iter()
+        .map(|segment| segment.chunk)
+        .filter(|chunk| !chunk.is_zero())
+        .collect())
+}
+
+/// Random values for the gear hash, fixed so boundaries are stable
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64
+    let mut table = [0u64; 256];
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic incompressible test data
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_boundaries_follow_content() {
+        let config = ChunkerConfig::new(4096).unwrap();
+        let data = noise(256 * 1024, 1);
+        let ends = config.boundaries(&data);
+        assert_eq!(*ends.last().unwrap(), data.len());
+
+        let mut start = 0;
+        for &end in &ends[..ends.len() - 1] {
+            assert!((1024..=16384).contains(&(end - start)));
+            start = end;
+        }
+        let average = data.len() / ends.len();
+        assert!((2048..=8192).contains(&average), "average chunk {}", average);
+
+        // After an insertion the boundaries resynchronize, shifted along
+        let mut shifted = noise(1000, 2);
+        shifted.extend_from_slice(&data);
+        let shifted_ends: Vec<usize> = config
+            .boundaries(&shifted)
+            .iter()
+            .filter_map(|&end| end.checked_sub(1000))
+            .collect();
+        let common = ends.iter().filter(|end| shifted_ends.contains(end)).count();
+        assert!(common * 10 >= ends.len() * 9, "{} of {} in common", common, ends.len());
+    }
+
+    #[test]
+    fn test_manifest_roundtrip() {
+        let segments = vec![
+            Segment {
+                chunk: Hash::from_data(b"chunk"),
+                chunk_len: 9000,
+                offset: 8000,
+                len: 1000,
+            },
+            Segment {
+                chunk: Hash::ZERO,
+                chunk_len: 4096,
+                offset: 0,
+                len: 3096,
+            },
+        ];
+        let stored = encode_manifest(&segments);
+        assert_eq!(decode_manifest(&stored).unwrap(), segments);
+        assert!(decode_manifest(&stored[..stored.len() - 1]).is_err());
+        assert!(ChunkerConfig::new(3000).is_err());
+    }
+}
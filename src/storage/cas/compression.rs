@@ -0,0 +1,322 @@
+//! Block compression for the CAS backend
+//!
+//! Every stored block starts with a marker byte naming its encoding, so
+//! blocks written under different compression settings coexist in one
+//! blob store and always decode regardless of the current setting.
+//!
+//! Dictionary-compressed blocks also carry the ID of their dictionary, and
+//! every dictionary a store has used is kept with it (see
+//! `keep_dictionary`), so rotating the dictionary or changing compression
+//! leaves earlier blocks readable.
+
+use crate::storage::{sync_parent_dir, StorageError, StorageResult};
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zstd::dict::{DecoderDictionary, EncoderDictionary};
+use zstd::zstd_safe;
+
+/// Stored as-is
+pub const MARKER_RAW: u8 = 0x00;
+/// lz4 with prepended size
+pub const MARKER_LZ4: u8 = 0x01;
+/// Plain zstd frame
+pub const MARKER_ZSTD: u8 = 0x02;
+/// Big-endian dictionary ID, then a zstd frame compressed with that dictionary
+pub const MARKER_ZSTD_DICT_ID: u8 = 0x04;
+// 0x10 marks a chunk manifest rather than data; see `chunking`
+
+/// Default zstd compression level
+pub const DEFAULT_ZSTD_LEVEL: i32 = 3;
+
+/// Compression applied to newly written blocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    None,
+    #[default]
+    Lz4,
+    Zstd { level: i32 },
+}
+
+/// Encodes and decodes CAS blocks
+#[derive(Clone, Default)]
+pub struct BlockCodec {
+    compression: Compression,
+    /// Dictionaries by zstd dictionary ID
+    dictionaries: HashMap<u32, Dictionary>,
+    /// Dictionary used for new zstd blocks
+    active_dictionary: Option<u32>,
+    /// The active dictionary digested at the current zstd level
+    encoder: Option<Arc<EncoderDictionary<'static>>>,
+}
+
+/// A zstd dictionary and its digested form for decoding, so neither
+/// direction digests it again per block
+#[derive(Clone)]
+struct Dictionary {
+    bytes: Vec<u8>,
+    decoder: Arc<DecoderDictionary<'static>>,
+}
+
+impl BlockCodec {
+    /// Create a codec writing blocks with the given compression
+    pub fn new(compression: Compression) -> Self {
+        Self {
+            compression,
+            ..Default::default()
+        }
+    }
+
+    /// Compression used for new blocks
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+
+    /// Change the compression used for new blocks
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.compression = compression;
+        self.prepare_encoder();
+    }
+
+    /// Register a zstd dictionary and use it for new zstd blocks.
+    ///
+    /// Previously added dictionaries stay available for decoding.
+    pub fn add_dictionary(&mut self, dictionary: Vec<u8>) -> StorageResult<u32> {
+        let id = self.insert_dictionary(dictionary)?;
+        self.active_dictionary = Some(id);
+        self.prepare_encoder();
+        Ok(id)
+    }
+
+    /// Digest the active dictionary for the zstd level in use
+    fn prepare_encoder(&mut self) {
+        self.encoder = match (self.compression, self.active_dictionary) {
+            (Compression::Zstd { level }, Some(id)) => {
+                let bytes = &self.dictionaries[&id].bytes;
+                Some(Arc::new(EncoderDictionary::copy(bytes, level)))
+            }
+            _ => None,
+        };
+    }
+
+    /// Make every dictionary kept in `dir` available for decoding, without
+    /// using any for new blocks
+    pub fn load_dictionaries(&mut self, dir: &Path) -> StorageResult<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "dict") {
+                self.insert_dictionary(fs::read(&path)?)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_dictionary(&mut self, dictionary: Vec<u8>) -> StorageResult<u32> {
+        let id = dictionary_id(&dictionary)?;
+        match self.dictionaries.get(&id) {
+            Some(existing) if existing.bytes != dictionary => Err(StorageError::Backend(format!(
+                "zstd dictionary {:08x} differs from the one stored under that ID; \
+                 retrain it",
+                id
+            ))),
+            Some(_) => Ok(id),
+            None => {
+                let decoder = Arc::new(DecoderDictionary::copy(&dictionary));
+                let bytes = dictionary;
+                self.dictionaries.insert(id, Dictionary { bytes, decoder });
+                Ok(id)
+            }
+        }
+    }
+
+    /// Encode a block, prefixed with its marker byte.
+    ///
+    /// Falls back to storing raw when compression doesn't shrink the block.
+    pub fn encode(&self, data: &[u8]) -> StorageResult<Vec<u8>> {
+        let compressed = match self.compression {
+            Compression::None => None,
+            Compression::Lz4 => Some((MARKER_LZ4, lz4_flex::compress_prepend_size(data))),
+            Compression::Zstd { level } => Some(self.encode_zstd(data, level)?),
+        };
+
+        let (marker, payload) = match compressed {
+            Some((marker, payload)) if payload.len() < data.len() => (marker, payload),
+            _ => (MARKER_RAW, data.to_vec()),
+        };
+
+        let mut encoded = Vec::with_capacity(payload.len() + 1);
+        encoded.push(marker);
+        encoded.extend_from_slice(&payload);
+        Ok(encoded)
+    }
+
+    fn encode_zstd(&self, data: &[u8], level: i32) -> StorageResult<(u8, Vec<u8>)> {
+        match (self.active_dictionary, &self.encoder) {
+            (Some(id), Some(encoder)) => {
+                let mut compressor = zstd::bulk::Compressor::with_prepared_dictionary(encoder)?;
+                let mut payload = id.to_be_bytes().to_vec();
+                payload.extend_from_slice(&compressor.compress(data)?);
+                Ok((MARKER_ZSTD_DICT_ID, payload))
+            }
+            _ => Ok((MARKER_ZSTD, zstd::bulk::compress(data, level)?)),
+        }
+    }
+
+    /// Decode a stored block of at most `block_size` bytes
+    pub fn decode(&self, stored: &[u8], block_size: usize) -> StorageResult<Vec<u8>> {
+        let (&marker, payload) = stored.split_first().ok_or(StorageError::Corrupted)?;
+
+        match marker {
+            MARKER_RAW => Ok(payload.to_vec()),
+            MARKER_LZ4 => {
+                lz4_flex::decompress_size_prepended(payload).map_err(|_| StorageError::Corrupted)
+            }
+            MARKER_ZSTD => {
+                zstd::bulk::decompress(payload, block_size).map_err(|_| StorageError::Corrupted)
+            }
+            MARKER_ZSTD_DICT_ID => {
+                let (id, frame) = payload
+                    .split_first_chunk::<4>()
+                    .ok_or(StorageError::Corrupted)?;
+                self.decode_with_dictionary(u32::from_be_bytes(*id), frame, block_size)
+            }
+            _ => Err(StorageError::Corrupted),
+        }
+    }
+
+    fn decode_with_dictionary(
+        &self,
+        id: u32,
+        frame: &[u8],
+        block_size: usize,
+    ) -> StorageResult<Vec<u8>> {
+        let dictionary = self
+            .dictionaries
+            .get(&id)
+            .ok_or_else(|| StorageError::Backend(format!("missing zstd dictionary {:08x}", id)))?;
+        zstd::bulk::Decompressor::with_prepared_dictionary(&dictionary.decoder)?
+            .decompress(frame, block_size)
+            .map_err(|_| StorageError::Corrupted)
+    }
+}
+
+/// ID a zstd dictionary is known by
+fn dictionary_id(dictionary: &[u8]) -> StorageResult<u32> {
+    zstd_safe::get_dict_id_from_dict(dictionary)
+        .map(|id| id.get())
+        .ok_or_else(|| StorageError::Backend("not a zstd dictionary".to_string()))
+}
+
+/// Keep `dictionary` in `dir` under its ID, so blocks compressed with it
+/// can be read after it stops being the configured one
+pub fn keep_dictionary(dir: &Path, dictionary: &[u8]) -> StorageResult<()> {
+    let path = dir.join(format!("{:08x}.dict", dictionary_id(dictionary)?));
+    if path.exists() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)?;
+    let tmp = path.with_extension("tmp");
+    {
+        let mut file = fs::File::create(&tmp)?;
+        file.write_all(dictionary)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp, &path)?;
+    sync_parent_dir(&path)?;
+    Ok(())
+}
+
+/// Directory of kept dictionaries for the store whose snapshots file is
+/// `snapshot_path`
+pub fn dictionary_dir(snapshot_path: &Path) -> PathBuf {
+    snapshot_path.with_extension("dicts")
+}
+
+/// Train a zstd dictionary from sample blocks (e.g. sectors read from a disk)
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> StorageResult<Vec<u8>> {
+    zstd::dict::from_samples(samples, max_size)
+        .map_err(|e| StorageError::Backend(format!("dictionary training failed: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_block(i: usize) -> Vec<u8> {
+        // Small, similar blocks: a common header with a varying record
+        let mut block = format!("inode {:06} mode 0644 uid 1000 gid 1000 ", i).into_bytes();
+        block.extend_from_slice(&[(i % 7) as u8; 64]);
+        block.resize(512, b' ');
+        block
+    }
+
+    #[test]
+    fn test_codecs_roundtrip_and_coexist() {
+        let data = sample_block(1);
+        let lz4 = BlockCodec::new(Compression::Lz4).encode(&data).unwrap();
+        let zstd = BlockCodec::new(Compression::Zstd { level: 3 }).encode(&data).unwrap();
+        let raw = BlockCodec::new(Compression::None).encode(&data).unwrap();
+        assert_eq!(lz4[0], MARKER_LZ4);
+        assert_eq!(zstd[0], MARKER_ZSTD);
+        assert_eq!(raw[0], MARKER_RAW);
+
+        // Any codec decodes blocks written by any other
+        let reader = BlockCodec::new(Compression::None);
+        for stored in [lz4, zstd, raw] {
+            assert_eq!(reader.decode(&stored, 512).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_zstd_dictionary() {
+        let samples: Vec<Vec<u8>> = (0..500).map(sample_block).collect();
+        let dictionary = train_dictionary(&samples, 4096).unwrap();
+
+        let mut codec = BlockCodec::new(Compression::Zstd { level: 3 });
+        codec.add_dictionary(dictionary).unwrap();
+
+        let data = sample_block(1234);
+        let with_dict = codec.encode(&data).unwrap();
+        let plain = BlockCodec::new(Compression::Zstd { level: 3 }).encode(&data).unwrap();
+        assert_eq!(with_dict[0], MARKER_ZSTD_DICT_ID);
+        assert!(with_dict.len() < plain.len());
+        assert_eq!(codec.decode(&with_dict, 512).unwrap(), data);
+
+        // Without the dictionary the block can't be read
+        let result = BlockCodec::default().decode(&with_dict, 512);
+        assert!(matches!(result, Err(StorageError::Backend(_))));
+    }
+
+    #[test]
+    fn test_rotated_dictionary_stays_readable() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let dir = dictionary_dir(&temp.path().join("snapshots.json"));
+        let samples: Vec<Vec<u8>> = (0..500).map(sample_block).collect();
+        let old = train_dictionary(&samples, 4096).unwrap();
+        let new = train_dictionary(&samples[..300], 2048).unwrap();
+
+        let mut codec = BlockCodec::new(Compression::Zstd { level: 3 });
+        codec.add_dictionary(old.clone()).unwrap();
+        keep_dictionary(&dir, &old).unwrap();
+        let data = sample_block(1234);
+        let stored = codec.encode(&data).unwrap();
+
+        // Rotated to a new dictionary, then to lz4: the old one is loaded
+        // from the store's kept dictionaries
+        let mut codec = BlockCodec::new(Compression::Zstd { level: 3 });
+        codec.load_dictionaries(&dir).unwrap();
+        codec.add_dictionary(new).unwrap();
+        assert_eq!(codec.decode(&stored, 512).unwrap(), data);
+
+        let mut codec = BlockCodec::new(Compression::Lz4);
+        codec.load_dictionaries(&dir).unwrap();
+        assert_eq!(codec.decode(&stored, 512).unwrap(), data);
+    }
+}
@@ -0,0 +1,238 @@
+//! Root hash journal for CAS backend
+//!
+//! Append-only log of root transitions. Every write appends the new root;
+//! flush fsyncs the log after the blob store, so a root that was flushed
+//! survives a crash. On open, the last intact record is the committed root.
+//!
+//! Record format: 32-byte root hash followed by the first 8 bytes of its
+//! BLAKE3 hash, so a torn write at the tail is detected and dropped.
+
+use crate::blob::Hash;
+use crate::storage::sync_parent_dir;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const RECORD_SIZE: usize = 40;
+
+/// Rewrite the journal down to one record once it holds this many
+const COMPACT_THRESHOLD: u64 = 4096;
+
+/// When a CAS backend makes its root durable for restarts
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PersistRoot {
+    /// Journal every root, synced on each flush
+    #[default]
+    OnFlush,
+    /// As `OnFlush`, and also sync a write's root once this long has passed
+    /// since the last sync, for initiators that seldom flush
+    Interval(Duration),
+    /// No journal: a restart resumes from the last snapshot taken or restored
+    Manual,
+}
+
+/// Write-ahead journal of root hash updates
+pub struct RootJournal {
+    path: PathBuf,
+    file: File,
+    /// Records currently in the file
+    records: u64,
+    /// Last appended root
+    last: Option<Hash>,
+}
+
+impl RootJournal {
+    /// Open (or create) a journal, returning it with the last committed root
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<(Self, Option<Hash>)> {
+        let path = path.as_ref().to_path_buf();
+        let created = !path.exists();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        if created {
+            sync_parent_dir(&path)?;
+        }
+
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        let (records, last) = intact_records(&content);
+
+        // Drop a torn or corrupt tail so new records follow intact ones
+        let valid_len = records * RECORD_SIZE as u64;
+        if valid_len != content.len() as u64 {
+            log::warn!(
+                "Root journal {}: discarding {} bytes of incomplete records",
+                path.display(),
+                content.len() as u64 - valid_len
+            );
+            file.set_len(valid_len)?;
+            file.sync_data()?;
+        }
+
+        let journal = Self {
+            path,
+            file,
+            records,
+            last,
+        };
+        Ok((journal, last))
+    }
+
+    /// Append a root transition (durable after the next `sync`)
+    pub fn append(&mut self, root: Hash) -> io::Result<()> {
+        if self.last == Some(root) {
+            return Ok(());
+        }
+
+        self.file.write_all(&encode_record(&root))?;
+        self.records += 1;
+        self.last = Some(root);
+        Ok(())
+    }
+
+    /// Make appended records durable, compacting the log if it has grown
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.sync_data()?;
+
+        if self.records >= COMPACT_THRESHOLD {
+            if let Some(root) = self.last {
+                self.compact(root)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop every record, so the next open finds no root
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.records = 0;
+        self.last = None;
+        Ok(())
+    }
+
+    /// Replace the log with a single record for `root`
+    fn compact(&mut self, root: Hash) -> io::Result<()> {
+        let tmp_path = self.path.with_extension("tmp");
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(&encode_record(&root))?;
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+        // Until the directory is synced, a crash can bring the old log back
+        sync_parent_dir(&self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = 1;
+        Ok(())
+    }
+}
+
+/// The last committed root in the journal at `path`, without opening it
+/// for appends or dropping a torn tail, so a running backend is undisturbed
+pub fn read_root<P: AsRef<Path>>(path: P) -> io::Result<Option<Hash>> {
+    match fs::read(path) {
+        Ok(content) => Ok(intact_records(&content).1),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Number of intact records at the start of `content`, and the last one
+fn intact_records(content: &[u8]) -> (u64, Option<Hash>) {
+    let mut last = None;
+    let mut records = 0;
+    for record in content.chunks_exact(RECORD_SIZE) {
+        match decode_record(record) {
+            Some(root) => {
+                last = Some(root);
+                records += 1;
+            }
+            None => break,
+        }
+    }
+    (records, last)
+}
+
+fn encode_record(root: &Hash) -> [u8; RECORD_SIZE] {
+    let mut record = [0u8; RECORD_SIZE];
+    record[..32].copy_from_slice(root.as_bytes());
+    record[32..].copy_from_slice(&blake3::hash(root.as_bytes()).as_bytes()[..8]);
+    record
+}
+
+fn decode_record(record: &[u8]) -> Option<Hash> {
+    let mut root = [0u8; 32];
+    root.copy_from_slice(&record[..32]);
+    let checksum = blake3::hash(&root);
+    (checksum.as_bytes()[..8] == record[32..]).then(|| Hash::from_bytes(root))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_journal_replay() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("roots.wal");
+
+        let (mut journal, last) = RootJournal::open(&path).unwrap();
+        assert_eq!(last, None);
+        journal.append(Hash::from_data(b"one")).unwrap();
+        journal.append(Hash::from_data(b"two")).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+
+        let (_, last) = RootJournal::open(&path).unwrap();
+        assert_eq!(last, Some(Hash::from_data(b"two")));
+    }
+
+    #[test]
+    fn test_journal_torn_tail() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("roots.wal");
+
+        let (mut journal, _) = RootJournal::open(&path).unwrap();
+        journal.append(Hash::from_data(b"committed")).unwrap();
+        journal.sync().unwrap();
+        drop(journal);
+
+        // Simulate a crash mid-append
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0xAB; 17]).unwrap();
+        drop(file);
+
+        assert_eq!(read_root(&path).unwrap(), Some(Hash::from_data(b"committed")));
+        let (mut journal, last) = RootJournal::open(&path).unwrap();
+        assert_eq!(last, Some(Hash::from_data(b"committed")));
+
+        // New records land after the last intact one
+        journal.append(Hash::from_data(b"next")).unwrap();
+        drop(journal);
+        let (_, last) = RootJournal::open(&path).unwrap();
+        assert_eq!(last, Some(Hash::from_data(b"next")));
+    }
+
+    #[test]
+    fn test_journal_compaction() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("roots.wal");
+
+        let (mut journal, _) = RootJournal::open(&path).unwrap();
+        for i in 0..COMPACT_THRESHOLD {
+            journal.append(Hash::from_data(&i.to_le_bytes())).unwrap();
+        }
+        journal.sync().unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), RECORD_SIZE as u64);
+
+        let last_root = Hash::from_data(&(COMPACT_THRESHOLD - 1).to_le_bytes());
+        let (_, last) = RootJournal::open(&path).unwrap();
+        assert_eq!(last, Some(last_root));
+    }
+}
@@ -2,35 +2,176 @@
 //!
 //! Implements BlockStorage using a Merkle tree structure with content-addressed
 //! block storage. Provides automatic deduplication and snapshot capabilities.
-
+//!
+//! Each tree leaf points at one data block. By default a block is one
+//! sector, which is how every store was laid out before the block size
+//! became configurable. Larger blocks (up to 64 KiB) cut the tree and
+//! per-blob overhead and let compression see more data at once; writes
+//! smaller than a block read, modify and rewrite the whole block. The
+//! block size decides the tree layout, so a store must always be opened
+//! with the block size it was written with. To move an existing store to
+//! larger blocks, create a new target with the new block size and copy the
+//! device across through a front-end (for example with `dd`).
+//!
+//! Archival targets can also chunk their writes by content (see
+//! `chunking`), so data that shifts between writes still dedups, and any
+//! target can store blocks that nearly match a stored one as deltas (see
+//! `similarity`).
+
+mod chunking;
+mod compression;
+mod journal;
+mod pool;
+mod readahead;
+mod similarity;
 mod snapshot;
+mod stats;
 mod tree;
 
+pub use chunking::{ChunkerConfig, DEFAULT_AVG_CHUNK};
+pub use compression::{
+    dictionary_dir, keep_dictionary, train_dictionary, BlockCodec, Compression,
+    DEFAULT_ZSTD_LEVEL,
+};
+pub use journal::{read_root, PersistRoot, RootJournal};
+pub use readahead::{request_prefetch, spawn_prefetcher, SequentialDetector};
+pub use similarity::DEFAULT_INDEX_BLOCKS;
 pub use snapshot::SnapshotManager;
-pub use tree::{calculate_depth, MerkleTree, MerkleTreeMut, BLOCK_SIZE, FANOUT};
+pub use stats::DedupStats;
+pub use tree::{
+    calculate_depth, node_children, MerkleTree, MerkleTreeMut, NodeCache, BLOCK_SIZE,
+    DEFAULT_NODE_CACHE_NODES, FANOUT,
+};
 
-use crate::blob::{BlobStore, Hash};
+use crate::blob::{BlobError, BlobStore, Hash, HashAlgorithm};
 use crate::storage::{
-    ArchivalStorage, BlockStorage, DeviceInfo, SnapshotInfo, StorageError, StorageResult,
+    is_all_zero, ArchivalStorage, BlockStorage, DeviceInfo, DeviceStrings, Geometry,
+    RetentionRule, SnapshotInfo, StorageError, StorageResult, TargetUuid, UsageStats,
 };
-use std::path::Path;
-use std::sync::Mutex;
+use pool::WritePool;
+use chunking::{decode_manifest, encode_manifest, referenced_chunks, Segment, MARKER_MANIFEST};
+use readahead::Readahead;
+use similarity::{apply_delta, delta_base, encode_delta, sketch, SimilarityIndex, Sketch};
+use similarity::MARKER_DELTA;
+use stats::StatsCounters;
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::Instant;
+
+/// Blocks read per tree update when ingesting an image
+const INGEST_BATCH_BLOCKS: usize = 1024;
+
+/// Blocks per tree update when zeroing a range, bounding memory use
+const ZEROES_BATCH_BLOCKS: usize = FANOUT * FANOUT;
+
+/// Largest supported data block
+pub const MAX_BLOCK_SIZE: u32 = 64 * 1024;
+
+/// A delta is kept only if it's at most this fraction of the block
+const MAX_DELTA_FRACTION: usize = 4;
+
+/// Least data worth handing a write thread; below this, starting a thread
+/// costs more than the encoding it saves
+const MIN_BYTES_PER_THREAD: usize = 32 * 1024;
+
+/// A block ready to store: its hash, stored form, and sketch if it's to be
+/// offered as a base for similar blocks
+type EncodedBlock = (Hash, Vec<u8>, Option<Sketch>);
+
+/// Whether `block_size` is usable with `sector_size`: a power of two from
+/// the sector size up to `MAX_BLOCK_SIZE`
+pub fn is_valid_block_size(block_size: u32, sector_size: u32) -> bool {
+    block_size.is_power_of_two() && block_size >= sector_size && block_size <= MAX_BLOCK_SIZE
+}
 
 /// Content-Addressed Storage backend
 ///
 /// Uses a Merkle tree to map LBAs to content hashes, with automatic
 /// deduplication through the underlying blob store.
+///
+/// Readers work against the root hash current when they start, so they run
+/// concurrently with each other and with a writer. Writers are serialized
+/// and publish the new root only once the tree update is complete.
 pub struct CasBackend {
     /// Blob store for actual data
-    blob_store: Box<dyn BlobStore>,
+    blob_store: Arc<dyn BlobStore>,
     /// Current root hash
-    root_hash: Mutex<Hash>,
+    root_hash: RwLock<Hash>,
+    /// Serializes writers (tree updates are read-modify-write)
+    write_lock: Mutex<()>,
     /// Device information
     info: DeviceInfo,
     /// Snapshot manager
     snapshots: Mutex<SnapshotManager>,
-    /// Whether to compress data
-    compress: bool,
+    /// Applied after each new snapshot
+    retention: Vec<RetentionRule>,
+    /// Block compression, shared with the write threads
+    codec: Arc<BlockCodec>,
+    /// Where every dictionary the store has used is kept
+    dictionary_dir: PathBuf,
+    /// Write-path dedup counters
+    stats: StatsCounters,
+    /// Root transitions, replayed on startup (None for explicit roots)
+    journal: Option<Mutex<RootJournal>>,
+    /// When the root is made durable
+    persist_root: PersistRoot,
+    /// Last time the journal was synced
+    root_synced: Mutex<Instant>,
+    /// Recently used tree nodes
+    node_cache: NodeCache,
+    /// Data block size in bytes (None: one sector per block)
+    block_size: Option<u32>,
+    /// Content-defined chunking of whole blocks, for archival targets
+    chunker: Option<ChunkerConfig>,
+    /// Allocated leaves of the current tree
+    allocated: Mutex<AllocatedCount>,
+    /// Prefetching for sequential reads
+    readahead: Option<Readahead>,
+    /// Recently stored blocks, for storing similar ones as deltas
+    similarity: Option<SimilarityIndex>,
+    /// Hash the blob store keys blobs by
+    hash_algorithm: HashAlgorithm,
+    /// Threads compressing and hashing the blocks of a large write (None:
+    /// on the writer's thread)
+    write_pool: Option<WritePool>,
+    /// Geometry recorded for the store, if any
+    geometry: Option<Geometry>,
+    /// Where the geometry is recorded (None for explicit roots)
+    geometry_path: Option<PathBuf>,
+    /// Set once the record matches, so writes needn't check it again
+    geometry_recorded: AtomicBool,
+}
+
+/// Allocated leaves of the current tree, kept up to date by commits once
+/// counted
+#[derive(Default)]
+struct AllocatedCount {
+    count: Option<u64>,
+    /// Leaves committed since a count in progress took its root
+    pending: Option<i64>,
+    /// Bumped when the root is replaced outright, voiding counts in progress
+    generation: u64,
+}
+
+impl AllocatedCount {
+    fn add(&mut self, delta: i64) {
+        if let Some(count) = self.count.as_mut() {
+            *count = count.saturating_add_signed(delta);
+        } else if let Some(pending) = self.pending.as_mut() {
+            *pending += delta;
+        }
+    }
+
+    /// Forget the count, to be taken again when next asked for
+    fn reset(&mut self) {
+        self.count = None;
+        self.pending = None;
+        self.generation += 1;
+    }
 }
 
 impl CasBackend {
@@ -43,24 +184,65 @@ impl CasBackend {
         let snapshots = SnapshotManager::new(snapshot_path)
             .map_err(|e| StorageError::Backend(format!("failed to load snapshots: {}", e)))?;
 
-        // Try to load from latest snapshot, or start fresh
-        let root_hash = snapshots.latest().unwrap_or(Hash::ZERO);
+        let (journal, journaled_root) = RootJournal::open(journal_path(snapshot_path))
+            .map_err(|e| StorageError::Backend(format!("failed to open root journal: {}", e)))?;
+
+        // Resume from the last committed root, else the last snapshot taken
+        // or restored, else fresh
+        let root_hash = journaled_root
+            .or_else(|| snapshots.head())
+            .unwrap_or(Hash::ZERO);
+
+        // Stores written before identities were kept keep their serial
+        let existing = root_hash != Hash::ZERO || snapshot_path.exists();
+        let uuid = TargetUuid::load_or_create(&identity_path(snapshot_path), || {
+            if existing {
+                TargetUuid::from_legacy_serial(hash_path(snapshot_path))
+            } else {
+                TargetUuid::generate()
+            }
+        })
+        .map_err(|e| StorageError::Backend(format!("failed to load target identity: {}", e)))?;
 
-        let info = DeviceInfo {
+        let mut info = DeviceInfo {
             model: "AoE CAS Backend".to_string(),
-            serial: format!("{:016X}", hash_path(snapshot_path)),
+            serial: uuid.serial(),
             firmware: env!("CARGO_PKG_VERSION").to_string(),
             total_sectors,
             sector_size: 512,
             lba48: true,
+            uuid: Some(uuid),
         };
+        let geometry = load_geometry(snapshot_path, &mut info)?;
+        if let Some(geometry) = &geometry {
+            geometry.check_size(info.size_bytes())?;
+        }
 
+        let hash_algorithm = blob_store.hash_algorithm();
         Ok(Self {
-            blob_store,
-            root_hash: Mutex::new(root_hash),
+            blob_store: Arc::from(blob_store),
+            root_hash: RwLock::new(root_hash),
+            write_lock: Mutex::new(()),
             info,
             snapshots: Mutex::new(snapshots),
-            compress: true,
+            retention: Vec::new(),
+            codec: Arc::new(load_codec(snapshot_path)?),
+            dictionary_dir: dictionary_dir(snapshot_path),
+            stats: StatsCounters::default(),
+            journal: Some(Mutex::new(journal)),
+            persist_root: PersistRoot::default(),
+            root_synced: Mutex::new(Instant::now()),
+            node_cache: NodeCache::new(DEFAULT_NODE_CACHE_NODES),
+            block_size: geometry.and_then(|geometry| geometry.block_size),
+            chunker: None,
+            allocated: Mutex::new(AllocatedCount::default()),
+            readahead: None,
+            similarity: None,
+            hash_algorithm,
+            write_pool: None,
+            geometry,
+            geometry_path: Some(geometry_path(snapshot_path)),
+            geometry_recorded: AtomicBool::new(false),
         })
     }
 
@@ -74,91 +256,787 @@ impl CasBackend {
         let snapshots = SnapshotManager::new(snapshot_path)
             .map_err(|e| StorageError::Backend(format!("failed to load snapshots: {}", e)))?;
 
-        let info = DeviceInfo {
+        // Restoring reads an existing store, which keeps its identity
+        let uuid = TargetUuid::load(&identity_path(snapshot_path))
+            .map_err(|e| StorageError::Backend(format!("failed to load target identity: {}", e)))?;
+
+        let mut info = DeviceInfo {
             model: "AoE CAS Backend".to_string(),
-            serial: format!("{:016X}", hash_path(snapshot_path)),
+            serial: uuid.map_or_else(
+                || format!("{:016X}", hash_path(snapshot_path)),
+                |uuid| uuid.serial(),
+            ),
             firmware: env!("CARGO_PKG_VERSION").to_string(),
             total_sectors,
             sector_size: 512,
             lba48: true,
+            uuid,
         };
+        let geometry = load_geometry(snapshot_path, &mut info)?;
 
+        let hash_algorithm = blob_store.hash_algorithm();
         Ok(Self {
-            blob_store,
-            root_hash: Mutex::new(root_hash),
+            blob_store: Arc::from(blob_store),
+            root_hash: RwLock::new(root_hash),
+            write_lock: Mutex::new(()),
             info,
             snapshots: Mutex::new(snapshots),
-            compress: true,
+            retention: Vec::new(),
+            codec: Arc::new(load_codec(snapshot_path)?),
+            dictionary_dir: dictionary_dir(snapshot_path),
+            stats: StatsCounters::default(),
+            journal: None,
+            persist_root: PersistRoot::default(),
+            root_synced: Mutex::new(Instant::now()),
+            node_cache: NodeCache::new(DEFAULT_NODE_CACHE_NODES),
+            block_size: geometry.and_then(|geometry| geometry.block_size),
+            chunker: None,
+            allocated: Mutex::new(AllocatedCount::default()),
+            readahead: None,
+            similarity: None,
+            hash_algorithm,
+            write_pool: None,
+            geometry,
+            geometry_path: None,
+            geometry_recorded: AtomicBool::new(false),
+        })
+    }
+
+    /// Use a different logical sector size (512 or 4096), keeping capacity.
+    ///
+    /// The tree is laid out in sectors, so a store keeps the sector size
+    /// it was first written with (recorded beside its snapshots); a
+    /// different one is refused.
+    pub fn with_sector_size(mut self, sector_size: u32) -> StorageResult<Self> {
+        if let Some(geometry) = &self.geometry {
+            geometry.check_sector_size(sector_size)?;
+        }
+        self.info.set_sector_size(sector_size);
+        Ok(self)
+    }
+
+    /// Report `strings` in place of the generated model, serial and firmware
+    pub fn with_device_strings(mut self, strings: &DeviceStrings) -> Self {
+        self.info.set_strings(strings);
+        self
+    }
+
+    /// Store data in blocks of `block_size` bytes; set after the sector size.
+    ///
+    /// The tree layout depends on it, so a store keeps the block size it
+    /// was first written with (recorded with its sector size) and opens
+    /// with it; a different one is refused.
+    pub fn with_block_size(mut self, block_size: u32) -> StorageResult<Self> {
+        if !is_valid_block_size(block_size, self.info.sector_size) {
+            return Err(StorageError::Backend(format!(
+                "invalid CAS block size {}: must be a power of two from {} to {}",
+                block_size, self.info.sector_size, MAX_BLOCK_SIZE
+            )));
+        }
+        if let Some(geometry) = &self.geometry {
+            geometry.check_block_size(block_size)?;
+        }
+        self.block_size = Some(block_size);
+        Ok(self)
+    }
+
+    /// Data block size in bytes
+    pub fn block_size(&self) -> u32 {
+        self.block_size.unwrap_or(self.info.sector_size)
+    }
+
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size() / self.info.sector_size) as u64
+    }
+
+    /// Number of tree leaves; the last block may be partly past the end
+    fn total_blocks(&self) -> u64 {
+        self.info.total_sectors.div_ceil(self.sectors_per_block())
+    }
+
+    /// Split a sector range into (block, first, end) runs, with `first`
+    /// and `end` the sector offsets covered within the block
+    fn block_runs(&self, lba: u64, count: u64) -> impl Iterator<Item = (u64, u64, u64)> {
+        let per_block = self.sectors_per_block();
+        let end = lba + count;
+        let mut sector = lba;
+        std::iter::from_fn(move || {
+            if sector >= end {
+                return None;
+            }
+            let block = sector / per_block;
+            let start = block * per_block;
+            let run = (block, sector - start, end.min(start + per_block) - start);
+            sector = start + run.2;
+            Some(run)
         })
     }
 
-    /// Store a data block, optionally with compression
+    /// Hash of block `block` with `data` written at byte `offset`, reading
+    /// the rest of the block from `tree`
+    fn modify_block(
+        &self,
+        tree: &MerkleTreeMut,
+        block: u64,
+        offset: usize,
+        data: &[u8],
+    ) -> StorageResult<Hash> {
+        let old_hash = tree
+            .lookup(block)
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let mut whole = self.retrieve_block(&old_hash)?;
+        whole[offset..offset + data.len()].copy_from_slice(data);
+        self.store_block(&whole)
+    }
+
+    /// Chunk whole-block writes by content instead of storing each block.
+    ///
+    /// Meant for larger block sizes: every chunked block costs a manifest
+    /// blob. Blocks already stored either way stay readable.
+    pub fn with_chunking(mut self, chunker: ChunkerConfig) -> Self {
+        self.chunker = Some(chunker);
+        self
+    }
+
+    /// Store whole blocks that differ from a recently stored one by a few
+    /// bytes as deltas against it, indexing the last `index_blocks` blocks.
+    /// Deltas stay readable if this is turned off again.
+    pub fn with_similarity(mut self, index_blocks: usize) -> Self {
+        self.similarity = Some(SimilarityIndex::new(index_blocks));
+        self
+    }
+
+    /// How the root hash survives restarts (default `PersistRoot::OnFlush`).
+    ///
+    /// `Manual` stops journaling roots, so the backend starts from the
+    /// snapshot last taken or restored and writes since then are lost on
+    /// restart. The journal is emptied, so a later restart with journaling
+    /// back on starts from that snapshot too, not from a stale root.
+    pub fn with_persist_root(mut self, persist_root: PersistRoot) -> Self {
+        let journal = match persist_root {
+            PersistRoot::Manual => self.journal.take(),
+            _ => None,
+        };
+        if let Some(journal) = journal {
+            if let Err(e) = journal.into_inner().unwrap().clear() {
+                log::warn!("Cannot empty the root journal: {}", e);
+            }
+            let head = self.snapshots.lock().unwrap().head();
+            *self.root_hash.get_mut().unwrap() = head.unwrap_or(Hash::ZERO);
+            self.allocated.get_mut().unwrap().reset();
+        }
+        self.persist_root = persist_root;
+        self
+    }
+
+    /// Prune snapshots by these rules whenever a snapshot is taken
+    pub fn with_retention(mut self, rules: Vec<RetentionRule>) -> Self {
+        self.retention = rules;
+        self
+    }
+
+    /// Compress and hash the blocks of large writes (and ingests) on up to
+    /// `threads` threads, so one sequential stream can use several cores
+    /// (default 1: on the writer's thread)
+    pub fn with_write_threads(mut self, threads: usize) -> Self {
+        self.write_pool = match threads {
+            0 | 1 => None,
+            threads => match WritePool::new("cas-encode", threads) {
+                Ok(pool) => Some(pool),
+                Err(e) => {
+                    log::warn!("Encoding writes on the writer's thread: {}", e);
+                    None
+                }
+            },
+        };
+        self
+    }
+
+    /// Prefetch the blobs of the next `blocks` blocks in the background
+    /// when reads turn sequential (0 disables readahead)
+    pub fn with_readahead(mut self, blocks: usize) -> Self {
+        self.readahead = (blocks > 0).then(|| Readahead::new(Arc::clone(&self.blob_store), blocks));
+        self
+    }
+
+    /// Deduplication statistics for writes since the backend was opened
+    pub fn stats(&self) -> DedupStats {
+        self.stats.snapshot()
+    }
+
+    /// Compression for newly written blocks (existing blocks stay readable)
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        Arc::make_mut(&mut self.codec).set_compression(compression);
+        self
+    }
+
+    /// Compress new zstd blocks with a trained dictionary.
+    ///
+    /// Small blocks rarely shrink on their own; a shared dictionary lets
+    /// zstd exploit what similar blocks have in common.
+    /// The dictionary is kept with the store, so blocks compressed with it
+    /// stay readable after it is replaced or compression changes.
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> StorageResult<Self> {
+        keep_dictionary(&self.dictionary_dir, &dictionary)?;
+        Arc::make_mut(&mut self.codec).add_dictionary(dictionary)?;
+        Ok(self)
+    }
+
+    /// Record a root transition in the journal (caller holds write_lock)
+    fn journal_root(&self, root: Hash) -> StorageResult<()> {
+        self.record_geometry()?;
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .unwrap()
+                .append(root)
+                .map_err(|e| StorageError::Backend(format!("root journal append failed: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Record the geometry and capacity before the first root that depends
+    /// on them
+    fn record_geometry(&self) -> StorageResult<()> {
+        if self.geometry_recorded.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        let current = Geometry {
+            sector_size: self.info.sector_size,
+            block_size: Some(self.block_size()),
+            size_bytes: Some(self.info.size_bytes()),
+        };
+        // Records from before the block size or capacity was kept get them
+        // added, and a grown store its new capacity
+        if let (true, Some(path)) = (self.geometry != Some(current), &self.geometry_path) {
+            current.save(path).map_err(|e| {
+                StorageError::Backend(format!("failed to record store geometry: {}", e))
+            })?;
+        }
+        self.geometry_recorded.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Copy a raw disk image into the device from LBA 0, e.g. to convert a
+    /// file target. Blocks are stored as by writes (zero blocks stay sparse,
+    /// the rest are compressed and deduplicated) and go into the tree in
+    /// large batches. Returns the number of bytes read; an image that is
+    /// not a whole number of sectors is padded with zeros.
+    pub fn ingest_from<R: Read>(&self, mut reader: R) -> StorageResult<u64> {
+        let block_size = self.block_size() as usize;
+        let sector_size = self.info.sector_size as usize;
+        let capacity = self.info.size_bytes();
+        let mut buffer = vec![0u8; INGEST_BATCH_BLOCKS * block_size];
+        let mut ingested = 0u64;
+        let mut block = 0u64;
+
+        loop {
+            let len = read_full(&mut reader, &mut buffer)?;
+            if len == 0 {
+                break;
+            }
+            if ingested + len as u64 > capacity {
+                return Err(StorageError::OutOfRange {
+                    lba: (ingested + len as u64).div_ceil(sector_size as u64),
+                    max: self.info.total_sectors,
+                });
+            }
+
+            let whole = len / block_size * block_size;
+            let _writer = self.write_lock.lock().unwrap();
+            let root_hash = *self.root_hash.read().unwrap();
+            let mut tree =
+                MerkleTreeMut::new(self.blob_store.as_ref(), root_hash, self.total_blocks())
+                    .with_cache(&self.node_cache);
+            let mut updates: Vec<(u64, Hash)> =
+                (block..).zip(self.store_blocks(&buffer[..whole])?).collect();
+            if whole < len {
+                // Trailing partial block: keep whatever follows the image
+                let tail = &mut buffer[whole..len.next_multiple_of(sector_size)];
+                tail[len - whole..].fill(0);
+                let last = block + (whole / block_size) as u64;
+                updates.push((last, self.modify_block(&tree, last, 0, tail)?));
+            }
+            tree.update_batch(&updates).map_err(write_error)?;
+            self.commit(&tree)?;
+
+            ingested += len as u64;
+            block += (whole / block_size) as u64;
+            if len < buffer.len() {
+                break;
+            }
+        }
+        Ok(ingested)
+    }
+
+    /// Publish an updated tree as the current root (caller holds write_lock)
+    fn commit(&self, tree: &MerkleTreeMut) -> StorageResult<()> {
+        let new_root = tree.root_hash();
+        self.journal_root(new_root)?;
+        *self.root_hash.write().unwrap() = new_root;
+        self.allocated.lock().unwrap().add(tree.allocated_delta());
+
+        if let PersistRoot::Interval(period) = self.persist_root {
+            if self.root_synced.lock().unwrap().elapsed() >= period {
+                self.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of blocks holding data. The first call walks the tree; after
+    /// that writes keep the count up to date. The walk doesn't hold off
+    /// writers: those committed meanwhile are added to its result.
+    pub fn allocated_blocks(&self) -> StorageResult<u64> {
+        if let Some(count) = self.allocated.lock().unwrap().count {
+            return Ok(count);
+        }
+        let (root_hash, tally) = {
+            // No commit between reading the root and starting the tally
+            let _writer = self.write_lock.lock().unwrap();
+            let mut allocated = self.allocated.lock().unwrap();
+            if let Some(count) = allocated.count {
+                return Ok(count);
+            }
+            // Only one walk tallies; any other just reports what it counted
+            let tally = allocated.pending.is_none().then(|| {
+                allocated.pending = Some(0);
+                allocated.generation
+            });
+            (*self.root_hash.read().unwrap(), tally)
+        };
+        let walked = MerkleTree::new(self.blob_store.as_ref(), root_hash, self.total_blocks())
+            .count_allocated()
+            .map_err(|e| StorageError::Backend(e.to_string()));
+
+        let mut allocated = self.allocated.lock().unwrap();
+        // A restore during the walk voids the tally
+        if tally.is_some_and(|generation| generation == allocated.generation) {
+            let pending = allocated.pending.take().unwrap_or(0);
+            let count = walked?.saturating_add_signed(pending);
+            allocated.count = Some(count);
+            return Ok(count);
+        }
+        walked
+    }
+
+    /// Store a data block, compressed per the configured codec or as a
+    /// delta against a similar block
     fn store_block(&self, data: &[u8]) -> StorageResult<Hash> {
+        self.store_data(data, self.similarity.as_ref())
+    }
+
+    /// Store a block or chunk, looking for a similar block in `similarity`.
+    /// Exact duplicates are found by their plain encoding before any delta
+    /// is tried, so a repeated block is never stored again as a delta.
+    fn store_data(
+        &self,
+        data: &[u8],
+        similarity: Option<&SimilarityIndex>,
+    ) -> StorageResult<Hash> {
         // Check for zero block (sparse)
-        if data.iter().all(|&b| b == 0) {
+        if is_all_zero(data) {
+            self.stats.record_zero(data.len());
             return Ok(Hash::ZERO);
         }
 
-        let (stored_data, hash) = if self.compress {
-            let compressed = lz4_flex::compress_prepend_size(data);
-            if compressed.len() < data.len() {
-                // Compression helped - store compressed with marker
-                let mut with_marker = vec![0x01]; // Compressed marker
-                with_marker.extend_from_slice(&compressed);
-                let hash = Hash::from_data(&with_marker);
-                (with_marker, hash)
-            } else {
-                // Compression didn't help - store uncompressed
-                let mut with_marker = vec![0x00]; // Uncompressed marker
-                with_marker.extend_from_slice(data);
-                let hash = Hash::from_data(&with_marker);
-                (with_marker, hash)
+        let mut block = self.encode_block(data, similarity)?;
+        let mut exists = self
+            .blob_store
+            .exists(&block.0)
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        if !exists {
+            let plain = block.0;
+            self.encode_deltas(vec![(data, &mut block)])?;
+            if block.0 != plain {
+                // Made from the same block before, the delta may be stored
+                exists = self
+                    .blob_store
+                    .exists(&block.0)
+                    .map_err(|e| StorageError::Backend(e.to_string()))?;
             }
+        }
+
+        let (hash, stored_data, sketch) = block;
+        if exists {
+            self.check_duplicates(&[(hash, &stored_data)])?;
+            self.stats.record_duplicate(data.len());
         } else {
-            let mut with_marker = vec![0x00];
-            with_marker.extend_from_slice(data);
-            let hash = Hash::from_data(&with_marker);
-            (with_marker, hash)
+            self.blob_store.put(&hash, &stored_data).map_err(write_error)?;
+            self.record_stored(data.len(), &stored_data);
+        }
+        self.index_block(sketch, hash);
+
+        Ok(hash)
+    }
+
+    /// Encode a block with the codec and hash it, with its sketch if it's
+    /// a whole block and `similarity` is on
+    fn encode_block(
+        &self,
+        data: &[u8],
+        similarity: Option<&SimilarityIndex>,
+    ) -> StorageResult<EncodedBlock> {
+        // Only whole blocks, so a delta and its base are the same size
+        let sketched = similarity.is_some() && data.len() == self.block_size() as usize;
+        encode_block(&self.codec, self.hash_algorithm, data, sketched)
+    }
+
+    /// Re-encode blocks (their data and plain encoding) as deltas against
+    /// similar stored blocks, where that makes them small enough. The bases
+    /// are fetched together; blocks re-encoded lose their sketch, as a
+    /// delta is never a base.
+    fn encode_deltas(&self, blocks: Vec<(&[u8], &mut EncodedBlock)>) -> StorageResult<()> {
+        let Some(similarity) = &self.similarity else {
+            return Ok(());
         };
+        let based: Vec<_> = blocks
+            .into_iter()
+            .filter_map(|(data, block)| Some((similarity.find(block.2.as_ref()?)?, data, block)))
+            .collect();
+        if based.is_empty() {
+            return Ok(());
+        }
+        let mut bases: Vec<Hash> = Vec::new();
+        for (base, _, _) in &based {
+            if !bases.contains(base) {
+                bases.push(*base);
+            }
+        }
+        let bases = self.fetch_bases(&bases);
+
+        for (base_hash, data, block) in based {
+            let Some(base) = bases.get(&base_hash) else {
+                continue;
+            };
+            let base = self.codec.decode(base, data.len())?;
+            let delta = encode_delta(&base_hash, &base, data);
+            if delta.len() <= data.len() / MAX_DELTA_FRACTION {
+                *block = (self.hash_algorithm.hash(&delta), delta, None);
+            }
+        }
+        Ok(())
+    }
 
-        self.blob_store
-            .put(&hash, &stored_data)
+    /// Stored bases for deltas, in one batch unless some have been deleted
+    /// since they were indexed; those are left out
+    fn fetch_bases(&self, hashes: &[Hash]) -> HashMap<Hash, Vec<u8>> {
+        if let Ok(bases) = self.blob_store.get_many(hashes) {
+            return hashes.iter().copied().zip(bases).collect();
+        }
+        hashes
+            .iter()
+            .filter_map(|hash| match self.blob_store.get(hash) {
+                Ok(base) => Some((*hash, base)),
+                Err(e) => {
+                    log::debug!("Similar block {} unavailable: {}", hash, e);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Count a block of `len` bytes newly stored as `stored_data`
+    fn record_stored(&self, len: usize, stored_data: &[u8]) {
+        if stored_data.first() == Some(&MARKER_DELTA) {
+            self.stats.record_delta(len, stored_data.len());
+        } else {
+            self.stats.record_unique(len, stored_data.len());
+        }
+    }
+
+    /// Under a hash that isn't collision resistant, compare dedup hits
+    /// with the blobs stored under their hashes: one that differs is a
+    /// collision, and the write fails rather than reading back someone
+    /// else's data
+    fn check_duplicates(&self, hits: &[(Hash, &[u8])]) -> StorageResult<()> {
+        if self.hash_algorithm.is_collision_resistant() || hits.is_empty() {
+            return Ok(());
+        }
+        let hashes: Vec<Hash> = hits.iter().map(|(hash, _)| *hash).collect();
+        let existing = self
+            .blob_store
+            .get_many(&hashes)
             .map_err(|e| StorageError::Backend(e.to_string()))?;
+        for ((hash, stored_data), existing) in hits.iter().zip(existing) {
+            if existing != *stored_data {
+                log::error!("{} hash collision on blob {}", self.hash_algorithm, hash);
+                return Err(StorageError::Backend(format!("hash collision on blob {}", hash)));
+            }
+        }
+        Ok(())
+    }
 
-        Ok(hash)
+    /// Offer a block stored whole as a base for similar blocks
+    fn index_block(&self, sketch: Option<Sketch>, hash: Hash) {
+        if let (Some(similarity), Some(sketch)) = (&self.similarity, sketch) {
+            similarity.insert(&sketch, hash);
+        }
     }
 
-    /// Retrieve a data block, decompressing if needed
-    fn retrieve_block(&self, hash: &Hash) -> StorageResult<Vec<u8>> {
-        if hash.is_zero() {
-            // Sparse block - return zeros
-            return Ok(vec![0u8; 512]);
+    /// Store whole blocks, chunked if configured, returning one hash per block.
+    /// Runs of zero blocks (most of a freshly formatted filesystem) become
+    /// sparse leaves without being chunked, compressed or hashed.
+    fn store_blocks(&self, data: &[u8]) -> StorageResult<Vec<Hash>> {
+        let block_size = self.block_size() as usize;
+        let mut hashes = Vec::with_capacity(data.len() / block_size);
+        let mut start = 0;
+        while start < data.len() {
+            let zero = is_all_zero(&data[start..start + block_size]);
+            let mut end = start + block_size;
+            while end < data.len() && is_all_zero(&data[end..end + block_size]) == zero {
+                end += block_size;
+            }
+
+            let run = &data[start..end];
+            if zero {
+                let blocks = run.len() / block_size;
+                self.stats.record_zero_run(blocks as u64, run.len());
+                hashes.resize(hashes.len() + blocks, Hash::ZERO);
+            } else {
+                match &self.chunker {
+                    Some(chunker) => hashes.extend(self.store_chunked(chunker, run)?),
+                    None => hashes.extend(self.store_run(run)?),
+                }
+            }
+            start = end;
         }
+        Ok(hashes)
+    }
 
-        let stored_data = self
+    /// Store a run of non-zero whole blocks, as `store_block` would one by
+    /// one, but asking the blob store which it has and storing the new
+    /// ones a batch at a time (one round trip each for a remote store)
+    fn store_run(&self, run: &[u8]) -> StorageResult<Vec<Hash>> {
+        let block_size = self.block_size() as usize;
+        let mut encoded = self.encode_run(run)?;
+        let plain: Vec<Hash> = encoded.iter().map(|(hash, _, _)| *hash).collect();
+        let mut exists = self
             .blob_store
-            .get(hash)
+            .exists_many(&plain)
             .map_err(|e| StorageError::Backend(e.to_string()))?;
 
-        if stored_data.is_empty() {
-            return Err(StorageError::Corrupted);
+        // Blocks not stored whole may go as deltas, which may be stored too
+        let missing = run
+            .chunks(block_size)
+            .zip(&mut encoded)
+            .zip(&exists)
+            .filter(|(_, exists)| !**exists)
+            .map(|(block, _)| block)
+            .collect();
+        self.encode_deltas(missing)?;
+        let deltas: Vec<usize> = (0..encoded.len()).filter(|&i| encoded[i].0 != plain[i]).collect();
+        if !deltas.is_empty() {
+            let hashes: Vec<Hash> = deltas.iter().map(|&i| encoded[i].0).collect();
+            let found = self
+                .blob_store
+                .exists_many(&hashes)
+                .map_err(|e| StorageError::Backend(e.to_string()))?;
+            for (i, found) in deltas.into_iter().zip(found) {
+                exists[i] = found;
+            }
         }
 
-        let marker = stored_data[0];
-        let payload = &stored_data[1..];
+        // A block repeated within the run is stored once
+        let mut new: Vec<(Hash, &[u8])> = Vec::new();
+        let mut hits: Vec<(Hash, &[u8])> = Vec::new();
+        for ((hash, stored_data, _), exists) in encoded.iter().zip(exists) {
+            if exists {
+                hits.push((*hash, stored_data));
+            } else if !new.iter().any(|(stored, _)| stored == hash) {
+                new.push((*hash, stored_data));
+            }
+        }
+        self.check_duplicates(&hits)?;
+        self.blob_store.put_many(&new).map_err(write_error)?;
 
-        match marker {
-            0x00 => {
-                // Uncompressed
-                Ok(payload.to_vec())
+        for (_, stored_data) in &new {
+            self.record_stored(block_size, stored_data);
+        }
+        for _ in new.len()..encoded.len() {
+            self.stats.record_duplicate(block_size);
+        }
+        let mut hashes = Vec::with_capacity(encoded.len());
+        for (hash, _, sketch) in encoded {
+            self.index_block(sketch, hash);
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    /// Encode and hash each block of a run, splitting a large run between
+    /// the write threads
+    fn encode_run(&self, run: &[u8]) -> StorageResult<Vec<EncodedBlock>> {
+        let block_size = self.block_size() as usize;
+        let (algorithm, sketched) = (self.hash_algorithm, self.similarity.is_some());
+        let pool = self.write_pool.as_ref();
+        let threads = pool.map_or(1, |pool| pool.threads().min(run.len() / MIN_BYTES_PER_THREAD));
+        let (Some(pool), 2..) = (pool, threads) else {
+            return encode_blocks(&self.codec, algorithm, block_size, sketched, run);
+        };
+
+        // The threads outlive the write, so they get their own copy of it
+        let per_thread = (run.len() / block_size).div_ceil(threads) * block_size;
+        let shared: Arc<[u8]> = run.into();
+        let mut parts = Vec::with_capacity(threads);
+        for start in (0..run.len()).step_by(per_thread) {
+            let (reply, part) = mpsc::channel();
+            let (codec, shared) = (Arc::clone(&self.codec), Arc::clone(&shared));
+            let end = run.len().min(start + per_thread);
+            pool.run(move || {
+                let blocks = &shared[start..end];
+                let _ = reply.send(encode_blocks(&codec, algorithm, block_size, sketched, blocks));
+            })?;
+            parts.push(part);
+        }
+
+        let mut encoded = Vec::with_capacity(run.len() / block_size);
+        for part in parts {
+            let blocks = part
+                .recv()
+                .map_err(|_| StorageError::Backend("block encoder panicked".to_string()))?;
+            encoded.extend(blocks?);
+        }
+        Ok(encoded)
+    }
+
+    /// Store whole blocks as content-defined chunks, returning one manifest
+    /// hash per block (zero for all-zero blocks)
+    fn store_chunked(&self, chunker: &ChunkerConfig, data: &[u8]) -> StorageResult<Vec<Hash>> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        for end in chunker.boundaries(data) {
+            // Chunks aren't blocks, so are never deltas
+            chunks.push((start, end, self.store_data(&data[start..end], None)?));
+            start = end;
+        }
+
+        let block_size = self.block_size() as usize;
+        let mut hashes = Vec::with_capacity(data.len() / block_size);
+        for block_start in (0..data.len()).step_by(block_size) {
+            let block_end = block_start + block_size;
+            let segments: Vec<Segment> = chunks
+                .iter()
+                .filter(|&&(start, end, _)| start < block_end && end > block_start)
+                .map(|&(start, end, chunk)| Segment {
+                    chunk,
+                    chunk_len: (end - start) as u32,
+                    offset: (block_start.max(start) - start) as u32,
+                    len: (block_end.min(end) - block_start.max(start)) as u32,
+                })
+                .collect();
+            if segments.iter().all(|segment| segment.chunk.is_zero()) {
+                hashes.push(Hash::ZERO);
+                continue;
             }
-            0x01 => {
-                // Compressed
-                lz4_flex::decompress_size_prepended(payload)
-                    .map_err(|_| StorageError::Corrupted)
+
+            let manifest = encode_manifest(&segments);
+            let hash = self.hash_algorithm.hash(&manifest);
+            let exists = self
+                .blob_store
+                .exists(&hash)
+                .map_err(|e| StorageError::Backend(e.to_string()))?;
+            if exists {
+                self.check_duplicates(&[(hash, &manifest)])?;
+            } else {
+                self.blob_store.put(&hash, &manifest).map_err(write_error)?;
+            }
+            hashes.push(hash);
+        }
+        Ok(hashes)
+    }
+
+    /// Rebuild a chunked block from its manifest
+    fn assemble_block(&self, manifest: &[u8]) -> StorageResult<Vec<u8>> {
+        let mut data = Vec::with_capacity(self.block_size() as usize);
+        for segment in decode_manifest(manifest)? {
+            let chunk_len = segment.chunk_len as usize;
+            let chunk = if segment.chunk.is_zero() {
+                vec![0u8; chunk_len]
+            } else {
+                let stored = self
+                    .blob_store
+                    .get(&segment.chunk)
+                    .map_err(|e| StorageError::Backend(e.to_string()))?;
+                self.codec.decode(&stored, chunk_len)?
+            };
+            if chunk.len() != chunk_len {
+                return Err(StorageError::Corrupted);
             }
-            _ => Err(StorageError::Corrupted),
+            let offset = segment.offset as usize;
+            data.extend_from_slice(&chunk[offset..offset + segment.len as usize]);
         }
+        Ok(data)
+    }
+
+    /// Rebuild a block stored as a delta from its base
+    fn apply_delta_block(&self, delta: &[u8]) -> StorageResult<Vec<u8>> {
+        let base_hash = delta_base(delta).ok_or(StorageError::Corrupted)?;
+        let base = self
+            .blob_store
+            .get(&base_hash)
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let base = self.codec.decode(&base, self.block_size() as usize)?;
+        apply_delta(delta, &base)
+    }
+
+    /// Retrieve a data block, decompressing if needed
+    fn retrieve_block(&self, hash: &Hash) -> StorageResult<Vec<u8>> {
+        let mut blocks = self.retrieve_blocks(&[*hash])?;
+        Ok(blocks.pop().expect("one block per hash"))
+    }
+
+    /// Retrieve data blocks, fetching the blobs that aren't sparse or
+    /// prefetched in one batch
+    fn retrieve_blocks(&self, hashes: &[Hash]) -> StorageResult<Vec<Vec<u8>>> {
+        let prefetched: Vec<Option<Arc<Vec<u8>>>> = hashes
+            .iter()
+            .map(|hash| match &self.readahead {
+                Some(readahead) if !hash.is_zero() => readahead.get(hash),
+                _ => None,
+            })
+            .collect();
+        let missing: Vec<Hash> = hashes
+            .iter()
+            .zip(&prefetched)
+            .filter(|(hash, prefetched)| !hash.is_zero() && prefetched.is_none())
+            .map(|(hash, _)| *hash)
+            .collect();
+        let mut fetched = self
+            .blob_store
+            .get_many(&missing)
+            .map_err(|e| StorageError::Backend(e.to_string()))?
+            .into_iter();
+
+        let block_size = self.block_size() as usize;
+        hashes
+            .iter()
+            .zip(prefetched)
+            .map(|(hash, prefetched)| {
+                if hash.is_zero() {
+                    // Sparse block - return zeros
+                    return Ok(vec![0u8; block_size]);
+                }
+                match prefetched {
+                    Some(stored_data) => self.decode_block(&stored_data),
+                    None => self.decode_block(&fetched.next().expect("one blob per hash")),
+                }
+            })
+            .collect()
+    }
+
+    /// Decode a block as stored
+    fn decode_block(&self, stored_data: &[u8]) -> StorageResult<Vec<u8>> {
+        let block_size = self.block_size() as usize;
+        let data = match stored_data.first() {
+            Some(&MARKER_MANIFEST) => self.assemble_block(stored_data)?,
+            Some(&MARKER_DELTA) => self.apply_delta_block(stored_data)?,
+            _ => self.codec.decode(stored_data, block_size)?,
+        };
+        if data.len() != block_size {
+            return Err(StorageError::Corrupted);
+        }
+        Ok(data)
     }
 }
 
@@ -166,60 +1044,179 @@ impl BlockStorage for CasBackend {
     fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
         self.validate_range(lba, count)?;
 
-        let root_hash = *self.root_hash.lock().unwrap();
-        let tree = MerkleTree::new(self.blob_store.as_ref(), root_hash, self.info.total_sectors);
+        let root_hash = *self.root_hash.read().unwrap();
+        let tree = MerkleTree::new(self.blob_store.as_ref(), root_hash, self.total_blocks())
+            .with_cache(&self.node_cache);
 
-        let mut result = Vec::with_capacity(count as usize * 512);
+        let sector_size = self.info.sector_size as usize;
+        let mut result = Vec::with_capacity(count as usize * sector_size);
 
-        for i in 0..count as u64 {
-            let data_hash = tree
-                .lookup(lba + i)
-                .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let runs: Vec<_> = self.block_runs(lba, count as u64).collect();
+        let hashes = runs
+            .iter()
+            .map(|&(block, _, _)| tree.lookup(block))
+            .collect::<Result<Vec<Hash>, BlobError>>()
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let blocks = self.retrieve_blocks(&hashes)?;
+        for (&(_, first, end), data) in runs.iter().zip(blocks) {
+            let (first, end) = (first as usize, end as usize);
+            result.extend_from_slice(&data[first * sector_size..end * sector_size]);
+        }
 
-            let block = self.retrieve_block(&data_hash)?;
-            result.extend_from_slice(&block);
+        if let Some(readahead) = &self.readahead {
+            let per_block = self.sectors_per_block();
+            let (first, end) = (lba / per_block, (lba + count as u64).div_ceil(per_block));
+            if let Some(ahead) = readahead.observe(first, end, self.total_blocks()) {
+                // Hashes come from tree nodes the read just cached
+                for block in ahead {
+                    match tree.lookup(block) {
+                        Ok(data_hash) => readahead.prefetch(data_hash),
+                        Err(_) => break,
+                    }
+                }
+            }
         }
 
         Ok(result)
     }
 
-    fn write(&mut self, lba: u64, data: &[u8]) -> StorageResult<()> {
-        let count = (data.len() / 512) as u8;
+    fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+        let sector_size = self.info.sector_size as usize;
+        let count = (data.len() / sector_size) as u8;
         self.validate_range(lba, count)?;
 
-        let mut root_hash = self.root_hash.lock().unwrap();
-        let mut tree =
-            MerkleTreeMut::new(self.blob_store.as_ref(), *root_hash, self.info.total_sectors);
-
-        for (i, chunk) in data.chunks(512).enumerate() {
-            let data_hash = self.store_block(chunk)?;
-            tree.update(lba + i as u64, data_hash)
-                .map_err(|e| StorageError::Backend(e.to_string()))?;
+        let _writer = self.write_lock.lock().unwrap();
+        let root_hash = *self.root_hash.read().unwrap();
+        let mut tree = MerkleTreeMut::new(self.blob_store.as_ref(), root_hash, self.total_blocks())
+            .with_cache(&self.node_cache);
+
+        let per_block = self.sectors_per_block();
+        let mut updates = Vec::new();
+        // Whole blocks, stored together: (first block, byte range of data)
+        let mut whole: Option<(u64, std::ops::Range<usize>)> = None;
+        for (block, first, end) in self.block_runs(lba, count as u64) {
+            let offset = (block * per_block + first - lba) as usize * sector_size;
+            let range = offset..offset + (end - first) as usize * sector_size;
+            if first == 0 && end == per_block {
+                let (_, whole_range) = whole.get_or_insert((block, range.clone()));
+                whole_range.end = range.end;
+            } else {
+                // Part of a block: read, modify and rewrite it
+                let data_hash =
+                    self.modify_block(&tree, block, first as usize * sector_size, &data[range])?;
+                updates.push((block, data_hash));
+            }
         }
+        if let Some((first_block, range)) = whole {
+            let hashes = self.store_blocks(&data[range])?;
+            updates.extend((first_block..).zip(hashes));
+        }
+        tree.update_batch(&updates).map_err(write_error)?;
 
-        *root_hash = tree.root_hash();
-        Ok(())
+        self.commit(&tree)
     }
 
-    fn flush(&mut self) -> StorageResult<()> {
+    fn flush(&self) -> StorageResult<()> {
+        // Blobs must be durable before the root that references them
         self.blob_store
             .sync()
-            .map_err(|e| StorageError::Backend(e.to_string()))
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
+
+        if let Some(journal) = &self.journal {
+            journal
+                .lock()
+                .unwrap()
+                .sync()
+                .map_err(|e| StorageError::Backend(format!("root journal sync failed: {}", e)))?;
+            *self.root_synced.lock().unwrap() = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn check_health(&self) -> StorageResult<()> {
+        self.blob_store
+            .check_health()
+            .map_err(|e| StorageError::Backend(format!("blob store: {}", e)))
+    }
+
+    fn write_zeroes(&self, lba: u64, count: u64) -> StorageResult<()> {
+        if lba + count > self.info.total_sectors {
+            return Err(StorageError::OutOfRange {
+                lba,
+                max: self.info.total_sectors,
+            });
+        }
+
+        // Point the range at the sparse zero block; no data is stored
+        let _writer = self.write_lock.lock().unwrap();
+        let root_hash = *self.root_hash.read().unwrap();
+        let mut tree = MerkleTreeMut::new(self.blob_store.as_ref(), root_hash, self.total_blocks())
+            .with_cache(&self.node_cache);
+        let per_block = self.sectors_per_block();
+        let sector_size = self.info.sector_size as usize;
+        let mut updates = Vec::new();
+        for (block, first, end) in self.block_runs(lba, count) {
+            let data_hash = if first == 0 && end == per_block {
+                Hash::ZERO
+            } else {
+                let zeros = vec![0u8; (end - first) as usize * sector_size];
+                self.modify_block(&tree, block, first as usize * sector_size, &zeros)?
+            };
+            updates.push((block, data_hash));
+            if updates.len() >= ZEROES_BATCH_BLOCKS {
+                tree.update_batch(&updates).map_err(write_error)?;
+                updates.clear();
+            }
+        }
+        tree.update_batch(&updates).map_err(write_error)?;
+
+        self.commit(&tree)
     }
 
     fn info(&self) -> &DeviceInfo {
         &self.info
     }
+
+    fn dedup_stats(&self) -> Option<DedupStats> {
+        Some(self.stats())
+    }
+
+    fn usage(&self) -> Option<UsageStats> {
+        let allocated = match self.allocated_blocks() {
+            Ok(blocks) => blocks * self.block_size() as u64,
+            Err(e) => {
+                log::warn!("Failed to count allocated blocks: {}", e);
+                return None;
+            }
+        };
+        let space = self.blob_store.space().unwrap_or_else(|e| {
+            log::warn!("Failed to read blob store free space: {}", e);
+            None
+        });
+        let capacity = self.info.size_bytes();
+        Some(
+            UsageStats::new(capacity, allocated.min(capacity), space)
+                .with_quota(self.blob_store.quota()),
+        )
+    }
+
+    fn as_archival(&self) -> Option<&dyn ArchivalStorage> {
+        Some(self)
+    }
 }
 
 impl ArchivalStorage for CasBackend {
-    fn snapshot(&mut self, description: Option<&str>) -> StorageResult<String> {
-        let root_hash = *self.root_hash.lock().unwrap();
-        let mut snapshots = self.snapshots.lock().unwrap();
+    fn snapshot(&self, description: Option<&str>) -> StorageResult<String> {
+        self.create_snapshot(description, None, &[])
+    }
 
-        snapshots
-            .create(root_hash, description)
-            .map_err(|e| StorageError::Backend(format!("failed to create snapshot: {}", e)))
+    fn snapshot_named(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        tags: &[String],
+    ) -> StorageResult<String> {
+        self.create_snapshot(description, Some(name), tags)
     }
 
     fn list_snapshots(&self) -> StorageResult<Vec<SnapshotInfo>> {
@@ -227,19 +1224,200 @@ impl ArchivalStorage for CasBackend {
         Ok(snapshots.list())
     }
 
-    fn restore(&mut self, snapshot_id: &str) -> StorageResult<()> {
-        let snapshots = self.snapshots.lock().unwrap();
-        let hash = snapshots
+    fn update_snapshot(
+        &self,
+        snapshot: &str,
+        name: Option<&str>,
+        tags: Option<&[String]>,
+    ) -> StorageResult<SnapshotInfo> {
+        Ok(self.snapshots.lock().unwrap().update(snapshot, name, tags)?)
+    }
+
+    fn prune_snapshots(&self, rules: &[RetentionRule]) -> StorageResult<Vec<SnapshotInfo>> {
+        let pruned = self.snapshots.lock().unwrap().prune(rules)?;
+        for snapshot in &pruned {
+            log::info!(
+                "Pruned snapshot {} ({})",
+                snapshot.id,
+                snapshot.name.as_deref().unwrap_or("unnamed")
+            );
+        }
+        Ok(pruned)
+    }
+
+    fn restore(&self, snapshot_id: &str) -> StorageResult<()> {
+        let mut snapshots = self.snapshots.lock().unwrap();
+        // Later snapshots descend from this one
+        let hash = snapshots.set_head(snapshot_id).map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => {
+                StorageError::Backend(format!("snapshot not found: {}", snapshot_id))
+            }
+            _ => e.into(),
+        })?;
+
+        // Wait for any in-flight write so it doesn't overwrite the restored root
+        let _writer = self.write_lock.lock().unwrap();
+        self.journal_root(hash)?;
+        *self.root_hash.write().unwrap() = hash;
+        // Recounted when next asked for
+        self.allocated.lock().unwrap().reset();
+        Ok(())
+    }
+
+    fn changed_since(
+        &self,
+        snapshot_id: &str,
+        sectors: Range<u64>,
+    ) -> StorageResult<Vec<Range<u64>>> {
+        let base = self
+            .snapshots
+            .lock()
+            .unwrap()
             .get(snapshot_id)
             .ok_or_else(|| StorageError::Backend(format!("snapshot not found: {}", snapshot_id)))?;
+        let root_hash = *self.root_hash.read().unwrap();
+        let per_block = self.sectors_per_block();
+        let blocks = sectors.start / per_block..sectors.end.div_ceil(per_block);
+        let blocks = MerkleTree::new(self.blob_store.as_ref(), root_hash, self.total_blocks())
+            .with_cache(&self.node_cache)
+            .diff(base, blocks)
+            .map_err(|e| StorageError::Backend(e.to_string()))?;
 
-        let mut root_hash = self.root_hash.lock().unwrap();
-        *root_hash = hash;
-        Ok(())
+        // Whole blocks, cut to the sectors asked for
+        let end = sectors.end.min(self.info.total_sectors);
+        Ok(blocks
+            .into_iter()
+            .map(|range| {
+                (range.start * per_block).max(sectors.start)..(range.end * per_block).min(end)
+            })
+            .collect())
+    }
+
+    fn ingest(&self, reader: &mut dyn Read) -> StorageResult<u64> {
+        self.ingest_from(reader)
     }
 }
 
-/// Hash a path for generating serial numbers
+impl CasBackend {
+    fn create_snapshot(
+        &self,
+        description: Option<&str>,
+        name: Option<&str>,
+        tags: &[String],
+    ) -> StorageResult<String> {
+        let root_hash = *self.root_hash.read().unwrap();
+        let id = self
+            .snapshots
+            .lock()
+            .unwrap()
+            .create_with(root_hash, description, name, tags)
+            .map_err(|e| match e.kind() {
+                // Bad names keep their kind so callers can tell them apart
+                std::io::ErrorKind::InvalidInput | std::io::ErrorKind::AlreadyExists => e.into(),
+                _ => StorageError::Backend(format!("failed to create snapshot: {}", e)),
+            })?;
+        if !self.retention.is_empty() {
+            self.prune_snapshots(&self.retention)?;
+        }
+        Ok(id)
+    }
+}
+
+/// Blobs a stored data block refers to: the stored chunks of a manifest,
+/// or the base of a delta (none for a plain block)
+pub fn referenced_blobs(stored: &[u8]) -> StorageResult<Vec<Hash>> {
+    match delta_base(stored) {
+        Some(base) => Ok(vec![base]),
+        None => referenced_chunks(stored),
+    }
+}
+
+/// Encode a block with `codec` and hash it by `algorithm`, with its sketch
+/// if it's to be offered as a base for similar blocks
+fn encode_block(
+    codec: &BlockCodec,
+    algorithm: HashAlgorithm,
+    data: &[u8],
+    sketched: bool,
+) -> StorageResult<EncodedBlock> {
+    let stored_data = codec.encode(data)?;
+    let sketch = sketched.then(|| sketch(data));
+    Ok((algorithm.hash(&stored_data), stored_data, sketch))
+}
+
+/// Encode and hash each `block_size` block of `blocks`
+fn encode_blocks(
+    codec: &BlockCodec,
+    algorithm: HashAlgorithm,
+    block_size: usize,
+    sketched: bool,
+    blocks: &[u8],
+) -> StorageResult<Vec<EncodedBlock>> {
+    blocks
+        .chunks(block_size)
+        .map(|block| encode_block(codec, algorithm, block, sketched))
+        .collect()
+}
+
+/// Storage error for a failed blob write, keeping a full disk distinct
+fn write_error(e: BlobError) -> StorageError {
+    match e {
+        BlobError::NoSpace { available, reserve } => StorageError::NoSpace { available, reserve },
+        BlobError::QuotaExceeded { used, quota } => StorageError::QuotaExceeded { used, quota },
+        BlobError::Io(e) if e.raw_os_error() == Some(libc::ENOSPC) => e.into(),
+        e => StorageError::Backend(e.to_string()),
+    }
+}
+
+/// Read until `buffer` is full or the reader is exhausted
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Root journal lives next to the snapshots file
+pub fn journal_path(snapshot_path: &Path) -> std::path::PathBuf {
+    snapshot_path.with_extension("wal")
+}
+
+/// Target identity lives next to the snapshots file
+pub fn identity_path(snapshot_path: &Path) -> std::path::PathBuf {
+    snapshot_path.with_extension("uuid")
+}
+
+/// Store geometry lives next to the snapshots file
+pub fn geometry_path(snapshot_path: &Path) -> PathBuf {
+    snapshot_path.with_extension("geometry")
+}
+
+/// A codec able to decode blocks compressed with any dictionary the store
+/// at `snapshot_path` has used
+fn load_codec(snapshot_path: &Path) -> StorageResult<BlockCodec> {
+    let mut codec = BlockCodec::default();
+    codec.load_dictionaries(&dictionary_dir(snapshot_path))?;
+    Ok(codec)
+}
+
+/// Load the recorded geometry of the store at `snapshot_path`, applying it
+/// to `info`
+fn load_geometry(snapshot_path: &Path, info: &mut DeviceInfo) -> StorageResult<Option<Geometry>> {
+    let geometry = Geometry::load(&geometry_path(snapshot_path))
+        .map_err(|e| StorageError::Backend(format!("failed to load store geometry: {}", e)))?;
+    if let Some(geometry) = geometry {
+        info.set_sector_size(geometry.sector_size);
+    }
+    Ok(geometry)
+}
+
+/// Hash a path for serials of stores without an identity
 fn hash_path(path: &Path) -> u64 {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
@@ -252,7 +1430,9 @@ fn hash_path(path: &Path) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::blob::FileBlobStore;
+    use crate::blob::{FileBlobStore, QuotaBlobStore};
+    use crate::storage::SnapshotTree;
+    use std::thread;
     use tempfile::TempDir;
 
     fn create_test_backend() -> (TempDir, CasBackend) {
@@ -266,9 +1446,29 @@ mod tests {
         (temp, backend)
     }
 
+    #[test]
+    fn test_identity_follows_store() {
+        let temp = TempDir::new().unwrap();
+        let open = |dir: &Path| {
+            let store = Box::new(FileBlobStore::new(dir.join("blobs")).unwrap());
+            CasBackend::new(store, 1024, &dir.join("snapshots.json")).unwrap()
+        };
+
+        let old = temp.path().join("old");
+        let uuid = open(&old).info().uuid.unwrap();
+        assert_eq!(open(&old).info().uuid, Some(uuid));
+
+        // Moving the data directory keeps the serial
+        let new = temp.path().join("new");
+        std::fs::rename(&old, &new).unwrap();
+        let moved = open(&new);
+        assert_eq!(moved.info().uuid, Some(uuid));
+        assert_eq!(moved.info().serial, uuid.serial());
+    }
+
     #[test]
     fn test_cas_read_write() {
-        let (_temp, mut backend) = create_test_backend();
+        let (_temp, backend) = create_test_backend();
 
         // Write a sector
         let write_data = vec![0xAA; 512];
@@ -279,6 +1479,39 @@ mod tests {
         assert_eq!(read_data, write_data);
     }
 
+    #[test]
+    fn test_sequential_readahead() {
+        let (_temp, backend) = create_test_backend();
+        let backend = backend.with_readahead(8);
+        for lba in 0..64u64 {
+            backend.write(lba, &[lba as u8 + 1; 512]).unwrap();
+        }
+
+        // Two reads in a row make a stream; the next 8 blocks get fetched
+        backend.read(0, 2).unwrap();
+        backend.read(2, 2).unwrap();
+        let readahead = backend.readahead.as_ref().unwrap();
+        let next = |lba| {
+            let root_hash = *backend.root_hash.read().unwrap();
+            let tree = MerkleTree::new(backend.blob_store.as_ref(), root_hash, 1024);
+            tree.lookup(lba).unwrap()
+        };
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while readahead.get(&next(11)).is_none() {
+            assert!(std::time::Instant::now() < deadline, "readahead never arrived");
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
+        assert!(readahead.get(&next(12)).is_none());
+        assert_eq!(backend.read(4, 8).unwrap()[7 * 512..], [12u8; 512]);
+
+        // Random reads prefetch nothing
+        backend.read(40, 1).unwrap();
+        backend.read(20, 1).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert!(readahead.get(&next(41)).is_none());
+        assert!(readahead.get(&next(21)).is_none());
+    }
+
     #[test]
     fn test_cas_sparse_read() {
         let (_temp, backend) = create_test_backend();
@@ -288,9 +1521,364 @@ mod tests {
         assert_eq!(data, vec![0u8; 512]);
     }
 
+    #[test]
+    fn test_cas_large_blocks() {
+        let (_temp, backend) = create_test_backend();
+        let backend = backend.with_block_size(4096).unwrap();
+        assert_eq!(backend.block_size(), 4096);
+
+        // Sectors 6..10 straddle the first two 8-sector blocks
+        let data: Vec<u8> = (0..4 * 512).map(|i| (i / 512 + 1) as u8).collect();
+        backend.write(6, &data).unwrap();
+        let read = backend.read(4, 8).unwrap();
+        assert_eq!(read[..1024], [0u8; 1024]);
+        assert_eq!(read[1024..3072], data[..]);
+        assert_eq!(read[3072..], [0u8; 1024]);
+
+        // A full, aligned block is stored without reading the old one
+        backend.write(16, &[0x77; 4096]).unwrap();
+        assert_eq!(backend.read(16, 8).unwrap(), vec![0x77; 4096]);
+
+        // Zeroing part of a block keeps the rest; whole blocks become sparse
+        backend.write_zeroes(7, 10).unwrap();
+        let read = backend.read(6, 12).unwrap();
+        assert_eq!(read[..512], [1u8; 512]);
+        assert_eq!(read[512..5632], [0u8; 5120]);
+        assert_eq!(read[5632..], [0x77; 512]);
+
+        // 1000 sectors: the last 128-sector block is only partly inside
+        let temp = TempDir::new().unwrap();
+        let store = Box::new(FileBlobStore::new(temp.path().join("blobs")).unwrap());
+        let backend = CasBackend::new(store, 1000, &temp.path().join("snapshots.json"))
+            .unwrap()
+            .with_block_size(65536)
+            .unwrap();
+        backend.write(998, &[0x5A; 1024]).unwrap();
+        assert_eq!(backend.read(997, 3).unwrap()[512..], [0x5A; 1024]);
+        assert!(backend.write(1000, &[0; 512]).is_err());
+    }
+
+    #[test]
+    fn test_cas_similar_blocks_stored_as_deltas() {
+        let (_temp, backend) = create_test_backend();
+        let backend = backend
+            .with_block_size(4096)
+            .unwrap()
+            .with_compression(Compression::None)
+            .with_similarity(16);
+
+        let base: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 7) as u8)
+            .collect();
+        let mut similar = base.clone();
+        similar[1000..1008].copy_from_slice(b"changed!");
+        backend.write(0, &base).unwrap();
+        backend.write(8, &similar).unwrap();
+        assert_eq!(backend.read(0, 8).unwrap(), base);
+        assert_eq!(backend.read(8, 8).unwrap(), similar);
+
+        // A partial write makes another variant, also against the base
+        similar[600] ^= 1;
+        backend.write(9, &similar[512..1024]).unwrap();
+        assert_eq!(backend.read(8, 8).unwrap(), similar);
+
+        // Repeats are duplicates, not deltas against themselves
+        backend.write(16, &base).unwrap();
+        backend.write(24, &[base.clone(), similar.clone()].concat()).unwrap();
+        assert_eq!(backend.read(24, 16).unwrap(), [base.clone(), similar.clone()].concat());
+
+        let stats = backend.stats();
+        assert_eq!((stats.unique_blocks, stats.delta_blocks), (3, 2));
+        assert_eq!(stats.duplicate_blocks, 3);
+        assert!(stats.stored_bytes < 4096 + 1024);
+
+        // The delta refers to its base
+        let root = *backend.root_hash.read().unwrap();
+        let tree = MerkleTree::new(backend.blob_store.as_ref(), root, backend.total_blocks());
+        let stored = backend.blob_store.get(&tree.lookup(1).unwrap()).unwrap();
+        assert_eq!(referenced_blobs(&stored).unwrap(), vec![tree.lookup(0).unwrap()]);
+    }
+
+    #[test]
+    fn test_cas_xxh3_store_checks_dedup_hits() {
+        let temp = TempDir::new().unwrap();
+        let blob_path = temp.path().join("blobs");
+        let store = FileBlobStore::unverified(&blob_path)
+            .unwrap()
+            .with_hash_algorithm(HashAlgorithm::Xxh3)
+            .unwrap();
+        let backend = CasBackend::new(Box::new(store), 1024, &temp.path().join("snapshots.json"))
+            .unwrap()
+            .with_compression(Compression::None);
+
+        backend.write(0, &[1; 512]).unwrap();
+        backend.write(1, &[1; 512]).unwrap();
+        assert_eq!(backend.read(0, 2).unwrap(), [1; 1024]);
+        assert_eq!(backend.stats().duplicate_blocks, 1);
+
+        // A different blob already stored under a block's hash is caught
+        let mut stored = vec![0x00];
+        stored.extend_from_slice(&[2; 512]);
+        let hash = HashAlgorithm::Xxh3.hash(&stored);
+        backend.blob_store.put(&hash, b"collides").unwrap();
+        assert!(backend.write(3, &[2; 512]).is_err());
+        assert!(backend.write(4, &[[1; 512], [2; 512]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_cas_ingest() {
+        let (_temp, backend) = create_test_backend();
+        let backend = backend.with_block_size(4096).unwrap();
+
+        // Two data blocks, a zero block, a repeat and a ragged tail
+        let mut image: Vec<u8> = (0..8192).map(|i| (i % 251) as u8).collect();
+        image.extend_from_slice(&[0; 4096]);
+        image.extend_from_slice(&[0x42; 4096]);
+        image.extend_from_slice(&[0x42; 4096]);
+        image.extend_from_slice(&[0x99; 700]);
+
+        let ingested = backend.ingest_from(std::io::Cursor::new(&image)).unwrap();
+        assert_eq!(ingested, image.len() as u64);
+        let read = backend.read(0, 42).unwrap();
+        assert_eq!(read[..image.len()], image[..]);
+        assert_eq!(read[image.len()..], [0u8; 324]);
+
+        let stats = backend.stats();
+        assert_eq!(stats.zero_blocks, 1);
+        assert_eq!(stats.duplicate_blocks, 1);
+
+        // An image larger than the device is refused
+        let too_big = vec![1u8; backend.info().size_bytes() as usize + 512];
+        assert!(backend.ingest_from(&too_big[..]).is_err());
+    }
+
+    #[test]
+    fn test_cas_write_threads() {
+        // Blocks repeating every 40, so each thread's share has duplicates
+        let image: Vec<u8> = (0..128u32)
+            .flat_map(|i| (i % 40 + 1).to_le_bytes().repeat(1024))
+            .collect();
+        let mut stats = Vec::new();
+        for threads in [1, 4] {
+            let (_temp, backend) = create_test_backend();
+            let backend = backend.with_block_size(4096).unwrap().with_write_threads(threads);
+            backend.ingest_from(std::io::Cursor::new(&image)).unwrap();
+            assert_eq!(backend.read(0, 255).unwrap(), image[..255 * 512]);
+            assert_eq!(backend.read(1000, 24).unwrap(), image[1000 * 512..]);
+            stats.push(backend.stats());
+        }
+        assert_eq!((stats[1].unique_blocks, stats[1].duplicate_blocks), (40, 88));
+        assert_eq!(stats[0], stats[1]);
+    }
+
+    #[test]
+    fn test_cas_chunking_dedups_shifted_data() {
+        let (_temp, backend) = create_test_backend();
+        let backend = backend
+            .with_block_size(65536)
+            .unwrap()
+            .with_chunking(ChunkerConfig::new(4096).unwrap())
+            .with_compression(Compression::None);
+
+        let mut state = 7u64;
+        let mut noise = |len: usize| -> Vec<u8> {
+            (0..len)
+                .map(|_| {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    (state >> 56) as u8
+                })
+                .collect()
+        };
+        let data = noise(256 * 1024);
+        for (i, block) in data.chunks(65536).enumerate() {
+            backend.write(i as u64 * 128, block).unwrap();
+        }
+        let before = backend.stats();
+
+        // The same data moved along by 1000 bytes, written elsewhere
+        let mut shifted = noise(1000);
+        shifted.extend_from_slice(&data[..data.len() - 1000]);
+        for (i, block) in shifted.chunks(65536).enumerate() {
+            backend.write(512 + i as u64 * 128, block).unwrap();
+        }
+        let added = backend.stats().unique_bytes - before.unique_bytes;
+        assert!(added < shifted.len() as u64 / 4, "{} new bytes", added);
+
+        assert_eq!(backend.read(0, 255).unwrap(), data[..255 * 512]);
+        assert_eq!(backend.read(700, 100).unwrap(), shifted[188 * 512..288 * 512]);
+
+        // Sub-block writes patch a chunked block
+        backend.write(3, &[0xEE; 512]).unwrap();
+        let read = backend.read(0, 8).unwrap();
+        assert_eq!(read[1536..2048], [0xEE; 512]);
+        assert_eq!(read[2048..], data[2048..4096]);
+    }
+
+    #[test]
+    fn test_cas_usage() {
+        let (_temp, backend) = create_test_backend();
+        backend.write(10, &vec![0xAA; 4 * 512]).unwrap();
+        let snapshot = backend.snapshot(None).unwrap();
+
+        // Counted by walking the tree on first use
+        let usage = backend.usage().unwrap();
+        assert_eq!(usage.capacity_bytes, 1024 * 512);
+        assert_eq!(usage.allocated_bytes, 4 * 512);
+        assert!(usage.space.unwrap().total_bytes > 0);
+
+        // Then tracked by writes, overwrites and zeroing
+        backend.write(10, &[0xBB; 512]).unwrap();
+        backend.write(500, &[0xCC; 512]).unwrap();
+        backend.write_zeroes(11, 2).unwrap();
+        assert_eq!(backend.allocated_blocks().unwrap(), 3);
+
+        backend.restore(&snapshot).unwrap();
+        assert_eq!(backend.allocated_blocks().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_cas_quota() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path().join("blobs")).unwrap();
+        let store = QuotaBlobStore::new(Box::new(store), Some(65536), temp.path().join("usage"));
+        let backend = CasBackend::new(
+            Box::new(store.unwrap()),
+            1024,
+            &temp.path().join("snapshots.json"),
+        )
+        .unwrap()
+        .with_compression(Compression::None);
+
+        // Distinct sectors (and the tree nodes over them) until the quota
+        // runs out
+        let mut result = Ok(());
+        for lba in 0..1024u64 {
+            result = backend.write(lba, &[lba as u8 + 1; 512]);
+            if result.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(result, Err(StorageError::QuotaExceeded { quota: 65536, .. })));
+        let quota = backend.usage().unwrap().quota.unwrap();
+        assert!(quota.attributed_bytes <= 65536);
+        assert_eq!(quota.quota_bytes, Some(65536));
+
+        // Rewriting what's there adds no blobs, so it's free
+        backend.write(0, &[1; 512]).unwrap();
+    }
+
+    #[test]
+    fn test_cas_invalid_block_size() {
+        for block_size in [256, 3072, 131072] {
+            let (_temp, backend) = create_test_backend();
+            assert!(backend.with_block_size(block_size).is_err());
+        }
+        let (_temp, backend) = create_test_backend();
+        assert!(backend
+            .with_sector_size(4096)
+            .unwrap()
+            .with_block_size(2048)
+            .is_err());
+    }
+
+    #[test]
+    fn test_cas_sector_size_recorded() {
+        let temp = TempDir::new().unwrap();
+        let blob_path = temp.path().join("blobs");
+        let snapshot_path = temp.path().join("snapshots.json");
+        let open = || {
+            let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
+            CasBackend::new(store, 1024, &snapshot_path).unwrap()
+        };
+
+        let backend = open().with_sector_size(4096).unwrap();
+        backend.write(3, &vec![0x4B; 4096]).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+
+        // Reopened as 512-byte sectors the data would move; refused
+        assert!(open().with_sector_size(512).is_err());
+        let backend = open();
+        assert_eq!(backend.info().sector_size, 4096);
+        assert_eq!(backend.read(3, 1).unwrap(), vec![0x4B; 4096]);
+    }
+
+    #[test]
+    fn test_cas_block_size_recorded() {
+        let temp = TempDir::new().unwrap();
+        let blob_path = temp.path().join("blobs");
+        let snapshot_path = temp.path().join("snapshots.json");
+        let open = || {
+            let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
+            CasBackend::new(store, 1024, &snapshot_path).unwrap()
+        };
+
+        let backend = open().with_block_size(16384).unwrap();
+        backend.write(40, &[0x3C; 512]).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+
+        // A different block size would lay the tree out differently; refused
+        assert!(open().with_block_size(4096).is_err());
+        let backend = open();
+        assert_eq!(backend.block_size(), 16384);
+        assert_eq!(backend.read(40, 1).unwrap(), vec![0x3C; 512]);
+        assert!(backend.with_block_size(16384).is_ok());
+
+        // A record from before block sizes were kept gets the configured one
+        let path = geometry_path(&snapshot_path);
+        std::fs::write(&path, r#"{"sector_size":512}"#).unwrap();
+        let backend = open().with_block_size(16384).unwrap();
+        backend.write(41, &[0x3D; 512]).unwrap();
+        drop(backend);
+        assert_eq!(Geometry::load(&path).unwrap().unwrap().block_size, Some(16384));
+    }
+
+    #[test]
+    fn test_cas_write_zeroes() {
+        let (_temp, backend) = create_test_backend();
+
+        backend.write(10, &vec![0xAA; 4 * 512]).unwrap();
+        let before = backend.stats().unique_blocks;
+        backend.write_zeroes(11, 2).unwrap();
+
+        let data = backend.read(10, 4).unwrap();
+        assert_eq!(data[..512], [0xAA; 512]);
+        assert_eq!(data[512..1536], [0u8; 1024]);
+        assert_eq!(data[1536..], [0xAA; 512]);
+        // Nothing new was stored
+        assert_eq!(backend.stats().unique_blocks, before);
+        assert!(backend.write_zeroes(backend.info().total_sectors, 1).is_err());
+    }
+
+    #[test]
+    fn test_cas_zero_writes_store_nothing() {
+        let (temp, backend) = create_test_backend();
+        let blobs = || FileBlobStore::new(temp.path().join("blobs")).unwrap().blobs().unwrap();
+
+        // Formatting a fresh target: zeros everywhere, nothing stored
+        backend.write(0, &vec![0u8; 64 * 512]).unwrap();
+        assert!(blobs().is_empty());
+        assert_eq!(backend.stats().zero_blocks, 64);
+
+        // Zero runs between data stay sparse
+        let mut data = vec![0u8; 8 * 512];
+        data[..512].fill(0xAA);
+        data[7 * 512..].fill(0xBB);
+        backend.write(100, &data).unwrap();
+        assert_eq!(backend.read(100, 8).unwrap(), data);
+        assert_eq!(backend.stats().zero_blocks, 70);
+        assert_eq!(backend.allocated_blocks().unwrap(), 2);
+
+        // Zeroing the data again leaves an empty tree
+        backend.write(100, &vec![0u8; 8 * 512]).unwrap();
+        assert_eq!(backend.allocated_blocks().unwrap(), 0);
+        assert_eq!(backend.read(100, 8).unwrap(), vec![0u8; 8 * 512]);
+    }
+
     #[test]
     fn test_cas_multiple_sectors() {
-        let (_temp, mut backend) = create_test_backend();
+        let (_temp, backend) = create_test_backend();
 
         // Write multiple sectors
         let mut write_data = Vec::new();
@@ -311,7 +1899,7 @@ mod tests {
         let snapshot_path = temp.path().join("snapshots.json");
 
         let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
-        let mut backend = CasBackend::new(store, 1024, &snapshot_path).unwrap();
+        let backend = CasBackend::new(store, 1024, &snapshot_path).unwrap();
 
         // Write same data to two different locations
         let data = vec![0xBB; 512];
@@ -322,14 +1910,22 @@ mod tests {
         assert_eq!(backend.read(0, 1).unwrap(), data);
         assert_eq!(backend.read(100, 1).unwrap(), data);
 
-        // The blob store should only have one copy (plus tree nodes)
-        // We can't easily verify this without exposing internals,
-        // but the implementation uses content-addressing
+        // The second write found the block already stored
+        backend.write(200, &[0u8; 512]).unwrap();
+        let stats = backend.stats();
+        assert_eq!(stats.blocks_written, 3);
+        assert_eq!(stats.logical_bytes, 3 * 512);
+        assert_eq!(stats.unique_blocks, 1);
+        assert_eq!(stats.duplicate_blocks, 1);
+        assert_eq!(stats.zero_blocks, 1);
+        // 0xBB repeated compresses well
+        assert!(stats.compression_savings() > 0);
+        assert!(stats.dedup_ratio() > 3.0);
     }
 
     #[test]
     fn test_cas_snapshots() {
-        let (_temp, mut backend) = create_test_backend();
+        let (_temp, backend) = create_test_backend();
 
         // Write initial data
         backend.write(0, &vec![0x11; 512]).unwrap();
@@ -345,11 +1941,125 @@ mod tests {
         // Restore to first snapshot
         backend.restore(&snap1).unwrap();
         assert_eq!(backend.read(0, 1).unwrap(), vec![0x11; 512]);
+
+        // Writing on from the restored snapshot branches off it
+        backend.write(0, &vec![0x33; 512]).unwrap();
+        backend.snapshot(Some("version 3")).unwrap();
+        let snapshots = backend.list_snapshots().unwrap();
+        assert_eq!(snapshots[2].parent.as_deref(), Some(snap1.as_str()));
+        let tree = SnapshotTree::build(&snapshots);
+        assert_eq!(tree.len(), 1);
+        assert_eq!(tree[0].children.len(), 2);
+    }
+
+    #[test]
+    fn test_cas_named_snapshots_retention() {
+        let (_temp, backend) = create_test_backend();
+        let backend = backend.with_retention(vec![RetentionRule {
+            tag: "nightly".into(),
+            keep: 2,
+        }]);
+        let nightly = vec!["nightly".to_string()];
+
+        for day in 1..=3u8 {
+            backend.write(0, &vec![day; 512]).unwrap();
+            let name = format!("nightly-{}", day);
+            backend.snapshot_named(&name, None, &nightly).unwrap();
+        }
+        // The oldest nightly was pruned when the third was taken
+        let names: Vec<_> = backend
+            .list_snapshots()
+            .unwrap()
+            .into_iter()
+            .map(|s| s.name.unwrap())
+            .collect();
+        assert_eq!(names, vec!["nightly-2", "nightly-3"]);
+
+        // Names work wherever an id does
+        let prod = ["prod".to_string()];
+        let renamed = backend.update_snapshot("nightly-2", Some("golden"), Some(&prod)).unwrap();
+        assert_eq!((renamed.name.as_deref(), renamed.tags), (Some("golden"), prod.to_vec()));
+        // A refused name leaves the tags alone too
+        let staging = ["staging".to_string()];
+        assert!(backend.update_snapshot("golden", Some("nightly-3"), Some(&staging)).is_err());
+        assert_eq!(backend.list_snapshots().unwrap()[0].tags, prod.to_vec());
+        backend.restore("golden").unwrap();
+        assert_eq!(backend.read(0, 1).unwrap(), vec![2; 512]);
+        assert_eq!(backend.changed_since("golden", 0..u64::MAX).unwrap(), vec![]);
+
+        let err = backend.snapshot_named("golden", None, &[]).unwrap_err();
+        let StorageError::Io(err) = err else { panic!("unexpected error: {}", err) };
+        assert_eq!(err.kind(), std::io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn test_cas_root_survives_reopen() {
+        let temp = TempDir::new().unwrap();
+        let blob_path = temp.path().join("blobs");
+        let snapshot_path = temp.path().join("snapshots.json");
+
+        let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
+        let backend = CasBackend::new(store, 1024, &snapshot_path).unwrap();
+        backend.write(5, &vec![0x5C; 512]).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+
+        // No snapshot was taken: the journal alone restores the root
+        let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
+        let backend = CasBackend::new(store, 1024, &snapshot_path).unwrap();
+        assert_eq!(backend.read(5, 1).unwrap(), vec![0x5C; 512]);
+        drop(backend);
+
+        // Fewer sectors than initiators were shown is refused
+        let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
+        assert!(CasBackend::new(store, 512, &snapshot_path).is_err());
+    }
+
+    #[test]
+    fn test_persist_root_manual() {
+        let temp = TempDir::new().unwrap();
+        let blob_path = temp.path().join("blobs");
+        let snapshot_path = temp.path().join("snapshots.json");
+        let open = |persist_root| {
+            let store = Box::new(FileBlobStore::new(&blob_path).unwrap());
+            CasBackend::new(store, 1024, &snapshot_path)
+                .unwrap()
+                .with_persist_root(persist_root)
+        };
+
+        // A journal left by an earlier run is emptied
+        let backend = open(PersistRoot::OnFlush);
+        backend.write(5, &[0x5B; 512]).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+
+        let backend = open(PersistRoot::Manual);
+        assert_eq!(backend.read(5, 1).unwrap(), vec![0; 512]);
+        backend.write(5, &[0x5C; 512]).unwrap();
+        backend.snapshot(None).unwrap();
+        backend.write(5, &[0x5D; 512]).unwrap();
+        backend.flush().unwrap();
+        drop(backend);
+
+        // Only the snapshot survives
+        let backend = open(PersistRoot::Manual);
+        assert_eq!(backend.read(5, 1).unwrap(), vec![0x5C; 512]);
+
+        // Journaling again carries on from the snapshot
+        backend.write(5, &[0x5E; 512]).unwrap();
+        drop(backend);
+        let backend = open(PersistRoot::Interval(std::time::Duration::ZERO));
+        let synced = *backend.root_synced.lock().unwrap();
+        backend.write(6, &[0x6E; 512]).unwrap();
+        assert!(*backend.root_synced.lock().unwrap() > synced);
+        drop(backend);
+        let backend = open(PersistRoot::OnFlush);
+        assert_eq!(backend.read(5, 2).unwrap(), [[0x5C; 512], [0x6E; 512]].concat());
     }
 
     #[test]
     fn test_cas_list_snapshots() {
-        let (_temp, mut backend) = create_test_backend();
+        let (_temp, backend) = create_test_backend();
 
         backend.snapshot(Some("first")).unwrap();
         backend.snapshot(Some("second")).unwrap();
@@ -362,7 +2072,7 @@ mod tests {
 
     #[test]
     fn test_cas_compression() {
-        let (_temp, mut backend) = create_test_backend();
+        let (_temp, backend) = create_test_backend();
 
         // Write highly compressible data (all same byte)
         let data = vec![0x00; 512];
@@ -382,4 +2092,36 @@ mod tests {
         let read = backend.read(1, 1).unwrap();
         assert_eq!(read, random_data);
     }
+
+    #[test]
+    fn test_cas_concurrent_access() {
+        use std::sync::Arc;
+        use std::thread;
+
+        let (_temp, backend) = create_test_backend();
+        let backend = Arc::new(backend);
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|t| {
+                let backend = Arc::clone(&backend);
+                thread::spawn(move || {
+                    for i in 0..8u64 {
+                        let lba = t as u64 * 8 + i;
+                        backend.write(lba, &vec![t + 1; 512]).unwrap();
+                        assert_eq!(backend.read(lba, 1).unwrap(), vec![t + 1; 512]);
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        // Every writer's update must survive the others
+        for t in 0..4u8 {
+            let data = backend.read(t as u64 * 8, 8).unwrap();
+            assert!(data.iter().all(|&b| b == t + 1));
+        }
+    }
 }
@@ -0,0 +1,73 @@
+//! Threads for the CPU-heavy part of large writes
+//!
+//! Compressing and hashing the blocks of a large write is split between a
+//! fixed set of threads started with the backend, so a write only pays for
+//! handing its blocks over, not for starting threads.
+
+use std::io;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running jobs handed to them, until the pool is dropped
+pub struct WritePool {
+    jobs: SyncSender<Job>,
+    threads: usize,
+}
+
+impl WritePool {
+    /// Start `threads` threads named `name-N`
+    pub fn new(name: &str, threads: usize) -> io::Result<Self> {
+        let (jobs, queue) = mpsc::sync_channel::<Job>(threads);
+        // Held only by the threads: once they have all exited, `run` fails
+        // instead of blocking
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..threads {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    let job = queue.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })?;
+        }
+        Ok(Self { jobs, threads })
+    }
+
+    /// Number of threads
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Run `job` on the next free thread, waiting while all are busy
+    pub fn run(&self, job: impl FnOnce() + Send + 'static) -> io::Result<()> {
+        self.jobs
+            .send(Box::new(job))
+            .map_err(|_| io::Error::other("write threads have exited"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_runs_jobs() {
+        let pool = WritePool::new("test-pool", 3).unwrap();
+        let (done, results) = mpsc::channel();
+        for i in 0..16u32 {
+            let done = done.clone();
+            pool.run(move || done.send(i * i).unwrap()).unwrap();
+        }
+        drop(done);
+        let mut results: Vec<u32> = results.iter().collect();
+        results.sort_unstable();
+        assert_eq!(results, (0..16).map(|i| i * i).collect::<Vec<_>>());
+        assert_eq!(pool.threads(), 3);
+    }
+}
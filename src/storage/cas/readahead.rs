@@ -0,0 +1,202 @@
+//! Readahead for sequential reads
+//!
+//! A read that starts where the previous one ended continues a stream.
+//! Once a stream is established, the blocks just past it are looked up in
+//! the Merkle tree (cheap: the nodes are already cached) and their blobs
+//! fetched by a background thread, so the next reads find them in memory.
+
+use crate::blob::{BlobStore, Hash};
+use std::collections::{HashMap, VecDeque};
+use std::ops::Range;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Consecutive reads before a stream counts as sequential
+const SEQUENTIAL_READS: u32 = 2;
+
+/// Tracks one read stream and decides what to prefetch
+#[derive(Debug, Default)]
+pub struct SequentialDetector {
+    /// Block the next sequential read would start at
+    next: u64,
+    /// Sequential reads in a row
+    streak: u32,
+    /// End of what has already been prefetched
+    ahead: u64,
+}
+
+impl SequentialDetector {
+    /// Record a read of blocks `first..end`, returning blocks to prefetch
+    /// (up to `window` past the read, below `limit`) if it continues a
+    /// sequential stream
+    pub fn observe(
+        &mut self,
+        first: u64,
+        end: u64,
+        window: u64,
+        limit: u64,
+    ) -> Option<Range<u64>> {
+        // Reads may overlap the previous one by a block when they are
+        // smaller than a block
+        if first == self.next || (first < self.next && end > self.next) {
+            self.streak = self.streak.saturating_add(1);
+        } else {
+            self.streak = 1;
+            self.ahead = 0;
+        }
+        self.next = end;
+
+        if self.streak < SEQUENTIAL_READS {
+            return None;
+        }
+        let start = self.ahead.max(end);
+        let stop = (end + window).min(limit);
+        if start >= stop {
+            return None;
+        }
+        self.ahead = stop;
+        Some(start..stop)
+    }
+}
+
+/// Run `fetch` on a background thread for each key sent, dropping keys
+/// when the thread falls `depth` behind. The thread exits once the
+/// sender is dropped.
+pub fn spawn_prefetcher<K, F>(name: &str, depth: usize, fetch: F) -> SyncSender<K>
+where
+    K: Send + 'static,
+    F: Fn(K) + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel::<K>(depth);
+    let spawned = thread::Builder::new().name(name.to_string()).spawn(move || {
+        for key in receiver {
+            fetch(key);
+        }
+    });
+    if let Err(e) = spawned {
+        log::warn!("Readahead disabled: cannot start {}: {}", name, e);
+    }
+    sender
+}
+
+/// Queue a prefetch, giving up quietly when the prefetcher is busy or gone
+pub fn request_prefetch<K>(sender: &SyncSender<K>, key: K) {
+    // Readahead is only a hint; the read itself will fetch what's missing
+    let _ = sender.try_send(key);
+}
+
+/// Recently prefetched blobs, as stored, oldest evicted first
+struct BlobCache {
+    capacity: usize,
+    inner: Mutex<CachedBlobs>,
+}
+
+#[derive(Default)]
+struct CachedBlobs {
+    blobs: HashMap<Hash, Arc<Vec<u8>>>,
+    /// Insertion order, oldest first
+    order: VecDeque<Hash>,
+}
+
+impl BlobCache {
+    fn get(&self, hash: &Hash) -> Option<Arc<Vec<u8>>> {
+        self.inner.lock().unwrap().blobs.get(hash).cloned()
+    }
+
+    fn contains(&self, hash: &Hash) -> bool {
+        self.inner.lock().unwrap().blobs.contains_key(hash)
+    }
+
+    fn insert(&self, hash: Hash, data: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.blobs.insert(hash, Arc::new(data)).is_none() {
+            inner.order.push_back(hash);
+        }
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.blobs.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Sequential readahead for a CAS backend
+pub struct Readahead {
+    blocks: usize,
+    detector: Mutex<SequentialDetector>,
+    cache: Arc<BlobCache>,
+    sender: SyncSender<Hash>,
+}
+
+impl Readahead {
+    /// Prefetch `blocks` blocks ahead of sequential reads from `store`
+    pub fn new(store: Arc<dyn BlobStore>, blocks: usize) -> Self {
+        // Room for the window being read plus the one being fetched
+        let cache = Arc::new(BlobCache {
+            capacity: blocks * 2,
+            inner: Mutex::new(CachedBlobs::default()),
+        });
+        let fetched = Arc::clone(&cache);
+        let sender = spawn_prefetcher("cas-readahead", blocks, move |hash: Hash| {
+            if fetched.contains(&hash) {
+                return;
+            }
+            match store.get(&hash) {
+                Ok(data) => fetched.insert(hash, data),
+                Err(e) => log::debug!("Readahead of {} failed: {}", hash, e),
+            }
+        });
+        Self {
+            blocks,
+            detector: Mutex::new(SequentialDetector::default()),
+            cache,
+            sender,
+        }
+    }
+
+    /// Record a read of blocks `first..end` out of `total`, returning the
+    /// blocks whose blobs should be prefetched
+    pub fn observe(&self, first: u64, end: u64, total: u64) -> Option<Range<u64>> {
+        self.detector
+            .lock()
+            .unwrap()
+            .observe(first, end, self.blocks as u64, total)
+    }
+
+    /// Fetch a blob in the background unless it's sparse or already here
+    pub fn prefetch(&self, hash: Hash) {
+        if !hash.is_zero() && !self.cache.contains(&hash) {
+            request_prefetch(&self.sender, hash);
+        }
+    }
+
+    /// A prefetched blob, as stored
+    pub fn get(&self, hash: &Hash) -> Option<Arc<Vec<u8>>> {
+        self.cache.get(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequential_detector() {
+        let mut detector = SequentialDetector::default();
+        // A lone read prefetches nothing
+        assert_eq!(detector.observe(100, 102, 8, 1000), None);
+        // The stream continues: prefetch past it
+        assert_eq!(detector.observe(102, 104, 8, 1000), Some(104..112));
+        // Only what wasn't prefetched yet
+        assert_eq!(detector.observe(104, 106, 8, 1000), Some(112..114));
+        // Sub-block reads overlap the previous block
+        assert_eq!(detector.observe(105, 107, 8, 1000), Some(114..115));
+        // A seek starts over
+        assert_eq!(detector.observe(500, 501, 8, 1000), None);
+        assert_eq!(detector.observe(501, 502, 8, 1000), Some(502..510));
+        // Never past the end of the device
+        assert_eq!(detector.observe(995, 998, 8, 1000), None);
+        assert_eq!(detector.observe(998, 1000, 8, 1000), None);
+    }
+}
@@ -0,0 +1,228 @@
+//! Similarity compression of near-duplicate blocks
+//!
+//! Blocks of OS images often match a block already stored except for a few
+//! bytes (a timestamp, a counter, a checksum), which exact dedup can't
+//! exploit. With similarity compression on, each new block's resemblance
+//! sketch is looked up among recently stored blocks; when a similar block
+//! is found and few enough bytes differ, the new block is stored as a
+//! delta: the base block's hash and the byte ranges that differ from it.
+//!
+//! A sketch is a handful of features, each the smallest of a differently
+//! salted hash over every 8-byte window of the block. Changing a few bytes
+//! changes few windows, so similar blocks usually share most features.
+//!
+//! Bases are always whole blocks stored normally, never deltas, so reading
+//! a delta costs one more blob fetch and no chains. The feature index is
+//! kept in memory only and bounded; after a restart, new blocks find bases
+//! among the blocks stored since.
+
+use crate::blob::Hash;
+use crate::storage::{StorageError, StorageResult};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+/// Marker byte of a stored delta (data blocks use the codec markers)
+pub const MARKER_DELTA: u8 = 0x11;
+
+/// Default number of recent blocks the index finds bases among
+pub const DEFAULT_INDEX_BLOCKS: usize = 65536;
+
+/// Features per sketch
+const FEATURES: usize = 4;
+
+/// Bytes hashed per window
+const WINDOW: usize = 8;
+
+/// Bytes of a delta range header: offset and length
+const RANGE_HEADER: usize = 8;
+
+/// Salts making each feature an independent sample
+const SALTS: [u64; FEATURES] = [
+    0x243F_6A88_85A3_08D3,
+    0x1319_8A2E_0370_7344,
+    0xA409_3822_299F_31D0,
+    0x082E_FA98_EC4E_6C89,
+];
+
+/// Resemblance sketch of a block
+pub type Sketch = [u64; FEATURES];
+
+/// Sketch a block
+pub fn sketch(data: &[u8]) -> Sketch {
+    let mut sketch = [u64::MAX; FEATURES];
+    for window in data.windows(WINDOW) {
+        let word = u64::from_le_bytes(window.try_into().unwrap());
+        for (feature, salt) in sketch.iter_mut().zip(SALTS) {
+            // splitmix64 finalizer
+            let mut hash = (word ^ salt).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+            hash = (hash ^ (hash >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            hash ^= hash >> 31;
+            *feature = (*feature).min(hash);
+        }
+    }
+    sketch
+}
+
+/// Recently stored blocks by feature, oldest evicted first
+pub struct SimilarityIndex {
+    capacity: usize,
+    inner: Mutex<Features>,
+}
+
+#[derive(Default)]
+struct Features {
+    bases: HashMap<u64, Hash>,
+    /// Features in insertion order, oldest first
+    order: VecDeque<u64>,
+}
+
+impl SimilarityIndex {
+    /// Index up to `blocks` recent blocks
+    pub fn new(blocks: usize) -> Self {
+        Self {
+            capacity: blocks.max(1) * FEATURES,
+            inner: Mutex::new(Features::default()),
+        }
+    }
+
+    /// The indexed block sharing most features with `sketch`, if any
+    pub fn find(&self, sketch: &Sketch) -> Option<Hash> {
+        let inner = self.inner.lock().unwrap();
+        let mut candidates: Vec<(Hash, usize)> = Vec::new();
+        for base in sketch.iter().filter_map(|feature| inner.bases.get(feature)) {
+            match candidates.iter_mut().find(|(hash, _)| hash == base) {
+                Some((_, shared)) => *shared += 1,
+                None => candidates.push((*base, 1)),
+            }
+        }
+        candidates.into_iter().max_by_key(|&(_, shared)| shared).map(|(hash, _)| hash)
+    }
+
+    /// Index a block stored as `hash`
+    pub fn insert(&self, sketch: &Sketch, hash: Hash) {
+        let mut inner = self.inner.lock().unwrap();
+        for &feature in sketch {
+            if inner.bases.insert(feature, hash).is_none() {
+                inner.order.push_back(feature);
+            }
+        }
+        while inner.order.len() > self.capacity {
+            if let Some(oldest) = inner.order.pop_front() {
+                inner.bases.remove(&oldest);
+            }
+        }
+    }
+}
+
+/// Encode `data` as a delta against `base` (the same length), marker byte
+/// first. Differences separated by less than a range header are merged.
+pub fn encode_delta(base_hash: &Hash, base: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(1 + 32 + RANGE_HEADER);
+    out.push(MARKER_DELTA);
+    out.extend_from_slice(base_hash.as_bytes());
+
+    let differs = |i: usize| data[i] != base[i];
+    let mut i = 0;
+    while i < data.len() {
+        if !differs(i) {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let mut end = i + 1;
+        let mut next = end;
+        while next < data.len() && next - end < RANGE_HEADER {
+            if differs(next) {
+                end = next + 1;
+            }
+            next += 1;
+        }
+        out.extend_from_slice(&(start as u32).to_le_bytes());
+        out.extend_from_slice(&((end - start) as u32).to_le_bytes());
+        out.extend_from_slice(&data[start..end]);
+        i = end;
+    }
+    out
+}
+
+/// The base block a stored delta refers to, or None for other blobs
+pub fn delta_base(stored: &[u8]) -> Option<Hash> {
+    match stored.split_first() {
+        Some((&MARKER_DELTA, body)) if body.len() >= 32 => {
+            Some(Hash::from_bytes(body[..32].try_into().unwrap()))
+        }
+        _ => None,
+    }
+}
+
+/// Rebuild a block from a delta produced by `encode_delta` and its base
+pub fn apply_delta(stored: &[u8], base: &[u8]) -> StorageResult<Vec<u8>> {
+    let mut ranges = match stored.split_first() {
+        Some((&MARKER_DELTA, body)) if body.len() >= 32 => &body[32..],
+        _ => return Err(StorageError::Corrupted),
+    };
+    let mut data = base.to_vec();
+    while !ranges.is_empty() {
+        if ranges.len() < RANGE_HEADER {
+            return Err(StorageError::Corrupted);
+        }
+        let offset = u32::from_le_bytes(ranges[..4].try_into().unwrap()) as usize;
+        let len = u32::from_le_bytes(ranges[4..8].try_into().unwrap()) as usize;
+        let bytes = ranges.get(RANGE_HEADER..RANGE_HEADER + len);
+        let target = data.get_mut(offset..offset.saturating_add(len));
+        let (Some(bytes), Some(target)) = (bytes, target) else {
+            return Err(StorageError::Corrupted);
+        };
+        target.copy_from_slice(bytes);
+        ranges = &ranges[RANGE_HEADER + len..];
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn block(seed: u32) -> Vec<u8> {
+        (0..4096u32).map(|i| (i.wrapping_mul(seed) >> 3) as u8).collect()
+    }
+
+    #[test]
+    fn test_delta_round_trip() {
+        let base = block(2654435761);
+        let mut data = base.clone();
+        data[100] ^= 0xFF;
+        data[104] ^= 0xFF;
+        data[4000..4010].iter_mut().for_each(|byte| *byte ^= 0x55);
+
+        let base_hash = Hash::from_data(&base);
+        let delta = encode_delta(&base_hash, &base, &data);
+        // Two ranges: the close differences merge
+        assert_eq!(delta.len(), 1 + 32 + (RANGE_HEADER + 5) + (RANGE_HEADER + 10));
+        assert_eq!(delta_base(&delta), Some(base_hash));
+        assert_eq!(apply_delta(&delta, &base).unwrap(), data);
+
+        assert_eq!(delta_base(&[0x00, 1, 2]), None);
+        assert!(apply_delta(&delta[..delta.len() - 1], &base).is_err());
+    }
+
+    #[test]
+    fn test_similar_blocks_found() {
+        let index = SimilarityIndex::new(16);
+        let base = block(2654435761);
+        let base_hash = Hash::from_data(&base);
+        index.insert(&sketch(&base), base_hash);
+
+        let mut similar = base.clone();
+        similar[2048..2056].copy_from_slice(b"modified");
+        assert_eq!(index.find(&sketch(&similar)), Some(base_hash));
+        assert_eq!(index.find(&sketch(&block(40503))), None);
+
+        // Old blocks give way to new ones
+        for seed in 0..32 {
+            index.insert(&sketch(&block(seed * 2 + 1)), Hash::from_data(&[seed as u8]));
+        }
+        assert_eq!(index.inner.lock().unwrap().order.len(), 16 * FEATURES);
+        assert_eq!(index.find(&sketch(&similar)), None);
+    }
+}
@@ -2,12 +2,19 @@
 //!
 //! Handles creating, listing, and restoring snapshots.
 //! A snapshot is simply a recorded root hash at a point in time.
+//! Snapshots may also carry a unique name and tags; a name can be used
+//! anywhere a root hash id is accepted.
+//!
+//! Each snapshot records its parent: the snapshot the device was at (last
+//! taken or restored) when it was taken. That snapshot, the head, is kept
+//! in a `.head` file next to the snapshots file so it survives restarts.
 
 use crate::blob::Hash;
-use crate::storage::SnapshotInfo;
+use crate::storage::{sync_parent_dir, RetentionRule, SnapshotInfo};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -21,6 +28,28 @@ struct SnapshotEntry {
     /// Optional description
     #[serde(default, skip_serializing_if = "Option::is_none")]
     description: Option<String>,
+    /// Unique name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Retention tags
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tags: Vec<String>,
+    /// Root hash of the parent snapshot
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<String>,
+}
+
+impl SnapshotEntry {
+    fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            id: self.root.clone(),
+            timestamp: self.timestamp,
+            description: self.description.clone(),
+            name: self.name.clone(),
+            tags: self.tags.clone(),
+            parent: self.parent.clone(),
+        }
+    }
 }
 
 /// Manages snapshots for a CAS backend
@@ -29,6 +58,8 @@ pub struct SnapshotManager {
     path: PathBuf,
     /// Loaded snapshots
     snapshots: Vec<SnapshotEntry>,
+    /// Root hash of the snapshot the device was last at
+    head: Option<String>,
 }
 
 impl SnapshotManager {
@@ -41,12 +72,38 @@ impl SnapshotManager {
         } else {
             Vec::new()
         };
+        // Histories from before heads were recorded carry on from the latest
+        let head = match fs::read_to_string(head_path(&path)) {
+            Ok(head) => Some(head.trim().to_string()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                snapshots.last().map(|s: &SnapshotEntry| s.root.clone())
+            }
+            Err(e) => return Err(e),
+        };
 
-        Ok(Self { path, snapshots })
+        Ok(Self {
+            path,
+            snapshots,
+            head,
+        })
     }
 
     /// Create a new snapshot
     pub fn create(&mut self, root_hash: Hash, description: Option<&str>) -> io::Result<String> {
+        self.create_with(root_hash, description, None, &[])
+    }
+
+    /// Create a new snapshot with an optional unique name and tags
+    pub fn create_with(
+        &mut self,
+        root_hash: Hash,
+        description: Option<&str>,
+        name: Option<&str>,
+        tags: &[String],
+    ) -> io::Result<String> {
+        if let Some(name) = name {
+            self.check_name(name)?;
+        }
         let timestamp = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
@@ -56,9 +113,13 @@ impl SnapshotManager {
             root: root_hash.to_hex(),
             timestamp,
             description: description.map(String::from),
+            name: name.map(String::from),
+            tags: dedup(tags),
+            parent: self.head.clone(),
         };
 
         self.snapshots.push(entry);
+        self.head = Some(root_hash.to_hex());
         self.save()?;
 
         Ok(root_hash.to_hex())
@@ -66,22 +127,99 @@ impl SnapshotManager {
 
     /// List all snapshots
     pub fn list(&self) -> Vec<SnapshotInfo> {
-        self.snapshots
-            .iter()
-            .map(|entry| SnapshotInfo {
-                id: entry.root.clone(),
-                timestamp: entry.timestamp,
-                description: entry.description.clone(),
-            })
-            .collect()
+        self.snapshots.iter().map(SnapshotEntry::info).collect()
     }
 
-    /// Get root hash for a snapshot ID
-    pub fn get(&self, snapshot_id: &str) -> Option<Hash> {
-        self.snapshots
-            .iter()
-            .find(|s| s.root == snapshot_id)
-            .and_then(|s| Hash::from_hex(&s.root).ok())
+    /// Get root hash for a snapshot ID or name
+    pub fn get(&self, snapshot: &str) -> Option<Hash> {
+        self.position(snapshot)
+            .and_then(|i| Hash::from_hex(&self.snapshots[i].root).ok())
+    }
+
+    /// Give a snapshot (by ID or name) a new name
+    pub fn rename(&mut self, snapshot: &str, name: &str) -> io::Result<()> {
+        self.update(snapshot, Some(name), None).map(drop)
+    }
+
+    /// Replace the tags of a snapshot (by ID or name)
+    pub fn set_tags(&mut self, snapshot: &str, tags: &[String]) -> io::Result<()> {
+        self.update(snapshot, None, Some(tags)).map(drop)
+    }
+
+    /// Rename a snapshot (by ID or name) and/or replace its tags in one
+    /// save: if the new name is refused, neither changes
+    pub fn update(
+        &mut self,
+        snapshot: &str,
+        name: Option<&str>,
+        tags: Option<&[String]>,
+    ) -> io::Result<SnapshotInfo> {
+        let index = self.position(snapshot).ok_or_else(|| not_found(snapshot))?;
+        let name = name.filter(|name| self.snapshots[index].name.as_deref() != Some(*name));
+        if let Some(name) = name {
+            self.check_name(name)?;
+        }
+
+        let previous = self.snapshots[index].clone();
+        let entry = &mut self.snapshots[index];
+        if let Some(name) = name {
+            entry.name = Some(name.to_string());
+        }
+        if let Some(tags) = tags {
+            entry.tags = dedup(tags);
+        }
+        let info = entry.info();
+        if let Err(e) = self.save() {
+            self.snapshots[index] = previous;
+            return Err(e);
+        }
+        Ok(info)
+    }
+
+    /// Delete snapshots the retention rules no longer keep, returning them.
+    ///
+    /// Each rule keeps the newest `keep` snapshots carrying its tag. A
+    /// snapshot is deleted only if it carries a ruled tag and no rule keeps
+    /// it; untagged snapshots are never pruned.
+    ///
+    /// The head and snapshots where history branches are never pruned: they
+    /// are what the live device and its sibling branches descend from.
+    pub fn prune(&mut self, rules: &[RetentionRule]) -> io::Result<Vec<SnapshotInfo>> {
+        let mut kept = self.protected();
+        for rule in rules {
+            // Snapshots are appended in order, so newest is last
+            let tagged = self
+                .snapshots
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, s)| s.tags.contains(&rule.tag));
+            kept.extend(tagged.take(rule.keep).map(|(i, _)| i));
+        }
+        let ruled = |s: &SnapshotEntry| rules.iter().any(|r| s.tags.contains(&r.tag));
+
+        let pruned = self.remove(|i, s| !kept.contains(&i) && ruled(s));
+        if !pruned.is_empty() {
+            self.save()?;
+        }
+        Ok(pruned.iter().map(SnapshotEntry::info).collect())
+    }
+
+    /// Root hash of the snapshot the device was last at
+    pub fn head(&self) -> Option<Hash> {
+        self.head.as_ref().and_then(|h| Hash::from_hex(h).ok())
+    }
+
+    /// Record that the device was restored to a snapshot (by ID or name),
+    /// so the next snapshot descends from it
+    pub fn set_head(&mut self, snapshot: &str) -> io::Result<Hash> {
+        let index = self.position(snapshot).ok_or_else(|| not_found(snapshot))?;
+        let root = self.snapshots[index].root.clone();
+        let hash = Hash::from_hex(&root)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.head = Some(root);
+        self.save()?;
+        Ok(hash)
     }
 
     /// Get the most recent snapshot
@@ -91,23 +229,114 @@ impl SnapshotManager {
             .and_then(|s| Hash::from_hex(&s.root).ok())
     }
 
-    /// Delete a snapshot by ID
+    /// Delete a snapshot by ID (every snapshot of that root) or by name
     pub fn delete(&mut self, snapshot_id: &str) -> io::Result<bool> {
-        let original_len = self.snapshots.len();
-        self.snapshots.retain(|s| s.root != snapshot_id);
+        let removed =
+            self.remove(|_, s| s.root == snapshot_id || s.name.as_deref() == Some(snapshot_id));
 
-        if self.snapshots.len() < original_len {
+        if removed.is_empty() {
+            Ok(false)
+        } else {
             self.save()?;
             Ok(true)
-        } else {
-            Ok(false)
         }
     }
 
+    /// Remove the snapshots `doomed` picks, pointing their children at the
+    /// nearest surviving ancestor
+    fn remove(&mut self, doomed: impl Fn(usize, &SnapshotEntry) -> bool) -> Vec<SnapshotEntry> {
+        let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.snapshots)
+            .into_iter()
+            .enumerate()
+            .partition(|(i, s)| doomed(*i, s));
+        self.snapshots = kept.into_iter().map(|(_, s)| s).collect();
+        let removed: Vec<_> = removed.into_iter().map(|(_, s)| s).collect();
+
+        // Where a removed root's history continues: the parent of its first
+        // snapshot, which is always older, so following these ends
+        let surviving: HashSet<_> = self.snapshots.iter().map(|s| s.root.as_str()).collect();
+        let mut parents: HashMap<String, Option<String>> = HashMap::new();
+        for snapshot in removed.iter().filter(|s| !surviving.contains(s.root.as_str())) {
+            if snapshot.parent.as_ref() != Some(&snapshot.root) {
+                parents.entry(snapshot.root.clone()).or_insert_with(|| snapshot.parent.clone());
+            }
+        }
+        let ancestor = |mut root: Option<String>| {
+            while let Some(parent) = root.as_ref().and_then(|r| parents.get(r)) {
+                root = parent.clone();
+            }
+            root
+        };
+        for snapshot in &mut self.snapshots {
+            snapshot.parent = ancestor(snapshot.parent.take());
+        }
+        self.head = ancestor(self.head.take());
+        removed
+    }
+
+    /// Indices of the head and of snapshots with more than one child
+    fn protected(&self) -> HashSet<usize> {
+        let mut children: HashMap<&str, usize> = HashMap::new();
+        for parent in self.snapshots.iter().filter_map(|s| s.parent.as_deref()) {
+            *children.entry(parent).or_default() += 1;
+        }
+        self.snapshots
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| {
+                self.head.as_deref() == Some(s.root.as_str())
+                    || children.get(s.root.as_str()).copied().unwrap_or(0) > 1
+            })
+            .map(|(i, _)| i)
+            .collect()
+    }
+
+    /// Index of the snapshot with this name, else the first with this ID
+    fn position(&self, snapshot: &str) -> Option<usize> {
+        self.snapshots
+            .iter()
+            .position(|s| s.name.as_deref() == Some(snapshot))
+            .or_else(|| self.snapshots.iter().position(|s| s.root == snapshot))
+    }
+
+    /// A name must be new, and must not be mistakable for an ID
+    fn check_name(&self, name: &str) -> io::Result<()> {
+        let looks_like_id = name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit());
+        if name.is_empty() || looks_like_id {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid snapshot name: {:?}", name),
+            ));
+        }
+        if self.snapshots.iter().any(|s| s.name.as_deref() == Some(name)) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("snapshot name already in use: {}", name),
+            ));
+        }
+        Ok(())
+    }
+
     /// Save snapshots to disk
     fn save(&self) -> io::Result<()> {
+        // Replaced whole, so a crash leaves the old list or the new one
         let content = serde_json::to_string_pretty(&self.snapshots)?;
-        fs::write(&self.path, content)
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+        sync_parent_dir(&self.path)?;
+        match &self.head {
+            Some(head) => fs::write(head_path(&self.path), head),
+            None => match fs::remove_file(head_path(&self.path)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
     }
 
     /// Get the path to the snapshot file
@@ -116,9 +345,25 @@ impl SnapshotManager {
     }
 }
 
+/// Head file lives next to the snapshots file
+fn head_path(path: &Path) -> PathBuf {
+    path.with_extension("head")
+}
+
+fn not_found(snapshot: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("snapshot not found: {}", snapshot))
+}
+
+/// Tags in first-seen order without repeats
+fn dedup(tags: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    tags.iter().filter(|t| seen.insert(t.as_str())).cloned().collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::SnapshotTree;
     use tempfile::TempDir;
 
     #[test]
@@ -189,4 +434,108 @@ mod tests {
         assert!(manager.delete(&id).unwrap());
         assert_eq!(manager.list().len(), 0);
     }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn test_snapshot_names() {
+        let temp = TempDir::new().unwrap();
+        let snapshot_path = temp.path().join("snapshots.json");
+        let mut manager = SnapshotManager::new(&snapshot_path).unwrap();
+
+        let hash = Hash::from_data(b"named");
+        let id = manager
+            .create_with(hash, None, Some("nightly-2024-06-01"), &tags(&["prod", "prod"]))
+            .unwrap();
+        assert_eq!(manager.get("nightly-2024-06-01"), Some(hash));
+        assert_eq!(manager.get(&id), Some(hash));
+        assert_eq!(manager.list()[0].tags, tags(&["prod"]));
+
+        // Names are unique and never look like an ID
+        let other = Hash::from_data(b"other");
+        let err = manager
+            .create_with(other, None, Some("nightly-2024-06-01"), &[])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let err = manager.create_with(other, None, Some(&other.to_hex()), &[]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        manager.rename("nightly-2024-06-01", "keeper").unwrap();
+        manager.set_tags("keeper", &tags(&["archive"])).unwrap();
+        assert!(manager.get("nightly-2024-06-01").is_none());
+
+        // Names and tags persist
+        let manager = SnapshotManager::new(&snapshot_path).unwrap();
+        let snapshots = manager.list();
+        assert_eq!(snapshots[0].name.as_deref(), Some("keeper"));
+        assert_eq!(snapshots[0].tags, tags(&["archive"]));
+        assert_eq!(manager.get("keeper"), Some(hash));
+    }
+
+    #[test]
+    fn test_snapshot_prune() {
+        let temp = TempDir::new().unwrap();
+        let mut manager = SnapshotManager::new(temp.path().join("snapshots.json")).unwrap();
+
+        let tagged: [&[&str]; 5] = [&["daily"], &["daily"], &["weekly"], &["daily"], &[]];
+        for (i, t) in tagged.iter().enumerate() {
+            let hash = Hash::from_data(format!("root{}", i).as_bytes());
+            manager.create_with(hash, None, Some(&format!("s{}", i)), &tags(t)).unwrap();
+        }
+        // s1 is also weekly: the weekly rule keeps it
+        manager.set_tags("s1", &tags(&["daily", "weekly"])).unwrap();
+
+        let rules = vec![
+            RetentionRule { tag: "daily".into(), keep: 1 },
+            RetentionRule { tag: "weekly".into(), keep: 2 },
+        ];
+        let pruned = manager.prune(&rules).unwrap();
+        let pruned: Vec<_> = pruned.iter().map(|s| s.name.clone().unwrap()).collect();
+        assert_eq!(pruned, vec!["s0"]);
+
+        let left: Vec<_> = manager.list().into_iter().map(|s| s.name.unwrap()).collect();
+        assert_eq!(left, vec!["s1", "s2", "s3", "s4"]);
+        assert!(manager.prune(&rules).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_snapshot_branches() {
+        let temp = TempDir::new().unwrap();
+        let snapshot_path = temp.path().join("snapshots.json");
+        let mut manager = SnapshotManager::new(&snapshot_path).unwrap();
+        let daily = tags(&["daily"]);
+
+        let roots: Vec<_> = (0..4).map(|i| Hash::from_data(&[i])).collect();
+        for (i, root) in roots[..3].iter().enumerate() {
+            manager.create_with(*root, None, Some(&format!("s{}", i)), &daily).unwrap();
+        }
+        // Restore s1 and write on: s3 branches off it
+        assert_eq!(manager.set_head("s1").unwrap(), roots[1]);
+        manager.create_with(roots[3], None, Some("s3"), &daily).unwrap();
+
+        let snapshots = manager.list();
+        assert_eq!(snapshots[0].parent, None);
+        assert_eq!(snapshots[3].parent, Some(roots[1].to_hex()));
+        let tree = SnapshotTree::build(&snapshots);
+        assert_eq!(tree.len(), 1);
+        let fork = &tree[0].children[0];
+        assert_eq!(fork.snapshot.name.as_deref(), Some("s1"));
+        assert_eq!(fork.children.len(), 2);
+
+        // The fork and the head survive retention
+        let rule = RetentionRule { tag: "daily".into(), keep: 1 };
+        let pruned = manager.prune(&[rule]).unwrap();
+        let pruned: Vec<_> = pruned.iter().map(|s| s.name.clone().unwrap()).collect();
+        assert_eq!(pruned, vec!["s0", "s2"]);
+
+        // Deleting the fork re-parents its children; the head persists
+        assert!(manager.delete("s1").unwrap());
+        let manager = SnapshotManager::new(&snapshot_path).unwrap();
+        assert_eq!(manager.head(), Some(roots[3]));
+        let snapshots = manager.list();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].parent, None);
+    }
 }
@@ -0,0 +1,108 @@
+//! Deduplication statistics
+//!
+//! Counters cover writes since the backend was opened; they are not persisted.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Point-in-time deduplication and compression statistics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct DedupStats {
+    /// Bytes written by initiators (including zero blocks)
+    pub logical_bytes: u64,
+    /// Blocks written
+    pub blocks_written: u64,
+    /// All-zero blocks (not stored)
+    pub zero_blocks: u64,
+    /// Blocks whose content was already in the blob store
+    pub duplicate_blocks: u64,
+    /// Blocks that added a new blob
+    pub unique_blocks: u64,
+    /// Of the unique blocks, those stored as deltas against a similar block
+    pub delta_blocks: u64,
+    /// Uncompressed size of the new blobs
+    pub unique_bytes: u64,
+    /// Bytes actually stored for the new blobs (after compression)
+    pub stored_bytes: u64,
+}
+
+impl DedupStats {
+    /// Bytes saved by compressing new blobs
+    pub fn compression_savings(&self) -> u64 {
+        self.unique_bytes.saturating_sub(self.stored_bytes)
+    }
+
+    /// Logical bytes per stored byte (1.0 when nothing was saved)
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.stored_bytes == 0 {
+            return if self.logical_bytes == 0 { 1.0 } else { f64::INFINITY };
+        }
+        self.logical_bytes as f64 / self.stored_bytes as f64
+    }
+}
+
+/// Lock-free counters updated on the write path
+#[derive(Default)]
+pub(super) struct StatsCounters {
+    logical_bytes: AtomicU64,
+    blocks_written: AtomicU64,
+    zero_blocks: AtomicU64,
+    duplicate_blocks: AtomicU64,
+    unique_blocks: AtomicU64,
+    delta_blocks: AtomicU64,
+    unique_bytes: AtomicU64,
+    stored_bytes: AtomicU64,
+}
+
+impl StatsCounters {
+    /// Record an all-zero block
+    pub(super) fn record_zero(&self, len: usize) {
+        self.record_write(len);
+        self.zero_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a run of `blocks` all-zero blocks, `len` bytes in all
+    pub(super) fn record_zero_run(&self, blocks: u64, len: usize) {
+        self.logical_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.blocks_written.fetch_add(blocks, Ordering::Relaxed);
+        self.zero_blocks.fetch_add(blocks, Ordering::Relaxed);
+    }
+
+    /// Record a block already present in the blob store
+    pub(super) fn record_duplicate(&self, len: usize) {
+        self.record_write(len);
+        self.duplicate_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record a block stored as a new blob of `stored_len` bytes
+    pub(super) fn record_unique(&self, len: usize, stored_len: usize) {
+        self.record_write(len);
+        self.unique_blocks.fetch_add(1, Ordering::Relaxed);
+        self.unique_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.stored_bytes.fetch_add(stored_len as u64, Ordering::Relaxed);
+    }
+
+    /// Record a block stored as a delta of `stored_len` bytes
+    pub(super) fn record_delta(&self, len: usize, stored_len: usize) {
+        self.record_unique(len, stored_len);
+        self.delta_blocks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_write(&self, len: usize) {
+        self.logical_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        self.blocks_written.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn snapshot(&self) -> DedupStats {
+        DedupStats {
+            logical_bytes: self.logical_bytes.load(Ordering::Relaxed),
+            blocks_written: self.blocks_written.load(Ordering::Relaxed),
+            zero_blocks: self.zero_blocks.load(Ordering::Relaxed),
+            duplicate_blocks: self.duplicate_blocks.load(Ordering::Relaxed),
+            unique_blocks: self.unique_blocks.load(Ordering::Relaxed),
+            delta_blocks: self.delta_blocks.load(Ordering::Relaxed),
+            unique_bytes: self.unique_bytes.load(Ordering::Relaxed),
+            stored_bytes: self.stored_bytes.load(Ordering::Relaxed),
+        }
+    }
+}
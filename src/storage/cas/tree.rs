@@ -2,8 +2,19 @@
 //!
 //! Implements a content-addressed tree structure where each node contains
 //! hashes pointing to child nodes or data blocks.
+//!
+//! Every read and write walks the tree from the root, so the same root and
+//! interior nodes are fetched again and again. A `NodeCache` shared by the
+//! tree views keeps recently used nodes in memory. Nodes are stored under
+//! the hash of their content and never change, so a cached node can't go
+//! stale; an updated tree simply has new hashes.
 
 use crate::blob::{BlobError, BlobStore, Hash};
+use crate::storage::is_all_zero;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 
 /// Number of hashes per pointer block (4096 / 32 = 128)
 pub const FANOUT: usize = 128;
@@ -14,9 +25,118 @@ pub const BLOCK_SIZE: usize = 4096;
 /// Hash size in bytes
 pub const HASH_SIZE: usize = 32;
 
+/// Default number of tree nodes a CAS backend keeps cached (4 MiB)
+pub const DEFAULT_NODE_CACHE_NODES: usize = 1024;
+
+/// Bounded LRU cache of tree nodes keyed by hash
+pub struct NodeCache {
+    capacity: usize,
+    inner: Mutex<CacheInner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    /// Hash -> (node, last use)
+    nodes: HashMap<Hash, (Arc<Vec<u8>>, u64)>,
+    /// Last use -> hash, oldest first
+    order: BTreeMap<u64, Hash>,
+    tick: u64,
+}
+
+impl NodeCache {
+    /// Create a cache holding up to `capacity` nodes; zero disables it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Mutex::new(CacheInner::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Look up a node, marking it recently used
+    pub fn get(&self, hash: &Hash) -> Option<Arc<Vec<u8>>> {
+        let mut guard = self.inner.lock().unwrap();
+        let inner = &mut *guard;
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let Some((node, last_use)) = inner.nodes.get_mut(hash) else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        let previous = std::mem::replace(last_use, tick);
+        let node = Arc::clone(node);
+        inner.order.remove(&previous);
+        inner.order.insert(tick, *hash);
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(node)
+    }
+
+    /// Add a node, evicting the least recently used ones to make room
+    pub fn insert(&self, hash: Hash, node: Arc<Vec<u8>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        if let Some((_, last_use)) = inner.nodes.insert(hash, (node, tick)) {
+            inner.order.remove(&last_use);
+        }
+        inner.order.insert(tick, hash);
+
+        while inner.nodes.len() > self.capacity {
+            let Some((_, oldest)) = inner.order.pop_first() else {
+                break;
+            };
+            inner.nodes.remove(&oldest);
+        }
+    }
+
+    /// (hits, misses) since the cache was created
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Number of nodes currently cached
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Fetch a node through `cache` if there is one, else from the store
+fn get_node(
+    blob_store: &dyn BlobStore,
+    cache: Option<&NodeCache>,
+    hash: &Hash,
+) -> Result<Arc<Vec<u8>>, BlobError> {
+    let Some(cache) = cache else {
+        return Ok(Arc::new(blob_store.get(hash)?));
+    };
+    if let Some(node) = cache.get(hash) {
+        return Ok(node);
+    }
+    let node = Arc::new(blob_store.get(hash)?);
+    cache.insert(*hash, Arc::clone(&node));
+    Ok(node)
+}
+
 /// Merkle tree for mapping LBAs to content hashes
 pub struct MerkleTree<'a> {
     blob_store: &'a dyn BlobStore,
+    cache: Option<&'a NodeCache>,
     root_hash: Hash,
     depth: u8,
     total_sectors: u64,
@@ -32,12 +152,19 @@ impl<'a> MerkleTree<'a> {
         let depth = calculate_depth(total_sectors);
         Self {
             blob_store,
+            cache: None,
             root_hash,
             depth,
             total_sectors,
         }
     }
 
+    /// Read nodes through `cache`
+    pub fn with_cache(mut self, cache: &'a NodeCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     /// Get the current root hash
     pub fn root_hash(&self) -> Hash {
         self.root_hash
@@ -59,7 +186,7 @@ impl<'a> MerkleTree<'a> {
 
         // For depth 1, root directly contains data hashes
         if self.depth == 1 {
-            let root_block = self.blob_store.get(&self.root_hash)?;
+            let root_block = get_node(self.blob_store, self.cache, &self.root_hash)?;
             let index = lba as usize;
             return Ok(extract_hash(&root_block, index));
         }
@@ -71,7 +198,7 @@ impl<'a> MerkleTree<'a> {
                 return Ok(Hash::ZERO); // Sparse region
             }
 
-            let node = self.blob_store.get(&current_hash)?;
+            let node = get_node(self.blob_store, self.cache, &current_hash)?;
             let index = extract_index(lba, level, self.depth);
 
             if level == self.depth - 1 {
@@ -85,14 +212,87 @@ impl<'a> MerkleTree<'a> {
 
         Ok(Hash::ZERO)
     }
+
+    /// Number of leaves pointing at data. Walks every node below the
+    /// root, bypassing the cache so the walk doesn't evict hot nodes.
+    pub fn count_allocated(&self) -> Result<u64, BlobError> {
+        self.count_below(self.root_hash, 0)
+    }
+
+    /// Leaf ranges within `leaves` whose hashes differ between this tree
+    /// and the tree rooted at `other`, which has the same size. Subtrees
+    /// with equal hashes or outside `leaves` are skipped, so the walk costs
+    /// in proportion to the change inside the window.
+    pub fn diff(&self, other: Hash, leaves: Range<u64>) -> Result<Vec<Range<u64>>, BlobError> {
+        let mut ranges = Vec::new();
+        self.diff_below(self.root_hash, other, 0, 0, &leaves, &mut ranges)?;
+        Ok(ranges)
+    }
+
+    fn diff_below(
+        &self,
+        ours: Hash,
+        theirs: Hash,
+        level: u8,
+        first_leaf: u64,
+        leaves: &Range<u64>,
+        ranges: &mut Vec<Range<u64>>,
+    ) -> Result<(), BlobError> {
+        let covered = (FANOUT as u64).pow((self.depth - level) as u32);
+        let outside = first_leaf >= leaves.end || first_leaf + covered <= leaves.start;
+        if ours == theirs || first_leaf >= self.total_sectors || outside {
+            return Ok(());
+        }
+        // A zero hash is a sparse subtree: every child is zero
+        let load = |hash: Hash| -> Result<Option<Arc<Vec<u8>>>, BlobError> {
+            match hash.is_zero() {
+                true => Ok(None),
+                false => get_node(self.blob_store, self.cache, &hash).map(Some),
+            }
+        };
+        let (ours, theirs) = (load(ours)?, load(theirs)?);
+        let child = |node: &Option<Arc<Vec<u8>>>, index| {
+            node.as_ref().map_or(Hash::ZERO, |node| extract_hash(node, index))
+        };
+        let span = (FANOUT as u64).pow((self.depth - 1 - level) as u32);
+
+        for index in 0..FANOUT {
+            let (a, b) = (child(&ours, index), child(&theirs, index));
+            let leaf = first_leaf + index as u64 * span;
+            if level < self.depth - 1 {
+                self.diff_below(a, b, level + 1, leaf, leaves, ranges)?;
+            } else if a != b && leaf < self.total_sectors && leaves.contains(&leaf) {
+                match ranges.last_mut() {
+                    Some(last) if last.end == leaf => last.end = leaf + 1,
+                    _ => ranges.push(leaf..leaf + 1),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn count_below(&self, hash: Hash, level: u8) -> Result<u64, BlobError> {
+        if hash.is_zero() {
+            return Ok(0);
+        }
+        let node = self.blob_store.get(&hash)?;
+        let children = (0..FANOUT).map(|index| extract_hash(&node, index));
+        if level == self.depth - 1 {
+            return Ok(children.filter(|child| !child.is_zero()).count() as u64);
+        }
+        children.map(|child| self.count_below(child, level + 1)).sum()
+    }
 }
 
 /// Mutable Merkle tree for updates
 pub struct MerkleTreeMut<'a> {
     blob_store: &'a dyn BlobStore,
+    cache: Option<&'a NodeCache>,
     root_hash: Hash,
     depth: u8,
     total_sectors: u64,
+    /// Change in the number of allocated leaves since creation
+    allocated_delta: i64,
 }
 
 impl<'a> MerkleTreeMut<'a> {
@@ -105,12 +305,20 @@ impl<'a> MerkleTreeMut<'a> {
         let depth = calculate_depth(total_sectors);
         Self {
             blob_store,
+            cache: None,
             root_hash,
             depth,
             total_sectors,
+            allocated_delta: 0,
         }
     }
 
+    /// Read nodes through `cache`, adding the nodes each update writes
+    pub fn with_cache(mut self, cache: &'a NodeCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     /// Create a new empty tree
     pub fn empty(blob_store: &'a dyn BlobStore, total_sectors: u64) -> Self {
         Self::new(blob_store, Hash::ZERO, total_sectors)
@@ -121,6 +329,16 @@ impl<'a> MerkleTreeMut<'a> {
         self.root_hash
     }
 
+    /// Leaves that became allocated minus those that became sparse
+    pub fn allocated_delta(&self) -> i64 {
+        self.allocated_delta
+    }
+
+    /// Count a leaf going from `old` to `new`
+    fn track_leaf(&mut self, old: Hash, new: Hash) {
+        self.allocated_delta += old.is_zero() as i64 - new.is_zero() as i64;
+    }
+
     /// Update the hash for a given LBA
     pub fn update(&mut self, lba: u64, data_hash: Hash) -> Result<(), BlobError> {
         if lba >= self.total_sectors {
@@ -142,7 +360,7 @@ impl<'a> MerkleTreeMut<'a> {
                 // Create empty node
                 vec![0u8; BLOCK_SIZE]
             } else {
-                self.blob_store.get(&current_hash)?
+                get_node(self.blob_store, self.cache, &current_hash)?.to_vec()
             };
 
             if level < self.depth - 1 {
@@ -155,7 +373,9 @@ impl<'a> MerkleTreeMut<'a> {
 
         // Update the leaf node with the data hash
         if let Some((ref mut leaf, index)) = path.last_mut() {
+            let old = extract_hash(leaf, *index);
             set_hash(leaf, *index, &data_hash);
+            self.track_leaf(old, data_hash);
         }
 
         // Walk back up, updating each node and computing new hashes
@@ -169,10 +389,7 @@ impl<'a> MerkleTreeMut<'a> {
                 set_hash(&mut node, index, &child_hash);
             }
 
-            // Compute and store new node hash
-            let new_hash = Hash::from_data(&node);
-            self.blob_store.put(&new_hash, &node)?;
-            child_hash = new_hash;
+            child_hash = self.store_node(node)?;
         }
 
         // Update root
@@ -180,9 +397,88 @@ impl<'a> MerkleTreeMut<'a> {
         Ok(())
     }
 
+    /// Apply many (LBA, hash) updates at once. Each affected node is read
+    /// and rewritten once, instead of once per LBA as with `update`. If an
+    /// LBA appears more than once, its last hash wins.
+    pub fn update_batch(&mut self, updates: &[(u64, Hash)]) -> Result<(), BlobError> {
+        if let Some(&(lba, _)) = updates.iter().find(|(lba, _)| *lba >= self.total_sectors) {
+            return Err(BlobError::Backend(format!(
+                "LBA {} out of range (max {})",
+                lba, self.total_sectors
+            )));
+        }
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        // A stable sort keeps duplicates in order, so dedup keeps the last
+        let mut sorted = updates.to_vec();
+        sorted.sort_by_key(|&(lba, _)| lba);
+        sorted.reverse();
+        sorted.dedup_by_key(|&mut (lba, _)| lba);
+        sorted.reverse();
+
+        self.root_hash = self.rewrite_node(self.root_hash, 0, &sorted)?;
+        Ok(())
+    }
+
+    /// Apply `updates` (sorted, all under this node) to the node `hash` at
+    /// `level`, returning the rewritten node's hash
+    fn rewrite_node(
+        &mut self,
+        hash: Hash,
+        level: u8,
+        updates: &[(u64, Hash)],
+    ) -> Result<Hash, BlobError> {
+        let mut node = if hash.is_zero() {
+            vec![0u8; BLOCK_SIZE]
+        } else {
+            get_node(self.blob_store, self.cache, &hash)?.to_vec()
+        };
+
+        if level == self.depth - 1 {
+            for &(lba, data_hash) in updates {
+                let index = extract_index(lba, level, self.depth);
+                self.track_leaf(extract_hash(&node, index), data_hash);
+                set_hash(&mut node, index, &data_hash);
+            }
+        } else {
+            // Updates are sorted, so each child's share is a contiguous run
+            let mut rest = updates;
+            while let Some(&(lba, _)) = rest.first() {
+                let index = extract_index(lba, level, self.depth);
+                let run = rest
+                    .iter()
+                    .take_while(|&&(l, _)| extract_index(l, level, self.depth) == index)
+                    .count();
+                let child = self.rewrite_node(extract_hash(&node, index), level + 1, &rest[..run])?;
+                set_hash(&mut node, index, &child);
+                rest = &rest[run..];
+            }
+        }
+
+        self.store_node(node)
+    }
+
+    /// Store a rewritten node, returning its hash. A node left with only
+    /// sparse children is sparse itself, so zeroing a region (or writing
+    /// zeros to a fresh target) stores no nodes at all.
+    fn store_node(&self, node: Vec<u8>) -> Result<Hash, BlobError> {
+        if is_all_zero(&node) {
+            return Ok(Hash::ZERO);
+        }
+        let hash = self.blob_store.hash_algorithm().hash(&node);
+        self.blob_store.put(&hash, &node)?;
+        if let Some(cache) = self.cache {
+            cache.insert(hash, Arc::new(node));
+        }
+        Ok(hash)
+    }
+
     /// Look up the data hash for a given LBA
     pub fn lookup(&self, lba: u64) -> Result<Hash, BlobError> {
-        let tree = MerkleTree::new(self.blob_store, self.root_hash, self.total_sectors);
+        let mut tree = MerkleTree::new(self.blob_store, self.root_hash, self.total_sectors);
+        tree.cache = self.cache;
         tree.lookup(lba)
     }
 }
@@ -226,6 +522,11 @@ fn extract_hash(node: &[u8], index: usize) -> Hash {
     Hash::from_bytes(bytes)
 }
 
+/// Child hashes of a node, zero for sparse children
+pub fn node_children(node: &[u8]) -> impl Iterator<Item = Hash> + '_ {
+    (0..FANOUT).map(move |index| extract_hash(node, index))
+}
+
 /// Set a hash in a node at the given index
 fn set_hash(node: &mut [u8], index: usize, hash: &Hash) {
     let start = index * HASH_SIZE;
@@ -239,9 +540,35 @@ fn set_hash(node: &mut [u8], index: usize, hash: &Hash) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::blob::FileBlobStore;
+    use crate::blob::{BlobResult, FileBlobStore};
+    use std::sync::atomic::AtomicUsize;
     use tempfile::TempDir;
 
+    /// Counts the gets reaching the store
+    struct CountingStore {
+        inner: FileBlobStore,
+        gets: AtomicUsize,
+    }
+
+    impl BlobStore for CountingStore {
+        fn put(&self, hash: &Hash, data: &[u8]) -> BlobResult<()> {
+            self.inner.put(hash, data)
+        }
+
+        fn get(&self, hash: &Hash) -> BlobResult<Vec<u8>> {
+            self.gets.fetch_add(1, Ordering::Relaxed);
+            self.inner.get(hash)
+        }
+
+        fn exists(&self, hash: &Hash) -> BlobResult<bool> {
+            self.inner.exists(hash)
+        }
+
+        fn sync(&self) -> BlobResult<()> {
+            self.inner.sync()
+        }
+    }
+
     #[test]
     fn test_calculate_depth() {
         assert_eq!(calculate_depth(1), 1);
@@ -290,6 +617,40 @@ mod tests {
         assert!(tree.lookup(50).unwrap().is_zero());
     }
 
+    #[test]
+    fn test_tree_diff() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path()).unwrap();
+        let total = 200_000; // Three levels
+
+        let mut tree = MerkleTreeMut::empty(&store, total);
+        for lba in [5, 6, 70_000] {
+            tree.update(lba, Hash::from_data(&lba.to_le_bytes())).unwrap();
+        }
+        let base = tree.root_hash();
+        // Rewriting a block with the same content is not a change
+        tree.update(5, Hash::from_data(&5u64.to_le_bytes())).unwrap();
+        for lba in [7, 8, 127, 128, 199_999] {
+            tree.update(lba, Hash::from_data(b"changed")).unwrap();
+        }
+        tree.update(70_000, Hash::ZERO).unwrap();
+
+        let view = MerkleTree::new(&store, tree.root_hash(), total);
+        assert_eq!(
+            view.diff(base, 0..total).unwrap(),
+            vec![7..9, 127..129, 70_000..70_001, 199_999..200_000]
+        );
+        assert!(view.diff(view.root_hash(), 0..total).unwrap().is_empty());
+        // Only the window asked for, cut at its edges
+        assert_eq!(view.diff(base, 8..128).unwrap(), vec![8..9, 127..128]);
+        assert!(view.diff(base, 129..70_000).unwrap().is_empty());
+        // Against an empty tree, everything allocated has changed
+        let from_empty = MerkleTree::new(&store, base, total)
+            .diff(Hash::ZERO, 0..total)
+            .unwrap();
+        assert_eq!(from_empty, vec![5..7, 70_000..70_001]);
+    }
+
     #[test]
     fn test_tree_persistence() {
         let temp = TempDir::new().unwrap();
@@ -308,4 +669,93 @@ mod tests {
         let expected = Hash::from_data(b"persistent data");
         assert_eq!(tree.lookup(42).unwrap(), expected);
     }
+
+    #[test]
+    fn test_node_cache_avoids_gets() {
+        let temp = TempDir::new().unwrap();
+        let store = CountingStore {
+            inner: FileBlobStore::new(temp.path()).unwrap(),
+            gets: AtomicUsize::new(0),
+        };
+        let cache = NodeCache::new(16);
+
+        // Depth 3: root, interior and leaf node on every walk
+        let mut tree = MerkleTreeMut::empty(&store, 1 << 20).with_cache(&cache);
+        for lba in 0..64 {
+            tree.update(lba, Hash::from_data(&lba.to_le_bytes())).unwrap();
+        }
+        // Nodes written by an update are served from the cache by the next
+        assert_eq!(store.gets.load(Ordering::Relaxed), 0);
+
+        let root = tree.root_hash();
+        let tree = MerkleTree::new(&store, root, 1 << 20).with_cache(&cache);
+        for lba in 0..64 {
+            assert_eq!(tree.lookup(lba).unwrap(), Hash::from_data(&lba.to_le_bytes()));
+        }
+        assert_eq!(store.gets.load(Ordering::Relaxed), 0);
+
+        // Without the cache every lookup walks all three levels
+        let uncached = MerkleTree::new(&store, root, 1 << 20);
+        uncached.lookup(5).unwrap();
+        assert_eq!(store.gets.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_node_cache_evicts_least_recently_used() {
+        let cache = NodeCache::new(2);
+        let (a, b, c) = (
+            Hash::from_data(b"a"),
+            Hash::from_data(b"b"),
+            Hash::from_data(b"c"),
+        );
+        cache.insert(a, Arc::new(vec![1]));
+        cache.insert(b, Arc::new(vec![2]));
+        assert!(cache.get(&a).is_some());
+        cache.insert(c, Arc::new(vec![3]));
+
+        assert!(cache.get(&b).is_none());
+        assert_eq!(cache.get(&c).unwrap()[0], 3);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.stats(), (2, 1));
+
+        let disabled = NodeCache::new(0);
+        disabled.insert(a, Arc::new(vec![1]));
+        assert!(disabled.is_empty());
+    }
+
+    #[test]
+    fn test_update_batch_matches_update() {
+        let temp = TempDir::new().unwrap();
+        let store = FileBlobStore::new(temp.path()).unwrap();
+        let updates: Vec<(u64, Hash)> = [5u64, 3, 200, 20_000, 4, 129, 3]
+            .iter()
+            .enumerate()
+            .map(|(i, &lba)| (lba, Hash::from_data(&[i as u8])))
+            .collect();
+
+        let mut single = MerkleTreeMut::empty(&store, 1 << 20);
+        for &(lba, hash) in &updates {
+            single.update(lba, hash).unwrap();
+        }
+        let mut batch = MerkleTreeMut::empty(&store, 1 << 20);
+        batch.update_batch(&updates).unwrap();
+
+        // Same tree, and the later of the two updates to LBA 3 wins
+        assert_eq!(batch.root_hash(), single.root_hash());
+        assert_eq!(batch.lookup(3).unwrap(), Hash::from_data(&[6]));
+        assert_eq!((batch.allocated_delta(), single.allocated_delta()), (6, 6));
+
+        // Batches apply on top of an existing tree too
+        batch.update_batch(&[(4, Hash::ZERO), (7, Hash::from_data(b"x"))]).unwrap();
+        single.update(4, Hash::ZERO).unwrap();
+        single.update(7, Hash::from_data(b"x")).unwrap();
+        assert_eq!(batch.root_hash(), single.root_hash());
+        assert_eq!(batch.allocated_delta(), 6);
+        let view = MerkleTree::new(&store, batch.root_hash(), 1 << 20);
+        assert_eq!(view.count_allocated().unwrap(), 6);
+
+        let root = batch.root_hash();
+        assert!(batch.update_batch(&[(1, Hash::ZERO), (1 << 20, Hash::ZERO)]).is_err());
+        assert_eq!(batch.root_hash(), root);
+    }
 }
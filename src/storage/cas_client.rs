@@ -3,25 +3,27 @@
 //! Maps LBA addresses to content hashes stored in a CAS service.
 //! Persists the LBA mapping to disk for durability.
 
-use super::{BlockStorage, DeviceInfo, StorageError};
-use crate::cas::protocol::{read_frame, write_frame, CasCommand, CasResponse};
+use super::{BlockStorage, DeviceInfo, StorageError, TargetUuid};
+use crate::cas::client::{CasPool, CasPoolConfig};
+use crate::cas::protocol::CasCommand;
+use crate::tls::TlsClient;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 
 const SECTOR_SIZE: usize = 512;
 
 /// CAS backend configuration
+#[derive(Clone)]
 pub struct CasBackendConfig {
     pub cas_server_addr: String,
     pub device_size_bytes: u64,
     pub device_model: String,
-    pub device_serial: String,
     pub index_path: PathBuf,
+    /// Reach the CAS server over mutual TLS
+    pub tls: Option<Arc<TlsClient>>,
 }
 
 impl Default for CasBackendConfig {
@@ -30,8 +32,8 @@ impl Default for CasBackendConfig {
             cas_server_addr: "127.0.0.1:3000".to_string(),
             device_size_bytes: 100 * 1024 * 1024, // 100 MB
             device_model: "CAS Virtual Disk".to_string(),
-            device_serial: "CAS001".to_string(),
             index_path: PathBuf::from("/var/lib/aoe-cas/index.json"),
+            tls: None,
         }
     }
 }
@@ -43,6 +45,9 @@ struct LbaIndex {
     mappings: HashMap<u64, [u8; 32]>,
     /// Hash of the zero block
     zero_block_hash: [u8; 32],
+    /// The device's identity (None until the backend first opens the index)
+    #[serde(default)]
+    uuid: Option<TargetUuid>,
 }
 
 impl LbaIndex {
@@ -50,6 +55,7 @@ impl LbaIndex {
         Self {
             mappings: HashMap::new(),
             zero_block_hash,
+            uuid: None,
         }
     }
 
@@ -83,87 +89,82 @@ impl LbaIndex {
     }
 }
 
-/// CAS backend state
-struct CasBackendState {
-    reader: BufReader<TcpStream>,
-    writer: BufWriter<TcpStream>,
-    index: LbaIndex,
-}
-
 /// CAS-backed block storage
+///
+/// The index is behind a read-write lock that is never held across CAS
+/// requests, so reads and writes from different connections run
+/// concurrently and only contend briefly on the index.
 pub struct CasBackend {
     config: CasBackendConfig,
-    state: Arc<Mutex<CasBackendState>>,
+    cas: CasPool,
+    index: RwLock<LbaIndex>,
+    /// Serializes saves so an older index never overwrites a newer one
+    save_lock: Mutex<()>,
     device_info: DeviceInfo,
 }
 
 impl CasBackend {
     /// Create a new CAS backend
     pub fn new(config: CasBackendConfig) -> Result<Self, StorageError> {
-        let stream = TcpStream::connect(&config.cas_server_addr).map_err(|e| {
-            StorageError::Backend(format!("failed to connect to CAS server: {}", e))
-        })?;
-
-        let mut reader = BufReader::new(stream.try_clone().map_err(|e| {
-            StorageError::Backend(format!("failed to clone stream: {}", e))
-        })?);
-        let mut writer = BufWriter::new(stream);
+        let pool_config = CasPoolConfig {
+            tls: config.tls.clone(),
+            ..CasPoolConfig::default()
+        };
+        let cas = CasPool::connect(&config.cas_server_addr, pool_config)
+            .map_err(|e| {
+                StorageError::Backend(format!("failed to connect to CAS server: {}", e))
+            })?;
 
         // Try to load existing index, or create new
-        let index = if config.index_path.exists() {
+        let mut index = if config.index_path.exists() {
             log::info!("Loading existing index from {:?}", config.index_path);
             LbaIndex::load(&config.index_path)?
         } else {
             log::info!("Creating new index");
             // Initialize zero block
             let zero_sector = vec![0u8; SECTOR_SIZE];
-            let mut temp_state = CasBackendState {
-                reader,
-                writer,
-                index: LbaIndex::new([0u8; 32]), // Temporary
-            };
-            let zero_hash = Self::write_to_cas(&mut temp_state, &zero_sector)?;
-
-            // Extract reader/writer back
-            reader = temp_state.reader;
-            writer = temp_state.writer;
-
-            log::info!("Initialized zero block hash: {}", hex::encode(&zero_hash));
+            let zero_hash = Self::write_to_cas(&cas, &zero_sector)?;
+
+            log::info!("Initialized zero block hash: {}", hex::encode(zero_hash));
             LbaIndex::new(zero_hash)
         };
 
-        let state = CasBackendState {
-            reader,
-            writer,
-            index,
+        // New indexes, and those from before identities were kept, get one
+        let uuid = match index.uuid {
+            Some(uuid) => uuid,
+            None => {
+                let uuid = TargetUuid::generate();
+                index.uuid = Some(uuid);
+                index.save(&config.index_path)?;
+                uuid
+            }
         };
 
         let device_info = DeviceInfo {
             model: config.device_model.clone(),
-            serial: config.device_serial.clone(),
+            serial: uuid.serial(),
             firmware: "1.0".to_string(),
             total_sectors: config.device_size_bytes / SECTOR_SIZE as u64,
             sector_size: SECTOR_SIZE as u32,
             lba48: true,
+            uuid: Some(uuid),
         };
 
         Ok(Self {
             config,
-            state: Arc::new(Mutex::new(state)),
+            cas,
+            index: RwLock::new(index),
+            save_lock: Mutex::new(()),
             device_info,
         })
     }
 
     /// Write data to CAS and get hash
-    fn write_to_cas(state: &mut CasBackendState, data: &[u8]) -> Result<[u8; 32], StorageError> {
-        write_frame(&mut state.writer, CasCommand::Write, data).map_err(|e| {
+    fn write_to_cas(cas: &CasPool, data: &[u8]) -> Result<[u8; 32], StorageError> {
+        let (cmd, hash_data) = cas.request(CasCommand::Write, data).map_err(|e| {
             StorageError::Backend(format!("failed to write to CAS: {}", e))
         })?;
 
-        let (cmd, hash_data) = read_frame(&mut state.reader).map_err(|e| {
-            StorageError::Backend(format!("failed to read CAS write response: {}", e))
-        })?;
-
         if let CasCommand::Write = cmd {
             if hash_data.len() == 32 {
                 let mut hash = [0u8; 32];
@@ -178,15 +179,11 @@ impl CasBackend {
     }
 
     /// Read data from CAS by hash
-    fn read_from_cas(state: &mut CasBackendState, hash: &[u8; 32]) -> Result<Vec<u8>, StorageError> {
-        write_frame(&mut state.writer, CasCommand::Read, hash).map_err(|e| {
+    fn read_from_cas(cas: &CasPool, hash: &[u8; 32]) -> Result<Vec<u8>, StorageError> {
+        let (cmd, data) = cas.request(CasCommand::Read, hash).map_err(|e| {
             StorageError::Backend(format!("failed to read from CAS: {}", e))
         })?;
 
-        let (cmd, data) = read_frame(&mut state.reader).map_err(|e| {
-            StorageError::Backend(format!("failed to read CAS read response: {}", e))
-        })?;
-
         match cmd {
             CasCommand::Read => Ok(data),
             _ => Err(StorageError::Backend(
@@ -197,86 +194,10 @@ impl CasBackend {
 
     /// Save the index to disk
     fn save_index(&self) -> Result<(), StorageError> {
-        let state = self.state.lock().unwrap();
-        state.index.save(&self.config.index_path)
-    }
-}
-
-impl CasBackend {
-    fn read_sectors(&mut self, lba: u64, count: u8, buffer: &mut [u8]) -> Result<(), StorageError> {
-        let expected_size = count as usize * SECTOR_SIZE;
-        if buffer.len() < expected_size {
-            return Err(StorageError::Backend(
-                "buffer too small".to_string(),
-            ));
-        }
-
-        let mut state = self.state.lock().unwrap();
-
-        for i in 0..count {
-            let sector_lba = lba + i as u64;
-            let offset = i as usize * SECTOR_SIZE;
-            let sector_buf = &mut buffer[offset..offset + SECTOR_SIZE];
-
-            // Get hash for this LBA, or use zero block
-            let hash = state
-                .index
-                .mappings
-                .get(&sector_lba)
-                .copied()
-                .unwrap_or(state.index.zero_block_hash);
-
-            // Read from CAS
-            let data = Self::read_from_cas(&mut state, &hash)?;
-
-            if data.len() != SECTOR_SIZE {
-                return Err(StorageError::Backend(format!(
-                    "CAS returned wrong size: expected {}, got {}",
-                    SECTOR_SIZE,
-                    data.len()
-                )));
-            }
-
-            sector_buf.copy_from_slice(&data);
-        }
-
-        Ok(())
-    }
-
-    fn write_sectors(&mut self, lba: u64, count: u8, data: &[u8]) -> Result<(), StorageError> {
-        let expected_size = count as usize * SECTOR_SIZE;
-        if data.len() < expected_size {
-            return Err(StorageError::Backend(
-                "data too small".to_string(),
-            ));
-        }
-
-        let mut state = self.state.lock().unwrap();
-
-        for i in 0..count {
-            let sector_lba = lba + i as u64;
-            let offset = i as usize * SECTOR_SIZE;
-            let sector_data = &data[offset..offset + SECTOR_SIZE];
-
-            // Write to CAS and get hash
-            let hash = Self::write_to_cas(&mut state, sector_data)?;
-
-            // Update LBA mapping
-            state.index.mappings.insert(sector_lba, hash);
-        }
-
-        // Save index after writes
-        drop(state);
-        self.save_index()?;
-
-        Ok(())
-    }
-
-    fn flush(&mut self) -> Result<(), StorageError> {
-        // Save index on flush
-        self.save_index()
+        let _guard = self.save_lock.lock().unwrap();
+        let index = self.index.read().unwrap();
+        index.save(&self.config.index_path)
     }
-
 }
 
 impl BlockStorage for CasBackend {
@@ -284,23 +205,26 @@ impl BlockStorage for CasBackend {
         let size = count as usize * SECTOR_SIZE;
         let mut buffer = vec![0u8; size];
 
-        let mut state = self.state.lock().unwrap();
+        // Hash for each LBA, or the zero block
+        let hashes: Vec<[u8; 32]> = {
+            let index = self.index.read().unwrap();
+            (0..count as u64)
+                .map(|i| {
+                    index
+                        .mappings
+                        .get(&(lba + i))
+                        .copied()
+                        .unwrap_or(index.zero_block_hash)
+                })
+                .collect()
+        };
 
-        for i in 0..count {
-            let sector_lba = lba + i as u64;
-            let offset = i as usize * SECTOR_SIZE;
+        for (i, hash) in hashes.iter().enumerate() {
+            let offset = i * SECTOR_SIZE;
             let sector_buf = &mut buffer[offset..offset + SECTOR_SIZE];
 
-            // Get hash for this LBA, or use zero block
-            let hash = state
-                .index
-                .mappings
-                .get(&sector_lba)
-                .copied()
-                .unwrap_or(state.index.zero_block_hash);
-
             // Read from CAS
-            let data = Self::read_from_cas(&mut state, &hash)?;
+            let data = Self::read_from_cas(&self.cas, hash)?;
 
             if data.len() != SECTOR_SIZE {
                 return Err(StorageError::Backend(format!(
@@ -316,17 +240,15 @@ impl BlockStorage for CasBackend {
         Ok(buffer)
     }
 
-    fn write(&mut self, lba: u64, data: &[u8]) -> super::StorageResult<()> {
-        let count = (data.len() + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    fn write(&self, lba: u64, data: &[u8]) -> super::StorageResult<()> {
+        let count = data.len().div_ceil(SECTOR_SIZE);
 
         if count > 255 {
             return Err(StorageError::InvalidSectorCount(count as u8));
         }
 
-        let mut state = self.state.lock().unwrap();
-
+        let mut hashes = Vec::with_capacity(count);
         for i in 0..count {
-            let sector_lba = lba + i as u64;
             let offset = i * SECTOR_SIZE;
             let end = (offset + SECTOR_SIZE).min(data.len());
 
@@ -335,20 +257,48 @@ impl BlockStorage for CasBackend {
             sector_data[..end - offset].copy_from_slice(&data[offset..end]);
 
             // Write to CAS and get hash
-            let hash = Self::write_to_cas(&mut state, &sector_data)?;
+            hashes.push(Self::write_to_cas(&self.cas, &sector_data)?);
+        }
 
-            // Update LBA mapping
-            state.index.mappings.insert(sector_lba, hash);
+        // Update LBA mappings once every sector is stored
+        {
+            let mut index = self.index.write().unwrap();
+            for (i, hash) in hashes.into_iter().enumerate() {
+                index.mappings.insert(lba + i as u64, hash);
+            }
         }
 
         // Save index after writes
-        drop(state);
         self.save_index()?;
 
         Ok(())
     }
 
-    fn flush(&mut self) -> super::StorageResult<()> {
+    fn flush(&self) -> super::StorageResult<()> {
+        self.save_index()
+    }
+
+    fn check_health(&self) -> super::StorageResult<()> {
+        match self.cas.request(CasCommand::Ping, &[]) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(StorageError::Backend(format!("CAS server unreachable: {}", e))),
+        }
+    }
+
+    fn write_zeroes(&self, lba: u64, count: u64) -> super::StorageResult<()> {
+        if lba + count > self.device_info.total_sectors {
+            return Err(StorageError::OutOfRange {
+                lba,
+                max: self.device_info.total_sectors,
+            });
+        }
+
+        // Unmapped LBAs read as the zero block
+        self.index
+            .write()
+            .unwrap()
+            .mappings
+            .retain(|&sector, _| sector < lba || sector >= lba + count);
         self.save_index()
     }
 
@@ -373,26 +323,25 @@ mod tests {
             cas_server_addr: "127.0.0.1:3000".to_string(),
             device_size_bytes: 1024 * 1024,
             device_model: "Test Disk".to_string(),
-            device_serial: "TEST001".to_string(),
             index_path: temp_index.clone(),
+            tls: None,
         };
 
         // Write some data
         {
-            let mut backend = CasBackend::new(config.clone()).unwrap();
+            let backend = CasBackend::new(config.clone()).unwrap();
             let write_data = b"Hello, persistent CAS!".to_vec();
             let mut padded_write = vec![0u8; SECTOR_SIZE];
             padded_write[..write_data.len()].copy_from_slice(&write_data);
 
-            backend.write_sectors(0, 1, &padded_write).unwrap();
+            backend.write(0, &padded_write).unwrap();
             backend.flush().unwrap();
         }
 
         // Read it back with a new backend instance
         {
-            let mut backend = CasBackend::new(config).unwrap();
-            let mut read_buf = vec![0u8; SECTOR_SIZE];
-            backend.read_sectors(0, 1, &mut read_buf).unwrap();
+            let backend = CasBackend::new(config).unwrap();
+            let read_buf = backend.read(0, 1).unwrap();
 
             assert_eq!(&read_buf[..22], b"Hello, persistent CAS!");
         }
@@ -1,17 +1,31 @@
 //! File-based storage backend
 //!
 //! Simple implementation that stores data in a regular file.
-
-use super::{BlockStorage, DeviceInfo, StorageResult};
+//! Uses positional I/O (pread/pwrite), so concurrent requests need no lock.
+//! Zero writes and discards punch holes so the file stays sparse.
+
+use super::device::backing_size;
+use super::geometry::Geometry;
+use super::health::{available_space, space_left, SpaceReserve};
+use super::{
+    is_all_zero, zero_fill, BlockStorage, DeviceInfo, DeviceStrings, StorageError, StorageResult,
+    TargetUuid,
+};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::path::Path;
-use std::sync::Mutex;
+use std::io;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 
 /// File-based block storage
 pub struct FileBackend {
-    file: Mutex<File>,
+    pub(super) file: File,
     info: DeviceInfo,
+    reserve: Option<SpaceReserve>,
+    /// Where the file's geometry is kept (None when opened read-only)
+    geometry_path: Option<PathBuf>,
+    /// Geometry the file was written with, if recorded
+    geometry: Option<Geometry>,
 }
 
 impl FileBackend {
@@ -20,38 +34,46 @@ impl FileBackend {
         Self::open_with_options(path, false)
     }
 
-    /// Open or create a file with specified size
+    /// Open or create a file with specified size; an existing file that
+    /// is larger keeps its size
     pub fn open_or_create<P: AsRef<Path>>(path: P, size_bytes: u64) -> StorageResult<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(path.as_ref())?;
 
-        let metadata = file.metadata()?;
-        let current_size = metadata.len();
+        let current_size = backing_size(&file)?;
+        let is_device = file.metadata()?.file_type().is_block_device();
 
-        // Extend file if needed
+        // Extend file if needed; a block device can't grow
         if current_size < size_bytes {
+            if is_device {
+                return Err(StorageError::Backend(format!(
+                    "{} is a {}-byte block device, smaller than {} bytes",
+                    path.as_ref().display(),
+                    current_size,
+                    size_bytes
+                )));
+            }
             file.set_len(size_bytes)?;
         }
 
-        let total_sectors = size_bytes / 512;
-        let serial = generate_serial(path.as_ref());
+        let total_sectors = current_size.max(size_bytes) / 512;
+        let uuid = file_identity(path.as_ref(), current_size > 0)?;
 
         let info = DeviceInfo {
             model: "AoE File Backend".to_string(),
-            serial,
+            serial: uuid.serial(),
             firmware: env!("CARGO_PKG_VERSION").to_string(),
             total_sectors,
             sector_size: 512,
             lba48: true,
+            uuid: Some(uuid),
         };
 
-        Ok(Self {
-            file: Mutex::new(file),
-            info,
-        })
+        Self::with_geometry(file, info, geometry_path(path.as_ref()), true)
     }
 
     /// Open with explicit read-only option
@@ -61,24 +83,52 @@ impl FileBackend {
             .write(!read_only)
             .open(path.as_ref())?;
 
-        let metadata = file.metadata()?;
-        let file_size = metadata.len();
+        let file_size = backing_size(&file)?;
         let total_sectors = file_size / 512;
 
-        let serial = generate_serial(path.as_ref());
+        // A read-only file may sit where no identity can be written
+        let uuid = if read_only {
+            TargetUuid::load(&identity_path(path.as_ref()))?
+        } else {
+            Some(file_identity(path.as_ref(), true)?)
+        };
 
         let info = DeviceInfo {
             model: "AoE File Backend".to_string(),
-            serial,
+            serial: uuid.map_or_else(|| generate_serial(path.as_ref()), |uuid| uuid.serial()),
             firmware: env!("CARGO_PKG_VERSION").to_string(),
             total_sectors,
             sector_size: 512,
             lba48: true,
+            uuid,
         };
 
+        Self::with_geometry(file, info, geometry_path(path.as_ref()), !read_only)
+    }
+
+    /// Finish opening: take on the recorded geometry, if any, keeping
+    /// `geometry_path` to record one later if `writable`. A writable file
+    /// smaller than it was last advertised is refused.
+    fn with_geometry(
+        file: File,
+        mut info: DeviceInfo,
+        geometry_path: PathBuf,
+        writable: bool,
+    ) -> StorageResult<Self> {
+        let geometry = Geometry::load(&geometry_path)?;
+        if let Some(geometry) = geometry {
+            info.set_sector_size(geometry.sector_size);
+            if writable {
+                geometry.check_size(info.size_bytes())?;
+            }
+        }
+
         Ok(Self {
-            file: Mutex::new(file),
+            file,
             info,
+            reserve: None,
+            geometry_path: writable.then_some(geometry_path),
+            geometry,
         })
     }
 
@@ -86,6 +136,139 @@ impl FileBackend {
     pub fn open_read_only<P: AsRef<Path>>(path: P) -> StorageResult<Self> {
         Self::open_with_options(path, true)
     }
+
+    /// Use a different logical sector size (512 or 4096), keeping capacity.
+    ///
+    /// A file keeps the sector size it was first given; a different one
+    /// is refused. The capacity it is advertised with is recorded too.
+    pub fn with_sector_size(mut self, sector_size: u32) -> StorageResult<Self> {
+        if let Some(geometry) = &self.geometry {
+            geometry.check_sector_size(sector_size)?;
+        }
+        self.info.set_sector_size(sector_size);
+        if let Some(path) = &self.geometry_path {
+            let geometry = Geometry {
+                sector_size,
+                block_size: None,
+                size_bytes: Some(self.info.size_bytes()),
+            };
+            if self.geometry != Some(geometry) {
+                geometry.save(path)?;
+                self.geometry = Some(geometry);
+            }
+        }
+        Ok(self)
+    }
+
+    /// Report `strings` in place of the generated model, serial and firmware
+    pub fn with_device_strings(mut self, strings: &DeviceStrings) -> Self {
+        self.info.set_strings(strings);
+        self
+    }
+
+    /// Refuse writes while the file's filesystem has less free space than
+    /// `reserve` wants kept back
+    pub fn with_reserve(mut self, reserve: SpaceReserve) -> Self {
+        self.reserve = Some(reserve);
+        self
+    }
+
+    /// Ok if the reserve (if any) allows another write
+    pub(super) fn check_space(&self) -> StorageResult<()> {
+        match &self.reserve {
+            Some(reserve) => reserve.check(&self.file),
+            None => Ok(()),
+        }
+    }
+
+    /// Storage error for a failed write, saying how much space is left
+    /// if the filesystem is full
+    fn write_error(&self, e: io::Error) -> StorageError {
+        if e.raw_os_error() == Some(libc::ENOSPC) {
+            let (available, reserve) = space_left(&self.file, self.reserve.as_ref());
+            return StorageError::NoSpace { available, reserve };
+        }
+        e.into()
+    }
+
+    /// Allocated (non-hole) sector ranges as (lba, count) among `count`
+    /// sectors from `lba`.
+    ///
+    /// Uses SEEK_DATA/SEEK_HOLE, so exporting a sparse file only needs to
+    /// read these ranges. Filesystems without hole support report one range.
+    #[cfg(target_os = "linux")]
+    fn data_ranges(&self, lba: u64, count: u64) -> StorageResult<Vec<(u64, u64)>> {
+        let sector_size = self.info.sector_size as u64;
+        let end = (lba + count).min(self.info.total_sectors) * sector_size;
+        let fd = self.file.as_raw_fd();
+
+        let mut ranges = Vec::new();
+        let mut pos = lba * sector_size;
+        while pos < end {
+            // SAFETY: lseek only moves the offset of our own open fd and
+            // touches no memory
+            let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+            if data < 0 {
+                let err = io::Error::last_os_error();
+                if err.raw_os_error() == Some(libc::ENXIO) {
+                    break; // No data past pos
+                }
+                return Err(err.into());
+            }
+
+            // SAFETY: as above. Reads and writes use positioned I/O, so
+            // moving the file offset can't disturb them.
+            let hole = unsafe { libc::lseek(fd, data, libc::SEEK_HOLE) };
+            if hole < 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let start = data as u64 / sector_size;
+            let stop = (hole as u64).min(end).div_ceil(sector_size);
+            if stop > start {
+                ranges.push((start, stop - start));
+            }
+            pos = hole as u64;
+        }
+
+        Ok(ranges)
+    }
+
+    /// Allocated sector ranges (hole detection unavailable: all of them)
+    #[cfg(not(target_os = "linux"))]
+    fn data_ranges(&self, lba: u64, count: u64) -> StorageResult<Vec<(u64, u64)>> {
+        Ok(vec![(lba, count)])
+    }
+
+    /// Deallocate a byte range, keeping the file size.
+    /// Returns false if the filesystem doesn't support hole punching.
+    #[cfg(target_os = "linux")]
+    fn punch_hole(&self, offset: u64, len: u64) -> StorageResult<bool> {
+        // SAFETY: fallocate takes our own open fd and plain integers; the
+        // range is checked by the callers and no memory is passed
+        let ret = unsafe {
+            libc::fallocate(
+                self.file.as_raw_fd(),
+                libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+                offset as libc::off_t,
+                len as libc::off_t,
+            )
+        };
+        if ret == 0 {
+            return Ok(true);
+        }
+
+        let err = io::Error::last_os_error();
+        match err.raw_os_error() {
+            Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS) => Ok(false),
+            _ => Err(err.into()),
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn punch_hole(&self, _offset: u64, _len: u64) -> StorageResult<bool> {
+        Ok(false)
+    }
 }
 
 impl BlockStorage for FileBackend {
@@ -95,47 +278,123 @@ impl BlockStorage for FileBackend {
         let offset = lba * self.info.sector_size as u64;
         let length = count as usize * self.info.sector_size as usize;
 
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(offset))?;
-
         let mut buffer = vec![0u8; length];
-        file.read_exact(&mut buffer)?;
+        self.file.read_exact_at(&mut buffer, offset)?;
 
         Ok(buffer)
     }
 
-    fn write(&mut self, lba: u64, data: &[u8]) -> StorageResult<()> {
+    fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
         let count = (data.len() / self.info.sector_size as usize) as u8;
         self.validate_range(lba, count)?;
 
         let offset = lba * self.info.sector_size as u64;
 
-        let mut file = self.file.lock().unwrap();
-        file.seek(SeekFrom::Start(offset))?;
-        file.write_all(data)?;
+        // Zero runs become holes rather than allocated zero blocks
+        if is_all_zero(data) && self.punch_hole(offset, data.len() as u64)? {
+            return Ok(());
+        }
+
+        self.check_space()?;
+        self.file
+            .write_all_at(data, offset)
+            .map_err(|e| self.write_error(e))?;
+
+        Ok(())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.file.sync_all().map_err(|e| self.write_error(e))?;
+        Ok(())
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> StorageResult<()> {
+        if lba + count > self.info.total_sectors {
+            return Err(StorageError::OutOfRange {
+                lba,
+                max: self.info.total_sectors,
+            });
+        }
 
+        let sector_size = self.info.sector_size as u64;
+        self.punch_hole(lba * sector_size, count * sector_size)?;
         Ok(())
     }
 
-    fn flush(&mut self) -> StorageResult<()> {
-        let file = self.file.lock().unwrap();
-        file.sync_all()?;
+    fn check_health(&self) -> StorageResult<()> {
+        let meta = self.file.metadata()?;
+        if meta.nlink() == 0 {
+            return Err(StorageError::Backend("backing file was deleted".to_string()));
+        }
+        // Sparse files need room for writes to holes; fully allocated ones don't
+        let allocated = meta.blocks() * 512;
+        if allocated < meta.len() && available_space(&self.file)? == 0 {
+            return Err(StorageError::Backend(
+                "no space left for the sparse backing file".to_string(),
+            ));
+        }
         Ok(())
     }
 
+    fn allocated_ranges(&self, lba: u64, count: u64) -> StorageResult<Vec<(u64, u64)>> {
+        self.data_ranges(lba, count)
+    }
+
+    fn write_zeroes(&self, lba: u64, count: u64) -> StorageResult<()> {
+        // Holes read back as zeroes; write them only if the filesystem can't punch
+        let sector_size = self.info.sector_size as u64;
+        if lba + count <= self.info.total_sectors
+            && self.punch_hole(lba * sector_size, count * sector_size)?
+        {
+            return Ok(());
+        }
+        zero_fill(self, lba, count)
+    }
+
     fn info(&self) -> &DeviceInfo {
         &self.info
     }
 }
 
+/// A file's identity is kept beside it, in `<file>.uuid`
+pub fn identity_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".uuid");
+    PathBuf::from(name)
+}
+
+/// A file's geometry is kept beside it, in `<file>.geometry`
+pub fn geometry_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".geometry");
+    PathBuf::from(name)
+}
+
+/// Load the file's identity, creating one if it has none; `existing`
+/// files keep the serial they had from their path
+fn file_identity(path: &Path, existing: bool) -> io::Result<TargetUuid> {
+    TargetUuid::load_or_create(&identity_path(path), || {
+        if existing {
+            TargetUuid::from_legacy_serial(hash_path(path))
+        } else {
+            TargetUuid::generate()
+        }
+    })
+}
+
 /// Generate a serial number from file path
-fn generate_serial(path: &Path) -> String {
+pub(super) fn generate_serial(path: &Path) -> String {
+    format!("{:016X}", hash_path(path))
+}
+
+/// Hash a path, for serials of stores without an identity
+fn hash_path(path: &Path) -> u64 {
     use std::collections::hash_map::DefaultHasher;
     use std::hash::{Hash, Hasher};
 
     let mut hasher = DefaultHasher::new();
     path.hash(&mut hasher);
-    format!("{:016X}", hasher.finish())
+    hasher.finish()
 }
 
 #[cfg(test)]
@@ -158,7 +417,7 @@ mod tests {
         let temp = NamedTempFile::new().unwrap();
         let path = temp.path();
 
-        let mut backend = FileBackend::open_or_create(path, 1024 * 1024).unwrap();
+        let backend = FileBackend::open_or_create(path, 1024 * 1024).unwrap();
 
         // Write some data
         let write_data = vec![0xAA; 512];
@@ -174,7 +433,7 @@ mod tests {
         let temp = NamedTempFile::new().unwrap();
         let path = temp.path();
 
-        let mut backend = FileBackend::open_or_create(path, 1024 * 1024).unwrap();
+        let backend = FileBackend::open_or_create(path, 1024 * 1024).unwrap();
 
         // Write 4 sectors
         let mut write_data = Vec::new();
@@ -188,6 +447,44 @@ mod tests {
         assert_eq!(read_data, write_data);
     }
 
+    #[test]
+    fn test_file_backend_zero_write_reads_back() {
+        let temp = NamedTempFile::new().unwrap();
+        let backend = FileBackend::open_or_create(temp.path(), 1024 * 1024).unwrap();
+
+        backend.write(8, &vec![0xAA; 1024]).unwrap();
+        backend.write(8, &vec![0x00; 512]).unwrap();
+
+        assert_eq!(backend.read(8, 1).unwrap(), vec![0u8; 512]);
+        assert_eq!(backend.read(9, 1).unwrap(), vec![0xAA; 512]);
+
+        backend.discard(9, 1).unwrap();
+        assert!(backend.discard(2047, 2).is_err());
+
+        backend.write(100, &vec![0xBB; 2048]).unwrap();
+        backend.write_zeroes(101, 2).unwrap();
+        let data = backend.read(100, 4).unwrap();
+        assert_eq!(data[..512], [0xBB; 512]);
+        assert_eq!(data[512..1536], [0u8; 1024]);
+        assert_eq!(data[1536..], [0xBB; 512]);
+        assert!(backend.write_zeroes(2047, 2).is_err());
+    }
+
+    #[test]
+    fn test_file_backend_allocated_ranges() {
+        let temp = NamedTempFile::new().unwrap();
+        let backend = FileBackend::open_or_create(temp.path(), 4 * 1024 * 1024).unwrap();
+
+        backend.write(0, &vec![0x11; 512]).unwrap();
+        backend.write(4096, &vec![0x22; 512]).unwrap();
+
+        // Written sectors must be covered; unwritten ones may be holes
+        let ranges = backend.allocated_ranges(0, 8192).unwrap();
+        let covered = |lba: u64| ranges.iter().any(|&(s, n)| lba >= s && lba < s + n);
+        assert!(covered(0));
+        assert!(covered(4096));
+    }
+
     #[test]
     fn test_file_backend_out_of_range() {
         let temp = NamedTempFile::new().unwrap();
@@ -199,4 +496,29 @@ mod tests {
         let result = backend.read(9, 2);
         assert!(matches!(result, Err(StorageError::OutOfRange { .. })));
     }
+
+    #[test]
+    fn test_file_backend_size_kept() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("disk.img");
+        let open = |size| FileBackend::open_or_create(&path, size)?.with_sector_size(512);
+
+        open(1024 * 1024).unwrap();
+        // A larger file isn't cut to the configured size
+        let grown = open(512 * 1024).unwrap();
+        assert_eq!(grown.info().size_bytes(), 1024 * 1024);
+        drop(grown);
+
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(512 * 1024)
+            .unwrap();
+        assert!(FileBackend::open(&path).is_err());
+        // Read-only opens, e.g. for export, still work
+        assert!(FileBackend::open_read_only(&path).is_ok());
+        // Extending it back to its advertised size is fine
+        open(1024 * 1024).unwrap();
+    }
 }
@@ -0,0 +1,130 @@
+//! Recorded store geometry
+//!
+//! The logical sector size decides which bytes an LBA addresses, and a
+//! CAS store's block size how its tree is laid out, so reopening a store
+//! with different ones would silently reinterpret everything initiators
+//! wrote to it. The values a store was written with are kept beside it (a
+//! `.geometry` file), a store opens with them, and configuring different
+//! ones is refused. The capacity last advertised is kept with them, so a
+//! store that has since shrunk, such as a truncated file or a lowered
+//! `total_sectors`, is refused before initiators see their data cut off.
+//!
+//! Stores written before geometry was kept, or before the block size was
+//! part of it, get a record of the configured values the next time they
+//! are opened for writing.
+
+use super::{sync_parent_dir, StorageError, StorageResult};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Layout a store was written with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Geometry {
+    pub sector_size: u32,
+    /// CAS data block size in bytes (None for file stores and older records)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_size: Option<u32>,
+    /// Capacity last advertised, in bytes (None for older records)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size_bytes: Option<u64>,
+}
+
+impl Geometry {
+    /// Read the geometry kept at `path`, if there is one
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map(Some).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Keep the geometry at `path`, replacing any there
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let content = serde_json::to_string(self).map_err(io::Error::other)?;
+        {
+            let mut file = fs::File::create(&tmp)?;
+            file.write_all(content.as_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        sync_parent_dir(path)
+    }
+
+    /// Refuse a sector size other than the one the store was written with
+    pub fn check_sector_size(&self, sector_size: u32) -> StorageResult<()> {
+        if sector_size != self.sector_size {
+            return Err(StorageError::Backend(format!(
+                "store was written with {}-byte sectors, not {}; configure sector_size = {}",
+                self.sector_size, sector_size, self.sector_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuse a block size other than the one the store was written with,
+    /// if that was recorded
+    pub fn check_block_size(&self, block_size: u32) -> StorageResult<()> {
+        match self.block_size {
+            Some(recorded) if recorded != block_size => Err(StorageError::Backend(format!(
+                "store was written with {}-byte blocks, not {}; configure block_size = {}",
+                recorded, block_size, recorded
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Refuse a capacity smaller than the one last advertised, if that
+    /// was recorded
+    pub fn check_size(&self, size_bytes: u64) -> StorageResult<()> {
+        match self.size_bytes {
+            Some(advertised) if size_bytes < advertised => Err(StorageError::Backend(format!(
+                "store was advertised with {} bytes but now holds {}; restore its size, as \
+                 initiators' data past the new end would be lost",
+                advertised, size_bytes
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_geometry_round_trip() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("disk.geometry");
+        assert_eq!(Geometry::load(&path).unwrap(), None);
+
+        let geometry = Geometry {
+            sector_size: 4096,
+            block_size: Some(16384),
+            size_bytes: Some(1 << 20),
+        };
+        geometry.save(&path).unwrap();
+        assert_eq!(Geometry::load(&path).unwrap(), Some(geometry));
+
+        assert!(geometry.check_sector_size(4096).is_ok());
+        assert!(geometry.check_sector_size(512).is_err());
+        assert!(geometry.check_block_size(16384).is_ok());
+        assert!(geometry.check_block_size(4096).is_err());
+        assert!(geometry.check_size(2 << 20).is_ok());
+        assert!(geometry.check_size(1 << 19).is_err());
+
+        // Records from before block sizes were kept still load, and accept any
+        fs::write(&path, r#"{"sector_size":512}"#).unwrap();
+        let legacy = Geometry::load(&path).unwrap().unwrap();
+        assert_eq!(legacy.block_size, None);
+        assert!(legacy.check_block_size(65536).is_ok());
+        assert!(legacy.check_size(512).is_ok());
+    }
+}
@@ -0,0 +1,381 @@
+//! Backend health tracking
+//!
+//! Front-ends ask a `HealthCheck` before each request. It re-runs the
+//! backend's `check_health` at most once per interval, so a target whose
+//! blob directory vanished or whose disk filled up is refused quickly
+//! instead of failing every request, and comes back on its own once the
+//! problem is fixed.
+//!
+//! A `SpaceReserve` keeps writes from filling a filesystem to the last
+//! byte: once free space drops below the reserve, writes fail with
+//! `StorageError::NoSpace` while reads carry on. It can also raise an
+//! alarm at a higher level, warning that a thin-provisioned target is
+//! running out of real space well before writes start failing.
+
+use super::{BlockStorage, StorageError, StorageResult};
+use serde::Serialize;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Default time between backend health checks
+pub const DEFAULT_HEALTH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a free space reading is reused before asking the filesystem again
+const SPACE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Cached health of one backend
+pub struct HealthCheck {
+    name: String,
+    interval: Duration,
+    state: Mutex<HealthState>,
+}
+
+struct HealthState {
+    checked_at: Option<Instant>,
+    /// Why the backend is unhealthy, if it is
+    failure: Option<String>,
+}
+
+impl HealthCheck {
+    /// Track the backend known as `name` in log messages
+    pub fn new(name: impl Into<String>, interval: Duration) -> Self {
+        Self {
+            name: name.into(),
+            interval,
+            state: Mutex::new(HealthState {
+                checked_at: None,
+                failure: None,
+            }),
+        }
+    }
+
+    pub fn set_interval(&mut self, interval: Duration) {
+        self.interval = interval;
+    }
+
+    /// Ok if the backend is healthy, else why not. Checks the backend
+    /// again if the last check is older than the interval; a zero
+    /// interval disables checking. The check runs without the lock held,
+    /// so requests arriving meanwhile get the previous result instead of
+    /// waiting on a slow backend.
+    pub fn check(&self, storage: &dyn BlockStorage) -> Result<(), String> {
+        if self.interval.is_zero() {
+            return Ok(());
+        }
+        let mut state = self.state.lock().unwrap();
+        let due = state
+            .checked_at
+            .is_none_or(|at| at.elapsed() >= self.interval);
+        if due {
+            // Claimed, so only this request runs the check
+            state.checked_at = Some(Instant::now());
+            drop(state);
+            let failure = storage.check_health().err().map(|e| e.to_string());
+            state = self.state.lock().unwrap();
+            match (&state.failure, &failure) {
+                (None, Some(reason)) => {
+                    log::error!("{} is unhealthy, taking it offline: {}", self.name, reason)
+                }
+                (Some(_), None) => log::info!("{} has recovered, back online", self.name),
+                _ => {}
+            }
+            state.failure = failure;
+        }
+        match &state.failure {
+            Some(reason) => Err(reason.clone()),
+            None => Ok(()),
+        }
+    }
+
+    /// Why the backend was unhealthy at the last check, if it was
+    pub fn failure(&self) -> Option<String> {
+        self.state.lock().unwrap().failure.clone()
+    }
+}
+
+/// Minimum free space to leave on a backend's filesystem
+pub struct SpaceReserve {
+    name: String,
+    reserve: u64,
+    /// Free space below which to warn (0: no alarm)
+    alarm: u64,
+    state: Mutex<SpaceState>,
+}
+
+/// Free space on a backend's filesystem, against its reserve and alarm
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct SpaceStatus {
+    pub total_bytes: u64,
+    pub available_bytes: u64,
+    /// Writes are refused below this much free space (0: never)
+    pub reserve_bytes: u64,
+    /// Free space level that raises the alarm (0: no alarm)
+    pub alarm_bytes: u64,
+    /// Free space is below the alarm level
+    pub alarm: bool,
+}
+
+struct SpaceState {
+    checked_at: Option<Instant>,
+    available: u64,
+}
+
+impl SpaceReserve {
+    /// Keep `reserve` bytes free on the filesystem of the backend `name`
+    pub fn new(name: impl Into<String>, reserve: u64) -> Self {
+        Self {
+            name: name.into(),
+            reserve,
+            alarm: 0,
+            state: Mutex::new(SpaceState {
+                checked_at: None,
+                available: 0,
+            }),
+        }
+    }
+
+    /// Also warn once free space drops below `alarm` bytes
+    pub fn with_alarm(mut self, alarm: u64) -> Self {
+        self.alarm = alarm;
+        self
+    }
+
+    pub fn reserve(&self) -> u64 {
+        self.reserve
+    }
+
+    /// Ok if a write may go ahead on the filesystem holding `file`.
+    /// The free space reading is cached for about a second.
+    pub fn check(&self, file: &impl AsRawFd) -> StorageResult<()> {
+        let mut state = self.state.lock().unwrap();
+        let due = state
+            .checked_at
+            .is_none_or(|at| at.elapsed() >= SPACE_CHECK_INTERVAL);
+        if due {
+            self.update(&mut state, fs_space(file)?.1);
+        }
+        if state.available < self.reserve {
+            return Err(StorageError::NoSpace {
+                available: state.available,
+                reserve: self.reserve,
+            });
+        }
+        Ok(())
+    }
+
+    /// Current free space on the filesystem holding `file`
+    pub fn status(&self, file: &impl AsRawFd) -> io::Result<SpaceStatus> {
+        let (total, available) = fs_space(file)?;
+        self.update(&mut self.state.lock().unwrap(), available);
+        Ok(SpaceStatus {
+            total_bytes: total,
+            available_bytes: available,
+            reserve_bytes: self.reserve,
+            alarm_bytes: self.alarm,
+            alarm: available < self.alarm,
+        })
+    }
+
+    /// Record a new free space reading, logging reserve and alarm crossings
+    fn update(&self, state: &mut SpaceState, available: u64) {
+        let previous = state.checked_at.map(|_| state.available);
+        let crossed = |level: u64| match previous {
+            Some(before) => (before >= level, available >= level),
+            None => (true, available >= level),
+        };
+
+        match crossed(self.reserve) {
+            (true, false) => log::warn!(
+                "{}: {} bytes free, below the {} byte reserve; refusing writes",
+                self.name,
+                available,
+                self.reserve
+            ),
+            (false, true) => log::info!("{}: free space above the reserve again", self.name),
+            _ => {}
+        }
+        if self.alarm > 0 {
+            match crossed(self.alarm) {
+                (true, false) => log::warn!(
+                    "{}: free space alarm: {} bytes free, below {} bytes",
+                    self.name,
+                    available,
+                    self.alarm
+                ),
+                (false, true) => log::info!("{}: free space alarm cleared", self.name),
+                _ => {}
+            }
+        }
+        state.available = available;
+        state.checked_at = Some(Instant::now());
+    }
+}
+
+/// Bytes free for unprivileged use on the filesystem holding `file`
+pub(crate) fn available_space(file: &impl AsRawFd) -> io::Result<u64> {
+    Ok(fs_space(file)?.1)
+}
+
+/// (available, reserve) bytes to report when a write to the filesystem
+/// holding `file` fails with ENOSPC: what is actually free there, and the
+/// reserve if there is one
+pub(crate) fn space_left(file: &impl AsRawFd, reserve: Option<&SpaceReserve>) -> (u64, u64) {
+    (
+        available_space(file).unwrap_or(0),
+        reserve.map_or(0, SpaceReserve::reserve),
+    )
+}
+
+/// (total, available) bytes of the filesystem holding `file`
+pub(crate) fn fs_space(file: &impl AsRawFd) -> io::Result<(u64, u64)> {
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: fstatvfs only writes into `stat`, which is read only on success
+    let ret = unsafe { libc::fstatvfs(file.as_raw_fd(), stat.as_mut_ptr()) };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: initialized by the successful call above
+    let stat = unsafe { stat.assume_init() };
+    Ok((stat.f_blocks * stat.f_frsize, stat.f_bavail * stat.f_frsize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{DeviceInfo, MemBackend, StorageError, StorageResult};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct Flaky {
+        inner: MemBackend,
+        broken: AtomicBool,
+    }
+
+    impl BlockStorage for Flaky {
+        fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+            self.inner.read(lba, count)
+        }
+
+        fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+            self.inner.write(lba, data)
+        }
+
+        fn flush(&self) -> StorageResult<()> {
+            Ok(())
+        }
+
+        fn check_health(&self) -> StorageResult<()> {
+            if self.broken.load(Ordering::SeqCst) {
+                return Err(StorageError::Backend("gone".to_string()));
+            }
+            Ok(())
+        }
+
+        fn info(&self) -> &DeviceInfo {
+            self.inner.info()
+        }
+    }
+
+    #[test]
+    fn test_offline_and_recover() {
+        let storage = Flaky {
+            inner: MemBackend::new(4096),
+            broken: AtomicBool::new(true),
+        };
+        let health = HealthCheck::new("e1.0", Duration::from_millis(20));
+        assert!(health.check(&storage).unwrap_err().contains("gone"));
+        assert!(health.failure().is_some());
+
+        // Cached until the interval passes
+        storage.broken.store(false, Ordering::SeqCst);
+        assert!(health.check(&storage).is_err());
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(health.check(&storage).is_ok());
+        assert!(health.failure().is_none());
+
+        let disabled = HealthCheck::new("e1.1", Duration::ZERO);
+        storage.broken.store(true, Ordering::SeqCst);
+        assert!(disabled.check(&storage).is_ok());
+    }
+
+    /// Health check that waits until released
+    struct Stalled {
+        inner: MemBackend,
+        entered: AtomicBool,
+        release: Mutex<std::sync::mpsc::Receiver<()>>,
+    }
+
+    impl BlockStorage for Stalled {
+        fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+            self.inner.read(lba, count)
+        }
+
+        fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+            self.inner.write(lba, data)
+        }
+
+        fn flush(&self) -> StorageResult<()> {
+            Ok(())
+        }
+
+        fn check_health(&self) -> StorageResult<()> {
+            self.entered.store(true, Ordering::SeqCst);
+            let _ = self.release.lock().unwrap().recv();
+            Err(StorageError::Backend("gone".to_string()))
+        }
+
+        fn info(&self) -> &DeviceInfo {
+            self.inner.info()
+        }
+    }
+
+    #[test]
+    fn test_check_does_not_block_requests() {
+        let (release, released) = std::sync::mpsc::channel();
+        let storage = Stalled {
+            inner: MemBackend::new(4096),
+            entered: AtomicBool::new(false),
+            release: Mutex::new(released),
+        };
+        let health = HealthCheck::new("e1.0", Duration::from_secs(60));
+
+        std::thread::scope(|scope| {
+            let checking = scope.spawn(|| health.check(&storage));
+            while !storage.entered.load(Ordering::SeqCst) {
+                std::thread::yield_now();
+            }
+            // Answered from the last result while the check is stuck
+            assert!(health.check(&storage).is_ok());
+            release.send(()).unwrap();
+            assert!(checking.join().unwrap().is_err());
+        });
+        assert!(health.check(&storage).is_err());
+    }
+
+    #[test]
+    fn test_space_reserve() {
+        let file = tempfile::tempfile().unwrap();
+        assert!(SpaceReserve::new("e1.0", 0).check(&file).is_ok());
+
+        match SpaceReserve::new("e1.1", u64::MAX).check(&file) {
+            Err(StorageError::NoSpace { reserve, .. }) => assert_eq!(reserve, u64::MAX),
+            other => panic!("expected NoSpace, got {:?}", other),
+        }
+
+        // The alarm warns without refusing writes
+        let alarmed = SpaceReserve::new("e1.2", 0).with_alarm(u64::MAX);
+        assert!(alarmed.check(&file).is_ok());
+        let status = alarmed.status(&file).unwrap();
+        assert!(status.alarm);
+        assert!(status.total_bytes >= status.available_bytes);
+        assert!(!SpaceReserve::new("e1.3", 0).status(&file).unwrap().alarm);
+
+        // A full filesystem is reported with what it really has left
+        let reserve = SpaceReserve::new("e1.4", 4096);
+        let (available, reserved) = space_left(&file, Some(&reserve));
+        assert!(available > 0);
+        assert_eq!(reserved, 4096);
+        assert_eq!(space_left(&file, None).1, 0);
+    }
+}
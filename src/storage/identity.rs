@@ -0,0 +1,190 @@
+//! Persistent target identity
+//!
+//! A target is given a random UUID when its data is first created, and
+//! the UUID is kept with that data (a `.uuid` file beside it, or beside an
+//! iSCSI target's index). The ATA serial and world wide name and the NBD
+//! export description are derived from it, so they follow the data rather
+//! than the path it happens to live at.
+//!
+//! Stores created before identities were kept had a serial hashed from
+//! their path. Their UUID starts with that hash, so the serial initiators
+//! already know them by carries over.
+//!
+//! Two AoE servers exporting one store report the same serial and world
+//! wide name, which is what multipath uses to recognise the paths as one
+//! disk. `iscsi-server` answers INQUIRY through `iscsi_target`, which takes
+//! neither, so iSCSI initiators don't see them; the status API reports the
+//! UUID instead.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use xxhash_rust::xxh3::xxh3_64;
+
+/// A target's UUID
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TargetUuid([u8; 16]);
+
+impl TargetUuid {
+    /// A new random (version 4) UUID
+    pub fn generate() -> Self {
+        let mut bytes: [u8; 16] = rand::random();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self(bytes)
+    }
+
+    /// A UUID for an existing store whose serial was the hex of `serial`
+    pub fn from_legacy_serial(serial: u64) -> Self {
+        let mut uuid = Self::generate();
+        uuid.0[..8].copy_from_slice(&serial.to_be_bytes());
+        uuid
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Self(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    /// Serial number: the first half as 16 upper-case hex digits, which
+    /// fits the 20-character ATA field
+    pub fn serial(&self) -> String {
+        hex::encode_upper(&self.0[..8])
+    }
+
+    /// World wide name: an NAA locally assigned (type 3) identifier hashed
+    /// from the UUID. Used for the ATA WWN and the NAA designator of the
+    /// SCSI Device Identification page.
+    pub fn wwn(&self) -> u64 {
+        (xxh3_64(&self.0) & 0x0fff_ffff_ffff_ffff) | (0x3 << 60)
+    }
+
+    /// Read the UUID kept at `path`, if there is one
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match fs::read_to_string(path) {
+            Ok(content) => content.trim().parse().map(Some).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the UUID kept at `path`, or save `new()` there if there is none
+    pub fn load_or_create(path: &Path, new: impl FnOnce() -> Self) -> io::Result<Self> {
+        if let Some(uuid) = Self::load(path)? {
+            return Ok(uuid);
+        }
+        let uuid = new();
+        let tmp = path.with_extension("uuid.tmp");
+        fs::write(&tmp, format!("{}\n", uuid))?;
+        fs::rename(&tmp, path)?;
+        log::info!("Target identity {} saved to {:?}", uuid, path);
+        Ok(uuid)
+    }
+
+    /// Give the store whose identity is kept at `path` the identity `uuid`,
+    /// refusing one that already has another. Every front-end exporting a
+    /// store with a configured UUID pins it this way.
+    pub fn pin(path: &Path, uuid: Self) -> io::Result<()> {
+        let existing = Self::load_or_create(path, || uuid)?;
+        if existing != uuid {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{:?} already holds identity {}, not the configured {}",
+                    path, existing, uuid
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TargetUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = hex::encode(self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+impl FromStr for TargetUuid {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(|&c| c != '-').collect();
+        let bytes = hex::decode(&digits).map_err(|e| format!("invalid UUID {:?}: {}", s, e))?;
+        let bytes: [u8; 16] = bytes
+            .try_into()
+            .map_err(|_| format!("invalid UUID {:?}: expected 32 hex digits", s))?;
+        Ok(Self(bytes))
+    }
+}
+
+impl Serialize for TargetUuid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for TargetUuid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uuid_round_trip() {
+        let uuid = TargetUuid::generate();
+        let text = uuid.to_string();
+        assert_eq!(text.len(), 36);
+        assert_eq!(&text[14..15], "4");
+        assert_eq!(text.parse::<TargetUuid>().unwrap(), uuid);
+        assert_eq!(text.replace('-', "").parse::<TargetUuid>().unwrap(), uuid);
+        assert!("not-a-uuid".parse::<TargetUuid>().is_err());
+
+        let legacy = TargetUuid::from_legacy_serial(0x0123_4567_89AB_CDEF);
+        assert_eq!(legacy.serial(), "0123456789ABCDEF");
+
+        assert_eq!(uuid.wwn() >> 60, 0x3);
+        assert_eq!(uuid.wwn(), text.parse::<TargetUuid>().unwrap().wwn());
+        assert_ne!(uuid.wwn(), legacy.wwn());
+    }
+
+    #[test]
+    fn test_uuid_persisted() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let path = temp.path().join("disk.img.uuid");
+        assert_eq!(TargetUuid::load(&path).unwrap(), None);
+
+        let uuid = TargetUuid::load_or_create(&path, TargetUuid::generate).unwrap();
+        let again = TargetUuid::load_or_create(&path, TargetUuid::generate).unwrap();
+        assert_eq!(uuid, again);
+        assert_eq!(TargetUuid::load(&path).unwrap(), Some(uuid));
+
+        assert!(TargetUuid::pin(&path, uuid).is_ok());
+        assert!(TargetUuid::pin(&path, TargetUuid::generate()).is_err());
+        let pinned = temp.path().join("index.uuid");
+        TargetUuid::pin(&pinned, uuid).unwrap();
+        assert_eq!(TargetUuid::load(&pinned).unwrap(), Some(uuid));
+    }
+}
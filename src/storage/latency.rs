@@ -0,0 +1,290 @@
+//! Latency histograms and slow operation logging
+//!
+//! `TimedStorage` wraps a backend and records how long each read, write
+//! and flush takes. Any single operation slower than the threshold is
+//! logged at warn level inside the current request span, so the line
+//! carries the target, initiator and opcode. A CAS backend's blob store
+//! (which also holds its tree nodes) can be wrapped in `TimedBlobStore`
+//! the same way, telling a slow blob store apart from a slow request.
+
+use super::{
+    ArchivalStorage, BlockStorage, DedupStats, DeviceInfo, StorageResult, UsageStats,
+};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Default time after which a single storage operation is logged as slow
+pub const DEFAULT_SLOW_THRESHOLD: Duration = Duration::from_secs(1);
+
+/// Bucket i counts operations of 2^(i-1) to 2^i - 1 µs; the last is open
+const BUCKETS: usize = 32;
+
+/// Log2-bucketed histogram of operation latencies
+pub struct LatencyHistogram {
+    buckets: [AtomicU64; BUCKETS],
+    total_us: AtomicU64,
+    max_us: AtomicU64,
+}
+
+/// Summary of one operation's latencies, in microseconds. Percentiles
+/// are bucket upper bounds, so they are accurate to a factor of two.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct OpLatency {
+    pub count: u64,
+    pub mean_us: u64,
+    pub p50_us: u64,
+    pub p99_us: u64,
+    pub max_us: u64,
+}
+
+/// Latencies of a backend's reads, writes and flushes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct LatencyStats {
+    pub read: OpLatency,
+    pub write: OpLatency,
+    pub flush: OpLatency,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            total_us: AtomicU64::new(0),
+            max_us: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = ((u64::BITS - us.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.total_us.fetch_add(us, Ordering::Relaxed);
+        self.max_us.fetch_max(us, Ordering::Relaxed);
+    }
+
+    pub fn stats(&self) -> OpLatency {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.load(Ordering::Relaxed))
+            .collect();
+        let count: u64 = counts.iter().sum();
+        if count == 0 {
+            return OpLatency::default();
+        }
+        let max_us = self.max_us.load(Ordering::Relaxed);
+        let percentile = |q: f64| {
+            let rank = ((count as f64 * q).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (i, &n) in counts.iter().enumerate() {
+                seen += n;
+                if seen >= rank {
+                    let upper = if i == 0 { 0 } else { (1u64 << i) - 1 };
+                    return upper.min(max_us);
+                }
+            }
+            max_us
+        };
+        OpLatency {
+            count,
+            mean_us: self.total_us.load(Ordering::Relaxed) / count,
+            p50_us: percentile(0.5),
+            p99_us: percentile(0.99),
+            max_us,
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// True if an operation took long enough to log; a zero threshold never does
+pub(crate) fn is_slow(elapsed: Duration, threshold: Duration) -> bool {
+    !threshold.is_zero() && elapsed >= threshold
+}
+
+/// Backend wrapper recording operation latencies
+pub struct TimedStorage {
+    name: String,
+    inner: Box<dyn BlockStorage>,
+    slow_threshold: Duration,
+    read: LatencyHistogram,
+    write: LatencyHistogram,
+    flush: LatencyHistogram,
+}
+
+impl TimedStorage {
+    /// Time `inner`, known as `name` in slow operation logs
+    pub fn new(name: impl Into<String>, inner: Box<dyn BlockStorage>) -> Self {
+        Self {
+            name: name.into(),
+            inner,
+            slow_threshold: DEFAULT_SLOW_THRESHOLD,
+            read: LatencyHistogram::new(),
+            write: LatencyHistogram::new(),
+            flush: LatencyHistogram::new(),
+        }
+    }
+
+    /// Log operations taking at least `threshold`; zero disables logging
+    pub fn with_slow_threshold(mut self, threshold: Duration) -> Self {
+        self.slow_threshold = threshold;
+        self
+    }
+
+    fn timed<T>(
+        &self,
+        op: &str,
+        histogram: Option<&LatencyHistogram>,
+        lba: u64,
+        sectors: u64,
+        f: impl FnOnce() -> StorageResult<T>,
+    ) -> StorageResult<T> {
+        let started = Instant::now();
+        let result = f();
+        let elapsed = started.elapsed();
+        if let Some(histogram) = histogram {
+            histogram.record(elapsed);
+        }
+        if is_slow(elapsed, self.slow_threshold) {
+            log::warn!(
+                "Slow {} on {}: {:?} (lba {}, {} sectors, {})",
+                op,
+                self.name,
+                elapsed,
+                lba,
+                sectors,
+                if result.is_ok() { "ok" } else { "failed" }
+            );
+        }
+        result
+    }
+}
+
+impl BlockStorage for TimedStorage {
+    fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+        self.timed("read", Some(&self.read), lba, count as u64, || {
+            self.inner.read(lba, count)
+        })
+    }
+
+    fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+        let sectors = (data.len() / self.inner.info().sector_size as usize) as u64;
+        self.timed("write", Some(&self.write), lba, sectors, || {
+            self.inner.write(lba, data)
+        })
+    }
+
+    fn read_runs(&self, runs: &[(u64, u8)]) -> StorageResult<Vec<Vec<u8>>> {
+        let lba = runs.first().map_or(0, |run| run.0);
+        let sectors = runs.iter().map(|run| run.1 as u64).sum();
+        self.timed("read", Some(&self.read), lba, sectors, || {
+            self.inner.read_runs(runs)
+        })
+    }
+
+    fn write_runs(&self, runs: &[(u64, &[u8])]) -> StorageResult<()> {
+        let sector_size = self.inner.info().sector_size as usize;
+        let lba = runs.first().map_or(0, |run| run.0);
+        let sectors = runs
+            .iter()
+            .map(|run| (run.1.len() / sector_size) as u64)
+            .sum();
+        self.timed("write", Some(&self.write), lba, sectors, || {
+            self.inner.write_runs(runs)
+        })
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.timed("flush", Some(&self.flush), 0, 0, || self.inner.flush())
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> StorageResult<()> {
+        self.timed("discard", None, lba, count, || {
+            self.inner.discard(lba, count)
+        })
+    }
+
+    fn write_zeroes(&self, lba: u64, count: u64) -> StorageResult<()> {
+        self.timed("write zeroes", None, lba, count, || {
+            self.inner.write_zeroes(lba, count)
+        })
+    }
+
+    fn allocated_ranges(&self, lba: u64, count: u64) -> StorageResult<Vec<(u64, u64)>> {
+        self.inner.allocated_ranges(lba, count)
+    }
+
+    fn check_health(&self) -> StorageResult<()> {
+        self.inner.check_health()
+    }
+
+    fn info(&self) -> &DeviceInfo {
+        self.inner.info()
+    }
+
+    fn dedup_stats(&self) -> Option<DedupStats> {
+        self.inner.dedup_stats()
+    }
+
+    fn usage(&self) -> Option<UsageStats> {
+        self.inner.usage()
+    }
+
+    fn latency_stats(&self) -> Option<LatencyStats> {
+        Some(LatencyStats {
+            read: self.read.stats(),
+            write: self.write.stats(),
+            flush: self.flush.stats(),
+        })
+    }
+
+    fn as_archival(&self) -> Option<&dyn ArchivalStorage> {
+        self.inner.as_archival()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemBackend;
+
+    #[test]
+    fn test_histogram_percentiles() {
+        let histogram = LatencyHistogram::new();
+        assert_eq!(histogram.stats(), OpLatency::default());
+
+        for _ in 0..98 {
+            histogram.record(Duration::from_micros(100));
+        }
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_millis(20));
+
+        let stats = histogram.stats();
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.p50_us, 127); // 100µs lands in the 64..=127 bucket
+        assert_eq!(stats.p99_us, 8191);
+        assert_eq!(stats.max_us, 20_000);
+        assert_eq!(stats.mean_us, (98 * 100 + 25_000) / 100);
+    }
+
+    #[test]
+    fn test_timed_storage_records() {
+        let storage = TimedStorage::new("e1.0", Box::new(MemBackend::new(64 * 512)))
+            .with_slow_threshold(Duration::ZERO);
+        storage.write(0, &[1; 1024]).unwrap();
+        storage.read(0, 2).unwrap();
+        storage.read(1, 1).unwrap();
+        storage.discard(0, 1).unwrap();
+
+        let stats = storage.latency_stats().unwrap();
+        assert_eq!(
+            (stats.read.count, stats.write.count, stats.flush.count),
+            (2, 1, 0)
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! In-memory storage backend
+//!
+//! Keeps the whole device in RAM. Contents are lost when the process exits,
+//! which suits protocol tests and ephemeral scratch disks.
+
+use super::{BlockStorage, DeviceInfo, DeviceStrings, StorageError, StorageResult, TargetUuid};
+use std::sync::RwLock;
+
+/// RAM-backed block storage
+pub struct MemBackend {
+    data: RwLock<Vec<u8>>,
+    info: DeviceInfo,
+}
+
+impl MemBackend {
+    /// Create a zero-filled device of the given size (rounded down to sectors)
+    pub fn new(size_bytes: u64) -> Self {
+        let total_sectors = size_bytes / 512;
+
+        let info = DeviceInfo {
+            model: "AoE Memory Backend".to_string(),
+            serial: format!("MEM-{:08X}", rand::random::<u32>()),
+            firmware: env!("CARGO_PKG_VERSION").to_string(),
+            total_sectors,
+            sector_size: 512,
+            lba48: true,
+            uuid: None,
+        };
+
+        Self {
+            data: RwLock::new(vec![0u8; (total_sectors * 512) as usize]),
+            info,
+        }
+    }
+
+    /// Use a different logical sector size (512 or 4096), keeping capacity
+    pub fn with_sector_size(mut self, sector_size: u32) -> Self {
+        self.info.set_sector_size(sector_size);
+        self
+    }
+
+    /// Report `uuid` as the backend's identity
+    pub fn with_uuid(mut self, uuid: TargetUuid) -> Self {
+        self.info.set_uuid(uuid);
+        self
+    }
+
+    /// Report `strings` in place of the generated model, serial and firmware
+    pub fn with_device_strings(mut self, strings: &DeviceStrings) -> Self {
+        self.info.set_strings(strings);
+        self
+    }
+}
+
+impl BlockStorage for MemBackend {
+    fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+        self.validate_range(lba, count)?;
+
+        let sector_size = self.info.sector_size as usize;
+        let start = lba as usize * sector_size;
+        let end = start + count as usize * sector_size;
+        Ok(self.data.read().unwrap()[start..end].to_vec())
+    }
+
+    fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+        let sector_size = self.info.sector_size as usize;
+        let count = (data.len() / sector_size) as u8;
+        self.validate_range(lba, count)?;
+
+        let start = lba as usize * sector_size;
+        self.data.write().unwrap()[start..start + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        Ok(())
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> StorageResult<()> {
+        let end = (lba + count).min(self.info.total_sectors);
+        if lba < end {
+            let sector_size = self.info.sector_size as usize;
+            self.data.write().unwrap()[lba as usize * sector_size..end as usize * sector_size]
+                .fill(0);
+        }
+        Ok(())
+    }
+
+    fn write_zeroes(&self, lba: u64, count: u64) -> StorageResult<()> {
+        if lba + count > self.info.total_sectors {
+            return Err(StorageError::OutOfRange {
+                lba,
+                max: self.info.total_sectors,
+            });
+        }
+        self.discard(lba, count)
+    }
+
+    fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageError;
+
+    #[test]
+    fn test_mem_backend_read_write() {
+        let backend = MemBackend::new(1024 * 1024);
+        assert_eq!(backend.info().total_sectors, 2048);
+
+        assert_eq!(backend.read(7, 1).unwrap(), vec![0u8; 512]);
+
+        backend.write(7, &vec![0xCD; 1024]).unwrap();
+        assert_eq!(backend.read(7, 2).unwrap(), vec![0xCD; 1024]);
+
+        backend.discard(8, 1).unwrap();
+        assert_eq!(backend.read(8, 1).unwrap(), vec![0u8; 512]);
+    }
+
+    #[test]
+    fn test_mem_backend_out_of_range() {
+        let backend = MemBackend::new(512 * 4);
+        let result = backend.write(3, &[0u8; 1024]);
+        assert!(matches!(result, Err(StorageError::OutOfRange { .. })));
+    }
+}
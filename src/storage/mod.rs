@@ -4,15 +4,29 @@
 
 pub mod cas;
 pub mod cas_client;
+pub mod device;
 pub mod file;
+pub mod geometry;
+pub mod health;
+pub mod identity;
+pub mod latency;
+pub mod memory;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub mod uring;
 
+use crate::blob::QuotaUsage;
+use std::io::Read;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 /// Storage errors
 #[derive(Debug, Error)]
 pub enum StorageError {
     #[error("I/O error: {0}")]
-    Io(#[from] std::io::Error),
+    Io(std::io::Error),
 
     #[error("LBA out of range: {lba} (max: {max})")]
     OutOfRange { lba: u64, max: u64 },
@@ -28,11 +42,38 @@ pub enum StorageError {
 
     #[error("data corruption detected")]
     Corrupted,
+
+    /// Free space fell below the reserve (or the filesystem is full)
+    #[error("no space left: {available} bytes free, {reserve} reserved")]
+    NoSpace { available: u64, reserve: u64 },
+
+    /// The target's blob store quota is used up
+    #[error("quota exceeded: {used} of {quota} bytes used")]
+    QuotaExceeded { used: u64, quota: u64 },
+}
+
+impl From<std::io::Error> for StorageError {
+    fn from(e: std::io::Error) -> Self {
+        if e.raw_os_error() == Some(libc::ENOSPC) {
+            return StorageError::NoSpace {
+                available: 0,
+                reserve: 0,
+            };
+        }
+        StorageError::Io(e)
+    }
 }
 
 /// Result type for storage operations
 pub type StorageResult<T> = Result<T, StorageError>;
 
+/// Longest model name IDENTIFY DEVICE can report
+pub const ATA_MODEL_LEN: usize = 40;
+/// Longest serial number IDENTIFY DEVICE can report
+pub const ATA_SERIAL_LEN: usize = 20;
+/// Longest firmware revision IDENTIFY DEVICE can report
+pub const ATA_FIRMWARE_LEN: usize = 8;
+
 /// Device information for IDENTIFY DEVICE
 #[derive(Debug, Clone)]
 pub struct DeviceInfo {
@@ -48,6 +89,88 @@ pub struct DeviceInfo {
     pub sector_size: u32,
     /// LBA48 support
     pub lba48: bool,
+    /// Persistent identity, for backends that keep one with their data
+    pub uuid: Option<TargetUuid>,
+}
+
+impl DeviceInfo {
+    /// Capacity in bytes
+    pub fn size_bytes(&self) -> u64 {
+        self.total_sectors * self.sector_size as u64
+    }
+
+    /// Change the sector size, keeping capacity (rounded down to whole sectors)
+    pub fn set_sector_size(&mut self, sector_size: u32) {
+        let size_bytes = self.size_bytes();
+        self.sector_size = sector_size;
+        self.total_sectors = size_bytes / sector_size as u64;
+    }
+
+    /// Take on `uuid` as the identity, with the serial derived from it
+    pub fn set_uuid(&mut self, uuid: TargetUuid) {
+        self.serial = uuid.serial();
+        self.uuid = Some(uuid);
+    }
+
+    /// Report the strings set in `strings` instead of the backend's own
+    pub fn set_strings(&mut self, strings: &DeviceStrings) {
+        if let Some(model) = &strings.model {
+            self.model = model.clone();
+        }
+        if let Some(serial) = &strings.serial {
+            self.serial = serial.clone();
+        }
+        if let Some(firmware) = &strings.firmware {
+            self.firmware = firmware.clone();
+        }
+    }
+}
+
+/// Model, serial and firmware to report in place of a backend's own, for
+/// initiators and asset tracking that key off them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStrings {
+    pub model: Option<String>,
+    pub serial: Option<String>,
+    pub firmware: Option<String>,
+}
+
+impl DeviceStrings {
+    /// Check each string is printable ASCII that fits its IDENTIFY DEVICE
+    /// field
+    pub fn validate(&self) -> Result<(), String> {
+        let fields = [
+            ("model", &self.model, ATA_MODEL_LEN),
+            ("serial", &self.serial, ATA_SERIAL_LEN),
+            ("firmware", &self.firmware, ATA_FIRMWARE_LEN),
+        ];
+        for (name, value, max) in fields {
+            let Some(value) = value else {
+                continue;
+            };
+            if value.trim().is_empty() {
+                return Err(format!("{} is empty", name));
+            }
+            if !value.bytes().all(|b| b.is_ascii_graphic() || b == b' ') {
+                return Err(format!("{} {:?} must be printable ASCII", name, value));
+            }
+            if value.len() > max {
+                return Err(format!(
+                    "{} {:?} is {} characters, more than ATA's {}",
+                    name,
+                    value,
+                    value.len(),
+                    max
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a sector size is supported (512e/512n or 4Kn)
+pub fn is_valid_sector_size(sector_size: u32) -> bool {
+    sector_size == 512 || sector_size == 4096
 }
 
 impl Default for DeviceInfo {
@@ -59,11 +182,15 @@ impl Default for DeviceInfo {
             total_sectors: 0,
             sector_size: 512,
             lba48: true,
+            uuid: None,
         }
     }
 }
 
 /// Block storage trait - the core abstraction for storage backends
+///
+/// All methods take `&self`; backends use interior mutability so a single
+/// instance can be shared between threads without an outer lock.
 pub trait BlockStorage: Send + Sync {
     /// Read sectors starting at LBA.
     /// Returns exactly count * sector_size bytes.
@@ -71,14 +198,76 @@ pub trait BlockStorage: Send + Sync {
 
     /// Write sectors starting at LBA.
     /// Data length must equal count * sector_size.
-    fn write(&mut self, lba: u64, data: &[u8]) -> StorageResult<()>;
+    fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()>;
+
+    /// Read several (lba, count) runs, returned in order. Backends that can
+    /// queue them together override this; the default reads them in turn.
+    fn read_runs(&self, runs: &[(u64, u8)]) -> StorageResult<Vec<Vec<u8>>> {
+        runs.iter().map(|&(lba, count)| self.read(lba, count)).collect()
+    }
+
+    /// Write several (lba, data) runs; the default writes them in turn.
+    fn write_runs(&self, runs: &[(u64, &[u8])]) -> StorageResult<()> {
+        for &(lba, data) in runs {
+            self.write(lba, data)?;
+        }
+        Ok(())
+    }
 
     /// Flush pending writes to stable storage.
-    fn flush(&mut self) -> StorageResult<()>;
+    fn flush(&self) -> StorageResult<()>;
+
+    /// Discard sectors the initiator no longer needs (TRIM/UNMAP).
+    /// Advisory: the default does nothing, backends may free space.
+    fn discard(&self, lba: u64, count: u64) -> StorageResult<()> {
+        let _ = (lba, count);
+        Ok(())
+    }
+
+    /// Zero sectors, so reads return zeroes afterwards. Backends may
+    /// deallocate the range instead of writing it; the default writes
+    /// zero buffers.
+    fn write_zeroes(&self, lba: u64, count: u64) -> StorageResult<()> {
+        zero_fill(self, lba, count)
+    }
+
+    /// Allocated sector ranges as (lba, count) among `count` sectors from
+    /// `lba`; the sectors between them read as zeroes. The default reports
+    /// the whole span allocated.
+    fn allocated_ranges(&self, lba: u64, count: u64) -> StorageResult<Vec<(u64, u64)>> {
+        Ok(vec![(lba, count)])
+    }
+
+    /// Check the backend can still serve requests, e.g. that its files
+    /// exist and its filesystem has room. Should be cheap; the default
+    /// assumes all is well.
+    fn check_health(&self) -> StorageResult<()> {
+        Ok(())
+    }
 
     /// Device information (size, model, serial, etc.)
     fn info(&self) -> &DeviceInfo;
 
+    /// Deduplication statistics, for backends that deduplicate
+    fn dedup_stats(&self) -> Option<DedupStats> {
+        None
+    }
+
+    /// Operation latencies, for backends wrapped in `TimedStorage`
+    fn latency_stats(&self) -> Option<LatencyStats> {
+        None
+    }
+
+    /// Allocated space and free space, for thin-provisioned backends
+    fn usage(&self) -> Option<UsageStats> {
+        None
+    }
+
+    /// Snapshot support, for backends that keep history
+    fn as_archival(&self) -> Option<&dyn ArchivalStorage> {
+        None
+    }
+
     /// Validate that a range is within bounds
     fn validate_range(&self, lba: u64, count: u8) -> StorageResult<()> {
         let info = self.info();
@@ -93,8 +282,100 @@ pub trait BlockStorage: Send + Sync {
     }
 }
 
+/// Flush everything acknowledged so far, then snapshot it: the consistent
+/// point an initiator asks for in-band. None if the backend keeps no
+/// snapshots.
+pub fn consistent_snapshot<S: BlockStorage + ?Sized>(
+    storage: &S,
+    description: Option<&str>,
+) -> Option<StorageResult<String>> {
+    let archival = storage.as_archival()?;
+    Some(storage.flush().and_then(|_| archival.snapshot(description)))
+}
+
+/// Whether a buffer is all zeros, compared a page at a time with memcmp
+/// rather than byte by byte
+pub fn is_all_zero(data: &[u8]) -> bool {
+    static ZEROS: [u8; 4096] = [0; 4096];
+    data.chunks(ZEROS.len()).all(|chunk| chunk == &ZEROS[..chunk.len()])
+}
+
+/// Fsync the directory holding `path`, so a file created or renamed there
+/// survives a crash along with its contents
+pub(crate) fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::File::open(dir)?.sync_all()
+}
+
+/// Zero a range by writing zero buffers, up to 255 sectors at a time
+pub(crate) fn zero_fill<S: BlockStorage + ?Sized>(
+    storage: &S,
+    lba: u64,
+    count: u64,
+) -> StorageResult<()> {
+    let info = storage.info();
+    if lba + count > info.total_sectors {
+        return Err(StorageError::OutOfRange {
+            lba,
+            max: info.total_sectors,
+        });
+    }
+
+    let chunk = count.min(255);
+    let zeroes = vec![0u8; chunk as usize * info.sector_size as usize];
+    let mut done = 0;
+    while done < count {
+        let n = (count - done).min(chunk) as usize;
+        storage.write(lba + done, &zeroes[..n * info.sector_size as usize])?;
+        done += n as u64;
+    }
+    Ok(())
+}
+
+/// Thin-provisioning usage: what a backend advertises against what it
+/// holds and what its filesystem has left
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct UsageStats {
+    /// Capacity advertised to initiators
+    pub capacity_bytes: u64,
+    /// Advertised bytes holding data (never written or zeroed ranges excluded)
+    pub allocated_bytes: u64,
+    /// Filesystem holding the data, if known
+    pub space: Option<SpaceStatus>,
+    /// Filling the rest of the device could need more than the filesystem
+    /// has free above its reserve (ignoring dedup and compression)
+    pub overcommitted: bool,
+    /// Blob store bytes this target added, against its quota
+    pub quota: Option<QuotaUsage>,
+}
+
+impl UsageStats {
+    pub fn new(capacity_bytes: u64, allocated_bytes: u64, space: Option<SpaceStatus>) -> Self {
+        let overcommitted = space.is_some_and(|space| {
+            let usable = space.available_bytes.saturating_sub(space.reserve_bytes);
+            capacity_bytes.saturating_sub(allocated_bytes) > usable
+        });
+        Self {
+            capacity_bytes,
+            allocated_bytes,
+            space,
+            overcommitted,
+            quota: None,
+        }
+    }
+
+    /// Also report the target's blob store quota usage
+    pub fn with_quota(mut self, quota: Option<QuotaUsage>) -> Self {
+        self.quota = quota;
+        self
+    }
+}
+
 /// Snapshot information
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct SnapshotInfo {
     /// Snapshot identifier (usually root hash)
     pub id: String,
@@ -102,20 +383,138 @@ pub struct SnapshotInfo {
     pub timestamp: u64,
     /// Optional description
     pub description: Option<String>,
+    /// Unique name, usable wherever the id is
+    pub name: Option<String>,
+    /// Labels that retention rules select on
+    pub tags: Vec<String>,
+    /// Id of the snapshot this one was taken on top of (the last one taken
+    /// or restored before it), None for the first of a history
+    pub parent: Option<String>,
+}
+
+/// A snapshot and the snapshots taken on top of it. Restoring an older
+/// snapshot and writing on starts a new branch.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotTree {
+    #[serde(flatten)]
+    pub snapshot: SnapshotInfo,
+    pub children: Vec<SnapshotTree>,
 }
 
-/// Extended trait for archival storage (CAS backend)
+impl SnapshotTree {
+    /// Arrange snapshots, oldest first as `list_snapshots` returns them,
+    /// by parent. Snapshots whose parent is gone become roots.
+    pub fn build(snapshots: &[SnapshotInfo]) -> Vec<SnapshotTree> {
+        // A parent is the latest earlier snapshot with that id (ids repeat
+        // when nothing was written between snapshots)
+        let mut children = vec![Vec::new(); snapshots.len()];
+        let mut roots = Vec::new();
+        for (i, snapshot) in snapshots.iter().enumerate() {
+            let parent = snapshot
+                .parent
+                .as_ref()
+                .and_then(|parent| snapshots[..i].iter().rposition(|s| &s.id == parent));
+            match parent {
+                Some(parent) => children[parent].push(i),
+                None => roots.push(i),
+            }
+        }
+
+        fn node(snapshots: &[SnapshotInfo], children: &[Vec<usize>], i: usize) -> SnapshotTree {
+            SnapshotTree {
+                snapshot: snapshots[i].clone(),
+                children: children[i].iter().map(|&c| node(snapshots, children, c)).collect(),
+            }
+        }
+        roots.into_iter().map(|i| node(snapshots, &children, i)).collect()
+    }
+
+    /// Print the tree; a lone child continues at the same indent, while
+    /// branches are indented under the snapshot they fork from
+    pub fn print(&self, indent: usize) {
+        let prefix = "  ".repeat(indent);
+        let snapshot = &self.snapshot;
+        println!(
+            "{}{} {}{}",
+            prefix,
+            snapshot.timestamp,
+            snapshot.name.as_deref().unwrap_or(&snapshot.id),
+            snapshot.description.as_deref().map(|d| format!("  ({})", d)).unwrap_or_default()
+        );
+        match self.children.as_slice() {
+            [only] => only.print(indent),
+            children => {
+                for child in children {
+                    child.print(indent + 1);
+                }
+            }
+        }
+    }
+}
+
+/// Keep the newest `keep` snapshots tagged `tag`; older ones go when
+/// pruning, unless another rule keeps them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionRule {
+    pub tag: String,
+    pub keep: usize,
+}
+
+/// Extended trait for archival storage (CAS backend).
+///
+/// Snapshots are referred to by id or by name.
 pub trait ArchivalStorage: BlockStorage {
     /// Create snapshot, return identifier (root hash).
-    fn snapshot(&mut self, description: Option<&str>) -> StorageResult<String>;
+    fn snapshot(&self, description: Option<&str>) -> StorageResult<String>;
+
+    /// Create a snapshot with a unique name and tags, return its identifier.
+    fn snapshot_named(
+        &self,
+        name: &str,
+        description: Option<&str>,
+        tags: &[String],
+    ) -> StorageResult<String>;
 
     /// List available snapshots.
     fn list_snapshots(&self) -> StorageResult<Vec<SnapshotInfo>>;
 
+    /// Give a snapshot a new (unique) name and/or replace its tags, both
+    /// or neither, returning the updated snapshot.
+    fn update_snapshot(
+        &self,
+        snapshot: &str,
+        name: Option<&str>,
+        tags: Option<&[String]>,
+    ) -> StorageResult<SnapshotInfo>;
+
+    /// Delete snapshots the rules no longer keep, returning them.
+    fn prune_snapshots(&self, rules: &[RetentionRule]) -> StorageResult<Vec<SnapshotInfo>>;
+
     /// Restore to a snapshot (reads will see that version).
-    fn restore(&mut self, snapshot_id: &str) -> StorageResult<()>;
+    fn restore(&self, snapshot_id: &str) -> StorageResult<()>;
+
+    /// Sector ranges within `sectors` whose contents differ from a
+    /// snapshot's, in order
+    fn changed_since(
+        &self,
+        snapshot_id: &str,
+        sectors: Range<u64>,
+    ) -> StorageResult<Vec<Range<u64>>>;
+
+    /// Replace the contents from LBA 0 with a raw disk image, returning
+    /// the number of bytes read (see `CasBackend::ingest_from`).
+    fn ingest(&self, reader: &mut dyn Read) -> StorageResult<u64>;
 }
 
 // Re-export backends
-pub use cas::CasBackend;
+pub use cas::{CasBackend, Compression, DedupStats};
+pub use device::DeviceBackend;
 pub use file::FileBackend;
+pub use geometry::Geometry;
+pub use health::{HealthCheck, SpaceReserve, SpaceStatus, DEFAULT_HEALTH_INTERVAL};
+pub use identity::TargetUuid;
+pub use latency::{LatencyStats, OpLatency, TimedStorage, DEFAULT_SLOW_THRESHOLD};
+pub use memory::MemBackend;
+#[cfg(all(target_os = "linux", feature = "uring"))]
+pub use uring::UringFileBackend;
+
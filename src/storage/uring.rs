@@ -0,0 +1,397 @@
+//! io_uring file backend (Linux only, feature `uring`)
+//!
+//! Same on-disk layout as FileBackend, but I/O is submitted through an
+//! io_uring. Each thread has its own ring, so threads serving different
+//! requests never wait on each other; the runs of one request are pushed
+//! to the submission queue together and reaped with a single
+//! `submit_and_wait`, avoiding a syscall per sector run.
+
+use super::file::FileBackend;
+use super::health::SpaceReserve;
+use super::{BlockStorage, DeviceInfo, DeviceStrings, StorageError, StorageResult};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cell::RefCell;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+
+/// Submission queue depth (requests in flight per submit)
+const QUEUE_DEPTH: u32 = 64;
+
+/// How often a failed ring is polled for the completions still owed
+const DRAIN_POLL: Duration = Duration::from_millis(1);
+
+thread_local! {
+    /// This thread's ring, shared by every backend the thread uses and
+    /// created on first I/O
+    static RING: RefCell<Option<Ring>> = const { RefCell::new(None) };
+}
+
+/// io_uring backed file storage
+pub struct UringFileBackend {
+    inner: FileBackend,
+}
+
+impl UringFileBackend {
+    /// Open an existing file as a block device
+    pub fn open<P: AsRef<Path>>(path: P) -> StorageResult<Self> {
+        Self::with_backend(FileBackend::open(path)?)
+    }
+
+    /// Open or create a file with specified size
+    pub fn open_or_create<P: AsRef<Path>>(path: P, size_bytes: u64) -> StorageResult<Self> {
+        Self::with_backend(FileBackend::open_or_create(path, size_bytes)?)
+    }
+
+    /// Use a different logical sector size (512 or 4096), keeping capacity;
+    /// refused if the file was written with another
+    pub fn with_sector_size(mut self, sector_size: u32) -> StorageResult<Self> {
+        self.inner = self.inner.with_sector_size(sector_size)?;
+        Ok(self)
+    }
+
+    /// Report `strings` in place of the generated model, serial and firmware
+    pub fn with_device_strings(mut self, strings: &DeviceStrings) -> Self {
+        self.inner = self.inner.with_device_strings(strings);
+        self
+    }
+
+    /// Refuse writes while free space is below the reserve
+    pub fn with_reserve(mut self, reserve: SpaceReserve) -> Self {
+        self.inner = self.inner.with_reserve(reserve);
+        self
+    }
+
+    fn with_backend(inner: FileBackend) -> StorageResult<Self> {
+        // Fail at open rather than on first I/O where io_uring is unavailable
+        drop(IoUring::new(QUEUE_DEPTH)?);
+        Ok(Self { inner })
+    }
+
+    /// Check a run of `sectors` from `lba` lies within the device
+    fn check_run(&self, lba: u64, sectors: u64) -> StorageResult<()> {
+        let max = self.inner.info().total_sectors;
+        match lba.checked_add(sectors) {
+            Some(end) if end <= max => Ok(()),
+            _ => Err(StorageError::OutOfRange { lba, max }),
+        }
+    }
+
+    /// Submit entries on this thread's ring, returning results in order.
+    ///
+    /// Buffers referenced by the entries must stay alive until this returns;
+    /// it doesn't return while the kernel may still use them.
+    fn submit(&self, entries: Vec<squeue::Entry>) -> StorageResult<Vec<i32>> {
+        RING.with(|ring| {
+            let mut ring = ring.borrow_mut();
+            let ring = match &mut *ring {
+                Some(ring) => ring,
+                empty => empty.insert(Ring::new()?),
+            };
+            ring.submit(entries)
+        })
+    }
+}
+
+/// A thread's ring and the tag of the batch in flight on it
+struct Ring {
+    uring: IoUring,
+    /// Bumped per batch and carried in user_data, so a completion from an
+    /// earlier batch can never be taken for one of the current batch
+    generation: u32,
+}
+
+impl Ring {
+    fn new() -> StorageResult<Self> {
+        Ok(Self {
+            uring: IoUring::new(QUEUE_DEPTH)?,
+            generation: 0,
+        })
+    }
+
+    /// Submit entries in QUEUE_DEPTH batches, returning results in order
+    fn submit(&mut self, entries: Vec<squeue::Entry>) -> StorageResult<Vec<i32>> {
+        let mut results = vec![0i32; entries.len()];
+
+        for (batch_index, batch) in entries.chunks(QUEUE_DEPTH as usize).enumerate() {
+            let base = batch_index * QUEUE_DEPTH as usize;
+            self.generation = self.generation.wrapping_add(1);
+            let generation = self.generation;
+
+            let mut pushed = 0;
+            for (i, entry) in batch.iter().enumerate() {
+                let entry = entry.clone().user_data(user_data(generation, i));
+                // SAFETY: the buffers outlive the submission. Everything pushed
+                // is reaped by `reap` below before we return, even if the rest
+                // of the batch doesn't fit, and if the ring fails `reap` waits
+                // for whatever the kernel took before giving it up.
+                if unsafe { self.uring.submission().push(&entry) }.is_err() {
+                    break;
+                }
+                pushed += 1;
+            }
+
+            self.reap(generation, &mut results[base..base + pushed])?;
+            if pushed < batch.len() {
+                return Err(StorageError::Backend("io_uring queue full".to_string()));
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// Submit the queued entries of `generation` and wait for all
+    /// `results.len()` of them to complete. Transient errors are retried, so
+    /// this only returns early if the ring itself fails, and then it is
+    /// drained and replaced.
+    fn reap(&mut self, generation: u32, results: &mut [i32]) -> StorageResult<()> {
+        let mut reaped = 0;
+        while reaped < results.len() {
+            if let Err(e) = self.uring.submit_and_wait(results.len() - reaped) {
+                match e.raw_os_error() {
+                    Some(libc::EINTR) | Some(libc::EAGAIN) | Some(libc::EBUSY) => {}
+                    _ => return Err(self.abandon(generation, results.len() - reaped, e)),
+                }
+            }
+            reaped += self.collect(generation, results);
+        }
+        Ok(())
+    }
+
+    /// Take the completions of `generation` off the queue, returning how many
+    fn collect(&mut self, generation: u32, results: &mut [i32]) -> usize {
+        let mut collected = 0;
+        for cqe in self.uring.completion() {
+            let (tag, index) = split_user_data(cqe.user_data());
+            if tag != generation || index >= results.len() {
+                log::warn!("Skipping stale io_uring completion {:#x}", cqe.user_data());
+                continue;
+            }
+            results[index] = cqe.result();
+            collected += 1;
+        }
+        collected
+    }
+
+    /// Give up on a failed ring once the kernel has finished with every
+    /// buffer it was handed, and start a fresh one. `outstanding` entries of
+    /// `generation` are unreaped; those the kernel never took off the
+    /// submission queue are dropped with the ring.
+    fn abandon(&mut self, generation: u32, outstanding: usize, error: io::Error) -> StorageError {
+        log::error!("io_uring failed with requests in flight: {}", error);
+        let mut results = vec![0i32; QUEUE_DEPTH as usize];
+        let mut in_flight = outstanding.saturating_sub(self.uring.submission().len());
+        while in_flight > 0 {
+            in_flight = in_flight.saturating_sub(self.collect(generation, &mut results));
+            if in_flight > 0 {
+                std::thread::sleep(DRAIN_POLL);
+            }
+        }
+
+        match IoUring::new(QUEUE_DEPTH) {
+            Ok(uring) => self.uring = uring,
+            Err(e) => log::error!("Failed to recreate io_uring: {}", e),
+        }
+        error.into()
+    }
+}
+
+/// Pack a batch generation and an index within the batch into user_data
+fn user_data(generation: u32, index: usize) -> u64 {
+    ((generation as u64) << 32) | index as u64
+}
+
+fn split_user_data(user_data: u64) -> (u32, usize) {
+    ((user_data >> 32) as u32, (user_data & 0xFFFF_FFFF) as usize)
+}
+
+/// Convert a completion result into a byte count
+fn check_result(result: i32) -> StorageResult<usize> {
+    if result < 0 {
+        Err(io::Error::from_raw_os_error(-result).into())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+impl BlockStorage for UringFileBackend {
+    fn read(&self, lba: u64, count: u8) -> StorageResult<Vec<u8>> {
+        let mut buffers = self.read_runs(&[(lba, count)])?;
+        Ok(buffers.pop().unwrap_or_default())
+    }
+
+    fn write(&self, lba: u64, data: &[u8]) -> StorageResult<()> {
+        self.write_runs(&[(lba, data)])
+    }
+
+    fn read_runs(&self, runs: &[(u64, u8)]) -> StorageResult<Vec<Vec<u8>>> {
+        let sector_size = self.inner.info().sector_size as usize;
+        for &(lba, count) in runs {
+            self.validate_range(lba, count)?;
+        }
+
+        let mut buffers: Vec<Vec<u8>> = runs
+            .iter()
+            .map(|&(_, count)| vec![0u8; count as usize * sector_size])
+            .collect();
+
+        let fd = types::Fd(self.inner.file.as_raw_fd());
+        let entries: Vec<squeue::Entry> = runs
+            .iter()
+            .zip(buffers.iter_mut())
+            .map(|(&(lba, _), buf)| {
+                opcode::Read::new(fd, buf.as_mut_ptr(), buf.len() as u32)
+                    .offset(lba * sector_size as u64)
+                    .build()
+            })
+            .collect();
+
+        let results = self.submit(entries)?;
+
+        for ((&(lba, _), buf), result) in runs.iter().zip(buffers.iter_mut()).zip(results) {
+            let done = check_result(result)?;
+            if done < buf.len() {
+                // Short read - finish the remainder synchronously
+                let offset = lba * sector_size as u64 + done as u64;
+                self.inner.file.read_exact_at(&mut buf[done..], offset)?;
+            }
+        }
+
+        Ok(buffers)
+    }
+
+    fn write_runs(&self, runs: &[(u64, &[u8])]) -> StorageResult<()> {
+        let sector_size = self.inner.info().sector_size as usize;
+        for &(lba, data) in runs {
+            if !data.len().is_multiple_of(sector_size) {
+                return Err(StorageError::Backend(format!(
+                    "write of {} bytes is not a whole number of {}-byte sectors",
+                    data.len(),
+                    sector_size
+                )));
+            }
+            self.check_run(lba, (data.len() / sector_size) as u64)?;
+        }
+        self.inner.check_space()?;
+
+        let fd = types::Fd(self.inner.file.as_raw_fd());
+        let entries: Vec<squeue::Entry> = runs
+            .iter()
+            .map(|&(lba, data)| {
+                opcode::Write::new(fd, data.as_ptr(), data.len() as u32)
+                    .offset(lba * sector_size as u64)
+                    .build()
+            })
+            .collect();
+
+        let results = self.submit(entries)?;
+
+        for (&(lba, data), result) in runs.iter().zip(results) {
+            let done = check_result(result)?;
+            if done < data.len() {
+                // Short write - finish the remainder synchronously
+                let offset = lba * sector_size as u64 + done as u64;
+                self.inner.file.write_all_at(&data[done..], offset)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush(&self) -> StorageResult<()> {
+        self.inner.flush()
+    }
+
+    fn discard(&self, lba: u64, count: u64) -> StorageResult<()> {
+        self.inner.discard(lba, count)
+    }
+
+    fn write_zeroes(&self, lba: u64, count: u64) -> StorageResult<()> {
+        self.inner.write_zeroes(lba, count)
+    }
+
+    fn allocated_ranges(&self, lba: u64, count: u64) -> StorageResult<Vec<(u64, u64)>> {
+        self.inner.allocated_ranges(lba, count)
+    }
+
+    fn check_health(&self) -> StorageResult<()> {
+        self.inner.check_health()
+    }
+
+    fn info(&self) -> &DeviceInfo {
+        self.inner.info()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn open_backend(temp: &NamedTempFile) -> Option<UringFileBackend> {
+        // Kernels or sandboxes without io_uring can't run these tests
+        UringFileBackend::open_or_create(temp.path(), 1024 * 1024).ok()
+    }
+
+    #[test]
+    fn test_uring_read_write() {
+        let temp = NamedTempFile::new().unwrap();
+        let Some(backend) = open_backend(&temp) else {
+            return;
+        };
+
+        let write_data = vec![0xAA; 1024];
+        backend.write(4, &write_data).unwrap();
+        assert_eq!(backend.read(4, 2).unwrap(), write_data);
+    }
+
+    #[test]
+    fn test_uring_batch() {
+        let temp = NamedTempFile::new().unwrap();
+        let Some(backend) = open_backend(&temp) else {
+            return;
+        };
+
+        // More requests than the queue depth to exercise multiple submissions
+        let data: Vec<Vec<u8>> = (0..100u32).map(|i| vec![i as u8; 512]).collect();
+        let writes: Vec<(u64, &[u8])> = data
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (i as u64, d.as_slice()))
+            .collect();
+        backend.write_runs(&writes).unwrap();
+
+        let reads: Vec<(u64, u8)> = (0..100u64).map(|lba| (lba, 1)).collect();
+        let result = backend.read_runs(&reads).unwrap();
+        assert_eq!(result, data);
+    }
+
+    #[test]
+    fn test_uring_write_validation() {
+        let temp = NamedTempFile::new().unwrap();
+        let Some(backend) = open_backend(&temp) else {
+            return;
+        };
+
+        // Partial sectors are refused
+        assert!(backend.write(0, &[0xAA; 100]).is_err());
+        // 300 sectors from LBA 1900 run past the 2048 sector device
+        let long = vec![0xBB; 300 * 512];
+        assert!(matches!(
+            backend.write_runs(&[(1900, &long)]),
+            Err(StorageError::OutOfRange { lba: 1900, .. })
+        ));
+        assert_eq!(backend.read(1900, 1).unwrap(), vec![0; 512]);
+    }
+
+    #[test]
+    fn test_user_data_generation() {
+        let (generation, index) = split_user_data(user_data(7, 63));
+        assert_eq!((generation, index), (7, 63));
+        // A completion tagged by an earlier batch is told apart
+        assert_ne!(split_user_data(user_data(6, 63)).0, 7);
+        assert_eq!(split_user_data(user_data(u32::MAX, 0)), (u32::MAX, 0));
+    }
+}
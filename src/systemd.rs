@@ -0,0 +1,260 @@
+//! Optional systemd integration
+//!
+//! Everything here is driven by the environment systemd sets up and is a
+//! no-op when the variables are absent, so the daemons behave the same when
+//! started by hand:
+//!
+//! - `LISTEN_PID`/`LISTEN_FDS`: socket activation, the unit's socket is
+//!   passed in as fd 3
+//! - `NOTIFY_SOCKET`: `READY=1` once serving and `STOPPING=1` on shutdown
+//! - `WATCHDOG_USEC`: `WATCHDOG=1` pings at half the configured interval
+
+use crate::net::Listener;
+use crate::shutdown;
+use std::env;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// First file descriptor passed by socket activation
+#[cfg(unix)]
+const SD_LISTEN_FDS_START: i32 = 3;
+
+/// Take the socket-activated TCP or UNIX listener, if systemd passed one.
+///
+/// The activation variables are cleared so child processes don't inherit
+/// them. Only the first socket is used.
+#[cfg(unix)]
+pub fn take_listener() -> io::Result<Option<Listener>> {
+    use std::net::TcpListener;
+    use std::os::fd::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixListener;
+
+    let fds = match listen_fds() {
+        Some(fds) => fds,
+        None => return Ok(None),
+    };
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if fds == 0 {
+        return Ok(None);
+    }
+    if fds > 1 {
+        log::warn!("systemd passed {} sockets; using only the first", fds);
+    }
+
+    // SAFETY: systemd guarantees fds 3..3+LISTEN_FDS are open sockets owned
+    // by this process, and LISTEN_PID confirmed they were meant for us.
+    let listener = unsafe { TcpListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    if listener.local_addr().is_ok() {
+        return Ok(Some(Listener::Tcp(listener)));
+    }
+    // SAFETY: the same fd, now owned by the UNIX listener instead
+    let listener = unsafe { UnixListener::from_raw_fd(listener.into_raw_fd()) };
+    listener.local_addr()?; // fails if the fd is neither a TCP nor UNIX socket
+    Ok(Some(Listener::from(listener)))
+}
+
+/// Socket activation is not available on this platform
+#[cfg(not(unix))]
+pub fn take_listener() -> io::Result<Option<Listener>> {
+    Ok(None)
+}
+
+/// Number of sockets passed to this process, if socket-activated
+fn listen_fds() -> Option<u32> {
+    listen_fds_in(|name| env::var(name).ok())
+}
+
+/// `listen_fds` with the variables looked up by `var`
+fn listen_fds_in(var: impl Fn(&str) -> Option<String>) -> Option<u32> {
+    let pid: u32 = var("LISTEN_PID")?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    var("LISTEN_FDS")?.parse().ok()
+}
+
+/// True if the process was started with socket activation variables
+pub fn socket_activated() -> bool {
+    listen_fds().is_some_and(|fds| fds > 0)
+}
+
+/// Send a state string to the service manager.
+///
+/// Returns Ok(false) when not running under systemd.
+#[cfg(unix)]
+pub fn notify(state: &str) -> io::Result<bool> {
+    let Some(path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(false);
+    };
+    send_state(&path, state)?;
+    Ok(true)
+}
+
+/// Send a state string to the notify socket at `path`
+#[cfg(unix)]
+fn send_state(path: &std::ffi::OsStr, state: &str) -> io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    let path = path.to_string_lossy();
+
+    if let Some(name) = path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        {
+            use std::os::linux::net::SocketAddrExt;
+            let addr = std::os::unix::net::SocketAddr::from_abstract_name(name.as_bytes())?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = name;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract notify sockets require Linux",
+            ));
+        }
+    } else {
+        socket.send_to(state.as_bytes(), path.as_ref())?;
+    }
+    Ok(())
+}
+
+/// Notification is not available on this platform
+#[cfg(not(unix))]
+pub fn notify(_state: &str) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Watchdog interval requested by the unit, if any
+pub fn watchdog_interval() -> Option<Duration> {
+    watchdog_interval_in(|name| env::var(name).ok())
+}
+
+/// `watchdog_interval` with the variables looked up by `var`
+fn watchdog_interval_in(var: impl Fn(&str) -> Option<String>) -> Option<Duration> {
+    if let Some(pid) = var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = var("WATCHDOG_USEC")?.parse().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Report readiness and supervise the rest of the service's life.
+///
+/// Sends `READY=1`, then a background thread pings the watchdog (if
+/// enabled) and sends `STOPPING=1` once shutdown is requested. Call this
+/// after listeners are bound, right before serving.
+pub fn notify_ready() {
+    match notify("READY=1") {
+        Ok(false) => return,
+        Ok(true) => log::debug!("Notified systemd: READY=1"),
+        Err(e) => {
+            log::warn!("Failed to notify systemd: {}", e);
+            return;
+        }
+    }
+
+    let watchdog = watchdog_interval().map(|interval| interval / 2);
+    if let Some(interval) = watchdog {
+        log::info!("systemd watchdog enabled, pinging every {:?}", interval);
+    }
+
+    let spawned = thread::Builder::new()
+        .name("sd-notify".to_string())
+        .spawn(move || supervise(watchdog));
+    if let Err(e) = spawned {
+        log::warn!("Failed to start systemd notifier: {}", e);
+    }
+}
+
+fn supervise(watchdog: Option<Duration>) {
+    let mut next_ping = Instant::now();
+    while !shutdown::requested() {
+        if let Some(interval) = watchdog {
+            if Instant::now() >= next_ping {
+                if let Err(e) = notify("WATCHDOG=1") {
+                    log::warn!("Failed to ping systemd watchdog: {}", e);
+                }
+                next_ping = Instant::now() + interval;
+            }
+        }
+        thread::sleep(shutdown::POLL_INTERVAL);
+    }
+
+    if let Err(e) = notify("STOPPING=1") {
+        log::warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::os::unix::net::UnixDatagram;
+    use tempfile::TempDir;
+
+    // The process environment is shared by every test thread, so these go
+    // through the lookup-taking variants rather than setting variables
+
+    fn vars(pairs: &[(&str, String)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_notify_socket() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("notify.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        send_state(path.as_os_str(), "READY=1").unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = receiver.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[test]
+    fn test_listen_env() {
+        let pid = std::process::id().to_string();
+        assert_eq!(listen_fds_in(vars(&[])), None);
+        assert_eq!(
+            listen_fds_in(vars(&[("LISTEN_PID", pid), ("LISTEN_FDS", "1".into())])),
+            Some(1)
+        );
+
+        // Activation variables for another process are ignored
+        let other = vars(&[("LISTEN_PID", "1".into()), ("LISTEN_FDS", "1".into())]);
+        assert_eq!(listen_fds_in(other), None);
+    }
+
+    #[test]
+    fn test_watchdog_env() {
+        let pid = std::process::id().to_string();
+        assert_eq!(watchdog_interval_in(vars(&[])), None);
+        assert_eq!(
+            watchdog_interval_in(vars(&[("WATCHDOG_USEC", "2000000".into())])),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            watchdog_interval_in(vars(&[
+                ("WATCHDOG_PID", pid),
+                ("WATCHDOG_USEC", "0".into())
+            ])),
+            None
+        );
+        let other = vars(&[
+            ("WATCHDOG_PID", "1".into()),
+            ("WATCHDOG_USEC", "1000".into()),
+        ]);
+        assert_eq!(watchdog_interval_in(other), None);
+    }
+}
@@ -0,0 +1,496 @@
+//! Mutual TLS for the CAS link
+//!
+//! In split deployments iSCSI and AoE heads reach the CAS server over the
+//! network. With TLS configured on both ends (`TlsConfig`), each side
+//! presents a certificate signed by a CA the other trusts, and may also
+//! pin the exact certificates it accepts by their SHA-256 fingerprints
+//! (`certificate_fingerprint`, `openssl x509 -fingerprint -sha256`).
+//!
+//! Certificates are read from PEM files. `TlsServer` and `TlsClient`
+//! re-read them for new connections after `install_reload_signal`'s
+//! SIGHUP handler (or `request_reload`) has run, so certificates can be
+//! rotated without a restart; established connections keep theirs. A
+//! reload that fails is logged and the previous certificates stay in use.
+//!
+//! `TlsStream` lets one thread read while another writes, as the CAS
+//! client's pipelining does: the plain socket is only read or written
+//! outside the connection's lock.
+
+use crate::net::Stream;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, ClientConnection, Connection, RootCertStore, ServerConfig};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Longest a server waits for a client to finish its handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Ciphertext read from the socket at a time
+const READ_SIZE: usize = 4096;
+
+/// Bumped by SIGHUP; contexts reload when it moves past what they loaded
+static RELOADS: AtomicU64 = AtomicU64::new(0);
+
+/// Certificates for one end of a mutual TLS link
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM certificate chain presented to the peer
+    pub cert: PathBuf,
+    /// PEM private key of `cert`
+    pub key: PathBuf,
+    /// PEM CA certificates the peer's certificate must chain to
+    pub ca: PathBuf,
+    /// SHA-256 fingerprints (hex, colons optional) of the only peer
+    /// certificates accepted; any the CA signed if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<String>,
+    /// Name the server's certificate must carry, for clients; the host
+    /// part of the address by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String>,
+}
+
+/// SHA-256 fingerprint of a DER certificate, as lowercase hex
+pub fn fingerprint(der: &[u8]) -> String {
+    hex::encode(Sha256::digest(der))
+}
+
+/// Fingerprint of the first certificate in a PEM file, for pinning
+pub fn certificate_fingerprint(path: &Path) -> io::Result<String> {
+    Ok(fingerprint(&read_certs(path)?[0]))
+}
+
+/// Reload certificates on SIGHUP
+#[cfg(unix)]
+pub fn install_reload_signal() -> io::Result<()> {
+    // SAFETY: the handler only touches an atomic, which is
+    // async-signal-safe; the sigaction struct is fully initialized.
+    let result = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_sighup as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGHUP, &action, std::ptr::null_mut())
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Signals are not supported here; only `request_reload` reloads
+#[cfg(not(unix))]
+pub fn install_reload_signal() -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+extern "C" fn handle_sighup(_signal: libc::c_int) {
+    request_reload();
+}
+
+/// Reload certificates for the next connections, without a signal
+pub fn request_reload() {
+    RELOADS.fetch_add(1, Ordering::SeqCst);
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn tls_error(e: rustls::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn read_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{}: no certificates", path.display())));
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> io::Result<PrivateKeyDer<'static>> {
+    PrivateKeyDer::from_pem_file(path).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+}
+
+fn read_roots(path: &Path) -> io::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert).map_err(tls_error)?;
+    }
+    Ok(roots)
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Pins normalized for comparison with `fingerprint`
+fn normalize_pins(pins: &[String]) -> Vec<String> {
+    pins.iter().map(|pin| pin.replace(':', "").to_lowercase()).collect()
+}
+
+/// A rustls config, reloaded from its files when a reload is requested
+struct Reloading<T> {
+    config: TlsConfig,
+    load: fn(&TlsConfig) -> io::Result<Arc<T>>,
+    current: Mutex<(u64, Arc<T>)>,
+}
+
+impl<T> Reloading<T> {
+    fn new(config: TlsConfig, load: fn(&TlsConfig) -> io::Result<Arc<T>>) -> io::Result<Self> {
+        let reloads = RELOADS.load(Ordering::SeqCst);
+        let loaded = load(&config)?;
+        Ok(Self {
+            config,
+            load,
+            current: Mutex::new((reloads, loaded)),
+        })
+    }
+
+    fn get(&self) -> Arc<T> {
+        let mut current = self.current.lock().unwrap();
+        let reloads = RELOADS.load(Ordering::SeqCst);
+        if current.0 != reloads {
+            current.0 = reloads;
+            match (self.load)(&self.config) {
+                Ok(loaded) => {
+                    log::info!("Reloaded TLS certificate {}", self.config.cert.display());
+                    current.1 = loaded;
+                }
+                Err(e) => log::error!("Failed to reload TLS certificates, keeping the old: {}", e),
+            }
+        }
+        current.1.clone()
+    }
+}
+
+/// Server end: requires clients to present a certificate
+pub struct TlsServer {
+    config: Reloading<ServerConfig>,
+    pins: Vec<String>,
+}
+
+impl TlsServer {
+    pub fn new(config: TlsConfig) -> io::Result<Self> {
+        let pins = normalize_pins(&config.pins);
+        Ok(Self {
+            config: Reloading::new(config, Self::load)?,
+            pins,
+        })
+    }
+
+    fn load(config: &TlsConfig) -> io::Result<Arc<ServerConfig>> {
+        let provider = provider();
+        let roots = Arc::new(read_roots(&config.ca)?);
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots, provider.clone())
+            .build()
+            .map_err(|e| invalid(e.to_string()))?;
+        let server = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(read_certs(&config.cert)?, read_key(&config.key)?)
+            .map_err(tls_error)?;
+        Ok(Arc::new(server))
+    }
+
+    /// Handshake with a client that just connected, giving up after
+    /// `HANDSHAKE_TIMEOUT`
+    pub fn accept(&self, stream: Stream) -> io::Result<Stream> {
+        let conn = rustls::ServerConnection::new(self.config.get()).map_err(tls_error)?;
+        stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        stream.set_write_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        let stream = TlsStream::handshake(conn.into(), stream, &self.pins)?;
+        stream.set_write_timeout(None)?;
+        Ok(stream)
+    }
+}
+
+/// Client end: presents its certificate and checks the server's
+pub struct TlsClient {
+    config: Reloading<ClientConfig>,
+    pins: Vec<String>,
+    server_name: Option<String>,
+}
+
+impl fmt::Debug for TlsClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsClient")
+            .field("cert", &self.config.config.cert)
+            .field("pins", &self.pins)
+            .finish()
+    }
+}
+
+impl TlsClient {
+    pub fn new(config: TlsConfig) -> io::Result<Self> {
+        let pins = normalize_pins(&config.pins);
+        let server_name = config.server_name.clone();
+        Ok(Self {
+            config: Reloading::new(config, Self::load)?,
+            pins,
+            server_name,
+        })
+    }
+
+    fn load(config: &TlsConfig) -> io::Result<Arc<ClientConfig>> {
+        let client = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .map_err(tls_error)?
+            .with_root_certificates(read_roots(&config.ca)?)
+            .with_client_auth_cert(read_certs(&config.cert)?, read_key(&config.key)?)
+            .map_err(tls_error)?;
+        Ok(Arc::new(client))
+    }
+
+    /// Handshake over `stream`, connected to `addr`. Timeouts already set
+    /// on the stream apply to the handshake.
+    pub fn connect(&self, addr: &str, stream: Stream) -> io::Result<Stream> {
+        let name = match &self.server_name {
+            Some(name) => name.as_str(),
+            None => host(addr),
+        };
+        let name = ServerName::try_from(name.to_string())
+            .map_err(|e| invalid(format!("bad TLS server name {}: {}", name, e)))?;
+        let conn = ClientConnection::new(self.config.get(), name).map_err(tls_error)?;
+        TlsStream::handshake(conn.into(), stream, &self.pins)
+    }
+}
+
+/// Host part of a `host:port` address
+fn host(addr: &str) -> &str {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+struct TlsShared {
+    conn: Mutex<Connection>,
+    /// Read only by the reading thread
+    incoming: Mutex<Stream>,
+    /// Held while ciphertext is taken from `conn` and sent, so records go
+    /// out in order
+    outgoing: Mutex<Stream>,
+    /// For socket options, which are shared by all the handles
+    control: Stream,
+}
+
+/// A TLS connection. Clones share it, so one can read while another
+/// writes.
+#[derive(Clone)]
+pub struct TlsStream(Arc<TlsShared>);
+
+impl TlsStream {
+    /// Complete the handshake and check the peer against `pins`
+    fn handshake(mut conn: Connection, mut stream: Stream, pins: &[String]) -> io::Result<Stream> {
+        while conn.is_handshaking() {
+            conn.complete_io(&mut stream)?;
+        }
+        while conn.wants_write() {
+            conn.write_tls(&mut stream)?;
+        }
+        if !pins.is_empty() {
+            let peer = conn.peer_certificates().and_then(|certs| certs.first());
+            let fingerprint = peer.map(|cert| fingerprint(cert));
+            if !fingerprint.as_ref().is_some_and(|fp| pins.contains(fp)) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("peer certificate {} is not pinned", fingerprint.unwrap_or_default()),
+                ));
+            }
+        }
+        Ok(Stream::Tls(TlsStream(Arc::new(TlsShared {
+            conn: Mutex::new(conn),
+            incoming: Mutex::new(stream.try_clone()?),
+            outgoing: Mutex::new(stream.try_clone()?),
+            control: stream,
+        }))))
+    }
+
+    /// The plain socket, for options and addresses
+    pub fn socket(&self) -> &Stream {
+        &self.0.control
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        if matches!(how, Shutdown::Write | Shutdown::Both) {
+            self.0.conn.lock().unwrap().send_close_notify();
+            let _ = self.send();
+        }
+        self.0.control.shutdown(how)
+    }
+
+    /// Send whatever ciphertext the connection has queued
+    fn send(&self) -> io::Result<()> {
+        let mut outgoing = self.0.outgoing.lock().unwrap();
+        let mut ciphertext = Vec::new();
+        {
+            let mut conn = self.0.conn.lock().unwrap();
+            while conn.wants_write() {
+                conn.write_tls(&mut ciphertext)?;
+            }
+        }
+        outgoing.write_all(&ciphertext)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+
+            let mut ciphertext = [0u8; READ_SIZE];
+            let n = self.0.incoming.lock().unwrap().read(&mut ciphertext)?;
+            {
+                let mut conn = self.0.conn.lock().unwrap();
+                let mut received = &ciphertext[..n];
+                loop {
+                    // Zero bytes read tells rustls the peer hung up
+                    conn.read_tls(&mut received)?;
+                    conn.process_new_packets().map_err(tls_error)?;
+                    if received.is_empty() {
+                        break;
+                    }
+                }
+            }
+            // Alerts and key updates may need answering
+            self.send()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.conn.lock().unwrap().writer().write(buf)?;
+        self.send()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cas::{CasPool, CasPoolConfig, CasServer, CasServerConfig};
+    use rcgen::{BasicConstraints, CertificateParams, ExtendedKeyUsagePurpose, IsCa, KeyPair};
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// A CA issuing test certificates into `dir`
+    struct TestCa {
+        dir: PathBuf,
+        cert: rcgen::Certificate,
+        key: KeyPair,
+    }
+
+    impl TestCa {
+        fn new(dir: &Path, name: &str) -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::<String>::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let cert = params.self_signed(&key).unwrap();
+            fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+            Self {
+                dir: dir.to_path_buf(),
+                cert,
+                key,
+            }
+        }
+
+        /// Issue `name`, returning its config trusting `ca_name`
+        fn issue(&self, name: &str, usage: ExtendedKeyUsagePurpose, ca_name: &str) -> TlsConfig {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec!["127.0.0.1".to_string()]).unwrap();
+            params.extended_key_usages = vec![usage];
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            let config = TlsConfig {
+                cert: self.dir.join(format!("{}.crt", name)),
+                key: self.dir.join(format!("{}.key", name)),
+                ca: self.dir.join(format!("{}.pem", ca_name)),
+                pins: Vec::new(),
+                server_name: None,
+            };
+            fs::write(&config.cert, cert.pem()).unwrap();
+            fs::write(&config.key, key.serialize_pem()).unwrap();
+            config
+        }
+    }
+
+    fn pool(addr: &str, tls: Option<TlsConfig>) -> io::Result<CasPool> {
+        let config = CasPoolConfig {
+            max_retries: 0,
+            tls: tls.map(|tls| Arc::new(TlsClient::new(tls).unwrap())),
+            ..CasPoolConfig::default()
+        };
+        CasPool::connect(addr, config).and_then(|pool| pool.write(&[1; 4096]).map(|_| pool))
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        let temp = tempfile::tempdir().unwrap();
+        let ca = TestCa::new(temp.path(), "ca");
+        let other_ca = TestCa::new(temp.path(), "other-ca");
+        let server_config = ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth, "ca");
+        let client_config = ca.issue("client", ExtendedKeyUsagePurpose::ClientAuth, "ca");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let server = CasServer::new(CasServerConfig {
+            bind_addr: addr.clone(),
+            storage_path: temp.path().join("cas").to_string_lossy().into_owned(),
+        })
+        .unwrap()
+        .with_tls(Arc::new(TlsServer::new(server_config.clone()).unwrap()));
+        thread::spawn(move || server.serve(listener));
+
+        let cas = pool(&addr, Some(client_config.clone())).unwrap();
+        let hash = cas.write(&[2; 4096]).unwrap();
+        assert_eq!(cas.read(&hash).unwrap(), vec![2; 4096]);
+        let blocks: Vec<_> = (0..64u8).map(|n| vec![n; 4096]).collect();
+        let responses = cas.pipeline(crate::cas::CasCommand::Write, &blocks).unwrap();
+        assert_eq!(responses.len(), 64);
+
+        // Plain clients and clients the CA didn't sign are turned away
+        assert!(pool(&addr, None).is_err());
+        let stranger = other_ca.issue("stranger", ExtendedKeyUsagePurpose::ClientAuth, "ca");
+        assert!(pool(&addr, Some(stranger)).is_err());
+
+        // Pinning
+        let pinned = certificate_fingerprint(&server_config.cert).unwrap();
+        let with_pins = |pins: Vec<String>| TlsConfig {
+            pins,
+            ..client_config.clone()
+        };
+        assert!(pool(&addr, Some(with_pins(vec![pinned.clone()]))).is_ok());
+        assert!(pool(&addr, Some(with_pins(vec!["00".repeat(32)]))).is_err());
+
+        // The server picks up a rotated certificate after a reload
+        ca.issue("server", ExtendedKeyUsagePurpose::ServerAuth, "ca");
+        assert!(pool(&addr, Some(with_pins(vec![pinned.clone()]))).is_ok());
+        request_reload();
+        assert!(pool(&addr, Some(with_pins(vec![pinned]))).is_err());
+        let rotated = certificate_fingerprint(&server_config.cert).unwrap();
+        assert!(pool(&addr, Some(with_pins(vec![rotated]))).is_ok());
+        assert!(cas.read(&hash).is_ok());
+    }
+}